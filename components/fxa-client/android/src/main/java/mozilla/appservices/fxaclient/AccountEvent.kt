@@ -16,6 +16,17 @@ val <T> T.exhaustive: T
 sealed class AccountEvent {
     // A tab with all its history entries (back button).
     class TabReceived(val from: Device?, val entries: Array<TabHistoryEntry>) : AccountEvent()
+    // The user's profile changed, and should be fetched again with `getProfile`.
+    object ProfileUpdated : AccountEvent()
+    // Another device was connected to the account.
+    class DeviceConnected(val deviceName: String) : AccountEvent()
+    // A device was disconnected from the account. If `isLocalDevice` is true,
+    // the account has been disconnected locally too.
+    class DeviceDisconnected(val deviceId: String, val isLocalDevice: Boolean) : AccountEvent()
+    // The account password was changed or reset.
+    object PasswordChanged : AccountEvent()
+    // The account was destroyed, and the local account state cleared.
+    object AccountDestroyed : AccountEvent()
 
     companion object {
         private fun fromMessage(msg: MsgTypes.AccountEvent): AccountEvent {
@@ -29,6 +40,16 @@ sealed class AccountEvent {
                         }.toTypedArray()
                     )
                 }
+                MsgTypes.AccountEvent.AccountEventType.PROFILE_UPDATED -> ProfileUpdated
+                MsgTypes.AccountEvent.AccountEventType.DEVICE_CONNECTED -> DeviceConnected(
+                    deviceName = msg.deviceConnectedData.deviceName
+                )
+                MsgTypes.AccountEvent.AccountEventType.DEVICE_DISCONNECTED -> DeviceDisconnected(
+                    deviceId = msg.deviceDisconnectedData.deviceId,
+                    isLocalDevice = msg.deviceDisconnectedData.isLocalDevice
+                )
+                MsgTypes.AccountEvent.AccountEventType.PASSWORD_CHANGED -> PasswordChanged
+                MsgTypes.AccountEvent.AccountEventType.ACCOUNT_DESTROYED -> AccountDestroyed
                 null -> throw NullPointerException("AccountEvent type cannot be null.")
             }.exhaustive
         }
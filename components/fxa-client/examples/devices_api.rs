@@ -104,6 +104,15 @@ fn main() -> Result<(), failure::Error> {
                             };
                             webbrowser::open(&tab.url).unwrap();
                         }
+                        AccountEvent::ProfileUpdated => println!("Profile updated"),
+                        AccountEvent::DeviceConnected { device_name } => {
+                            println!("Device connected: {}", device_name)
+                        }
+                        AccountEvent::DeviceDisconnected { device_id, .. } => {
+                            println!("Device disconnected: {}", device_id)
+                        }
+                        AccountEvent::PasswordChanged => println!("Password changed"),
+                        AccountEvent::AccountDestroyed => println!("Account destroyed"),
                     }
                 }
                 thread::sleep(time::Duration::from_secs(1));
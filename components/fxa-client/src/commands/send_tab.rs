@@ -41,6 +41,13 @@ impl EncryptedSendTabPayload {
 #[derive(Serialize, Deserialize)]
 pub struct SendTabPayload {
     pub entries: Vec<TabHistoryEntry>,
+    /// Identifies a single "send tab" action in telemetry, on both the
+    /// sending and receiving ends. Older senders don't include it.
+    #[serde(rename = "flowID", default)]
+    pub flow_id: String,
+    /// Identifies the sending of a tab to a particular device.
+    #[serde(rename = "streamID", default)]
+    pub stream_id: String,
 }
 
 impl SendTabPayload {
@@ -50,6 +57,8 @@ impl SendTabPayload {
                 title: title.to_string(),
                 url: url.to_string(),
             }],
+            flow_id: String::new(),
+            stream_id: String::new(),
         }
     }
     fn encrypt(&self, keys: PublicSendTabKeys) -> Result<EncryptedSendTabPayload> {
@@ -213,3 +222,110 @@ fn extract_oldsync_key_components(oldsync_key: &ScopedKey) -> Result<(Vec<u8>, V
     let ksync = oldsync_key.key_bytes()?;
     Ok((ksync, kxcs))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client::{DeviceLocation, DeviceResponseCommon, DeviceType};
+    use std::collections::HashMap;
+
+    fn oldsync_key() -> ScopedKey {
+        ScopedKey {
+            kty: "oct".to_string(),
+            scope: scopes::OLD_SYNC.to_string(),
+            k: base64::encode_config(&[0x42u8; 64][..], base64::URL_SAFE_NO_PAD),
+            kid: format!(
+                "1542236016429-{}",
+                base64::encode_config(&[0x13u8; 16], base64::URL_SAFE_NO_PAD)
+            ),
+        }
+    }
+
+    fn device_with_command_data(command_data: Option<String>) -> Device {
+        let mut available_commands = HashMap::new();
+        if let Some(data) = command_data {
+            available_commands.insert(COMMAND_NAME.to_owned(), data);
+        }
+        Device {
+            common: DeviceResponseCommon {
+                id: "target".to_owned(),
+                display_name: "Target Device".to_owned(),
+                device_type: DeviceType::Mobile,
+                push_subscription: None,
+                available_commands,
+                push_endpoint_expired: false,
+            },
+            is_current_device: false,
+            location: DeviceLocation {
+                city: None,
+                country: None,
+                state: None,
+                state_code: None,
+            },
+            last_access_time: None,
+        }
+    }
+
+    #[test]
+    fn test_private_keys_serialization() {
+        let keys = PrivateSendTabKeys::from_random().unwrap();
+        let serialized = keys.serialize().unwrap();
+        let deserialized = PrivateSendTabKeys::deserialize(&serialized).unwrap();
+        assert_eq!(deserialized.auth_secret, keys.auth_secret);
+        assert_eq!(deserialized.serialize().unwrap(), serialized);
+    }
+
+    #[test]
+    fn test_send_tab_round_trip() {
+        let scoped_key = oldsync_key();
+        let keys = PrivateSendTabKeys::from_random().unwrap();
+        let public_keys: PublicSendTabKeys = keys.clone().into();
+        let command_data = public_keys.as_command_data(&scoped_key).unwrap();
+        let target = device_with_command_data(Some(command_data));
+
+        let payload = SendTabPayload::single_tab("Mozilla", "https://mozilla.org");
+        let command = build_send_command(&scoped_key, &target, &payload).unwrap();
+
+        let encrypted: EncryptedSendTabPayload = serde_json::from_value(command).unwrap();
+        let decrypted = encrypted.decrypt(&keys).unwrap();
+        assert_eq!(decrypted.entries.len(), 1);
+        assert_eq!(decrypted.entries[0].title, "Mozilla");
+        assert_eq!(decrypted.entries[0].url, "https://mozilla.org");
+    }
+
+    #[test]
+    fn test_send_tab_unsupported_target() {
+        let target = device_with_command_data(None);
+        let payload = SendTabPayload::single_tab("Mozilla", "https://mozilla.org");
+        match build_send_command(&oldsync_key(), &target, &payload)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::UnsupportedCommand(COMMAND_NAME) => {}
+            e => panic!("Unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn test_send_tab_mismatched_keys() {
+        let keys = PrivateSendTabKeys::from_random().unwrap();
+        let public_keys: PublicSendTabKeys = keys.into();
+        let command_data = public_keys.as_command_data(&oldsync_key()).unwrap();
+        let target = device_with_command_data(Some(command_data));
+
+        // The target's keys were wrapped with a different kSync.
+        let mut other_key = oldsync_key();
+        other_key.kid = format!(
+            "1542236016429-{}",
+            base64::encode_config(&[0x37u8; 16], base64::URL_SAFE_NO_PAD)
+        );
+        let payload = SendTabPayload::single_tab("Mozilla", "https://mozilla.org");
+        match build_send_command(&other_key, &target, &payload)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::MismatchedKeys => {}
+            e => panic!("Unexpected error {}", e),
+        }
+    }
+}
@@ -28,6 +28,10 @@ struct OpenIdConfigurationResponse {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
     content_url: String,
+    // Set for users running their own Sync server. Accounts persisted before
+    // this was added don't have one; see the note on `StateV3`.
+    #[serde(default)]
+    token_server_url_override: Option<String>,
     // RemoteConfig is lazily fetched from the server.
     remote_config: RefCell<Option<Arc<RemoteConfig>>>,
     pub client_id: String,
@@ -66,12 +70,32 @@ impl Config {
     pub fn new(content_url: &str, client_id: &str, redirect_uri: &str) -> Self {
         Self {
             content_url: content_url.to_string(),
+            token_server_url_override: None,
             client_id: client_id.to_string(),
             redirect_uri: redirect_uri.to_string(),
             remote_config: RefCell::new(None),
         }
     }
 
+    /// Create a `Config` for an FxA server at `content_url`, but which
+    /// uses a self-hosted Sync token server at `token_server_url`, instead
+    /// of the one advertised by the FxA server.
+    ///
+    /// The token server URL must be an absolute `https` URL, unless it
+    /// points to `localhost`, and is typically of the form
+    /// `https://sync.example.com/token/1.0/sync/1.5`.
+    pub fn with_overrides(
+        content_url: &str,
+        token_server_url: &str,
+        client_id: &str,
+        redirect_uri: &str,
+    ) -> Result<Self> {
+        validate_token_server_url(token_server_url)?;
+        let mut config = Self::new(content_url, client_id, redirect_uri);
+        config.token_server_url_override = Some(token_server_url.to_string());
+        Ok(config)
+    }
+
     // FIXME
     #[allow(clippy::too_many_arguments)]
     pub(crate) fn init(
@@ -102,6 +126,7 @@ impl Config {
 
         Config {
             content_url,
+            token_server_url_override: None,
             remote_config: RefCell::new(Some(Arc::new(remote_config))),
             client_id,
             redirect_uri,
@@ -178,6 +203,9 @@ impl Config {
     }
 
     pub fn token_server_endpoint_url(&self) -> Result<Url> {
+        if let Some(ref token_server_url) = self.token_server_url_override {
+            return Url::parse(token_server_url).map_err(Into::into);
+        }
         Url::parse(&self.remote_config()?.token_server_endpoint_url).map_err(Into::into)
     }
 
@@ -197,11 +225,29 @@ impl Config {
         Url::parse(&self.remote_config()?.token_endpoint).map_err(Into::into)
     }
 
+    pub fn introspection_endpoint(&self) -> Result<Url> {
+        self.oauth_url_path("v1/introspect")
+    }
+
     pub fn userinfo_endpoint(&self) -> Result<Url> {
         Url::parse(&self.remote_config()?.userinfo_endpoint).map_err(Into::into)
     }
 }
 
+// We'd rather refuse a bad URL right away than have every sync fail later.
+// This matches what viaduct lets through.
+fn validate_token_server_url(token_server_url: &str) -> Result<()> {
+    let url = Url::parse(token_server_url)?;
+    match url.host_str() {
+        None => Err(ErrorKind::InvalidTokenServerUrl("URL does not have a host").into()),
+        Some("localhost") | Some("127.0.0.1") => Ok(()),
+        Some(_) if url.scheme() != "https" => {
+            Err(ErrorKind::InvalidTokenServerUrl("URL does not use TLS").into())
+        }
+        Some(_) => Ok(()),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -224,6 +270,7 @@ mod tests {
 
         let config = Config {
             content_url: "https://stable.dev.lcip.org/".to_string(),
+            token_server_url_override: None,
             remote_config: RefCell::new(Some(Arc::new(remote_config))),
             client_id: "263ceaa5546dce83".to_string(),
             redirect_uri: "https://127.0.0.1:8080".to_string(),
@@ -253,5 +300,63 @@ mod tests {
             config.token_endpoint().unwrap().to_string(),
             "https://stable.dev.lcip.org/auth/v1/oauth/token"
         );
+
+        assert_eq!(
+            config.introspection_endpoint().unwrap().to_string(),
+            "https://oauth-stable.dev.lcip.org/v1/introspect"
+        );
+    }
+
+    #[test]
+    fn test_token_server_url_override() {
+        let config = Config::with_overrides(
+            "https://stable.dev.lcip.org",
+            "https://sync.example.com/token/1.0/sync/1.5",
+            "263ceaa5546dce83",
+            "https://127.0.0.1:8080",
+        )
+        .unwrap();
+        // This doesn't need to fetch the remote config.
+        assert_eq!(
+            config.token_server_endpoint_url().unwrap().to_string(),
+            "https://sync.example.com/token/1.0/sync/1.5"
+        );
+        // The override is persisted.
+        let config: Config =
+            serde_json::from_str(&serde_json::to_string(&config).unwrap()).unwrap();
+        assert_eq!(
+            config.token_server_endpoint_url().unwrap().to_string(),
+            "https://sync.example.com/token/1.0/sync/1.5"
+        );
+
+        // Local servers don't need TLS.
+        assert!(Config::with_overrides(
+            "https://stable.dev.lcip.org",
+            "http://localhost:5000/token/1.0/sync/1.5",
+            "263ceaa5546dce83",
+            "https://127.0.0.1:8080",
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn test_invalid_token_server_url_override() {
+        for url in &[
+            "sync.example.com/token/1.0/sync/1.5",
+            "http://sync.example.com/token/1.0/sync/1.5",
+            "data:text/plain,hello",
+        ] {
+            assert!(
+                Config::with_overrides(
+                    "https://stable.dev.lcip.org",
+                    url,
+                    "263ceaa5546dce83",
+                    "https://127.0.0.1:8080",
+                )
+                .is_err(),
+                "{} should be rejected",
+                url
+            );
+        }
     }
 }
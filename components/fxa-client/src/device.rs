@@ -3,7 +3,8 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 pub use crate::http_client::{
-    DeviceLocation as Location, DeviceType as Type, GetDeviceResponse as Device, PushSubscription,
+    DeviceLocation as Location, DeviceType as Type, GetAttachedClientResponse as AttachedClient,
+    GetDeviceResponse as Device, PushSubscription,
 };
 use crate::{
     commands::{self, send_tab::SendTabPayload},
@@ -12,10 +13,12 @@ use crate::{
         CommandData, DeviceUpdateRequest, DeviceUpdateRequestBuilder, PendingCommand,
         UpdateDeviceResponse,
     },
+    telemetry::ReceivedReason,
     AccountEvent, FirefoxAccount,
 };
 use serde_derive::*;
 use std::collections::{HashMap, HashSet};
+use sync15::clients;
 
 impl FirefoxAccount {
     /// Fetches the list of devices from the current account including
@@ -32,6 +35,51 @@ impl FirefoxAccount {
             .find(|d| d.is_current_device))
     }
 
+    /// Fetches the list of clients attached to the current account,
+    /// including the current one. Unlike `get_devices`, this also
+    /// includes web sessions and OAuth clients that don't have a device
+    /// record, which is what an account management screen wants to show.
+    ///
+    /// This requires a session token, so it's only available for accounts
+    /// that have been migrated from a session token for now.
+    pub fn get_attached_clients(&self) -> Result<Vec<AttachedClient>> {
+        let session_token = self.get_session_token()?;
+        self.client
+            .attached_clients(&self.state.config, session_token)
+    }
+
+    /// Get the ID of our own device record, as remembered from the last
+    /// time it was created or updated by this account.
+    /// This is also the ID the Sync clients engine should use for our
+    /// client record.
+    pub fn get_current_device_id(&self) -> Result<&str> {
+        match self.state.current_device_id {
+            Some(ref device_id) => Ok(device_id),
+            None => Err(ErrorKind::DeviceUnregistered.into()),
+        }
+    }
+
+    /// Get the settings the Sync clients engine should use to build our own
+    /// client record, using the device ID and name from the last time our
+    /// device record was created or updated by this account.
+    ///
+    /// The clients engine re-uploads our record whenever its name no longer
+    /// matches the one on the server, so passing these settings to the next
+    /// sync is enough for a rename to be reflected in the clients collection.
+    pub fn get_clients_settings(&self, device_type: clients::Type) -> Result<clients::Settings> {
+        match (
+            &self.state.current_device_id,
+            &self.state.current_device_name,
+        ) {
+            (Some(device_id), Some(device_name)) => Ok(clients::Settings {
+                fxa_device_id: device_id.clone(),
+                device_name: device_name.clone(),
+                device_type,
+            }),
+            _ => Err(ErrorKind::DeviceUnregistered.into()),
+        }
+    }
+
     /// Replaces the internal set of "tracked" device capabilities by re-registering
     /// new capabilities and returns a set of device commands to register with the
     /// server.
@@ -117,14 +165,15 @@ impl FirefoxAccount {
     pub fn poll_device_commands(&mut self) -> Result<Vec<AccountEvent>> {
         let last_command_index = self.state.last_handled_command.unwrap_or(0);
         // We increment last_command_index by 1 because the server response includes the current index.
-        self.fetch_and_parse_commands(last_command_index + 1, None)
+        self.fetch_and_parse_commands(last_command_index + 1, None, ReceivedReason::Poll)
     }
 
     /// Retrieve and parse a specific command designated by its index.
     ///
     /// **💾 This method alters the persisted account state.**
     pub fn fetch_device_command(&mut self, index: u64) -> Result<AccountEvent> {
-        let mut account_events = self.fetch_and_parse_commands(index, Some(1))?;
+        let mut account_events =
+            self.fetch_and_parse_commands(index, Some(1), ReceivedReason::Push)?;
         let account_event = account_events
             .pop()
             .ok_or_else(|| ErrorKind::IllegalState("Index fetch came out empty."))?;
@@ -138,6 +187,7 @@ impl FirefoxAccount {
         &mut self,
         index: u64,
         limit: Option<u64>,
+        reason: ReceivedReason,
     ) -> Result<Vec<AccountEvent>> {
         let refresh_token = self.get_refresh_token()?;
         let pending_commands =
@@ -147,19 +197,31 @@ impl FirefoxAccount {
             return Ok(Vec::new());
         }
         log::info!("Handling {} messages", pending_commands.messages.len());
-        let account_events = self.parse_commands_messages(pending_commands.messages)?;
+        let account_events = self.parse_commands_messages(pending_commands.messages, reason)?;
         self.state.last_handled_command = Some(pending_commands.index);
         Ok(account_events)
     }
 
-    fn parse_commands_messages(&self, messages: Vec<PendingCommand>) -> Result<Vec<AccountEvent>> {
+    fn parse_commands_messages(
+        &mut self,
+        messages: Vec<PendingCommand>,
+        reason: ReceivedReason,
+    ) -> Result<Vec<AccountEvent>> {
         let mut account_events: Vec<AccountEvent> = Vec::with_capacity(messages.len());
         let commands: Vec<_> = messages.into_iter().map(|m| m.data).collect();
-        let devices = self.get_devices()?;
+        // Failing to fetch the devices list only means we can't tell who sent
+        // the commands, which is no reason to drop them on the floor.
+        let devices = self.get_devices().unwrap_or_else(|e| {
+            log::warn!("Could not fetch the devices list: {}", e);
+            vec![]
+        });
         for data in commands {
-            match self.parse_command(data, &devices) {
+            match self.parse_command(data, &devices, reason) {
                 Ok((sender, tab)) => account_events.push(AccountEvent::TabReceived((sender, tab))),
-                Err(e) => log::error!("Error while processing command: {}", e),
+                Err(e) => {
+                    log::error!("Error while processing command: {}", e);
+                    self.telemetry.record_command_failed(&e, reason);
+                }
             };
         }
         Ok(account_events)
@@ -168,35 +230,44 @@ impl FirefoxAccount {
     // Returns SendTabPayload for now because we only receive send-tab commands and
     // it's way easier, but should probably return AccountEvent or similar in the future.
     fn parse_command(
-        &self,
+        &mut self,
         command_data: CommandData,
         devices: &[Device],
+        reason: ReceivedReason,
     ) -> Result<(Option<Device>, SendTabPayload)> {
         let sender = command_data
             .sender
             .and_then(|s| devices.iter().find(|i| i.id == s).cloned());
         match command_data.command.as_str() {
             commands::send_tab::COMMAND_NAME => {
-                self.handle_send_tab_command(sender, command_data.payload)
+                self.handle_send_tab_command(sender, command_data.payload, reason)
             }
             _ => Err(ErrorKind::UnknownCommand(command_data.command).into()),
         }
     }
 
-    pub fn set_device_name(&self, name: &str) -> Result<UpdateDeviceResponse> {
+    /// Rename our own device record. The new name is also used for our Sync
+    /// client record, see `get_clients_settings`.
+    ///
+    /// **💾 This method alters the persisted account state.**
+    pub fn set_device_name(&mut self, name: &str) -> Result<UpdateDeviceResponse> {
         let update = DeviceUpdateRequestBuilder::new().display_name(name).build();
         self.update_device(update)
     }
 
-    pub fn clear_device_name(&self) -> Result<UpdateDeviceResponse> {
+    pub fn clear_device_name(&mut self) -> Result<UpdateDeviceResponse> {
         let update = DeviceUpdateRequestBuilder::new()
             .clear_display_name()
             .build();
         self.update_device(update)
     }
 
+    /// Update the push subscription of our own device record.
+    /// This doesn't affect our Sync client record, which doesn't include it.
+    ///
+    /// **💾 This method alters the persisted account state.**
     pub fn set_push_subscription(
-        &self,
+        &mut self,
         push_subscription: &PushSubscription,
     ) -> Result<UpdateDeviceResponse> {
         let update = DeviceUpdateRequestBuilder::new()
@@ -210,7 +281,7 @@ impl FirefoxAccount {
     // endpoint yet.
     #[allow(dead_code)]
     pub(crate) fn register_command(
-        &self,
+        &mut self,
         command: &str,
         value: &str,
     ) -> Result<UpdateDeviceResponse> {
@@ -225,7 +296,7 @@ impl FirefoxAccount {
     // TODO: this currently deletes every command registered for the device
     // because the server does not have a `PATCH commands` endpoint yet.
     #[allow(dead_code)]
-    pub(crate) fn unregister_command(&self, _: &str) -> Result<UpdateDeviceResponse> {
+    pub(crate) fn unregister_command(&mut self, _: &str) -> Result<UpdateDeviceResponse> {
         let commands = HashMap::new();
         let update = DeviceUpdateRequestBuilder::new()
             .available_commands(&commands)
@@ -234,7 +305,7 @@ impl FirefoxAccount {
     }
 
     #[allow(dead_code)]
-    pub(crate) fn clear_commands(&self) -> Result<UpdateDeviceResponse> {
+    pub(crate) fn clear_commands(&mut self) -> Result<UpdateDeviceResponse> {
         let update = DeviceUpdateRequestBuilder::new()
             .clear_available_commands()
             .build();
@@ -242,7 +313,7 @@ impl FirefoxAccount {
     }
 
     pub(crate) fn replace_device(
-        &self,
+        &mut self,
         display_name: &str,
         device_type: &Type,
         push_subscription: &Option<PushSubscription>,
@@ -258,10 +329,14 @@ impl FirefoxAccount {
         self.update_device(builder.build())
     }
 
-    fn update_device(&self, update: DeviceUpdateRequest<'_>) -> Result<UpdateDeviceResponse> {
+    fn update_device(&mut self, update: DeviceUpdateRequest<'_>) -> Result<UpdateDeviceResponse> {
         let refresh_token = self.get_refresh_token()?;
-        self.client
-            .update_device(&self.state.config, refresh_token, update)
+        let res = self
+            .client
+            .update_device(&self.state.config, refresh_token, update)?;
+        self.state.current_device_id = Some(res.id.clone());
+        self.state.current_device_name = Some(res.display_name.clone());
+        Ok(res)
     }
 }
 
@@ -269,3 +344,305 @@ impl FirefoxAccount {
 pub enum Capability {
     SendTab,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        commands::send_tab, http_client::*, oauth::RefreshToken, scoped_keys::ScopedKey, scopes,
+        Config,
+    };
+    use std::sync::Arc;
+
+    fn device(id: &str, is_current_device: bool) -> Device {
+        Device {
+            common: DeviceResponseCommon {
+                id: id.to_owned(),
+                display_name: format!("Device {}", id),
+                device_type: Type::Desktop,
+                push_subscription: None,
+                available_commands: HashMap::default(),
+                push_endpoint_expired: false,
+            },
+            is_current_device,
+            location: Location {
+                city: None,
+                country: None,
+                state: None,
+                state_code: None,
+            },
+            last_access_time: None,
+        }
+    }
+
+    fn setup() -> FirefoxAccount {
+        let mut fxa =
+            FirefoxAccount::with_config(Config::stable_dev("12345678", "https://foo.bar"));
+        fxa.state.refresh_token = Some(RefreshToken {
+            token: "refreshtok".to_owned(),
+            scopes: HashSet::default(),
+        });
+        fxa.state.scoped_keys.insert(
+            scopes::OLD_SYNC.to_owned(),
+            ScopedKey {
+                kty: "oct".to_owned(),
+                scope: scopes::OLD_SYNC.to_owned(),
+                k: base64::encode_config(&[0x42u8; 64][..], base64::URL_SAFE_NO_PAD),
+                kid: format!(
+                    "1542236016429-{}",
+                    base64::encode_config(&[0x13u8; 16], base64::URL_SAFE_NO_PAD)
+                ),
+            },
+        );
+        fxa
+    }
+
+    // Encrypts a tab the way another device would when sending it to us.
+    fn send_tab_command_to(fxa: &mut FirefoxAccount, sender: &str) -> PendingCommand {
+        let command_data = fxa.generate_send_tab_command_data().unwrap();
+        let mut target = device("us", true);
+        target
+            .common
+            .available_commands
+            .insert(send_tab::COMMAND_NAME.to_owned(), command_data);
+        let mut payload = SendTabPayload::single_tab("Mozilla", "https://mozilla.org");
+        payload.flow_id = "flow".to_owned();
+        payload.stream_id = "stream".to_owned();
+        let oldsync_key = fxa.get_scoped_key(scopes::OLD_SYNC).unwrap();
+        PendingCommand {
+            index: 3,
+            data: CommandData {
+                command: send_tab::COMMAND_NAME.to_owned(),
+                payload: send_tab::build_send_command(oldsync_key, &target, &payload).unwrap(),
+                sender: Some(sender.to_owned()),
+            },
+        }
+    }
+
+    #[test]
+    fn test_poll_device_commands() {
+        let mut fxa = setup();
+        let command = send_tab_command_to(&mut fxa, "sender");
+
+        let mut client = FxAClientMock::new();
+        client
+            .expect_pending_commands(
+                mockiato::Argument::any,
+                |token| token.partial_eq("refreshtok"),
+                |index| index.partial_eq(1),
+                |limit| limit.partial_eq(None),
+            )
+            .times(1)
+            .returns_once(Ok(PendingCommandsResponse {
+                index: 3,
+                last: Some(true),
+                messages: vec![command],
+            }));
+        client
+            .expect_devices(mockiato::Argument::any, |token| {
+                token.partial_eq("refreshtok")
+            })
+            .times(1)
+            .returns_once(Ok(vec![device("us", true), device("sender", false)]));
+        fxa.set_client(Arc::new(client));
+
+        let events = fxa.poll_device_commands().unwrap();
+        assert_eq!(events.len(), 1);
+        match events[0] {
+            AccountEvent::TabReceived((Some(ref sender), ref payload)) => {
+                assert_eq!(sender.id, "sender");
+                assert_eq!(payload.entries.len(), 1);
+                assert_eq!(payload.entries[0].url, "https://mozilla.org");
+                assert_eq!(payload.flow_id, "flow");
+            }
+            _ => panic!("Expected a tab with a known sender"),
+        }
+        let telemetry: serde_json::Value =
+            serde_json::from_str(&fxa.gather_telemetry().unwrap()).unwrap();
+        assert_eq!(
+            telemetry["commands_received"],
+            serde_json::json!([{
+                "flow_id": "flow",
+                "stream_id": "stream",
+                "reason": "poll",
+            }])
+        );
+        assert_eq!(fxa.state.last_handled_command, Some(3));
+    }
+
+    #[test]
+    fn test_poll_device_commands_unknown_sender() {
+        let mut fxa = setup();
+        let command = send_tab_command_to(&mut fxa, "sender");
+
+        let mut client = FxAClientMock::new();
+        client
+            .expect_pending_commands(
+                mockiato::Argument::any,
+                mockiato::Argument::any,
+                mockiato::Argument::any,
+                mockiato::Argument::any,
+            )
+            .times(1)
+            .returns_once(Ok(PendingCommandsResponse {
+                index: 3,
+                last: Some(true),
+                messages: vec![command],
+            }));
+        client
+            .expect_devices(mockiato::Argument::any, mockiato::Argument::any)
+            .times(1)
+            .returns_once(Err(ErrorKind::RemoteError {
+                code: 500,
+                errno: 101,
+                error: "Did not work!".to_owned(),
+                message: "Did not work!".to_owned(),
+                info: "Did not work!".to_owned(),
+            }
+            .into()));
+        fxa.set_client(Arc::new(client));
+
+        let events = fxa.poll_device_commands().unwrap();
+        assert_eq!(events.len(), 1);
+        match events[0] {
+            AccountEvent::TabReceived((None, ref payload)) => {
+                assert_eq!(payload.entries[0].title, "Mozilla");
+            }
+            _ => panic!("Expected a tab without a sender"),
+        }
+        assert_eq!(fxa.state.last_handled_command, Some(3));
+    }
+
+    #[test]
+    fn test_initialize_device_remembers_device_id() {
+        let mut fxa = setup();
+        assert!(fxa.get_current_device_id().is_err());
+
+        let mut client = FxAClientMock::new();
+        client
+            .expect_update_device(
+                mockiato::Argument::any,
+                |token| token.partial_eq("refreshtok"),
+                mockiato::Argument::any,
+            )
+            .times(1)
+            .returns_once(Ok(device("us", true).common));
+        fxa.set_client(Arc::new(client));
+
+        fxa.initialize_device("My Device", Type::Mobile, &[Capability::SendTab])
+            .unwrap();
+        assert_eq!(fxa.get_current_device_id().unwrap(), "us");
+        assert!(fxa.state.commands_data.contains_key(send_tab::COMMAND_NAME));
+
+        // The device ID survives a round-trip through the persisted state...
+        let mut fxa = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        assert_eq!(fxa.get_current_device_id().unwrap(), "us");
+
+        // ...but not starting over.
+        fxa.start_over();
+        assert!(fxa.get_current_device_id().is_err());
+    }
+
+    #[test]
+    fn test_set_device_name_updates_clients_settings() {
+        let mut fxa = setup();
+        assert!(fxa.get_clients_settings(clients::Type::Mobile).is_err());
+
+        let mut client = FxAClientMock::new();
+        client
+            .expect_update_device(
+                mockiato::Argument::any,
+                |token| token.partial_eq("refreshtok"),
+                mockiato::Argument::any,
+            )
+            .times(1)
+            .returns_once(Ok(device("us", true).common));
+        fxa.set_client(Arc::new(client));
+        fxa.initialize_device("Device us", Type::Mobile, &[Capability::SendTab])
+            .unwrap();
+        assert_eq!(
+            fxa.get_clients_settings(clients::Type::Mobile).unwrap(),
+            clients::Settings {
+                fxa_device_id: "us".to_owned(),
+                device_name: "Device us".to_owned(),
+                device_type: clients::Type::Mobile,
+            }
+        );
+
+        let mut renamed = device("us", true).common;
+        renamed.display_name = "My phone".to_owned();
+        let mut client = FxAClientMock::new();
+        client
+            .expect_update_device(
+                mockiato::Argument::any,
+                |token| token.partial_eq("refreshtok"),
+                mockiato::Argument::any,
+            )
+            .times(1)
+            .returns_once(Ok(renamed));
+        fxa.set_client(Arc::new(client));
+        fxa.set_device_name("My phone").unwrap();
+
+        // The new name survives a round-trip through the persisted state.
+        let mut fxa = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        let settings = fxa.get_clients_settings(clients::Type::Mobile).unwrap();
+        assert_eq!(settings.fxa_device_id, "us");
+        assert_eq!(settings.device_name, "My phone");
+
+        fxa.start_over();
+        assert!(fxa.get_clients_settings(clients::Type::Mobile).is_err());
+    }
+
+    #[test]
+    fn test_get_attached_clients() {
+        let mut fxa = setup();
+        match fxa.get_attached_clients() {
+            Err(e) => match e.kind() {
+                ErrorKind::NoSessionToken => {}
+                e => panic!("Unexpected error {}", e),
+            },
+            Ok(_) => panic!("Should not fetch attached clients without a session token"),
+        }
+
+        fxa.state.session_token = Some("sessiontok".to_owned());
+        let mut client = FxAClientMock::new();
+        client
+            .expect_attached_clients(mockiato::Argument::any, |token| {
+                token.partial_eq("sessiontok")
+            })
+            .times(1)
+            .returns_once(Ok(vec![AttachedClient {
+                client_id: Some("12345678".to_owned()),
+                session_token_id: None,
+                refresh_token_id: Some("refreshtokid".to_owned()),
+                device_id: Some("us".to_owned()),
+                device_type: Some(Type::Mobile),
+                is_current_session: true,
+                name: Some("My Device".to_owned()),
+                created_time: Some(1_574_106_708_000),
+                last_access_time: Some(1_574_106_740_000),
+                scope: Some(vec!["profile".to_owned()]),
+                user_agent: "Firefox".to_owned(),
+                os: Some("Android".to_owned()),
+            }]));
+        fxa.set_client(Arc::new(client));
+
+        let clients = fxa.get_attached_clients().unwrap();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].device_id, Some("us".to_owned()));
+        assert!(clients[0].is_current_session);
+    }
+
+    #[test]
+    fn test_handle_send_tab_command_without_keys() {
+        let mut fxa = setup();
+        let payload = serde_json::json!({ "encrypted": "" });
+        match fxa.handle_send_tab_command(None, payload, ReceivedReason::Poll) {
+            Err(e) => match e.kind() {
+                ErrorKind::IllegalState(_) => {}
+                e => panic!("Unexpected error {}", e),
+            },
+            Ok(_) => panic!("Should not decrypt without send-tab keys"),
+        }
+    }
+}
@@ -29,6 +29,12 @@ pub enum ErrorKind {
     #[fail(display = "No stored refresh token")]
     NoRefreshToken,
 
+    #[fail(display = "No stored session token")]
+    NoSessionToken,
+
+    #[fail(display = "No migration to retry")]
+    NoMigrationData,
+
     #[fail(display = "Could not find a refresh token in the server response")]
     RefreshTokenNotPresent,
 
@@ -68,6 +74,12 @@ pub enum ErrorKind {
     #[fail(display = "Origin mismatch")]
     OriginMismatch,
 
+    #[fail(display = "Invalid pairing URL: {}", _0)]
+    InvalidPairingUrl(&'static str),
+
+    #[fail(display = "Invalid token server URL: {}", _0)]
+    InvalidTokenServerUrl(&'static str),
+
     #[fail(display = "JWT signature validation failed")]
     JWTSignatureValidationFailed,
 
@@ -171,6 +171,36 @@ impl From<AccountEvent> for msg_types::AccountEvent {
                     },
                 )),
             },
+            AccountEvent::ProfileUpdated => Self {
+                r#type: msg_types::account_event::AccountEventType::ProfileUpdated as i32,
+                data: None,
+            },
+            AccountEvent::DeviceConnected { device_name } => Self {
+                r#type: msg_types::account_event::AccountEventType::DeviceConnected as i32,
+                data: Some(msg_types::account_event::Data::DeviceConnectedData(
+                    msg_types::account_event::DeviceConnectedData { device_name },
+                )),
+            },
+            AccountEvent::DeviceDisconnected {
+                device_id,
+                is_local_device,
+            } => Self {
+                r#type: msg_types::account_event::AccountEventType::DeviceDisconnected as i32,
+                data: Some(msg_types::account_event::Data::DeviceDisconnectedData(
+                    msg_types::account_event::DeviceDisconnectedData {
+                        device_id,
+                        is_local_device,
+                    },
+                )),
+            },
+            AccountEvent::PasswordChanged => Self {
+                r#type: msg_types::account_event::AccountEventType::PasswordChanged as i32,
+                data: None,
+            },
+            AccountEvent::AccountDestroyed => Self {
+                r#type: msg_types::account_event::AccountEventType::AccountDestroyed as i32,
+                data: None,
+            },
         }
     }
 }
@@ -2,10 +2,12 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+#[cfg(feature = "integration_test")]
+use crate::util::Xorable;
 use crate::{config::Config, error::*};
 use hex;
 use rc_crypto::hawk::{Credentials, Key, PayloadHasher, RequestBuilder, SHA256};
-use rc_crypto::{digest, hkdf, hmac};
+use rc_crypto::{digest, hkdf, hmac, pbkdf2};
 use serde_derive::*;
 use serde_json::json;
 use std::collections::HashMap;
@@ -14,6 +16,7 @@ use viaduct::{header_names, status_codes, Method, Request, Response};
 
 const HAWK_HKDF_SALT: [u8; 32] = [0b0; 32];
 const HAWK_KEY_LENGTH: usize = 32;
+const QUICK_STRETCH_ITERATIONS: u32 = 1000;
 
 #[cfg_attr(test, mockiato::mockable)]
 pub trait FxAClient {
@@ -41,12 +44,18 @@ pub trait FxAClient {
         session_token: &str,
         scopes: &[&str],
     ) -> Result<OAuthTokenResponse>;
+    fn check_refresh_token_status(
+        &self,
+        config: &Config,
+        refresh_token: &str,
+    ) -> Result<IntrospectResponse>;
     fn duplicate_session(
         &self,
         config: &Config,
         session_token: &str,
     ) -> Result<DuplicateTokenResponse>;
     fn destroy_access_token(&self, config: &Config, token: &str) -> Result<()>;
+    fn destroy_account(&self, config: &Config, email: &str, auth_pw: &str) -> Result<()>;
     fn destroy_refresh_token(&self, config: &Config, token: &str) -> Result<()>;
     fn profile(
         &self,
@@ -77,6 +86,11 @@ pub trait FxAClient {
         update: DeviceUpdateRequest<'_>,
     ) -> Result<UpdateDeviceResponse>;
     fn destroy_device(&self, config: &Config, refresh_token: &str, id: &str) -> Result<()>;
+    fn attached_clients(
+        &self,
+        config: &Config,
+        session_token: &str,
+    ) -> Result<Vec<GetAttachedClientResponse>>;
     fn scoped_key_data(
         &self,
         config: &Config,
@@ -185,6 +199,19 @@ impl FxAClient for Client {
         Self::make_request(request)?.json().map_err(Into::into)
     }
 
+    fn check_refresh_token_status(
+        &self,
+        config: &Config,
+        refresh_token: &str,
+    ) -> Result<IntrospectResponse> {
+        let body = json!({
+            "token_type_hint": "refresh_token",
+            "token": refresh_token,
+        });
+        let url = config.introspection_endpoint()?;
+        Ok(Self::make_request(Request::post(url).json(&body))?.json()?)
+    }
+
     fn duplicate_session(
         &self,
         config: &Config,
@@ -216,6 +243,16 @@ impl FxAClient for Client {
         self.destroy_token_helper(config, &body)
     }
 
+    fn destroy_account(&self, config: &Config, email: &str, auth_pw: &str) -> Result<()> {
+        let body = json!({
+            "email": email,
+            "authPW": auth_pw,
+        });
+        let url = config.auth_url_path("v1/account/destroy")?;
+        Self::make_request(Request::post(url).json(&body))?;
+        Ok(())
+    }
+
     fn pending_commands(
         &self,
         config: &Config,
@@ -290,6 +327,17 @@ impl FxAClient for Client {
         Ok(())
     }
 
+    fn attached_clients(
+        &self,
+        config: &Config,
+        session_token: &str,
+    ) -> Result<Vec<GetAttachedClientResponse>> {
+        let url = config.auth_url_path("v1/account/attached_clients")?;
+        let key = derive_auth_key_from_session_token(session_token)?;
+        let request = HawkRequestBuilder::new(Method::Get, url, &key).build()?;
+        Ok(Self::make_request(request)?.json()?)
+    }
+
     fn scoped_key_data(
         &self,
         config: &Config,
@@ -329,6 +377,31 @@ impl Client {
         Ok(Self::make_request(Request::post(url).json(&body))?.json()?)
     }
 
+    /// Sign in with an email and `authPW` (see `derive_auth_pw`), getting a
+    /// session token and a key fetch token back.
+    #[cfg(feature = "integration_test")]
+    pub fn login(&self, config: &Config, email: &str, auth_pw: &str) -> Result<LoginResponse> {
+        let body = json!({
+            "email": email,
+            "authPW": auth_pw,
+        });
+        let mut url = config.auth_url_path("v1/account/login")?;
+        url.query_pairs_mut().append_pair("keys", "true");
+        Ok(Self::make_request(Request::post(url).json(&body))?.json()?)
+    }
+
+    /// Fetch the encrypted keys bundle of an account using a key fetch
+    /// token. The token can only be used once.
+    #[cfg(feature = "integration_test")]
+    pub fn account_keys(&self, config: &Config, key_fetch_token: &str) -> Result<String> {
+        let url = config.auth_url_path("v1/account/keys")?;
+        let keys = derive_key_fetch_token_keys(key_fetch_token)?;
+        let request =
+            HawkRequestBuilder::new(Method::Get, url, &keys[..HAWK_KEY_LENGTH * 2]).build()?;
+        let response: AccountKeysResponse = Self::make_request(request)?.json()?;
+        Ok(response.bundle)
+    }
+
     fn make_request(request: Request) -> Result<Response> {
         let resp = request.send()?;
         if resp.is_success() || resp.status == status_codes::NOT_MODIFIED {
@@ -360,6 +433,12 @@ fn kw(name: &str) -> Vec<u8> {
         .to_vec()
 }
 
+fn kwe(name: &str, email: &str) -> Vec<u8> {
+    format!("identity.mozilla.com/picl/v1/{}:{}", name, email)
+        .as_bytes()
+        .to_vec()
+}
+
 pub fn derive_auth_key_from_session_token(session_token: &str) -> Result<Vec<u8>> {
     let session_token_bytes = hex::decode(session_token)?;
     let context_info = kw("sessionToken");
@@ -369,6 +448,92 @@ pub fn derive_auth_key_from_session_token(session_token: &str) -> Result<Vec<u8>
     Ok(out)
 }
 
+fn quick_stretch_password(email: &str, password: &str) -> Result<Vec<u8>> {
+    let mut quick_stretched_pw = vec![0u8; 32];
+    pbkdf2::derive(
+        &digest::SHA256,
+        QUICK_STRETCH_ITERATIONS,
+        &kwe("quickStretch", email),
+        password.as_bytes(),
+        &mut quick_stretched_pw,
+    )?;
+    Ok(quick_stretched_pw)
+}
+
+/// Derive the hex-formatted `authPW` the auth server expects instead of the
+/// password itself (see the "onepw" protocol).
+pub fn derive_auth_pw(email: &str, password: &str) -> Result<String> {
+    let quick_stretched_pw = quick_stretch_password(email, password)?;
+    let salt = hmac::SigningKey::new(&digest::SHA256, &HAWK_HKDF_SALT);
+    let mut auth_pw = vec![0u8; 32];
+    hkdf::extract_and_expand(&salt, &quick_stretched_pw, &kw("authPW"), &mut auth_pw)?;
+    Ok(hex::encode(auth_pw))
+}
+
+// Derives the HAWK credentials (the first `HAWK_KEY_LENGTH * 2` bytes) and the
+// key used to decrypt the `/account/keys` response (the rest) from a key fetch
+// token.
+#[cfg(feature = "integration_test")]
+fn derive_key_fetch_token_keys(key_fetch_token: &str) -> Result<Vec<u8>> {
+    let key_fetch_token_bytes = hex::decode(key_fetch_token)?;
+    let salt = hmac::SigningKey::new(&digest::SHA256, &HAWK_HKDF_SALT);
+    let mut out = vec![0u8; HAWK_KEY_LENGTH * 3];
+    hkdf::extract_and_expand(
+        &salt,
+        &key_fetch_token_bytes,
+        &kw("keyFetchToken"),
+        &mut out,
+    )?;
+    Ok(out)
+}
+
+// Decrypts and authenticates the `/account/keys` bundle, returning `wrap(kB)`.
+#[cfg(feature = "integration_test")]
+fn unbundle_wrap_kb(key_request_key: &[u8], bundle: &str) -> Result<Vec<u8>> {
+    let bundle = hex::decode(bundle)?;
+    if bundle.len() != 96 {
+        return Err(ErrorKind::IllegalState("Invalid account keys bundle length.").into());
+    }
+    let (ciphertext, mac) = bundle.split_at(64);
+    let salt = hmac::SigningKey::new(&digest::SHA256, &HAWK_HKDF_SALT);
+    let mut keys = vec![0u8; 32 + 64];
+    hkdf::extract_and_expand(&salt, key_request_key, &kw("account/keys"), &mut keys)?;
+    let (resp_hmac_key, resp_xor_key) = keys.split_at(32);
+    let resp_hmac_key = hmac::SigningKey::new(&digest::SHA256, resp_hmac_key);
+    hmac::verify_with_own_key(&resp_hmac_key, ciphertext, mac)?;
+    // The plaintext is kA followed by wrap(kB).
+    let plaintext = ciphertext.xored_with(resp_xor_key)?;
+    Ok(plaintext[32..].to_vec())
+}
+
+/// Derive the hex-formatted kSync and kXCS of an account, as expected by
+/// `FirefoxAccount::migrate_from_session_token`, from its password and the
+/// keys bundle returned by `Client::account_keys` for `key_fetch_token`.
+#[cfg(feature = "integration_test")]
+pub fn derive_sync_keys(
+    email: &str,
+    password: &str,
+    key_fetch_token: &str,
+    bundle: &str,
+) -> Result<(String, String)> {
+    let key_fetch_token_keys = derive_key_fetch_token_keys(key_fetch_token)?;
+    let wrap_kb = unbundle_wrap_kb(&key_fetch_token_keys[HAWK_KEY_LENGTH * 2..], bundle)?;
+    let quick_stretched_pw = quick_stretch_password(email, password)?;
+    let salt = hmac::SigningKey::new(&digest::SHA256, &HAWK_HKDF_SALT);
+    let mut unwrap_kb = vec![0u8; 32];
+    hkdf::extract_and_expand(
+        &salt,
+        &quick_stretched_pw,
+        &kw("unwrapBkey"),
+        &mut unwrap_kb,
+    )?;
+    let kb = wrap_kb.xored_with(&unwrap_kb)?;
+    let mut k_sync = vec![0u8; 64];
+    hkdf::extract_and_expand(&salt, &kb, &kw("oldsync"), &mut k_sync)?;
+    let k_xcs = digest::digest(&digest::SHA256, &kb)?;
+    Ok((hex::encode(k_sync), hex::encode(&k_xcs.as_ref()[..16])))
+}
+
 struct HawkRequestBuilder<'a> {
     url: Url,
     method: Method,
@@ -606,6 +771,36 @@ pub struct DeviceResponseCommon {
     pub push_endpoint_expired: bool,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+pub struct GetAttachedClientResponse {
+    #[serde(rename = "clientId")]
+    pub client_id: Option<String>,
+    #[serde(rename = "sessionTokenId")]
+    pub session_token_id: Option<String>,
+    #[serde(rename = "refreshTokenId")]
+    pub refresh_token_id: Option<String>,
+    #[serde(rename = "deviceId")]
+    pub device_id: Option<String>,
+    #[serde(rename = "deviceType")]
+    pub device_type: Option<DeviceType>,
+    #[serde(rename = "isCurrentSession")]
+    pub is_current_session: bool,
+    pub name: Option<String>,
+    #[serde(rename = "createdTime")]
+    pub created_time: Option<u64>,
+    #[serde(rename = "lastAccessTime")]
+    pub last_access_time: Option<u64>,
+    pub scope: Option<Vec<String>>,
+    #[serde(rename = "userAgent")]
+    pub user_agent: String,
+    pub os: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct IntrospectResponse {
+    pub active: bool,
+}
+
 #[derive(Deserialize)]
 pub struct OAuthTokenResponse {
     pub keys_jwe: Option<String>,
@@ -649,3 +844,88 @@ pub struct DuplicateTokenResponse {
     #[serde(rename = "authAt")]
     pub auth_at: u64,
 }
+
+#[cfg(feature = "integration_test")]
+#[derive(Deserialize)]
+pub struct LoginResponse {
+    pub uid: String,
+    #[serde(rename = "sessionToken")]
+    pub session_token: String,
+    #[serde(rename = "keyFetchToken")]
+    pub key_fetch_token: Option<String>,
+    pub verified: bool,
+}
+
+#[cfg(feature = "integration_test")]
+#[derive(Deserialize)]
+struct AccountKeysResponse {
+    bundle: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Test vectors from the FxA "onepw" protocol documentation.
+    #[test]
+    fn test_derive_auth_pw() {
+        assert_eq!(
+            derive_auth_pw("andr\u{e9}@example.org", "p\u{e4}ssw\u{f6}rd").unwrap(),
+            "247b675ffb4c46310bc87e26d712153abe5e1c90ef00a4784594f97ef54f2375"
+        );
+    }
+
+    #[cfg(feature = "integration_test")]
+    fn make_keys_bundle(key_fetch_token: &str, wrap_kb: &[u8]) -> String {
+        let keys = derive_key_fetch_token_keys(key_fetch_token).unwrap();
+        let salt = hmac::SigningKey::new(&digest::SHA256, &HAWK_HKDF_SALT);
+        let mut resp_keys = vec![0u8; 32 + 64];
+        hkdf::extract_and_expand(
+            &salt,
+            &keys[HAWK_KEY_LENGTH * 2..],
+            &kw("account/keys"),
+            &mut resp_keys,
+        )
+        .unwrap();
+        let plaintext = [&[0x11u8; 32][..], wrap_kb].concat();
+        let ciphertext = plaintext.xored_with(&resp_keys[32..]).unwrap();
+        let resp_hmac_key = hmac::SigningKey::new(&digest::SHA256, &resp_keys[..32]);
+        let mac = hmac::sign(&resp_hmac_key, &ciphertext).unwrap();
+        hex::encode([&ciphertext[..], mac.as_ref()].concat())
+    }
+
+    #[cfg(feature = "integration_test")]
+    #[test]
+    fn test_unbundle_wrap_kb() {
+        let key_fetch_token = hex::encode(&[0x42u8; 32]);
+        let bundle = make_keys_bundle(&key_fetch_token, &[0x22u8; 32]);
+        let keys = derive_key_fetch_token_keys(&key_fetch_token).unwrap();
+        assert_eq!(
+            unbundle_wrap_kb(&keys[HAWK_KEY_LENGTH * 2..], &bundle).unwrap(),
+            vec![0x22u8; 32]
+        );
+
+        // A tampered bundle is rejected.
+        let mut tampered = hex::decode(&bundle).unwrap();
+        tampered[0] ^= 1;
+        assert!(unbundle_wrap_kb(&keys[HAWK_KEY_LENGTH * 2..], &hex::encode(tampered)).is_err());
+        // So is a bundle decrypted with the wrong key fetch token.
+        let other_keys = derive_key_fetch_token_keys(&hex::encode(&[0x43u8; 32])).unwrap();
+        assert!(unbundle_wrap_kb(&other_keys[HAWK_KEY_LENGTH * 2..], &bundle).is_err());
+    }
+
+    #[cfg(feature = "integration_test")]
+    #[test]
+    fn test_derive_sync_keys() {
+        let key_fetch_token = hex::encode(&[0x42u8; 32]);
+        let bundle = make_keys_bundle(&key_fetch_token, &[0x22u8; 32]);
+        let (k_sync, k_xcs) =
+            derive_sync_keys("foo@example.com", "password", &key_fetch_token, &bundle).unwrap();
+        assert_eq!(hex::decode(&k_sync).unwrap().len(), 64);
+        assert_eq!(hex::decode(&k_xcs).unwrap().len(), 16);
+        // Our password is part of the derivation.
+        let (other_k_sync, _) =
+            derive_sync_keys("foo@example.com", "hunter2", &key_fetch_token, &bundle).unwrap();
+        assert_ne!(k_sync, other_k_sync);
+    }
+}
@@ -8,10 +8,17 @@
 use crate::{
     commands::send_tab::SendTabPayload,
     device::{Capability as DeviceCapability, Device},
+    migrator::MigrationData,
     oauth::{OAuthFlow, RefreshToken},
+    telemetry::FxaTelemetry,
+};
+pub use crate::{
+    config::Config,
+    error::*,
+    oauth::{AccessTokenInfo, AuthorizationInfo},
+    profile::Profile,
     scoped_keys::ScopedKey,
 };
-pub use crate::{config::Config, error::*, oauth::AccessTokenInfo, profile::Profile};
 use serde_derive::*;
 use std::{
     collections::{HashMap, HashSet},
@@ -31,11 +38,14 @@ pub mod msg_types {
 }
 mod http_client;
 mod oauth;
+#[cfg(feature = "integration_test")]
+mod password_auth;
 mod profile;
 mod scoped_keys;
 pub mod scopes;
 pub mod send_tab;
 mod state_persistence;
+mod telemetry;
 mod util;
 
 type FxAClient = dyn http_client::FxAClient + Sync + Send;
@@ -51,9 +61,10 @@ unsafe impl<'a> Sync for http_client::FxAClientMock<'a> {}
 // to be modified.
 pub struct FirefoxAccount {
     client: Arc<FxAClient>,
-    state: StateV2,
+    state: StateV3,
     access_token_cache: HashMap<String, AccessTokenInfo>,
     flow_store: HashMap<String, OAuthFlow>,
+    telemetry: FxaTelemetry,
 }
 
 // If this structure is modified, please:
@@ -62,26 +73,32 @@ pub struct FirefoxAccount {
 // (see `state_persistence.rs`).
 // 2. Check if the `StateVX.start_over` function
 // also needs to to be modified.
+// Adding a field is a schema change too: rather than reaching for
+// `#[serde(default)]`, add a new state version and a migration from this
+// one, so that it's explicit what existing accounts start out with.
+// `Config` is the exception: it's shared by every state version, so optional
+// settings added to it, like `token_server_url_override`, use
+// `#[serde(default)]` and start out unset.
 #[derive(Clone, Serialize, Deserialize)]
-pub(crate) struct StateV2 {
+pub(crate) struct StateV3 {
     config: Config,
     refresh_token: Option<RefreshToken>,
     scoped_keys: HashMap<String, ScopedKey>,
     last_handled_command: Option<u64>,
-    // Remove serde(default) once we are V3.
-    #[serde(default)]
     commands_data: HashMap<String, String>,
-    #[serde(default)] // Same
     device_capabilities: HashSet<DeviceCapability>,
     session_token: Option<String>, // Hex-formatted string.
     last_seen_profile: Option<CachedResponse<Profile>>,
+    current_device_id: Option<String>,
+    current_device_name: Option<String>,
+    in_flight_migration: Option<MigrationData>,
 }
 
-impl StateV2 {
+impl StateV3 {
     /// Clear the whole persisted state of the account, but keep just enough
     /// information to eventually reconnect to the same user account later.
-    fn start_over(&self) -> StateV2 {
-        StateV2 {
+    fn start_over(&self) -> StateV3 {
+        StateV3 {
             config: self.config.clone(),
             // Leave the profile cache untouched so we can reconnect later.
             last_seen_profile: self.last_seen_profile.clone(),
@@ -91,17 +108,21 @@ impl StateV2 {
             commands_data: HashMap::new(),
             device_capabilities: HashSet::new(),
             session_token: None,
+            current_device_id: None,
+            current_device_name: None,
+            in_flight_migration: None,
         }
     }
 }
 
 impl FirefoxAccount {
-    fn from_state(state: StateV2) -> Self {
+    fn from_state(state: StateV3) -> Self {
         Self {
             client: Arc::new(http_client::Client::new()),
             state,
             access_token_cache: HashMap::new(),
             flow_store: HashMap::new(),
+            telemetry: FxaTelemetry::new(),
         }
     }
 
@@ -109,7 +130,7 @@ impl FirefoxAccount {
     ///
     /// **💾 This method alters the persisted account state.**
     pub fn with_config(config: Config) -> Self {
-        Self::from_state(StateV2 {
+        Self::from_state(StateV3 {
             config,
             refresh_token: None,
             scoped_keys: HashMap::new(),
@@ -118,6 +139,9 @@ impl FirefoxAccount {
             device_capabilities: HashSet::new(),
             session_token: None,
             last_seen_profile: None,
+            current_device_id: None,
+            current_device_name: None,
+            in_flight_migration: None,
         })
     }
 
@@ -179,6 +203,13 @@ impl FirefoxAccount {
         Ok(url)
     }
 
+    /// Get the pairing authority page URL.
+    /// A signed-in device navigates there to display the QR code
+    /// that a new device scans to start `begin_pairing_flow`.
+    pub fn get_pairing_authority_url(&self) -> Result<Url> {
+        self.state.config.content_url_path("pair")
+    }
+
     /// Get the "manage account" page URL.
     /// It is typically used in the application's account status UI,
     /// to link the user out to a webpage where they can manage
@@ -187,9 +218,8 @@ impl FirefoxAccount {
     /// * `entrypoint` - Application-provided string identifying the UI touchpoint
     ///                  through which the page was accessed, for metrics purposes.
     pub fn get_manage_account_url(&mut self, entrypoint: &str) -> Result<Url> {
-        let mut url = self.state.config.content_url_path("settings")?;
-        url.query_pairs_mut().append_pair("entrypoint", entrypoint);
-        self.add_account_identifiers_to_url(url)
+        let url = self.state.config.content_url_path("settings")?;
+        self.add_metrics_params_to_url(url, entrypoint)
     }
 
     /// Get the "manage devices" page URL.
@@ -200,8 +230,32 @@ impl FirefoxAccount {
     /// * `entrypoint` - Application-provided string identifying the UI touchpoint
     ///                  through which the page was accessed, for metrics purposes.
     pub fn get_manage_devices_url(&mut self, entrypoint: &str) -> Result<Url> {
-        let mut url = self.state.config.content_url_path("settings/clients")?;
+        let url = self.state.config.content_url_path("settings/clients")?;
+        self.add_metrics_params_to_url(url, entrypoint)
+    }
+
+    /// Get the "connect another device" page URL.
+    /// It is typically used in the application's account status UI or
+    /// devices list, to guide the user through signing in on another device.
+    ///
+    /// * `entrypoint` - Application-provided string identifying the UI touchpoint
+    ///                  through which the page was accessed, for metrics purposes.
+    pub fn get_connect_another_device_url(&mut self, entrypoint: &str) -> Result<Url> {
+        let url = self
+            .state
+            .config
+            .content_url_path("connect_another_device")?;
+        self.add_metrics_params_to_url(url, entrypoint)
+    }
+
+    // All the account pages we link to use the same parameters, so that
+    // the flows they start can be attributed to an app, device and UI
+    // touchpoint consistently across platforms.
+    fn add_metrics_params_to_url(&mut self, mut url: Url, entrypoint: &str) -> Result<Url> {
         url.query_pairs_mut().append_pair("entrypoint", entrypoint);
+        if let Some(ref device_id) = self.state.current_device_id {
+            url.query_pairs_mut().append_pair("device_id", device_id);
+        }
         self.add_account_identifiers_to_url(url)
     }
 
@@ -219,6 +273,10 @@ impl FirefoxAccount {
     /// Due to iOS platform restrictions, a push notification must always show UI,
     /// and therefore we only retrieve 1 command per message.
     ///
+    /// If the message says our own device was disconnected, or the account
+    /// destroyed, the account state is cleared (like `start_over`) before the
+    /// corresponding event is returned.
+    ///
     /// **💾 This method alters the persisted account state.**
     pub fn handle_push_message(&mut self, payload: &str) -> Result<Vec<AccountEvent>> {
         let payload = serde_json::from_str(payload)?;
@@ -230,6 +288,41 @@ impl FirefoxAccount {
                     self.poll_device_commands()
                 }
             }
+            PushPayload::ProfileUpdated => {
+                self.invalidate_cached_profile();
+                Ok(vec![AccountEvent::ProfileUpdated])
+            }
+            PushPayload::DeviceConnected(DeviceConnectedPushPayload { device_name }) => {
+                Ok(vec![AccountEvent::DeviceConnected { device_name }])
+            }
+            PushPayload::DeviceDisconnected(DeviceDisconnectedPushPayload { device_id }) => {
+                let is_local_device = self.state.current_device_id.as_ref() == Some(&device_id);
+                if is_local_device {
+                    // Our device record, and with it our refresh token, are gone.
+                    self.start_over();
+                }
+                Ok(vec![AccountEvent::DeviceDisconnected {
+                    device_id,
+                    is_local_device,
+                }])
+            }
+            PushPayload::PasswordChanged | PushPayload::PasswordReset => {
+                Ok(vec![AccountEvent::PasswordChanged])
+            }
+            PushPayload::AccountDestroyed(AccountDestroyedPushPayload { account_uid }) => {
+                let is_local_account = match self.state.last_seen_profile {
+                    Some(ref cached_profile) => cached_profile.response.uid == account_uid,
+                    // We can't tell, and the server only sends this to our account anyway.
+                    None => true,
+                };
+                if !is_local_account {
+                    log::warn!("Ignoring an account destroyed message for another account.");
+                    return Ok(vec![]);
+                }
+                self.start_over();
+                self.state.last_seen_profile = None;
+                Ok(vec![AccountEvent::AccountDestroyed])
+            }
             PushPayload::Unknown => {
                 log::info!("Unknown Push command.");
                 Ok(vec![])
@@ -244,6 +337,13 @@ impl FirefoxAccount {
         }
     }
 
+    fn get_session_token(&self) -> Result<&str> {
+        match self.state.session_token {
+            Some(ref session_token) => Ok(session_token),
+            None => Err(ErrorKind::NoSessionToken.into()),
+        }
+    }
+
     /// Disconnect from the account and optionaly destroy our device record. This will
     /// leave the account object in a state where it can eventually reconnect to the same user.
     /// This is a "best effort" infallible method: e.g. if the network is unreachable,
@@ -271,11 +371,43 @@ impl FirefoxAccount {
         }
         self.start_over();
     }
+
+    /// Permanently delete the user's Firefox Account, and every device and
+    /// session attached to it. This requires the user to re-type their
+    /// password. On success, the local account state is entirely cleared.
+    ///
+    /// **💾 This method alters the persisted account state.**
+    pub fn destroy_account(&mut self, email: &str, password: &str) -> Result<()> {
+        let auth_pw = http_client::derive_auth_pw(email, password)?;
+        self.client
+            .destroy_account(&self.state.config, email, &auth_pw)?;
+        // There's nothing left to reconnect to.
+        self.start_over();
+        self.state.last_seen_profile = None;
+        Ok(())
+    }
 }
 
+#[allow(clippy::large_enum_variant)]
 pub enum AccountEvent {
-    // In the future: ProfileUpdated etc.
     TabReceived((Option<Device>, SendTabPayload)),
+    // The cached profile is stale, `get_profile` will fetch it again.
+    ProfileUpdated,
+    // Another device was connected to the account.
+    DeviceConnected {
+        device_name: String,
+    },
+    // A device was disconnected from the account. If it is our own device,
+    // the account has been disconnected locally too.
+    DeviceDisconnected {
+        device_id: String,
+        is_local_device: bool,
+    },
+    // The account password was changed or reset: `check_authorization_status`
+    // tells whether we need to sign in again.
+    PasswordChanged,
+    // The account was destroyed, and the local account state cleared.
+    AccountDestroyed,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -290,6 +422,18 @@ pub(crate) struct CachedResponse<T> {
 pub enum PushPayload {
     #[serde(rename = "fxaccounts:command_received")]
     CommandReceived(CommandReceivedPushPayload),
+    #[serde(rename = "fxaccounts:profile_updated")]
+    ProfileUpdated,
+    #[serde(rename = "fxaccounts:device_connected")]
+    DeviceConnected(DeviceConnectedPushPayload),
+    #[serde(rename = "fxaccounts:device_disconnected")]
+    DeviceDisconnected(DeviceDisconnectedPushPayload),
+    #[serde(rename = "fxaccounts:password_changed")]
+    PasswordChanged,
+    #[serde(rename = "fxaccounts:password_reset")]
+    PasswordReset,
+    #[serde(rename = "fxaccounts:account_destroyed")]
+    AccountDestroyed(AccountDestroyedPushPayload),
     #[serde(other)]
     Unknown,
 }
@@ -302,6 +446,24 @@ pub struct CommandReceivedPushPayload {
     url: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct DeviceConnectedPushPayload {
+    #[serde(rename = "deviceName")]
+    device_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeviceDisconnectedPushPayload {
+    #[serde(rename = "id")]
+    device_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AccountDestroyedPushPayload {
+    #[serde(rename = "uid")]
+    account_uid: String,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -335,6 +497,13 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_get_pairing_authority_url() {
+        let fxa = FirefoxAccount::new("https://stable.dev.lcip.org", "12345678", "https://foo.bar");
+        let url = fxa.get_pairing_authority_url().unwrap().to_string();
+        assert_eq!(url, "https://stable.dev.lcip.org/pair".to_string());
+    }
+
     #[test]
     fn test_get_manage_account_url() {
         let mut fxa =
@@ -373,12 +542,152 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_get_connect_another_device_url() {
+        let mut fxa =
+            FirefoxAccount::new("https://stable.dev.lcip.org", "12345678", "https://foo.bar");
+        // No current user -> Error.
+        match fxa
+            .get_connect_another_device_url("test")
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::NoCachedToken(_) => {}
+            _ => panic!("error not NoCachedToken"),
+        };
+        // With current user -> expected Url.
+        fxa.add_cached_profile("123", "test@example.com");
+        let url = fxa
+            .get_connect_another_device_url("test")
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            url,
+            "https://stable.dev.lcip.org/connect_another_device?entrypoint=test&uid=123&email=test%40example.com"
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_metrics_params_with_device_id() {
+        let mut fxa =
+            FirefoxAccount::new("https://stable.dev.lcip.org", "12345678", "https://foo.bar");
+        fxa.add_cached_profile("123", "test@example.com");
+        fxa.state.current_device_id = Some("abcd".to_owned());
+        let url = fxa.get_manage_devices_url("test").unwrap().to_string();
+        assert_eq!(
+            url,
+            "https://stable.dev.lcip.org/settings/clients?entrypoint=test&device_id=abcd&uid=123&email=test%40example.com"
+                .to_string()
+        );
+        let url = fxa
+            .get_connect_another_device_url("test")
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            url,
+            "https://stable.dev.lcip.org/connect_another_device?entrypoint=test&device_id=abcd&uid=123&email=test%40example.com"
+                .to_string()
+        );
+    }
+
     #[test]
     fn test_deserialize_push_message() {
         let json = "{\"version\":1,\"command\":\"fxaccounts:command_received\",\"data\":{\"command\":\"send-tab-recv\",\"index\":1,\"sender\":\"bobo\",\"url\":\"https://mozilla.org\"}}";
         let _: PushPayload = serde_json::from_str(&json).unwrap();
     }
 
+    #[test]
+    fn test_deserialize_profile_updated_push_message() {
+        let json = "{\"version\":1,\"command\":\"fxaccounts:profile_updated\"}";
+        match serde_json::from_str(&json).unwrap() {
+            PushPayload::ProfileUpdated => {}
+            _ => panic!("Expected a ProfileUpdated payload"),
+        }
+    }
+
+    #[test]
+    fn test_handle_push_message_device_connected() {
+        let mut fxa =
+            FirefoxAccount::with_config(Config::stable_dev("12345678", "https://foo.bar"));
+        let json = "{\"version\":1,\"command\":\"fxaccounts:device_connected\",\"data\":{\"deviceName\":\"My Phone\"}}";
+        let events = fxa.handle_push_message(json).unwrap();
+        assert_eq!(events.len(), 1);
+        match events[0] {
+            AccountEvent::DeviceConnected { ref device_name } => {
+                assert_eq!(device_name, "My Phone")
+            }
+            _ => panic!("Expected a DeviceConnected event"),
+        }
+    }
+
+    #[test]
+    fn test_handle_push_message_device_disconnected() {
+        let mut fxa =
+            FirefoxAccount::with_config(Config::stable_dev("12345678", "https://foo.bar"));
+        fxa.state.refresh_token = Some(RefreshToken {
+            token: "refreshtok".to_string(),
+            scopes: HashSet::default(),
+        });
+        fxa.state.current_device_id = Some("us".to_owned());
+
+        let json = "{\"version\":1,\"command\":\"fxaccounts:device_disconnected\",\"data\":{\"id\":\"them\"}}";
+        match fxa.handle_push_message(json).unwrap()[0] {
+            AccountEvent::DeviceDisconnected {
+                ref device_id,
+                is_local_device: false,
+            } => assert_eq!(device_id, "them"),
+            _ => panic!("Expected a remote DeviceDisconnected event"),
+        }
+        assert!(fxa.state.refresh_token.is_some());
+
+        let json = "{\"version\":1,\"command\":\"fxaccounts:device_disconnected\",\"data\":{\"id\":\"us\"}}";
+        match fxa.handle_push_message(json).unwrap()[0] {
+            AccountEvent::DeviceDisconnected {
+                is_local_device: true,
+                ..
+            } => {}
+            _ => panic!("Expected a local DeviceDisconnected event"),
+        }
+        assert!(fxa.state.refresh_token.is_none());
+    }
+
+    #[test]
+    fn test_handle_push_message_password_changed() {
+        let mut fxa =
+            FirefoxAccount::with_config(Config::stable_dev("12345678", "https://foo.bar"));
+        for command in &["fxaccounts:password_changed", "fxaccounts:password_reset"] {
+            let json = format!("{{\"version\":1,\"command\":\"{}\"}}", command);
+            match fxa.handle_push_message(&json).unwrap()[0] {
+                AccountEvent::PasswordChanged => {}
+                _ => panic!("Expected a PasswordChanged event"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_handle_push_message_account_destroyed() {
+        let mut fxa =
+            FirefoxAccount::with_config(Config::stable_dev("12345678", "https://foo.bar"));
+        fxa.add_cached_profile("123", "test@example.com");
+        fxa.state.refresh_token = Some(RefreshToken {
+            token: "refreshtok".to_string(),
+            scopes: HashSet::default(),
+        });
+
+        let json = "{\"version\":1,\"command\":\"fxaccounts:account_destroyed\",\"data\":{\"uid\":\"456\"}}";
+        assert!(fxa.handle_push_message(json).unwrap().is_empty());
+        assert!(fxa.state.refresh_token.is_some());
+
+        let json = "{\"version\":1,\"command\":\"fxaccounts:account_destroyed\",\"data\":{\"uid\":\"123\"}}";
+        match fxa.handle_push_message(json).unwrap()[0] {
+            AccountEvent::AccountDestroyed => {}
+            _ => panic!("Expected an AccountDestroyed event"),
+        }
+        assert!(fxa.state.refresh_token.is_none());
+        assert!(fxa.state.last_seen_profile.is_none());
+    }
+
     #[test]
     fn test_handle_push_message_unknown_command() {
         let mut fxa =
@@ -527,6 +836,37 @@ mod tests {
         assert!(fxa.state.refresh_token.is_none());
     }
 
+    #[test]
+    fn test_destroy_account() {
+        let mut fxa =
+            FirefoxAccount::with_config(Config::stable_dev("12345678", "https://foo.bar"));
+        fxa.add_cached_profile("123", "andr\u{e9}@example.org");
+        fxa.state.refresh_token = Some(RefreshToken {
+            token: "refreshtok".to_string(),
+            scopes: HashSet::default(),
+        });
+
+        let mut client = FxAClientMock::new();
+        client
+            .expect_destroy_account(
+                mockiato::Argument::any,
+                |email| email.partial_eq("andr\u{e9}@example.org"),
+                |auth_pw| {
+                    auth_pw.partial_eq(
+                        "247b675ffb4c46310bc87e26d712153abe5e1c90ef00a4784594f97ef54f2375",
+                    )
+                },
+            )
+            .times(1)
+            .returns_once(Ok(()));
+        fxa.set_client(Arc::new(client));
+
+        fxa.destroy_account("andr\u{e9}@example.org", "p\u{e4}ssw\u{f6}rd")
+            .unwrap();
+        assert!(fxa.state.refresh_token.is_none());
+        assert!(fxa.state.last_seen_profile.is_none());
+    }
+
     #[test]
     fn test_disconnect_network_errors() {
         let mut fxa =
@@ -3,12 +3,18 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::{error::*, scoped_keys::ScopedKey, scopes, FirefoxAccount};
+use serde_derive::*;
 
 impl FirefoxAccount {
     /// Migrate from a logged-in with a sessionToken Firefox Account.
     /// As part of this process the server duplicates
     /// a valid session into a new, independent session.
     ///
+    /// If the migration fails because of a network or server error, the
+    /// migration data is kept around, `is_in_migration_state` will return
+    /// `true`, and the migration can be resumed later using
+    /// `retry_migrate_from_session_token`.
+    ///
     /// * `session_token` - Hex-formatted session token.
     /// * `k_xcs` - Hex-formatted kXCS.
     /// * `k_sync` - Hex-formatted kSync.
@@ -24,10 +30,54 @@ impl FirefoxAccount {
         if self.state.session_token.is_some() {
             return Err(ErrorKind::IllegalState("Session Token is already set.").into());
         }
+        self.try_migration(MigrationData {
+            session_token: session_token.to_owned(),
+            k_sync: k_sync.to_owned(),
+            k_xcs: k_xcs.to_owned(),
+        })
+    }
+
+    /// Whether a previous `migrate_from_session_token` call failed
+    /// with a transient error and can be retried.
+    pub fn is_in_migration_state(&self) -> bool {
+        self.state.in_flight_migration.is_some()
+    }
+
+    /// Resume a migration that `migrate_from_session_token` could not
+    /// complete because of a transient error.
+    ///
+    /// **💾 This method alters the persisted account state.**
+    pub fn retry_migrate_from_session_token(&mut self) -> Result<()> {
+        let migration_data = match self.state.in_flight_migration {
+            Some(ref migration_data) => migration_data.clone(),
+            None => return Err(ErrorKind::NoMigrationData.into()),
+        };
+        self.try_migration(migration_data)
+    }
+
+    fn try_migration(&mut self, migration_data: MigrationData) -> Result<()> {
+        match self.network_migration(&migration_data) {
+            Ok(()) => {
+                self.state.in_flight_migration = None;
+                Ok(())
+            }
+            Err(e) => {
+                if is_transient_error(&e) {
+                    log::warn!("Migration failed, it can be retried later: {}", e);
+                    self.state.in_flight_migration = Some(migration_data);
+                } else {
+                    self.state.in_flight_migration = None;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn network_migration(&mut self, migration_data: &MigrationData) -> Result<()> {
         // Trade our session token for a refresh token.
         let duplicate_session = self
             .client
-            .duplicate_session(&self.state.config, &session_token)?;
+            .duplicate_session(&self.state.config, &migration_data.session_token)?;
 
         let duplicated_session_token = duplicate_session.session_token;
 
@@ -39,9 +89,9 @@ impl FirefoxAccount {
         self.handle_oauth_response(oauth_response, None)?;
 
         // Synthesize a scoped key from our kSync.
-        let k_sync = hex::decode(k_sync)?;
+        let k_sync = hex::decode(&migration_data.k_sync)?;
         let k_sync = base64::encode_config(&k_sync, base64::URL_SAFE_NO_PAD);
-        let k_xcs = hex::decode(k_xcs)?;
+        let k_xcs = hex::decode(&migration_data.k_xcs)?;
         let k_xcs = base64::encode_config(&k_xcs, base64::URL_SAFE_NO_PAD);
         let scoped_key_data = self.client.scoped_key_data(
             &self.state.config,
@@ -65,3 +115,148 @@ impl FirefoxAccount {
         Ok(())
     }
 }
+
+/// The arguments of a migration which could not be completed yet.
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct MigrationData {
+    session_token: String, // Hex-formatted string.
+    k_sync: String,
+    k_xcs: String,
+}
+
+// Network errors and server-side failures might go away on their own,
+// anything else (e.g. an invalid session token) won't.
+fn is_transient_error(err: &Error) -> bool {
+    match err.kind() {
+        ErrorKind::RequestError(_) => true,
+        ErrorKind::RemoteError { code, .. } => *code >= 500,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{http_client::*, Config};
+    use std::{collections::HashMap, sync::Arc};
+
+    fn network_error() -> Error {
+        ErrorKind::RequestError(viaduct::Error::NetworkError("Offline".to_owned())).into()
+    }
+
+    fn expect_successful_migration(client: &mut FxAClientMock<'_>) {
+        client
+            .expect_duplicate_session(mockiato::Argument::any, |token| {
+                token.partial_eq("sessiontok")
+            })
+            .times(1)
+            .returns_once(Ok(DuplicateTokenResponse {
+                uid: "123".to_owned(),
+                session_token: "dupsessiontok".to_owned(),
+                verified: true,
+                auth_at: 123_456,
+            }));
+        client
+            .expect_oauth_tokens_from_session_token(
+                mockiato::Argument::any,
+                |token| token.partial_eq("dupsessiontok"),
+                mockiato::Argument::any,
+            )
+            .times(1)
+            .returns_once(Ok(OAuthTokenResponse {
+                keys_jwe: None,
+                refresh_token: Some("refreshtok".to_owned()),
+                expires_in: 6_000,
+                scope: "profile https://identity.mozilla.com/apps/oldsync".to_owned(),
+                access_token: "accesstok".to_owned(),
+            }));
+        client
+            .expect_destroy_access_token(mockiato::Argument::any, |token| {
+                token.partial_eq("accesstok")
+            })
+            .times(1)
+            .returns_once(Ok(()));
+        let mut key_data = HashMap::new();
+        key_data.insert(
+            scopes::OLD_SYNC.to_owned(),
+            ScopedKeyDataResponse {
+                identifier: scopes::OLD_SYNC.to_owned(),
+                key_rotation_secret: "00".repeat(32),
+                key_rotation_timestamp: 1_542_236_016_429,
+            },
+        );
+        client
+            .expect_scoped_key_data(
+                mockiato::Argument::any,
+                |token| token.partial_eq("dupsessiontok"),
+                |scope| scope.partial_eq(scopes::OLD_SYNC),
+            )
+            .times(1)
+            .returns_once(Ok(key_data));
+    }
+
+    #[test]
+    fn test_migration_can_be_retried() {
+        let mut fxa =
+            FirefoxAccount::with_config(Config::stable_dev("12345678", "https://foo.bar"));
+        let mut client = FxAClientMock::new();
+        client
+            .expect_duplicate_session(mockiato::Argument::any, mockiato::Argument::any)
+            .times(1)
+            .returns_once(Err(network_error()));
+        fxa.set_client(Arc::new(client));
+
+        assert!(!fxa.is_in_migration_state());
+        assert!(fxa
+            .migrate_from_session_token("sessiontok", &"42".repeat(64), &"13".repeat(16))
+            .is_err());
+        assert!(fxa.is_in_migration_state());
+
+        // The migration data is persisted with the rest of the account state.
+        let mut fxa = FirefoxAccount::from_json(&fxa.to_json().unwrap()).unwrap();
+        assert!(fxa.is_in_migration_state());
+
+        let mut client = FxAClientMock::new();
+        expect_successful_migration(&mut client);
+        fxa.set_client(Arc::new(client));
+        fxa.retry_migrate_from_session_token().unwrap();
+        assert!(!fxa.is_in_migration_state());
+        assert_eq!(fxa.state.session_token, Some("dupsessiontok".to_owned()));
+        let oldsync_key = fxa.get_scoped_key(scopes::OLD_SYNC).unwrap();
+        assert_eq!(
+            oldsync_key.kid,
+            format!(
+                "1542236016429-{}",
+                base64::encode_config(&[0x13u8; 16], base64::URL_SAFE_NO_PAD)
+            )
+        );
+    }
+
+    #[test]
+    fn test_migration_permanent_failure() {
+        let mut fxa =
+            FirefoxAccount::with_config(Config::stable_dev("12345678", "https://foo.bar"));
+        let mut client = FxAClientMock::new();
+        client
+            .expect_duplicate_session(mockiato::Argument::any, mockiato::Argument::any)
+            .times(1)
+            .returns_once(Err(ErrorKind::RemoteError {
+                code: 401,
+                errno: 110,
+                error: "Unauthorized".to_owned(),
+                message: "Invalid authentication token in request signature".to_owned(),
+                info: "".to_owned(),
+            }
+            .into()));
+        fxa.set_client(Arc::new(client));
+
+        assert!(fxa
+            .migrate_from_session_token("sessiontok", &"42".repeat(64), &"13".repeat(16))
+            .is_err());
+        assert!(!fxa.is_in_migration_state());
+        match fxa.retry_migrate_from_session_token().unwrap_err().kind() {
+            ErrorKind::NoMigrationData => {}
+            e => panic!("Unexpected error {}", e),
+        }
+    }
+}
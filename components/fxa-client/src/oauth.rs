@@ -11,7 +11,7 @@ use crate::{
 use rc_crypto::digest;
 use serde_derive::*;
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     iter::FromIterator,
     time::{SystemTime, UNIX_EPOCH},
 };
@@ -23,6 +23,8 @@ const OAUTH_MIN_TIME_LEFT: u64 = 60;
 // Special redirect urn based on the OAuth native spec, signals that the
 // WebChannel flow is used
 const OAUTH_WEBCHANNEL_REDIRECT: &str = "urn:ietf:wg:oauth:2.0:oob:oauth-redirect-webchannel";
+// The pairing channel key is a 256-bit AES key.
+const PAIRING_CHANNEL_KEY_LENGTH: usize = 32;
 
 impl FirefoxAccount {
     /// Fetch a short-lived access token using the saved refresh token.
@@ -87,6 +89,7 @@ impl FirefoxAccount {
         if url.host_str() != pairing_url.host_str() {
             return Err(ErrorKind::OriginMismatch.into());
         }
+        validate_pairing_channel(&pairing_url)?;
         url.set_fragment(pairing_url.fragment());
         self.oauth_flow(url, scopes)
     }
@@ -255,6 +258,71 @@ impl FirefoxAccount {
     pub fn clear_access_token_cache(&mut self) {
         self.access_token_cache.clear();
     }
+
+    /// Check with the server whether our refresh token is still valid.
+    /// Unlike a failed request, this tells whether the user needs to sign
+    /// in again, or whether the error was caused by something else (e.g. a
+    /// stale access token).
+    pub fn check_authorization_status(&self) -> Result<AuthorizationInfo> {
+        let refresh_token = self.get_refresh_token()?;
+        let resp = self
+            .client
+            .check_refresh_token_status(&self.state.config, &refresh_token)?;
+        Ok(AuthorizationInfo {
+            active: resp.active,
+        })
+    }
+
+    /// Recover from an authentication error (e.g. a 401 from a service we
+    /// made a request to with one of our access tokens).
+    ///
+    /// The access tokens cache is always cleared, so the next
+    /// `get_access_token` call fetches a new token. If the refresh token
+    /// turns out to be revoked too, the account is disconnected locally
+    /// (like `start_over`) so that the application can ask the user to sign
+    /// in again, instead of failing the same way forever.
+    ///
+    /// If the status can't be checked (e.g. because of a network error),
+    /// the error is returned and the account is left untouched.
+    ///
+    /// **💾 This method alters the persisted account state.**
+    pub fn handle_auth_error(&mut self) -> Result<AuthorizationInfo> {
+        self.clear_access_token_cache();
+        let info = self.check_authorization_status()?;
+        if !info.active {
+            log::warn!("Refresh token is no longer valid, the user needs to sign in again.");
+            self.start_over();
+        }
+        Ok(info)
+    }
+}
+
+/// The pairing channel ID and key are passed to the supplicant page in the
+/// URL fragment, and it can't do anything useful without them, so we'd rather
+/// fail early than send the user to a broken page.
+fn validate_pairing_channel(pairing_url: &Url) -> Result<()> {
+    let fragment = pairing_url
+        .fragment()
+        .ok_or_else(|| ErrorKind::InvalidPairingUrl("missing channel information"))?;
+    let params: HashMap<_, _> = url::form_urlencoded::parse(fragment.as_bytes()).collect();
+    match params.get("channel_id") {
+        Some(channel_id) if !channel_id.is_empty() => {}
+        _ => return Err(ErrorKind::InvalidPairingUrl("missing channel ID").into()),
+    };
+    let channel_key = params
+        .get("channel_key")
+        .ok_or_else(|| ErrorKind::InvalidPairingUrl("missing channel key"))?;
+    let channel_key = base64::decode_config(channel_key.as_ref(), base64::URL_SAFE_NO_PAD)
+        .map_err(|_| ErrorKind::InvalidPairingUrl("malformed channel key"))?;
+    if channel_key.len() != PAIRING_CHANNEL_KEY_LENGTH {
+        return Err(ErrorKind::InvalidPairingUrl("malformed channel key").into());
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug)]
+pub struct AuthorizationInfo {
+    pub active: bool,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -370,6 +438,187 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_complete_oauth_flow_scoped_keys() {
+        use crate::http_client::FxAClientMock;
+        use rc_crypto::agreement::{self, KeyPair, PrivateKey};
+        use std::sync::Arc;
+
+        let mut fxa = FirefoxAccount::new(
+            "https://accounts.firefox.com",
+            "12345678",
+            "https://foo.bar",
+        );
+        // Same key pair and JWE as `scoped_keys::tests::test_flow`.
+        let decode = |s| base64::decode_config(s, base64::URL_SAFE_NO_PAD).unwrap();
+        let ec_key = agreement::EcKey::from_coordinates(
+            agreement::Curve::P256,
+            &decode("UayD4kn_4QHvLvLLSSaANfDUp9AcQndQu_TohQKoyn8"),
+            &decode("ARvGIPJ5eIFdp6YTM-INVDqwfun2R9FfCUvXbH7QCIU"),
+            &decode("hk8gP0Po8nBh-WSiTsvsyesC5c1L6fGOEVuX8FHsvTs"),
+        )
+        .unwrap();
+        let private_key = PrivateKey::<agreement::Static>::import(&ec_key).unwrap();
+        let key_pair = KeyPair::from(private_key).unwrap();
+        fxa.flow_store.insert(
+            "mystate".to_owned(),
+            OAuthFlow {
+                scoped_keys_flow: Some(ScopedKeysFlow::from_static_key_pair(key_pair).unwrap()),
+                code_verifier: "verifier".to_owned(),
+            },
+        );
+
+        let mut client = FxAClientMock::new();
+        client
+            .expect_oauth_tokens_from_code(
+                mockiato::Argument::any,
+                |code| code.partial_eq("mycode"),
+                |verifier| verifier.partial_eq("verifier"),
+            )
+            .times(1)
+            .returns_once(Ok(OAuthTokenResponse {
+                keys_jwe: Some("eyJhbGciOiJFQ0RILUVTIiwia2lkIjoiNFBKTTl5dGVGeUtsb21ILWd2UUtyWGZ0a0N3ak9HNHRfTmpYVXhLM1VqSSIsImVwayI6eyJrdHkiOiJFQyIsImNydiI6IlAtMjU2IiwieCI6IlB3eG9Na1RjSVZ2TFlKWU4wM2R0Y3o2TEJrR0FHaU1hZWlNQ3lTZXEzb2MiLCJ5IjoiLUYtTllRRDZwNUdSQ2ZoYm1hN3NvNkhxdExhVlNub012S0pFcjFBeWlaSSJ9LCJlbmMiOiJBMjU2R0NNIn0..b9FPhjjpmAmo_rP8.ur9jTry21Y2trvtcanSFmAtiRfF6s6qqyg6ruRal7PCwa7PxDzAuMN6DZW5BiK8UREOH08-FyRcIgdDOm5Zq8KwVAn56PGfcH30aNDGQNkA_mpfjx5Tj2z8kI6ryLWew4PGZb-PsL1g-_eyXhktq7dAhetjNYttKwSREWQFokv7N3nJGpukBqnwL1ost-MjDXlINZLVJKAiMHDcu-q7Epitwid2c2JVGOSCJjbZ4-zbxVmZ4o9xhFb2lbvdiaMygH6bPlrjEK99uT6XKtaIZmyDwftbD6G3x4On-CqA2TNL6ILRaJMtmyX--ctL0IrngUIHg_F0Wz94v.zBD8NACkUcZTPLH0tceGnA".to_owned()),
+                refresh_token: Some("refreshtok".to_owned()),
+                expires_in: 6_000_000,
+                scope: "profile https://identity.mozilla.com/apps/oldsync".to_owned(),
+                access_token: "accesstok".to_owned(),
+            }));
+        client
+            .expect_destroy_access_token(mockiato::Argument::any, |token| {
+                token.partial_eq("accesstok")
+            })
+            .times(1)
+            .returns_once(Ok(()));
+        fxa.set_client(Arc::new(client));
+
+        match fxa
+            .get_scoped_key(crate::scopes::OLD_SYNC)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::NoScopedKey(_) => {}
+            _ => panic!("error not NoScopedKey"),
+        }
+        fxa.complete_oauth_flow("mycode", "mystate").unwrap();
+        let key = fxa.get_scoped_key(crate::scopes::OLD_SYNC).unwrap();
+        assert_eq!(key.kid, "1526414944666-zgTjf5oXmPmBjxwXWFsDWg");
+        assert_eq!(key.scope, crate::scopes::OLD_SYNC);
+        assert!(fxa.get_scoped_key("profile").is_err());
+        let refresh_token = fxa.state.refresh_token.as_ref().unwrap();
+        assert!(refresh_token.scopes.contains(crate::scopes::OLD_SYNC));
+        assert!(refresh_token.scopes.contains(crate::scopes::PROFILE));
+    }
+
+    #[test]
+    fn test_access_token_cache() {
+        use crate::http_client::FxAClientMock;
+        use std::sync::Arc;
+
+        let mut fxa =
+            FirefoxAccount::new("https://stable.dev.lcip.org", "12345678", "https://foo.bar");
+        let mut refresh_token_scopes = HashSet::new();
+        refresh_token_scopes.insert("profile".to_owned());
+        fxa.state.refresh_token = Some(RefreshToken {
+            token: "refreshtok".to_owned(),
+            scopes: refresh_token_scopes,
+        });
+        // This one expires too soon to be handed out.
+        fxa.add_cached_token(
+            "profile",
+            AccessTokenInfo {
+                scope: "profile".to_string(),
+                token: "expiringtok".to_string(),
+                key: None,
+                expires_at: util::now_secs() + OAUTH_MIN_TIME_LEFT - 1,
+            },
+        );
+
+        let client_returning = |access_token: &str| {
+            let mut client = FxAClientMock::new();
+            client
+                .expect_oauth_token_with_refresh_token(
+                    mockiato::Argument::any,
+                    |token| token.partial_eq("refreshtok"),
+                    mockiato::Argument::any,
+                )
+                .times(1)
+                .returns_once(Ok(OAuthTokenResponse {
+                    keys_jwe: None,
+                    refresh_token: None,
+                    expires_in: 6_000,
+                    scope: "profile".to_owned(),
+                    access_token: access_token.to_owned(),
+                }));
+            Arc::new(client)
+        };
+
+        fxa.set_client(client_returning("freshtok"));
+        assert_eq!(fxa.get_access_token("profile").unwrap().token, "freshtok");
+        // Served from the cache this time.
+        assert_eq!(fxa.get_access_token("profile").unwrap().token, "freshtok");
+        // Until it gets cleared.
+        fxa.clear_access_token_cache();
+        fxa.set_client(client_returning("freshertok"));
+        assert_eq!(fxa.get_access_token("profile").unwrap().token, "freshertok");
+        // We never ask for scopes the refresh token doesn't have.
+        match fxa.get_access_token("other").unwrap_err().kind() {
+            ErrorKind::NoCachedToken(_) => {}
+            _ => panic!("error not NoCachedToken"),
+        }
+    }
+
+    fn authorized_fxa(active: bool) -> FirefoxAccount {
+        use crate::http_client::{FxAClientMock, IntrospectResponse};
+        use std::sync::Arc;
+
+        let mut fxa =
+            FirefoxAccount::new("https://stable.dev.lcip.org", "12345678", "https://foo.bar");
+        fxa.state.refresh_token = Some(RefreshToken {
+            token: "refreshtok".to_owned(),
+            scopes: HashSet::new(),
+        });
+        fxa.add_cached_token(
+            "profile",
+            AccessTokenInfo {
+                scope: "profile".to_string(),
+                token: "profiletok".to_string(),
+                key: None,
+                expires_at: u64::max_value(),
+            },
+        );
+        let mut client = FxAClientMock::new();
+        client
+            .expect_check_refresh_token_status(mockiato::Argument::any, |token| {
+                token.partial_eq("refreshtok")
+            })
+            .times(1)
+            .returns_once(Ok(IntrospectResponse { active }));
+        fxa.set_client(Arc::new(client));
+        fxa
+    }
+
+    #[test]
+    fn test_check_authorization_status() {
+        let fxa = authorized_fxa(true);
+        assert!(fxa.check_authorization_status().unwrap().active);
+    }
+
+    #[test]
+    fn test_handle_auth_error_still_authorized() {
+        let mut fxa = authorized_fxa(true);
+        assert!(fxa.handle_auth_error().unwrap().active);
+        assert!(fxa.access_token_cache.is_empty());
+        assert!(fxa.state.refresh_token.is_some());
+    }
+
+    #[test]
+    fn test_handle_auth_error_revoked() {
+        let mut fxa = authorized_fxa(false);
+        assert!(!fxa.handle_auth_error().unwrap().active);
+        assert!(fxa.access_token_cache.is_empty());
+        assert!(fxa.state.refresh_token.is_none());
+    }
+
     #[test]
     fn test_force_auth_url() {
         let mut fxa =
@@ -506,4 +755,29 @@ mod tests {
             },
         }
     }
+
+    #[test]
+    fn test_pairing_flow_invalid_channel() {
+        let mut fxa = FirefoxAccount::new(
+            "https://accounts.firefox.com",
+            "12345678",
+            "https://foo.bar",
+        );
+        for pairing_url in &[
+            "https://accounts.firefox.com/pair",
+            "https://accounts.firefox.com/pair#channel_key=1hIDzTj5oY2HDeSg_jA2DhcOcAn5Uqq0cAYlZRNUIo4",
+            "https://accounts.firefox.com/pair#channel_id=658db7fe98b249a5897b884f98fb31b7",
+            "https://accounts.firefox.com/pair#channel_id=658db7fe98b249a5897b884f98fb31b7&channel_key=bar",
+            "https://accounts.firefox.com/pair#channel_id=658db7fe98b249a5897b884f98fb31b7&channel_key=$$$",
+        ] {
+            match fxa.begin_pairing_flow(pairing_url, &["https://identity.mozilla.com/apps/oldsync"]) {
+                Ok(_) => panic!("should have error for {}", pairing_url),
+                Err(err) => match err.kind() {
+                    ErrorKind::InvalidPairingUrl(_) => {}
+                    _ => panic!("error not InvalidPairingUrl"),
+                },
+            }
+        }
+        assert!(fxa.flow_store.is_empty());
+    }
 }
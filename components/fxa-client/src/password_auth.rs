@@ -0,0 +1,32 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::{
+    error::*,
+    http_client::{self, derive_auth_pw, derive_sync_keys},
+    FirefoxAccount,
+};
+
+impl FirefoxAccount {
+    /// Sign in directly with an email and password, without going through
+    /// the OAuth flow in a browser. This is only meant for integration tests
+    /// and command-line tools running against stage or dev servers, and
+    /// requires the account to be verified.
+    ///
+    /// The session token obtained by signing in is then migrated exactly
+    /// like `migrate_from_session_token` would.
+    ///
+    /// **💾 This method alters the persisted account state.**
+    pub fn sign_in_with_password(&mut self, email: &str, password: &str) -> Result<()> {
+        let client = http_client::Client::new();
+        let auth_pw = derive_auth_pw(email, password)?;
+        let login = client.login(&self.state.config, email, &auth_pw)?;
+        let key_fetch_token = login.key_fetch_token.ok_or_else(|| {
+            ErrorKind::IllegalState("The login response is missing a key fetch token.")
+        })?;
+        let bundle = client.account_keys(&self.state.config, &key_fetch_token)?;
+        let (k_sync, k_xcs) = derive_sync_keys(email, password, &key_fetch_token, &bundle)?;
+        self.migrate_from_session_token(&login.session_token, &k_sync, &k_xcs)
+    }
+}
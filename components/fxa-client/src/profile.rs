@@ -34,6 +34,15 @@ impl FirefoxAccount {
         }
     }
 
+    /// Mark the cached profile as stale, so the next `get_profile` call
+    /// checks with the server. We keep it around rather than dropping it,
+    /// both to send its eTag and to still know who the user is.
+    pub(crate) fn invalidate_cached_profile(&mut self) {
+        if let Some(ref mut cached_profile) = self.state.last_seen_profile {
+            cached_profile.cached_at = 0;
+        }
+    }
+
     fn get_profile_helper(&mut self, ignore_cache: bool) -> Result<Profile> {
         let mut etag = None;
         if let Some(ref cached_profile) = self.state.last_seen_profile {
@@ -85,6 +94,7 @@ mod tests {
     use crate::{
         http_client::*,
         oauth::{AccessTokenInfo, RefreshToken},
+        AccountEvent,
     };
     use std::sync::Arc;
 
@@ -149,6 +159,74 @@ mod tests {
         assert_eq!(p.email, "foo@bar.com");
     }
 
+    #[test]
+    fn test_cached_profile() {
+        let mut fxa =
+            FirefoxAccount::new("https://stable.dev.lcip.org", "12345678", "https://foo.bar");
+        fxa.add_cached_profile("123", "test@example.com");
+        // A fresh cached profile doesn't hit the network.
+        fxa.set_client(Arc::new(FxAClientMock::new()));
+        let p = fxa.get_profile(false).unwrap();
+        assert_eq!(p.email, "test@example.com");
+    }
+
+    #[test]
+    fn test_profile_updated_push_message() {
+        let mut fxa =
+            FirefoxAccount::new("https://stable.dev.lcip.org", "12345678", "https://foo.bar");
+        fxa.add_cached_profile("123", "test@example.com");
+        fxa.add_cached_token(
+            "profile",
+            AccessTokenInfo {
+                scope: "profile".to_string(),
+                token: "profiletok".to_string(),
+                key: None,
+                expires_at: u64::max_value(),
+            },
+        );
+
+        let mut client = FxAClientMock::new();
+        client
+            .expect_profile(
+                mockiato::Argument::any,
+                |token| token.partial_eq("profiletok"),
+                |etag| etag.partial_eq(Some("fake etag".to_owned())),
+            )
+            .times(1)
+            .returns_once(Ok(Some(ResponseAndETag {
+                response: ProfileResponse {
+                    uid: "123".to_string(),
+                    email: "new@example.com".to_string(),
+                    locale: "en-US".to_string(),
+                    display_name: Some("Bobo".to_string()),
+                    avatar: "https://foo.avatar".to_string(),
+                    avatar_default: false,
+                    amr_values: vec![],
+                    two_factor_authentication: false,
+                },
+                etag: Some("new etag".to_string()),
+            })));
+        fxa.set_client(Arc::new(client));
+
+        let json = "{\"version\":1,\"command\":\"fxaccounts:profile_updated\"}";
+        let events = fxa.handle_push_message(json).unwrap();
+        assert_eq!(events.len(), 1);
+        match events[0] {
+            AccountEvent::ProfileUpdated => {}
+            _ => panic!("Expected a ProfileUpdated event"),
+        }
+        // The stale profile is still known until we refetch it.
+        assert!(fxa.state.last_seen_profile.is_some());
+
+        let p = fxa.get_profile(false).unwrap();
+        assert_eq!(p.email, "new@example.com");
+        assert_eq!(p.avatar, "https://foo.avatar");
+        assert!(!p.avatar_default);
+        // The new profile is cached again.
+        let p = fxa.get_profile(false).unwrap();
+        assert_eq!(p.display_name, Some("Bobo".to_string()));
+    }
+
     #[test]
     fn test_expired_access_token_refetch() {
         let mut fxa =
@@ -13,7 +13,11 @@ use serde_derive::*;
 use serde_json::{self, json};
 
 impl FirefoxAccount {
-    pub(crate) fn get_scoped_key(&self, scope: &str) -> Result<&ScopedKey> {
+    /// Get the scoped key for `scope`, as obtained at the end of the last
+    /// OAuth flow that requested it, or by `migrate_from_session_token`.
+    /// This method will error-out if we don't hold a key for that scope,
+    /// in which case a new OAuth flow requesting it needs to be initiated.
+    pub fn get_scoped_key(&self, scope: &str) -> Result<&ScopedKey> {
         self.state
             .scoped_keys
             .get(scope)
@@ -7,7 +7,9 @@ use crate::{
     commands::send_tab::{self, EncryptedSendTabPayload, PrivateSendTabKeys, PublicSendTabKeys},
     error::*,
     http_client::GetDeviceResponse,
-    scopes, FirefoxAccount,
+    scopes,
+    telemetry::ReceivedReason,
+    util, FirefoxAccount,
 };
 
 impl FirefoxAccount {
@@ -36,22 +38,30 @@ impl FirefoxAccount {
     }
 
     /// Send a single tab to another device designated by its device ID.
-    pub fn send_tab(&self, target_device_id: &str, title: &str, url: &str) -> Result<()> {
+    ///
+    /// The flow and stream IDs of the sent tab are recorded in telemetry,
+    /// see `gather_telemetry`.
+    pub fn send_tab(&mut self, target_device_id: &str, title: &str, url: &str) -> Result<()> {
         let devices = self.get_devices()?;
         let target = devices
             .iter()
             .find(|d| d.id == target_device_id)
             .ok_or_else(|| ErrorKind::UnknownTargetDevice(target_device_id.to_owned()))?;
-        let payload = SendTabPayload::single_tab(title, url);
+        let mut payload = SendTabPayload::single_tab(title, url);
+        payload.flow_id = util::random_base64_url_string(16)?;
+        payload.stream_id = util::random_base64_url_string(16)?;
         let oldsync_key = self.get_scoped_key(scopes::OLD_SYNC)?;
         let command_payload = send_tab::build_send_command(&oldsync_key, target, &payload)?;
-        self.invoke_command(send_tab::COMMAND_NAME, target, &command_payload)
+        self.invoke_command(send_tab::COMMAND_NAME, target, &command_payload)?;
+        self.telemetry.record_tab_sent(&payload);
+        Ok(())
     }
 
     pub(crate) fn handle_send_tab_command(
-        &self,
+        &mut self,
         sender: Option<GetDeviceResponse>,
         payload: serde_json::Value,
+        reason: ReceivedReason,
     ) -> Result<(Option<GetDeviceResponse>, SendTabPayload)> {
         let send_tab_key: PrivateSendTabKeys =
             match self.state.commands_data.get(send_tab::COMMAND_NAME) {
@@ -64,6 +74,8 @@ impl FirefoxAccount {
                 }
             };
         let encrypted_payload: EncryptedSendTabPayload = serde_json::from_value(payload)?;
-        Ok((sender, encrypted_payload.decrypt(&send_tab_key)?))
+        let payload = encrypted_payload.decrypt(&send_tab_key)?;
+        self.telemetry.record_tab_received(&payload, reason);
+        Ok((sender, payload))
     }
 }
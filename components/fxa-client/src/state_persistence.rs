@@ -2,13 +2,22 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::{config::Config, error::*, RefreshToken, ScopedKey, StateV2};
+//! The account state is persisted as JSON, tagged with the version of its
+//! schema. Older versions are only ever deserialized, and are upgraded one
+//! version at a time until they reach the current one: `V1 -> V2 -> V3`.
+//! Whenever `State` changes, add a new version along with the migration from
+//! the previous one, and keep the old structure around below.
+
+use crate::{
+    config::Config, device::Capability as DeviceCapability, error::*, migrator::MigrationData,
+    CachedResponse, Profile, RefreshToken, ScopedKey, StateV3,
+};
 use serde_derive::*;
 use std::{
     collections::{HashMap, HashSet},
     iter::FromIterator,
 };
-type State = StateV2;
+type State = StateV3;
 
 pub(crate) fn state_from_json(data: &str) -> Result<State> {
     let stored_state: PersistedState = serde_json::from_str(data)?;
@@ -16,7 +25,7 @@ pub(crate) fn state_from_json(data: &str) -> Result<State> {
 }
 
 pub(crate) fn state_to_json(state: &State) -> Result<String> {
-    let state = PersistedState::V2(state.clone());
+    let state = PersistedState::V3(state.clone());
     serde_json::to_string(&state).map_err(Into::into)
 }
 
@@ -26,17 +35,41 @@ pub(crate) fn state_to_json(state: &State) -> Result<String> {
 enum PersistedState {
     #[serde(skip_serializing)]
     V1(StateV1),
+    #[serde(skip_serializing)]
     V2(StateV2),
+    V3(StateV3),
 }
 
 fn upgrade_state(in_state: PersistedState) -> Result<State> {
     match in_state {
-        PersistedState::V1(state) => state.into(),
-        PersistedState::V2(state) => Ok(state),
+        PersistedState::V1(state) => {
+            let state: Result<StateV2> = state.into();
+            Ok(state?.into())
+        }
+        PersistedState::V2(state) => Ok(state.into()),
+        PersistedState::V3(state) => Ok(state),
     }
 }
 
 // Migrations
+impl From<StateV2> for StateV3 {
+    fn from(state: StateV2) -> Self {
+        StateV3 {
+            config: state.config,
+            refresh_token: state.refresh_token,
+            scoped_keys: state.scoped_keys,
+            last_handled_command: state.last_handled_command,
+            commands_data: state.commands_data,
+            device_capabilities: state.device_capabilities,
+            session_token: state.session_token,
+            last_seen_profile: state.last_seen_profile,
+            current_device_id: state.current_device_id,
+            current_device_name: state.current_device_name,
+            in_flight_migration: state.in_flight_migration,
+        }
+    }
+}
+
 impl From<StateV1> for Result<StateV2> {
     fn from(state: StateV1) -> Self {
         let mut all_refresh_tokens: Vec<V1AuthInfo> = vec![];
@@ -88,11 +121,36 @@ impl From<StateV1> for Result<StateV2> {
             device_capabilities: HashSet::new(),
             session_token: None,
             last_seen_profile: None,
+            current_device_id: None,
+            current_device_name: None,
+            in_flight_migration: None,
         })
     }
 }
 
 // Older data structures used for migration purposes.
+// Fields were added to V2 over time, so every field that wasn't there from
+// the start defaults to its empty value.
+#[derive(Deserialize)]
+struct StateV2 {
+    config: Config,
+    refresh_token: Option<RefreshToken>,
+    scoped_keys: HashMap<String, ScopedKey>,
+    last_handled_command: Option<u64>,
+    #[serde(default)]
+    commands_data: HashMap<String, String>,
+    #[serde(default)]
+    device_capabilities: HashSet<DeviceCapability>,
+    session_token: Option<String>,
+    last_seen_profile: Option<CachedResponse<Profile>>,
+    #[serde(default)]
+    current_device_id: Option<String>,
+    #[serde(default)]
+    current_device_name: Option<String>,
+    #[serde(default)]
+    in_flight_migration: Option<MigrationData>,
+}
+
 #[derive(Deserialize)]
 struct StateV1 {
     client_id: String,
@@ -165,4 +223,182 @@ mod tests {
             "https://identity.mozilla.com/apps/lockbox"
         );
     }
+
+    fn v2_config() -> serde_json::Value {
+        serde_json::to_value(Config::stable_dev("12345678", "https://foo.bar")).unwrap()
+    }
+
+    #[test]
+    fn test_v2_migration() {
+        // The earliest V2 states didn't have most of the fields.
+        let state_v2_json = serde_json::json!({
+            "schema_version": "V2",
+            "config": v2_config(),
+            "refresh_token": {
+                "token": "refreshtok",
+                "scopes": ["profile"],
+            },
+            "scoped_keys": {},
+            "last_handled_command": 12,
+            "session_token": null,
+            "last_seen_profile": null,
+        })
+        .to_string();
+        let state = state_from_json(&state_v2_json).unwrap();
+        assert_eq!(state.config.client_id, "12345678");
+        assert_eq!(state.refresh_token.unwrap().token, "refreshtok");
+        assert_eq!(state.last_handled_command, Some(12));
+        assert!(state.commands_data.is_empty());
+        assert!(state.device_capabilities.is_empty());
+        assert!(state.current_device_id.is_none());
+        assert!(state.current_device_name.is_none());
+        assert!(state.in_flight_migration.is_none());
+    }
+
+    #[test]
+    fn test_v2_migration_keeps_all_fields() {
+        let state_v2_json = serde_json::json!({
+            "schema_version": "V2",
+            "config": v2_config(),
+            "refresh_token": null,
+            "scoped_keys": {
+                "https://identity.mozilla.com/apps/oldsync": {
+                    "kty": "oct",
+                    "scope": "https://identity.mozilla.com/apps/oldsync",
+                    "k": "kMtwpVC0ZaYFJymPza8rXK_0CgCp3KMwRStwGfBRBDtL6hXRDVJgQFaoOQ2dimw0Bko5WVv2gNTy7RX5zFYZHg",
+                    "kid": "1542236016429-Ox1FbJfFfwTe5t-xq4v2hQ",
+                },
+            },
+            "last_handled_command": null,
+            "commands_data": {
+                "https://identity.mozilla.com/cmd/open-uri": "keys",
+            },
+            "device_capabilities": ["SendTab"],
+            "session_token": "abcd",
+            "last_seen_profile": {
+                "response": {
+                    "uid": "12345ab",
+                    "email": "foo@bar.com",
+                    "locale": "en-US",
+                    "displayName": null,
+                    "avatar": "https://foo.avatar",
+                    "avatarDefault": true,
+                    "amrValues": [],
+                    "twoFactorAuthentication": false,
+                },
+                "cached_at": 1000,
+                "etag": "etag",
+            },
+            "current_device_id": "us",
+            "current_device_name": "My phone",
+            "in_flight_migration": {
+                "session_token": "abcd",
+                "k_sync": "1234",
+                "k_xcs": "5678",
+            },
+        })
+        .to_string();
+        let state = state_from_json(&state_v2_json).unwrap();
+        assert!(state.refresh_token.is_none());
+        assert_eq!(
+            state.scoped_keys["https://identity.mozilla.com/apps/oldsync"].kid,
+            "1542236016429-Ox1FbJfFfwTe5t-xq4v2hQ"
+        );
+        assert_eq!(
+            state.commands_data["https://identity.mozilla.com/cmd/open-uri"],
+            "keys"
+        );
+        assert!(state
+            .device_capabilities
+            .contains(&DeviceCapability::SendTab));
+        assert_eq!(state.session_token.unwrap(), "abcd");
+        assert_eq!(state.last_seen_profile.unwrap().response.uid, "12345ab");
+        assert_eq!(state.current_device_id.unwrap(), "us");
+        assert_eq!(state.current_device_name.unwrap(), "My phone");
+        assert!(state.in_flight_migration.is_some());
+    }
+
+    #[test]
+    fn test_state_is_persisted_as_v3() {
+        let state_v2_json = serde_json::json!({
+            "schema_version": "V2",
+            "config": v2_config(),
+            "refresh_token": null,
+            "scoped_keys": {},
+            "last_handled_command": null,
+            "session_token": "abcd",
+            "last_seen_profile": null,
+            "current_device_id": "us",
+        })
+        .to_string();
+        let state = state_from_json(&state_v2_json).unwrap();
+        let json = state_to_json(&state).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["schema_version"], "V3");
+        // Fields which were missing in V2 are now written out.
+        assert_eq!(value["device_capabilities"], serde_json::json!([]));
+        assert_eq!(value["in_flight_migration"], serde_json::Value::Null);
+
+        let state = state_from_json(&json).unwrap();
+        assert_eq!(state.session_token.unwrap(), "abcd");
+        assert_eq!(state.current_device_id.unwrap(), "us");
+    }
+
+    #[test]
+    fn test_v3_requires_all_fields() {
+        let state_v3_json = serde_json::json!({
+            "schema_version": "V3",
+            "config": v2_config(),
+            "refresh_token": null,
+            "scoped_keys": {},
+            "last_handled_command": null,
+            "session_token": null,
+            "last_seen_profile": null,
+        })
+        .to_string();
+        assert!(state_from_json(&state_v3_json).is_err());
+    }
+
+    #[test]
+    fn test_v3_config_without_token_server_url_override() {
+        // Accounts persisted before the override was added don't have it.
+        let mut config = v2_config();
+        config
+            .as_object_mut()
+            .unwrap()
+            .remove("token_server_url_override")
+            .expect("should serialize the override");
+        let state_v3_json = serde_json::json!({
+            "schema_version": "V3",
+            "config": config,
+            "refresh_token": null,
+            "scoped_keys": {},
+            "last_handled_command": null,
+            "commands_data": {},
+            "device_capabilities": [],
+            "session_token": null,
+            "last_seen_profile": null,
+            "current_device_id": null,
+            "current_device_name": null,
+            "in_flight_migration": null,
+        })
+        .to_string();
+        let state = state_from_json(&state_v3_json).unwrap();
+        let json = state_to_json(&state).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            value["config"]["token_server_url_override"],
+            serde_json::Value::Null
+        );
+    }
+
+    #[test]
+    fn test_unknown_schema_version() {
+        let state_json = serde_json::json!({
+            "schema_version": "V42",
+            "config": v2_config(),
+        })
+        .to_string();
+        assert!(state_from_json(&state_json).is_err());
+    }
 }
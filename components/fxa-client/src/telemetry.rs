@@ -0,0 +1,139 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::{commands::send_tab::SendTabPayload, error::*, FirefoxAccount};
+use serde_derive::*;
+
+impl FirefoxAccount {
+    /// Gathers and resets telemetry for this account instance.
+    /// The result is a JSON string, intended to be recorded by the
+    /// application alongside the desktop send-tab metrics.
+    pub fn gather_telemetry(&mut self) -> Result<String> {
+        let telemetry = std::mem::replace(&mut self.telemetry, FxaTelemetry::new());
+        Ok(serde_json::to_string(&telemetry)?)
+    }
+}
+
+// A container for all telemetry recorded by the component.
+// It is not persisted, so anything that wasn't gathered before the
+// account instance is dropped is lost.
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct FxaTelemetry {
+    commands_sent: Vec<SentCommand>,
+    commands_received: Vec<ReceivedCommand>,
+    commands_failed: Vec<FailedCommand>,
+}
+
+impl FxaTelemetry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_tab_sent(&mut self, payload: &SendTabPayload) {
+        self.commands_sent.push(SentCommand {
+            flow_id: payload.flow_id.clone(),
+            stream_id: payload.stream_id.clone(),
+        });
+    }
+
+    pub fn record_tab_received(&mut self, payload: &SendTabPayload, reason: ReceivedReason) {
+        self.commands_received.push(ReceivedCommand {
+            flow_id: payload.flow_id.clone(),
+            stream_id: payload.stream_id.clone(),
+            reason,
+        });
+    }
+
+    pub fn record_command_failed(&mut self, error: &Error, reason: ReceivedReason) {
+        self.commands_failed.push(FailedCommand {
+            error: error.to_string(),
+            reason,
+        });
+    }
+}
+
+/// Why a command was received.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ReceivedReason {
+    /// A push message told us about the command.
+    Push,
+    /// We found the command while polling for missed commands.
+    Poll,
+}
+
+#[derive(Debug, Serialize)]
+struct SentCommand {
+    flow_id: String,
+    stream_id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ReceivedCommand {
+    flow_id: String,
+    stream_id: String,
+    reason: ReceivedReason,
+}
+
+// A command we received but couldn't process. We don't know its flow ID,
+// since that lives in the (possibly undecryptable) payload.
+#[derive(Debug, Serialize)]
+struct FailedCommand {
+    error: String,
+    reason: ReceivedReason,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gather_telemetry() {
+        let mut fxa =
+            FirefoxAccount::new("https://stable.dev.lcip.org", "12345678", "https://foo.bar");
+        let mut payload = SendTabPayload::single_tab("Mozilla", "https://mozilla.org");
+        payload.flow_id = "flow".to_owned();
+        payload.stream_id = "stream".to_owned();
+        fxa.telemetry.record_tab_sent(&payload);
+        fxa.telemetry
+            .record_tab_received(&payload, ReceivedReason::Push);
+        fxa.telemetry.record_command_failed(
+            &ErrorKind::UnknownCommand("foo".to_owned()).into(),
+            ReceivedReason::Poll,
+        );
+
+        let telemetry: serde_json::Value =
+            serde_json::from_str(&fxa.gather_telemetry().unwrap()).unwrap();
+        assert_eq!(
+            telemetry,
+            serde_json::json!({
+                "commands_sent": [{
+                    "flow_id": "flow",
+                    "stream_id": "stream",
+                }],
+                "commands_received": [{
+                    "flow_id": "flow",
+                    "stream_id": "stream",
+                    "reason": "push",
+                }],
+                "commands_failed": [{
+                    "error": "Unknown command: foo",
+                    "reason": "poll",
+                }],
+            })
+        );
+
+        // Gathering resets the telemetry.
+        let telemetry: serde_json::Value =
+            serde_json::from_str(&fxa.gather_telemetry().unwrap()).unwrap();
+        assert_eq!(
+            telemetry,
+            serde_json::json!({
+                "commands_sent": [],
+                "commands_received": [],
+                "commands_failed": [],
+            })
+        );
+    }
+}
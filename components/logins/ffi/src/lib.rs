@@ -103,6 +103,8 @@ pub extern "C" fn sync15_passwords_sync(
                 key_id: key_id.into_string(),
                 access_token: access_token.into_string(),
                 tokenserver_url: parse_url(tokenserver_url.as_str())?,
+                access_token_refresher: None,
+                interceptors: vec![],
             },
             &sync15::KeyBundle::from_ksync_base64(sync_key.as_str())?,
         )?;
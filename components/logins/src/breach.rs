@@ -0,0 +1,350 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Checking saved passwords against a breached password database.
+//!
+//! This uses the k-anonymity "range" API that [Have I Been
+//! Pwned](https://haveibeenpwned.com/API/v3#PwnedPasswords) provides. We never
+//! send the password, or even its full hash: only the first five hex
+//! characters of its SHA-1 hash. The server responds with the remaining
+//! characters of every breached hash with that prefix, and how many times each
+//! one was seen, so the server can't tell which password we were asking about.
+//!
+//! The results are cached in the `loginsBreachCache` table, so that checking
+//! again doesn't need to look up passwords that were checked recently, and
+//! haven't changed since.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::util;
+use rc_crypto::digest;
+use rusqlite::{named_params, Row, NO_PARAMS};
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{Duration, SystemTime};
+use sync_guid::Guid;
+use url::Url;
+use viaduct::Request;
+
+/// The Have I Been Pwned range endpoint.
+pub const DEFAULT_RANGE_ENDPOINT: &str = "https://api.pwnedpasswords.com/range/";
+
+/// The number of hex characters of the hash that we send to the server.
+const PREFIX_LEN: usize = 5;
+
+#[derive(Debug, Clone)]
+pub struct BreachCheckConfig {
+    /// The URL of the range endpoint. The hash prefix is appended to it, so it
+    /// should end with a `/`.
+    pub range_endpoint: Url,
+    /// How long a cached result is used before we check the password again.
+    pub max_cache_age: Duration,
+}
+
+impl Default for BreachCheckConfig {
+    fn default() -> Self {
+        Self {
+            range_endpoint: Url::parse(DEFAULT_RANGE_ENDPOINT).unwrap(),
+            max_cache_age: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+/// A saved login whose password appears in the breached password database.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BreachedLogin {
+    pub guid: Guid,
+    /// The number of times the password was seen in breaches.
+    pub breach_count: u64,
+}
+
+struct CachedCheck {
+    time_password_changed: i64,
+    breach_count: u64,
+}
+
+impl CachedCheck {
+    fn from_row(row: &Row<'_>) -> Result<(Guid, CachedCheck)> {
+        Ok((
+            row.get("guid")?,
+            CachedCheck {
+                time_password_changed: row.get("time_password_changed")?,
+                breach_count: row.get::<_, i64>("breach_count")? as u64,
+            },
+        ))
+    }
+}
+
+// A login that needs to be looked up, because it isn't in the cache, or its
+// password changed since it was cached.
+struct PendingCheck {
+    guid: Guid,
+    time_password_changed: i64,
+    hash_suffix: String,
+}
+
+// Removes cached results that are too old, and ones for logins that have been
+// deleted.
+const PRUNE_CACHE_SQL: &str = "
+    DELETE FROM loginsBreachCache
+    WHERE checked_at < :oldest
+       OR guid NOT IN (
+           SELECT guid FROM loginsL WHERE is_deleted = 0
+           UNION ALL
+           SELECT guid FROM loginsM WHERE is_overridden = 0
+       )";
+
+const PUT_CACHED_CHECK_SQL: &str = "
+    REPLACE INTO loginsBreachCache (guid, time_password_changed, breach_count, checked_at)
+    VALUES (:guid, :time_password_changed, :breach_count, :checked_at)";
+
+impl LoginDb {
+    /// Checks every saved password against the breached password database at
+    /// `config.range_endpoint`, and returns the logins whose passwords were
+    /// found. Passwords that were checked less than `config.max_cache_age`
+    /// ago, and haven't changed since, aren't looked up again.
+    pub fn check_breached_passwords(
+        &self,
+        config: &BreachCheckConfig,
+    ) -> Result<Vec<BreachedLogin>> {
+        let scope = self.begin_interrupt_scope();
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let max_age_ms =
+            i64::try_from(config.max_cache_age.as_millis()).unwrap_or_else(|_| i64::max_value());
+        self.execute_named_cached(
+            PRUNE_CACHE_SQL,
+            named_params! { ":oldest": now_ms.saturating_sub(max_age_ms) },
+        )?;
+        let cached: HashMap<Guid, CachedCheck> = self.query_rows_into(
+            "SELECT guid, time_password_changed, breach_count FROM loginsBreachCache",
+            &[],
+            CachedCheck::from_row,
+        )?;
+
+        let logins = self.get_all()?;
+        let mut counts: HashMap<Guid, u64> = HashMap::new();
+        // Logins to look up, grouped by hash prefix, so that we only request
+        // each range once.
+        let mut pending: HashMap<String, Vec<PendingCheck>> = HashMap::new();
+        for login in &logins {
+            match cached.get(&login.guid) {
+                Some(check) if check.time_password_changed == login.time_password_changed => {
+                    counts.insert(login.guid.clone(), check.breach_count);
+                }
+                _ => {
+                    let hash = sha1_hex(&login.password)?;
+                    let (prefix, suffix) = hash.split_at(PREFIX_LEN);
+                    pending
+                        .entry(prefix.to_owned())
+                        .or_default()
+                        .push(PendingCheck {
+                            guid: login.guid.clone(),
+                            time_password_changed: login.time_password_changed,
+                            hash_suffix: suffix.to_owned(),
+                        });
+                }
+            }
+        }
+        scope.err_if_interrupted()?;
+
+        for (prefix, checks) in pending {
+            let range = fetch_range(&config.range_endpoint, &prefix)?;
+            scope.err_if_interrupted()?;
+            // Cache each range as we fetch it, so that a network error partway
+            // through doesn't throw away the lookups that succeeded.
+            let tx = self.unchecked_transaction()?;
+            for check in checks {
+                let breach_count = range.get(&check.hash_suffix).cloned().unwrap_or(0);
+                self.execute_named_cached(
+                    PUT_CACHED_CHECK_SQL,
+                    named_params! {
+                        ":guid": check.guid,
+                        ":time_password_changed": check.time_password_changed,
+                        ":breach_count": breach_count as i64,
+                        ":checked_at": now_ms,
+                    },
+                )?;
+                counts.insert(check.guid, breach_count);
+            }
+            tx.commit()?;
+        }
+
+        Ok(logins
+            .into_iter()
+            .filter_map(|login| match counts.get(&login.guid) {
+                Some(&breach_count) if breach_count > 0 => Some(BreachedLogin {
+                    guid: login.guid,
+                    breach_count,
+                }),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Forgets every cached result, so that the next check looks up every
+    /// password again.
+    pub fn clear_breach_cache(&self) -> Result<()> {
+        self.execute("DELETE FROM loginsBreachCache", NO_PARAMS)?;
+        Ok(())
+    }
+}
+
+// Returns the uppercase hex SHA-1 hash of `password`, which is the format the
+// range API uses.
+fn sha1_hex(password: &str) -> Result<String> {
+    let hash = digest::digest(&digest::SHA1, password.as_bytes())?;
+    Ok(hex::encode_upper(hash.as_ref()))
+}
+
+// Fetches the range of breached hashes that start with `prefix`, returning
+// a map of the rest of each hash to the number of times it was seen.
+fn fetch_range(endpoint: &Url, prefix: &str) -> Result<HashMap<String, u64>> {
+    let url = endpoint.join(prefix)?;
+    // Ask the server to pad the response with fake entries (with a count of
+    // 0), so that the size of the response doesn't reveal the prefix.
+    let resp = Request::get(url)
+        .header("Add-Padding", "true")?
+        .send()?
+        .require_success()?;
+    Ok(parse_range(&resp.text()))
+}
+
+fn parse_range(text: &str) -> HashMap<String, u64> {
+    text.lines()
+        .filter_map(|line| {
+            let mut parts = line.trim().splitn(2, ':');
+            let suffix = parts.next()?;
+            let count = parts.next()?.parse::<u64>().ok()?;
+            if count == 0 {
+                // Padding.
+                return None;
+            }
+            Some((suffix.to_ascii_uppercase(), count))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::Login;
+    use mockito::{mock, server_address};
+
+    fn add_login(db: &LoginDb, guid: &str, password: &str) -> Login {
+        db.add(Login {
+            guid: guid.into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "user".into(),
+            password: password.into(),
+            ..Login::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let range = parse_range(
+            "0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n\
+             00D4F6E8FA6EECAD2A3AA415EEC418D38EC:2\r\n\
+             011053FD0102E94D6AE2F8B83D76FAF94F6:0\r\n\
+             garbage\r\n",
+        );
+        assert_eq!(range.len(), 2);
+        assert_eq!(range["0018A45C4D1DEF81644B54AB7F969B88D65"], 1);
+        assert_eq!(range["00D4F6E8FA6EECAD2A3AA415EEC418D38EC"], 2);
+    }
+
+    #[test]
+    fn test_check_breached_passwords() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        // SHA-1("password") is 5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8.
+        add_login(&db, "dummy_000001", "password");
+        // SHA-1("hunter2") is F3BBBD66A63D4BF1747940578EC3D0103530E21D.
+        add_login(&db, "dummy_000002", "hunter2");
+        // SHA-1("not in any breach") is 2758214B342CE2ED74EA1C97258268F961C9154F.
+        add_login(&db, "dummy_000003", "not in any breach");
+
+        let config = BreachCheckConfig {
+            range_endpoint: Url::parse(&format!("http://{}/range/", server_address())).unwrap(),
+            ..BreachCheckConfig::default()
+        };
+        let expected = vec![BreachedLogin {
+            guid: "dummy_000001".into(),
+            breach_count: 3_730_471,
+        }];
+
+        {
+            let m1 = mock("GET", "/range/5BAA6")
+                .match_header("Add-Padding", "true")
+                .with_status(200)
+                .with_body(
+                    "1E4C9B93F3F0682250B6CF8331B7EE68FD8:3730471\r\n\
+                     1D72CD07550416C216D8AD296BF5C0AE8E0:10\r\n",
+                )
+                .create();
+            let m2 = mock("GET", "/range/F3BBB")
+                .with_status(200)
+                .with_body("0000000000000000000000000000000000A:0\r\n")
+                .create();
+            let m3 = mock("GET", "/range/27582")
+                .with_status(200)
+                .with_body("")
+                .create();
+            let breached = db.check_breached_passwords(&config).unwrap();
+            m1.assert();
+            m2.assert();
+            m3.assert();
+            assert_eq!(breached, expected);
+        }
+
+        // Everything is cached now, so checking again doesn't make any
+        // requests.
+        {
+            let m = mock("GET", mockito::Matcher::Any).expect(0).create();
+            assert_eq!(db.check_breached_passwords(&config).unwrap(), expected);
+            m.assert();
+        }
+
+        // Changing a password means it's looked up again.
+        {
+            let mut login = db.get_by_id("dummy_000002").unwrap().unwrap();
+            login.password = "password".into();
+            db.update(login).unwrap();
+            let m = mock("GET", "/range/5BAA6")
+                .with_status(200)
+                .with_body("1E4C9B93F3F0682250B6CF8331B7EE68FD8:3730471\r\n")
+                .expect(1)
+                .create();
+            let mut breached = db.check_breached_passwords(&config).unwrap();
+            m.assert();
+            breached.sort_by(|a, b| a.guid.cmp(&b.guid));
+            assert_eq!(
+                breached,
+                vec![
+                    expected[0].clone(),
+                    BreachedLogin {
+                        guid: "dummy_000002".into(),
+                        breach_count: 3_730_471,
+                    },
+                ]
+            );
+        }
+
+        // Errors are reported, and don't poison the cache.
+        {
+            db.clear_breach_cache().unwrap();
+            let m = mock("GET", mockito::Matcher::Any).with_status(503).create();
+            assert!(db.check_breached_passwords(&config).is_err());
+            m.assert();
+            let count: i64 = db
+                .query_one("SELECT COUNT(*) FROM loginsBreachCache")
+                .unwrap();
+            assert_eq!(count, 0);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Exporting logins as CSV, in the same format as Firefox Desktop, which
+//! Chrome and other password managers can import, and so can
+//! `LoginDb::import_csv`.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use std::io::Write;
+
+impl LoginDb {
+    /// Writes every login to `writer` as CSV. Passwords are only included
+    /// if `include_passwords` is true; otherwise the password column is left
+    /// out entirely, so the file can't be mistaken for a complete backup.
+    pub fn export_csv(&self, writer: impl Write, include_passwords: bool) -> Result<()> {
+        let mut writer = csv::WriterBuilder::new()
+            .quote_style(csv::QuoteStyle::Always)
+            .from_writer(writer);
+        let mut header = vec!["url", "username"];
+        if include_passwords {
+            header.push("password");
+        }
+        header.extend_from_slice(&[
+            "httpRealm",
+            "formActionOrigin",
+            "guid",
+            "timeCreated",
+            "timeLastUsed",
+            "timePasswordChanged",
+        ]);
+        writer.write_record(&header)?;
+
+        for login in self.get_all()? {
+            let mut row = vec![login.hostname, login.username];
+            if include_passwords {
+                row.push(login.password);
+            }
+            row.extend(vec![
+                login.http_realm.unwrap_or_default(),
+                login.form_submit_url.unwrap_or_default(),
+                login.guid.into_string(),
+                login.time_created.to_string(),
+                login.time_last_used.to_string(),
+                login.time_password_changed.to_string(),
+            ]);
+            writer.write_record(&row)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::Login;
+
+    #[test]
+    fn test_export() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = db
+            .add(Login {
+                guid: "dummy_000001".into(),
+                hostname: "https://www.example.com".into(),
+                form_submit_url: Some("https://www.example.com".into()),
+                username: "user".into(),
+                password: "pass,\"word\"".into(),
+                ..Login::default()
+            })
+            .unwrap();
+
+        let mut without_passwords = Vec::new();
+        db.export_csv(&mut without_passwords, false).unwrap();
+        assert_eq!(
+            String::from_utf8(without_passwords).unwrap(),
+            format!(
+                "\"url\",\"username\",\"httpRealm\",\"formActionOrigin\",\"guid\",\"timeCreated\",\"timeLastUsed\",\"timePasswordChanged\"\n\
+                 \"https://www.example.com\",\"user\",\"\",\"https://www.example.com\",\"dummy_000001\",\"{t}\",\"{t}\",\"{t}\"\n",
+                t = login.time_created
+            )
+        );
+
+        let mut with_passwords = Vec::new();
+        db.export_csv(&mut with_passwords, true).unwrap();
+        let csv = String::from_utf8(with_passwords).unwrap();
+        assert!(csv.contains("\"pass,\"\"word\"\"\""));
+
+        // And the export can be imported again.
+        let other = LoginDb::open_in_memory(Some("testing")).unwrap();
+        other.import_csv(csv.as_bytes()).unwrap();
+        let imported = other.get_by_id("dummy_000001").unwrap().unwrap();
+        assert_eq!(imported.password, login.password);
+        assert_eq!(imported.form_submit_url, login.form_submit_url);
+        assert_eq!(imported.time_created, login.time_created);
+    }
+}
@@ -0,0 +1,334 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Importing logins from the CSV files exported by other password managers.
+//!
+//! We find the columns by name, so the same code handles Chrome's
+//! (`name,url,username,password`), Firefox's (`url,username,password,
+//! httpRealm,formActionOrigin,guid,timeCreated,...`) and LastPass's
+//! (`url,username,password,extra,name,grouping,fav`) dialects, along with
+//! anything else that uses the same column names.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::{normalize_origin, Login};
+use crate::util;
+use rusqlite::named_params;
+use serde_derive::*;
+use sql_support::ConnExt;
+use std::io::Read;
+use std::time::SystemTime;
+use sync_guid::Guid;
+
+// The number of rows we import in each transaction.
+const IMPORT_BATCH_SIZE: usize = 500;
+
+// LastPass exports secure notes as rows with this URL.
+const LASTPASS_SECURE_NOTE_URL: &str = "http://sn";
+
+/// What happened to a row of an imported CSV file.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "camelCase")]
+pub enum CsvImportStatus {
+    /// The login was imported as a new record with this GUID.
+    Imported { guid: Guid },
+    /// We already have this login, so the row was skipped.
+    SkippedDuplicate { existing_guid: Guid },
+    /// The row wasn't a valid login.
+    Error { reason: String },
+}
+
+/// The result of importing a row, for the import summary.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CsvImportRow {
+    /// The line of the file the row started on, counting from 1 (the header).
+    pub line: u64,
+    #[serde(flatten)]
+    pub status: CsvImportStatus,
+}
+
+// Where the columns we know about are in the file.
+struct Columns {
+    url: usize,
+    username: Option<usize>,
+    password: usize,
+    http_realm: Option<usize>,
+    form_action_origin: Option<usize>,
+    guid: Option<usize>,
+    time_created: Option<usize>,
+    time_last_used: Option<usize>,
+    time_password_changed: Option<usize>,
+}
+
+impl Columns {
+    fn new(headers: &csv::StringRecord) -> Result<Self> {
+        let find = |names: &[&str]| {
+            headers
+                .iter()
+                .position(|h| names.iter().any(|name| h.trim().eq_ignore_ascii_case(name)))
+        };
+        Ok(Columns {
+            url: find(&["url", "origin", "hostname"])
+                .ok_or_else(|| ErrorKind::InvalidCsv("Missing URL column".into()))?,
+            username: find(&["username", "login"]),
+            password: find(&["password"])
+                .ok_or_else(|| ErrorKind::InvalidCsv("Missing password column".into()))?,
+            http_realm: find(&["httpRealm"]),
+            form_action_origin: find(&["formActionOrigin", "formSubmitURL"]),
+            guid: find(&["guid"]),
+            time_created: find(&["timeCreated"]),
+            time_last_used: find(&["timeLastUsed"]),
+            time_password_changed: find(&["timePasswordChanged"]),
+        })
+    }
+
+    // Builds a login from a row, or returns why it isn't one.
+    fn login(&self, row: &csv::StringRecord, now_ms: i64) -> std::result::Result<Login, String> {
+        let get = |col: Option<usize>| {
+            col.and_then(|i| row.get(i))
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+        };
+        let get_time = |col: Option<usize>| get(col).and_then(|v| v.parse::<i64>().ok());
+
+        let url = get(Some(self.url)).ok_or_else(|| "Missing URL".to_string())?;
+        if url == LASTPASS_SECURE_NOTE_URL {
+            return Err("Not a login".into());
+        }
+        let hostname = normalize_origin(url).ok_or_else(|| "Invalid URL".to_string())?;
+        let http_realm = get(self.http_realm).map(ToString::to_string);
+        // Without a realm or form action, assume it's a form on the same
+        // site, which is what the other browsers do.
+        let form_submit_url = match (http_realm.as_ref(), get(self.form_action_origin)) {
+            (Some(_), _) => None,
+            (None, Some(action)) => {
+                Some(normalize_origin(action).unwrap_or_else(|| hostname.clone()))
+            }
+            (None, None) => Some(hostname.clone()),
+        };
+        let guid = get(self.guid)
+            .map(Guid::from)
+            .filter(Guid::is_valid_for_sync_server)
+            .unwrap_or_else(Guid::random);
+        let time_created = get_time(self.time_created).unwrap_or(now_ms);
+        let login = Login {
+            guid,
+            hostname,
+            form_submit_url,
+            http_realm,
+            username: get(self.username).unwrap_or_default().to_string(),
+            // Passwords might deliberately start or end with spaces.
+            password: row.get(self.password).unwrap_or_default().to_string(),
+            time_created,
+            time_last_used: get_time(self.time_last_used).unwrap_or(time_created),
+            time_password_changed: get_time(self.time_password_changed).unwrap_or(time_created),
+            times_used: 1,
+            ..Login::default()
+        };
+        login.check_valid().map_err(|e| e.to_string())?;
+        Ok(login)
+    }
+}
+
+impl LoginDb {
+    /// Imports the logins in a CSV file, returning what happened to each
+    /// row. Rows that fail to parse, or aren't valid logins, are reported as
+    /// errors, and logins we already have are skipped. Fails without
+    /// importing anything if the file doesn't have columns for the URL and
+    /// password.
+    pub fn import_csv(&self, reader: impl Read) -> Result<Vec<CsvImportRow>> {
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+        let columns = Columns::new(reader.headers()?)?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let mut results = Vec::new();
+        let mut records = reader.records().peekable();
+        while records.peek().is_some() {
+            let tx = self.unchecked_transaction()?;
+            for record in records.by_ref().take(IMPORT_BATCH_SIZE) {
+                let (line, status) = match record {
+                    Ok(row) => (
+                        row.position().map_or(0, csv::Position::line),
+                        match columns.login(&row, now_ms) {
+                            Ok(login) => self.import_csv_login(login, now_ms)?,
+                            Err(reason) => CsvImportStatus::Error { reason },
+                        },
+                    ),
+                    Err(e) => (
+                        e.position().map_or(0, csv::Position::line),
+                        CsvImportStatus::Error {
+                            reason: e.to_string(),
+                        },
+                    ),
+                };
+                if let CsvImportStatus::Error { reason } = &status {
+                    log::warn!("Skipping CSV line {}: {}", line, reason);
+                }
+                results.push(CsvImportRow { line, status });
+            }
+            tx.commit()?;
+        }
+        Ok(results)
+    }
+
+    fn import_csv_login(&self, login: Login, now_ms: i64) -> Result<CsvImportStatus> {
+        if let Some(existing_guid) = self.find_csv_dupe(&login)? {
+            return Ok(CsvImportStatus::SkippedDuplicate { existing_guid });
+        }
+        Ok(if self.insert_new_login(&login, now_ms)? == 0 {
+            // Firefox exports include the GUID, so this means we're importing
+            // a file we exported ourselves.
+            CsvImportStatus::SkippedDuplicate {
+                existing_guid: login.guid,
+            }
+        } else {
+            CsvImportStatus::Imported { guid: login.guid }
+        })
+    }
+
+    // The CSV dialects without a form action don't tell us anything about the
+    // target, so any form login for the same site and username is a dupe.
+    fn find_csv_dupe(&self, login: &Login) -> Result<Option<Guid>> {
+        Ok(self.try_query_row(
+            "SELECT guid FROM loginsL
+             WHERE is_deleted = 0
+               AND hostname = :hostname
+               AND username = :username
+               AND (CASE WHEN :http_realm IS NULL
+                         THEN formSubmitURL IS NOT NULL
+                         ELSE httpRealm = :http_realm END)
+             UNION ALL
+             SELECT guid FROM loginsM
+             WHERE is_overridden = 0
+               AND hostname = :hostname
+               AND username = :username
+               AND (CASE WHEN :http_realm IS NULL
+                         THEN formSubmitURL IS NOT NULL
+                         ELSE httpRealm = :http_realm END)
+             LIMIT 1",
+            named_params! {
+                ":hostname": login.hostname,
+                ":username": login.username,
+                ":http_realm": login.http_realm,
+            },
+            |row| row.get::<_, Guid>(0),
+            true,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn statuses(results: &[CsvImportRow]) -> Vec<(u64, &'static str)> {
+        results
+            .iter()
+            .map(|r| {
+                let status = match r.status {
+                    CsvImportStatus::Imported { .. } => "imported",
+                    CsvImportStatus::SkippedDuplicate { .. } => "duplicate",
+                    CsvImportStatus::Error { .. } => "error",
+                };
+                (r.line, status)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_import_chrome() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let csv = "name,url,username,password\n\
+                   example.com,https://www.example.com/login?foo,user, p4ss \n\
+                   example.com,https://www.example.com/other,user,other\n\
+                   example.com,https://www.example.com,,nouser\n\
+                   bad,not a url,user,password\n\
+                   empty,https://empty.example.com,user,\n";
+        let results = db.import_csv(csv.as_bytes()).unwrap();
+        assert_eq!(
+            statuses(&results),
+            vec![
+                (2, "imported"),
+                (3, "duplicate"),
+                (4, "imported"),
+                (5, "error"),
+                (6, "error"),
+            ]
+        );
+
+        let guid = match &results[0].status {
+            CsvImportStatus::Imported { guid } => guid.clone(),
+            s => panic!("unexpected status {:?}", s),
+        };
+        let login = db.get_by_id(guid.as_str()).unwrap().unwrap();
+        assert_eq!(login.hostname, "https://www.example.com");
+        assert_eq!(
+            login.form_submit_url.as_ref().map(String::as_str),
+            Some("https://www.example.com")
+        );
+        assert_eq!(login.username, "user");
+        assert_eq!(login.password, " p4ss ");
+        assert_eq!(db.get_all().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_import_firefox() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let csv = "\"url\",\"username\",\"password\",\"httpRealm\",\"formActionOrigin\",\"guid\",\"timeCreated\",\"timeLastUsed\",\"timePasswordChanged\"\n\
+                   \"https://example.com\",\"user\",\"pass\",\"My Realm\",,\"dummy_000001\",\"1000\",\"3000\",\"2000\"\n\
+                   \"https://example.com\",\"user\",\"pass\",,\"https://login.example.com/form\",\"abcd,efgh\",\"1000\",,\n";
+        let results = db.import_csv(csv.as_bytes()).unwrap();
+        assert_eq!(statuses(&results), vec![(2, "imported"), (3, "imported")]);
+
+        let login = db.get_by_id("dummy_000001").unwrap().unwrap();
+        assert_eq!(
+            login.http_realm.as_ref().map(String::as_str),
+            Some("My Realm")
+        );
+        assert!(login.form_submit_url.is_none());
+        assert_eq!(login.time_created, 1000);
+        assert_eq!(login.time_last_used, 3000);
+        assert_eq!(login.time_password_changed, 2000);
+
+        // The second GUID isn't valid for sync, so it gets a new one.
+        match &results[1].status {
+            CsvImportStatus::Imported { guid } => {
+                assert_ne!(guid, "abcd,efgh");
+                let login = db.get_by_id(guid.as_str()).unwrap().unwrap();
+                assert_eq!(
+                    login.form_submit_url.as_ref().map(String::as_str),
+                    Some("https://login.example.com")
+                );
+                assert_eq!(login.time_last_used, 1000);
+            }
+            s => panic!("unexpected status {:?}", s),
+        }
+
+        // Importing the same file again doesn't add anything.
+        let results = db.import_csv(csv.as_bytes()).unwrap();
+        assert_eq!(statuses(&results), vec![(2, "duplicate"), (3, "duplicate")]);
+    }
+
+    #[test]
+    fn test_import_lastpass() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let csv = "url,username,password,totp,extra,name,grouping,fav\n\
+                   https://example.com/,user,pass,,,Example,,0\n\
+                   http://sn,,,,\"NoteType:Credit Card\",Card,,0\n";
+        let results = db.import_csv(csv.as_bytes()).unwrap();
+        assert_eq!(statuses(&results), vec![(2, "imported"), (3, "error")]);
+    }
+
+    #[test]
+    fn test_import_missing_columns() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        match db.import_csv("url,username\n".as_bytes()) {
+            Err(e) => match e.kind() {
+                ErrorKind::InvalidCsv(_) => {}
+                k => panic!("unexpected error {:?}", k),
+            },
+            Ok(_) => panic!("should fail without a password column"),
+        }
+    }
+}
@@ -15,7 +15,7 @@ use rusqlite::{
 };
 use sql_support::{self, ConnExt};
 use sql_support::{SqlInterruptHandle, SqlInterruptScope};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::ops::Deref;
 use std::path::Path;
 use std::result;
@@ -26,6 +26,7 @@ use sync15::{
     OutgoingChangeset, Payload, ServerTimestamp, Store, StoreSyncAssociation,
 };
 use sync_guid::Guid;
+use url::{Host, Url};
 
 pub struct LoginDb {
     pub db: Connection,
@@ -40,18 +41,7 @@ impl LoginDb {
         }
 
         if let Some(key) = encryption_key {
-            db.set_pragma("key", key)?
-                .set_pragma("secure_delete", true)?;
-
-            // SQLcipher pre-4.0.0 compatibility. Using SHA1 still
-            // is less than ideal, but should be fine. Real uses of
-            // this (lockwise, etc) use a real random string for the
-            // encryption key, so the reduced KDF iteration count
-            // is fine.
-            db.set_pragma("cipher_page_size", 1024)?
-                .set_pragma("kdf_iter", 64000)?
-                .set_pragma("cipher_hmac_algorithm", "HMAC_SHA1")?
-                .set_pragma("cipher_kdf_algorithm", "PBKDF2_HMAC_SHA1")?;
+            set_encryption_key(&db, key)?;
         }
 
         // `temp_store = 2` is required on Android to force the DB to keep temp
@@ -89,6 +79,18 @@ impl LoginDb {
         Ok(())
     }
 
+    /// Re-encrypts the database with `new_encryption_key`, and checks that
+    /// it can still be read. The database must already be encrypted.
+    pub fn rekey(&self, new_encryption_key: &str) -> Result<()> {
+        self.set_pragma("rekey", new_encryption_key)?;
+        let problems: Vec<String> =
+            self.query_rows_and_then_named("PRAGMA quick_check", &[], |row| row.get(0))?;
+        if problems != ["ok"] {
+            throw!(ErrorKind::IntegrityCheckFailed(problems.join(", ")));
+        }
+        Ok(())
+    }
+
     pub fn new_interrupt_handle(&self) -> SqlInterruptHandle {
         SqlInterruptHandle::new(
             self.db.get_interrupt_handle(),
@@ -102,6 +104,24 @@ impl LoginDb {
     }
 }
 
+/// Sets the key for an encrypted database. These are the settings firefox-ios
+/// uses, so this can also open its `logins.db`.
+pub(crate) fn set_encryption_key(db: &Connection, key: &str) -> Result<()> {
+    db.set_pragma("key", key)?
+        .set_pragma("secure_delete", true)?;
+
+    // SQLcipher pre-4.0.0 compatibility. Using SHA1 still
+    // is less than ideal, but should be fine. Real uses of
+    // this (lockwise, etc) use a real random string for the
+    // encryption key, so the reduced KDF iteration count
+    // is fine.
+    db.set_pragma("cipher_page_size", 1024)?
+        .set_pragma("kdf_iter", 64000)?
+        .set_pragma("cipher_hmac_algorithm", "HMAC_SHA1")?
+        .set_pragma("cipher_kdf_algorithm", "PBKDF2_HMAC_SHA1")?;
+    Ok(())
+}
+
 impl ConnExt for LoginDb {
     #[inline]
     fn conn(&self) -> &Connection {
@@ -295,6 +315,60 @@ impl LoginDb {
         Ok(self.try_query_row(&query, args, |row| Login::from_row(row), false)?)
     }
 
+    /// Returns the logins that would be duplicates of `login` if it had a
+    /// different username - that is, logins for the same hostname and
+    /// target (the same `formSubmitURL`, or the same `httpRealm`), other than
+    /// `login` itself. The add and edit UIs can use this to warn before the
+    /// user saves a login that's a duplicate of one they already have.
+    pub fn potential_dupes_ignoring_username(&self, login: &Login) -> Result<Vec<Login>> {
+        let mut stmt = self.db.prepare_cached(&GET_POTENTIAL_DUPES_SQL)?;
+        let rows = stmt.query_and_then_named(
+            named_params! {
+                ":guid": login.guid,
+                ":hostname": login.hostname,
+                ":http_realm": login.http_realm,
+                ":form_submit_url": login.form_submit_url,
+            },
+            Login::from_row,
+        )?;
+        rows.collect::<Result<_>>()
+    }
+
+    /// Checks that `login` is valid, and that saving it wouldn't duplicate a
+    /// different login (one for the same site, target, and username).
+    pub fn check_valid_with_no_dupes(&self, login: &Login) -> Result<()> {
+        login.check_valid()?;
+        let dupes = self.potential_dupes_ignoring_username(login)?;
+        if dupes.iter().any(|dupe| dupe.username == login.username) {
+            throw!(InvalidLogin::DuplicateLogin);
+        }
+        Ok(())
+    }
+
+    /// Groups the logins that have the same hostname, username and
+    /// password, for a "clean up duplicate logins" feature. Only groups with
+    /// more than one login are returned. Each group is sorted so that the
+    /// most recently used login is first, which is usually the one to keep.
+    pub fn find_duplicates(&self) -> Result<Vec<Vec<Login>>> {
+        let mut groups: BTreeMap<(String, String, String), Vec<Login>> = BTreeMap::new();
+        for login in self.get_all()? {
+            let key = (
+                login.hostname.clone(),
+                login.username.clone(),
+                login.password.clone(),
+            );
+            groups.entry(key).or_default().push(login);
+        }
+        Ok(groups
+            .into_iter()
+            .map(|(_, mut logins)| {
+                logins.sort_by(|a, b| b.time_last_used.cmp(&a.time_last_used));
+                logins
+            })
+            .filter(|logins| logins.len() > 1)
+            .collect())
+    }
+
     pub fn get_all(&self) -> Result<Vec<Login>> {
         let mut stmt = self.db.prepare_cached(&GET_ALL_SQL)?;
         let rows = stmt.query_and_then(NO_PARAMS, Login::from_row)?;
@@ -310,6 +384,52 @@ impl LoginDb {
         )
     }
 
+    /// Returns the logins with the same base domain as `domain`, found using
+    /// the public suffix list. For example, both `www.example.co.uk` and
+    /// `example.co.uk` return the logins saved on `accounts.example.co.uk`,
+    /// but not those on `other.co.uk`. Fails with `ErrorKind::PublicSuffix`
+    /// if `domain` is itself a public suffix, like `co.uk`. IP addresses only
+    /// match exactly.
+    pub fn get_by_base_domain(&self, domain: &str) -> Result<Vec<Login>> {
+        // Parse the domain so that it's normalized the same way as the
+        // hostnames we compare it to.
+        let base_host = match Host::parse(domain) {
+            Ok(Host::Domain(domain)) => match util::base_domain(&domain)? {
+                Some(base_domain) => Host::Domain(base_domain),
+                None => {
+                    log::warn!("get_by_base_domain was passed an invalid domain");
+                    return Ok(vec![]);
+                }
+            },
+            Ok(host) => host,
+            Err(e) => {
+                // Don't log the domain, since it's PII.
+                log::warn!("get_by_base_domain was passed an invalid domain: {}", e);
+                return Ok(vec![]);
+            }
+        };
+        // Most people don't have many logins, so a linear scan is fine, and
+        // simpler than keeping an index of reversed hosts.
+        Ok(self
+            .get_all()?
+            .into_iter()
+            .filter(|login| match Url::parse(&login.hostname) {
+                Ok(url) => match (&base_host, url.host()) {
+                    (Host::Domain(base), Some(Host::Domain(host))) => {
+                        match util::base_domain(host) {
+                            Ok(Some(host_base)) => host_base == *base,
+                            _ => false,
+                        }
+                    }
+                    (Host::Ipv4(base), Some(Host::Ipv4(host))) => *base == host,
+                    (Host::Ipv6(base), Some(Host::Ipv6(host))) => *base == host,
+                    _ => false,
+                },
+                Err(_) => false,
+            })
+            .collect())
+    }
+
     pub fn touch(&self, id: &str) -> Result<()> {
         let tx = self.unchecked_transaction()?;
         self.ensure_local_overlay_exists(id)?;
@@ -333,12 +453,69 @@ impl LoginDb {
         Ok(())
     }
 
-    pub fn add(&self, mut login: Login) -> Result<Login> {
+    /// Records that all of the logins in `ids` were used, in a single
+    /// transaction. Unlike `touch`, this also marks them as changed, so that
+    /// their new usage counts get synced. If any of them don't exist, none of
+    /// them are changed.
+    pub fn touch_many(&self, ids: &[&str]) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        for id in ids {
+            self.ensure_local_overlay_exists(id)?;
+            self.mark_mirror_overridden(id)?;
+            self.execute_named_cached(
+                &format!(
+                    "UPDATE loginsL
+                     SET timeLastUsed = :now_millis,
+                         timesUsed = timesUsed + 1,
+                         local_modified = :now_millis,
+                         sync_status = (CASE sync_status
+                                        WHEN {synced} THEN {changed}
+                                        ELSE sync_status
+                                        END)
+                     WHERE guid = :guid
+                         AND is_deleted = 0",
+                    synced = SyncStatus::Synced as u8,
+                    changed = SyncStatus::Changed as u8
+                ),
+                named_params! {
+                    ":now_millis": now_ms,
+                    ":guid": id,
+                },
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn add(&self, login: Login) -> Result<Login> {
         login.check_valid()?;
+        let tx = self.unchecked_transaction()?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let login = self.add_without_transaction(login, now_ms)?;
+        tx.commit()?;
+        Ok(login)
+    }
 
+    /// Adds all of `logins` in a single transaction, which is much faster
+    /// than calling `add` for each of them. Returns the result of adding each
+    /// login, in the same order; one of them failing (because it's invalid,
+    /// for example) doesn't stop the others from being added.
+    pub fn add_many(&self, logins: Vec<Login>) -> Result<Vec<Result<Login>>> {
         let tx = self.unchecked_transaction()?;
         let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let results = logins
+            .into_iter()
+            .map(|login| {
+                login.check_valid()?;
+                self.add_without_transaction(login, now_ms)
+            })
+            .collect();
+        tx.commit()?;
+        Ok(results)
+    }
 
+    fn add_without_transaction(&self, mut login: Login, now_ms: i64) -> Result<Login> {
         // Allow an empty GUID to be passed to indicate that we should generate
         // one. (Note that the FFI, does not require that the `id` field be
         // present in the JSON, and replaces it with an empty string if missing).
@@ -353,6 +530,20 @@ impl LoginDb {
         login.time_last_used = now_ms;
         login.times_used = 1;
 
+        let rows_changed = self.insert_new_login(&login, now_ms)?;
+        if rows_changed == 0 {
+            log::error!(
+                "Record {:?} already exists (use `update` to update records, not add)",
+                login.guid
+            );
+            throw!(ErrorKind::DuplicateGuid(login.guid.into_string()));
+        }
+        Ok(login)
+    }
+
+    // Inserts `login` into the local table as a new record, returning the
+    // number of rows changed, which is 0 if its GUID is already taken.
+    pub(crate) fn insert_new_login(&self, login: &Login, local_modified: i64) -> Result<usize> {
         let sql = format!(
             "INSERT OR IGNORE INTO loginsL (
                 hostname,
@@ -390,7 +581,7 @@ impl LoginDb {
             new = SyncStatus::New as u8
         );
 
-        let rows_changed = self.execute_named(
+        Ok(self.execute_named_cached(
             &sql,
             named_params! {
                 ":hostname": login.hostname,
@@ -405,22 +596,13 @@ impl LoginDb {
                 ":times_used": login.times_used,
                 ":time_last_used": login.time_last_used,
                 ":time_password_changed": login.time_password_changed,
-                ":local_modified": now_ms,
+                ":local_modified": local_modified,
             },
-        )?;
-        if rows_changed == 0 {
-            log::error!(
-                "Record {:?} already exists (use `update` to update records, not add)",
-                login.guid
-            );
-            throw!(ErrorKind::DuplicateGuid(login.guid.into_string()));
-        }
-        tx.commit()?;
-        Ok(login)
+        )?)
     }
 
-    pub fn import_multiple(&self, logins: &[Login]) -> Result<u64> {
-        // Check if the logins table is empty first.
+    /// Imports only work on empty tables, so that they can't add duplicates.
+    pub(crate) fn ensure_empty(&self) -> Result<()> {
         let mut num_existing_logins =
             self.query_row::<i64, _, _>("SELECT COUNT(*) FROM loginsL", NO_PARAMS, |r| r.get(0))?;
         num_existing_logins +=
@@ -428,6 +610,12 @@ impl LoginDb {
         if num_existing_logins > 0 {
             return Err(ErrorKind::NonEmptyTable.into());
         }
+        Ok(())
+    }
+
+    pub fn import_multiple(&self, logins: &[Login]) -> Result<u64> {
+        // Check if the logins table is empty first.
+        self.ensure_empty()?;
         let tx = self.unchecked_transaction()?;
         let now_ms = util::system_time_ms_i64(SystemTime::now());
         let sql = format!(
@@ -511,11 +699,32 @@ impl LoginDb {
     pub fn update(&self, login: Login) -> Result<()> {
         login.check_valid()?;
         let tx = self.unchecked_transaction()?;
-        // Note: These fail with DuplicateGuid if the record doesn't exist.
-        self.ensure_local_overlay_exists(login.guid_str())?;
-        self.mark_mirror_overridden(login.guid_str())?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        self.update_without_transaction(&login, now_ms)?;
+        tx.commit()?;
+        Ok(())
+    }
 
+    /// Updates all of `logins` in a single transaction. Like `add_many`, this
+    /// returns the result of updating each login, in the same order.
+    pub fn update_many(&self, logins: Vec<Login>) -> Result<Vec<Result<()>>> {
+        let tx = self.unchecked_transaction()?;
         let now_ms = util::system_time_ms_i64(SystemTime::now());
+        let results = logins
+            .iter()
+            .map(|login| {
+                login.check_valid()?;
+                self.update_without_transaction(login, now_ms)
+            })
+            .collect();
+        tx.commit()?;
+        Ok(results)
+    }
+
+    fn update_without_transaction(&self, login: &Login, now_ms: i64) -> Result<()> {
+        // Note: These fail with NoSuchRecord if the record doesn't exist.
+        self.ensure_local_overlay_exists(login.guid_str())?;
+        self.mark_mirror_overridden(login.guid_str())?;
 
         let sql = format!(
             "UPDATE loginsL
@@ -541,7 +750,7 @@ impl LoginDb {
             changed = SyncStatus::Changed as u8
         );
 
-        self.db.execute_named(
+        self.execute_named_cached(
             &sql,
             named_params! {
                 ":hostname": login.hostname,
@@ -555,7 +764,6 @@ impl LoginDb {
                 ":now_millis": now_ms,
             },
         )?;
-        tx.commit()?;
         Ok(())
     }
 
@@ -613,6 +821,11 @@ impl LoginDb {
             named_params! { ":guid": id },
         )?;
 
+        self.execute_named(
+            "DELETE FROM loginsVulnerable WHERE guid = :guid",
+            named_params! { ":guid": id },
+        )?;
+
         // If we don't have a local record for this ID, but do have it in the mirror
         // insert a tombstone.
         self.execute_named(&format!("
@@ -716,6 +929,12 @@ impl LoginDb {
         self.execute("UPDATE loginsM SET is_overridden = 1", NO_PARAMS)?;
         scope.err_if_interrupted()?;
 
+        self.execute_all(&[
+            "DELETE FROM loginsBreachCache",
+            "DELETE FROM loginsVulnerable",
+        ])?;
+        scope.err_if_interrupted()?;
+
         self.execute_named(
             &format!("
                 INSERT OR IGNORE INTO loginsL
@@ -736,6 +955,9 @@ impl LoginDb {
             "DELETE FROM loginsL",
             "DELETE FROM loginsM",
             "DELETE FROM loginsSyncMeta",
+            "DELETE FROM loginsBreachCache",
+            "DELETE FROM loginsVulnerable",
+            "DELETE FROM loginsNeverSave",
         ])?;
         tx.commit()?;
         Ok(())
@@ -883,6 +1105,17 @@ impl LoginDb {
         self.put_meta(schema::LAST_SYNC_META_KEY, &last_sync_millis)
     }
 
+    fn has_outgoing_changes(&self) -> Result<bool> {
+        Ok(self.db.query_row(
+            &format!(
+                "SELECT EXISTS(SELECT 1 FROM loginsL WHERE sync_status IS NOT {synced})",
+                synced = SyncStatus::Synced as u8
+            ),
+            NO_PARAMS,
+            |row| row.get(0),
+        )?)
+    }
+
     fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
         let millis = self.get_meta::<i64>(schema::LAST_SYNC_META_KEY)?.unwrap();
         Ok(Some(ServerTimestamp(millis)))
@@ -949,6 +1182,10 @@ impl<'a> Store for LoginStore<'a> {
         Ok(self.db.do_apply_incoming(inbound, telem, &self.scope)?)
     }
 
+    fn has_outgoing_changes(&self) -> result::Result<bool, failure::Error> {
+        Ok(self.db.has_outgoing_changes()?)
+    }
+
     fn sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
@@ -1012,6 +1249,24 @@ lazy_static! {
          LIMIT 1",
         common_cols = schema::COMMON_COLS,
     );
+    static ref GET_POTENTIAL_DUPES_SQL: String = format!(
+        "SELECT {common_cols} FROM loginsL
+         WHERE is_deleted = 0
+           AND guid <> :guid
+           AND hostname = :hostname
+           AND ((:form_submit_url IS NOT NULL AND formSubmitURL = :form_submit_url)
+             OR (:http_realm IS NOT NULL AND httpRealm = :http_realm))
+
+         UNION ALL
+
+         SELECT {common_cols} FROM loginsM
+         WHERE is_overridden = 0
+           AND guid <> :guid
+           AND hostname = :hostname
+           AND ((:form_submit_url IS NOT NULL AND formSubmitURL = :form_submit_url)
+             OR (:http_realm IS NOT NULL AND httpRealm = :http_realm))",
+        common_cols = schema::COMMON_COLS,
+    );
     static ref CLONE_ENTIRE_MIRROR_SQL: String = format!(
         "INSERT OR IGNORE INTO loginsL ({common_cols}, local_modified, is_deleted, sync_status)
          SELECT {common_cols}, NULL AS local_modified, 0 AS is_deleted, 0 AS sync_status
@@ -1070,4 +1325,313 @@ mod tests {
         assert_eq!(res[0].guid, "dummy_000001");
         assert_eq!(res[1].guid, "dummy_000003");
     }
+
+    #[test]
+    fn test_duplicates() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = |guid: &str, username: &str, password: &str| Login {
+            guid: guid.into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: username.into(),
+            password: password.into(),
+            ..Login::default()
+        };
+        db.add(login("dummy_000001", "user", "password")).unwrap();
+        db.add(login("dummy_000002", "user", "password")).unwrap();
+        db.add(login("dummy_000003", "other", "password")).unwrap();
+        db.add(Login {
+            http_realm: Some("realm".into()),
+            form_submit_url: None,
+            ..login("dummy_000004", "user", "other password")
+        })
+        .unwrap();
+
+        let mut dupes = db
+            .potential_dupes_ignoring_username(&login("dummy_000001", "new username", "new"))
+            .unwrap()
+            .into_iter()
+            .map(|l| l.guid)
+            .collect::<Vec<_>>();
+        dupes.sort();
+        assert_eq!(dupes, vec!["dummy_000002", "dummy_000003"]);
+
+        db.execute(
+            "UPDATE loginsL SET timeLastUsed = timeLastUsed + 1000 WHERE guid = 'dummy_000002'",
+            NO_PARAMS,
+        )
+        .unwrap();
+        let groups = db.find_duplicates().unwrap();
+        assert_eq!(groups.len(), 1);
+        let guids = groups[0]
+            .iter()
+            .map(|l| l.guid.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(guids, vec!["dummy_000002", "dummy_000001"]);
+    }
+
+    #[test]
+    fn test_get_by_base_domain() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let hostnames = [
+            "https://www.example.co.uk",
+            "https://accounts.example.co.uk:8443",
+            "https://example.co.uk",
+            "https://notexample.co.uk",
+            "https://www.example.com",
+            "http://127.0.0.1",
+            "http://127.0.0.10",
+        ];
+        for hostname in &hostnames {
+            db.add(Login {
+                hostname: (*hostname).into(),
+                form_submit_url: Some((*hostname).into()),
+                username: "test".into(),
+                password: "test".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        let get = |base_domain: &str| {
+            let mut found = db
+                .get_by_base_domain(base_domain)
+                .unwrap()
+                .into_iter()
+                .map(|l| l.hostname)
+                .collect::<Vec<_>>();
+            found.sort();
+            found
+        };
+        assert_eq!(
+            get("example.co.uk"),
+            vec![
+                "https://accounts.example.co.uk:8443",
+                "https://example.co.uk",
+                "https://www.example.co.uk",
+            ]
+        );
+        assert_eq!(
+            get("www.example.co.uk"),
+            vec![
+                "https://accounts.example.co.uk:8443",
+                "https://example.co.uk",
+                "https://www.example.co.uk",
+            ]
+        );
+        assert_eq!(get("EXAMPLE.com"), vec!["https://www.example.com"]);
+        assert_eq!(get("127.0.0.1"), vec!["http://127.0.0.1"]);
+        assert!(get("example.org").is_empty());
+        assert!(get("not a domain!").is_empty());
+
+        for public_suffix in &["co.uk", "com", "CO.UK"] {
+            match db.get_by_base_domain(public_suffix).unwrap_err().kind() {
+                ErrorKind::PublicSuffix => {}
+                e => panic!("Expected PublicSuffix error, got {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn test_touch_many() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        for guid in &["dummy_000001", "dummy_000002", "dummy_000003"] {
+            db.add(Login {
+                guid: (*guid).into(),
+                hostname: "https://www.example.com".into(),
+                form_submit_url: Some("https://www.example.com".into()),
+                username: (*guid).into(),
+                password: "test".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        db.execute(
+            &format!(
+                "UPDATE loginsL SET sync_status = {synced}, timeLastUsed = 0
+                 WHERE guid <> 'dummy_000003'",
+                synced = SyncStatus::Synced as u8
+            ),
+            NO_PARAMS,
+        )
+        .unwrap();
+        db.touch_many(&["dummy_000001", "dummy_000003"]).unwrap();
+
+        let sync_status = |guid: &str| -> u8 {
+            db.query_row_named(
+                "SELECT sync_status FROM loginsL WHERE guid = :guid",
+                named_params! { ":guid": guid },
+                |row| row.get(0),
+            )
+            .unwrap()
+        };
+        let login = db.get_by_id("dummy_000001").unwrap().unwrap();
+        assert_eq!(login.times_used, 2);
+        assert_ne!(login.time_last_used, 0);
+        assert_eq!(sync_status("dummy_000001"), SyncStatus::Changed as u8);
+        let login = db.get_by_id("dummy_000002").unwrap().unwrap();
+        assert_eq!(login.times_used, 1);
+        assert_eq!(login.time_last_used, 0);
+        assert_eq!(sync_status("dummy_000002"), SyncStatus::Synced as u8);
+        // New logins stay new.
+        assert_eq!(db.get_by_id("dummy_000003").unwrap().unwrap().times_used, 2);
+        assert_eq!(sync_status("dummy_000003"), SyncStatus::New as u8);
+
+        // If any of the logins don't exist, nothing changes.
+        assert!(db.touch_many(&["dummy_000002", "dummy_999999"]).is_err());
+        let login = db.get_by_id("dummy_000002").unwrap().unwrap();
+        assert_eq!(login.times_used, 1);
+        assert_eq!(sync_status("dummy_000002"), SyncStatus::Synced as u8);
+    }
+
+    // Whether we're linked against SQLCipher, or plain SQLite (which ignores
+    // the encryption key), as some test environments are.
+    fn is_sqlcipher() -> bool {
+        let db = Connection::open_in_memory().unwrap();
+        let versions: Vec<String> = db
+            .query_rows_and_then_named("PRAGMA cipher_version", &[], |row| row.get(0))
+            .unwrap();
+        !versions.is_empty()
+    }
+
+    #[test]
+    fn test_rekey() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logins.sqlite");
+        {
+            let db = LoginDb::open(&path, Some("old key")).unwrap();
+            db.add(Login {
+                guid: "dummy_000001".into(),
+                hostname: "https://www.example.com".into(),
+                form_submit_url: Some("https://www.example.com".into()),
+                username: "test".into(),
+                password: "test".into(),
+                ..Login::default()
+            })
+            .unwrap();
+            db.rekey("new key").unwrap();
+            // The connection keeps working after rekeying.
+            assert!(db.get_by_id("dummy_000001").unwrap().is_some());
+        }
+        if is_sqlcipher() {
+            assert!(LoginDb::open(&path, Some("old key")).is_err());
+        }
+        let db = LoginDb::open(&path, Some("new key")).unwrap();
+        assert!(db.get_by_id("dummy_000001").unwrap().is_some());
+    }
+
+    #[test]
+    fn test_add_and_update_many() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = |guid: &str, password: &str| Login {
+            guid: guid.into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: guid.into(),
+            password: password.into(),
+            ..Login::default()
+        };
+        db.add(login("dummy_000001", "password")).unwrap();
+
+        let results = db
+            .add_many(vec![
+                login("dummy_000002", "password"),
+                login("dummy_000001", "password"),
+                login("dummy_000003", ""),
+                login("", "password"),
+            ])
+            .unwrap();
+        assert_eq!(results.len(), 4);
+        assert_eq!(results[0].as_ref().unwrap().guid, "dummy_000002");
+        match results[1].as_ref().unwrap_err().kind() {
+            ErrorKind::DuplicateGuid(guid) => assert_eq!(guid, "dummy_000001"),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        match results[2].as_ref().unwrap_err().kind() {
+            ErrorKind::InvalidLogin(InvalidLogin::EmptyPassword) => {}
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        let added = results[3].as_ref().unwrap();
+        assert!(!added.guid.is_empty());
+        assert_eq!(db.get_all().unwrap().len(), 3);
+
+        let results = db
+            .update_many(vec![
+                login("dummy_000001", "new password"),
+                login("dummy_999999", "new password"),
+                login("dummy_000002", "new password"),
+            ])
+            .unwrap();
+        assert!(results[0].is_ok());
+        match results[1].as_ref().unwrap_err().kind() {
+            ErrorKind::NoSuchRecord(guid) => assert_eq!(guid, "dummy_999999"),
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        assert!(results[2].is_ok());
+        for guid in &["dummy_000001", "dummy_000002"] {
+            let updated = db.get_by_id(guid).unwrap().unwrap();
+            assert_eq!(updated.password, "new password");
+        }
+        assert_eq!(
+            db.get_by_id(added.guid.as_str()).unwrap().unwrap().password,
+            "password"
+        );
+    }
+
+    #[test]
+    fn test_check_valid_with_no_dupes() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let login = Login {
+            guid: "dummy_000001".into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "user".into(),
+            password: "password".into(),
+            ..Login::default()
+        };
+        db.check_valid_with_no_dupes(&login).unwrap();
+        db.add(login.clone()).unwrap();
+        // A login doesn't duplicate itself.
+        db.check_valid_with_no_dupes(&login).unwrap();
+
+        let dupe = Login {
+            guid: "dummy_000002".into(),
+            password: "other password".into(),
+            ..login.clone()
+        };
+        match db.check_valid_with_no_dupes(&dupe).unwrap_err().kind() {
+            ErrorKind::InvalidLogin(InvalidLogin::DuplicateLogin) => {}
+            e => panic!("Unexpected error: {:?}", e),
+        }
+        db.check_valid_with_no_dupes(&Login {
+            username: "other user".into(),
+            ..dupe.clone()
+        })
+        .unwrap();
+        match db
+            .check_valid_with_no_dupes(&Login {
+                password: "".into(),
+                ..dupe
+            })
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidLogin(InvalidLogin::EmptyPassword) => {}
+            e => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_has_outgoing_changes() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        assert!(!db.has_outgoing_changes().unwrap());
+        db.add(Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        })
+        .unwrap();
+        assert!(db.has_outgoing_changes().unwrap());
+    }
 }
@@ -1,11 +1,20 @@
 /* This Source Code Form is subject to the terms of the Mozilla Public
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+use crate::breach::{BreachCheckConfig, BreachedLogin};
+use crate::csv_import::CsvImportRow;
 use crate::db::{LoginDb, LoginStore};
 use crate::error::*;
+use crate::ios_import::MigrationMetrics;
 use crate::login::Login;
+use crate::observer::{
+    diff_logins, ChangeType, LoginChange, LoginsObserver, ObserverHandle, Observers,
+};
+use crate::vulnerable::VulnerableLogin;
 use std::cell::Cell;
+use std::fs::File;
 use std::path::Path;
+use std::sync::Arc;
 use sync15::{
     sync_multiple, telemetry, KeyBundle, MemoryCachedState, StoreSyncAssociation,
     Sync15StorageClientInit,
@@ -17,6 +26,7 @@ use sync15::{
 pub struct PasswordEngine {
     pub db: LoginDb,
     pub mem_cached_state: Cell<MemoryCachedState>,
+    observers: Observers,
 }
 
 impl PasswordEngine {
@@ -25,6 +35,7 @@ impl PasswordEngine {
         Ok(Self {
             db,
             mem_cached_state: Cell::default(),
+            observers: Observers::default(),
         })
     }
 
@@ -33,9 +44,48 @@ impl PasswordEngine {
         Ok(Self {
             db,
             mem_cached_state: Cell::default(),
+            observers: Observers::default(),
         })
     }
 
+    /// Registers an observer to be told about every change to the logins,
+    /// after it's been committed. Returns a handle for unregistering it.
+    pub fn register_observer(&self, observer: Arc<dyn LoginsObserver>) -> ObserverHandle {
+        self.observers.register(observer)
+    }
+
+    /// Returns true if the observer was registered.
+    pub fn unregister_observer(&self, handle: ObserverHandle) -> bool {
+        self.observers.unregister(handle)
+    }
+
+    fn notify(&self, guids: &[&str], change_type: ChangeType) {
+        let changes = guids
+            .iter()
+            .map(|&guid| LoginChange::new(guid, change_type))
+            .collect::<Vec<_>>();
+        self.observers.notify(&changes);
+    }
+
+    // Runs `f`, and notifies observers about the logins it changed, for
+    // operations that don't tell us which logins they change. This compares
+    // every login before and after, so it's only done if there are observers.
+    fn notify_changed_by<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        if self.observers.is_empty() {
+            return f();
+        }
+        let before = self.db.get_all()?;
+        let result = f();
+        // Check even if `f` failed, since it might have committed some
+        // changes first (a sync can fail after applying incoming records,
+        // for example).
+        match self.db.get_all() {
+            Ok(after) => self.observers.notify(&diff_logins(before, after)),
+            Err(e) => log::warn!("Failed to find changed logins: {}", e),
+        }
+        result
+    }
+
     pub fn list(&self) -> Result<Vec<Login>> {
         self.db.get_all()
     }
@@ -44,23 +94,51 @@ impl PasswordEngine {
         self.db.get_by_id(id)
     }
 
+    pub fn potential_dupes_ignoring_username(&self, login: &Login) -> Result<Vec<Login>> {
+        self.db.potential_dupes_ignoring_username(login)
+    }
+
+    pub fn check_valid_with_no_dupes(&self, login: &Login) -> Result<()> {
+        self.db.check_valid_with_no_dupes(login)
+    }
+
+    pub fn find_duplicates(&self) -> Result<Vec<Vec<Login>>> {
+        self.db.find_duplicates()
+    }
+
+    pub fn get_by_base_domain(&self, base_domain: &str) -> Result<Vec<Login>> {
+        self.db.get_by_base_domain(base_domain)
+    }
+
     pub fn touch(&self, id: &str) -> Result<()> {
-        self.db.touch(id)
+        self.db.touch(id)?;
+        self.notify(&[id], ChangeType::Updated);
+        Ok(())
+    }
+
+    pub fn touch_many(&self, ids: &[&str]) -> Result<()> {
+        self.db.touch_many(ids)?;
+        self.notify(ids, ChangeType::Updated);
+        Ok(())
     }
 
     pub fn delete(&self, id: &str) -> Result<bool> {
-        self.db.delete(id)
+        let existed = self.db.delete(id)?;
+        if existed {
+            self.notify(&[id], ChangeType::Deleted);
+        }
+        Ok(existed)
     }
 
     pub fn wipe(&self) -> Result<()> {
-        let scope = self.db.begin_interrupt_scope();
-        self.db.wipe(&scope)?;
-        Ok(())
+        self.notify_changed_by(|| {
+            let scope = self.db.begin_interrupt_scope();
+            self.db.wipe(&scope)
+        })
     }
 
     pub fn wipe_local(&self) -> Result<()> {
-        self.db.wipe_local()?;
-        Ok(())
+        self.notify_changed_by(|| self.db.wipe_local())
     }
 
     pub fn reset(&self) -> Result<()> {
@@ -69,16 +147,159 @@ impl PasswordEngine {
     }
 
     pub fn update(&self, login: Login) -> Result<()> {
-        self.db.update(login)
+        let guid = login.guid.clone();
+        self.db.update(login)?;
+        self.notify(&[guid.as_str()], ChangeType::Updated);
+        Ok(())
     }
 
     pub fn add(&self, login: Login) -> Result<String> {
         // Just return the record's ID (which we may have generated).
-        self.db.add(login).map(|record| record.guid.into_string())
+        let guid = self.db.add(login)?.guid.into_string();
+        self.notify(&[&guid], ChangeType::Added);
+        Ok(guid)
+    }
+
+    /// Adds all of `logins` in a single transaction, returning the ID of
+    /// each one that was added, or the reason it wasn't, in the same order.
+    pub fn add_many(&self, logins: Vec<Login>) -> Result<Vec<Result<String>>> {
+        let results = self
+            .db
+            .add_many(logins)?
+            .into_iter()
+            .map(|result| result.map(|record| record.guid.into_string()))
+            .collect::<Vec<_>>();
+        let added = results
+            .iter()
+            .filter_map(|result| result.as_ref().ok().map(String::as_str))
+            .collect::<Vec<_>>();
+        self.notify(&added, ChangeType::Added);
+        Ok(results)
+    }
+
+    /// Updates all of `logins` in a single transaction, returning the result
+    /// of updating each one, in the same order.
+    pub fn update_many(&self, logins: Vec<Login>) -> Result<Vec<Result<()>>> {
+        let guids = logins.iter().map(|l| l.guid.clone()).collect::<Vec<_>>();
+        let results = self.db.update_many(logins)?;
+        let updated = guids
+            .iter()
+            .zip(&results)
+            .filter(|(_, result)| result.is_ok())
+            .map(|(guid, _)| guid.as_str())
+            .collect::<Vec<_>>();
+        self.notify(&updated, ChangeType::Updated);
+        Ok(results)
     }
 
     pub fn import_multiple(&self, logins: &[Login]) -> Result<u64> {
-        self.db.import_multiple(logins)
+        self.notify_changed_by(|| self.db.import_multiple(logins))
+    }
+
+    /// Migrates the logins from a firefox-ios `logins.db`. See
+    /// `LoginDb::import_ios_logins` for details.
+    pub fn import_ios_logins(
+        &self,
+        path: impl AsRef<Path>,
+        encryption_key: Option<&str>,
+    ) -> Result<MigrationMetrics> {
+        self.notify_changed_by(|| self.db.import_ios_logins(path, encryption_key))
+    }
+
+    /// Imports logins from a CSV file exported by Chrome, Firefox, LastPass,
+    /// or anything else that uses the same column names. See
+    /// `LoginDb::import_csv` for details.
+    pub fn import_csv(&self, csv: &str) -> Result<Vec<CsvImportRow>> {
+        self.notify_changed_by(|| self.db.import_csv(csv.as_bytes()))
+    }
+
+    pub fn import_csv_file(&self, path: impl AsRef<Path>) -> Result<Vec<CsvImportRow>> {
+        let file = File::open(path)?;
+        self.notify_changed_by(|| self.db.import_csv(file))
+    }
+
+    /// Exports every login as CSV, in the format Firefox Desktop uses, which
+    /// Chrome and other password managers can import. Passwords are only
+    /// included if `include_passwords` is true.
+    pub fn export_csv(&self, include_passwords: bool) -> Result<String> {
+        let mut csv = Vec::new();
+        self.db.export_csv(&mut csv, include_passwords)?;
+        // The CSV is built from strings, so it's always valid UTF-8.
+        Ok(String::from_utf8(csv).expect("CSV should be UTF-8"))
+    }
+
+    pub fn export_csv_file(&self, path: impl AsRef<Path>, include_passwords: bool) -> Result<()> {
+        self.db.export_csv(File::create(path)?, include_passwords)
+    }
+
+    /// Checks every saved password against a breached password database,
+    /// returning the logins whose passwords were found. This makes network
+    /// requests, but only for passwords that aren't already cached.
+    pub fn check_breached_passwords(
+        &self,
+        config: &BreachCheckConfig,
+    ) -> Result<Vec<BreachedLogin>> {
+        self.db.check_breached_passwords(config)
+    }
+
+    pub fn clear_breach_cache(&self) -> Result<()> {
+        self.db.clear_breach_cache()
+    }
+
+    /// Marks a login as vulnerable, because a breach was detected at
+    /// `breach_detected_at` (in milliseconds). This is only stored locally,
+    /// and isn't synced.
+    pub fn set_vulnerable(&self, id: &str, breach_detected_at: i64) -> Result<()> {
+        self.db.set_vulnerable(id, breach_detected_at)
+    }
+
+    pub fn clear_vulnerable(&self, id: &str) -> Result<bool> {
+        self.db.clear_vulnerable(id)
+    }
+
+    pub fn clear_all_vulnerable(&self) -> Result<()> {
+        self.db.clear_all_vulnerable()
+    }
+
+    pub fn is_vulnerable(&self, id: &str) -> Result<bool> {
+        self.db.is_vulnerable(id)
+    }
+
+    pub fn breach_detected_at(&self, id: &str) -> Result<Option<i64>> {
+        self.db.breach_detected_at(id)
+    }
+
+    pub fn get_vulnerable(&self) -> Result<Vec<VulnerableLogin>> {
+        self.db.get_vulnerable()
+    }
+
+    /// Adds an origin to the list of origins where we should never offer to
+    /// save logins. This is only stored locally, and isn't synced.
+    pub fn add_never_save(&self, origin: &str) -> Result<()> {
+        self.db.add_never_save(origin)
+    }
+
+    pub fn remove_never_save(&self, origin: &str) -> Result<bool> {
+        self.db.remove_never_save(origin)
+    }
+
+    pub fn is_never_save(&self, origin: &str) -> Result<bool> {
+        self.db.is_never_save(origin)
+    }
+
+    pub fn get_never_save(&self) -> Result<Vec<String>> {
+        self.db.get_never_save()
+    }
+
+    pub fn clear_never_save(&self) -> Result<()> {
+        self.db.clear_never_save()
+    }
+
+    /// Re-encrypts the database with a new key, for example because the old
+    /// key's keystore entry had to be regenerated. The new key must be used
+    /// to open the database from then on.
+    pub fn rekey(&self, new_encryption_key: &str) -> Result<()> {
+        self.db.rekey(new_encryption_key)
     }
 
     pub fn disable_mem_security(&self) -> Result<()> {
@@ -100,6 +321,14 @@ impl PasswordEngine {
         &self,
         storage_init: &Sync15StorageClientInit,
         root_sync_key: &KeyBundle,
+    ) -> Result<telemetry::SyncTelemetryPing> {
+        self.notify_changed_by(|| self.sync_without_notifying(storage_init, root_sync_key))
+    }
+
+    fn sync_without_notifying(
+        &self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
     ) -> Result<telemetry::SyncTelemetryPing> {
         // migrate our V1 state - this needn't live for long.
         self.db.migrate_global_state()?;
@@ -257,6 +486,81 @@ mod test {
         // Should be two even though we updated twice
         assert_eq!(b_after_update.times_used, 2);
     }
+
+    #[test]
+    fn test_observers() {
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct TestObserver(Mutex<Vec<LoginChange>>);
+        impl LoginsObserver for TestObserver {
+            fn on_changes(&self, changes: &[LoginChange]) {
+                self.0.lock().unwrap().extend_from_slice(changes);
+            }
+        }
+        impl TestObserver {
+            fn take(&self) -> Vec<(String, ChangeType)> {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .drain(..)
+                    .map(|c| (c.guid.into_string(), c.change_type))
+                    .collect()
+            }
+        }
+
+        let engine = PasswordEngine::new_in_memory(Some("secret")).unwrap();
+        let observer = Arc::new(TestObserver::default());
+        let handle = engine.register_observer(observer.clone());
+        let login = |guid: &str| Login {
+            guid: guid.into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: guid.into(),
+            password: "password".into(),
+            ..Login::default()
+        };
+
+        engine.add(login("dummy_000001")).unwrap();
+        engine
+            .add_many(vec![login("dummy_000002"), login("dummy_000001")])
+            .unwrap();
+        assert_eq!(
+            observer.take(),
+            vec![
+                ("dummy_000001".into(), ChangeType::Added),
+                ("dummy_000002".into(), ChangeType::Added),
+            ]
+        );
+
+        engine.update(login("dummy_000001")).unwrap();
+        engine
+            .touch_many(&["dummy_000001", "dummy_000002"])
+            .unwrap();
+        assert!(engine.update(login("dummy_999999")).is_err());
+        assert!(!engine.delete("dummy_999999").unwrap());
+        assert!(engine.delete("dummy_000002").unwrap());
+        assert_eq!(
+            observer.take(),
+            vec![
+                ("dummy_000001".into(), ChangeType::Updated),
+                ("dummy_000001".into(), ChangeType::Updated),
+                ("dummy_000002".into(), ChangeType::Updated),
+                ("dummy_000002".into(), ChangeType::Deleted),
+            ]
+        );
+
+        engine.wipe_local().unwrap();
+        assert_eq!(
+            observer.take(),
+            vec![("dummy_000001".into(), ChangeType::Deleted)]
+        );
+
+        assert!(engine.unregister_observer(handle));
+        assert!(!engine.unregister_observer(handle));
+        engine.add(login("dummy_000003")).unwrap();
+        assert!(observer.take().is_empty());
+    }
 }
 
 #[test]
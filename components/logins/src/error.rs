@@ -32,7 +32,7 @@ pub enum ErrorKind {
     )]
     NoSuchRecord(String),
 
-    // Fennec import only works on empty logins tables.
+    // Fennec and iOS imports only work on empty logins tables.
     #[fail(display = "The logins tables are not empty")]
     NonEmptyTable,
 
@@ -48,6 +48,41 @@ pub enum ErrorKind {
     #[fail(display = "Error parsing URL: {}", _0)]
     UrlParseError(#[fail(cause)] url::ParseError),
 
+    #[fail(display = "Invalid CSV file: {}", _0)]
+    InvalidCsv(String),
+
+    #[fail(display = "Error reading CSV: {}", _0)]
+    CsvError(#[fail(cause)] csv::Error),
+
+    #[fail(display = "IO error: {}", _0)]
+    IoError(#[fail(cause)] std::io::Error),
+
+    #[fail(display = "Not a firefox-ios logins database: {}", _0)]
+    InvalidIosDatabase(String),
+
+    #[fail(display = "Not a valid origin")]
+    InvalidOrigin,
+
+    // Returned when asked for the logins for a public suffix, like `co.uk`,
+    // which would match logins for unrelated sites.
+    #[fail(display = "Domain is a public suffix")]
+    PublicSuffix,
+
+    #[fail(display = "Database integrity check failed: {}", _0)]
+    IntegrityCheckFailed(String),
+
+    #[fail(display = "Invalid password options: {}", _0)]
+    InvalidPasswordOptions(String),
+
+    #[fail(display = "Network error: {}", _0)]
+    RequestError(#[fail(cause)] viaduct::Error),
+
+    #[fail(display = "Unexpected HTTP status: {}", _0)]
+    UnexpectedStatus(#[fail(cause)] viaduct::UnexpectedStatus),
+
+    #[fail(display = "Crypto error: {}", _0)]
+    CryptoError(#[fail(cause)] rc_crypto::Error),
+
     #[fail(display = "{}", _0)]
     Interrupted(#[fail(cause)] interrupt::Interrupted),
 }
@@ -57,7 +92,12 @@ error_support::define_error! {
         (SyncAdapterError, sync15::Error),
         (JsonError, serde_json::Error),
         (UrlParseError, url::ParseError),
+        (CsvError, csv::Error),
+        (IoError, std::io::Error),
         (SqlError, rusqlite::Error),
+        (RequestError, viaduct::Error),
+        (UnexpectedStatus, viaduct::UnexpectedStatus),
+        (CryptoError, rc_crypto::Error),
         (InvalidLogin, InvalidLogin),
         (Interrupted, interrupt::Interrupted),
     }
@@ -73,4 +113,6 @@ pub enum InvalidLogin {
     BothTargets,
     #[fail(display = "Neither `formSubmitUrl` and `httpRealm` are present")]
     NoTarget,
+    #[fail(display = "Login already exists")]
+    DuplicateLogin,
 }
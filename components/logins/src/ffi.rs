@@ -79,6 +79,10 @@ fn get_code(err: &Error) -> ErrorCode {
             ErrorCode::new(error_codes::INTERRUPTED)
         }
 
+        ErrorKind::RequestError(_) | ErrorKind::UnexpectedStatus(_) => {
+            log::error!("Network error: {}", err);
+            ErrorCode::new(error_codes::NETWORK)
+        }
         ErrorKind::Interrupted(_) => {
             log::warn!("Operation interrupted (Outside SQL)");
             ErrorCode::new(error_codes::INTERRUPTED)
@@ -0,0 +1,414 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Migrating logins from the `logins.db` that firefox-ios used before it
+//! switched to this crate.
+//!
+//! Our schema is an evolution of that one (see the `schema` module), so the
+//! rows are copied almost as they are. The differences are that firefox-ios
+//! stores `timeCreated`, `timeLastUsed`, and `timePasswordChanged` in
+//! microseconds, and that we fix up or skip logins that aren't valid.
+//!
+//! The sync metadata (`local_modified`, `is_deleted`, `sync_status`, and the
+//! mirror) is kept, so that logins that have already been synced aren't
+//! uploaded again as new ones, and local changes that haven't been synced yet
+//! aren't lost.
+
+use crate::db::{set_encryption_key, LoginDb};
+use crate::error::*;
+use crate::login::{Login, SyncStatus};
+use crate::schema;
+use lazy_static::lazy_static;
+use rusqlite::{named_params, types::ToSql, Connection, OpenFlags, Row, NO_PARAMS};
+use serde_derive::*;
+use sql_support::{ConnExt, SqlInterruptScope};
+use std::path::Path;
+use std::time::Instant;
+
+/// The last firefox-ios logins schema version.
+const IOS_SCHEMA_VERSION: i64 = 3;
+
+/// Statistics about a migration, for telemetry. They don't include any login
+/// data.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MigrationMetrics {
+    /// The number of rows read from the local and mirror tables.
+    pub num_processed: u64,
+    pub num_succeeded: u64,
+    pub num_failed: u64,
+    /// The number of rows that were imported after fixing them up, which are
+    /// included in `num_succeeded`.
+    pub num_fixed: u64,
+    /// How long the migration took, in milliseconds.
+    pub total_duration: u64,
+    /// Why each failed row couldn't be imported.
+    pub errors: Vec<String>,
+}
+
+// The values for `schema::COMMON_COLS`, in the same order.
+const COMMON_VALUES: &str = "
+    :guid,
+    :username,
+    :password,
+    :hostname,
+    :http_realm,
+    :form_submit_url,
+    :username_field,
+    :password_field,
+    :time_created,
+    :time_last_used,
+    :time_password_changed,
+    :times_used
+";
+
+lazy_static! {
+    static ref GET_IOS_LOCAL_SQL: String = format!(
+        "SELECT {common_cols}, local_modified, is_deleted, sync_status FROM loginsL",
+        common_cols = schema::COMMON_COLS
+    );
+    static ref GET_IOS_MIRROR_SQL: String = format!(
+        "SELECT {common_cols}, server_modified, is_overridden FROM loginsM",
+        common_cols = schema::COMMON_COLS
+    );
+    static ref INSERT_LOCAL_SQL: String = format!(
+        "INSERT INTO loginsL ({common_cols}, local_modified, is_deleted, sync_status)
+         VALUES ({common_values}, :local_modified, :is_deleted, :sync_status)",
+        common_cols = schema::COMMON_COLS,
+        common_values = COMMON_VALUES
+    );
+    static ref INSERT_MIRROR_SQL: String = format!(
+        "INSERT INTO loginsM ({common_cols}, server_modified, is_overridden)
+         VALUES ({common_values}, :server_modified, :is_overridden)",
+        common_cols = schema::COMMON_COLS,
+        common_values = COMMON_VALUES
+    );
+}
+
+// If we skipped the local row for a login that's overridden in the mirror, we
+// use the mirror instead, rather than losing the login entirely.
+const RESTORE_ORPHANED_MIRROR_SQL: &str = "
+    UPDATE loginsM
+    SET is_overridden = 0
+    WHERE is_overridden = 1
+      AND guid NOT IN (SELECT guid FROM loginsL)";
+
+fn ios_login_from_row(row: &Row<'_>) -> Result<Login> {
+    let mut login = Login::from_row(row)?;
+    login.time_created /= 1000;
+    login.time_last_used /= 1000;
+    login.time_password_changed /= 1000;
+    Ok(login)
+}
+
+// Fixes up `login` if it isn't a tombstone, returning true if anything
+// needed fixing.
+fn fixup_unless_deleted(login: &mut Login, is_deleted: bool) -> Result<bool> {
+    if is_deleted {
+        // Tombstones have their fields cleared, so they aren't valid logins.
+        return Ok(false);
+    }
+    let (fixed, fixes) = login.fixup()?;
+    *login = fixed;
+    Ok(!fixes.is_empty())
+}
+
+fn login_params(login: &Login) -> Vec<(&'static str, &dyn ToSql)> {
+    let params = named_params! {
+        ":guid": login.guid,
+        ":username": login.username,
+        ":password": login.password,
+        ":hostname": login.hostname,
+        ":http_realm": login.http_realm,
+        ":form_submit_url": login.form_submit_url,
+        ":username_field": login.username_field,
+        ":password_field": login.password_field,
+        ":time_created": login.time_created,
+        ":time_last_used": login.time_last_used,
+        ":time_password_changed": login.time_password_changed,
+        ":times_used": login.times_used,
+    };
+    params.to_vec()
+}
+
+impl LoginDb {
+    /// Imports every login from the firefox-ios database at `path`, keeping
+    /// their GUIDs, usage counts, and sync metadata. The database is opened
+    /// read-only, and decrypted with `encryption_key` if one is given.
+    ///
+    /// Like `import_multiple`, this only works if there aren't any logins yet.
+    /// Rows that can't be imported are skipped and counted in the returned
+    /// metrics, rather than failing the whole migration.
+    pub fn import_ios_logins(
+        &self,
+        path: impl AsRef<Path>,
+        encryption_key: Option<&str>,
+    ) -> Result<MigrationMetrics> {
+        let start = Instant::now();
+        let scope = self.begin_interrupt_scope();
+        self.ensure_empty()?;
+
+        let ios = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        if let Some(key) = encryption_key {
+            set_encryption_key(&ios, key)?;
+        }
+        // This is the first read, so it's also where we find out if the key
+        // is wrong.
+        let version = ios.query_one::<i64>("PRAGMA user_version")?;
+        if version != IOS_SCHEMA_VERSION {
+            throw!(ErrorKind::InvalidIosDatabase(format!(
+                "Unsupported schema version {}",
+                version
+            )));
+        }
+
+        let mut metrics = MigrationMetrics::default();
+        let tx = self.unchecked_transaction()?;
+        self.import_ios_rows(&ios, &GET_IOS_MIRROR_SQL, &scope, &mut metrics, |row| {
+            let mut login = ios_login_from_row(row)?;
+            let is_overridden: bool = row.get("is_overridden")?;
+            let fixed = fixup_unless_deleted(&mut login, false)?;
+            let server_modified: i64 = row.get("server_modified")?;
+            let mut params = login_params(&login);
+            params.extend_from_slice(named_params! {
+                ":server_modified": server_modified,
+                ":is_overridden": is_overridden,
+            });
+            self.execute_named_cached(&INSERT_MIRROR_SQL, &params)?;
+            Ok(fixed)
+        })?;
+        self.import_ios_rows(&ios, &GET_IOS_LOCAL_SQL, &scope, &mut metrics, |row| {
+            let mut login = ios_login_from_row(row)?;
+            let is_deleted: bool = row.get("is_deleted")?;
+            let sync_status = SyncStatus::from_u8(row.get("sync_status")?)? as u8;
+            let fixed = fixup_unless_deleted(&mut login, is_deleted)?;
+            let local_modified: Option<i64> = row.get("local_modified")?;
+            let mut params = login_params(&login);
+            params.extend_from_slice(named_params! {
+                ":local_modified": local_modified,
+                ":is_deleted": is_deleted,
+                ":sync_status": sync_status,
+            });
+            self.execute_named_cached(&INSERT_LOCAL_SQL, &params)?;
+            Ok(fixed)
+        })?;
+        self.execute(RESTORE_ORPHANED_MIRROR_SQL, NO_PARAMS)?;
+        tx.commit()?;
+
+        metrics.total_duration = start.elapsed().as_millis() as u64;
+        log::info!(
+            "Migrated {} of {} iOS login rows ({} fixed) in {}ms",
+            metrics.num_succeeded,
+            metrics.num_processed,
+            metrics.num_fixed,
+            metrics.total_duration
+        );
+        Ok(metrics)
+    }
+
+    // Runs `import_row` for every row returned by `sql` from the iOS
+    // database. It returns true if the row needed fixing up.
+    fn import_ios_rows(
+        &self,
+        ios: &Connection,
+        sql: &str,
+        scope: &SqlInterruptScope,
+        metrics: &mut MigrationMetrics,
+        import_row: impl Fn(&Row<'_>) -> Result<bool>,
+    ) -> Result<()> {
+        let mut stmt = ios.prepare(sql)?;
+        let mut rows = stmt.query(NO_PARAMS)?;
+        while let Some(row) = rows.next()? {
+            scope.err_if_interrupted()?;
+            metrics.num_processed += 1;
+            match import_row(row) {
+                Ok(fixed) => {
+                    metrics.num_succeeded += 1;
+                    if fixed {
+                        metrics.num_fixed += 1;
+                    }
+                }
+                Err(e) => {
+                    let guid = row.get::<_, String>("guid").unwrap_or_default();
+                    log::warn!("Could not migrate iOS login {} ({}).", guid, e);
+                    metrics.num_failed += 1;
+                    metrics.errors.push(e.to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The firefox-ios v3 schema.
+    const IOS_SCHEMA_SQL: &str = "
+        CREATE TABLE loginsL (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            hostname TEXT NOT NULL,
+            httpRealm TEXT,
+            formSubmitURL TEXT,
+            usernameField TEXT,
+            passwordField TEXT,
+            timesUsed INTEGER NOT NULL DEFAULT 0,
+            timeCreated INTEGER NOT NULL,
+            timeLastUsed INTEGER,
+            timePasswordChanged INTEGER NOT NULL,
+            username TEXT,
+            password TEXT NOT NULL,
+            guid TEXT NOT NULL UNIQUE,
+            local_modified INTEGER,
+            is_deleted TINYINT NOT NULL DEFAULT 0,
+            sync_status TINYINT NOT NULL DEFAULT 2
+        );
+        CREATE TABLE loginsM (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            hostname TEXT NOT NULL,
+            httpRealm TEXT,
+            formSubmitURL TEXT,
+            usernameField TEXT,
+            passwordField TEXT,
+            timesUsed INTEGER NOT NULL DEFAULT 0,
+            timeCreated INTEGER NOT NULL,
+            timeLastUsed INTEGER,
+            timePasswordChanged INTEGER NOT NULL,
+            username TEXT,
+            password TEXT NOT NULL,
+            guid TEXT NOT NULL UNIQUE,
+            server_modified INTEGER NOT NULL,
+            is_overridden TINYINT NOT NULL DEFAULT 0
+        );
+        PRAGMA user_version = 3;
+    ";
+
+    const IOS_DATA_SQL: &str = "
+        INSERT INTO loginsM (guid, hostname, formSubmitURL, username, password,
+                             timesUsed, timeCreated, timeLastUsed, timePasswordChanged,
+                             server_modified, is_overridden)
+        VALUES ('synced000001', 'https://www.example.com', 'https://www.example.com',
+                'synced', 'password', 5, 1000000, 2000000, 1000000, 1500, 0),
+               ('changed00001', 'https://www.example.com', 'https://www.example.com',
+                'changed', 'password', 1, 1000000, 1000000, 1000000, 1500, 1),
+               ('deleted00001', 'https://www.example.com', 'https://www.example.com',
+                'deleted', 'password', 1, 1000000, 1000000, 1000000, 1500, 1);
+
+        INSERT INTO loginsL (guid, hostname, formSubmitURL, httpRealm, username, password,
+                             timesUsed, timeCreated, timeLastUsed, timePasswordChanged,
+                             local_modified, is_deleted, sync_status)
+        VALUES ('changed00001', 'https://www.example.com', 'https://www.example.com', NULL,
+                'changed', 'new password', 2, 1000000, 3000000, 3000000, 3000, 0, 1),
+               ('deleted00001', '', NULL, NULL, '', '', 1, 1000000, NULL, 4000000, 4000, 1, 1),
+               ('new000000001', 'https://www.example.org', NULL, 'realm',
+                'new', 'password', 0, 5000000, NULL, 5000000, 5000, 0, 2),
+               ('needsfixup01', 'https://www.example.org/login', 'https://www.example.org', NULL,
+                'fixup', 'password', 0, 5000000, NULL, 5000000, 5000, 0, 2),
+               ('invalid00001', 'https://www.example.org', 'https://www.example.org', NULL,
+                'invalid', '', 0, 5000000, NULL, 5000000, 5000, 0, 2);
+    ";
+
+    fn create_ios_db() -> tempfile::NamedTempFile {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let conn = Connection::open(file.path()).unwrap();
+        conn.execute_batch(IOS_SCHEMA_SQL).unwrap();
+        conn.execute_batch(IOS_DATA_SQL).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_import_ios_logins() {
+        let ios_db = create_ios_db();
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        let metrics = db.import_ios_logins(ios_db.path(), None).unwrap();
+        assert_eq!(metrics.num_processed, 8);
+        assert_eq!(metrics.num_succeeded, 7);
+        assert_eq!(metrics.num_failed, 1);
+        assert_eq!(metrics.num_fixed, 1);
+        assert_eq!(metrics.errors, vec!["Invalid login: Password is empty"]);
+
+        let mut guids = db
+            .get_all()
+            .unwrap()
+            .into_iter()
+            .map(|login| login.guid.into_string())
+            .collect::<Vec<_>>();
+        guids.sort();
+        assert_eq!(
+            guids,
+            vec![
+                "changed00001",
+                "needsfixup01",
+                "new000000001",
+                "synced000001"
+            ]
+        );
+
+        // Timestamps are converted to milliseconds, and usage is kept.
+        let synced = db.get_by_id("synced000001").unwrap().unwrap();
+        assert_eq!(synced.times_used, 5);
+        assert_eq!(synced.time_created, 1000);
+        assert_eq!(synced.time_last_used, 2000);
+        let changed = db.get_by_id("changed00001").unwrap().unwrap();
+        assert_eq!(changed.password, "new password");
+        assert_eq!(changed.time_password_changed, 3000);
+        let fixed = db.get_by_id("needsfixup01").unwrap().unwrap();
+        assert_eq!(fixed.hostname, "https://www.example.org");
+
+        // So is the sync metadata.
+        let server_modified: i64 = db
+            .query_row(
+                "SELECT server_modified FROM loginsM WHERE guid = 'synced000001'",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(server_modified, 1500);
+        let statuses: Vec<(String, bool, u8)> = db
+            .query_rows_and_then_named(
+                "SELECT guid, is_deleted, sync_status FROM loginsL ORDER BY guid",
+                &[],
+                |row| -> Result<_> { Ok((row.get(0)?, row.get(1)?, row.get(2)?)) },
+            )
+            .unwrap();
+        assert_eq!(
+            statuses,
+            vec![
+                ("changed00001".into(), false, SyncStatus::Changed as u8),
+                ("deleted00001".into(), true, SyncStatus::Changed as u8),
+                ("needsfixup01".into(), false, SyncStatus::New as u8),
+                ("new000000001".into(), false, SyncStatus::New as u8),
+            ]
+        );
+
+        // It's a one-shot migration.
+        match db
+            .import_ios_logins(ios_db.path(), None)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::NonEmptyTable => {}
+            e => panic!("Unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_import_ios_logins_wrong_version() {
+        let ios_db = create_ios_db();
+        Connection::open(ios_db.path())
+            .unwrap()
+            .execute_batch("PRAGMA user_version = 2")
+            .unwrap();
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        match db
+            .import_ios_logins(ios_db.path(), None)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidIosDatabase(_) => {}
+            e => panic!("Unexpected error: {:?}", e),
+        }
+    }
+}
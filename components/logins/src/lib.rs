@@ -9,14 +9,28 @@
 mod error;
 mod login;
 
+mod breach;
+mod csv_export;
+mod csv_import;
 mod db;
 mod engine;
+mod ios_import;
+mod never_save;
+mod observer;
+mod password_generator;
 pub mod schema;
 mod update_plan;
 mod util;
+mod vulnerable;
 
 mod ffi;
 
+pub use crate::breach::{BreachCheckConfig, BreachedLogin, DEFAULT_RANGE_ENDPOINT};
+pub use crate::csv_import::{CsvImportRow, CsvImportStatus};
 pub use crate::engine::*;
 pub use crate::error::*;
+pub use crate::ios_import::MigrationMetrics;
 pub use crate::login::*;
+pub use crate::observer::{ChangeType, LoginChange, LoginsObserver, ObserverHandle};
+pub use crate::password_generator::{generate_password, PasswordOptions, MAX_PASSWORD_LENGTH};
+pub use crate::vulnerable::VulnerableLogin;
@@ -7,8 +7,9 @@ use crate::util;
 use rusqlite::Row;
 use serde_derive::*;
 use std::time::{self, SystemTime};
-use sync15::ServerTimestamp;
+use sync15::{MaybeTombstone, ServerTimestamp};
 use sync_guid::Guid;
+use url::Url;
 
 #[derive(Debug, Clone, Hash, PartialEq, Serialize, Deserialize, Default)]
 #[serde(rename_all = "camelCase")]
@@ -69,6 +70,36 @@ where
     Ok(i64::deserialize(deserializer).unwrap_or_default().max(0))
 }
 
+/// A problem with a login that `Login::fixup` corrected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LoginFix {
+    /// The hostname wasn't a normalized origin. For example, it had
+    /// whitespace or a path, an uppercase or non-ASCII (instead of punycode)
+    /// domain, or a default port.
+    NormalizedHostname,
+    /// The same as `NormalizedHostname`, but for the `formSubmitURL`.
+    NormalizedFormSubmitUrl,
+    /// Both an empty `httpRealm` and a `formSubmitURL` were present, so the
+    /// realm was removed.
+    RemovedEmptyHttpRealm,
+    /// Both an empty `formSubmitURL` and a `httpRealm` were present, so the
+    /// `formSubmitURL` was removed.
+    RemovedEmptyFormSubmitUrl,
+}
+
+// Returns the normalized origin of `url`, or None if it isn't a URL with an
+// origin (which includes `javascript:` URLs, which Desktop saves as the
+// `formSubmitURL` for some forms).
+pub(crate) fn normalize_origin(url: &str) -> Option<String> {
+    let origin = Url::parse(url.trim()).ok()?.origin();
+    if origin.is_tuple() {
+        Some(origin.ascii_serialization())
+    } else {
+        None
+    }
+}
+
 fn string_or_default(row: &Row<'_>, col: &str) -> Result<String> {
     Ok(row.get::<_, Option<String>>(col)?.unwrap_or_default())
 }
@@ -103,6 +134,47 @@ impl Login {
         Ok(())
     }
 
+    /// Returns a copy of this login with any problems that we know how to fix
+    /// corrected, along with the fixes that were made, or an error if the
+    /// corrected login still isn't valid.
+    pub fn fixup(&self) -> Result<(Login, Vec<LoginFix>)> {
+        let mut login = self.clone();
+        let mut fixes = Vec::new();
+
+        if let Some(hostname) = normalize_origin(&login.hostname) {
+            if hostname != login.hostname {
+                login.hostname = hostname;
+                fixes.push(LoginFix::NormalizedHostname);
+            }
+        }
+
+        if let Some(form_submit_url) = login
+            .form_submit_url
+            .as_ref()
+            .and_then(|url| normalize_origin(url))
+        {
+            if Some(&form_submit_url) != login.form_submit_url.as_ref() {
+                login.form_submit_url = Some(form_submit_url);
+                fixes.push(LoginFix::NormalizedFormSubmitUrl);
+            }
+        }
+
+        if let (Some(form_submit_url), Some(http_realm)) =
+            (&login.form_submit_url, &login.http_realm)
+        {
+            if http_realm.is_empty() {
+                login.http_realm = None;
+                fixes.push(LoginFix::RemovedEmptyHttpRealm);
+            } else if form_submit_url.is_empty() {
+                login.form_submit_url = None;
+                fixes.push(LoginFix::RemovedEmptyFormSubmitUrl);
+            }
+        }
+
+        login.check_valid()?;
+        Ok((login, fixes))
+    }
+
     pub(crate) fn from_row(row: &Row<'_>) -> Result<Login> {
         Ok(Login {
             guid: row.get("guid")?,
@@ -266,12 +338,7 @@ impl SyncLoginData {
         ts: ServerTimestamp,
     ) -> std::result::Result<Self, serde_json::Error> {
         let guid = payload.id.clone();
-        let login: Option<Login> = if payload.is_tombstone() {
-            None
-        } else {
-            let record: Login = payload.into_record()?;
-            Some(record)
-        };
+        let login = MaybeTombstone::<Login>::from_payload(payload)?.into_record();
         Ok(Self {
             guid,
             local: None,
@@ -468,6 +535,59 @@ impl Login {
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn test_fixup() {
+        let login = Login {
+            hostname: " https://Www.Example.COM:443/path ".into(),
+            form_submit_url: Some("https://münchen.example/login".into()),
+            http_realm: Some("".into()),
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        };
+        let (fixed, fixes) = login.fixup().unwrap();
+        assert_eq!(fixed.hostname, "https://www.example.com");
+        assert_eq!(
+            fixed.form_submit_url.as_ref().unwrap(),
+            "https://xn--mnchen-3ya.example"
+        );
+        assert_eq!(fixed.http_realm, None);
+        assert_eq!(
+            fixes,
+            vec![
+                LoginFix::NormalizedHostname,
+                LoginFix::NormalizedFormSubmitUrl,
+                LoginFix::RemovedEmptyHttpRealm,
+            ]
+        );
+
+        // Valid logins aren't changed.
+        let (refixed, fixes) = fixed.fixup().unwrap();
+        assert_eq!(refixed, fixed);
+        assert!(fixes.is_empty());
+
+        let login = Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("".into()),
+            http_realm: Some("realm".into()),
+            username: "test".into(),
+            password: "test".into(),
+            ..Login::default()
+        };
+        let (fixed, fixes) = login.fixup().unwrap();
+        assert_eq!(fixed.form_submit_url, None);
+        assert_eq!(fixes, vec![LoginFix::RemovedEmptyFormSubmitUrl]);
+
+        let login = Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("javascript:".into()),
+            ..login
+        };
+        // Both targets are still present, and we can't tell which is right.
+        assert!(login.fixup().is_err());
+    }
+
     #[test]
     fn test_invalid_payload_timestamp() {
         #[allow(clippy::unreadable_literal)]
@@ -0,0 +1,134 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The list of origins where the user asked us to never offer to save their
+//! logins. This is stored in the `loginsNeverSave` table.
+//!
+//! This isn't synced. The passwords collection is the only one we could use,
+//! and other clients expect every record in it to be a login.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use crate::login::normalize_origin;
+use crate::util;
+use rusqlite::{named_params, NO_PARAMS};
+use sql_support::ConnExt;
+use std::time::SystemTime;
+
+fn origin_or_error(origin: &str) -> Result<String> {
+    match normalize_origin(origin) {
+        Some(origin) => Ok(origin),
+        None => throw!(ErrorKind::InvalidOrigin),
+    }
+}
+
+impl LoginDb {
+    /// Adds `origin` to the never-save list. Any URL can be passed, and only
+    /// its origin is stored, so every page on the site is covered.
+    pub fn add_never_save(&self, origin: &str) -> Result<()> {
+        let origin = origin_or_error(origin)?;
+        let now_ms = util::system_time_ms_i64(SystemTime::now());
+        self.execute_named_cached(
+            "INSERT OR IGNORE INTO loginsNeverSave (origin, time_created)
+             VALUES (:origin, :now_ms)",
+            named_params! { ":origin": origin, ":now_ms": now_ms },
+        )?;
+        Ok(())
+    }
+
+    /// Removes `origin` from the never-save list, returning true if it was on
+    /// the list.
+    pub fn remove_never_save(&self, origin: &str) -> Result<bool> {
+        let origin = origin_or_error(origin)?;
+        Ok(self.execute_named_cached(
+            "DELETE FROM loginsNeverSave WHERE origin = :origin",
+            named_params! { ":origin": origin },
+        )? != 0)
+    }
+
+    /// Returns true if we should never offer to save logins for `origin`.
+    /// Invalid origins aren't on the list.
+    pub fn is_never_save(&self, origin: &str) -> Result<bool> {
+        let origin = match normalize_origin(origin) {
+            Some(origin) => origin,
+            None => return Ok(false),
+        };
+        Ok(self.query_row_named(
+            "SELECT EXISTS(SELECT 1 FROM loginsNeverSave WHERE origin = :origin)",
+            named_params! { ":origin": origin },
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Returns every origin on the never-save list, most recently added first.
+    pub fn get_never_save(&self) -> Result<Vec<String>> {
+        self.query_rows_and_then_named_cached(
+            "SELECT origin FROM loginsNeverSave ORDER BY time_created DESC, origin",
+            &[],
+            |row| -> Result<String> { Ok(row.get(0)?) },
+        )
+    }
+
+    pub fn clear_never_save(&self) -> Result<()> {
+        self.execute("DELETE FROM loginsNeverSave", NO_PARAMS)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_never_save() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        assert!(!db.is_never_save("https://www.example.com").unwrap());
+
+        db.add_never_save("https://www.example.com/login?next=/")
+            .unwrap();
+        db.add_never_save("https://WWW.EXAMPLE.COM").unwrap();
+        db.add_never_save("http://example.org:8080").unwrap();
+        assert!(db.add_never_save("not a url").is_err());
+        assert!(db.add_never_save("javascript:").is_err());
+
+        assert!(db.is_never_save("https://www.example.com").unwrap());
+        assert!(db.is_never_save("https://www.example.com/account").unwrap());
+        assert!(!db.is_never_save("http://www.example.com").unwrap());
+        assert!(!db.is_never_save("http://example.org").unwrap());
+        assert!(!db.is_never_save("not a url").unwrap());
+        let mut origins = db.get_never_save().unwrap();
+        origins.sort();
+        assert_eq!(
+            origins,
+            vec!["http://example.org:8080", "https://www.example.com"]
+        );
+
+        assert!(db.remove_never_save("https://www.example.com/").unwrap());
+        assert!(!db.remove_never_save("https://www.example.com").unwrap());
+        assert_eq!(
+            db.get_never_save().unwrap(),
+            vec!["http://example.org:8080"]
+        );
+
+        db.clear_never_save().unwrap();
+        assert!(db.get_never_save().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_never_save_wipe() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        db.add_never_save("https://www.example.com").unwrap();
+
+        // Deleting all logins isn't the same as asking us to forget the
+        // sites the user doesn't want to save logins for.
+        db.wipe(&db.begin_interrupt_scope()).unwrap();
+        assert_eq!(
+            db.get_never_save().unwrap(),
+            vec!["https://www.example.com"]
+        );
+
+        db.wipe_local().unwrap();
+        assert!(db.get_never_save().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,155 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Notifying observers when logins change, so that UIs can update the logins
+//! they show without querying all of them again.
+
+use crate::login::Login;
+use serde_derive::*;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::sync::Arc;
+use sync_guid::Guid;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ChangeType {
+    Added,
+    Updated,
+    Deleted,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LoginChange {
+    pub guid: Guid,
+    pub change_type: ChangeType,
+}
+
+impl LoginChange {
+    pub(crate) fn new(guid: impl Into<Guid>, change_type: ChangeType) -> Self {
+        Self {
+            guid: guid.into(),
+            change_type,
+        }
+    }
+}
+
+/// Something that wants to know when logins change. This is called after
+/// the changes have been committed, both for local changes (adding, updating,
+/// deleting, or using logins) and for incoming changes applied by a sync.
+pub trait LoginsObserver: Send + Sync {
+    fn on_changes(&self, changes: &[LoginChange]);
+}
+
+/// Returned when registering an observer, and used to unregister it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObserverHandle(u64);
+
+#[derive(Default)]
+pub(crate) struct Observers {
+    next_id: Cell<u64>,
+    observers: RefCell<Vec<(ObserverHandle, Arc<dyn LoginsObserver>)>>,
+}
+
+impl Observers {
+    pub fn register(&self, observer: Arc<dyn LoginsObserver>) -> ObserverHandle {
+        let handle = ObserverHandle(self.next_id.get());
+        self.next_id.set(handle.0 + 1);
+        self.observers.borrow_mut().push((handle, observer));
+        handle
+    }
+
+    /// Returns true if the observer was registered.
+    pub fn unregister(&self, handle: ObserverHandle) -> bool {
+        let mut observers = self.observers.borrow_mut();
+        let count = observers.len();
+        observers.retain(|(h, _)| *h != handle);
+        observers.len() != count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.borrow().is_empty()
+    }
+
+    pub fn notify(&self, changes: &[LoginChange]) {
+        if changes.is_empty() {
+            return;
+        }
+        // Copy the list first, so that an observer can register or unregister
+        // observers from `on_changes`.
+        let observers = self
+            .observers
+            .borrow()
+            .iter()
+            .map(|(_, o)| o.clone())
+            .collect::<Vec<_>>();
+        for observer in observers {
+            observer.on_changes(changes);
+        }
+    }
+}
+
+/// Returns the changes between two lists of logins, for operations that can
+/// change logins we don't know about in advance, like syncing or wiping.
+pub(crate) fn diff_logins(before: Vec<Login>, after: Vec<Login>) -> Vec<LoginChange> {
+    let mut before = before
+        .into_iter()
+        .map(|login| (login.guid.clone(), login))
+        .collect::<HashMap<_, _>>();
+    let mut changes = Vec::new();
+    for login in after {
+        match before.remove(&login.guid) {
+            None => changes.push(LoginChange::new(login.guid, ChangeType::Added)),
+            Some(old) if old != login => {
+                changes.push(LoginChange::new(login.guid, ChangeType::Updated))
+            }
+            Some(_) => {}
+        }
+    }
+    changes.extend(
+        before
+            .into_iter()
+            .map(|(guid, _)| LoginChange::new(guid, ChangeType::Deleted)),
+    );
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff_logins() {
+        let login = |guid: &str, password: &str| Login {
+            guid: guid.into(),
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com".into()),
+            username: "user".into(),
+            password: password.into(),
+            ..Login::default()
+        };
+        let mut changes = diff_logins(
+            vec![
+                login("dummy_000001", "a"),
+                login("dummy_000002", "a"),
+                login("dummy_000003", "a"),
+            ],
+            vec![
+                login("dummy_000001", "a"),
+                login("dummy_000002", "b"),
+                login("dummy_000004", "a"),
+            ],
+        );
+        changes.sort_by(|a, b| a.guid.cmp(&b.guid));
+        assert_eq!(
+            changes,
+            vec![
+                LoginChange::new("dummy_000002", ChangeType::Updated),
+                LoginChange::new("dummy_000003", ChangeType::Deleted),
+                LoginChange::new("dummy_000004", ChangeType::Added),
+            ]
+        );
+    }
+}
@@ -0,0 +1,172 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Generating random passwords, so that every app generates them the same
+//! way. A generated password can be saved with `PasswordEngine::add`, like
+//! any other, which fills in its timestamps.
+
+use crate::error::*;
+use rc_crypto::rand;
+use serde_derive::*;
+
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "0123456789";
+const SYMBOLS: &str = "-~!@#$%^&*_+=)}:;\"'>,.?]";
+// Characters that are easy to mistake for each other when reading or typing
+// a password.
+const AMBIGUOUS: &str = "lI1O0";
+
+/// Passwords longer than this aren't accepted by many sites.
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PasswordOptions {
+    pub length: usize,
+    pub lowercase: bool,
+    pub uppercase: bool,
+    pub digits: bool,
+    pub symbols: bool,
+    /// Leaves out characters that look alike, such as `l`, `I` and `1`.
+    pub avoid_ambiguous: bool,
+}
+
+impl Default for PasswordOptions {
+    fn default() -> Self {
+        Self {
+            length: 15,
+            lowercase: true,
+            uppercase: true,
+            digits: true,
+            symbols: false,
+            avoid_ambiguous: true,
+        }
+    }
+}
+
+/// Generates a random password. It contains at least one character from each
+/// character class enabled in `options`, and the rest are chosen from all of
+/// them.
+pub fn generate_password(options: &PasswordOptions) -> Result<String> {
+    let classes = [
+        (options.lowercase, LOWERCASE),
+        (options.uppercase, UPPERCASE),
+        (options.digits, DIGITS),
+        (options.symbols, SYMBOLS),
+    ]
+    .iter()
+    .filter(|(enabled, _)| *enabled)
+    .map(|(_, chars)| {
+        chars
+            .chars()
+            .filter(|c| !options.avoid_ambiguous || !AMBIGUOUS.contains(*c))
+            .collect::<Vec<_>>()
+    })
+    .collect::<Vec<_>>();
+
+    if classes.is_empty() {
+        throw!(ErrorKind::InvalidPasswordOptions(
+            "At least one character class must be enabled".into()
+        ));
+    }
+    if options.length < classes.len() || options.length > MAX_PASSWORD_LENGTH {
+        throw!(ErrorKind::InvalidPasswordOptions(format!(
+            "Length must be between {} and {}",
+            classes.len(),
+            MAX_PASSWORD_LENGTH
+        )));
+    }
+
+    let all = classes.concat();
+    let mut password = Vec::with_capacity(options.length);
+    for class in &classes {
+        password.push(class[random_index(class.len())?]);
+    }
+    while password.len() < options.length {
+        password.push(all[random_index(all.len())?]);
+    }
+    // Shuffle, so that the required characters aren't always at the start.
+    for i in (1..password.len()).rev() {
+        password.swap(i, random_index(i + 1)?);
+    }
+    Ok(password.into_iter().collect())
+}
+
+// Returns a uniformly distributed random number in `0..len`.
+fn random_index(len: usize) -> Result<usize> {
+    debug_assert!(len > 0 && len <= 256);
+    // Discard bytes that would bias the result towards lower numbers.
+    let limit = 256 - (256 % len);
+    loop {
+        let mut byte = [0u8; 1];
+        rand::fill(&mut byte)?;
+        let value = usize::from(byte[0]);
+        if value < limit {
+            return Ok(value % len);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_password() {
+        let password = generate_password(&PasswordOptions::default()).unwrap();
+        assert_eq!(password.len(), 15);
+        assert!(password.chars().any(|c| c.is_ascii_lowercase()));
+        assert!(password.chars().any(|c| c.is_ascii_uppercase()));
+        assert!(password.chars().any(|c| c.is_ascii_digit()));
+        assert!(password.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert!(!password.chars().any(|c| AMBIGUOUS.contains(c)));
+        assert_ne!(
+            password,
+            generate_password(&PasswordOptions::default()).unwrap()
+        );
+
+        let options = PasswordOptions {
+            length: 2,
+            lowercase: false,
+            uppercase: false,
+            digits: true,
+            symbols: true,
+            avoid_ambiguous: false,
+        };
+        for _ in 0..20 {
+            let password = generate_password(&options).unwrap();
+            assert_eq!(password.len(), 2);
+            assert!(password.chars().any(|c| c.is_ascii_digit()));
+            assert!(password.chars().any(|c| SYMBOLS.contains(c)));
+        }
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        for options in &[
+            // Too short to include every character class.
+            PasswordOptions {
+                length: 3,
+                symbols: true,
+                ..PasswordOptions::default()
+            },
+            PasswordOptions {
+                length: MAX_PASSWORD_LENGTH + 1,
+                ..PasswordOptions::default()
+            },
+            PasswordOptions {
+                lowercase: false,
+                uppercase: false,
+                digits: false,
+                ..PasswordOptions::default()
+            },
+        ] {
+            match generate_password(options).unwrap_err().kind() {
+                ErrorKind::InvalidPasswordOptions(_) => {}
+                e => panic!("Unexpected error: {:?}", e),
+            }
+        }
+    }
+}
@@ -2,15 +2,19 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-//! Logins Schema v4
+//! Logins Schema v7
 //! ================
 //!
 //! The schema we use is a evolution of the firefox-ios logins database format.
-//! There are three tables:
+//! There are six tables:
 //!
 //! - `loginsL`: The local table.
 //! - `loginsM`: The mirror table.
 //! - `loginsSyncMeta`: The table used to to store various sync metadata.
+//! - `loginsBreachCache`: The table used to cache breached password checks.
+//! - `loginsVulnerable`: The table used to store which logins are vulnerable.
+//! - `loginsNeverSave`: The table used to store the origins where we should
+//!   never offer to save logins.
 //!
 //! ## `loginsL`
 //!
@@ -87,15 +91,66 @@
 //!    [GLOBAL_STATE_META_KEY]. This is a `sync15::GlobalState` stored as
 //!    JSON.
 //!
+//! ## `loginsBreachCache`
+//!
+//! This caches the results of checking saved passwords against a breached
+//! password database (see the `breach` module), so that we don't need to
+//! check every password again each time. It was added in version 5, and is
+//! never synced.
+//!
+//! ### `loginsBreachCache` Columns
+//!
+//! - `guid`: The GUID of the login that was checked.
+//!
+//! - `time_password_changed`: The login's `timePasswordChanged` when it was
+//!   checked. If it doesn't match the login's current `timePasswordChanged`,
+//!   the password has changed since, and the row is ignored.
+//!
+//! - `breach_count`: The number of times the password appears in the
+//!   breached password database, or 0 if it doesn't.
+//!
+//! - `checked_at`: A millisecond local timestamp indicating when the
+//!   password was checked.
+//!
+//! ## `loginsVulnerable`
+//!
+//! This stores which logins a breach alert feature has found to be
+//! vulnerable (see the `vulnerable` module). It's kept apart from `loginsL`,
+//! so that it isn't lost when a sync replaces the local record with the
+//! mirror. It was added in version 6, and is never synced.
+//!
+//! ### `loginsVulnerable` Columns
+//!
+//! - `guid`: The GUID of the vulnerable login.
+//!
+//! - `breach_detected_at`: A millisecond timestamp indicating when the
+//!   breach was detected.
+//!
+//! ## `loginsNeverSave`
+//!
+//! This stores the origins where the user asked us to never save logins (see
+//! the `never_save` module). It was added in version 7. It isn't synced,
+//! since the passwords collection can only contain logins, and other clients
+//! would reject anything else.
+//!
+//! ### `loginsNeverSave` Columns
+//!
+//! - `origin`: The normalized origin, in the same format as `hostname`.
+//!
+//! - `time_created`: A millisecond local timestamp indicating when the
+//!   origin was added.
+//!
 
 use crate::error::*;
 use lazy_static::lazy_static;
 use rusqlite::Connection;
 use sql_support::ConnExt;
 
-/// Note that firefox-ios is currently on version 3. Version 4 is this version,
-/// which adds a metadata table and changes timestamps to be in milliseconds
-pub const VERSION: i64 = 4;
+/// Note that firefox-ios is currently on version 3. Version 4 adds a metadata
+/// table and changes timestamps to be in milliseconds, version 5 adds the
+/// breached password cache, version 6 adds the table of vulnerable logins, and
+/// version 7 (this version) adds the never-save list.
+pub const VERSION: i64 = 7;
 
 /// Every column shared by both tables except for `id`
 ///
@@ -179,6 +234,32 @@ const CREATE_META_TABLE_SQL: &str = "
     )
 ";
 
+const CREATE_BREACH_CACHE_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsBreachCache (
+        guid                  TEXT PRIMARY KEY,
+        time_password_changed INTEGER NOT NULL,
+        breach_count          INTEGER NOT NULL,
+        -- Milliseconds.
+        checked_at            INTEGER NOT NULL
+    )
+";
+
+const CREATE_VULNERABLE_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsVulnerable (
+        guid               TEXT PRIMARY KEY,
+        -- Milliseconds.
+        breach_detected_at INTEGER NOT NULL
+    )
+";
+
+const CREATE_NEVER_SAVE_TABLE_SQL: &str = "
+    CREATE TABLE IF NOT EXISTS loginsNeverSave (
+        origin       TEXT PRIMARY KEY,
+        -- Milliseconds.
+        time_created INTEGER NOT NULL
+    )
+";
+
 const CREATE_OVERRIDE_HOSTNAME_INDEX_SQL: &str = "
     CREATE INDEX IF NOT EXISTS idx_loginsM_is_overridden_hostname
     ON loginsM (is_overridden, hostname)
@@ -280,6 +361,18 @@ fn upgrade(db: &Connection, from: i64) -> Result<()> {
             &*SET_VERSION_SQL,
         ])?;
     }
+    if from < 5 {
+        // The `loginsBreachCache` table was added in v5.
+        db.execute_all(&[CREATE_BREACH_CACHE_TABLE_SQL, &*SET_VERSION_SQL])?;
+    }
+    if from < 6 {
+        // The `loginsVulnerable` table was added in v6.
+        db.execute_all(&[CREATE_VULNERABLE_TABLE_SQL, &*SET_VERSION_SQL])?;
+    }
+    if from < 7 {
+        // The `loginsNeverSave` table was added in v7.
+        db.execute_all(&[CREATE_NEVER_SAVE_TABLE_SQL, &*SET_VERSION_SQL])?;
+    }
     Ok(())
 }
 
@@ -291,6 +384,9 @@ pub(crate) fn create(db: &Connection) -> Result<()> {
         CREATE_OVERRIDE_HOSTNAME_INDEX_SQL,
         CREATE_DELETED_HOSTNAME_INDEX_SQL,
         CREATE_META_TABLE_SQL,
+        CREATE_BREACH_CACHE_TABLE_SQL,
+        CREATE_VULNERABLE_TABLE_SQL,
+        CREATE_NEVER_SAVE_TABLE_SQL,
         &*SET_VERSION_SQL,
     ])?;
     Ok(())
@@ -302,6 +398,9 @@ pub(crate) fn drop(db: &Connection) -> Result<()> {
         "DROP TABLE IF EXISTS loginsM",
         "DROP TABLE IF EXISTS loginsL",
         "DROP TABLE IF EXISTS loginsSyncMeta",
+        "DROP TABLE IF EXISTS loginsBreachCache",
+        "DROP TABLE IF EXISTS loginsVulnerable",
+        "DROP TABLE IF EXISTS loginsNeverSave",
         "PRAGMA user_version = 0",
     ])?;
     Ok(())
@@ -3,10 +3,20 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::error::*;
+use lazy_static::lazy_static;
+use publicsuffix::List;
 use rusqlite::Row;
 use std::time;
 use url::Url;
 
+lazy_static! {
+    // A copy of https://publicsuffix.org/list/public_suffix_list.dat. It
+    // should be updated from there from time to time.
+    static ref PUBLIC_SUFFIX_LIST: List =
+        List::from_str(include_str!("../data/public_suffix_list.dat"))
+            .expect("The bundled public suffix list should be valid");
+}
+
 pub fn url_host_port(url_str: &str) -> Option<String> {
     let url = Url::parse(url_str).ok()?;
     let host = url.host_str()?;
@@ -17,6 +27,24 @@ pub fn url_host_port(url_str: &str) -> Option<String> {
     })
 }
 
+/// Returns the registrable domain for `domain`, which should already be
+/// normalized (as `url::Host` does). This is its public suffix, plus one more
+/// label: `example.co.uk` for `www.example.co.uk`. Domains without a known
+/// suffix, like `localhost`, are their own base domain. Returns `None` for
+/// invalid domains, and fails for domains that are themselves public
+/// suffixes.
+pub fn base_domain(domain: &str) -> Result<Option<String>> {
+    let parsed = match PUBLIC_SUFFIX_LIST.parse_domain(domain) {
+        Ok(parsed) => parsed,
+        Err(_) => return Ok(None),
+    };
+    match parsed.root() {
+        Some(root) => Ok(Some(root.to_owned())),
+        None if !parsed.has_known_suffix() => Ok(Some(domain.to_owned())),
+        None => throw!(ErrorKind::PublicSuffix),
+    }
+}
+
 pub fn system_time_millis_from_row(row: &Row<'_>, col_name: &str) -> Result<time::SystemTime> {
     let time_ms = row.get::<_, Option<i64>>(col_name)?.unwrap_or_default() as u64;
     Ok(time::UNIX_EPOCH + time::Duration::from_millis(time_ms))
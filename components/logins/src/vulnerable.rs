@@ -0,0 +1,156 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Remembering which logins are vulnerable, for breach alert features. This
+//! is stored locally in the `loginsVulnerable` table, and is never synced.
+
+use crate::db::LoginDb;
+use crate::error::*;
+use rusqlite::{named_params, Row, NO_PARAMS};
+use serde_derive::*;
+use sql_support::ConnExt;
+use sync_guid::Guid;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VulnerableLogin {
+    pub guid: Guid,
+    /// When the breach was detected, in milliseconds since the Unix epoch.
+    pub breach_detected_at: i64,
+}
+
+impl VulnerableLogin {
+    fn from_row(row: &Row<'_>) -> Result<VulnerableLogin> {
+        Ok(VulnerableLogin {
+            guid: row.get("guid")?,
+            breach_detected_at: row.get("breach_detected_at")?,
+        })
+    }
+}
+
+// Rows for logins that have been deleted by a sync are left behind, so we
+// only return the ones for logins that still exist.
+const GET_VULNERABLE_SQL: &str = "
+    SELECT guid, breach_detected_at
+    FROM loginsVulnerable
+    WHERE guid IN (
+        SELECT guid FROM loginsL WHERE is_deleted = 0
+        UNION ALL
+        SELECT guid FROM loginsM WHERE is_overridden = 0
+    )";
+
+impl LoginDb {
+    /// Marks the login with the given `id` as vulnerable, because a breach
+    /// was detected at `breach_detected_at` (in milliseconds). Marking it
+    /// again replaces the time.
+    pub fn set_vulnerable(&self, id: &str, breach_detected_at: i64) -> Result<()> {
+        let tx = self.unchecked_transaction()?;
+        if !self.exists(id)? {
+            throw!(ErrorKind::NoSuchRecord(id.to_owned()));
+        }
+        self.execute_named_cached(
+            "REPLACE INTO loginsVulnerable (guid, breach_detected_at)
+             VALUES (:guid, :breach_detected_at)",
+            named_params! {
+                ":guid": id,
+                ":breach_detected_at": breach_detected_at,
+            },
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Clears the vulnerable mark on the login with the given `id`, for
+    /// example after the user changes its password. Returns true if it was
+    /// marked.
+    pub fn clear_vulnerable(&self, id: &str) -> Result<bool> {
+        Ok(self.execute_named_cached(
+            "DELETE FROM loginsVulnerable WHERE guid = :guid",
+            named_params! { ":guid": id },
+        )? != 0)
+    }
+
+    pub fn clear_all_vulnerable(&self) -> Result<()> {
+        self.execute("DELETE FROM loginsVulnerable", NO_PARAMS)?;
+        Ok(())
+    }
+
+    /// Returns when a breach was detected for the login with the given `id`,
+    /// or None if it isn't vulnerable.
+    pub fn breach_detected_at(&self, id: &str) -> Result<Option<i64>> {
+        self.try_query_row(
+            "SELECT breach_detected_at FROM loginsVulnerable WHERE guid = :guid",
+            named_params! { ":guid": id },
+            |row| -> Result<i64> { Ok(row.get(0)?) },
+            true,
+        )
+    }
+
+    pub fn is_vulnerable(&self, id: &str) -> Result<bool> {
+        Ok(self.breach_detected_at(id)?.is_some())
+    }
+
+    pub fn get_vulnerable(&self) -> Result<Vec<VulnerableLogin>> {
+        self.query_rows_and_then_named_cached(GET_VULNERABLE_SQL, &[], VulnerableLogin::from_row)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::login::Login;
+
+    #[test]
+    fn test_vulnerable() {
+        let db = LoginDb::open_in_memory(Some("testing")).unwrap();
+        for guid in &["dummy_000001", "dummy_000002"] {
+            db.add(Login {
+                guid: (*guid).into(),
+                hostname: "https://www.example.com".into(),
+                form_submit_url: Some("https://www.example.com".into()),
+                username: (*guid).into(),
+                password: "password".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        }
+        assert!(!db.is_vulnerable("dummy_000001").unwrap());
+        assert!(db.get_vulnerable().unwrap().is_empty());
+
+        db.set_vulnerable("dummy_000001", 1000).unwrap();
+        db.set_vulnerable("dummy_000002", 1000).unwrap();
+        db.set_vulnerable("dummy_000002", 2000).unwrap();
+        assert!(db.set_vulnerable("dummy_999999", 1000).is_err());
+        assert!(db.is_vulnerable("dummy_000001").unwrap());
+        assert_eq!(db.breach_detected_at("dummy_000002").unwrap(), Some(2000));
+        assert_eq!(db.breach_detected_at("dummy_999999").unwrap(), None);
+
+        // Updating a login keeps the mark.
+        let mut login = db.get_by_id("dummy_000001").unwrap().unwrap();
+        login.username = "new username".into();
+        db.update(login).unwrap();
+        let mut vulnerable = db.get_vulnerable().unwrap();
+        vulnerable.sort_by(|a, b| a.guid.cmp(&b.guid));
+        assert_eq!(
+            vulnerable,
+            vec![
+                VulnerableLogin {
+                    guid: "dummy_000001".into(),
+                    breach_detected_at: 1000,
+                },
+                VulnerableLogin {
+                    guid: "dummy_000002".into(),
+                    breach_detected_at: 2000,
+                },
+            ]
+        );
+
+        assert!(db.clear_vulnerable("dummy_000001").unwrap());
+        assert!(!db.clear_vulnerable("dummy_000001").unwrap());
+        // Deleting a login clears the mark.
+        db.delete("dummy_000002").unwrap();
+        assert!(!db.is_vulnerable("dummy_000002").unwrap());
+        assert!(db.get_vulnerable().unwrap().is_empty());
+    }
+}
@@ -192,6 +192,25 @@ internal interface LibPlacesFFI : Library {
         out_err: RustError.ByReference
     )
 
+    fun places_api_create_diagnostic_bundle(
+        handle: PlacesApiHandle,
+        out_err: RustError.ByReference
+    ): Pointer?
+
+    fun places_api_checkpoint(
+        apiHandle: PlacesApiHandle,
+        connHandle: PlacesConnectionHandle,
+        mode: Byte,
+        out_err: RustError.ByReference
+    ): Pointer?
+
+    fun places_api_set_journal_size_limit(
+        apiHandle: PlacesApiHandle,
+        connHandle: PlacesConnectionHandle,
+        limit: Long,
+        out_err: RustError.ByReference
+    )
+
     fun bookmarks_get_all_with_url(
         handle: PlacesConnectionHandle,
         url: String,
@@ -105,6 +105,47 @@ class PlacesApi(path: String) : PlacesManager, AutoCloseable {
         }
         return SyncTelemetryPing.fromJSONString(pingJSONString)
     }
+
+    /**
+     * Returns a JSON string describing the database and the most recent
+     * syncs, suitable for attaching to bug reports. It never includes
+     * URLs, titles or account identifiers.
+     */
+    fun createDiagnosticBundle(): String {
+        return rustCallForString(this) { error ->
+            LibPlacesFFI.INSTANCE.places_api_create_diagnostic_bundle(this.handle.get(), error)
+        }
+    }
+
+    /**
+     * Copies the write-ahead log back into the database, using the writer
+     * connection. Use [CheckpointMode.TRUNCATE] before backing up the
+     * database file.
+     */
+    fun checkpoint(mode: CheckpointMode = CheckpointMode.PASSIVE): CheckpointResult {
+        val json = synchronized(writeConn) {
+            rustCallForString(this) { error ->
+                LibPlacesFFI.INSTANCE.places_api_checkpoint(
+                        this.handle.get(), writeConn.handle.get(), mode.value, error)
+            }
+        }
+        return CheckpointResult.fromJSON(JSONObject(json))
+    }
+
+    /**
+     * Limits how big the write-ahead log stays after a checkpoint, for the
+     * writer connection and every reader connection.
+     *
+     * @param limit The limit, in bytes, or null to remove it.
+     */
+    fun setJournalSizeLimit(limit: Long?) {
+        synchronized(writeConn) {
+            rustCall(this) { error ->
+                LibPlacesFFI.INSTANCE.places_api_set_journal_size_limit(
+                        this.handle.get(), writeConn.handle.get(), limit ?: -1L, error)
+            }
+        }
+    }
 }
 
 internal inline fun <U> rustCall(syncOn: Any, callback: (RustError.ByReference) -> U): U {
@@ -142,7 +183,7 @@ internal inline fun rustCallForOptString(syncOn: Any, callback: (RustError.ByRef
 
 @Suppress("TooGenericExceptionCaught")
 open class PlacesConnection internal constructor(connHandle: Long) : InterruptibleConnection, AutoCloseable {
-    protected var handle: AtomicLong = AtomicLong(0)
+    internal var handle: AtomicLong = AtomicLong(0)
     protected var interruptHandle: InterruptHandle
 
     init {
@@ -901,6 +942,43 @@ fun stringOrNull(jsonObject: JSONObject, key: String): String? {
     }
 }
 
+/**
+ * How hard [PlacesApi.checkpoint] should try to copy the write-ahead log
+ * back into the database. These are the same as SQLite's checkpoint modes.
+ */
+enum class CheckpointMode(val value: Byte) {
+    /** Copies as much as it can without waiting for readers or writers. */
+    PASSIVE(0),
+    /** Waits for writers to finish, then copies the whole log. */
+    FULL(1),
+    /** Like [FULL], and also waits for readers, so that the next writer starts the log over. */
+    RESTART(2),
+    /** Like [RESTART], and also truncates the log file. Use this before backing up the database file. */
+    TRUNCATE(3)
+}
+
+/**
+ * What [PlacesApi.checkpoint] did.
+ */
+data class CheckpointResult(
+    /** True if another connection kept the checkpoint from finishing. */
+    val busy: Boolean,
+    /** The number of pages in the write-ahead log. */
+    val logPages: Long,
+    /** The number of those pages that were copied into the database. */
+    val checkpointedPages: Long
+) {
+    companion object {
+        fun fromJSON(jsonObject: JSONObject): CheckpointResult {
+            return CheckpointResult(
+                busy = jsonObject.getBoolean("busy"),
+                logPages = jsonObject.getLong("logPages"),
+                checkpointedPages = jsonObject.getLong("checkpointedPages")
+            )
+        }
+    }
+}
+
 data class SearchResult(
     val searchString: String,
     val url: String,
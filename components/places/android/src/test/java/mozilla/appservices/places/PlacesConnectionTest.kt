@@ -12,6 +12,7 @@ import org.robolectric.RobolectricTestRunner
 import org.robolectric.annotation.Config
 import org.junit.Test
 import org.junit.Assert.assertEquals
+import org.junit.Assert.assertFalse
 import org.junit.Before
 
 @RunWith(RobolectricTestRunner::class)
@@ -227,6 +228,17 @@ class PlacesConnectionTest {
         assertEquals(0, empty.size)
     }
 
+    @Test
+    fun testCheckpoint() {
+        // Both of these use the writer connection, which we're holding.
+        api.setJournalSizeLimit(0)
+        db.noteObservation(VisitObservation(url = "https://www.example.com/1", visitType = VisitType.LINK))
+        val result = api.checkpoint(CheckpointMode.TRUNCATE)
+        assertFalse(result.busy)
+        assertEquals(0, result.logPages)
+        api.setJournalSizeLimit(null)
+    }
+
     @Test
     fun testCreateBookmark() {
         val itemGUID = db.createBookmarkItem(
@@ -81,6 +81,7 @@ fn bench_search_frecent(c: &mut Criterion) {
             SearchParams {
                 search_string: "mozilla".into(),
                 limit: 10,
+                ..SearchParams::default()
             },
         )
         .unwrap()
@@ -91,6 +92,7 @@ fn bench_search_frecent(c: &mut Criterion) {
             SearchParams {
                 search_string: "blog.mozilla.org".into(),
                 limit: 10,
+                ..SearchParams::default()
             },
         )
         .unwrap()
@@ -101,6 +103,7 @@ fn bench_search_frecent(c: &mut Criterion) {
             SearchParams {
                 search_string: "https://hg.mozilla.org/mozilla-central".into(),
                 limit: 10,
+                ..SearchParams::default()
             },
         )
         .unwrap()
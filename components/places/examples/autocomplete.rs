@@ -551,6 +551,7 @@ mod autocomplete {
                             autocompleter.query(SearchParams {
                                 search_string: query_str.clone(),
                                 limit: 10,
+                                ..SearchParams::default()
                             })?;
                         }
                     }
@@ -633,6 +634,7 @@ mod autocomplete {
                         autocompleter.query(SearchParams {
                             search_string: query_str.clone(),
                             limit: 10,
+                            ..SearchParams::default()
                         })?;
                     } else {
                         pending_change = true;
@@ -661,6 +663,7 @@ mod autocomplete {
                     autocompleter.query(SearchParams {
                         search_string: query_str.clone(),
                         limit: 10,
+                        ..SearchParams::default()
                     })?;
                 }
             }
@@ -57,6 +57,19 @@ pub extern "C" fn places_new_sync_conn_interrupt_handle(
     })
 }
 
+/// Get a JSON blob describing the database and recent syncs, for attaching
+/// to bug reports.
+#[no_mangle]
+pub extern "C" fn places_api_create_diagnostic_bundle(
+    handle: u64,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("places_api_create_diagnostic_bundle");
+    APIS.call_with_result(error, handle, |api| -> places::Result<_> {
+        api.create_diagnostic_bundle()
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn places_connection_new(
     handle: u64,
@@ -137,6 +150,55 @@ pub extern "C" fn places_api_return_write_conn(
     })
 }
 
+/// Copies the write-ahead log back into the database, using the connection
+/// for `conn_handle`, and returns a JSON `CheckpointResult`. `mode` is one
+/// of SQLite's `SQLITE_CHECKPOINT_*` constants.
+#[no_mangle]
+pub extern "C" fn places_api_checkpoint(
+    api_handle: u64,
+    conn_handle: u64,
+    mode: u8,
+    error: &mut ExternError,
+) -> *mut c_char {
+    log::debug!("places_api_checkpoint");
+    APIS.call_with_result(
+        error,
+        api_handle,
+        |api| -> std::result::Result<_, ExternError> {
+            let mode = match storage::CheckpointMode::from_primitive(mode) {
+                Some(mode) => mode,
+                None => return Err(Error::from(ErrorKind::InvalidCheckpointMode(mode)).into()),
+            };
+            CONNECTIONS.get_u64(conn_handle, |conn| -> std::result::Result<_, ExternError> {
+                Ok(api.checkpoint(conn, mode)?)
+            })
+        },
+    )
+}
+
+/// Limits how big the write-ahead log stays after a checkpoint, in bytes,
+/// for the connection for `conn_handle` and the API's other connections.
+/// A negative `limit` removes the limit.
+#[no_mangle]
+pub extern "C" fn places_api_set_journal_size_limit(
+    api_handle: u64,
+    conn_handle: u64,
+    limit: i64,
+    error: &mut ExternError,
+) {
+    log::debug!("places_api_set_journal_size_limit");
+    APIS.call_with_result(
+        error,
+        api_handle,
+        |api| -> std::result::Result<_, ExternError> {
+            let limit = if limit < 0 { None } else { Some(limit as u64) };
+            CONNECTIONS.get_u64(conn_handle, |conn| -> std::result::Result<_, ExternError> {
+                Ok(api.set_journal_size_limit(conn, limit)?)
+            })
+        },
+    )
+}
+
 #[no_mangle]
 pub extern "C" fn places_api_reset_bookmarks(api_handle: u64, error: &mut ExternError) {
     log::debug!("places_api_reset_bookmarks");
@@ -193,6 +255,7 @@ pub extern "C" fn places_query_autocomplete(
             SearchParams {
                 search_string: search.into_string(),
                 limit,
+                ..SearchParams::default()
             },
         )?;
         Ok(serde_json::to_string(&res)?)
@@ -254,7 +317,7 @@ pub extern "C" fn places_get_visited_urls_in_range(
 ) -> *mut c_char {
     log::debug!("places_get_visited_in_range");
     CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
-        let visited = storage::history::get_visited_urls(
+        let visited = storage::history::get_visited_urls_in_range(
             conn,
             // Probably should allow into()...
             places::Timestamp(start.max(0) as u64),
@@ -341,7 +404,10 @@ pub extern "C" fn places_wipe_local(handle: u64, error: &mut ExternError) {
 #[no_mangle]
 pub extern "C" fn places_run_maintenance(handle: u64, error: &mut ExternError) {
     log::debug!("places_run_maintenance");
-    CONNECTIONS.call_with_result(error, handle, |conn| storage::run_maintenance(conn))
+    CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
+        storage::run_maintenance(conn)?;
+        Ok(())
+    })
 }
 
 #[no_mangle]
@@ -457,6 +523,8 @@ pub extern "C" fn sync15_history_sync(
                 key_id: key_id.into_string(),
                 access_token: access_token.into_string(),
                 tokenserver_url: parse_url(tokenserver_url.as_str())?,
+                access_token_refresher: None,
+                interceptors: vec![],
             },
             &sync15::KeyBundle::from_ksync_base64(sync_key.as_str())?,
         )?;
@@ -480,6 +548,8 @@ pub extern "C" fn sync15_bookmarks_sync(
                 key_id: key_id.into_string(),
                 access_token: access_token.into_string(),
                 tokenserver_url: parse_url(tokenserver_url.as_str())?,
+                access_token_refresher: None,
+                interceptors: vec![],
             },
             &sync15::KeyBundle::from_ksync_base64(sync_key.as_str())?,
         )?;
@@ -194,6 +194,72 @@ public class PlacesAPI {
         }
     }
 
+    /**
+     * Returns a JSON string describing the database and the most recent syncs,
+     * suitable for attaching to bug reports. It never includes URLs, titles or
+     * account identifiers.
+     *
+     * - Throws:
+     *     - `PlacesError.unexpected`: When an error that has not specifically been exposed
+     *                                 to Swift is encountered (for example IO errors from
+     *                                 the database code, etc).
+     *     - `PlacesError.panic`: If the rust code panics while completing this
+     *                            operation. (If this occurs, please let us know).
+     */
+    open func createDiagnosticBundle() throws -> String {
+        return try queue.sync {
+            let bundle = try PlacesError.unwrap { err in
+                places_api_create_diagnostic_bundle(handle, err)
+            }
+            return String(freeingPlacesString: bundle)
+        }
+    }
+
+    /**
+     * Copies the write-ahead log back into the database, using the writer
+     * connection. Use `.truncate` before backing up the database file.
+     *
+     * - Throws:
+     *     - `PlacesError.unexpected`: When an error that has not specifically been exposed
+     *                                 to Swift is encountered (for example IO errors from
+     *                                 the database code, etc).
+     *     - `PlacesError.panic`: If the rust code panics while completing this
+     *                            operation. (If this occurs, please let us know).
+     */
+    open func checkpoint(mode: CheckpointMode = .passive) throws -> CheckpointResult {
+        return try queue.sync {
+            try self.writeConn.queue.sync {
+                let json = try PlacesError.unwrap { err in
+                    places_api_checkpoint(handle, self.writeConn.handle, mode.rawValue, err)
+                }
+                return try CheckpointResult(fromJSONString: String(freeingPlacesString: json))
+            }
+        }
+    }
+
+    /**
+     * Limits how big the write-ahead log stays after a checkpoint, for the
+     * writer connection and every reader connection.
+     *
+     * - Parameter limit: The limit, in bytes, or `nil` to remove it.
+     *
+     * - Throws:
+     *     - `PlacesError.unexpected`: When an error that has not specifically been exposed
+     *                                 to Swift is encountered (for example IO errors from
+     *                                 the database code, etc).
+     *     - `PlacesError.panic`: If the rust code panics while completing this
+     *                            operation. (If this occurs, please let us know).
+     */
+    open func setJournalSizeLimit(_ limit: Int64?) throws {
+        try queue.sync {
+            try self.writeConn.queue.sync {
+                try PlacesError.unwrap { err in
+                    places_api_set_journal_size_limit(handle, self.writeConn.handle, limit ?? -1, err)
+                }
+            }
+        }
+    }
+
     /**
      * Attempt to interrupt a long-running operation which may be happening
      * concurrently (specifically, for `interrupt` on `PlacesAPI`, this refers
@@ -207,6 +273,43 @@ public class PlacesAPI {
     }
 }
 
+/**
+ * How hard `PlacesAPI.checkpoint` should try to copy the write-ahead log back
+ * into the database. These are the same as SQLite's checkpoint modes.
+ */
+public enum CheckpointMode: UInt8 {
+    /// Copies as much as it can without waiting for readers or writers.
+    case passive = 0
+    /// Waits for writers to finish, then copies the whole log.
+    case full = 1
+    /// Like `full`, and also waits for readers, so that the next writer
+    /// starts the log over.
+    case restart = 2
+    /// Like `restart`, and also truncates the log file. Use this before
+    /// backing up the database file.
+    case truncate = 3
+}
+
+/**
+ * What `PlacesAPI.checkpoint` did.
+ */
+public struct CheckpointResult {
+    /// True if another connection kept the checkpoint from finishing.
+    public let busy: Bool
+    /// The number of pages in the write-ahead log.
+    public let logPages: Int64
+    /// The number of those pages that were copied into the database.
+    public let checkpointedPages: Int64
+
+    internal init(fromJSONString json: String) throws {
+        let dict = try JSONSerialization.jsonObject(with: json.data(using: .utf8)!,
+                                                    options: []) as? [String: Any] ?? [:]
+        busy = dict["busy"] as? Bool ?? false
+        logPages = (dict["logPages"] as? NSNumber)?.int64Value ?? 0
+        checkpointedPages = (dict["checkpointedPages"] as? NSNumber)?.int64Value ?? 0
+    }
+}
+
 /**
  * A read-only connection to the places database.
  */
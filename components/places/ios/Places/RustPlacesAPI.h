@@ -142,6 +142,19 @@ char *_Nonnull sync15_bookmarks_sync(PlacesAPIHandle handle,
 void places_api_reset_bookmarks(PlacesAPIHandle handle,
                                 PlacesRustError *_Nonnull out_err);
 
+char *_Nonnull places_api_create_diagnostic_bundle(PlacesAPIHandle handle,
+                                                   PlacesRustError *_Nonnull out_err);
+
+char *_Nonnull places_api_checkpoint(PlacesAPIHandle api,
+                                     PlacesConnectionHandle conn,
+                                     uint8_t mode,
+                                     PlacesRustError *_Nonnull out_err);
+
+void places_api_set_journal_size_limit(PlacesAPIHandle api,
+                                       PlacesConnectionHandle conn,
+                                       int64_t limit,
+                                       PlacesRustError *_Nonnull out_err);
+
 RawPlacesInterruptHandle *_Nullable
 places_new_sync_conn_interrupt_handle(PlacesAPIHandle handle,
                                       PlacesRustError *_Nonnull out_err);
@@ -48,3 +48,50 @@ BEGIN
         syncChangeCounter = syncChangeCounter + 1
     WHERE fk = OLD.place_id;
 END;
+
+-- These triggers bump the Sync change counter for all bookmarks with a URL
+-- when its keyword changes.
+CREATE TEMP TRIGGER moz_keywords_afterinsert_sync_trigger
+AFTER INSERT ON moz_keywords
+BEGIN
+    UPDATE moz_bookmarks SET
+        syncChangeCounter = syncChangeCounter + 1
+    WHERE fk = NEW.place_id;
+END;
+
+CREATE TEMP TRIGGER moz_keywords_afterupdate_sync_trigger
+AFTER UPDATE ON moz_keywords
+BEGIN
+    UPDATE moz_bookmarks SET
+        syncChangeCounter = syncChangeCounter + 1
+    WHERE fk IN (OLD.place_id, NEW.place_id);
+END;
+
+CREATE TEMP TRIGGER moz_keywords_afterdelete_sync_trigger
+AFTER DELETE ON moz_keywords
+BEGIN
+    UPDATE moz_bookmarks SET
+        syncChangeCounter = syncChangeCounter + 1
+    WHERE fk = OLD.place_id;
+END;
+
+-- Like on Desktop, a URL's keyword is removed along with its last bookmark,
+-- so that it doesn't keep resolving to an unbookmarked URL, or keep the page
+-- from expiring.
+CREATE TEMP TRIGGER moz_bookmarks_keywords_afterdelete_trigger
+AFTER DELETE ON moz_bookmarks
+FOR EACH ROW WHEN OLD.fk NOT NULL
+BEGIN
+    DELETE FROM moz_keywords
+    WHERE place_id = OLD.fk AND
+          NOT EXISTS(SELECT 1 FROM moz_bookmarks WHERE fk = OLD.fk);
+END;
+
+CREATE TEMP TRIGGER moz_bookmarks_keywords_afterupdate_trigger
+AFTER UPDATE OF fk ON moz_bookmarks
+FOR EACH ROW WHEN OLD.fk NOT NULL AND OLD.fk IS NOT NEW.fk
+BEGIN
+    DELETE FROM moz_keywords
+    WHERE place_id = OLD.fk AND
+          NOT EXISTS(SELECT 1 FROM moz_bookmarks WHERE fk = OLD.fk);
+END;
@@ -131,7 +131,12 @@ CREATE TABLE IF NOT EXISTS moz_bookmarks_deleted (
     dateRemoved INTEGER NOT NULL
 ) WITHOUT ROWID;
 
--- Note: desktop has/had a 'keywords' table, but we intentionally do not.
+-- Unlike Desktop, a URL can only have one keyword, since bookmark records only
+-- have room for one. We don't support POST data for keywords, either.
+CREATE TABLE IF NOT EXISTS moz_keywords(
+    keyword TEXT PRIMARY KEY,
+    place_id INTEGER NOT NULL UNIQUE REFERENCES moz_places(id) ON DELETE CASCADE
+) WITHOUT ROWID;
 
 
 CREATE TABLE IF NOT EXISTS moz_origins (
@@ -146,6 +151,46 @@ CREATE TABLE IF NOT EXISTS moz_origins (
 CREATE INDEX IF NOT EXISTS hostindex ON moz_origins(rev_host);
 
 
+-- Favicons, which are associated with pages, or with entire origins for icons
+-- like `/favicon.ico` that are used by every page on a site. An icon can be
+-- stored as a URL only, so that the app fetches and caches the data itself, or
+-- with its data. Unlike Desktop, we don't store multiple payloads for an icon,
+-- so an icon with a different size needs a different URL.
+CREATE TABLE IF NOT EXISTS moz_icons(
+    id INTEGER PRIMARY KEY,
+    icon_url TEXT NOT NULL UNIQUE,
+    -- The width of the icon in pixels, or 0 if unknown.
+    width INTEGER NOT NULL DEFAULT 0,
+    data BLOB,
+    expires_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS moz_icons_to_pages(
+    place_id INTEGER NOT NULL REFERENCES moz_places(id) ON DELETE CASCADE,
+    icon_id INTEGER NOT NULL REFERENCES moz_icons(id) ON DELETE CASCADE,
+    PRIMARY KEY(place_id, icon_id)
+) WITHOUT ROWID;
+
+CREATE TABLE IF NOT EXISTS moz_icons_to_origins(
+    origin_id INTEGER NOT NULL REFERENCES moz_origins(id) ON DELETE CASCADE,
+    icon_id INTEGER NOT NULL REFERENCES moz_icons(id) ON DELETE CASCADE,
+    PRIMARY KEY(origin_id, icon_id)
+) WITHOUT ROWID;
+
+
+-- Sites that the user pinned to the top sites grid on the home screen. These
+-- store their own URLs and titles, instead of referencing `moz_places`, so
+-- that clearing history doesn't remove them. Positions are the tiles' slots in
+-- the grid, so they can have gaps where top sites are shown instead.
+CREATE TABLE IF NOT EXISTS moz_pinned_sites(
+    id INTEGER PRIMARY KEY,
+    url TEXT NOT NULL UNIQUE,
+    title TEXT,
+    position INTEGER NOT NULL UNIQUE,
+    date_added INTEGER NOT NULL
+);
+
+
 -- This table holds key-value metadata for Places and its consumers. Sync stores
 -- the sync IDs for the bookmarks and history collections in this table, and the
 -- last sync time for history.
@@ -8,7 +8,15 @@ CREATE TEMP TRIGGER moz_places_afterinsert_trigger
 AFTER INSERT ON moz_places FOR EACH ROW
 BEGIN
     INSERT OR IGNORE INTO moz_origins(prefix, host, rev_host, frecency)
-    VALUES(get_prefix(NEW.url), get_host_and_port(NEW.url), reverse_host(get_host_and_port(NEW.url)), NEW.frecency);
+    VALUES(get_prefix(NEW.url), get_host_and_port(NEW.url), reverse_host(get_host_and_port(NEW.url)), 0);
+
+    -- The new page's frecency is added to its origin's frecency, and the
+    -- origin frecency stats, when the pending origin updates are flushed.
+    INSERT INTO moz_updateoriginsupdate_temp (prefix, host, frecency_delta)
+    SELECT get_prefix(NEW.url), get_host_and_port(NEW.url), NEW.frecency
+    WHERE NEW.frecency > 0
+    ON CONFLICT(prefix, host) DO UPDATE
+    SET frecency_delta = frecency_delta + EXCLUDED.frecency_delta;
 
     -- This is temporary.
     UPDATE moz_places SET
@@ -62,6 +70,16 @@ BEGIN
     WHERE id = OLD.place_id;
 END;
 
+-- Visits that came from a removed visit, like the targets of a redirect,
+-- are kept, but no longer point to it.
+CREATE TEMP TRIGGER moz_historyvisits_afterdelete_from_visit_trigger
+AFTER DELETE ON moz_historyvisits FOR EACH ROW
+BEGIN
+    UPDATE moz_historyvisits SET
+        from_visit = NULL
+    WHERE from_visit = OLD.id;
+END;
+
 CREATE TEMP TRIGGER moz_bookmarks_foreign_count_afterdelete_trigger
 AFTER DELETE ON moz_bookmarks FOR EACH ROW
 BEGIN
@@ -306,3 +324,53 @@ BEGIN
         foreign_count = foreign_count - 1
     WHERE id = OLD.place_id;
 END;
+
+-- Like tags, keywords increase the foreign count of their URLs, so that they
+-- won't be expired.
+CREATE TEMP TRIGGER moz_keywords_afterinsert_trigger
+AFTER INSERT ON moz_keywords
+BEGIN
+    UPDATE moz_places SET
+        foreign_count = foreign_count + 1
+    WHERE id = NEW.place_id;
+END;
+
+CREATE TEMP TRIGGER moz_keywords_afterupdate_trigger
+AFTER UPDATE ON moz_keywords
+BEGIN
+    UPDATE moz_places SET
+        foreign_count = foreign_count + 1
+    WHERE id = NEW.place_id;
+
+    UPDATE moz_places SET
+        foreign_count = foreign_count - 1
+    WHERE id = OLD.place_id;
+END;
+
+CREATE TEMP TRIGGER moz_keywords_afterdelete_trigger
+AFTER DELETE ON moz_keywords
+BEGIN
+    UPDATE moz_places SET
+        foreign_count = foreign_count - 1
+    WHERE id = OLD.place_id;
+END;
+
+-- Icons are removed along with the last page or origin that uses them, so
+-- that removing history also removes the icons for the removed pages.
+CREATE TEMP TRIGGER moz_icons_to_pages_afterdelete_trigger
+AFTER DELETE ON moz_icons_to_pages
+BEGIN
+    DELETE FROM moz_icons
+    WHERE id = OLD.icon_id AND
+          NOT EXISTS(SELECT 1 FROM moz_icons_to_pages WHERE icon_id = OLD.icon_id) AND
+          NOT EXISTS(SELECT 1 FROM moz_icons_to_origins WHERE icon_id = OLD.icon_id);
+END;
+
+CREATE TEMP TRIGGER moz_icons_to_origins_afterdelete_trigger
+AFTER DELETE ON moz_icons_to_origins
+BEGIN
+    DELETE FROM moz_icons
+    WHERE id = OLD.icon_id AND
+          NOT EXISTS(SELECT 1 FROM moz_icons_to_pages WHERE icon_id = OLD.icon_id) AND
+          NOT EXISTS(SELECT 1 FROM moz_icons_to_origins WHERE icon_id = OLD.icon_id);
+END;
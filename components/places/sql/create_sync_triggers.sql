@@ -124,6 +124,20 @@ BEGIN
     DELETE FROM moz_tags_relation
     WHERE place_id IN (OLD.oldPlaceId, OLD.newPlaceId);
 
+    -- Keywords are unique, so if another URL has the new keyword, reupload
+    -- its bookmarks without it.
+    UPDATE moz_bookmarks SET
+        syncChangeCounter = syncChangeCounter + 1
+    WHERE fk IN (SELECT place_id FROM moz_keywords
+                 WHERE keyword = OLD.newKeyword AND
+                       place_id <> OLD.newPlaceId);
+
+    -- Remove existing keywords for the old and new URLs, and the new keyword
+    -- from any other URL.
+    DELETE FROM moz_keywords
+    WHERE place_id IN (OLD.oldPlaceId, OLD.newPlaceId) OR
+          keyword = OLD.newKeyword;
+
     -- Insert the new item, using the Places root as the placeholder parent, and
     -- -1 as the position. We'll update these later, when we fire the
     -- `updateLocalStructure` trigger.
@@ -157,6 +171,12 @@ BEGIN
     SELECT tagId, OLD.newPlaceId
     FROM moz_bookmarks_synced_tag_relation
     WHERE itemId = OLD.remoteId;
+
+    -- Insert the new keyword for the new URL.
+    INSERT INTO moz_keywords(keyword, place_id)
+    SELECT OLD.newKeyword, OLD.newPlaceId
+    WHERE OLD.newKeyword NOT NULL AND
+          OLD.newPlaceId NOT NULL;
 END;
 
 -- Updates all parents and positions to reflect the merged tree.
@@ -0,0 +1,364 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Support for building a "diagnostic bundle" - a single JSON blob that
+//! describes the state of the places database and its recent syncs, which
+//! users can attach to bug reports. It's assembled here, rather than by each
+//! app, so that the contents are the same on every platform.
+//!
+//! The bundle is meant to be shared, so it never includes URLs, titles or
+//! anything that identifies the user. Any URLs that sneak in via error
+//! messages are redacted.
+
+use crate::bookmark_sync::store as bookmark_store;
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::history_sync::store as history_store;
+use crate::storage::get_meta;
+use crate::types::BookmarkType;
+use serde_derive::*;
+use serde_json::Value;
+use sql_support::ConnExt;
+use std::collections::{BTreeMap, VecDeque};
+use sync15::SyncResult;
+
+/// The number of syncs we remember for the bundle.
+pub const MAX_SYNC_HISTORY: usize = 10;
+
+const REDACTED: &str = "[redacted]";
+
+/// A summary of a single sync, as it appears in the bundle.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncHistoryEntry {
+    pub service_status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub engine_errors: BTreeMap<String, String>,
+    /// The telemetry ping for the sync, which includes the validation
+    /// summaries for engines that ran validation.
+    pub telemetry: Value,
+}
+
+impl SyncHistoryEntry {
+    pub fn new(result: &SyncResult) -> Self {
+        let engine_errors = result
+            .engine_results
+            .iter()
+            .filter_map(|(name, r)| match r {
+                Ok(()) => None,
+                Err(e) => Some((name.clone(), redact(&e.to_string()))),
+            })
+            .collect();
+        let mut telemetry = serde_json::to_value(&result.telemetry).unwrap_or(Value::Null);
+        redact_value(&mut telemetry);
+        SyncHistoryEntry {
+            service_status: format!("{:?}", result.service_status),
+            error: result.result.as_ref().err().map(|e| redact(&e.to_string())),
+            engine_errors,
+            telemetry,
+        }
+    }
+}
+
+/// The most recent syncs, newest last.
+#[derive(Debug, Default)]
+pub struct SyncHistory(VecDeque<SyncHistoryEntry>);
+
+impl SyncHistory {
+    pub fn record(&mut self, result: &SyncResult) {
+        if self.0.len() >= MAX_SYNC_HISTORY {
+            self.0.pop_front();
+        }
+        self.0.push_back(SyncHistoryEntry::new(result));
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &SyncHistoryEntry> {
+        self.0.iter()
+    }
+}
+
+/// The sync state we've persisted for an engine.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineState {
+    pub name: &'static str,
+    pub last_sync: i64,
+    pub global_sync_id: Option<String>,
+    pub collection_sync_id: Option<String>,
+}
+
+impl EngineState {
+    fn fetch(
+        db: &PlacesDb,
+        name: &'static str,
+        last_sync_key: &str,
+        global_sync_id_key: &str,
+        collection_sync_id_key: &str,
+    ) -> Result<Self> {
+        Ok(EngineState {
+            name,
+            last_sync: get_meta(db, last_sync_key)?.unwrap_or_default(),
+            global_sync_id: get_meta(db, global_sync_id_key)?,
+            collection_sync_id: get_meta(db, collection_sync_id_key)?,
+        })
+    }
+}
+
+/// The number of folders with the most children that `DbStats` lists.
+pub const MAX_LARGEST_FOLDERS: u32 = 5;
+
+/// A folder and how many children it has.
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FolderStats {
+    pub guid: String,
+    pub children: i64,
+}
+
+/// Row counts and sizes for the database. Apps can also use these for
+/// storage UI.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DbStats {
+    pub places: i64,
+    pub visits: i64,
+    pub place_tombstones: i64,
+    pub visit_tombstones: i64,
+    pub places_with_changes: i64,
+    pub bookmarks: i64,
+    pub folders: i64,
+    pub separators: i64,
+    pub bookmark_tombstones: i64,
+    pub bookmarks_with_changes: i64,
+    pub synced_bookmarks: i64,
+    pub size_bytes: i64,
+    /// The folders with the most children, up to `MAX_LARGEST_FOLDERS`, so
+    /// that we can spot pathological trees.
+    pub largest_folders: Vec<FolderStats>,
+}
+
+impl DbStats {
+    pub fn fetch(db: &PlacesDb) -> Result<Self> {
+        let count_bookmarks = |typ: BookmarkType| -> Result<i64> {
+            Ok(db.query_row_named(
+                "SELECT COUNT(*) FROM moz_bookmarks WHERE type = :type",
+                rusqlite::named_params! { ":type": typ as u8 },
+                |row| row.get(0),
+            )?)
+        };
+        Ok(DbStats {
+            places: db.query_one("SELECT COUNT(*) FROM moz_places")?,
+            visits: db.query_one("SELECT COUNT(*) FROM moz_historyvisits")?,
+            place_tombstones: db.query_one("SELECT COUNT(*) FROM moz_places_tombstones")?,
+            visit_tombstones: db.query_one("SELECT COUNT(*) FROM moz_historyvisit_tombstones")?,
+            places_with_changes: db
+                .query_one("SELECT COUNT(*) FROM moz_places WHERE sync_change_counter > 0")?,
+            bookmarks: count_bookmarks(BookmarkType::Bookmark)?,
+            folders: count_bookmarks(BookmarkType::Folder)?,
+            separators: count_bookmarks(BookmarkType::Separator)?,
+            bookmark_tombstones: db.query_one("SELECT COUNT(*) FROM moz_bookmarks_deleted")?,
+            bookmarks_with_changes: db
+                .query_one("SELECT COUNT(*) FROM moz_bookmarks WHERE syncChangeCounter > 0")?,
+            synced_bookmarks: db.query_one("SELECT COUNT(*) FROM moz_bookmarks_synced")?,
+            size_bytes: db.query_one(
+                "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+            )?,
+            largest_folders: db.query_rows_and_then_named(
+                "SELECT p.guid, COUNT(*) AS children
+                 FROM moz_bookmarks b
+                 JOIN moz_bookmarks p ON p.id = b.parent
+                 GROUP BY b.parent
+                 ORDER BY children DESC, p.guid
+                 LIMIT :limit",
+                &[(":limit", &MAX_LARGEST_FOLDERS)],
+                |row| -> Result<_> {
+                    Ok(FolderStats {
+                        guid: row.get("guid")?,
+                        children: row.get("children")?,
+                    })
+                },
+            )?,
+        })
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DiagnosticBundle<'a> {
+    schema_version: u32,
+    sync_history: Vec<&'a SyncHistoryEntry>,
+    engines: Vec<EngineState>,
+    db: DbStats,
+}
+
+/// Builds the diagnostic bundle for the database and the given sync history,
+/// returning it as a JSON string.
+pub fn create_diagnostic_bundle(db: &PlacesDb, history: &SyncHistory) -> Result<String> {
+    let bundle = DiagnosticBundle {
+        schema_version: 1,
+        sync_history: history.entries().collect(),
+        engines: vec![
+            EngineState::fetch(
+                db,
+                "history",
+                history_store::LAST_SYNC_META_KEY,
+                history_store::GLOBAL_SYNCID_META_KEY,
+                history_store::COLLECTION_SYNCID_META_KEY,
+            )?,
+            EngineState::fetch(
+                db,
+                "bookmarks",
+                bookmark_store::LAST_SYNC_META_KEY,
+                bookmark_store::GLOBAL_SYNCID_META_KEY,
+                bookmark_store::COLLECTION_SYNCID_META_KEY,
+            )?,
+        ],
+        db: DbStats::fetch(db)?,
+    };
+    Ok(serde_json::to_string(&bundle)?)
+}
+
+// Replaces anything that looks like a URL with a placeholder. This is used
+// for error messages, which sometimes include the URL that failed.
+fn redact(s: &str) -> String {
+    s.split(' ')
+        .map(|word| if word.contains("://") { REDACTED } else { word })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// Redacts the strings in a telemetry ping. The ping only has a hashed uid,
+// but we'd still rather not tie bug reports to an account.
+fn redact_value(value: &mut Value) {
+    match value {
+        Value::String(s) => *s = redact(s),
+        Value::Array(values) => values.iter_mut().for_each(redact_value),
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if key == "uid" || key == "deviceID" {
+                    *value = Value::String(REDACTED.into());
+                } else {
+                    redact_value(value);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableItem, InsertableSeparator,
+    };
+    use crate::storage::history::apply_observation;
+    use crate::types::VisitTransition;
+    use serde_json::json;
+    use url::Url;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact("Network error: fetching https://example.com/foo?bar failed"),
+            "Network error: fetching [redacted] failed"
+        );
+        let mut value = json!({
+            "uid": "abcdef",
+            "syncs": [{
+                "failureReason": {
+                    "name": "othererror",
+                    "error": "Bad URL file:///home/user/places.sqlite",
+                },
+            }],
+            "took": 5,
+        });
+        redact_value(&mut value);
+        assert_eq!(
+            value,
+            json!({
+                "uid": "[redacted]",
+                "syncs": [{
+                    "failureReason": {
+                        "name": "othererror",
+                        "error": "Bad URL [redacted]",
+                    },
+                }],
+                "took": 5,
+            })
+        );
+    }
+
+    #[test]
+    fn test_db_stats() -> Result<()> {
+        let conn = new_mem_connection();
+        let url = Url::parse("https://www.example.com/secret-page").unwrap();
+        apply_observation(
+            &conn,
+            VisitObservation::new(url).with_visit_type(VisitTransition::Link),
+        )?;
+        insert_bookmark(
+            &conn,
+            &InsertableItem::Separator(InsertableSeparator {
+                parent_guid: BookmarkRootGuid::Menu.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+            }),
+        )?;
+
+        let stats = DbStats::fetch(&conn)?;
+        assert_eq!(stats.places, 1);
+        assert_eq!(stats.visits, 1);
+        assert_eq!(stats.places_with_changes, 1);
+        assert_eq!(stats.separators, 1);
+        // The roots.
+        assert_eq!(stats.folders, 5);
+        assert!(stats.size_bytes > 0);
+        assert_eq!(
+            stats.largest_folders,
+            vec![
+                FolderStats {
+                    guid: BookmarkRootGuid::Root.as_str().into(),
+                    children: 4,
+                },
+                FolderStats {
+                    guid: BookmarkRootGuid::Menu.as_str().into(),
+                    children: 1,
+                },
+            ]
+        );
+
+        let bundle = create_diagnostic_bundle(&conn, &SyncHistory::default())?;
+        assert!(!bundle.contains("example.com"));
+        let value: Value = serde_json::from_str(&bundle).unwrap();
+        assert_eq!(value["db"]["places"], 1);
+        assert_eq!(value["engines"][0]["name"], "history");
+        assert_eq!(value["engines"][1]["lastSync"], 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_history_is_capped() {
+        let mut history = SyncHistory::default();
+        for _ in 0..MAX_SYNC_HISTORY + 3 {
+            history.record(&SyncResult {
+                service_status: sync15::ServiceStatus::Ok,
+                result: Ok(()),
+                engine_results: Default::default(),
+                recent_clients: Default::default(),
+                enabled_engines: Default::default(),
+                skipped_records: Default::default(),
+                deferred_engines: Default::default(),
+                telemetry: Default::default(),
+            });
+        }
+        assert_eq!(history.entries().count(), MAX_SYNC_HISTORY);
+    }
+}
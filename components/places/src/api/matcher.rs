@@ -5,9 +5,13 @@
 use crate::db::PlacesDb;
 use crate::error::Result;
 pub use crate::match_impl::{MatchBehavior, SearchBehavior};
+use crate::storage::favicons::get_icon_url_for_page;
+use crate::storage::history::get_page_metadata;
+use crate::storage::{get_meta, put_meta};
+use crate::types::Timestamp;
 use rusqlite::{types::ToSql, Row};
 use serde_derive::*;
-use sql_support::{maybe_log_plan, ConnExt};
+use sql_support::{maybe_log_plan, ConnExt, SqlInterruptScope};
 use url::Url;
 
 // A helper to log, cache and execute a query, returning a vector of flattened rows.
@@ -40,15 +44,65 @@ where
 pub struct SearchParams {
     pub search_string: String,
     pub limit: u32,
+    /// How the search string is matched against URLs, titles and tags. Like
+    /// Desktop, `BoundaryAnywhere` matches on word boundaries first, then
+    /// anywhere if there aren't enough results, and `Beginning` anchors URL
+    /// matches at the start of the host.
+    pub match_behavior: MatchBehavior,
+    /// Which pages to match, and whether to match their titles, URLs, or
+    /// both. For example, `BOOKMARK | RESTRICT` only matches bookmarks, and
+    /// `HISTORY | RESTRICT` only matches visited pages.
+    pub search_behavior: SearchBehavior,
 }
 
+impl Default for SearchParams {
+    fn default() -> Self {
+        SearchParams {
+            search_string: String::new(),
+            limit: 10,
+            match_behavior: MatchBehavior::Anywhere,
+            search_behavior: SearchBehavior::default(),
+        }
+    }
+}
+
+impl SearchParams {
+    /// Returns true if we should look for an origin or URL to autofill. We
+    /// skip this for restricted searches, since we can't restrict autofill
+    /// results to bookmarks or history, and for searches that only match
+    /// titles.
+    fn wants_autofill(&self) -> bool {
+        !self.search_behavior.contains(SearchBehavior::RESTRICT)
+            && (self.search_behavior.contains(SearchBehavior::URL)
+                || !self.search_behavior.contains(SearchBehavior::TITLE))
+    }
+
+    fn match_behaviors(&self) -> &'static [MatchBehavior] {
+        match self.match_behavior {
+            MatchBehavior::BoundaryAnywhere => &[MatchBehavior::Boundary, MatchBehavior::Anywhere],
+            MatchBehavior::Anywhere => &[MatchBehavior::Anywhere],
+            MatchBehavior::Boundary => &[MatchBehavior::Boundary],
+            MatchBehavior::Beginning => &[MatchBehavior::Beginning],
+            MatchBehavior::AnywhereUnmodified => &[MatchBehavior::AnywhereUnmodified],
+            MatchBehavior::BeginningCaseSensitive => &[MatchBehavior::BeginningCaseSensitive],
+        }
+    }
+}
+
+/// The width of the icons we return for search results, which are shown at
+/// the same size as on Desktop.
+const SEARCH_RESULT_ICON_WIDTH: u32 = 16;
+
 /// Synchronously queries all providers for autocomplete matches, then filters
-/// the matches. This isn't cancelable yet; once a search is started, it can't
-/// be interrupted, even if the user moves on (see
-/// https://github.com/mozilla/application-services/issues/265).
+/// the matches. Searches can be canceled with the connection's interrupt
+/// handle, from another thread, when the user keeps typing. A canceled search
+/// fails with an `InterruptedError` or an interrupted SQL error, instead of
+/// returning stale results.
 ///
 /// A provider can be anything that returns URL suggestions: Places history
 /// and bookmarks, synced tabs, search engine suggestions, and search keywords.
+/// Results are returned in rank order, with the autofilled origin or URL
+/// first, followed by adaptive matches and suggestions.
 pub fn search_frecent(conn: &PlacesDb, params: SearchParams) -> Result<Vec<SearchResult>> {
     // TODO: Tokenize the query.
 
@@ -58,28 +112,36 @@ pub fn search_frecent(conn: &PlacesDb, params: SearchParams) -> Result<Vec<Searc
     // and a search if all else fails. We only try origins and URLs for
     // heuristic matches, since that's all we support.
 
-    let mut matches = match_with_limit(
-        conn,
-        &[
-            // Try to match on the origin, or the full URL.
-            &OriginOrUrl::new(&params.search_string),
-            // query adaptive matches and suggestions, matching Anywhere.
-            &Adaptive::with_behavior(
-                &params.search_string,
-                MatchBehavior::Anywhere,
-                SearchBehavior::default(),
-            ),
-            &Suggestions::with_behavior(
-                &params.search_string,
-                MatchBehavior::Anywhere,
-                SearchBehavior::default(),
-            ),
-        ],
-        params.limit,
-    )?;
+    let mut matchers: Vec<Box<dyn Matcher + '_>> = Vec::new();
+    if params.wants_autofill() {
+        // Try to match on the origin, or the full URL.
+        matchers.push(Box::new(OriginOrUrl::new(&params.search_string)));
+    }
+    for &match_behavior in params.match_behaviors() {
+        // query adaptive matches and suggestions.
+        matchers.push(Box::new(Adaptive::with_behavior(
+            &params.search_string,
+            match_behavior,
+            params.search_behavior,
+        )));
+        matchers.push(Box::new(Suggestions::with_behavior(
+            &params.search_string,
+            match_behavior,
+            params.search_behavior,
+        )));
+    }
 
-    matches.sort_unstable_by(|a, b| a.url.cmp(&b.url));
-    matches.dedup_by(|a, b| a.url == b.url);
+    let scope = conn.begin_interrupt_scope();
+    let mut matches = match_with_limit(conn, &scope, &matchers, params.limit)?;
+
+    for m in &mut matches {
+        scope.err_if_interrupted()?;
+        m.icon_url = get_icon_url_for_page(conn, &m.url, SEARCH_RESULT_ICON_WIDTH)?;
+        if let Some(metadata) = get_page_metadata(conn, &m.url)? {
+            m.description = metadata.description;
+            m.preview_image_url = metadata.preview_image_url;
+        }
+    }
 
     Ok(matches)
 }
@@ -99,29 +161,73 @@ pub fn match_url(conn: &PlacesDb, query: impl AsRef<str>) -> Result<Option<Strin
     }
 }
 
+/// Returns up to `limit` origins whose hosts start with `prefix`, with or
+/// without a leading `www.`, ranked by the total frecency of all their
+/// pages. Unlike `search_frecent`, this only looks at origins, so "you"
+/// matches "youtube.com" once any page on it has been visited, even if
+/// no page URL or title matches. Each host is returned once, with the
+/// prefix (like `https://`) of its most frecent origin.
+pub fn search_origins(conn: &PlacesDb, prefix: &str, limit: u32) -> Result<Vec<SearchResult>> {
+    let search_string = normalize_input(prefix);
+    if !looks_like_origin(&search_string) {
+        return Ok(Vec::new());
+    }
+    let scope = conn.begin_interrupt_scope();
+    let mut results = query_flat_rows_and_then_named(
+        conn,
+        ORIGINS_SQL,
+        &[(":searchString", &search_string), (":limit", &limit)],
+        SearchResult::from_origin_row,
+    )?;
+    for result in &mut results {
+        scope.err_if_interrupted()?;
+        result.icon_url = get_icon_url_for_page(conn, &result.url, SEARCH_RESULT_ICON_WIDTH)?;
+    }
+    Ok(results)
+}
+
 fn match_with_limit(
     conn: &PlacesDb,
-    matchers: &[&dyn Matcher],
+    scope: &SqlInterruptScope,
+    matchers: &[Box<dyn Matcher + '_>],
     max_results: u32,
 ) -> Result<(Vec<SearchResult>)> {
-    let mut results = Vec::new();
-    let mut rem_results = max_results;
-    let scope = conn.begin_interrupt_scope();
+    let mut results: Vec<SearchResult> = Vec::new();
     for m in matchers {
+        let rem_results = max_results.saturating_sub(results.len() as u32);
         if rem_results == 0 {
             break;
         }
         scope.err_if_interrupted()?;
         let matches = m.search(conn, rem_results)?;
-        results.extend(matches);
-        rem_results = rem_results.saturating_sub(results.len() as u32);
+        // Earlier matchers rank higher, so we keep the first result for
+        // each URL.
+        for result in matches {
+            if !results.iter().any(|r| r.url == result.url) {
+                results.push(result);
+            }
+        }
     }
+    results.truncate(max_results as usize);
     Ok(results)
 }
 
+/// Returns the form of `search_string` that we store in, and look up from,
+/// the input history. Ignoring case and surrounding whitespace means that
+/// "Ample " and "ample" boost the same results.
+fn normalize_input(search_string: &str) -> String {
+    search_string.trim().to_lowercase()
+}
+
 /// Records an accepted autocomplete match, recording the query string,
-/// and chosen URL for subsequent matches.
+/// and chosen URL for subsequent matches. Each time a URL is chosen for a
+/// query, it ranks higher for that query, and for shorter queries that the
+/// query starts with.
 pub fn accept_result(conn: &PlacesDb, search_string: &str, url: &Url) -> Result<()> {
+    let input = normalize_input(search_string);
+    if input.is_empty() {
+        return Ok(());
+    }
     // See `nsNavHistory::AutoCompleteFeedback`.
     conn.execute_named(
         "INSERT OR REPLACE INTO moz_inputhistory(place_id, input, use_count)
@@ -129,15 +235,50 @@ pub fn accept_result(conn: &PlacesDb, search_string: &str, url: &Url) -> Result<
          FROM moz_places h
          LEFT JOIN moz_inputhistory i ON i.place_id = h.id AND i.input = :input_text
          WHERE url_hash = hash(:page_url) AND url = :page_url",
-        &[
-            (":input_text", &search_string),
-            (":page_url", &url.as_str()),
-        ],
+        &[(":input_text", &input), (":page_url", &url.as_str())],
     )?;
 
     Ok(())
 }
 
+const INPUT_HISTORY_LAST_DECAY_META_KEY: &str = "input_history_last_decay";
+const INPUT_HISTORY_DECAY_INTERVAL_MS: u64 = 24 * 60 * 60 * 1000;
+
+/// Decays the use counts in the input history, once for every day since we
+/// last did, like Desktop's daily idle maintenance. This way, results that
+/// the user chose a long time ago stop being boosted, and are eventually
+/// removed.
+pub(crate) fn decay_input_history(conn: &PlacesDb, now: Timestamp) -> Result<()> {
+    let last_decay = match get_meta::<Timestamp>(conn, INPUT_HISTORY_LAST_DECAY_META_KEY)? {
+        Some(last_decay) => last_decay,
+        None => {
+            put_meta(conn, INPUT_HISTORY_LAST_DECAY_META_KEY, &now)?;
+            return Ok(());
+        }
+    };
+    let days =
+        now.as_millis().saturating_sub(last_decay.as_millis()) / INPUT_HISTORY_DECAY_INTERVAL_MS;
+    if days == 0 {
+        return Ok(());
+    }
+    let tx = conn.begin_transaction()?;
+    conn.execute_named_cached(
+        "UPDATE moz_inputhistory SET use_count = use_count * :factor",
+        &[(
+            ":factor",
+            &0.975f64.powi(days.min(i32::max_value() as u64) as i32),
+        )],
+    )?;
+    conn.execute_named_cached("DELETE FROM moz_inputhistory WHERE use_count < .01", &[])?;
+    put_meta(
+        conn,
+        INPUT_HISTORY_LAST_DECAY_META_KEY,
+        &Timestamp(last_decay.as_millis() + days * INPUT_HISTORY_DECAY_INTERVAL_MS),
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
 pub fn split_after_prefix(href: &str) -> (&str, &str) {
     match memchr::memchr(b':', href.as_bytes()) {
         None => ("", href),
@@ -185,6 +326,30 @@ pub enum MatchReason {
     Tags(String),
 }
 
+/// The kind of an autocomplete search result, so that the UI can show an
+/// icon or label for it.
+#[derive(Debug, Clone, Copy, Serialize, Eq, PartialEq)]
+pub enum ResultType {
+    /// An origin autofilled from the search string.
+    Origin,
+    /// A URL autofilled from the search string.
+    Url,
+    /// A bookmarked page.
+    Bookmark,
+    /// A page from history.
+    History,
+}
+
+impl ResultType {
+    fn for_page(bookmarked: bool) -> Self {
+        if bookmarked {
+            ResultType::Bookmark
+        } else {
+            ResultType::History
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Eq, PartialEq)]
 pub struct SearchResult {
     /// The search string for this match.
@@ -204,11 +369,23 @@ pub struct SearchResult {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub icon_url: Option<Url>,
 
+    /// The page description, if one was noted for the page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+
+    /// The page preview image URL, if one was noted for the page.
+    #[serde(with = "url_serde")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preview_image_url: Option<Url>,
+
     /// A frecency score for this match.
     pub frecency: i64,
 
     /// A list of reasons why this matched.
     pub reasons: Vec<MatchReason>,
+
+    /// The kind of result.
+    pub result_type: ResultType,
 }
 
 impl SearchResult {
@@ -241,16 +418,19 @@ impl SearchResult {
             url,
             title,
             icon_url: None,
+            description: None,
+            preview_image_url: None,
             frecency,
             reasons,
+            result_type: ResultType::for_page(bookmarked),
         })
     }
 
     pub fn from_suggestion_row(row: &rusqlite::Row<'_>) -> Result<Self> {
-        let mut reasons = vec![MatchReason::Bookmark];
-
         let search_string = row.get::<_, String>("searchString")?;
         let url = row.get::<_, String>("url")?;
+        let bookmarked = row.get::<_, bool>("bookmarked")?;
+        let mut reasons = Vec::new();
 
         let history_title = row.get::<_, Option<String>>("title")?;
         let bookmark_title = row.get::<_, Option<String>>("btitle")?;
@@ -260,6 +440,9 @@ impl SearchResult {
         if let Some(tags) = tags {
             reasons.push(MatchReason::Tags(tags));
         }
+        if bookmarked {
+            reasons.push(MatchReason::Bookmark);
+        }
         let url = Url::parse(&url)?;
 
         let frecency = row.get::<_, i64>("frecency")?;
@@ -269,8 +452,11 @@ impl SearchResult {
             url,
             title,
             icon_url: None,
+            description: None,
+            preview_image_url: None,
             frecency,
             reasons,
+            result_type: ResultType::for_page(bookmarked),
         })
     }
 
@@ -287,8 +473,11 @@ impl SearchResult {
             url,
             title: display_url,
             icon_url: None,
+            description: None,
+            preview_image_url: None,
             frecency,
             reasons: vec![MatchReason::Origin],
+            result_type: ResultType::Origin,
         })
     }
 
@@ -328,8 +517,11 @@ impl SearchResult {
             url,
             title: display_url,
             icon_url: None,
+            description: None,
+            preview_image_url: None,
             frecency,
             reasons,
+            result_type: ResultType::Url,
         })
     }
 }
@@ -408,6 +600,29 @@ const ORIGIN_SQL: &str = "
     LIMIT 1
 ";
 
+// Like `ORIGIN_SQL`, but returns every matching host, with the total
+// frecency of its origins, instead of only the best origin.
+const ORIGINS_SQL: &str = "
+    SELECT (SELECT o.prefix FROM moz_origins o
+            WHERE o.host = grouped_hosts.host
+            ORDER BY o.frecency DESC, o.id DESC
+            LIMIT 1) || host || '/' AS url,
+            host || '/' AS displayURL,
+            host_frecency AS frecency,
+            :searchString AS searchString
+    FROM (
+        SELECT host,
+                CAST(TOTAL(frecency) AS INTEGER) AS host_frecency
+        FROM moz_origins
+        WHERE host BETWEEN :searchString AND :searchString || X'FFFF'
+           OR host BETWEEN 'www.' || :searchString AND 'www.' || :searchString || X'FFFF'
+        GROUP BY host
+        HAVING host_frecency > 0
+    ) AS grouped_hosts
+    ORDER BY frecency DESC, host
+    LIMIT :limit
+";
+
 impl<'query> Matcher for OriginOrUrl<'query> {
     fn search(&self, conn: &PlacesDb, _: u32) -> Result<Vec<SearchResult>> {
         Ok(if looks_like_origin(self.query) {
@@ -493,10 +708,10 @@ impl<'query> Matcher for Adaptive<'query> {
                    h.frecency as frecency,
                    :searchString AS searchString
             FROM (
-              SELECT ROUND(MAX(use_count) * (1 + (input = :searchString)), 1) AS rank,
+              SELECT ROUND(MAX(use_count) * (1 + (input = :input)), 1) AS rank,
                      place_id
               FROM moz_inputhistory
-              WHERE input BETWEEN :searchString AND :searchString || X'FFFF'
+              WHERE input BETWEEN :input AND :input || X'FFFF'
               GROUP BY place_id
             ) AS i
             JOIN moz_places h ON h.id = i.place_id
@@ -508,6 +723,7 @@ impl<'query> Matcher for Adaptive<'query> {
             LIMIT :maxResults",
             &[
                 (":searchString", &self.query),
+                (":input", &normalize_input(self.query)),
                 (":matchBehavior", &self.match_behavior),
                 (":searchBehavior", &self.search_behavior),
                 (":maxResults", &max_results),
@@ -556,19 +772,26 @@ impl<'query> Matcher for Suggestions<'query> {
                    h.id as id,
                    NULL AS open_count, h.frecency, :searchString AS searchString
             FROM moz_places h
-            WHERE h.frecency > 0
+            WHERE h.frecency <> 0
               AND AUTOCOMPLETE_MATCH(:searchString, h.url,
                                      IFNULL(btitle, h.title), tags,
                                      visit_count, h.typed,
                                      bookmarked, NULL,
                                      :matchBehavior, :searchBehavior)
-              AND (+h.visit_count_local > 0 OR +h.visit_count_remote > 0)
+              AND (NOT :requireVisits OR
+                   +h.visit_count_local > 0 OR +h.visit_count_remote > 0)
             ORDER BY h.frecency DESC, h.id DESC
             LIMIT :maxResults",
             &[
                 (":searchString", &self.query),
                 (":matchBehavior", &self.match_behavior),
                 (":searchBehavior", &self.search_behavior),
+                // Like Desktop, we suggest unvisited bookmarks when we're
+                // searching bookmarks.
+                (
+                    ":requireVisits",
+                    &!self.search_behavior.contains(SearchBehavior::BOOKMARK),
+                ),
                 (":maxResults", &max_results),
             ],
             SearchResult::from_suggestion_row,
@@ -582,7 +805,7 @@ mod tests {
     use crate::api::places_api::test::new_mem_connection;
     use crate::observation::VisitObservation;
     use crate::storage::history::apply_observation;
-    use crate::types::{Timestamp, VisitTransition};
+    use crate::types::VisitTransition;
 
     #[test]
     fn split() {
@@ -611,6 +834,61 @@ mod tests {
         assert_eq!(split_after_host_and_port("foo:example"), ("example", ""));
     }
 
+    #[test]
+    fn search_origins_by_prefix() {
+        let conn = new_mem_connection();
+        for (url, visit_type) in &[
+            ("https://www.youtube.com/watch?v=1", VisitTransition::Typed),
+            ("https://www.youtube.com/watch?v=2", VisitTransition::Link),
+            ("http://www.youtube.com/", VisitTransition::Link),
+            ("https://yourbank.example/", VisitTransition::Link),
+            ("https://example.com/you", VisitTransition::Typed),
+        ] {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(url).unwrap())
+                    .with_visit_type(*visit_type)
+                    .with_at(Timestamp::now()),
+            )
+            .expect("Should apply visit");
+        }
+
+        let results = search_origins(&conn, "You", 10).expect("Should search origins");
+        assert_eq!(
+            results
+                .iter()
+                .map(|result| (result.url.as_str(), result.title.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("https://www.youtube.com/", "www.youtube.com/"),
+                ("https://yourbank.example/", "yourbank.example/"),
+            ]
+        );
+        assert!(results
+            .iter()
+            .all(|result| result.result_type == ResultType::Origin));
+        // The host's frecency includes both origins.
+        let origin_frecency = conn
+            .query_one::<i64>(
+                "SELECT SUM(frecency) FROM moz_origins WHERE host = 'www.youtube.com'",
+            )
+            .expect("Should sum frecencies");
+        assert_eq!(results[0].frecency, origin_frecency);
+
+        assert_eq!(
+            search_origins(&conn, "you", 1)
+                .expect("Should search origins")
+                .len(),
+            1
+        );
+        assert!(search_origins(&conn, "you tube", 10)
+            .expect("Should search origins")
+            .is_empty());
+        assert!(search_origins(&conn, "vimeo", 10)
+            .expect("Should search origins")
+            .is_empty());
+    }
+
     #[test]
     fn search() {
         let conn = new_mem_connection();
@@ -628,6 +906,7 @@ mod tests {
             SearchParams {
                 search_string: "example.com".into(),
                 limit: 10,
+                ..SearchParams::default()
             },
         )
         .expect("Should search by origin");
@@ -643,6 +922,7 @@ mod tests {
             SearchParams {
                 search_string: "http://example.com".into(),
                 limit: 10,
+                ..SearchParams::default()
             },
         )
         .expect("Should search by URL without path");
@@ -657,6 +937,7 @@ mod tests {
             SearchParams {
                 search_string: "http://example.com/1".into(),
                 limit: 10,
+                ..SearchParams::default()
             },
         )
         .expect("Should search by URL with path");
@@ -673,6 +954,7 @@ mod tests {
             SearchParams {
                 search_string: "ample".into(),
                 limit: 10,
+                ..SearchParams::default()
             },
         )
         .expect("Should search by adaptive input history");
@@ -687,6 +969,7 @@ mod tests {
             SearchParams {
                 search_string: "example".into(),
                 limit: 1,
+                ..SearchParams::default()
             },
         )
         .expect("Should search until reaching limit");
@@ -697,11 +980,274 @@ mod tests {
                 url: Url::parse("http://example.com/").unwrap(),
                 title: "example.com/".into(),
                 icon_url: None,
-                frecency: -1,
+                description: None,
+                preview_image_url: None,
+                frecency: 2000,
                 reasons: vec![MatchReason::Origin],
+                result_type: ResultType::Origin,
             }]
         );
     }
+    #[test]
+    fn adaptive() {
+        let conn = new_mem_connection();
+
+        let url1 = Url::parse("http://example.com/1").unwrap();
+        let url2 = Url::parse("http://example.com/2").unwrap();
+        for url in &[&url1, &url1, &url2] {
+            let visit = VisitObservation::new((*url).clone())
+                .with_visit_type(VisitTransition::Typed)
+                .with_at(Timestamp::now());
+            apply_observation(&conn, visit).expect("Should apply visit");
+        }
+
+        let search = |search_string: &str| {
+            search_frecent(
+                &conn,
+                SearchParams {
+                    search_string: search_string.into(),
+                    limit: 10,
+                    search_behavior: SearchBehavior::HISTORY | SearchBehavior::RESTRICT,
+                    ..SearchParams::default()
+                },
+            )
+            .expect("Should search")
+            .into_iter()
+            .map(|result| result.url)
+            .collect::<Vec<_>>()
+        };
+        assert_eq!(search("example"), vec![url1.clone(), url2.clone()]);
+
+        // Choosing a result boosts it for the query, and for shorter queries
+        // that it starts with, so the user can type less next time.
+        accept_result(&conn, "Example ", &url2).expect("Should accept result");
+        assert_eq!(search("EXAMPLE"), vec![url2.clone(), url1.clone()]);
+        assert_eq!(search("exam"), vec![url2.clone(), url1.clone()]);
+        assert_eq!(search("example.com"), vec![url1.clone(), url2.clone()]);
+
+        // Old choices decay, and are eventually forgotten.
+        let now = Timestamp::now();
+        decay_input_history(&conn, now).expect("Should set last decay time");
+        decay_input_history(
+            &conn,
+            Timestamp(now.as_millis() + 365 * 24 * 60 * 60 * 1000),
+        )
+        .expect("Should decay input history");
+        let count: i64 = conn
+            .query_one("SELECT COUNT(*) FROM moz_inputhistory")
+            .unwrap();
+        assert_eq!(count, 0);
+        assert_eq!(search("exam"), vec![url1, url2]);
+    }
+
+    #[test]
+    fn search_behaviors() {
+        use crate::storage::bookmarks::{
+            insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+        };
+
+        let conn = new_mem_connection();
+
+        let visited = Url::parse("http://example.com/sweet-treats").unwrap();
+        let visit = VisitObservation::new(visited.clone())
+            .with_title("Donuts".to_string())
+            .with_visit_type(VisitTransition::Typed)
+            .with_at(Timestamp::now());
+        apply_observation(&conn, visit).expect("Should apply visit");
+
+        let bookmarked = Url::parse("http://example.org/treats").unwrap();
+        insert_bookmark(
+            &conn,
+            &InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: bookmarked.clone(),
+                title: Some("Cakes".into()),
+            }
+            .into(),
+        )
+        .expect("Should insert bookmark");
+
+        let search = |search_string: &str, match_behavior, search_behavior| {
+            search_frecent(
+                &conn,
+                SearchParams {
+                    search_string: search_string.into(),
+                    limit: 10,
+                    match_behavior,
+                    search_behavior,
+                },
+            )
+            .expect("Should search")
+            .into_iter()
+            .map(|result| (result.url, result.result_type))
+            .collect::<Vec<_>>()
+        };
+
+        // By default, we match history and bookmarks anywhere.
+        assert_eq!(
+            search("treats", MatchBehavior::Anywhere, SearchBehavior::default()),
+            vec![
+                (visited.clone(), ResultType::History),
+                (bookmarked.clone(), ResultType::Bookmark),
+            ]
+        );
+        assert_eq!(
+            search(
+                "treats",
+                MatchBehavior::Anywhere,
+                SearchBehavior::BOOKMARK | SearchBehavior::RESTRICT
+            ),
+            vec![(bookmarked.clone(), ResultType::Bookmark)]
+        );
+        assert_eq!(
+            search(
+                "treats",
+                MatchBehavior::Anywhere,
+                SearchBehavior::HISTORY | SearchBehavior::RESTRICT
+            ),
+            vec![(visited.clone(), ResultType::History)]
+        );
+
+        // "sweet-treats" only has "treats" on a word boundary, which isn't
+        // enough to match it at the beginning.
+        assert_eq!(
+            search("treats", MatchBehavior::Boundary, SearchBehavior::default()),
+            vec![
+                (visited.clone(), ResultType::History),
+                (bookmarked.clone(), ResultType::Bookmark),
+            ]
+        );
+        assert_eq!(
+            search(
+                "treats",
+                MatchBehavior::Beginning,
+                SearchBehavior::default()
+            ),
+            vec![]
+        );
+        assert_eq!(
+            search("reats", MatchBehavior::Boundary, SearchBehavior::default()),
+            vec![]
+        );
+        assert_eq!(
+            search(
+                "reats",
+                MatchBehavior::BoundaryAnywhere,
+                SearchBehavior::default()
+            )
+            .len(),
+            2
+        );
+
+        // Title searches don't autofill, and don't match URLs.
+        assert_eq!(
+            search(
+                "example.com",
+                MatchBehavior::Anywhere,
+                SearchBehavior::default()
+            ),
+            vec![
+                (
+                    Url::parse("http://example.com/").unwrap(),
+                    ResultType::Origin
+                ),
+                (visited.clone(), ResultType::History),
+            ]
+        );
+        assert_eq!(
+            search(
+                "example.com",
+                MatchBehavior::Anywhere,
+                SearchBehavior::default() | SearchBehavior::TITLE
+            ),
+            vec![]
+        );
+        assert_eq!(
+            search(
+                "donuts",
+                MatchBehavior::Anywhere,
+                SearchBehavior::default() | SearchBehavior::TITLE
+            ),
+            vec![(visited, ResultType::History)]
+        );
+    }
+
+    #[test]
+    fn search_icons() {
+        use crate::storage::favicons::{set_icon_for_origin, set_icon_for_page, Icon};
+
+        let conn = new_mem_connection();
+
+        let url = Url::parse("http://example.com/123").unwrap();
+        let visit = VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Typed)
+            .with_at(Timestamp::now());
+        apply_observation(&conn, visit).expect("Should apply visit");
+
+        let origin_icon = Url::parse("http://example.com/favicon.ico").unwrap();
+        let page_icon = Url::parse("http://example.com/123.png").unwrap();
+        set_icon_for_origin(&conn, &url, &Icon::new(origin_icon.clone(), 0))
+            .expect("Should set origin icon");
+        set_icon_for_page(&conn, &url, &Icon::new(page_icon.clone(), 16))
+            .expect("Should set page icon");
+
+        let results = search_frecent(
+            &conn,
+            SearchParams {
+                search_string: "example".into(),
+                limit: 10,
+                ..SearchParams::default()
+            },
+        )
+        .expect("Should search");
+        assert!(results
+            .iter()
+            .any(|result| result.url.as_str() == "http://example.com/"
+                && result.icon_url.as_ref() == Some(&origin_icon)));
+        assert!(results
+            .iter()
+            .any(|result| result.url == url && result.icon_url.as_ref() == Some(&page_icon)));
+    }
+
+    #[test]
+    fn search_metadata() {
+        use crate::storage::history::note_page_metadata;
+
+        let conn = new_mem_connection();
+
+        let url = Url::parse("http://example.com/123").unwrap();
+        let image = Url::parse("http://example.com/123.png").unwrap();
+        let visit = VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Typed)
+            .with_at(Timestamp::now());
+        apply_observation(&conn, visit).expect("Should apply visit");
+        note_page_metadata(&conn, &url, Some("Page 123"), Some(&image))
+            .expect("Should note metadata");
+
+        let results = search_frecent(
+            &conn,
+            SearchParams {
+                search_string: "example.com/1".into(),
+                limit: 10,
+                ..SearchParams::default()
+            },
+        )
+        .expect("Should search");
+        let result = results
+            .iter()
+            .find(|result| result.url == url)
+            .expect("Should find page");
+        assert_eq!(
+            result.description.as_ref().map(String::as_str),
+            Some("Page 123")
+        );
+        assert_eq!(result.preview_image_url.as_ref(), Some(&image));
+    }
+
     #[test]
     fn search_unicode() {
         let conn = new_mem_connection();
@@ -719,6 +1265,7 @@ mod tests {
             SearchParams {
                 search_string: "http://exämple.com".into(),
                 limit: 10,
+                ..SearchParams::default()
             },
         )
         .expect("Should search by URL without path");
@@ -734,6 +1281,7 @@ mod tests {
             SearchParams {
                 search_string: "http://exämple.com/1".into(),
                 limit: 10,
+                ..SearchParams::default()
             },
         )
         .expect("Should search by URL with path");
@@ -758,6 +1306,7 @@ mod tests {
             SearchParams {
                 search_string: ball_of_yarn_about_blank.into(),
                 limit: 10,
+                ..SearchParams::default()
             },
         )
         .unwrap();
@@ -786,7 +1335,58 @@ mod tests {
             SearchParams {
                 search_string: "not-a-url".into(),
                 limit: 10,
+                ..SearchParams::default()
             },
         );
     }
+
+    #[test]
+    fn search_interrupted() {
+        use crate::error::ErrorKind;
+        use sql_support::SqlInterruptHandle;
+
+        // A matcher that cancels the search, like if the user typed another
+        // character while we were still matching.
+        struct InterruptingMatcher(SqlInterruptHandle);
+
+        impl Matcher for InterruptingMatcher {
+            fn search(&self, _conn: &PlacesDb, _max_results: u32) -> Result<Vec<SearchResult>> {
+                self.0.interrupt();
+                Ok(Vec::new())
+            }
+        }
+
+        let conn = new_mem_connection();
+        let url = Url::parse("https://example.com/").unwrap();
+        apply_observation(
+            &conn,
+            VisitObservation::new(url).with_visit_type(VisitTransition::Typed),
+        )
+        .expect("Should apply visit");
+
+        let matchers: Vec<Box<dyn Matcher>> = vec![
+            Box::new(InterruptingMatcher(conn.new_interrupt_handle())),
+            Box::new(OriginOrUrl::new("example")),
+        ];
+        let scope = conn.begin_interrupt_scope();
+        match match_with_limit(&conn, &scope, &matchers, 10) {
+            Err(e) => match e.kind() {
+                ErrorKind::InterruptedError(_) => {}
+                kind => panic!("Unexpected error {:?}", kind),
+            },
+            Ok(results) => panic!("Unexpected results {:?}", results),
+        }
+
+        // Later searches aren't affected.
+        let results = search_frecent(
+            &conn,
+            SearchParams {
+                search_string: "example".into(),
+                limit: 10,
+                ..SearchParams::default()
+            },
+        )
+        .expect("Should search after interrupting");
+        assert_eq!(results.len(), 1);
+    }
 }
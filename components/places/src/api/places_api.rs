@@ -2,11 +2,16 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use super::diagnostics::{self, SyncHistory};
 use crate::bookmark_sync::store::BookmarksStore;
 use crate::db::db::PlacesDb;
 use crate::error::*;
+use crate::export;
 use crate::history_sync::store::HistoryStore;
-use crate::storage::{delete_meta, get_meta, put_meta};
+use crate::import;
+use crate::observer::{ObserverHandle, Observers, PlacesObserver};
+use crate::storage::{self, delete_meta, get_meta, put_meta};
+use crate::types::Timestamp;
 use crate::util::normalize_path;
 use lazy_static::lazy_static;
 use rusqlite::OpenFlags;
@@ -61,6 +66,89 @@ impl ConnectionType {
     }
 }
 
+/// Describes a corrupt database that we replaced with a new, empty one when
+/// opening it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseRecovery {
+    /// Where we moved the corrupt database, in case support wants to try to
+    /// recover anything from it.
+    pub corrupt_path: PathBuf,
+    /// The error we got when opening the corrupt database.
+    pub reason: String,
+}
+
+/// Returns true if `error`, from opening the database at `path`, means the
+/// database is corrupt. If it's a different SQLite error, we run an integrity
+/// check to make sure, since a damaged database can fail in other ways, too.
+/// Any other error, or an error running the check, means we can't say, so we
+/// leave the database alone.
+fn is_corrupt(error: &Error, path: &Path) -> bool {
+    let code = match error.kind() {
+        ErrorKind::SqlError(rusqlite::Error::SqliteFailure(err, _)) => err.code,
+        _ => return false,
+    };
+    match code {
+        rusqlite::ErrorCode::DatabaseCorrupt | rusqlite::ErrorCode::NotADatabase => true,
+        rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked => false,
+        _ => {
+            // Memory databases can't be moved aside.
+            if !path.exists() {
+                return false;
+            }
+            let check = rusqlite::Connection::open_with_flags(
+                path,
+                ConnectionType::ReadOnly.rusqlite_flags(),
+            )
+            .and_then(|conn| {
+                conn.query_row("PRAGMA quick_check(1)", rusqlite::NO_PARAMS, |row| {
+                    row.get::<_, String>(0)
+                })
+            });
+            match check {
+                Ok(result) => result != "ok",
+                Err(rusqlite::Error::SqliteFailure(err, _)) => {
+                    err.code == rusqlite::ErrorCode::DatabaseCorrupt
+                        || err.code == rusqlite::ErrorCode::NotADatabase
+                }
+                Err(_) => false,
+            }
+        }
+    }
+}
+
+/// Moves the corrupt database at `path` to `path.corrupt`, replacing an older
+/// corrupt database, so that we can create a new one in its place. Its WAL
+/// and shared memory files are deleted, so that SQLite doesn't try to apply
+/// them to the new database.
+fn move_corrupt_db_aside(path: &Path, error: &Error) -> Result<DatabaseRecovery> {
+    let mut corrupt_path = path.as_os_str().to_owned();
+    corrupt_path.push(".corrupt");
+    let corrupt_path = PathBuf::from(corrupt_path);
+    log::error!(
+        "Database is corrupt, moving it to {:?} and starting over: {}",
+        corrupt_path,
+        error
+    );
+    if corrupt_path.exists() {
+        fs::remove_file(&corrupt_path)?;
+    }
+    fs::rename(path, &corrupt_path)?;
+    for suffix in &["-wal", "-shm"] {
+        let mut journal_path = path.as_os_str().to_owned();
+        journal_path.push(suffix);
+        match fs::remove_file(&journal_path) {
+            Err(ref e) if e.kind() != std::io::ErrorKind::NotFound => {
+                log::warn!("Failed to remove {:?}: {}", journal_path, e);
+            }
+            _ => {}
+        }
+    }
+    Ok(DatabaseRecovery {
+        corrupt_path,
+        reason: error.to_string(),
+    })
+}
+
 // We only allow a single PlacesApi per filename.
 lazy_static! {
     static ref APIS: Mutex<HashMap<PathBuf, Weak<PlacesApi>>> = Mutex::new(HashMap::new());
@@ -80,9 +168,16 @@ pub struct PlacesApi {
     db_name: PathBuf,
     write_connection: Mutex<Option<PlacesDb>>,
     sync_state: Mutex<Option<SyncState>>,
+    sync_history: Mutex<SyncHistory>,
     coop_tx_lock: Arc<Mutex<()>>,
     sync_conn_active: AtomicBool,
     id: usize,
+    observers: Arc<Observers>,
+    // Idle read-only connections for `reader`.
+    reader_pool: Mutex<Vec<PlacesDb>>,
+    recovery: Option<DatabaseRecovery>,
+    // Set by `set_journal_size_limit`, for connections opened afterward.
+    journal_size_limit: Mutex<Option<u64>>,
 }
 impl PlacesApi {
     /// Create a new, or fetch an already open, PlacesApi backed by a file on disk.
@@ -102,6 +197,7 @@ impl PlacesApi {
         target: &mut HashMap<PathBuf, Weak<PlacesApi>>,
         db_name: PathBuf,
         delete_on_fail: bool,
+        recovery: Option<DatabaseRecovery>,
     ) -> Result<Arc<Self>> {
         let id = ID_COUNTER.fetch_add(1, Ordering::SeqCst);
         match target.get(&db_name).and_then(Weak::upgrade) {
@@ -110,20 +206,27 @@ impl PlacesApi {
                 // We always create a new read-write connection for an initial open so
                 // we can create the schema and/or do version upgrades.
                 let coop_tx_lock = Arc::new(Mutex::new(()));
+                let observers = Arc::new(Observers::default());
                 match PlacesDb::open(
                     &db_name,
                     ConnectionType::ReadWrite,
                     id,
                     coop_tx_lock.clone(),
                 ) {
-                    Ok(connection) => {
+                    Ok(mut connection) => {
+                        connection.set_observers(observers.clone());
                         let new = PlacesApi {
                             db_name: db_name.clone(),
                             write_connection: Mutex::new(Some(connection)),
                             sync_state: Mutex::new(None),
+                            sync_history: Mutex::default(),
                             sync_conn_active: AtomicBool::new(false),
                             id,
                             coop_tx_lock,
+                            observers,
+                            reader_pool: Mutex::default(),
+                            recovery,
+                            journal_size_limit: Mutex::default(),
                         };
                         let arc = Arc::new(new);
                         target.insert(db_name, Arc::downgrade(&arc));
@@ -135,7 +238,10 @@ impl PlacesApi {
                         }
                         if let ErrorKind::DatabaseUpgradeError = e.kind() {
                             fs::remove_file(&db_name)?;
-                            Self::new_or_existing_into(target, db_name, false)
+                            Self::new_or_existing_into(target, db_name, false, None)
+                        } else if is_corrupt(&e, &db_name) {
+                            let recovery = move_corrupt_db_aside(&db_name, &e)?;
+                            Self::new_or_existing_into(target, db_name, false, Some(recovery))
                         } else {
                             Err(e)
                         }
@@ -147,7 +253,14 @@ impl PlacesApi {
 
     fn new_or_existing(db_name: PathBuf) -> Result<Arc<Self>> {
         let mut guard = APIS.lock().unwrap();
-        Self::new_or_existing_into(&mut guard, db_name, true)
+        Self::new_or_existing_into(&mut guard, db_name, true, None)
+    }
+
+    /// Returns how the database was recovered, if it was corrupt when this API
+    /// opened it. Consumers can use this to tell the user that their history
+    /// and bookmarks were lost, and to record telemetry.
+    pub fn database_recovery(&self) -> Option<&DatabaseRecovery> {
+        self.recovery.as_ref()
     }
 
     /// Open a connection to the database.
@@ -155,12 +268,15 @@ impl PlacesApi {
         match conn_type {
             ConnectionType::ReadOnly => {
                 // make a new one - we can have as many of these as we want.
-                PlacesDb::open(
+                let mut db = PlacesDb::open(
                     self.db_name.clone(),
                     ConnectionType::ReadOnly,
                     self.id,
                     self.coop_tx_lock.clone(),
-                )
+                )?;
+                db.set_observers(self.observers.clone());
+                self.apply_journal_size_limit(&db)?;
+                Ok(db)
             }
             ConnectionType::ReadWrite => {
                 // We only allow one of these.
@@ -176,6 +292,24 @@ impl PlacesApi {
         }
     }
 
+    /// Borrows a read-only connection from a small pool, opening a new one if
+    /// they're all in use. The connection goes back to the pool when the
+    /// reader is dropped, so callers that read often, like autocomplete and
+    /// top sites, can read in parallel without opening a connection for every
+    /// query. Each connection has its own interrupt handle, so canceling one
+    /// reader's queries doesn't cancel the others.
+    pub fn reader(&self) -> Result<PooledReader<'_>> {
+        let pooled = self.reader_pool.lock().unwrap().pop();
+        let db = match pooled {
+            Some(db) => db,
+            None => self.open_connection(ConnectionType::ReadOnly)?,
+        };
+        Ok(PooledReader {
+            db: Some(db),
+            pool: &self.reader_pool,
+        })
+    }
+
     pub fn open_sync_connection(&self) -> Result<SyncConn<'_>> {
         let prev_value = self
             .sync_conn_active
@@ -183,12 +317,14 @@ impl PlacesApi {
         if prev_value {
             Err(ErrorKind::ConnectionAlreadyOpen.into())
         } else {
-            let db = PlacesDb::open(
+            let mut db = PlacesDb::open(
                 self.db_name.clone(),
                 ConnectionType::Sync,
                 self.id,
                 self.coop_tx_lock.clone(),
             )?;
+            db.set_observers(self.observers.clone());
+            self.apply_journal_size_limit(&db)?;
             Ok(SyncConn {
                 db,
                 flag: &self.sync_conn_active,
@@ -196,6 +332,18 @@ impl PlacesApi {
         }
     }
 
+    /// Registers an observer to be told about changes to history and
+    /// bookmarks, made on any connection opened by this API, including by
+    /// syncs.
+    pub fn register_observer(&self, observer: Arc<dyn PlacesObserver>) -> ObserverHandle {
+        self.observers.register(observer)
+    }
+
+    /// Returns true if the observer was registered.
+    pub fn unregister_observer(&self, handle: ObserverHandle) -> bool {
+        self.observers.unregister(handle)
+    }
+
     /// Close a connection to the database. If the connection is the write
     /// connection, you can re-fetch it using open_connection.
     pub fn close_connection(&self, connection: PlacesDb) -> Result<()> {
@@ -294,6 +442,7 @@ impl PlacesApi {
         let mut mem_cached_state = sync_state.mem_cached_state.take();
         let mut disk_cached_state = sync_state.disk_cached_state.take();
         let mut result = syncer(&conn, &mut mem_cached_state, &mut disk_cached_state);
+        self.sync_history.lock().unwrap().record(&result);
         // even on failure we set the persisted state - sync itself takes care
         // to ensure this has been None'd out if necessary.
         self.set_disk_persisted_state(&conn, &disk_cached_state)?;
@@ -352,6 +501,7 @@ impl PlacesApi {
             key_bundle,
             &interruptee,
         );
+        self.sync_history.lock().unwrap().record(&result);
         // even on failure we set the persisted state - sync itself takes care
         // to ensure this has been None'd out if necessary.
         if let Err(e) = self.set_disk_persisted_state(&conn, &disk_cached_state) {
@@ -382,6 +532,129 @@ impl PlacesApi {
         Ok(())
     }
 
+    /// Returns a JSON blob describing the database and the most recent syncs,
+    /// suitable for attaching to bug reports. URLs, titles and account
+    /// identifiers are never included.
+    pub fn create_diagnostic_bundle(&self) -> Result<String> {
+        let conn = self.reader()?;
+        let history = self.sync_history.lock().unwrap();
+        diagnostics::create_diagnostic_bundle(&conn, &history)
+    }
+
+    /// Returns row counts and sizes for the database, and the folders with
+    /// the most children.
+    pub fn db_stats(&self) -> Result<diagnostics::DbStats> {
+        let conn = self.reader()?;
+        diagnostics::DbStats::fetch(&conn)
+    }
+
+    /// Cleans up the database, using the write connection. This fails if the
+    /// write connection is open; in that case, use
+    /// `storage::run_maintenance` with it instead.
+    pub fn run_maintenance(&self) -> Result<storage::MaintenanceReport> {
+        let conn = self.open_connection(ConnectionType::ReadWrite)?;
+        let result = storage::run_maintenance(&conn);
+        self.close_connection(conn)?;
+        result
+    }
+
+    /// Returns the sizes of the database and its write-ahead log.
+    pub fn db_size(&self) -> Result<storage::DbSize> {
+        let conn = self.reader()?;
+        storage::db_size(&conn)
+    }
+
+    /// Copies the write-ahead log back into the database, using `conn`,
+    /// which must be the write connection or a sync connection. Apps should
+    /// use `CheckpointMode::Truncate` before backing up the database file.
+    pub fn checkpoint(
+        &self,
+        conn: &PlacesDb,
+        mode: storage::CheckpointMode,
+    ) -> Result<storage::CheckpointResult> {
+        if conn.conn_type() == ConnectionType::ReadOnly {
+            return Err(ErrorKind::InvalidConnectionType.into());
+        }
+        storage::checkpoint(conn, mode)
+    }
+
+    /// Limits how big the write-ahead log stays after a checkpoint, in
+    /// bytes. `None` removes the limit. This applies to `conn`, which is
+    /// usually the write connection the app holds, to this API's idle
+    /// connections, and to the connections it opens afterward.
+    pub fn set_journal_size_limit(&self, conn: &PlacesDb, limit: Option<u64>) -> Result<()> {
+        storage::set_journal_size_limit(conn, limit)?;
+        if let Some(writer) = &*self.write_connection.lock().unwrap() {
+            storage::set_journal_size_limit(writer, limit)?;
+        }
+        for reader in self.reader_pool.lock().unwrap().iter() {
+            storage::set_journal_size_limit(reader, limit)?;
+        }
+        *self.journal_size_limit.lock().unwrap() = limit;
+        Ok(())
+    }
+
+    fn apply_journal_size_limit(&self, db: &PlacesDb) -> Result<()> {
+        let limit = *self.journal_size_limit.lock().unwrap();
+        if limit.is_some() {
+            storage::set_journal_size_limit(db, limit)?;
+        }
+        Ok(())
+    }
+
+    /// Backs up every bookmark to a JSON file in `dir`, named like Desktop's
+    /// backups, and returns its path. A backup made earlier on the same day
+    /// is replaced.
+    pub fn backup_bookmarks(&self, dir: impl AsRef<Path>) -> Result<PathBuf> {
+        let conn = self.reader()?;
+        let path = dir
+            .as_ref()
+            .join(export::json_bookmarks::backup_file_name(Timestamp::now()));
+        export::backup_bookmarks_json(&conn, &path)?;
+        Ok(path)
+    }
+
+    /// Replaces every bookmark with the ones in the JSON backup at `path`,
+    /// using the write connection. This fails if the write connection is
+    /// open; in that case, use `import::restore_bookmarks_json` with it
+    /// instead. Returns the number of restored items.
+    pub fn restore_bookmarks(&self, path: impl AsRef<Path>) -> Result<usize> {
+        let conn = self.open_connection(ConnectionType::ReadWrite)?;
+        let result = import::restore_bookmarks_json(&conn, path);
+        self.close_connection(conn)?;
+        result
+    }
+
+    /// Marks the frecencies of pages that the user hasn't visited in a while
+    /// as stale, and recalculates all stale frecencies, using the sync
+    /// connection. This can take a while for large databases, so it should
+    /// be run during idle time; it can be interrupted with the sync
+    /// connection's interrupt handle, and resumed by calling it again.
+    /// Returns how many frecencies were recalculated.
+    pub fn update_frecencies(&self) -> Result<usize> {
+        // Take the lock to prevent syncing while we're doing this.
+        let _guard = self.sync_state.lock().unwrap();
+        let conn = self.open_sync_connection()?;
+        let scope = conn.begin_interrupt_scope();
+        storage::history::decay_frecencies(&conn, Timestamp::now())?;
+        storage::history::recalculate_stale_frecencies(&conn, &scope)
+    }
+
+    /// Runs one step of history expiration with `policy`, using the sync
+    /// connection. Like `update_frecencies`, this should be run during idle
+    /// time, and can be interrupted with the sync connection's interrupt
+    /// handle. If the returned report isn't `finished`, call it again later
+    /// to expire more.
+    pub fn expire_history(
+        &self,
+        policy: &storage::expiration::ExpirationPolicy,
+    ) -> Result<storage::expiration::ExpirationReport> {
+        let _guard = self.sync_state.lock().unwrap();
+        let conn = self.open_sync_connection()?;
+        let scope = conn.begin_interrupt_scope();
+        storage::expiration::expire_history(&conn, policy, Timestamp::now(), &scope)
+    }
+
     /// Get a new interrupt handle for the sync connection.
     pub fn new_sync_conn_interrupt_handle(&self) -> Result<SqlInterruptHandle> {
         // Probably not necessary to lock here, since this should only get
@@ -412,6 +685,35 @@ impl<'a> std::ops::Deref for SyncConn<'a> {
     }
 }
 
+/// The most idle read-only connections that we keep open for `reader`. Extra
+/// connections are closed when their readers are dropped.
+const MAX_POOLED_READERS: usize = 4;
+
+/// A read-only connection borrowed from `PlacesApi::reader`.
+pub struct PooledReader<'api> {
+    // Only `None` while dropping.
+    db: Option<PlacesDb>,
+    pool: &'api Mutex<Vec<PlacesDb>>,
+}
+
+impl<'a> Drop for PooledReader<'a> {
+    fn drop(&mut self) {
+        if let Some(db) = self.db.take() {
+            let mut pool = self.pool.lock().unwrap();
+            if pool.len() < MAX_POOLED_READERS {
+                pool.push(db);
+            }
+        }
+    }
+}
+
+impl<'a> std::ops::Deref for PooledReader<'a> {
+    type Target = PlacesDb;
+    fn deref(&self) -> &PlacesDb {
+        self.db.as_ref().unwrap()
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
@@ -512,6 +814,58 @@ mod tests {
         assert_eq!(val, 999);
     }
 
+    #[test]
+    fn test_reader_pool() {
+        let api = new_mem_api();
+        let writer = api
+            .open_connection(ConnectionType::ReadWrite)
+            .expect("should get writer");
+        writer
+            .execute_batch(
+                "CREATE TABLE test_table (test_value INTEGER);
+                 INSERT INTO test_table VALUES (999)",
+            )
+            .expect("should insert");
+
+        {
+            let reader1 = api.reader().expect("should get first reader");
+            let reader2 = api.reader().expect("should get second reader");
+            assert_eq!(api.reader_pool.lock().unwrap().len(), 0);
+
+            // Interrupting one reader doesn't interrupt the other.
+            let scope1 = reader1.begin_interrupt_scope();
+            let scope2 = reader2.begin_interrupt_scope();
+            reader1.new_interrupt_handle().interrupt();
+            assert!(scope1.err_if_interrupted().is_err());
+            assert!(scope2.err_if_interrupted().is_ok());
+
+            let val = reader2
+                .query_one::<i64>("SELECT test_value FROM test_table")
+                .expect("should get value");
+            assert_eq!(val, 999);
+        }
+        assert_eq!(api.reader_pool.lock().unwrap().len(), 2);
+
+        // Dropped readers are reused.
+        {
+            let reader = api.reader().expect("should get pooled reader");
+            assert_eq!(api.reader_pool.lock().unwrap().len(), 1);
+            let val = reader
+                .query_one::<i64>("SELECT test_value FROM test_table")
+                .expect("should get value");
+            assert_eq!(val, 999);
+        }
+
+        // But we don't keep too many idle readers open.
+        {
+            let _readers = (0..MAX_POOLED_READERS + 2)
+                .map(|_| api.reader().expect("should get reader"))
+                .collect::<Vec<_>>();
+            assert_eq!(api.reader_pool.lock().unwrap().len(), 0);
+        }
+        assert_eq!(api.reader_pool.lock().unwrap().len(), MAX_POOLED_READERS);
+    }
+
     #[test]
     fn test_wrong_writer_close() {
         let api = new_mem_api();
@@ -546,6 +900,55 @@ mod tests {
         assert!(api.open_connection(ConnectionType::ReadWrite).is_ok());
     }
 
+    #[test]
+    fn test_db_size_and_checkpoint() -> Result<()> {
+        let dirname = tempfile::tempdir().unwrap();
+        let api = PlacesApi::new(dirname.path().join("places.sqlite"))?;
+        let pooled_limit = || -> Result<i64> {
+            let reader = api.reader()?;
+            Ok(reader.query_one::<i64>("PRAGMA journal_size_limit")?)
+        };
+        assert_eq!(pooled_limit()?, -1);
+
+        // Apps hold on to the write connection, so both of these need to
+        // work while it's open.
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+        api.set_journal_size_limit(&conn, Some(0))?;
+        assert_eq!(conn.query_one::<i64>("PRAGMA journal_size_limit")?, 0);
+        conn.execute_batch(
+            "CREATE TABLE test_table(test_value TEXT);
+             INSERT INTO test_table VALUES(hex(randomblob(100000)));",
+        )?;
+
+        let size = api.db_size()?;
+        assert!(size.db_bytes > 0);
+        assert!(size.wal_bytes > 0);
+
+        // Idle pooled connections, and connections opened afterward, get the
+        // limit, too.
+        assert_eq!(pooled_limit()?, 0);
+        let reader = api.open_connection(ConnectionType::ReadOnly)?;
+        assert_eq!(reader.query_one::<i64>("PRAGMA journal_size_limit")?, 0);
+
+        // Read-only connections can't checkpoint.
+        match api
+            .checkpoint(&reader, storage::CheckpointMode::Truncate)
+            .unwrap_err()
+            .kind()
+        {
+            ErrorKind::InvalidConnectionType => {}
+            e => panic!("Expected InvalidConnectionType, got {:?}", e),
+        }
+        drop(reader);
+
+        let result = api.checkpoint(&conn, storage::CheckpointMode::Truncate)?;
+        assert!(!result.busy);
+        assert_eq!(result.log_pages, 0);
+        assert_eq!(api.db_size()?.wal_bytes, 0);
+        api.close_connection(conn)?;
+        Ok(())
+    }
+
     #[test]
     fn test_old_db_version() -> Result<()> {
         let dirname = tempfile::tempdir().unwrap();
@@ -563,4 +966,32 @@ mod tests {
         assert_ne!(1, conn.db.query_one::<i64>("PRAGMA user_version")?);
         Ok(())
     }
+
+    #[test]
+    fn test_corrupt_db() -> Result<()> {
+        let dirname = tempfile::tempdir().unwrap();
+        let db_name = dirname.path().join("temp.db");
+        fs::write(&db_name, vec![0xff; 4096])?;
+
+        let api = PlacesApi::new(&db_name)?;
+        let recovery = api
+            .database_recovery()
+            .expect("should recover from corrupt database");
+        assert_eq!(
+            recovery.corrupt_path,
+            dirname.path().join("temp.db.corrupt")
+        );
+        assert_eq!(fs::read(&recovery.corrupt_path)?, vec![0xff; 4096]);
+
+        // The new database should work.
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+        assert_eq!(conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places")?, 0);
+        api.close_connection(conn)?;
+        drop(api);
+
+        // And shouldn't be recovered again next time.
+        let api = PlacesApi::new(&db_name)?;
+        assert!(api.database_recovery().is_none());
+        Ok(())
+    }
 }
@@ -4,7 +4,9 @@
 
 mod incoming;
 pub mod record;
+pub mod repair;
 pub mod store;
+mod validation;
 
 #[cfg(test)]
 mod tests;
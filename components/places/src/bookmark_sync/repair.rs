@@ -0,0 +1,119 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The responder side of Desktop's bookmark repair protocol. When Desktop's
+//! validator finds records missing or broken on the server, it sends a
+//! `repairRequest` command listing their IDs to the other clients. Clients
+//! that have those items locally reupload them, and send a `repairResponse`
+//! back with the IDs they uploaded.
+//!
+//! The clients engine handles the commands; this module only flags the
+//! requested items for upload on the next bookmark sync.
+
+use super::record::BookmarkRecordId;
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::bookmarks::BookmarkRootGuid;
+use sync_guid::Guid as SyncGuid;
+
+/// Flags the local items with the requested record IDs for reupload, by
+/// bumping their change counters, and returns the record IDs of the items
+/// we'll upload. IDs that we don't have locally are skipped. The Places
+/// root is never uploaded, so it's skipped, too.
+pub fn prepare_repair_upload(db: &PlacesDb, ids: &[String]) -> Result<Vec<String>> {
+    let tx = db.begin_transaction()?;
+    let guids = ids
+        .iter()
+        .map(|id| BookmarkRecordId::from_payload_id(id.as_str().into()).into())
+        .filter(|guid: &SyncGuid| *guid != BookmarkRootGuid::Root.as_guid())
+        .collect::<Vec<SyncGuid>>();
+    let mut found = Vec::with_capacity(guids.len());
+    sql_support::each_chunk(&guids, |chunk, _| -> Result<()> {
+        let mut stmt = db.prepare(&format!(
+            "SELECT guid FROM moz_bookmarks WHERE guid IN ({})",
+            sql_support::repeat_sql_vars(chunk.len())
+        ))?;
+        let mut rows = stmt.query(chunk)?;
+        while let Some(row) = rows.next()? {
+            found.push(row.get::<_, SyncGuid>("guid")?);
+        }
+        db.execute(
+            &format!(
+                "UPDATE moz_bookmarks SET
+                     syncChangeCounter = syncChangeCounter + 1
+                 WHERE guid IN ({})",
+                sql_support::repeat_sql_vars(chunk.len())
+            ),
+            chunk,
+        )?;
+        Ok(())
+    })?;
+    tx.commit()?;
+    log::info!(
+        "Reuploading {} of {} bookmarks requested for repair",
+        found.len(),
+        ids.len()
+    );
+    Ok(found
+        .into_iter()
+        .map(|guid| BookmarkRecordId::from(guid).into_payload_id())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::tests::insert_json_tree;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use sql_support::ConnExt;
+
+    #[test]
+    fn test_prepare_repair_upload() -> Result<()> {
+        let conn = new_mem_connection();
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "A",
+                    "url": "https://example.com/a",
+                }, {
+                    "guid": "bookmarkBBBB",
+                    "title": "B",
+                    "url": "https://example.com/b",
+                }],
+            }),
+        );
+        conn.execute_batch("UPDATE moz_bookmarks SET syncChangeCounter = 0")?;
+
+        let mut ids = prepare_repair_upload(
+            &conn,
+            &[
+                "places".into(),
+                "unfiled".into(),
+                "bookmarkAAAA".into(),
+                "bookmarkCCCC".into(),
+            ],
+        )?;
+        ids.sort();
+        assert_eq!(ids, vec!["bookmarkAAAA", "unfiled"]);
+
+        let change_counter = |guid: &str| -> Result<u32> {
+            Ok(conn.query_row_and_then_named(
+                "SELECT syncChangeCounter FROM moz_bookmarks WHERE guid = :guid",
+                &[(":guid", &guid)],
+                |row| row.get(0),
+                false,
+            )?)
+        };
+        assert_eq!(change_counter("bookmarkAAAA")?, 1);
+        assert_eq!(change_counter("bookmarkBBBB")?, 0);
+        assert_eq!(change_counter(BookmarkRootGuid::Unfiled.as_str())?, 1);
+        assert_eq!(change_counter(BookmarkRootGuid::Root.as_str())?, 0);
+        Ok(())
+    }
+}
@@ -8,12 +8,13 @@ use super::record::{
     BookmarkItemRecord, BookmarkRecord, BookmarkRecordId, FolderRecord, QueryRecord,
     SeparatorRecord,
 };
+use super::validation::{self, LocalTreeProblems};
 use super::{SyncedBookmarkKind, SyncedBookmarkValidity};
 use crate::api::places_api::ConnectionType;
 use crate::db::PlacesDb;
 use crate::error::*;
-use crate::frecency::{calculate_frecency, DEFAULT_FRECENCY_SETTINGS};
-use crate::storage::{bookmarks::BookmarkRootGuid, delete_meta, get_meta, put_meta};
+use crate::observer::PlacesEvent;
+use crate::storage::{bookmarks::BookmarkRootGuid, delete_meta, get_meta, history, put_meta};
 use crate::types::{BookmarkType, SyncStatus, Timestamp};
 use dogear::{
     self, AbortSignal, Content, Deletion, Item, MergedDescendant, MergedRoot, TelemetryEvent, Tree,
@@ -34,14 +35,8 @@ use sync_guid::Guid as SyncGuid;
 pub const LAST_SYNC_META_KEY: &str = "bookmarks_last_sync_time";
 // Note that all engines in this crate should use a *different* meta key
 // for the global sync ID, because engines are reset individually.
-const GLOBAL_SYNCID_META_KEY: &str = "bookmarks_global_sync_id";
-const COLLECTION_SYNCID_META_KEY: &str = "bookmarks_sync_id";
-
-/// The maximum number of URLs for which to recalculate frecencies at once.
-/// This is a trade-off between write efficiency and transaction time: higher
-/// maximums mean fewer write statements, but longer transactions, possibly
-/// blocking writes from other connections.
-const MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK: usize = 400;
+pub(crate) const GLOBAL_SYNCID_META_KEY: &str = "bookmarks_global_sync_id";
+pub(crate) const COLLECTION_SYNCID_META_KEY: &str = "bookmarks_sync_id";
 
 /// Adapts an interruptee to a Dogear abort signal.
 struct MergeInterruptee<'a, I>(&'a I);
@@ -245,11 +240,11 @@ impl<'a> BookmarksStore<'a> {
                                        kind, url, keyword, position)
              SELECT s.id, s.guid, s.syncChangeCounter, s.parentGuid,
                     s.parentTitle, s.dateAdded, s.title, s.placeId,
-                    {kind}, h.url, v.keyword, s.position
+                    {kind}, h.url, k.keyword, s.position
              FROM localItems s
              JOIN mergedTree r ON r.mergedGuid = s.guid
-             LEFT JOIN moz_bookmarks_synced v ON v.guid = r.remoteGuid
              LEFT JOIN moz_places h ON h.id = s.placeId
+             LEFT JOIN moz_keywords k ON k.place_id = s.placeId
              LEFT JOIN idsToWeaklyUpload w ON w.id = s.id
              WHERE s.guid <> '{root_guid}' AND
                    (s.syncChangeCounter > 0 OR w.id NOT NULL)",
@@ -455,75 +450,7 @@ impl<'a> BookmarksStore<'a> {
     }
 
     pub(crate) fn update_frecencies(&self) -> Result<()> {
-        let mut tx = self.db.begin_transaction()?;
-
-        let mut frecencies = Vec::with_capacity(MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK);
-        loop {
-            let sql = format!(
-                "SELECT place_id FROM moz_places_stale_frecencies
-                 ORDER BY stale_at DESC
-                 LIMIT {}",
-                MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK
-            );
-            let mut stmt = self.db.prepare_maybe_cached(&sql, true)?;
-            let mut results = stmt.query(NO_PARAMS)?;
-            while let Some(row) = results.next()? {
-                let place_id = row.get("place_id")?;
-                // Frecency recalculation runs several statements, so check to
-                // make sure we aren't interrupted before each calculation.
-                self.interruptee.err_if_interrupted()?;
-                let frecency = calculate_frecency(
-                    &self.db,
-                    &DEFAULT_FRECENCY_SETTINGS,
-                    place_id,
-                    Some(false),
-                )?;
-                frecencies.push((place_id, frecency));
-            }
-            if frecencies.is_empty() {
-                break;
-            }
-
-            // Update all frecencies in one fell swoop...
-            self.db.execute_batch(&format!(
-                "WITH frecencies(id, frecency) AS (
-                   VALUES {}
-                 )
-                 UPDATE moz_places SET
-                   frecency = (SELECT frecency FROM frecencies f
-                               WHERE f.id = id)
-                 WHERE id IN (SELECT f.id FROM frecencies f)",
-                sql_support::repeat_display(frecencies.len(), ",", |index, f| {
-                    let (id, frecency) = frecencies[index];
-                    write!(f, "({}, {})", id, frecency)
-                })
-            ))?;
-            tx.maybe_commit()?;
-            self.interruptee.err_if_interrupted()?;
-
-            // ...And remove them from the stale table.
-            self.db.execute_batch(&format!(
-                "DELETE FROM moz_places_stale_frecencies
-                 WHERE place_id IN ({})",
-                sql_support::repeat_display(frecencies.len(), ",", |index, f| {
-                    let (id, _) = frecencies[index];
-                    write!(f, "{}", id)
-                })
-            ))?;
-            tx.maybe_commit()?;
-            self.interruptee.err_if_interrupted()?;
-
-            // If the query returned fewer URLs than the maximum, we're done.
-            // Otherwise, we might have more, so clear the ones we just
-            // recalculated and fetch the next chunk.
-            if frecencies.len() < MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK {
-                break;
-            }
-            frecencies.clear();
-        }
-
-        tx.commit()?;
-
+        history::recalculate_stale_frecencies(self.db, self.interruptee)?;
         Ok(())
     }
 
@@ -572,6 +499,7 @@ impl<'a> Store for BookmarksStore<'a> {
         telem: &mut telemetry::Engine,
     ) -> result::Result<OutgoingChangeset, failure::Error> {
         // Stage all incoming items.
+        let num_duplicate_guids = validation::count_duplicate_guids(&inbound.changes);
         let mut incoming_telemetry = telemetry::EngineIncoming::new();
         let timestamp = self.stage_incoming(inbound, &mut incoming_telemetry)?;
         telem.incoming(incoming_telemetry);
@@ -583,6 +511,7 @@ impl<'a> Store for BookmarksStore<'a> {
 
         // Merge.
         let mut merger = Merger::with_telemetry(&self, timestamp, telem);
+        merger.num_duplicate_guids = num_duplicate_guids;
         merger.merge()?;
 
         // Finally, stage outgoing items.
@@ -655,6 +584,7 @@ impl<'a> Store for BookmarksStore<'a> {
         );
         self.db.execute_batch(&sql)?;
         create_synced_bookmark_roots(self.db)?;
+        self.db.notify(PlacesEvent::BookmarksChanged);
         tx.commit()?;
         Ok(())
     }
@@ -703,6 +633,9 @@ pub(crate) struct Merger<'a> {
     // turns it on, to avoid accidentally enabling unintentionally.
     external_transaction: bool,
     telem: Option<&'a mut telemetry::Engine>,
+    // The number of incoming records with the same GUIDs as other records,
+    // for validation telemetry.
+    num_duplicate_guids: usize,
 }
 
 impl<'a> Merger<'a> {
@@ -713,6 +646,7 @@ impl<'a> Merger<'a> {
             local_time: Timestamp::now(),
             external_transaction: false,
             telem: None,
+            num_duplicate_guids: 0,
         }
     }
 
@@ -727,6 +661,7 @@ impl<'a> Merger<'a> {
             local_time: Timestamp::now(),
             external_transaction: false,
             telem: Some(telem),
+            num_duplicate_guids: 0,
         }
     }
 
@@ -750,7 +685,17 @@ impl<'a> Merger<'a> {
 
         // Record telemetry in all cases, even if the merge fails.
         if let Some(ref mut telem) = self.telem {
-            telem.validation(driver.validation.into_inner());
+            let mut validation = driver.validation.into_inner();
+            validation.problem("duplicates", self.num_duplicate_guids);
+            // If the merge failed, the local tree didn't change, so there's
+            // nothing new to check.
+            if result.is_ok() {
+                match LocalTreeProblems::fetch(self.store.db) {
+                    Ok(problems) => problems.record(&mut validation),
+                    Err(e) => log::warn!("Failed to validate local tree: {}", e),
+                }
+            }
+            telem.validation(validation);
         }
         result
     }
@@ -1031,8 +976,15 @@ impl<'a> dogear::Store<Error> for Merger<'a> {
         } else {
             None
         };
+        let changed_locally = !deletions.is_empty()
+            || descendants
+                .iter()
+                .any(|d| d.merged_node.merge_state.should_apply());
         self.store
             .update_local_items(self.local_time, descendants, deletions)?;
+        if changed_locally {
+            self.store.db.notify(PlacesEvent::BookmarksChanged);
+        }
         self.store.stage_local_items_to_upload()?;
         self.store.db.execute_batch(
             "DELETE FROM mergedTree;
@@ -1180,9 +1132,12 @@ mod tests {
     use crate::bookmark_sync::store::BookmarksStore;
     use crate::db::PlacesDb;
     use crate::storage::{
-        bookmarks::{get_raw_bookmark, update_bookmark, UpdatableBookmark, USER_CONTENT_ROOTS},
+        bookmarks::{
+            bookmarks_get_url_for_keyword, get_raw_bookmark, update_bookmark, UpdatableBookmark,
+            USER_CONTENT_ROOTS,
+        },
         history::frecency_stale_at,
-        tags,
+        keywords, tags,
     };
     use crate::tests::{
         assert_json_tree as assert_local_json_tree, insert_json_tree as insert_local_json_tree,
@@ -1820,6 +1775,124 @@ mod tests {
         assert_eq!(outgoing.changes[0].id, "bookmarkAAAA");
         assert_eq!(outgoing.changes[0].data["keyword"], "a");
 
+        let outgoing_ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.clone())
+            .collect::<Vec<_>>();
+        store
+            .sync_finished(ServerTimestamp(1000), outgoing_ids)
+            .expect("Should push synced changes back to the store");
+
+        let url = Url::parse("http://example.com/a")?;
+        assert_eq!(
+            bookmarks_get_url_for_keyword(&writer, "a")?,
+            Some(url.clone()),
+            "Should apply incoming keywords"
+        );
+
+        // Changing the keyword locally should reupload the bookmark.
+        keywords::set_keyword(&writer, &url, "b")?;
+        assert_eq!(bookmarks_get_url_for_keyword(&writer, "a")?, None);
+
+        let outgoing = store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(2000)),
+                &mut telemetry::Engine::new("bookmarks"),
+            )
+            .expect("Should fetch outgoing records after changing the keyword");
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].id, "bookmarkAAAA");
+        assert_eq!(outgoing.changes[0].data["keyword"], "b");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mobile_root() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        let syncer = api.open_sync_connection()?;
+
+        apply_incoming(
+            &syncer,
+            ServerTimestamp(0),
+            json!([{
+                "id": "mobile",
+                "type": "folder",
+                "parentid": "places",
+                "parentName": "",
+                "dateAdded": 0,
+                "title": "mobile",
+                "children": ["bookmarkAAAA"],
+            }, {
+                "id": "bookmarkAAAA",
+                "type": "bookmark",
+                "parentid": "mobile",
+                "parentName": "mobile",
+                "dateAdded": 1_552_183_116_885u64,
+                "title": "A",
+                "bmkUri": "http://example.com/a",
+            }]),
+        );
+        assert_local_json_tree(
+            &writer,
+            &BookmarkRootGuid::Mobile.as_guid(),
+            json!({
+                "guid": &BookmarkRootGuid::Mobile.as_guid(),
+                "children": [{
+                    "guid": "bookmarkAAAA",
+                    "title": "A",
+                    "url": "http://example.com/a",
+                }],
+            }),
+        );
+
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Mobile.as_guid(),
+                "children": [{
+                    "guid": "bookmarkBBBB",
+                    "title": "B",
+                    "url": "http://example.com/b",
+                }],
+            }),
+        );
+
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+        let outgoing = store
+            .apply_incoming(
+                IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(1000)),
+                &mut telemetry::Engine::new("bookmarks"),
+            )
+            .expect("Should fetch outgoing records for the mobile root");
+        let mut outgoing_ids = outgoing
+            .changes
+            .iter()
+            .map(|p| p.id.as_str())
+            .collect::<Vec<_>>();
+        outgoing_ids.sort();
+        assert_eq!(outgoing_ids, &["bookmarkBBBB", "mobile"]);
+
+        let mobile = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "mobile")
+            .expect("Should upload the mobile root");
+        assert_eq!(mobile.data["parentid"], "places");
+        assert_eq!(
+            mobile.data["children"],
+            json!(["bookmarkAAAA", "bookmarkBBBB"])
+        );
+        let record_for_b = outgoing
+            .changes
+            .iter()
+            .find(|p| p.id == "bookmarkBBBB")
+            .expect("Should upload B");
+        assert_eq!(record_for_b.data["parentid"], "mobile");
+
         Ok(())
     }
 
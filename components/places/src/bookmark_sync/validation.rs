@@ -0,0 +1,213 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Like Desktop's bookmark validator, this checks the bookmarks trees for
+//! structural problems during a sync, and reports how many of each we found
+//! in the sync ping, so that we can measure how often trees are corrupt.
+//!
+//! Dogear reports problems with the remote tree, like orphans, missing
+//! children, and parent-child disagreements, when we build it for merging.
+//! This module adds the problems that dogear can't see: records that the
+//! server sent more than once, and problems with the local tree after we
+//! applied the merged tree to it.
+
+use super::record::BookmarkRecordId;
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::bookmarks::BookmarkRootGuid;
+use crate::types::BookmarkType;
+use sql_support::ConnExt;
+use std::collections::HashSet;
+use sync15::{telemetry, Payload, ServerTimestamp};
+
+/// Returns the number of incoming records whose GUIDs we've already seen in
+/// the same sync. Record IDs for the roots are normalized first, so `menu`
+/// and `menu________` are duplicates.
+pub(crate) fn count_duplicate_guids(changes: &[(Payload, ServerTimestamp)]) -> usize {
+    let mut seen = HashSet::with_capacity(changes.len());
+    changes
+        .iter()
+        .filter(|(payload, _)| {
+            let guid = BookmarkRecordId::from_payload_id(payload.id.clone())
+                .as_guid()
+                .clone();
+            !seen.insert(guid)
+        })
+        .count()
+}
+
+/// Structural problems with the local tree. The schema's constraints rule
+/// out most of the problems that remote trees can have, like bookmarks
+/// without URLs and children with missing parents, so these are the ones
+/// that can still happen.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct LocalTreeProblems {
+    /// Items that can't be reached from the root, because they're in a
+    /// parent-child cycle.
+    pub orphans: usize,
+    /// Items whose parents are bookmarks or separators.
+    pub non_folder_parents: usize,
+    /// Folders whose children's positions have gaps or duplicates, or don't
+    /// start at 0.
+    pub misordered_folders: usize,
+}
+
+impl LocalTreeProblems {
+    pub fn fetch(db: &PlacesDb) -> Result<Self> {
+        let orphans = db.query_row_and_then_named(
+            "WITH RECURSIVE
+             reachable(id) AS (
+               SELECT id FROM moz_bookmarks
+               WHERE guid = :root_guid
+               UNION
+               SELECT b.id FROM moz_bookmarks b
+               JOIN reachable r ON b.parent = r.id
+             )
+             SELECT COUNT(*) FROM moz_bookmarks
+             WHERE id NOT IN reachable",
+            &[(":root_guid", &BookmarkRootGuid::Root.as_guid())],
+            |row| row.get::<_, i64>(0),
+            false,
+        )?;
+        let non_folder_parents = db.query_row_and_then_named(
+            "SELECT COUNT(*) FROM moz_bookmarks b
+             JOIN moz_bookmarks p ON p.id = b.parent
+             WHERE p.type <> :folder_type",
+            &[(":folder_type", &BookmarkType::Folder)],
+            |row| row.get::<_, i64>(0),
+            false,
+        )?;
+        let misordered_folders = db.query_one::<i64>(
+            "SELECT COUNT(*) FROM (
+               SELECT parent FROM moz_bookmarks
+               WHERE parent NOT NULL
+               GROUP BY parent
+               HAVING MIN(position) <> 0 OR
+                      MAX(position) <> COUNT(*) - 1 OR
+                      COUNT(DISTINCT position) <> COUNT(*)
+             )",
+        )?;
+        Ok(LocalTreeProblems {
+            orphans: orphans as usize,
+            non_folder_parents: non_folder_parents as usize,
+            misordered_folders: misordered_folders as usize,
+        })
+    }
+
+    pub fn record(&self, validation: &mut telemetry::Validation) {
+        validation
+            .problem("clientOrphans", self.orphans)
+            .problem("clientNonFolderParents", self.non_folder_parents)
+            .problem("clientMisorderedFolders", self.misordered_folders);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::tests::insert_json_tree;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use sync_guid::Guid as SyncGuid;
+
+    #[test]
+    fn test_count_duplicate_guids() {
+        let changes = [
+            "menu",
+            "menu________",
+            "bookmarkAAAA",
+            "bookmarkBBBB",
+            "bookmarkAAAA",
+        ]
+        .iter()
+        .map(|&id| (Payload::new_tombstone(id.to_owned()), ServerTimestamp(0)))
+        .collect::<Vec<_>>();
+        assert_eq!(count_duplicate_guids(&changes), 2);
+        assert_eq!(count_duplicate_guids(&changes[2..4]), 0);
+    }
+
+    #[test]
+    fn test_local_tree_problems() -> Result<()> {
+        let conn = new_mem_connection();
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [{
+                    "guid": "folderAAAAAA",
+                    "title": "A",
+                    "children": [{
+                        "guid": "folderBBBBBB",
+                        "title": "B",
+                        "children": [{
+                            "guid": "bookmarkCCCC",
+                            "url": "https://example.com/c",
+                        }],
+                    }],
+                }, {
+                    "guid": "bookmarkDDDD",
+                    "url": "https://example.com/d",
+                }, {
+                    "guid": "bookmarkEEEE",
+                    "url": "https://example.com/e",
+                }],
+            }),
+        );
+        assert_eq!(
+            LocalTreeProblems::fetch(&conn)?,
+            LocalTreeProblems::default()
+        );
+
+        let reparent = |guid: &str, parent_guid: &str| {
+            conn.execute_named(
+                "UPDATE moz_bookmarks SET
+                   parent = (SELECT id FROM moz_bookmarks WHERE guid = :parent_guid)
+                 WHERE guid = :guid",
+                &[
+                    (":guid", &SyncGuid::from(guid)),
+                    (":parent_guid", &SyncGuid::from(parent_guid)),
+                ],
+            )
+            .expect("should reparent");
+        };
+        // A and B are now each others' parents, so neither they nor C are
+        // reachable from the root. This also leaves a gap in unfiled.
+        reparent("folderAAAAAA", "folderBBBBBB");
+        // E is now a child of D, at position 2.
+        reparent("bookmarkEEEE", "bookmarkDDDD");
+
+        let problems = LocalTreeProblems::fetch(&conn)?;
+        assert_eq!(
+            problems,
+            LocalTreeProblems {
+                orphans: 3,
+                non_folder_parents: 1,
+                // Unfiled, which only has D at position 1; B, which has A
+                // and C at position 0; and D, which only has E at position 2.
+                misordered_folders: 3,
+            }
+        );
+
+        let mut validation = telemetry::Validation::default();
+        problems.record(&mut validation);
+        assert_eq!(
+            serde_json::to_value(&validation)?,
+            json!({
+                "version": 0,
+                "problems": [{
+                    "name": "clientOrphans",
+                    "count": 3,
+                }, {
+                    "name": "clientNonFolderParents",
+                    "count": 1,
+                }, {
+                    "name": "clientMisorderedFolders",
+                    "count": 3,
+                }],
+            })
+        );
+        Ok(())
+    }
+}
@@ -5,8 +5,10 @@
 use super::schema;
 use crate::api::places_api::ConnectionType;
 use crate::error::*;
+use crate::observer::{Observers, PlacesEvent};
 use rusqlite::Connection;
 use sql_support::{ConnExt, SqlInterruptHandle, SqlInterruptScope};
+use std::cell::RefCell;
 use std::ops::Deref;
 use std::path::Path;
 
@@ -22,6 +24,9 @@ pub struct PlacesDb {
     api_id: usize,
     in_memory: bool,
     pub(super) coop_tx_lock: Arc<Mutex<()>>,
+    observers: Arc<Observers>,
+    // Events for changes in the current transaction, sent when it commits.
+    pending_events: RefCell<Vec<PlacesEvent>>,
 }
 
 impl PlacesDb {
@@ -79,6 +84,9 @@ impl PlacesDb {
             interrupt_counter: Arc::new(AtomicUsize::new(0)),
             coop_tx_lock,
             in_memory,
+            // The API sets this, too, if it has observers.
+            observers: Arc::default(),
+            pending_events: RefCell::default(),
         };
         match res.conn_type() {
             // For read-only connections, we can avoid opening a transaction,
@@ -150,6 +158,31 @@ impl PlacesDb {
     pub fn is_in_memory(&self) -> bool {
         self.in_memory
     }
+
+    pub(crate) fn set_observers(&mut self, observers: Arc<Observers>) {
+        self.observers = observers;
+    }
+
+    /// Tells observers about a change. If we're in a transaction, this waits
+    /// until it commits.
+    pub(crate) fn notify(&self, event: PlacesEvent) {
+        if self.db.is_autocommit() {
+            self.observers.notify(&[event]);
+        } else {
+            self.pending_events.borrow_mut().push(event);
+        }
+    }
+
+    /// Sends the events for a transaction that just committed.
+    pub(crate) fn notify_pending(&self) {
+        let events = self.pending_events.replace(Vec::new());
+        self.observers.notify(&events);
+    }
+
+    /// Drops the events for a transaction that was rolled back.
+    pub(crate) fn discard_pending(&self) {
+        self.pending_events.borrow_mut().clear();
+    }
 }
 
 impl Drop for PlacesDb {
@@ -16,7 +16,7 @@ use crate::types::SyncStatus;
 use rusqlite::NO_PARAMS;
 use sql_support::ConnExt;
 
-const VERSION: i64 = 9;
+const VERSION: i64 = 13;
 
 // Shared schema and temp tables for the read-write and Sync connections.
 const CREATE_SHARED_SCHEMA_SQL: &str = include_str!("../../sql/create_shared_schema.sql");
@@ -219,6 +219,57 @@ fn upgrade(db: &PlacesDb, from: i64) -> Result<()> {
         ],
         || Ok(()),
     )?;
+    migration(
+        db,
+        9,
+        10,
+        &[
+            CREATE_SHARED_SCHEMA_SQL,
+            // Keywords used to only be stored in the synced bookmarks table,
+            // so copy them over. The temp triggers that maintain the foreign
+            // count don't exist yet, so we do that here, too.
+            "INSERT OR IGNORE INTO moz_keywords(keyword, place_id)
+             SELECT keyword, placeId FROM moz_bookmarks_synced
+             WHERE keyword NOT NULL AND
+                   placeId NOT NULL AND
+                   NOT isDeleted",
+            "UPDATE moz_places SET
+                 foreign_count = foreign_count + 1
+             WHERE id IN (SELECT place_id FROM moz_keywords)",
+        ],
+        || Ok(()),
+    )?;
+    migration(db, 10, 11, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?;
+    migration(db, 11, 12, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?; // pinned sites.
+    migration(
+        db,
+        12,
+        13,
+        &[
+            // Origin frecencies weren't updated when page frecencies
+            // changed, so recalculate them, and the stats.
+            "UPDATE moz_origins SET
+                 frecency = (SELECT CAST(TOTAL(MAX(frecency, 0)) AS INTEGER)
+                             FROM moz_places
+                             WHERE origin_id = moz_origins.id)",
+            &format!(
+                "INSERT OR REPLACE INTO moz_meta(key, value)
+                 SELECT '{frecency_count}', COUNT(*)
+                 FROM moz_origins WHERE frecency > 0
+                 UNION
+                 SELECT '{frecency_sum}', CAST(TOTAL(frecency) AS INTEGER)
+                 FROM moz_origins WHERE frecency > 0
+                 UNION
+                 SELECT '{frecency_sum_of_squares}',
+                        CAST(TOTAL(frecency * frecency) AS INTEGER)
+                 FROM moz_origins WHERE frecency > 0",
+                frecency_count = MOZ_META_KEY_ORIGIN_FRECENCY_COUNT,
+                frecency_sum = MOZ_META_KEY_ORIGIN_FRECENCY_SUM,
+                frecency_sum_of_squares = MOZ_META_KEY_ORIGIN_FRECENCY_SUM_OF_SQUARES,
+            ),
+        ],
+        || Ok(()),
+    )?;
     // Add more migrations here...
 
     if get_current_schema_version(db)? == VERSION {
@@ -626,7 +677,7 @@ mod tests {
             ),
             NO_PARAMS,
         )
-        .expect("should insert regular bookmark folder");;
+        .expect("should insert regular bookmark folder");
         conn.execute(
             "DELETE FROM moz_bookmarks WHERE guid = 'bookmarkguid'",
             NO_PARAMS,
@@ -659,7 +710,7 @@ mod tests {
                         (3, 1, 0, 1, 1, 'bookmarkguid')",
             NO_PARAMS,
         )
-        .expect("should insert regular bookmark folder");;
+        .expect("should insert regular bookmark folder");
         // tombstone should have vanished.
         assert_eq!(
             select_simple_int(&conn, "SELECT COUNT(*) from moz_bookmarks_deleted"),
@@ -686,7 +737,7 @@ mod tests {
                         (3, 1, 0, 1, 1, 'fake_guid___')",
             NO_PARAMS,
         )
-        .expect("should insert regular bookmark folder");;
+        .expect("should insert regular bookmark folder");
         // tombstone should remain.
         assert_eq!(
             select_simple_int(&conn, "SELECT COUNT(*) from moz_bookmarks_deleted"),
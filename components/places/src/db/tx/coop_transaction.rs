@@ -136,14 +136,15 @@ impl<'conn> ChunkedCoopTransaction<'conn> {
 
     /// Checks to see if we have held a transaction for longer than the
     /// requested time, and if so, commits the current transaction and opens
-    /// another.
+    /// another. Returns true if it committed.
     #[inline]
-    pub fn maybe_commit(&mut self) -> Result<()> {
+    pub fn maybe_commit(&mut self) -> Result<bool> {
         if self.tx.started_at.elapsed() >= self.commit_after {
             log::debug!("ChunkedCoopTransaction commiting after taking allocated time");
             self.commit_and_start_new_tx()?;
+            return Ok(true);
         }
-        Ok(())
+        Ok(false)
     }
 
     fn commit_and_start_new_tx(&mut self) -> Result<()> {
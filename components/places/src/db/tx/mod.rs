@@ -20,7 +20,7 @@ macro_rules! debug_complaint {
 }
 /// High level transaction type which "does the right thing" for you.
 /// Construct one with `PlacesDb::begin_transaction()`.
-pub struct PlacesTransaction<'conn>(PlacesTransactionRepr<'conn>);
+pub struct PlacesTransaction<'conn>(PlacesTransactionRepr<'conn>, &'conn super::PlacesDb);
 
 /// Only separated from PlacesTransaction so that the internals of the former
 /// are private (so that it can't be `matched` on, for example)
@@ -41,7 +41,9 @@ impl<'conn> PlacesTransaction<'conn> {
     #[inline]
     pub fn maybe_commit(&mut self) -> Result<()> {
         if let PlacesTransactionRepr::ChunkedWrite(tx) = &mut self.0 {
-            tx.maybe_commit()?;
+            if tx.maybe_commit()? {
+                self.1.notify_pending();
+            }
         } else {
             debug_complaint!("maybe_commit called on a non-chunked transaction");
         }
@@ -55,6 +57,7 @@ impl<'conn> PlacesTransaction<'conn> {
             PlacesTransactionRepr::UnchunkedWrite(t) => t.commit()?,
             PlacesTransactionRepr::ReadOnly(t) => t.commit()?,
         };
+        self.1.notify_pending();
         Ok(())
     }
 
@@ -67,6 +70,7 @@ impl<'conn> PlacesTransaction<'conn> {
             PlacesTransactionRepr::UnchunkedWrite(t) => t.rollback()?,
             PlacesTransactionRepr::ReadOnly(t) => t.rollback()?,
         };
+        self.1.discard_pending();
         Ok(())
     }
 }
@@ -78,7 +82,10 @@ impl super::PlacesDb {
     /// - for ReadWrite connections, begins a normal coop transaction
     /// - for ReadOnly connections, begins an unchecked transaction.
     pub fn begin_transaction(&self) -> Result<PlacesTransaction<'_>> {
-        Ok(PlacesTransaction(match self.conn_type() {
+        // Events from a transaction that was dropped without committing
+        // were rolled back with it.
+        self.discard_pending();
+        let repr = match self.conn_type() {
             ConnectionType::Sync => {
                 PlacesTransactionRepr::ChunkedWrite(self.chunked_coop_trransaction()?)
             }
@@ -89,7 +96,8 @@ impl super::PlacesDb {
                 // Use an unchecked transaction with no locking.
                 PlacesTransactionRepr::ReadOnly(self.unchecked_transaction()?)
             }
-        }))
+        };
+        Ok(PlacesTransaction(repr, self))
     }
 }
 
@@ -39,6 +39,9 @@ pub enum ErrorKind {
     #[fail(display = "An invalid connection type was specified")]
     InvalidConnectionType,
 
+    #[fail(display = "An invalid checkpoint mode was specified: {}", _0)]
+    InvalidCheckpointMode(u8),
+
     #[fail(display = "IO error: {}", _0)]
     IoError(#[fail(cause)] std::io::Error),
 
@@ -128,6 +131,11 @@ pub enum InvalidPlaceInfo {
     // Like Urls, a tag is considered private info, so the value isn't in the error.
     #[fail(display = "The tag value is invalid")]
     InvalidTag,
+
+    // Keywords are private info too.
+    #[fail(display = "The keyword value is invalid")]
+    InvalidKeyword,
+
     #[fail(
         display = "Cannot change the '{}' property of a bookmark of type {:?}",
         _0, _1
@@ -0,0 +1,339 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Exports the bookmarks tree in the Netscape bookmarks HTML format, like
+//! Desktop, so that users can back up their bookmarks or take them to
+//! another browser. `import::import_bookmarks_html` reads these files.
+//!
+//! Like on Desktop, the menu's children are at the top level, followed by
+//! a folder for each of the other roots. The mobile root is only exported
+//! if it isn't empty.
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::bookmarks::{
+    fetch_tree, BookmarkNode, BookmarkRootGuid, BookmarkTreeNode, FetchDepth, FolderNode,
+};
+use crate::storage::{favicons, keywords, tags};
+use crate::types::Timestamp;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// The width of the icons to export. Desktop exports 16px icons.
+const EXPORT_ICON_WIDTH: u32 = 16;
+
+const HEADER: &str = "<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<!-- This is an automatically generated file.
+     It will be read and overwritten.
+     DO NOT EDIT! -->
+<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=UTF-8\">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks Menu</H1>
+
+<DL><p>";
+
+/// Exports every bookmark to the HTML file at `path`, replacing it if it
+/// exists. Returns the number of exported bookmarks, folders and
+/// separators, not including the roots.
+pub fn export_bookmarks_html(db: &PlacesDb, path: impl AsRef<Path>) -> Result<usize> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let num_exported = write_bookmarks_html(db, &mut out)?;
+    out.flush()?;
+    log::info!("Exported {} items to bookmarks HTML", num_exported);
+    Ok(num_exported)
+}
+
+fn write_bookmarks_html(db: &PlacesDb, out: &mut impl Write) -> Result<usize> {
+    let mut exporter = Exporter {
+        db,
+        out,
+        num_exported: 0,
+    };
+    writeln!(exporter.out, "{}", HEADER)?;
+    for child in &fetch_root(db, BookmarkRootGuid::Menu)?.children {
+        exporter.write_node(child, 1)?;
+    }
+    exporter.write_folder(
+        &fetch_root(db, BookmarkRootGuid::Toolbar)?,
+        "Bookmarks Toolbar",
+        Some("PERSONAL_TOOLBAR_FOLDER"),
+        1,
+    )?;
+    exporter.write_folder(
+        &fetch_root(db, BookmarkRootGuid::Unfiled)?,
+        "Other Bookmarks",
+        Some("UNFILED_BOOKMARKS_FOLDER"),
+        1,
+    )?;
+    let mobile = fetch_root(db, BookmarkRootGuid::Mobile)?;
+    if !mobile.children.is_empty() {
+        exporter.write_folder(&mobile, "Mobile Bookmarks", None, 1)?;
+    }
+    writeln!(exporter.out, "</DL>")?;
+    Ok(exporter.num_exported)
+}
+
+fn fetch_root(db: &PlacesDb, root: BookmarkRootGuid) -> Result<FolderNode> {
+    match fetch_tree(db, root.guid(), &FetchDepth::Deepest)? {
+        Some((BookmarkTreeNode::Folder(folder), _, _)) => Ok(folder),
+        _ => Err(InvalidPlaceInfo::NoSuchGuid(root.as_str().into()).into()),
+    }
+}
+
+struct Exporter<'a, W> {
+    db: &'a PlacesDb,
+    out: &'a mut W,
+    num_exported: usize,
+}
+
+impl<'a, W: Write> Exporter<'a, W> {
+    fn write_node(&mut self, node: &BookmarkTreeNode, depth: usize) -> Result<()> {
+        match node {
+            BookmarkTreeNode::Bookmark(bookmark) => self.write_bookmark(bookmark, depth)?,
+            BookmarkTreeNode::Separator(_) => {
+                writeln!(self.out, "{}<HR>", indent(depth))?;
+                self.num_exported += 1;
+            }
+            BookmarkTreeNode::Folder(folder) => {
+                let title = folder.title.as_ref().map_or("", String::as_str);
+                self.write_folder(folder, title, None, depth)?;
+                self.num_exported += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_bookmark(&mut self, bookmark: &BookmarkNode, depth: usize) -> Result<()> {
+        write!(
+            self.out,
+            "{}<DT><A HREF=\"{}\"",
+            indent(depth),
+            escape(bookmark.url.as_str())
+        )?;
+        self.write_dates(bookmark.date_added, bookmark.last_modified)?;
+        if let Some(icon_url) =
+            favicons::get_icon_url_for_page(self.db, &bookmark.url, EXPORT_ICON_WIDTH)?
+        {
+            // Desktop puts the icon data in `ICON`, and its URL in
+            // `ICON_URI`. We usually only have the URL, unless it's a
+            // `data:` URL.
+            let name = if icon_url.scheme() == "data" {
+                "ICON"
+            } else {
+                "ICON_URI"
+            };
+            write!(self.out, " {}=\"{}\"", name, escape(icon_url.as_str()))?;
+        }
+        if let Some(keyword) = keywords::get_keyword_for_url(self.db, &bookmark.url)? {
+            write!(self.out, " SHORTCUTURL=\"{}\"", escape(&keyword))?;
+        }
+        let mut tags = tags::get_tags_for_url(self.db, &bookmark.url)?;
+        tags.sort();
+        if !tags.is_empty() {
+            write!(self.out, " TAGS=\"{}\"", escape(&tags.join(",")))?;
+        }
+        let title = bookmark.title.as_ref().map_or("", String::as_str);
+        writeln!(self.out, ">{}</A>", escape(title))?;
+        self.num_exported += 1;
+        Ok(())
+    }
+
+    fn write_folder(
+        &mut self,
+        folder: &FolderNode,
+        title: &str,
+        root_attr: Option<&str>,
+        depth: usize,
+    ) -> Result<()> {
+        write!(self.out, "{}<DT><H3", indent(depth))?;
+        self.write_dates(folder.date_added, folder.last_modified)?;
+        if let Some(root_attr) = root_attr {
+            write!(self.out, " {}=\"true\"", root_attr)?;
+        }
+        writeln!(self.out, ">{}</H3>", escape(title))?;
+        writeln!(self.out, "{}<DL><p>", indent(depth))?;
+        for child in &folder.children {
+            self.write_node(child, depth + 1)?;
+        }
+        writeln!(self.out, "{}</DL><p>", indent(depth))?;
+        Ok(())
+    }
+
+    fn write_dates(
+        &mut self,
+        date_added: Option<Timestamp>,
+        last_modified: Option<Timestamp>,
+    ) -> Result<()> {
+        // Dates are in seconds.
+        if let Some(date_added) = date_added {
+            write!(self.out, " ADD_DATE=\"{}\"", date_added.as_millis() / 1000)?;
+        }
+        if let Some(last_modified) = last_modified {
+            write!(
+                self.out,
+                " LAST_MODIFIED=\"{}\"",
+                last_modified.as_millis() / 1000
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn indent(depth: usize) -> String {
+    "    ".repeat(depth)
+}
+
+fn escape(text: &str) -> Cow<'_, str> {
+    if !text.contains(|c| c == '&' || c == '<' || c == '>' || c == '"') {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::import::import_bookmarks_html;
+    use crate::tests::{assert_json_tree, insert_json_tree};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use url::Url;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("Donuts"), "Donuts");
+        assert_eq!(
+            escape("<Fish & \"chips\">"),
+            "&lt;Fish &amp; &quot;chips&quot;&gt;"
+        );
+    }
+
+    #[test]
+    fn test_export_bookmarks_html() -> Result<()> {
+        let conn = new_mem_connection();
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": BookmarkRootGuid::Menu.as_guid(),
+                "children": [{
+                    "title": "Recipes & more",
+                    "date_added": 1_500_000_000_000u64,
+                    "last_modified": 1_500_000_100_000u64,
+                    "children": [{
+                        "title": "Donuts",
+                        "url": "https://example.com/donuts",
+                        "date_added": 1_500_000_010_000u64,
+                        "last_modified": 1_500_000_010_000u64,
+                    }],
+                }, {
+                    "type": 3,
+                    "date_added": 1_500_000_020_000u64,
+                    "last_modified": 1_500_000_020_000u64,
+                }],
+            }),
+        );
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": BookmarkRootGuid::Toolbar.as_guid(),
+                "children": [{
+                    "title": "Home",
+                    "url": "https://example.org/",
+                    "date_added": 1_500_000_030_000u64,
+                    "last_modified": 1_500_000_030_000u64,
+                }],
+            }),
+        );
+        let donuts = Url::parse("https://example.com/donuts")?;
+        favicons::set_icon_for_page(
+            &conn,
+            &donuts,
+            &favicons::Icon::new(Url::parse("https://example.com/favicon.ico")?, 16),
+        )?;
+        keywords::set_keyword(&conn, &donuts, "donut")?;
+        tags::tag_url(&conn, &donuts, "sweet")?;
+        tags::tag_url(&conn, &donuts, "food")?;
+
+        let mut html = Vec::new();
+        assert_eq!(write_bookmarks_html(&conn, &mut html)?, 4);
+        let html = String::from_utf8(html).unwrap();
+        let body = &html[HEADER.len() + 1..];
+        let mut lines = body.lines();
+        assert_eq!(
+            lines.next(),
+            Some("    <DT><H3 ADD_DATE=\"1500000000\" LAST_MODIFIED=\"1500000100\">Recipes &amp; more</H3>")
+        );
+        assert_eq!(lines.next(), Some("    <DL><p>"));
+        let donuts_line = lines.next().unwrap();
+        assert!(donuts_line.starts_with(
+            "        <DT><A HREF=\"https://example.com/donuts\" ADD_DATE=\"1500000010\""
+        ));
+        assert!(donuts_line.contains(" ICON_URI=\"https://example.com/favicon.ico\""));
+        assert!(donuts_line.contains(" SHORTCUTURL=\"donut\""));
+        assert!(donuts_line.contains(" TAGS=\"food,sweet\""));
+        assert!(donuts_line.ends_with(">Donuts</A>"));
+        assert_eq!(lines.next(), Some("    </DL><p>"));
+        assert_eq!(lines.next(), Some("    <HR>"));
+        // The roots' dates are when the database was created.
+        let toolbar_line = lines.next().unwrap();
+        assert!(toolbar_line.starts_with("    <DT><H3 ADD_DATE="));
+        assert!(toolbar_line.ends_with(" PERSONAL_TOOLBAR_FOLDER=\"true\">Bookmarks Toolbar</H3>"));
+        assert_eq!(lines.next(), Some("    <DL><p>"));
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("        <DT><A HREF=\"https://example.org/\" ADD_DATE=\"1500000030\""));
+
+        // Exported files can be imported again.
+        let file = tempfile::NamedTempFile::new()?;
+        assert_eq!(export_bookmarks_html(&conn, file.path())?, 4);
+        let other_conn = new_mem_connection();
+        assert_eq!(
+            import_bookmarks_html(
+                &other_conn,
+                file.path(),
+                &BookmarkRootGuid::Unfiled.as_guid()
+            )?,
+            6
+        );
+        assert_json_tree(
+            &other_conn,
+            &BookmarkRootGuid::Unfiled.as_guid(),
+            json!({
+                "children": [{
+                    "title": "Recipes & more",
+                    "children": [{
+                        "title": "Donuts",
+                        "url": "https://example.com/donuts",
+                    }],
+                }, {
+                    "type": 3,
+                }, {
+                    "title": "Bookmarks Toolbar",
+                    "children": [{
+                        "title": "Home",
+                        "url": "https://example.org/",
+                    }],
+                }, {
+                    "title": "Other Bookmarks",
+                    "children": [],
+                }],
+            }),
+        );
+        Ok(())
+    }
+}
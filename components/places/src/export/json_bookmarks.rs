@@ -0,0 +1,273 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Backs up the bookmarks tree in Desktop's JSON backup format, so that
+//! support can recover a user's tree, and tools that read Desktop backups
+//! can read ours. `import::restore_bookmarks_json` restores these backups.
+//!
+//! Each item is an object with its GUID, title, position (`index`), dates
+//! in microseconds, and type. Folders have their `children`, and the roots
+//! have a `root` name. Bookmarks have a `uri`, and optionally an `iconuri`,
+//! a `keyword`, and comma-separated `tags`. Unlike Desktop, we don't write
+//! local row ids, since Desktop ignores them when restoring.
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::storage::bookmarks::{fetch_tree, BookmarkRootGuid, BookmarkTreeNode, FetchDepth};
+use crate::storage::{favicons, keywords, tags};
+use crate::types::Timestamp;
+use serde_derive::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+pub(crate) const TYPE_BOOKMARK: &str = "text/x-moz-place";
+pub(crate) const TYPE_FOLDER: &str = "text/x-moz-place-container";
+pub(crate) const TYPE_SEPARATOR: &str = "text/x-moz-place-separator";
+
+/// An item in a JSON backup. Every field is optional when reading, since
+/// older Desktop versions wrote fewer fields.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub(crate) struct BackupNode {
+    pub guid: Option<String>,
+    pub title: String,
+    pub index: u32,
+    /// In microseconds.
+    pub date_added: u64,
+    /// In microseconds.
+    pub last_modified: u64,
+    /// 1 for bookmarks, 2 for folders, and 3 for separators.
+    pub type_code: Option<u8>,
+    #[serde(rename = "type")]
+    pub node_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uri: Option<String>,
+    #[serde(rename = "iconuri", skip_serializing_if = "Option::is_none")]
+    pub icon_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyword: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tags: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<BackupNode>,
+}
+
+/// Returns the name Desktop uses for `root` in backups.
+pub(crate) fn root_name(root: BookmarkRootGuid) -> &'static str {
+    match root {
+        BookmarkRootGuid::Root => "placesRoot",
+        BookmarkRootGuid::Menu => "bookmarksMenuFolder",
+        BookmarkRootGuid::Toolbar => "toolbarFolder",
+        BookmarkRootGuid::Unfiled => "unfiledBookmarksFolder",
+        BookmarkRootGuid::Mobile => "mobileFolder",
+    }
+}
+
+/// Returns the name of a backup made at `when`, like
+/// `bookmarks-2019-12-31.json`. Desktop uses the same prefix and date
+/// format, so tools that look for backups by name find ours.
+pub fn backup_file_name(when: Timestamp) -> String {
+    // Convert days since the Unix epoch to a date in the proleptic
+    // Gregorian calendar, from http://howardhinnant.github.io/date_algorithms.html.
+    let days = (when.as_millis() / (24 * 60 * 60 * 1000)) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("bookmarks-{:04}-{:02}-{:02}.json", year, month, day)
+}
+
+/// Backs up every bookmark to the JSON file at `path`, replacing it if it
+/// exists. Returns the number of backed up bookmarks, folders and
+/// separators, not including the roots.
+pub fn backup_bookmarks_json(db: &PlacesDb, path: impl AsRef<Path>) -> Result<usize> {
+    let (root, num_backed_up) = fetch_backup_tree(db)?;
+    let mut out = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut out, &root)?;
+    out.flush()?;
+    log::info!("Backed up {} bookmarks to JSON", num_backed_up);
+    Ok(num_backed_up)
+}
+
+fn fetch_backup_tree(db: &PlacesDb) -> Result<(BackupNode, usize)> {
+    let root = match fetch_tree(db, BookmarkRootGuid::Root.guid(), &FetchDepth::Deepest)? {
+        Some((root, _, _)) => root,
+        None => {
+            return Err(InvalidPlaceInfo::NoSuchGuid(BookmarkRootGuid::Root.as_str().into()).into())
+        }
+    };
+    let mut num_nodes = 0;
+    let root = to_backup_node(db, &root, 0, &mut num_nodes)?;
+    // `num_nodes` includes the roots, which always exist.
+    let num_roots = 1 + root.children.len();
+    Ok((root, num_nodes - num_roots))
+}
+
+fn to_backup_node(
+    db: &PlacesDb,
+    node: &BookmarkTreeNode,
+    index: u32,
+    num_nodes: &mut usize,
+) -> Result<BackupNode> {
+    *num_nodes += 1;
+    let (date_added, last_modified) = node.created_modified();
+    let mut backup_node = BackupNode {
+        guid: Some(node.guid().as_str().to_owned()),
+        index,
+        date_added: date_added.as_millis() * 1000,
+        last_modified: last_modified.as_millis() * 1000,
+        type_code: Some(node.node_type() as u8),
+        ..BackupNode::default()
+    };
+    match node {
+        BookmarkTreeNode::Bookmark(bookmark) => {
+            backup_node.title = bookmark.title.clone().unwrap_or_default();
+            backup_node.node_type = TYPE_BOOKMARK.into();
+            backup_node.uri = Some(bookmark.url.as_str().to_owned());
+            backup_node.icon_uri = favicons::get_icon_url_for_page(db, &bookmark.url, 16)?
+                .map(|icon_url| icon_url.into_string());
+            backup_node.keyword = keywords::get_keyword_for_url(db, &bookmark.url)?;
+            let mut tags = tags::get_tags_for_url(db, &bookmark.url)?;
+            tags.sort();
+            if !tags.is_empty() {
+                backup_node.tags = Some(tags.join(","));
+            }
+        }
+        BookmarkTreeNode::Separator(_) => {
+            backup_node.node_type = TYPE_SEPARATOR.into();
+        }
+        BookmarkTreeNode::Folder(folder) => {
+            backup_node.title = folder.title.clone().unwrap_or_default();
+            backup_node.node_type = TYPE_FOLDER.into();
+            backup_node.root = BookmarkRootGuid::from_guid(node.guid())
+                .map(root_name)
+                .map(String::from);
+            for (index, child) in folder.children.iter().enumerate() {
+                backup_node
+                    .children
+                    .push(to_backup_node(db, child, index as u32, num_nodes)?);
+            }
+        }
+    }
+    Ok(backup_node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::tests::insert_json_tree;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use url::Url;
+
+    #[test]
+    fn test_backup_file_name() {
+        assert_eq!(backup_file_name(Timestamp(0)), "bookmarks-1970-01-01.json");
+        // 2020-02-29T23:59:59.999Z
+        assert_eq!(
+            backup_file_name(Timestamp(1_583_020_799_999)),
+            "bookmarks-2020-02-29.json"
+        );
+        assert_eq!(
+            backup_file_name(Timestamp(1_583_020_800_000)),
+            "bookmarks-2020-03-01.json"
+        );
+    }
+
+    #[test]
+    fn test_backup_bookmarks_json() -> Result<()> {
+        let conn = new_mem_connection();
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": BookmarkRootGuid::Toolbar.as_guid(),
+                "children": [{
+                    "guid": "folder1_____",
+                    "title": "Recipes",
+                    "date_added": 1_500_000_000_000u64,
+                    "last_modified": 1_500_000_000_000u64,
+                    "children": [{
+                        "guid": "bookmark1___",
+                        "title": "Donuts",
+                        "url": "https://example.com/donuts",
+                        "date_added": 1_500_000_010_000u64,
+                        "last_modified": 1_500_000_010_000u64,
+                    }],
+                }, {
+                    "guid": "separator1__",
+                    "type": 3,
+                    "date_added": 1_500_000_020_000u64,
+                    "last_modified": 1_500_000_020_000u64,
+                }],
+            }),
+        );
+        let donuts = Url::parse("https://example.com/donuts")?;
+        keywords::set_keyword(&conn, &donuts, "donut")?;
+        tags::tag_url(&conn, &donuts, "sweet")?;
+        tags::tag_url(&conn, &donuts, "food")?;
+
+        let (root, num_backed_up) = fetch_backup_tree(&conn)?;
+        assert_eq!(num_backed_up, 3);
+        let mut json = serde_json::to_value(&root)?;
+        // The roots' dates are when the database was created.
+        let roots = json["children"].as_array_mut().unwrap();
+        let toolbar = roots.remove(1);
+        assert_eq!(
+            roots
+                .iter()
+                .map(|root| root["root"].as_str().unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                "bookmarksMenuFolder",
+                "unfiledBookmarksFolder",
+                "mobileFolder"
+            ]
+        );
+        assert_eq!(toolbar["guid"], "toolbar_____");
+        assert_eq!(toolbar["root"], "toolbarFolder");
+        assert_eq!(toolbar["typeCode"], 2);
+        assert_eq!(
+            toolbar["children"],
+            json!([{
+                "guid": "folder1_____",
+                "title": "Recipes",
+                "index": 0,
+                "dateAdded": 1_500_000_000_000_000u64,
+                "lastModified": 1_500_000_000_000_000u64,
+                "typeCode": 2,
+                "type": "text/x-moz-place-container",
+                "children": [{
+                    "guid": "bookmark1___",
+                    "title": "Donuts",
+                    "index": 0,
+                    "dateAdded": 1_500_000_010_000_000u64,
+                    "lastModified": 1_500_000_010_000_000u64,
+                    "typeCode": 1,
+                    "type": "text/x-moz-place",
+                    "uri": "https://example.com/donuts",
+                    "keyword": "donut",
+                    "tags": "food,sweet",
+                }],
+            }, {
+                "guid": "separator1__",
+                "title": "",
+                "index": 1,
+                "dateAdded": 1_500_000_020_000_000u64,
+                "lastModified": 1_500_000_020_000_000u64,
+                "typeCode": 3,
+                "type": "text/x-moz-place-separator",
+            }])
+        );
+        Ok(())
+    }
+}
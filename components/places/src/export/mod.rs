@@ -0,0 +1,8 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+pub mod html_bookmarks;
+pub use html_bookmarks::export_bookmarks_html;
+pub mod json_bookmarks;
+pub use json_bookmarks::backup_bookmarks_json;
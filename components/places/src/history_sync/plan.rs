@@ -7,6 +7,7 @@ use super::{HISTORY_TTL, MAX_OUTGOING_PLACES, MAX_VISITS};
 use crate::api::history::can_add_url;
 use crate::db::PlacesDb;
 use crate::error::*;
+use crate::observer::PlacesEvent;
 use crate::storage::history::history_sync::{
     apply_synced_deletion, apply_synced_reconciliation, apply_synced_visits, fetch_outgoing,
     fetch_visits, finish_incoming, finish_outgoing, FetchedVisit, FetchedVisitPage, OutgoingInfo,
@@ -17,7 +18,7 @@ use serde_json;
 use std::collections::HashSet;
 use std::time::{SystemTime, UNIX_EPOCH};
 use sync15::telemetry;
-use sync15::{IncomingChangeset, OutgoingChangeset, Payload};
+use sync15::{IncomingChangeset, OutgoingChangeset, Payload, ServerTimestamp};
 use sync_guid::Guid as SyncGuid;
 use url::Url;
 
@@ -176,6 +177,21 @@ pub fn apply_plan(
     telem: &mut telemetry::EngineIncoming,
     interruptee: &impl Interruptee,
 ) -> Result<OutgoingChangeset> {
+    let timestamp = inbound.timestamp;
+    apply_incoming(db, inbound, telem, interruptee)?;
+    let outgoing = get_outgoing(db, timestamp)?;
+    log::info!("incoming: {}", serde_json::to_string(&telem).unwrap());
+    Ok(outgoing)
+}
+
+/// Plans and applies the incoming records, in a single transaction. This is
+/// also used to apply each chunk of a streamed sync.
+pub fn apply_incoming(
+    db: &PlacesDb,
+    inbound: IncomingChangeset,
+    telem: &mut telemetry::EngineIncoming,
+    interruptee: &impl Interruptee,
+) -> Result<()> {
     // for a first-cut, let's do this in the most naive way possible...
     let mut plans: Vec<(SyncGuid, IncomingPlan)> = Vec::with_capacity(inbound.changes.len());
     for incoming in inbound.changes {
@@ -198,9 +214,8 @@ pub fn apply_plan(
         plans.push((guid, plan));
     }
 
+    let mut changed_locally = false;
     let mut tx = db.begin_transaction()?;
-
-    let mut outgoing = OutgoingChangeset::new("history".into(), inbound.timestamp);
     for (guid, plan) in plans {
         interruptee.err_if_interrupted()?;
         tx.maybe_commit()?;
@@ -225,6 +240,7 @@ pub fn apply_plan(
                 log::trace!("incoming: deleting {:?}", guid);
                 apply_synced_deletion(&db, &guid)?;
                 telem.applied(1);
+                changed_locally = true;
             }
             IncomingPlan::Apply {
                 url,
@@ -240,6 +256,7 @@ pub fn apply_plan(
                 );
                 apply_synced_visits(&db, &guid, &url, new_title, visits)?;
                 telem.applied(1);
+                changed_locally = true;
             }
             IncomingPlan::Reconciled => {
                 telem.reconciled(1);
@@ -249,7 +266,16 @@ pub fn apply_plan(
         };
     }
     finish_incoming(&db)?;
+    if changed_locally {
+        db.notify(PlacesEvent::HistoryChanged);
+    }
     tx.commit()?;
+    Ok(())
+}
+
+/// Builds the outgoing changeset from the local changes.
+pub fn get_outgoing(db: &PlacesDb, timestamp: ServerTimestamp) -> Result<OutgoingChangeset> {
+    let mut outgoing = OutgoingChangeset::new("history".into(), timestamp);
     // It might make sense for fetch_outgoing to manage its own
     // begin_transaction - even though doesn't seem a large bottleneck
     // at this time, the fact we hold a single transaction for the entire call
@@ -268,8 +294,6 @@ pub fn apply_plan(
         outgoing.changes.push(payload);
     }
     tx.commit()?;
-
-    log::info!("incoming: {}", serde_json::to_string(&telem).unwrap());
     Ok(outgoing)
 }
 
@@ -780,6 +804,7 @@ mod tests {
             SearchParams {
                 search_string: "http://example.com".into(),
                 limit: 2,
+                ..SearchParams::default()
             },
         )?;
         assert_eq!(found.len(), 1);
@@ -791,6 +816,40 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_apply_incoming_chunks() -> Result<()> {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+        let now: Timestamp = SystemTime::now().into();
+        let mut telem = telemetry::EngineIncoming::new();
+        for (guid, url) in &[
+            ("aaaaaaaaaaaa", "http://example.com/a"),
+            ("bbbbbbbbbbbb", "http://example.com/b"),
+        ] {
+            let json = json!({
+                "id": guid,
+                "title": "title",
+                "histUri": url,
+                "sortindex": 0,
+                "ttl": 100,
+                "visits": [ {"date": ServerVisitTimestamp::from(now), "type": 1}]
+            });
+            let mut chunk = IncomingChangeset::new("history".to_string(), ServerTimestamp(0i64));
+            chunk
+                .changes
+                .push((Payload::from_json(json).unwrap(), ServerTimestamp(0i64)));
+            apply_incoming(&db, chunk, &mut telem, &NeverInterrupts)?;
+        }
+        assert_eq!(telem.get_applied(), 2);
+
+        for url in &["http://example.com/a", "http://example.com/b"] {
+            let (_page, visits) = fetch_visits(&db, &Url::parse(url)?, 2)?.expect("page exists");
+            assert_eq!(visits.len(), 1);
+        }
+        assert_eq!(get_outgoing(&db, ServerTimestamp(0i64))?.changes.len(), 0);
+        Ok(())
+    }
+
     #[test]
     fn test_apply_plan_outgoing_new() -> Result<()> {
         let _ = env_logger::try_init();
@@ -5,6 +5,7 @@
 use super::ServerVisitTimestamp;
 use crate::error::*;
 use serde_derive::*;
+use sync15::MaybeTombstone;
 use sync_guid::Guid as SyncGuid;
 
 #[derive(Debug, Clone, Hash, PartialEq, Serialize, Deserialize, Default)]
@@ -49,12 +50,7 @@ pub struct HistorySyncRecord {
 impl HistorySyncRecord {
     pub fn from_payload(payload: sync15::Payload) -> Result<Self> {
         let guid = payload.id.clone();
-        let record: Option<HistoryRecord> = if payload.is_tombstone() {
-            None
-        } else {
-            let record: HistoryRecord = payload.into_record()?;
-            Some(record)
-        };
+        let record = MaybeTombstone::<HistoryRecord>::from_payload(payload)?.into_record();
         Ok(Self { guid, record })
     }
 }
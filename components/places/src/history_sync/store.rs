@@ -18,14 +18,14 @@ use sync15::{
 };
 use sync_guid::Guid;
 
-use super::plan::{apply_plan, finish_plan};
-use super::MAX_INCOMING_PLACES;
+use super::plan::{self, apply_plan, finish_plan};
+use super::{INCOMING_PAGE_SIZE, MAX_INCOMING_PLACES};
 
-const LAST_SYNC_META_KEY: &str = "history_last_sync_time";
+pub(crate) const LAST_SYNC_META_KEY: &str = "history_last_sync_time";
 // Note that all engines in this crate should use a *different* meta key
 // for the global sync ID, because engines are reset individually.
-const GLOBAL_SYNCID_META_KEY: &str = "history_global_sync_id";
-const COLLECTION_SYNCID_META_KEY: &str = "history_sync_id";
+pub(crate) const GLOBAL_SYNCID_META_KEY: &str = "history_global_sync_id";
+pub(crate) const COLLECTION_SYNCID_META_KEY: &str = "history_sync_id";
 
 // A HistoryStore is short-lived and constructed each sync by something which
 // owns the connection and ClientInfo.
@@ -70,6 +70,14 @@ impl<'a> HistoryStore<'a> {
         Ok(outgoing)
     }
 
+    fn do_finish_incoming(&self, timestamp: ServerTimestamp) -> Result<OutgoingChangeset> {
+        // As in `do_apply_incoming`, we write the timestamp before building
+        // the outgoing changeset. We only get here once every chunk has been
+        // applied, so there's nothing left to fetch.
+        self.put_meta(LAST_SYNC_META_KEY, &(timestamp.as_millis() as i64))?;
+        plan::get_outgoing(&self.db, timestamp)
+    }
+
     fn do_sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
@@ -158,6 +166,27 @@ impl<'a> Store for HistoryStore<'a> {
         Ok(self.do_apply_incoming(inbound, telem)?)
     }
 
+    fn applies_incoming_in_chunks(&self) -> bool {
+        true
+    }
+
+    fn apply_incoming_chunk(
+        &self,
+        chunk: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> result::Result<(), failure::Error> {
+        plan::apply_incoming(&self.db, chunk, telem, self.interruptee)?;
+        Ok(())
+    }
+
+    fn finish_incoming(
+        &self,
+        timestamp: ServerTimestamp,
+        _telem: &mut telemetry::Engine,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        Ok(self.do_finish_incoming(timestamp)?)
+    }
+
     fn sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
@@ -174,7 +203,8 @@ impl<'a> Store for HistoryStore<'a> {
         Ok(CollectionRequest::new("history")
             .full()
             .newer_than(ServerTimestamp(since))
-            .limit(MAX_INCOMING_PLACES))
+            .limit(MAX_INCOMING_PLACES)
+            .page_size(INCOMING_PAGE_SIZE))
     }
 
     fn get_sync_assoc(&self) -> result::Result<StoreSyncAssociation, failure::Error> {
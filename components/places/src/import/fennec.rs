@@ -4,5 +4,8 @@
 
 mod bookmarks;
 mod history;
+mod pinned_sites;
 pub use bookmarks::import as import_bookmarks;
+pub use bookmarks::BookmarksMigrationResult;
 pub use history::import as import_history;
+pub use pinned_sites::import as import_pinned_sites;
@@ -9,19 +9,51 @@ use crate::bookmark_sync::{
 };
 use crate::error::*;
 use crate::import::common::{attached_database, ExecuteOnDrop};
+use crate::observer::PlacesEvent;
+use crate::storage::bookmarks::BookmarkRootGuid;
 use crate::types::SyncStatus;
+use serde_derive::*;
 use sql_support::ConnExt;
+use std::time::Instant;
 use url::Url;
 
 // From https://searchfox.org/mozilla-central/rev/597a69c70a5cce6f42f159eb54ad1ef6745f5432/mobile/android/base/java/org/mozilla/gecko/db/BrowserDatabaseHelper.java#73.
 const FENNEC_DB_VERSION: i64 = 39;
 
-pub fn import(places_api: &PlacesApi, path: impl AsRef<std::path::Path>) -> Result<()> {
+/// Counts for migration telemetry, returned from `import`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookmarksMigrationResult {
+    /// The number of Fennec bookmarks, folders and separators we tried to
+    /// migrate. This doesn't include the roots, pinned sites, deleted items,
+    /// or anything in the tags folder.
+    pub num_total: u32,
+    pub num_succeeded: u32,
+    /// Items we couldn't migrate, usually because they have invalid URLs.
+    pub num_failed: u32,
+    /// In milliseconds.
+    pub total_duration: u64,
+}
+
+/// Imports the bookmarks tree from the Fennec `browser.db` at `path`,
+/// keeping Fennec's GUIDs and the order of each folder's children.
+///
+/// Fennec keeps a few things in its bookmarks table that aren't bookmarks:
+/// pinned top sites live in the `pinned` folder, and the `tags` folder is
+/// unused, since Fennec stores tags in a column. We skip both folders and
+/// their contents; `import_pinned_sites` imports the pinned sites. Any other folder directly under the Fennec root, like
+/// the one that holds migrated reading list items, ends up at the end of
+/// the mobile root, since places doesn't allow custom roots.
+pub fn import(
+    places_api: &PlacesApi,
+    path: impl AsRef<std::path::Path>,
+) -> Result<BookmarksMigrationResult> {
     let url = crate::util::ensure_url_path(path)?;
     do_import(places_api, url)
 }
 
-fn do_import(places_api: &PlacesApi, fennec_db_file_url: Url) -> Result<()> {
+fn do_import(places_api: &PlacesApi, fennec_db_file_url: Url) -> Result<BookmarksMigrationResult> {
+    let import_start = Instant::now();
     let conn = places_api.open_sync_connection()?;
 
     let scope = conn.begin_interrupt_scope();
@@ -62,6 +94,10 @@ fn do_import(places_api: &PlacesApi, fennec_db_file_url: Url) -> Result<()> {
     conn.execute_batch(&POPULATE_STAGING)?;
     scope.err_if_interrupted()?;
 
+    log::debug!("Moving custom roots into the mobile root");
+    conn.execute_batch(&REPARENT_CUSTOM_ROOTS)?;
+    scope.err_if_interrupted()?;
+
     log::debug!("Populating missing entries in moz_places");
     conn.execute_batch(&FILL_MOZ_PLACES)?;
     scope.err_if_interrupted()?;
@@ -95,8 +131,13 @@ fn do_import(places_api: &PlacesApi, fennec_db_file_url: Url) -> Result<()> {
     log::debug!("Fixing up bookmarks");
     conn.execute_batch(&FIXUP_MOZ_BOOKMARKS)?;
     scope.err_if_interrupted()?;
+
+    let num_total = conn.db.query_one::<u32>(&COUNT_FENNEC_BOOKMARKS)?;
+    let num_succeeded = conn.db.query_one::<u32>(&COUNT_IMPORTED_BOOKMARKS)?;
+
     log::debug!("Cleaning up mirror...");
     clear_mirror_on_drop.execute_now()?;
+    conn.notify(PlacesEvent::BookmarksChanged);
     log::debug!("Committing...");
     tx.commit()?;
 
@@ -105,11 +146,16 @@ fn do_import(places_api: &PlacesApi, fennec_db_file_url: Url) -> Result<()> {
     log::debug!("Updating frecencies");
     store.update_frecencies()?;
 
-    log::info!("Successfully imported bookmarks!");
-
     auto_detach.execute_now()?;
 
-    Ok(())
+    let result = BookmarksMigrationResult {
+        num_total,
+        num_succeeded,
+        num_failed: num_total.saturating_sub(num_succeeded),
+        total_duration: import_start.elapsed().as_millis() as u64,
+    };
+    log::info!("Successfully imported bookmarks: {:?}", result);
+    Ok(result)
 }
 
 #[derive(Clone, Copy, PartialEq, PartialOrd, Hash, Debug, Eq, Ord)]
@@ -188,6 +234,21 @@ lazy_static::lazy_static! {
     );
 }
 
+const ROOT_GUIDS: &str =
+    "'root________', 'menu________', 'toolbar_____', 'unfiled_____', 'mobile______'";
+
+// The ids of Fennec's pinned sites and tags folders, and everything in them.
+// Fennec's root is its own parent, so we make sure not to follow it.
+const SKIPPED_FENNEC_IDS: &str = "
+    WITH RECURSIVE skipped(id) AS (
+        SELECT _id FROM fennec.bookmarks WHERE guid IN ('pinned', 'tags')
+        UNION
+        SELECT b._id FROM fennec.bookmarks b
+        JOIN skipped ON b.parent = skipped.id
+        WHERE b._id <> b.parent
+    )
+    SELECT id FROM skipped";
+
 const WIPE_MIRROR: &str = "DELETE FROM main.moz_bookmarks_synced;
  DELETE FROM main.moz_bookmarks_synced_structure;";
 
@@ -197,7 +258,7 @@ REPLACE INTO main.moz_bookmarks_synced_structure(guid, parentGuid, position)
 ";
 
 lazy_static::lazy_static! {
-    static ref POPULATE_STAGING: &'static str =
+    static ref POPULATE_STAGING: String = format!(
         "INSERT OR IGNORE INTO temp.fennecBookmarksStaging(
             guid,
             type,
@@ -215,7 +276,14 @@ lazy_static::lazy_static! {
             normalize_root_guid(b.guid),
             b.type,
             (SELECT normalize_root_guid(p.guid) FROM fennec.bookmarks p WHERE p._id = b.parent),
-            b.position,
+            -- Fennec's positions may have gaps, duplicates, and huge
+            -- negative numbers, so we use the rank among siblings instead,
+            -- breaking ties the way Fennec does, by id.
+            (SELECT COUNT(*) FROM fennec.bookmarks s
+             WHERE s.parent = b.parent
+               AND s._id <> s.parent
+               AND (s.position < b.position OR
+                    (s.position = b.position AND s._id < b._id))),
             b.title,
             CASE
                 WHEN b.url IS NOT NULL
@@ -229,8 +297,45 @@ lazy_static::lazy_static! {
             1
         FROM fennec.bookmarks b
         WHERE NOT b.deleted
-        ;
-    ";
+          AND b._id NOT IN ({skipped_ids})
+        ;",
+        skipped_ids = SKIPPED_FENNEC_IDS,
+    );
+
+    // Appends folders that Fennec keeps directly under its root, but that
+    // aren't one of our roots, to the mobile root. Their ranks among the
+    // root's children are unique, so adding them to the end of the mobile
+    // root keeps their order.
+    static ref REPARENT_CUSTOM_ROOTS: String = format!(
+        "UPDATE temp.fennecBookmarksStaging SET
+           pos = pos + 1 + (SELECT IFNULL(MAX(s.pos), -1)
+                            FROM temp.fennecBookmarksStaging s
+                            WHERE s.parent_guid = '{mobile}'),
+           parent_guid = '{mobile}'
+         WHERE parent_guid = '{root}'
+           AND guid NOT IN ({roots})",
+        mobile = BookmarkRootGuid::Mobile.as_str(),
+        root = BookmarkRootGuid::Root.as_str(),
+        roots = ROOT_GUIDS,
+    );
+
+    static ref COUNT_FENNEC_BOOKMARKS: String = format!(
+        "SELECT COUNT(*) FROM fennec.bookmarks b
+         WHERE NOT b.deleted
+           AND normalize_root_guid(b.guid) NOT IN ({roots})
+           AND b._id NOT IN ({skipped_ids})",
+        roots = ROOT_GUIDS,
+        skipped_ids = SKIPPED_FENNEC_IDS,
+    );
+
+    // Counts staged items that made it into `moz_bookmarks`. Staging drops
+    // items with invalid URLs, so those don't count.
+    static ref COUNT_IMPORTED_BOOKMARKS: String = format!(
+        "SELECT COUNT(*) FROM temp.fennecBookmarksStaging stage
+         JOIN main.moz_bookmarks b ON b.guid = stage.guid
+         WHERE stage.guid NOT IN ({roots})",
+        roots = ROOT_GUIDS,
+    );
 
     static ref CREATE_STAGING_TABLE: String = format!("
         CREATE TEMP TABLE temp.fennecBookmarksStaging(
@@ -4,10 +4,15 @@
 
 use crate::api::places_api::PlacesApi;
 use crate::bookmark_sync::store::BookmarksStore;
+use crate::db::{PlacesDb, PlacesTransaction};
 use crate::error::*;
 use crate::import::common::attached_database;
-use rusqlite::Connection;
-use sql_support::ConnExt;
+use crate::observer::PlacesEvent;
+use crate::storage::{delete_meta, get_meta, put_meta};
+use crate::types::VisitTransition;
+use rusqlite::{functions::Context, Connection};
+use sql_support::{ConnExt, SqlInterruptScope};
+use std::convert::TryFrom;
 use url::Url;
 
 // From https://searchfox.org/mozilla-central/rev/597a69c70a5cce6f42f159eb54ad1ef6745f5432/mobile/android/base/java/org/mozilla/gecko/db/BrowserDatabaseHelper.java#73.
@@ -18,6 +23,15 @@ pub fn import(places_api: &PlacesApi, path: impl AsRef<std::path::Path>) -> Resu
     do_import(places_api, url)
 }
 
+// Some users have hundreds of thousands of visits, so we import pages and
+// visits this many at a time, and remember the last one we imported in
+// `moz_meta`. Chunks are committed every so often by the sync connection's
+// transaction, so if we're interrupted, or the app is killed, the next call
+// to `import` picks up after the last committed chunk.
+const IMPORT_CHUNK_SIZE: i64 = 5000;
+const LAST_IMPORTED_PAGE_ID_META_KEY: &str = "fennec_history_import_last_page_id";
+const LAST_IMPORTED_VISIT_ID_META_KEY: &str = "fennec_history_import_last_visit_id";
+
 fn do_import(places_api: &PlacesApi, android_db_file_url: Url) -> Result<()> {
     let conn = places_api.open_sync_connection()?;
 
@@ -38,16 +52,33 @@ fn do_import(places_api: &PlacesApi, android_db_file_url: Url) -> Result<()> {
         return Err(ErrorKind::UnsupportedDatabaseVersion(db_version).into());
     }
 
-    let tx = conn.begin_transaction()?;
+    let mut tx = conn.begin_transaction()?;
 
     log::debug!("Populating missing entries in moz_places");
-    conn.execute_batch(&FILL_MOZ_PLACES)?;
-    scope.err_if_interrupted()?;
+    import_in_chunks(
+        &conn,
+        &scope,
+        &mut tx,
+        "fennec.history",
+        LAST_IMPORTED_PAGE_ID_META_KEY,
+        &FILL_MOZ_PLACES,
+    )?;
 
     log::debug!("Inserting the history visits");
-    conn.execute_batch(&INSERT_HISTORY_VISITS)?;
-    scope.err_if_interrupted()?;
+    import_in_chunks(
+        &conn,
+        &scope,
+        &mut tx,
+        "fennec.visits",
+        LAST_IMPORTED_VISIT_ID_META_KEY,
+        &INSERT_HISTORY_VISITS,
+    )?;
 
+    // We're done, so the next import should start from the beginning.
+    delete_meta(&conn, LAST_IMPORTED_PAGE_ID_META_KEY)?;
+    delete_meta(&conn, LAST_IMPORTED_VISIT_ID_META_KEY)?;
+
+    conn.notify(PlacesEvent::HistoryChanged);
     log::debug!("Committing...");
     tx.commit()?;
 
@@ -64,6 +95,48 @@ fn do_import(places_api: &PlacesApi, android_db_file_url: Url) -> Result<()> {
     Ok(())
 }
 
+/// Runs `sql` for each chunk of rows in the Fennec `table`, in `_id` order,
+/// starting after the last chunk we imported. `sql` should only import the
+/// rows with `_id > :after_id AND _id <= :through_id`.
+fn import_in_chunks(
+    conn: &PlacesDb,
+    scope: &SqlInterruptScope,
+    tx: &mut PlacesTransaction<'_>,
+    table: &str,
+    meta_key: &str,
+    sql: &str,
+) -> Result<()> {
+    let mut after_id = get_meta::<i64>(conn, meta_key)?.unwrap_or(i64::min_value());
+    let next_chunk_sql = format!(
+        "SELECT MAX(_id) FROM (SELECT _id FROM {table}
+                               WHERE _id > :after_id
+                               ORDER BY _id
+                               LIMIT {chunk_size})",
+        table = table,
+        chunk_size = IMPORT_CHUNK_SIZE,
+    );
+    loop {
+        let through_id =
+            conn.query_row_named(&next_chunk_sql, &[(":after_id", &after_id)], |row| {
+                row.get::<_, Option<i64>>(0)
+            })?;
+        let through_id = match through_id {
+            Some(id) => id,
+            None => break,
+        };
+        conn.execute_named(
+            sql,
+            &[(":after_id", &after_id), (":through_id", &through_id)],
+        )?;
+        put_meta(conn, meta_key, &through_id)?;
+        log::trace!("Imported {} through {}", table, through_id);
+        tx.maybe_commit()?;
+        scope.err_if_interrupted()?;
+        after_id = through_id;
+    }
+    Ok(())
+}
+
 lazy_static::lazy_static! {
     // Insert any missing entries into moz_places that we'll need for this.
     static ref FILL_MOZ_PLACES: &'static str =
@@ -79,21 +152,34 @@ lazy_static::lazy_static! {
                 -1,
                 1
             FROM fennec.history h
-            WHERE is_valid_url(h.url)"
+            WHERE h._id > :after_id AND h._id <= :through_id
+              AND is_valid_url(h.url)"
     ;
 
-    // Insert history visits
+    // Insert history visits. We skip visits that we already have, so that
+    // importing the same database twice doesn't duplicate them.
     static ref INSERT_HISTORY_VISITS: &'static str =
         "INSERT OR IGNORE INTO main.moz_historyvisits(from_visit, place_id, visit_date, visit_type, is_local)
             SELECT
                 NULL, -- Fenec does not store enough information to rebuild redirect chains.
-                (SELECT p.id FROM main.moz_places p WHERE p.url_hash = hash(h.url) AND p.url = h.url),
-                sanitize_timestamp(v.date),
-                v.visit_type, -- Fennec stores visit types maps 1:1 to ours.
+                p.id,
+                v.visit_date,
+                v.visit_type,
                 v.is_local
-            FROM fennec.visits v
-            LEFT JOIN fennec.history h on v.history_guid = h.guid
-            WHERE is_valid_url(h.url)"
+            FROM (SELECT
+                      h.url,
+                      sanitize_timestamp(v.date) AS visit_date,
+                      to_visit_transition(v.visit_type) AS visit_type,
+                      v.is_local
+                  FROM fennec.visits v
+                  JOIN fennec.history h ON v.history_guid = h.guid
+                  WHERE v._id > :after_id AND v._id <= :through_id
+                    AND is_valid_url(h.url)) v
+            JOIN main.moz_places p ON p.url_hash = hash(v.url) AND p.url = v.url
+            WHERE v.visit_type IS NOT NULL
+              AND NOT EXISTS(SELECT 1 FROM main.moz_historyvisits e
+                             WHERE e.place_id = p.id
+                               AND e.visit_date = v.visit_date)"
     ;
 }
 
@@ -110,6 +196,7 @@ pub(super) fn define_sql_functions(c: &Connection) -> Result<()> {
         true,
         crate::import::common::sql_fns::sanitize_timestamp,
     )?;
+    c.create_scalar_function("to_visit_transition", 1, true, to_visit_transition)?;
     c.create_scalar_function("hash", -1, true, crate::db::db::sql_fns::hash)?;
     c.create_scalar_function(
         "generate_guid",
@@ -119,3 +206,18 @@ pub(super) fn define_sql_functions(c: &Connection) -> Result<()> {
     )?;
     Ok(())
 }
+
+// Fennec's visit types are the same as ours, but Fennec didn't validate the
+// types of visits that it synced, so we treat unknown types as links. We
+// don't store embed visits, so we return `None` to skip those.
+fn to_visit_transition(ctx: &Context<'_>) -> rusqlite::Result<Option<u8>> {
+    let visit_type = ctx.get::<Option<i64>>(0)?;
+    let transition = visit_type
+        .and_then(|t| u8::try_from(t).ok())
+        .and_then(VisitTransition::from_primitive)
+        .unwrap_or(VisitTransition::Link);
+    Ok(match transition {
+        VisitTransition::Embed => None,
+        transition => Some(transition as u8),
+    })
+}
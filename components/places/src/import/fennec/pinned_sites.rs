@@ -0,0 +1,86 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::api::places_api::PlacesApi;
+use crate::error::*;
+use crate::import::common::{attached_database, sql_fns};
+use crate::storage::pinned_sites::pin_site_in_tx;
+use crate::types::Timestamp;
+use sql_support::ConnExt;
+use url::Url;
+
+// From https://searchfox.org/mozilla-central/rev/597a69c70a5cce6f42f159eb54ad1ef6745f5432/mobile/android/base/java/org/mozilla/gecko/db/BrowserDatabaseHelper.java#73.
+const FENNEC_DB_VERSION: i64 = 39;
+
+/// Imports the pinned top sites from the Fennec `browser.db` at `path`, and
+/// returns how many were imported. Fennec stores these as bookmarks in its
+/// `pinned` folder, with their slots in the grid as their positions, so
+/// `import_bookmarks` skips them. Sites that we import replace any sites that
+/// are already pinned to the same slots.
+pub fn import(places_api: &PlacesApi, path: impl AsRef<std::path::Path>) -> Result<usize> {
+    let url = crate::util::ensure_url_path(path)?;
+    do_import(places_api, url)
+}
+
+fn do_import(places_api: &PlacesApi, fennec_db_file_url: Url) -> Result<usize> {
+    let conn = places_api.open_sync_connection()?;
+
+    conn.create_scalar_function("validate_url", 1, true, sql_fns::validate_url)?;
+    conn.create_scalar_function("sanitize_timestamp", 1, true, sql_fns::sanitize_timestamp)?;
+
+    log::trace!("Attaching database {}", fennec_db_file_url);
+    let auto_detach = attached_database(&conn, &fennec_db_file_url, "fennec")?;
+
+    let db_version = conn.db.query_one::<i64>("PRAGMA fennec.user_version")?;
+    if db_version != FENNEC_DB_VERSION {
+        return Err(ErrorKind::UnsupportedDatabaseVersion(db_version).into());
+    }
+
+    // Fennec's positions can be negative, which we skip, and duplicated, in
+    // which case the site with the highest id wins.
+    let sites = conn.query_rows_and_then_named(
+        "SELECT validate_url(b.url) AS url, b.title, b.position,
+                sanitize_timestamp(b.created) AS created
+         FROM fennec.bookmarks b
+         JOIN fennec.bookmarks p ON p._id = b.parent
+         WHERE p.guid = 'pinned'
+           AND NOT b.deleted
+           AND b.position >= 0
+         ORDER BY b.position, b._id",
+        &[],
+        |row| -> Result<_> {
+            Ok((
+                row.get::<_, Option<String>>("url")?,
+                row.get::<_, Option<String>>("title")?,
+                row.get::<_, u32>("position")?,
+                row.get::<_, Timestamp>("created")?,
+            ))
+        },
+    )?;
+
+    let tx = conn.begin_transaction()?;
+    let mut num_imported = 0;
+    for (url, title, position, created) in sites {
+        // `validate_url` only returns valid URLs.
+        let url = match url.as_ref().map(|url| Url::parse(url)) {
+            Some(Ok(url)) => url,
+            _ => continue,
+        };
+        pin_site_in_tx(
+            &conn,
+            &url,
+            title.as_ref().map(String::as_str),
+            position,
+            created,
+        )?;
+        num_imported += 1;
+    }
+    log::debug!("Committing...");
+    tx.commit()?;
+
+    auto_detach.execute_now()?;
+
+    log::info!("Successfully imported {} pinned sites", num_imported);
+    Ok(num_imported)
+}
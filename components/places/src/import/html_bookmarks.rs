@@ -0,0 +1,587 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Imports bookmarks from the Netscape bookmarks HTML format, which every
+//! browser can export, for users migrating from other browsers.
+//!
+//! The format isn't well-formed HTML: `<DT>`, `<DD>` and `<p>` are never
+//! closed, and some exporters leave off other end tags, too. So, instead of
+//! building a DOM, we scan the file for the tags we care about:
+//!
+//! - `<H3>` is the title of a folder, and the `<DL>` that follows it holds
+//!   the folder's children.
+//! - `<A HREF>` is a bookmark. Its icon comes from `ICON_URI`, or from
+//!   `ICON`, which is usually a `data:` URL. Livemarks, which have a
+//!   `FEEDURL`, are skipped.
+//! - `<HR>` is a separator.
+//!
+//! `ADD_DATE` and `LAST_MODIFIED` are in seconds. Everything else, including
+//! descriptions, keywords and tags, is ignored.
+//!
+//! Most exporters use UTF-8, but older ones use the system's legacy
+//! encoding, so we decode the file using the charset in its
+//! `<META HTTP-EQUIV="Content-Type">` tag.
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::observer::PlacesEvent;
+use crate::storage::bookmarks::{
+    insert_tree_in_tx, BookmarkNode, BookmarkTreeNode, FolderNode, SeparatorNode,
+};
+use crate::storage::favicons::{self, Icon};
+use crate::storage::{delete_pending_temp_tables, URL_LENGTH_MAX};
+use crate::types::Timestamp;
+use encoding_rs::{Encoding, UTF_8};
+use std::borrow::Cow;
+use std::fs;
+use std::path::Path;
+use sync_guid::Guid as SyncGuid;
+use url::Url;
+
+/// Imports the bookmarks in the HTML file at `path` into the folder with
+/// `parent_guid`, after its existing children, in a single transaction.
+/// Returns the number of imported bookmarks, folders and separators.
+pub fn import_bookmarks_html(
+    db: &PlacesDb,
+    path: impl AsRef<Path>,
+    parent_guid: &SyncGuid,
+) -> Result<usize> {
+    let html = fs::read(path)?;
+    let parsed = parse_bookmarks_html(&decode_bookmarks_html(&html), parent_guid);
+
+    let tx = db.begin_transaction()?;
+    let num_imported = insert_tree_in_tx(db, &parsed.tree)?;
+    for (page_url, icon) in &parsed.icons {
+        favicons::set_icon_for_page_in_tx(db, page_url, icon)?;
+    }
+    delete_pending_temp_tables(db)?;
+    db.notify(PlacesEvent::BookmarksChanged);
+    tx.commit()?;
+
+    log::info!("Imported {} items from bookmarks HTML", num_imported);
+    Ok(num_imported)
+}
+
+/// Decodes the bookmarks file using the encoding its byte order mark or
+/// `<META>` charset says it's in, or UTF-8 if it doesn't say. Like browsers,
+/// we only look for a charset in the first 1024 bytes, and replace malformed
+/// sequences instead of failing.
+fn decode_bookmarks_html(html: &[u8]) -> Cow<'_, str> {
+    let encoding = declared_charset(&html[..html.len().min(1024)])
+        .and_then(Encoding::for_label)
+        .unwrap_or(UTF_8);
+    let (text, actual_encoding, had_errors) = encoding.decode(html);
+    if had_errors {
+        log::warn!(
+            "Bookmarks HTML contains malformed {} sequences; replacing them",
+            actual_encoding.name()
+        );
+    }
+    text
+}
+
+/// Returns the label that follows the first `charset=` in `head`, without
+/// quotes.
+fn declared_charset(head: &[u8]) -> Option<&[u8]> {
+    const CHARSET: &[u8] = b"charset=";
+    let start = head
+        .windows(CHARSET.len())
+        .position(|window| window.eq_ignore_ascii_case(CHARSET))?
+        + CHARSET.len();
+    let label = &head[start..];
+    let label = match label.first() {
+        Some(b'"') | Some(b'\'') => &label[1..],
+        _ => label,
+    };
+    let end = label
+        .iter()
+        .position(|&b| b == b'"' || b == b'\'' || b == b';' || b == b'>' || b.is_ascii_whitespace())
+        .unwrap_or_else(|| label.len());
+    Some(&label[..end])
+}
+
+struct ParsedBookmarks {
+    tree: FolderNode,
+    icons: Vec<(Url, Icon)>,
+}
+
+fn parse_bookmarks_html(html: &str, parent_guid: &SyncGuid) -> ParsedBookmarks {
+    // The folders we're in, starting with the one we're importing into.
+    let mut folders = vec![FolderNode {
+        guid: Some(parent_guid.clone()),
+        ..FolderNode::default()
+    }];
+    // A folder whose title we've seen, but not its `<DL>` yet.
+    let mut pending_folder = None;
+    // Whether each open `<DL>` belongs to a folder. The outermost one
+    // doesn't, and neither do stray lists.
+    let mut lists = Vec::new();
+    let mut icons = Vec::new();
+
+    let mut tokens = Tokenizer::new(html);
+    while let Some(token) = tokens.next() {
+        match token {
+            Token::StartTag(name, attrs) => match name.as_str() {
+                "h3" => {
+                    add_pending_folder(&mut folders, &mut pending_folder);
+                    pending_folder = Some(FolderNode {
+                        title: tokens.text_until("h3"),
+                        date_added: timestamp_attr(&attrs, "add_date"),
+                        last_modified: timestamp_attr(&attrs, "last_modified"),
+                        ..FolderNode::default()
+                    });
+                }
+                "dl" => match pending_folder.take() {
+                    Some(folder) => {
+                        folders.push(folder);
+                        lists.push(true);
+                    }
+                    None => lists.push(false),
+                },
+                "a" => {
+                    add_pending_folder(&mut folders, &mut pending_folder);
+                    let title = tokens.text_until("a");
+                    let url = match attr(&attrs, "href").and_then(|href| Url::parse(href).ok()) {
+                        Some(url) => url,
+                        None => continue,
+                    };
+                    if url.as_str().len() > URL_LENGTH_MAX || attr(&attrs, "feedurl").is_some() {
+                        continue;
+                    }
+                    let icon_url = attr(&attrs, "icon_uri")
+                        .and_then(|icon_uri| Url::parse(icon_uri).ok())
+                        .or_else(|| attr(&attrs, "icon").and_then(|icon| Url::parse(icon).ok()));
+                    if let Some(icon_url) = icon_url {
+                        icons.push((url.clone(), Icon::new(icon_url, 0)));
+                    }
+                    add_child(
+                        &mut folders,
+                        BookmarkNode {
+                            guid: None,
+                            date_added: timestamp_attr(&attrs, "add_date"),
+                            last_modified: timestamp_attr(&attrs, "last_modified"),
+                            title,
+                            url,
+                        }
+                        .into(),
+                    );
+                }
+                "hr" => {
+                    add_pending_folder(&mut folders, &mut pending_folder);
+                    add_child(
+                        &mut folders,
+                        SeparatorNode {
+                            guid: None,
+                            date_added: None,
+                            last_modified: None,
+                        }
+                        .into(),
+                    );
+                }
+                _ => {}
+            },
+            Token::EndTag(name) => {
+                if name == "dl" {
+                    add_pending_folder(&mut folders, &mut pending_folder);
+                    if lists.pop() == Some(true) {
+                        close_folder(&mut folders);
+                    }
+                }
+            }
+            Token::Text(_) => {}
+        }
+    }
+
+    // Close any folders that are still open, in case the file was truncated.
+    add_pending_folder(&mut folders, &mut pending_folder);
+    while folders.len() > 1 {
+        close_folder(&mut folders);
+    }
+    ParsedBookmarks {
+        tree: folders.pop().unwrap(),
+        icons,
+    }
+}
+
+fn add_child(folders: &mut Vec<FolderNode>, child: BookmarkTreeNode) {
+    folders.last_mut().unwrap().children.push(child);
+}
+
+/// Adds a folder without a `<DL>` as an empty folder.
+fn add_pending_folder(folders: &mut Vec<FolderNode>, pending_folder: &mut Option<FolderNode>) {
+    if let Some(folder) = pending_folder.take() {
+        add_child(folders, folder.into());
+    }
+}
+
+fn close_folder(folders: &mut Vec<FolderNode>) {
+    if folders.len() > 1 {
+        let folder = folders.pop().unwrap();
+        add_child(folders, folder.into());
+    }
+}
+
+fn attr<'a>(attrs: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    attrs
+        .iter()
+        .find(|(attr_name, _)| attr_name == name)
+        .map(|(_, value)| value.as_str())
+}
+
+fn timestamp_attr(attrs: &[(String, String)], name: &str) -> Option<Timestamp> {
+    attr(attrs, name)
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .and_then(|seconds| seconds.checked_mul(1000))
+        .filter(|&ms| ms > 0)
+        .map(Timestamp)
+}
+
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    /// A start tag, with its lowercased name and attributes.
+    StartTag(String, Vec<(String, String)>),
+    /// An end tag, with its lowercased name.
+    EndTag(String),
+    Text(&'a str),
+}
+
+struct Tokenizer<'a> {
+    html: &'a str,
+    pos: usize,
+}
+
+impl<'a> Tokenizer<'a> {
+    fn new(html: &'a str) -> Self {
+        Self { html, pos: 0 }
+    }
+
+    /// Returns the text up to the end tag `name`, or up to the next tag
+    /// that's part of the structure, if the end tag is missing.
+    fn text_until(&mut self, name: &str) -> Option<String> {
+        let mut text = String::new();
+        loop {
+            let start = self.pos;
+            match self.next() {
+                Some(Token::Text(t)) => text.push_str(&decode_entities(t)),
+                Some(Token::EndTag(ref end_name)) if end_name == name => break,
+                Some(Token::StartTag(ref start_name, _)) | Some(Token::EndTag(ref start_name))
+                    if ["a", "dd", "dl", "dt", "h3", "hr"].contains(&start_name.as_str()) =>
+                {
+                    self.pos = start;
+                    break;
+                }
+                Some(_) => {}
+                None => break,
+            }
+        }
+        let text = text.trim();
+        if text.is_empty() {
+            None
+        } else {
+            Some(text.to_owned())
+        }
+    }
+}
+
+impl<'a> Iterator for Tokenizer<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Token<'a>> {
+        loop {
+            let rest = &self.html[self.pos..];
+            if rest.is_empty() {
+                return None;
+            }
+            if !rest.starts_with('<') {
+                let end = rest.find('<').unwrap_or_else(|| rest.len());
+                self.pos += end;
+                return Some(Token::Text(&rest[..end]));
+            }
+            if rest.starts_with("<!--") {
+                let end = rest.find("-->").map_or(rest.len(), |end| end + 3);
+                self.pos += end;
+                continue;
+            }
+
+            // Find the end of the tag, skipping over any `>`s in quoted
+            // attribute values.
+            let mut quote = None;
+            let end = rest
+                .char_indices()
+                .skip(1)
+                .find(|&(_, c)| match quote {
+                    Some(q) if c == q => {
+                        quote = None;
+                        false
+                    }
+                    Some(_) => false,
+                    None if c == '"' || c == '\'' => {
+                        quote = Some(c);
+                        false
+                    }
+                    None => c == '>',
+                })
+                .map_or(rest.len(), |(end, _)| end);
+            let tag = &rest[1..end];
+            self.pos += (end + 1).min(rest.len());
+
+            if tag.starts_with('/') {
+                let name = tag[1..].trim();
+                return Some(Token::EndTag(name.to_ascii_lowercase()));
+            }
+            if tag.starts_with('!') || tag.starts_with('?') {
+                // Doctypes and processing instructions.
+                continue;
+            }
+            let (name, attrs) = parse_tag(tag);
+            return Some(Token::StartTag(name, attrs));
+        }
+    }
+}
+
+fn is_name_end(c: char) -> bool {
+    c.is_ascii_whitespace() || c == '=' || c == '/'
+}
+
+fn parse_tag(tag: &str) -> (String, Vec<(String, String)>) {
+    let name_end = tag.find(is_name_end).unwrap_or_else(|| tag.len());
+    let name = tag[..name_end].to_ascii_lowercase();
+
+    let mut attrs = Vec::new();
+    let mut rest = tag[name_end..].trim_start();
+    while let Some(first) = rest.chars().next() {
+        let attr_name_end = rest.find(is_name_end).unwrap_or_else(|| rest.len());
+        if attr_name_end == 0 {
+            // A stray `=` or `/`, like in `<HR/>`.
+            rest = rest[first.len_utf8()..].trim_start();
+            continue;
+        }
+        let attr_name = rest[..attr_name_end].to_ascii_lowercase();
+        rest = rest[attr_name_end..].trim_start();
+
+        let mut value = "";
+        if rest.starts_with('=') {
+            rest = rest[1..].trim_start();
+            match rest.chars().next() {
+                Some(q) if q == '"' || q == '\'' => {
+                    let value_end = rest[1..].find(q).map_or(rest.len(), |end| end + 1);
+                    value = &rest[1..value_end];
+                    rest = &rest[(value_end + 1).min(rest.len())..];
+                }
+                _ => {
+                    let value_end = rest
+                        .find(|c: char| c.is_ascii_whitespace())
+                        .unwrap_or_else(|| rest.len());
+                    value = &rest[..value_end];
+                    rest = &rest[value_end..];
+                }
+            }
+        }
+        attrs.push((attr_name, decode_entities(value)));
+        rest = rest.trim_start();
+    }
+    (name, attrs)
+}
+
+/// The length of the longest entity we decode, like `&#x10FFFF;`.
+const MAX_ENTITY_LEN: usize = 10;
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest
+            .find(';')
+            .filter(|&end| end <= MAX_ENTITY_LEN)
+            .and_then(|end| Some((decode_entity(&rest[1..end])?, end)));
+        match entity {
+            Some((c, end)) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                // Leave unknown entities and lone `&`s as they are.
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some('\u{a0}'),
+        _ => {
+            let code = if entity.starts_with("#x") || entity.starts_with("#X") {
+                u32::from_str_radix(&entity[2..], 16).ok()?
+            } else if entity.starts_with('#') {
+                entity[1..].parse().ok()?
+            } else {
+                return None;
+            };
+            std::char::from_u32(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::storage::bookmarks::BookmarkRootGuid;
+    use crate::tests::assert_json_tree;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::io::Write;
+
+    #[test]
+    fn test_decode_entities() {
+        assert_eq!(
+            decode_entities("Fish &amp; chips &lt;3 &#233;&#xE9; &bogus; &"),
+            "Fish & chips <3 éé &bogus; &"
+        );
+    }
+
+    #[test]
+    fn test_decode_bookmarks_html() {
+        // No charset, so UTF-8.
+        assert_eq!(
+            decode_bookmarks_html("<TITLE>Café</TITLE>".as_bytes()),
+            "<TITLE>Café</TITLE>"
+        );
+        assert_eq!(
+            decode_bookmarks_html(
+                b"<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=windows-1252\">\
+                  <TITLE>Caf\xe9 \x93quoted\x94</TITLE>"
+            ),
+            "<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=windows-1252\">\
+             <TITLE>Café “quoted”</TITLE>"
+        );
+        assert_eq!(
+            decode_bookmarks_html(b"<meta content='text/html; CHARSET=\"ISO-8859-1\"'>\xe9"),
+            "<meta content='text/html; CHARSET=\"ISO-8859-1\"'>é"
+        );
+        // A byte order mark wins over the declared charset.
+        assert_eq!(
+            decode_bookmarks_html(
+                b"\xef\xbb\xbf<META CONTENT=\"text/html; charset=windows-1252\">\xc3\xa9"
+            ),
+            "<META CONTENT=\"text/html; charset=windows-1252\">é"
+        );
+        // Unknown charsets fall back to UTF-8, and malformed sequences are
+        // replaced.
+        assert_eq!(
+            decode_bookmarks_html(b"<META CONTENT=\"text/html; charset=bogus\">\xe9"),
+            "<META CONTENT=\"text/html; charset=bogus\">\u{FFFD}"
+        );
+    }
+
+    #[test]
+    fn test_tokenizer() {
+        let tokens = Tokenizer::new(
+            "<!DOCTYPE NETSCAPE-Bookmark-file-1><!-- <A> -->\
+             <DT><A HREF=\"http://example.com/?a=1&amp;b=>\" ADD_DATE=123 PRIVATE>Hi</a><HR/>",
+        )
+        .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::StartTag("dt".into(), vec![]),
+                Token::StartTag(
+                    "a".into(),
+                    vec![
+                        ("href".into(), "http://example.com/?a=1&b=>".into()),
+                        ("add_date".into(), "123".into()),
+                        ("private".into(), "".into()),
+                    ]
+                ),
+                Token::Text("Hi"),
+                Token::EndTag("a".into()),
+                Token::StartTag("hr".into(), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_import_bookmarks_html() -> Result<()> {
+        let conn = new_mem_connection();
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(
+            r#"<!DOCTYPE NETSCAPE-Bookmark-file-1>
+<!-- This is an automatically generated file.
+     It will be read and overwritten.
+     DO NOT EDIT! -->
+<META HTTP-EQUIV="Content-Type" CONTENT="text/html; charset=UTF-8">
+<TITLE>Bookmarks</TITLE>
+<H1>Bookmarks Menu</H1>
+
+<DL><p>
+    <DT><H3 ADD_DATE="1500000000" LAST_MODIFIED="1500000100">Recipes &amp; more</H3>
+    <DD>Things to cook
+    <DL><p>
+        <DT><A HREF="https://example.com/donuts" ADD_DATE="1500000010" ICON_URI="https://example.com/favicon.ico" ICON="data:image/png;base64,AAAA">Donuts</A>
+        <HR>
+        <DT><H3>Empty</H3>
+        <DT><A HREF="not a url">Broken</A>
+        <DT><A HREF="https://example.com/feed" FEEDURL="https://example.com/rss">Livemark</A>
+    </DL><p>
+    <DT><A HREF="https://example.org/" ICON="data:image/png;base64,AAAA">
+</DL>
+"#
+            .as_bytes(),
+        )?;
+
+        let num_imported =
+            import_bookmarks_html(&conn, file.path(), &BookmarkRootGuid::Unfiled.as_guid())?;
+        assert_eq!(num_imported, 5);
+
+        assert_json_tree(
+            &conn,
+            &BookmarkRootGuid::Unfiled.as_guid(),
+            json!({
+                "children": [{
+                    "title": "Recipes & more",
+                    "date_added": 1_500_000_000_000u64,
+                    "last_modified": 1_500_000_100_000u64,
+                    "children": [{
+                        "title": "Donuts",
+                        "url": "https://example.com/donuts",
+                        "date_added": 1_500_000_010_000u64,
+                    }, {
+                        "type": 3,
+                    }, {
+                        "title": "Empty",
+                        "children": [],
+                    }],
+                }, {
+                    "url": "https://example.org/",
+                }],
+            }),
+        );
+
+        assert_eq!(
+            favicons::get_icon_url_for_page(&conn, &Url::parse("https://example.com/donuts")?, 16)?,
+            Some(Url::parse("https://example.com/favicon.ico")?)
+        );
+        assert_eq!(
+            favicons::get_icon_url_for_page(&conn, &Url::parse("https://example.org/")?, 16)?,
+            Some(Url::parse("data:image/png;base64,AAAA")?)
+        );
+
+        // Importing into a folder that doesn't exist fails.
+        assert!(
+            import_bookmarks_html(&conn, file.path(), &SyncGuid::from("bogus_______")).is_err()
+        );
+        Ok(())
+    }
+}
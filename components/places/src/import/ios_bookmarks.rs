@@ -9,6 +9,7 @@ use crate::bookmark_sync::{
 };
 use crate::error::*;
 use crate::import::common::{attached_database, ExecuteOnDrop};
+use crate::observer::PlacesEvent;
 use crate::types::SyncStatus;
 use rusqlite::{named_params, NO_PARAMS};
 use sql_support::ConnExt;
@@ -154,6 +155,7 @@ fn do_import_ios_bookmarks(places_api: &PlacesApi, ios_db_file_url: Url) -> Resu
     scope.err_if_interrupted()?;
     log::debug!("Cleaning up mirror...");
     clear_mirror_on_drop.execute_now()?;
+    conn.notify(PlacesEvent::BookmarksChanged);
     log::debug!("Committing...");
     tx.commit()?;
 
@@ -0,0 +1,415 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Restores bookmarks from a JSON backup made by Desktop, or by
+//! `export::backup_bookmarks_json`.
+//!
+//! Like on Desktop, restoring replaces every bookmark, so the next sync
+//! uploads tombstones for the old ones. The roots' children are restored
+//! into the matching roots, and anything else at the top level (like the
+//! tags folder in old Desktop backups) is skipped. GUIDs are kept if they're
+//! valid and unique, so that restoring a backup of a synced tree doesn't
+//! duplicate it on other devices.
+
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::export::json_bookmarks::{
+    root_name, BackupNode, TYPE_BOOKMARK, TYPE_FOLDER, TYPE_SEPARATOR,
+};
+use crate::observer::PlacesEvent;
+use crate::storage::bookmarks::{
+    insert_tree_in_tx, BookmarkNode, BookmarkRootGuid, BookmarkTreeNode, FolderNode, SeparatorNode,
+};
+use crate::storage::favicons::{self, Icon};
+use crate::storage::{delete_pending_temp_tables, keywords, tags, URL_LENGTH_MAX};
+use crate::types::{BookmarkType, Timestamp};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use sync_guid::Guid as SyncGuid;
+use url::Url;
+
+const ROOTS: &[BookmarkRootGuid] = &[
+    BookmarkRootGuid::Menu,
+    BookmarkRootGuid::Toolbar,
+    BookmarkRootGuid::Unfiled,
+    BookmarkRootGuid::Mobile,
+];
+
+/// Replaces every bookmark with the ones in the JSON backup at `path`, in a
+/// single transaction. Returns the number of restored bookmarks, folders
+/// and separators.
+pub fn restore_bookmarks_json(db: &PlacesDb, path: impl AsRef<Path>) -> Result<usize> {
+    let backup: BackupNode = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    let mut restorer = Restorer::default();
+    let trees = ROOTS
+        .iter()
+        .map(|&root| restorer.restore_root(&backup, root))
+        .collect::<Vec<_>>();
+
+    let tx = db.begin_transaction()?;
+    // Removing the old bookmarks makes tombstones for any that were synced.
+    db.execute_batch(&format!(
+        "DELETE FROM moz_bookmarks WHERE guid NOT IN ('{}', {});
+         UPDATE moz_bookmarks SET
+           syncChangeCounter = syncChangeCounter + 1
+         WHERE guid IN ({roots});
+         DELETE FROM moz_keywords;",
+        BookmarkRootGuid::Root.as_str(),
+        roots = sql_support::repeat_display(ROOTS.len(), ",", |index, f| write!(
+            f,
+            "'{}'",
+            ROOTS[index].as_str()
+        )),
+    ))?;
+    let mut num_restored = 0;
+    for tree in &trees {
+        num_restored += insert_tree_in_tx(db, tree)?;
+    }
+    for (url, icon_url) in &restorer.icons {
+        favicons::set_icon_for_page_in_tx(db, url, &Icon::new(icon_url.clone(), 0))?;
+    }
+    for (url, keyword) in &restorer.keywords {
+        // Ignore invalid keywords, and keep the first bookmark's keyword if
+        // there's more than one for the URL.
+        if keywords::get_keyword_for_url(db, url)?.is_none() {
+            if let Err(e) = keywords::set_keyword_in_tx(db, url, keyword) {
+                log::warn!("Skipping invalid keyword in bookmarks backup: {}", e);
+            }
+        }
+    }
+    for (url, tag) in &restorer.tags {
+        if let Err(e) = tags::tag_url_in_tx(db, url, tag) {
+            log::warn!("Skipping invalid tag in bookmarks backup: {}", e);
+        }
+    }
+    // Items that we restored with their old GUIDs aren't deleted.
+    db.execute_batch(
+        "DELETE FROM moz_bookmarks_deleted
+         WHERE guid IN (SELECT guid FROM moz_bookmarks)",
+    )?;
+    delete_pending_temp_tables(db)?;
+    db.notify(PlacesEvent::BookmarksChanged);
+    tx.commit()?;
+
+    log::info!("Restored {} items from bookmarks JSON", num_restored);
+    Ok(num_restored)
+}
+
+#[derive(Default)]
+struct Restorer {
+    guids: HashSet<SyncGuid>,
+    icons: Vec<(Url, Url)>,
+    keywords: Vec<(Url, String)>,
+    tags: Vec<(Url, String)>,
+}
+
+impl Restorer {
+    /// Returns a tree with the children of `root` in the backup, which is
+    /// empty if the backup doesn't have the root.
+    fn restore_root(&mut self, backup: &BackupNode, root: BookmarkRootGuid) -> FolderNode {
+        let children = backup
+            .children
+            .iter()
+            .find(|node| {
+                node.guid.as_ref().map(String::as_str) == Some(root.as_str())
+                    || node.root.as_ref().map(String::as_str) == Some(root_name(root))
+            })
+            .map(|node| self.restore_children(node))
+            .unwrap_or_default();
+        FolderNode {
+            guid: Some(root.as_guid()),
+            children,
+            ..FolderNode::default()
+        }
+    }
+
+    fn restore_children(&mut self, folder: &BackupNode) -> Vec<BookmarkTreeNode> {
+        // Desktop sorts children by `index` when restoring, too.
+        let mut children = folder.children.iter().collect::<Vec<_>>();
+        children.sort_by_key(|child| child.index);
+        children
+            .into_iter()
+            .filter_map(|child| self.restore_node(child))
+            .collect()
+    }
+
+    fn restore_node(&mut self, node: &BackupNode) -> Option<BookmarkTreeNode> {
+        let guid = self.restore_guid(node);
+        let date_added = timestamp_from_micros(node.date_added);
+        let last_modified = timestamp_from_micros(node.last_modified);
+        let title = if node.title.is_empty() {
+            None
+        } else {
+            Some(node.title.clone())
+        };
+        let kind = match (node.type_code, node.node_type.as_str()) {
+            (Some(1), _) | (None, TYPE_BOOKMARK) => BookmarkType::Bookmark,
+            (Some(2), _) | (None, TYPE_FOLDER) => BookmarkType::Folder,
+            (Some(3), _) | (None, TYPE_SEPARATOR) => BookmarkType::Separator,
+            _ => {
+                log::warn!("Skipping unknown item type in bookmarks backup");
+                return None;
+            }
+        };
+        Some(match kind {
+            BookmarkType::Bookmark => {
+                let url = node
+                    .uri
+                    .as_ref()
+                    .and_then(|uri| Url::parse(uri).ok())
+                    .filter(|url| url.as_str().len() <= URL_LENGTH_MAX)?;
+                if let Some(icon_url) = node.icon_uri.as_ref().and_then(|u| Url::parse(u).ok()) {
+                    self.icons.push((url.clone(), icon_url));
+                }
+                if let Some(keyword) = &node.keyword {
+                    self.keywords.push((url.clone(), keyword.clone()));
+                }
+                if let Some(tags) = &node.tags {
+                    for tag in tags.split(',') {
+                        self.tags.push((url.clone(), tag.to_owned()));
+                    }
+                }
+                BookmarkNode {
+                    guid,
+                    date_added,
+                    last_modified,
+                    title,
+                    url,
+                }
+                .into()
+            }
+            BookmarkType::Folder => FolderNode {
+                guid,
+                date_added,
+                last_modified,
+                title,
+                children: self.restore_children(node),
+            }
+            .into(),
+            BookmarkType::Separator => SeparatorNode {
+                guid,
+                date_added,
+                last_modified,
+            }
+            .into(),
+        })
+    }
+
+    /// Returns the item's GUID, or None if it's invalid, a root, or a
+    /// duplicate, so that the item gets a new one.
+    fn restore_guid(&mut self, node: &BackupNode) -> Option<SyncGuid> {
+        let guid = SyncGuid::from(node.guid.as_ref()?.as_str());
+        if !guid.is_valid_for_places()
+            || BookmarkRootGuid::from_guid(&guid).is_some()
+            || !self.guids.insert(guid.clone())
+        {
+            return None;
+        }
+        Some(guid)
+    }
+}
+
+fn timestamp_from_micros(micros: u64) -> Option<Timestamp> {
+    if micros == 0 {
+        None
+    } else {
+        Some(Timestamp(micros / 1000))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::storage::bookmarks::get_raw_bookmark;
+    use crate::tests::{assert_json_tree, insert_json_tree};
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+    use std::io::Write;
+
+    #[test]
+    fn test_restore_bookmarks_json() -> Result<()> {
+        let conn = new_mem_connection();
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": BookmarkRootGuid::Unfiled.as_guid(),
+                "children": [{
+                    "guid": "oldbookmark_",
+                    "url": "https://example.com/old",
+                }],
+            }),
+        );
+
+        // Split up to keep `json!` under the recursion limit.
+        let recipes = json!({
+            "guid": "folder1_____",
+            "title": "Recipes",
+            "index": 0,
+            "dateAdded": 1_500_000_000_000_000u64,
+            "lastModified": 1_500_000_100_000_000u64,
+            "typeCode": 2,
+            "type": "text/x-moz-place-container",
+            "children": [{
+                "guid": "bookmark1___",
+                "title": "Donuts",
+                "index": 0,
+                "typeCode": 1,
+                "type": "text/x-moz-place",
+                "uri": "https://example.com/donuts",
+                "iconuri": "https://example.com/favicon.ico",
+                "keyword": "donut",
+                "tags": "food,sweet",
+            }, {
+                // A duplicate GUID.
+                "guid": "bookmark1___",
+                "title": "Cake",
+                "index": 1,
+                // Old backups don't have `typeCode`.
+                "type": "text/x-moz-place",
+                "uri": "https://example.com/cake",
+            }, {
+                "guid": "bogus",
+                "title": "Invalid URL",
+                "index": 2,
+                "typeCode": 1,
+                "type": "text/x-moz-place",
+                "uri": "not a url",
+            }],
+        });
+        let menu = json!({
+            "guid": "menu________",
+            "title": "menu",
+            "index": 0,
+            "typeCode": 2,
+            "type": "text/x-moz-place-container",
+            "root": "bookmarksMenuFolder",
+            "children": [{
+                "guid": "separator1__",
+                "index": 1,
+                "typeCode": 3,
+                "type": "text/x-moz-place-separator",
+            }, recipes],
+        });
+        let tags_folder = json!({
+            "guid": "tags________",
+            "title": "tags",
+            "index": 1,
+            "typeCode": 2,
+            "type": "text/x-moz-place-container",
+            "root": "tagsFolder",
+            "children": [{
+                "guid": "tagfolder___",
+                "title": "food",
+                "typeCode": 2,
+                "type": "text/x-moz-place-container",
+            }],
+        });
+        let mut file = tempfile::NamedTempFile::new()?;
+        serde_json::to_writer(
+            &mut file,
+            &json!({
+                "guid": "root________",
+                "title": "",
+                "index": 0,
+                "dateAdded": 1_500_000_000_000_000u64,
+                "lastModified": 1_500_000_000_000_000u64,
+                "id": 1,
+                "typeCode": 2,
+                "type": "text/x-moz-place-container",
+                "root": "placesRoot",
+                "children": [menu, tags_folder],
+            }),
+        )?;
+        file.flush()?;
+
+        assert_eq!(restore_bookmarks_json(&conn, file.path())?, 4);
+        assert_json_tree(
+            &conn,
+            &BookmarkRootGuid::Menu.as_guid(),
+            json!({
+                "children": [{
+                    "guid": "folder1_____",
+                    "title": "Recipes",
+                    "date_added": 1_500_000_000_000u64,
+                    "last_modified": 1_500_000_100_000u64,
+                    "children": [{
+                        "guid": "bookmark1___",
+                        "title": "Donuts",
+                        "url": "https://example.com/donuts",
+                    }, {
+                        "title": "Cake",
+                        "url": "https://example.com/cake",
+                    }],
+                }, {
+                    "guid": "separator1__",
+                    "type": 3,
+                }],
+            }),
+        );
+        assert_json_tree(
+            &conn,
+            &BookmarkRootGuid::Unfiled.as_guid(),
+            json!({ "children": [] }),
+        );
+        assert!(get_raw_bookmark(&conn, &"oldbookmark_".into())?.is_none());
+
+        let donuts = Url::parse("https://example.com/donuts")?;
+        assert_eq!(
+            keywords::get_keyword_for_url(&conn, &donuts)?,
+            Some("donut".into())
+        );
+        let mut donut_tags = tags::get_tags_for_url(&conn, &donuts)?;
+        donut_tags.sort();
+        assert_eq!(donut_tags, vec!["food", "sweet"]);
+        assert_eq!(
+            favicons::get_icon_url_for_page(&conn, &donuts, 16)?,
+            Some(Url::parse("https://example.com/favicon.ico")?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_and_restore() -> Result<()> {
+        use crate::export::json_bookmarks::backup_bookmarks_json;
+
+        let conn = new_mem_connection();
+        let tree = json!({
+            "guid": BookmarkRootGuid::Toolbar.as_guid(),
+            "children": [{
+                "guid": "folder1_____",
+                "title": "Recipes",
+                "children": [{
+                    "guid": "bookmark1___",
+                    "title": "Donuts",
+                    "url": "https://example.com/donuts",
+                }],
+            }, {
+                "guid": "separator1__",
+                "type": 3,
+            }],
+        });
+        insert_json_tree(&conn, tree.clone());
+        let file = tempfile::NamedTempFile::new()?;
+        assert_eq!(backup_bookmarks_json(&conn, file.path())?, 3);
+
+        // Restoring into a database with other bookmarks replaces them.
+        let other_conn = new_mem_connection();
+        insert_json_tree(
+            &other_conn,
+            json!({
+                "guid": BookmarkRootGuid::Toolbar.as_guid(),
+                "children": [{
+                    "guid": "bookmark2___",
+                    "url": "https://example.com/other",
+                }],
+            }),
+        );
+        assert_eq!(restore_bookmarks_json(&other_conn, file.path())?, 3);
+        assert_json_tree(&other_conn, &BookmarkRootGuid::Toolbar.as_guid(), tree);
+        Ok(())
+    }
+}
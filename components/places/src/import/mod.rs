@@ -6,5 +6,10 @@ pub mod common;
 pub mod fennec;
 pub use fennec::import_bookmarks as import_fennec_bookmarks;
 pub use fennec::import_history as import_fennec_history;
+pub use fennec::import_pinned_sites as import_fennec_pinned_sites;
+pub mod html_bookmarks;
+pub use html_bookmarks::import_bookmarks_html;
 pub mod ios_bookmarks;
 pub use ios_bookmarks::import_ios_bookmarks;
+pub mod json_bookmarks;
+pub use json_bookmarks::restore_bookmarks_json;
@@ -11,6 +11,7 @@ pub mod types;
 // Making these all pub for now while we flesh out the API.
 pub mod bookmark_sync;
 pub mod db;
+pub mod export;
 pub mod ffi;
 pub mod frecency;
 pub mod hash;
@@ -19,6 +20,7 @@ pub mod history_sync;
 pub mod import;
 pub mod match_impl;
 pub mod observation;
+pub mod observer;
 pub mod storage;
 #[cfg(test)]
 mod tests;
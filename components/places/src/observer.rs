@@ -0,0 +1,385 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Notifying observers when history and bookmarks change, so that UIs can
+//! update what they show without polling.
+//!
+//! Connections opened by a `PlacesApi` share its observers. Changes made in
+//! a transaction are only sent once it commits, and are dropped if it rolls
+//! back. Bulk changes, like syncs, wipes and imports from other browsers, are
+//! sent as a single `HistoryChanged` or `BookmarksChanged` event, instead of
+//! one event for each item.
+
+use crate::types::{Timestamp, VisitTransition};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use sync_guid::Guid as SyncGuid;
+use url::Url;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlacesEvent {
+    VisitAdded {
+        url: Url,
+        page_guid: SyncGuid,
+        visit_date: Timestamp,
+        visit_type: VisitTransition,
+    },
+    PageRemoved {
+        page_guid: SyncGuid,
+    },
+    /// All history was removed.
+    HistoryCleared,
+    /// Many pages or visits were added, changed or removed at once, like by
+    /// a sync, or when removing visits in a date range. Observers should
+    /// fetch the history they show again.
+    HistoryChanged,
+    BookmarkAdded {
+        guid: SyncGuid,
+        parent_guid: SyncGuid,
+        position: u32,
+    },
+    /// A bookmark's title or URL, or a folder's title, changed.
+    BookmarkChanged {
+        guid: SyncGuid,
+    },
+    BookmarkMoved {
+        guid: SyncGuid,
+        old_parent_guid: SyncGuid,
+        old_position: u32,
+        new_parent_guid: SyncGuid,
+        new_position: u32,
+    },
+    /// A bookmark, separator or folder was removed. For folders, this is
+    /// only sent for the folder, and not its descendants.
+    BookmarkRemoved {
+        guid: SyncGuid,
+        parent_guid: SyncGuid,
+    },
+    /// Many bookmarks changed at once, like by a sync or an import.
+    /// Observers should fetch the bookmarks they show again.
+    BookmarksChanged,
+    /// Frecencies changed, so the order of top sites and autocomplete results
+    /// might have, too.
+    RankingChanged,
+}
+
+/// Something that wants to know when history or bookmarks change. This is
+/// called on the thread that committed the changes, after committing them.
+pub trait PlacesObserver: Send + Sync {
+    fn on_events(&self, events: &[PlacesEvent]);
+}
+
+/// Returned when registering an observer, and used to unregister it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObserverHandle(u64);
+
+#[derive(Default)]
+pub(crate) struct Observers {
+    next_id: AtomicU64,
+    observers: Mutex<Vec<(ObserverHandle, Arc<dyn PlacesObserver>)>>,
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Observers")
+            .field("count", &self.observers.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Observers {
+    pub fn register(&self, observer: Arc<dyn PlacesObserver>) -> ObserverHandle {
+        let handle = ObserverHandle(self.next_id.fetch_add(1, Ordering::SeqCst));
+        self.observers.lock().unwrap().push((handle, observer));
+        handle
+    }
+
+    /// Returns true if the observer was registered.
+    pub fn unregister(&self, handle: ObserverHandle) -> bool {
+        let mut observers = self.observers.lock().unwrap();
+        let count = observers.len();
+        observers.retain(|(h, _)| *h != handle);
+        observers.len() != count
+    }
+
+    pub fn notify(&self, events: &[PlacesEvent]) {
+        if events.is_empty() {
+            return;
+        }
+        // Copy the list first, so that an observer can register or unregister
+        // observers, or make more changes, from `on_events`.
+        let observers = self
+            .observers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, o)| o.clone())
+            .collect::<Vec<_>>();
+        for observer in observers {
+            observer.on_events(events);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::{test::new_mem_api, ConnectionType};
+    use crate::export::json_bookmarks::backup_bookmarks_json;
+    use crate::import::html_bookmarks::import_bookmarks_html;
+    use crate::import::json_bookmarks::restore_bookmarks_json;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        delete_bookmark, insert_bookmark, insert_tree, update_bookmark, BookmarkNode,
+        BookmarkPosition, BookmarkRootGuid, BookmarkTreeNode, FolderNode, InsertableBookmark,
+        InsertableItem, UpdatableBookmark, UpdatableItem, UpdateTreeLocation,
+    };
+    use crate::storage::history::{
+        apply_observation, apply_observation_direct, delete_place_by_guid, wipe_local,
+    };
+    use pretty_assertions::assert_eq;
+    use sql_support::ConnExt;
+    use std::io::Write;
+
+    #[derive(Default)]
+    struct TestObserver(Mutex<Vec<PlacesEvent>>);
+
+    impl TestObserver {
+        fn take(&self) -> Vec<PlacesEvent> {
+            std::mem::replace(&mut *self.0.lock().unwrap(), Vec::new())
+        }
+    }
+
+    impl PlacesObserver for TestObserver {
+        fn on_events(&self, events: &[PlacesEvent]) {
+            self.0.lock().unwrap().extend_from_slice(events);
+        }
+    }
+
+    #[test]
+    fn test_register_unregister() {
+        let api = new_mem_api();
+        let conn = api
+            .open_connection(ConnectionType::ReadWrite)
+            .expect("should get a connection");
+        let observer = Arc::new(TestObserver::default());
+        let handle = api.register_observer(observer.clone());
+
+        wipe_local(&conn).expect("should wipe");
+        assert_eq!(observer.take(), vec![PlacesEvent::HistoryCleared]);
+
+        assert!(api.unregister_observer(handle));
+        assert!(!api.unregister_observer(handle));
+        wipe_local(&conn).expect("should wipe");
+        assert_eq!(observer.take(), vec![]);
+    }
+
+    #[test]
+    fn test_history_events() {
+        let api = new_mem_api();
+        let conn = api
+            .open_connection(ConnectionType::ReadWrite)
+            .expect("should get a connection");
+        let observer = Arc::new(TestObserver::default());
+        api.register_observer(observer.clone());
+
+        let url = Url::parse("https://www.example.com/").unwrap();
+        let visit_date = Timestamp(1_500_000_000_000);
+        apply_observation(
+            &conn,
+            VisitObservation::new(url.clone())
+                .with_visit_type(VisitTransition::Typed)
+                .with_at(visit_date),
+        )
+        .expect("should apply");
+        let page_guid = conn
+            .query_one::<SyncGuid>("SELECT guid FROM moz_places")
+            .expect("should fetch page GUID");
+        assert_eq!(
+            observer.take(),
+            vec![PlacesEvent::VisitAdded {
+                url,
+                page_guid: page_guid.clone(),
+                visit_date,
+                visit_type: VisitTransition::Typed,
+            }]
+        );
+
+        delete_place_by_guid(&conn, &page_guid).expect("should delete");
+        assert_eq!(
+            observer.take(),
+            vec![PlacesEvent::PageRemoved {
+                page_guid: page_guid.clone(),
+            }]
+        );
+
+        // Deleting a page that doesn't exist doesn't change anything.
+        delete_place_by_guid(&conn, &page_guid).expect("should delete");
+        assert_eq!(observer.take(), vec![]);
+    }
+
+    #[test]
+    fn test_bookmark_events() {
+        let api = new_mem_api();
+        let conn = api
+            .open_connection(ConnectionType::ReadWrite)
+            .expect("should get a connection");
+        let observer = Arc::new(TestObserver::default());
+        api.register_observer(observer.clone());
+
+        let guid = insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: Url::parse("https://www.example.com/").unwrap(),
+                title: Some("Example".into()),
+            }),
+        )
+        .expect("should insert");
+        assert_eq!(
+            observer.take(),
+            vec![PlacesEvent::BookmarkAdded {
+                guid: guid.clone(),
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: 0,
+            }]
+        );
+
+        update_bookmark(
+            &conn,
+            &guid,
+            &UpdatableItem::Bookmark(UpdatableBookmark {
+                location: UpdateTreeLocation::Parent(
+                    BookmarkRootGuid::Toolbar.into(),
+                    BookmarkPosition::Append,
+                ),
+                title: Some("New title".into()),
+                ..UpdatableBookmark::default()
+            }),
+        )
+        .expect("should update");
+        assert_eq!(
+            observer.take(),
+            vec![
+                PlacesEvent::BookmarkChanged { guid: guid.clone() },
+                PlacesEvent::BookmarkMoved {
+                    guid: guid.clone(),
+                    old_parent_guid: BookmarkRootGuid::Unfiled.into(),
+                    old_position: 0,
+                    new_parent_guid: BookmarkRootGuid::Toolbar.into(),
+                    new_position: 0,
+                },
+            ]
+        );
+
+        assert!(delete_bookmark(&conn, &guid).expect("should delete"));
+        assert_eq!(
+            observer.take(),
+            vec![PlacesEvent::BookmarkRemoved {
+                guid,
+                parent_guid: BookmarkRootGuid::Toolbar.into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_bulk_bookmark_events() {
+        let api = new_mem_api();
+        let conn = api
+            .open_connection(ConnectionType::ReadWrite)
+            .expect("should get a connection");
+        let observer = Arc::new(TestObserver::default());
+        api.register_observer(observer.clone());
+
+        let bookmark = |url: &str| -> BookmarkTreeNode {
+            BookmarkNode {
+                guid: None,
+                date_added: None,
+                last_modified: None,
+                title: None,
+                url: Url::parse(url).unwrap(),
+            }
+            .into()
+        };
+        insert_tree(
+            &conn,
+            &FolderNode {
+                guid: Some(BookmarkRootGuid::Unfiled.into()),
+                children: vec![
+                    bookmark("https://www.example.com/1"),
+                    bookmark("https://www.example.com/2"),
+                ],
+                ..FolderNode::default()
+            },
+        )
+        .expect("should insert tree");
+        assert_eq!(observer.take(), vec![PlacesEvent::BookmarksChanged]);
+
+        let json = tempfile::NamedTempFile::new().unwrap();
+        backup_bookmarks_json(&conn, json.path()).expect("should back up");
+        assert_eq!(observer.take(), vec![]);
+        assert_eq!(
+            restore_bookmarks_json(&conn, json.path()).expect("should restore"),
+            2
+        );
+        assert_eq!(observer.take(), vec![PlacesEvent::BookmarksChanged]);
+
+        let mut html = tempfile::NamedTempFile::new().unwrap();
+        html.write_all(
+            br#"<DL><p>
+    <DT><A HREF="https://www.example.com/3">Three</A>
+    <DT><A HREF="https://www.example.com/4">Four</A>
+</DL>"#,
+        )
+        .unwrap();
+        assert_eq!(
+            import_bookmarks_html(&conn, html.path(), &BookmarkRootGuid::Menu.into())
+                .expect("should import"),
+            2
+        );
+        assert_eq!(observer.take(), vec![PlacesEvent::BookmarksChanged]);
+    }
+
+    #[test]
+    fn test_events_sent_on_commit() {
+        let api = new_mem_api();
+        let conn = api
+            .open_connection(ConnectionType::ReadWrite)
+            .expect("should get a connection");
+        let observer = Arc::new(TestObserver::default());
+        api.register_observer(observer.clone());
+
+        let visit = |url: &str| {
+            apply_observation_direct(
+                &conn,
+                VisitObservation::new(Url::parse(url).unwrap())
+                    .with_visit_type(VisitTransition::Link)
+                    .with_at(Timestamp(1_500_000_000_000)),
+            )
+        };
+
+        let tx = conn.begin_transaction().expect("should begin");
+        visit("https://www.example.com/rolled-back").expect("should apply");
+        tx.rollback().expect("should roll back");
+        assert_eq!(observer.take(), vec![]);
+
+        let tx = conn.begin_transaction().expect("should begin");
+        visit("https://www.example.com/committed").expect("should apply");
+        assert_eq!(observer.take(), vec![]);
+        tx.commit().expect("should commit");
+        let events = observer.take();
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            PlacesEvent::VisitAdded { url, .. } => {
+                assert_eq!(url.as_str(), "https://www.example.com/committed")
+            }
+            event => panic!("Unexpected event {:?}", event),
+        }
+    }
+}
@@ -6,6 +6,7 @@ use super::RowId;
 use super::{fetch_page_info, new_page_info};
 use crate::db::PlacesDb;
 use crate::error::*;
+use crate::observer::PlacesEvent;
 use crate::types::{BookmarkType, SyncStatus, Timestamp};
 use rusqlite::types::ToSql;
 use rusqlite::{Connection, Row};
@@ -18,7 +19,7 @@ use serde_derive::*;
 use serde_json::{self, json};
 use sql_support::{self, ConnExt};
 use std::cmp::{max, min};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use sync_guid::Guid as SyncGuid;
 use url::Url;
 
@@ -246,10 +247,20 @@ pub fn insert_bookmark(db: &PlacesDb, bm: &InsertableItem) -> Result<SyncGuid> {
     let result = insert_bookmark_in_tx(db, bm);
     super::delete_pending_temp_tables(db)?;
     match result {
-        Ok(_) => tx.commit()?,
-        Err(_) => tx.rollback()?,
+        Ok((guid, position)) => {
+            db.notify(PlacesEvent::BookmarkAdded {
+                guid: guid.clone(),
+                parent_guid: bm.parent_guid().clone(),
+                position,
+            });
+            tx.commit()?;
+            Ok(guid)
+        }
+        Err(e) => {
+            tx.rollback()?;
+            Err(e)
+        }
     }
-    result
 }
 
 pub fn maybe_truncate_title(t: &Option<String>) -> Option<&str> {
@@ -258,7 +269,9 @@ pub fn maybe_truncate_title(t: &Option<String>) -> Option<&str> {
     t.as_ref().map(|title| slice_up_to(title, TITLE_LENGTH_MAX))
 }
 
-fn insert_bookmark_in_tx(db: &PlacesDb, bm: &InsertableItem) -> Result<SyncGuid> {
+/// Inserts `bm`, returning its GUID and position. This doesn't notify
+/// observers, so that bulk inserts can send a single event instead.
+fn insert_bookmark_in_tx(db: &PlacesDb, bm: &InsertableItem) -> Result<(SyncGuid, u32)> {
     // find the row ID of the parent.
     if bm.parent_guid() == BookmarkRootGuid::Root {
         return Err(InvalidPlaceInfo::CannotUpdateRoot(BookmarkRootGuid::Root).into());
@@ -359,7 +372,7 @@ fn insert_bookmark_in_tx(db: &PlacesDb, bm: &InsertableItem) -> Result<SyncGuid>
         WHERE id = :parent_id";
     db.execute_named_cached(sql_counter, &[(":parent_id", &parent.row_id)])?;
 
-    Ok(guid)
+    Ok((guid, position))
 }
 
 /// Delete the specified bookmark. Returns true if a bookmark with the guid
@@ -400,6 +413,12 @@ fn delete_bookmark_in_tx(db: &PlacesDb, guid: &SyncGuid) -> Result<bool> {
         &[(":id", &record.row_id)],
     )?;
     super::delete_pending_temp_tables(db)?;
+    if let Some(parent_guid) = record.parent_guid {
+        db.notify(PlacesEvent::BookmarkRemoved {
+            guid: record.guid,
+            parent_guid,
+        });
+    }
     Ok(true)
 }
 
@@ -646,6 +665,23 @@ fn update_bookmark_in_tx(
         set_ancestors_last_modified(db, parent_id, now)?;
         db.execute_named_cached(sql_counter, &[(":parent_id", &parent_id)])?;
     }
+
+    if change_incr {
+        db.notify(PlacesEvent::BookmarkChanged { guid: guid.clone() });
+    }
+    if parent_id != existing_parent_id || position != raw.position {
+        let new_parent_guid = match item.location() {
+            UpdateTreeLocation::Parent(new_parent_guid, _) => new_parent_guid.clone(),
+            _ => existing_parent_guid.clone(),
+        };
+        db.notify(PlacesEvent::BookmarkMoved {
+            guid: guid.clone(),
+            old_parent_guid: existing_parent_guid.clone(),
+            old_position: raw.position,
+            new_parent_guid,
+            new_position: position,
+        });
+    }
     Ok(())
 }
 
@@ -673,6 +709,104 @@ fn set_ancestors_last_modified(db: &PlacesDb, parent_id: RowId, time: Timestamp)
     Ok(())
 }
 
+/// Reorders the children of a folder in one go, instead of moving each child
+/// with `update_bookmark`. Like Desktop, the children in `ordered_child_guids`
+/// come first, in that order, followed by any children that aren't in the
+/// list, in their current order. GUIDs that aren't children of the folder are
+/// ignored.
+///
+/// Only the folder's change counter is bumped, since Sync stores the order
+/// of a folder's children in the folder's record.
+pub fn reorder_children(
+    db: &PlacesDb,
+    folder_guid: &SyncGuid,
+    ordered_child_guids: &[SyncGuid],
+) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    let result = reorder_children_in_tx(db, folder_guid, ordered_child_guids);
+    match result {
+        Ok(_) => tx.commit()?,
+        Err(_) => tx.rollback()?,
+    }
+    result
+}
+
+fn reorder_children_in_tx(
+    db: &PlacesDb,
+    folder_guid: &SyncGuid,
+    ordered_child_guids: &[SyncGuid],
+) -> Result<()> {
+    // Desktop doesn't let you reorder the roots.
+    if folder_guid == BookmarkRootGuid::Root {
+        return Err(InvalidPlaceInfo::CannotUpdateRoot(BookmarkRootGuid::Root).into());
+    }
+    let folder = get_raw_bookmark(db, folder_guid)?
+        .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(folder_guid.to_string()))?;
+    if folder.bookmark_type != BookmarkType::Folder {
+        return Err(InvalidPlaceInfo::InvalidParent(folder_guid.to_string()).into());
+    }
+
+    let existing_child_guids = db.query_rows_and_then_named_cached(
+        "SELECT guid FROM moz_bookmarks
+         WHERE parent = :parent_id
+         ORDER BY position",
+        &[(":parent_id", &folder.row_id)],
+        |row| row.get::<_, SyncGuid>(0),
+    )?;
+    let is_child = existing_child_guids.iter().collect::<HashSet<_>>();
+    let mut seen = HashSet::with_capacity(existing_child_guids.len());
+    let mut new_child_guids = Vec::with_capacity(existing_child_guids.len());
+    for guid in ordered_child_guids {
+        if is_child.contains(guid) && seen.insert(guid) {
+            new_child_guids.push(guid);
+        }
+    }
+    for guid in &existing_child_guids {
+        if seen.insert(guid) {
+            new_child_guids.push(guid);
+        }
+    }
+    if new_child_guids
+        .iter()
+        .copied()
+        .eq(existing_child_guids.iter())
+    {
+        return Ok(());
+    }
+
+    let old_positions = existing_child_guids
+        .iter()
+        .enumerate()
+        .map(|(position, guid)| (guid, position as u32))
+        .collect::<HashMap<_, _>>();
+    for (position, guid) in new_child_guids.into_iter().enumerate() {
+        let position = position as u32;
+        let old_position = old_positions[guid];
+        if position == old_position {
+            continue;
+        }
+        db.execute_named_cached(
+            "UPDATE moz_bookmarks SET position = :position WHERE guid = :guid",
+            &[(":position", &position), (":guid", guid)],
+        )?;
+        db.notify(PlacesEvent::BookmarkMoved {
+            guid: guid.clone(),
+            old_parent_guid: folder.guid.clone(),
+            old_position,
+            new_parent_guid: folder.guid.clone(),
+            new_position: position,
+        });
+    }
+    let now = Timestamp::now();
+    set_ancestors_last_modified(db, folder.row_id, now)?;
+    db.execute_named_cached(
+        "UPDATE moz_bookmarks SET syncChangeCounter = syncChangeCounter + 1
+         WHERE id = :parent_id",
+        &[(":parent_id", &folder.row_id)],
+    )?;
+    Ok(())
+}
+
 /// Support for inserting and fetching a tree. Same limitations as desktop.
 /// Note that the guids are optional when inserting a tree. They will always
 /// have values when fetching it.
@@ -903,10 +1037,14 @@ impl<'de> Deserialize<'de> for BookmarkTreeNode {
 
 /// Get the URL of the bookmark matching a keyword
 pub fn bookmarks_get_url_for_keyword(db: &PlacesDb, keyword: &str) -> Result<Option<Url>> {
+    let keyword = match super::keywords::normalize_keyword(keyword) {
+        Some(keyword) => keyword,
+        None => return Ok(None),
+    };
     let bookmark_url = db.try_query_row(
         "SELECT url FROM moz_places p
-        JOIN moz_bookmarks_synced b ON b.placeId = p.id
-        WHERE b.keyword = :keyword",
+        JOIN moz_keywords k ON k.place_id = p.id
+        WHERE k.keyword = :keyword",
         &[(":keyword", &keyword)],
         |row| row.get::<_, String>("url"),
         true,
@@ -1016,7 +1154,6 @@ mod test_serialize {
 
         Ok(())
     }
-
 }
 
 fn add_subtree_infos(parent: &SyncGuid, tree: &FolderNode, insert_infos: &mut Vec<InsertableItem>) {
@@ -1069,7 +1206,22 @@ fn add_subtree_infos(parent: &SyncGuid, tree: &FolderNode, insert_infos: &mut Ve
     }
 }
 
+/// Inserts the children of `tree` into the folder with its GUID, in a single
+/// transaction. Observers are sent one `BookmarksChanged` event, instead of
+/// one for each item.
 pub fn insert_tree(db: &PlacesDb, tree: &FolderNode) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    insert_tree_in_tx(db, tree)?;
+    super::delete_pending_temp_tables(db)?;
+    db.notify(PlacesEvent::BookmarksChanged);
+    tx.commit()?;
+    Ok(())
+}
+
+/// Inserts the children of `tree` into the folder with its GUID, returning
+/// the number of inserted items. The caller must be in a transaction, and
+/// delete the pending temp tables and notify observers afterward.
+pub(crate) fn insert_tree_in_tx(db: &PlacesDb, tree: &FolderNode) -> Result<usize> {
     let parent_guid = match &tree.guid {
         Some(guid) => guid,
         None => return Err(InvalidPlaceInfo::InvalidParent("<no guid>".into()).into()),
@@ -1078,14 +1230,10 @@ pub fn insert_tree(db: &PlacesDb, tree: &FolderNode) -> Result<()> {
     let mut insert_infos: Vec<InsertableItem> = Vec::new();
     add_subtree_infos(&parent_guid, tree, &mut insert_infos);
     log::info!("insert_tree inserting {} records", insert_infos.len());
-    let tx = db.begin_transaction()?;
-
-    for insertable in insert_infos {
-        insert_bookmark_in_tx(db, &insertable)?;
+    for insertable in &insert_infos {
+        insert_bookmark_in_tx(db, insertable)?;
     }
-    super::delete_pending_temp_tables(db)?;
-    tx.commit()?;
-    Ok(())
+    Ok(insert_infos.len())
 }
 
 #[derive(Debug)]
@@ -1416,7 +1564,6 @@ mod tests {
     use pretty_assertions::assert_eq;
     use rusqlite::NO_PARAMS;
     use serde_json::Value;
-    use std::collections::HashSet;
 
     fn get_pos(conn: &PlacesDb, guid: &SyncGuid) -> u32 {
         get_raw_bookmark(conn, guid)
@@ -1440,12 +1587,12 @@ mod tests {
         .expect("should work");
         let place_id = conn.last_insert_rowid();
 
-        // create a bookmark with keyword 'donut' pointing at it.
+        // create a keyword 'donut' pointing at it.
         conn.execute_named_cached(
-            "INSERT INTO moz_bookmarks_synced
-                (keyword, placeId, guid)
+            "INSERT INTO moz_keywords
+                (keyword, place_id)
             VALUES
-                ('donut', :place_id, 'fake_guid___')",
+                ('donut', :place_id)",
             &[(":place_id", &place_id)],
         )
         .expect("should work");
@@ -1458,10 +1605,9 @@ mod tests {
 
         // now change the keyword to 'ice cream'
         conn.execute_named_cached(
-            "REPLACE INTO moz_bookmarks_synced
-                (keyword, placeId, guid)
-            VALUES
-                ('ice cream', :place_id, 'fake_guid___')",
+            "UPDATE moz_keywords
+            SET keyword = 'ice cream'
+            WHERE place_id = :place_id",
             &[(":place_id", &place_id)],
         )
         .expect("should work");
@@ -1642,6 +1788,97 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_reorder_children() -> Result<()> {
+        let conn = new_mem_connection();
+        let unfiled = &BookmarkRootGuid::Unfiled.as_guid();
+
+        insert_json_tree(
+            &conn,
+            json!({
+                "guid": unfiled,
+                "children": [
+                    {
+                        "guid": "bookmark1___",
+                        "url": "https://www.example1.com/"
+                    },
+                    {
+                        "guid": "bookmark2___",
+                        "url": "https://www.example2.com/"
+                    },
+                    {
+                        "guid": "bookmark3___",
+                        "url": "https://www.example3.com/"
+                    },
+                    {
+                        "guid": "bookmark4___",
+                        "url": "https://www.example4.com/"
+                    },
+                ]
+            }),
+        );
+        conn.execute_cached("UPDATE moz_bookmarks SET syncChangeCounter = 0", NO_PARAMS)?;
+        let get_change_counter = |guid: &str| -> Result<i64> {
+            Ok(conn.query_row_and_then_named(
+                "SELECT syncChangeCounter FROM moz_bookmarks WHERE guid = :guid",
+                &[(":guid", &guid)],
+                |row| row.get(0),
+                false,
+            )?)
+        };
+
+        // Children that aren't listed keep their order after the ones that
+        // are, and GUIDs that aren't children are ignored.
+        reorder_children(
+            &conn,
+            unfiled,
+            &[
+                "bookmark3___".into(),
+                "nonexistent_".into(),
+                "bookmark1___".into(),
+                "bookmark3___".into(),
+            ],
+        )?;
+        assert_json_tree(
+            &conn,
+            unfiled,
+            json!({
+                "guid": unfiled,
+                "children": [
+                    {"guid": "bookmark3___", "url": "https://www.example3.com/"},
+                    {"guid": "bookmark1___", "url": "https://www.example1.com/"},
+                    {"guid": "bookmark2___", "url": "https://www.example2.com/"},
+                    {"guid": "bookmark4___", "url": "https://www.example4.com/"},
+                ]
+            }),
+        );
+        assert_eq!(get_change_counter(unfiled.as_str())?, 1);
+        assert_eq!(get_change_counter("bookmark1___")?, 0);
+        assert_eq!(get_change_counter("bookmark3___")?, 0);
+
+        // Reordering with the same order doesn't change anything.
+        reorder_children(&conn, unfiled, &["bookmark3___".into()])?;
+        assert_eq!(get_change_counter(unfiled.as_str())?, 1);
+
+        match reorder_children(&conn, &"bookmark1___".into(), &[])
+            .expect_err("shouldn't reorder a bookmark")
+            .kind()
+        {
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::InvalidParent(_)) => {}
+            e => panic!("Unexpected error: {}", e),
+        }
+        match reorder_children(&conn, &"nonexistent_".into(), &[])
+            .expect_err("shouldn't reorder a nonexistent folder")
+            .kind()
+        {
+            ErrorKind::InvalidPlaceInfo(InvalidPlaceInfo::NoSuchGuid(_)) => {}
+            e => panic!("Unexpected error: {}", e),
+        }
+        reorder_children(&conn, &BookmarkRootGuid::Root.as_guid(), &[])
+            .expect_err("shouldn't reorder the roots");
+        Ok(())
+    }
+
     #[test]
     fn test_update_move_same_parent() -> Result<()> {
         let _ = env_logger::try_init();
@@ -2297,5 +2534,4 @@ mod tests {
 
         Ok(())
     }
-
 }
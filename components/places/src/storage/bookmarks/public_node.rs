@@ -166,6 +166,36 @@ pub fn fetch_public_tree_with_depth(
     Ok(Some(proto))
 }
 
+/// Fetches the roots to show at the top level of a bookmarks UI, with their
+/// children. The mobile root always exists, since Sync needs it, but it's
+/// only meaningful once the first mobile bookmark is saved to it. When
+/// `mobile_first` is true, like on mobile, the mobile root is always
+/// returned, before the other roots. Otherwise, it's only returned if it
+/// has children, after the other roots, like Desktop's "Mobile Bookmarks"
+/// folder.
+pub fn fetch_display_roots(db: &PlacesDb, mobile_first: bool) -> Result<Vec<PublicNode>> {
+    let root =
+        fetch_public_tree_with_depth(db, BookmarkRootGuid::Root.guid(), &FetchDepth::Specific(1))?
+            .ok_or_else(|| InvalidPlaceInfo::NoSuchGuid(BookmarkRootGuid::Root.as_str().into()))?;
+    let mut roots = root.child_nodes.unwrap_or_default();
+    if let Some(index) = roots
+        .iter()
+        .position(|node| node.guid == BookmarkRootGuid::Mobile)
+    {
+        let mobile = roots.remove(index);
+        let has_children = mobile
+            .child_nodes
+            .as_ref()
+            .map_or(false, |children| !children.is_empty());
+        if mobile_first {
+            roots.insert(0, mobile);
+        } else if has_children {
+            roots.push(mobile);
+        }
+    }
+    Ok(roots)
+}
+
 pub fn search_bookmarks(db: &PlacesDb, search: &str, limit: u32) -> Result<Vec<PublicNode>> {
     let scope = db.begin_interrupt_scope();
     Ok(db.query_rows_into_cached(
@@ -192,6 +222,73 @@ pub fn search_bookmarks(db: &PlacesDb, search: &str, limit: u32) -> Result<Vec<P
     )?)
 }
 
+/// A folder that contains a bookmark returned from
+/// `search_bookmarks_with_folder_paths`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FolderPathEntry {
+    pub guid: SyncGuid,
+    // The roots have untranslated titles, so UIs should use their GUIDs to
+    // show localized names instead.
+    pub title: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BookmarkSearchResult {
+    pub bookmark: PublicNode,
+    /// The folders that contain the bookmark, starting with its root (like
+    /// the toolbar), and ending with its parent.
+    pub folder_path: Vec<FolderPathEntry>,
+}
+
+/// Like `search_bookmarks`, but also returns the folders that contain each
+/// bookmark, so that a bookmarks manager can show where they are without
+/// fetching the whole tree.
+pub fn search_bookmarks_with_folder_paths(
+    db: &PlacesDb,
+    search: &str,
+    limit: u32,
+) -> Result<Vec<BookmarkSearchResult>> {
+    let scope = db.begin_interrupt_scope();
+    let mut results = Vec::new();
+    for bookmark in search_bookmarks(db, search, limit)? {
+        scope.err_if_interrupted()?;
+        let folder_path = match &bookmark.parent_guid {
+            Some(parent_guid) => fetch_folder_path(db, parent_guid)?,
+            None => Vec::new(),
+        };
+        results.push(BookmarkSearchResult {
+            bookmark,
+            folder_path,
+        });
+    }
+    Ok(results)
+}
+
+// Returns `folder_guid` and its ancestors, starting with the one under the
+// places root, which we leave out.
+fn fetch_folder_path(db: &PlacesDb, folder_guid: &SyncGuid) -> Result<Vec<FolderPathEntry>> {
+    Ok(db.query_rows_and_then_named_cached(
+        "WITH RECURSIVE
+         ancestors(id, parent, guid, title, depth) AS (
+             SELECT id, parent, guid, title, 0 FROM moz_bookmarks
+             WHERE guid = :guid
+             UNION ALL
+             SELECT b.id, b.parent, b.guid, b.title, a.depth + 1 FROM moz_bookmarks b
+             JOIN ancestors a ON b.id = a.parent
+         )
+         SELECT guid, NULLIF(title, '') AS title FROM ancestors
+         WHERE parent IS NOT NULL
+         ORDER BY depth DESC",
+        &[(":guid", folder_guid)],
+        |row| -> Result<_> {
+            Ok(FolderPathEntry {
+                guid: row.get("guid")?,
+                title: row.get("title")?,
+            })
+        },
+    )?)
+}
+
 pub fn recent_bookmarks(db: &PlacesDb, limit: u32) -> Result<Vec<PublicNode>> {
     let scope = db.begin_interrupt_scope();
     let sql = format!(
@@ -437,6 +534,76 @@ mod test {
         }
         Ok(())
     }
+
+    #[test]
+    fn test_search_with_folder_paths() -> Result<()> {
+        let conns = new_mem_connections();
+        insert_json_tree(
+            &conns.write,
+            json!({
+                "guid": String::from(BookmarkRootGuid::Toolbar.as_str()),
+                "children": [
+                    {
+                        "guid": "folder1_____",
+                        "title": "Recipes",
+                        "children": [
+                            {
+                                "guid": "folder2_____",
+                                "title": "",
+                                "children": [
+                                    {
+                                        "guid": "bookmark1___",
+                                        "url": "https://www.example.com/donuts",
+                                        "title": "Donuts",
+                                    },
+                                ],
+                            },
+                        ],
+                    },
+                    {
+                        "guid": "bookmark2___",
+                        "url": "https://www.example.com/muffins",
+                        "title": "Muffins",
+                    },
+                ]
+            }),
+        );
+        let mut results = search_bookmarks_with_folder_paths(&conns.read, "example", 10)?;
+        results.sort_by_key(|r| r.bookmark.guid.as_str().to_string());
+        assert_eq!(
+            results
+                .iter()
+                .map(|r| (r.bookmark.guid.as_str(), r.folder_path.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    "bookmark1___",
+                    vec![
+                        FolderPathEntry {
+                            guid: BookmarkRootGuid::Toolbar.into(),
+                            title: Some("toolbar".into()),
+                        },
+                        FolderPathEntry {
+                            guid: "folder1_____".into(),
+                            title: Some("Recipes".into()),
+                        },
+                        FolderPathEntry {
+                            guid: "folder2_____".into(),
+                            title: None,
+                        },
+                    ]
+                ),
+                (
+                    "bookmark2___",
+                    vec![FolderPathEntry {
+                        guid: BookmarkRootGuid::Toolbar.into(),
+                        title: Some("toolbar".into()),
+                    }]
+                ),
+            ]
+        );
+        Ok(())
+    }
     #[test]
     fn test_fetch_bookmark() -> Result<()> {
         let conns = new_mem_connections();
@@ -553,6 +720,60 @@ mod test {
         Ok(())
     }
     #[test]
+    fn test_fetch_display_roots() -> Result<()> {
+        let conns = new_mem_connections();
+        let _ = env_logger::try_init();
+
+        let guids = |roots: &[PublicNode]| -> Vec<String> {
+            roots.iter().map(|r| r.guid.as_str().to_owned()).collect()
+        };
+
+        // The mobile root is hidden on Desktop until it has bookmarks.
+        let roots = fetch_display_roots(&conns.read, false)?;
+        assert_eq!(
+            guids(&roots),
+            vec!["menu________", "toolbar_____", "unfiled_____"]
+        );
+        let roots = fetch_display_roots(&conns.read, true)?;
+        assert_eq!(
+            guids(&roots),
+            vec![
+                "mobile______",
+                "menu________",
+                "toolbar_____",
+                "unfiled_____"
+            ]
+        );
+
+        insert_json_tree(
+            &conns.write,
+            json!({
+                "guid": BookmarkRootGuid::Mobile.as_guid(),
+                "children": [{
+                    "guid": "bookmark1___",
+                    "url": "https://www.example1.com/"
+                }]
+            }),
+        );
+        let roots = fetch_display_roots(&conns.read, false)?;
+        assert_eq!(
+            guids(&roots),
+            vec![
+                "menu________",
+                "toolbar_____",
+                "unfiled_____",
+                "mobile______"
+            ]
+        );
+        let mobile = &roots[3];
+        assert_eq!(mobile.parent_guid.as_ref().unwrap(), BookmarkRootGuid::Root);
+        assert_eq!(mobile.position, 3);
+        let children = mobile.child_nodes.as_ref().unwrap();
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].guid, "bookmark1___");
+        Ok(())
+    }
+    #[test]
     fn test_recent() -> Result<()> {
         let conns = new_mem_connections();
         let _ = env_logger::try_init();
@@ -0,0 +1,459 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Expiring old history, so that the database doesn't grow forever on
+//! long-lived profiles.
+//!
+//! Like Desktop, expiration runs in small steps during idle time. Each step
+//! removes visits older than the policy's maximum age, and, if there are too
+//! many pages or the database is too big, the least interesting pages:
+//! hidden pages first, then the ones with the lowest frecency, then the ones
+//! visited longest ago. Pages that are bookmarked, tagged, or have keywords
+//! are never removed, though their old visits are.
+//!
+//! Expired visits and pages aren't synced as deletions, so they stay on the
+//! server and on other devices.
+
+use super::{delete_pending_temp_tables, history::update_frecency, RowId};
+use crate::db::PlacesDb;
+use crate::error::*;
+use crate::observer::PlacesEvent;
+use crate::types::Timestamp;
+use serde_derive::*;
+use sql_support::{self, ConnExt, SqlInterruptScope};
+use std::time::Duration;
+
+/// The most visits, and the most pages, to remove in one step. This keeps
+/// each step's transaction short, so that it doesn't block the main writer.
+pub const EXPIRATION_CHUNK_SIZE: usize = 200;
+
+/// Limits on how much history to keep. `None` means no limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExpirationPolicy {
+    /// The most pages to keep. Pages that can't be expired, like bookmarks,
+    /// count toward this, too.
+    pub max_pages: Option<usize>,
+    /// Visits older than this are removed.
+    pub max_age: Option<Duration>,
+    /// The largest the database should be, in bytes, not counting free
+    /// pages that haven't been returned to the file system yet.
+    pub max_db_size: Option<u64>,
+}
+
+impl Default for ExpirationPolicy {
+    /// Desktop's defaults: at most 60 MiB, or as many pages as fit in that,
+    /// assuming 600 bytes per page. There's no age limit.
+    fn default() -> Self {
+        ExpirationPolicy {
+            max_pages: Some(100_000),
+            max_age: None,
+            max_db_size: Some(60 * 1024 * 1024),
+        }
+    }
+}
+
+/// What an expiration step did.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExpirationReport {
+    pub expired_visits: usize,
+    pub expired_pages: usize,
+    /// False if there's more to expire, and the caller should run another
+    /// step later.
+    pub finished: bool,
+}
+
+/// Runs one expiration step, using `now` to find old visits. This checks
+/// `scope` between each part of the step, so that it can be interrupted;
+/// if it is, nothing from the step is removed.
+pub fn expire_history(
+    db: &PlacesDb,
+    policy: &ExpirationPolicy,
+    now: Timestamp,
+    scope: &SqlInterruptScope,
+) -> Result<ExpirationReport> {
+    let tx = db.begin_transaction()?;
+    let mut report = ExpirationReport {
+        finished: true,
+        ..ExpirationReport::default()
+    };
+    let mut affected_pages = Vec::new();
+
+    if let Some(cutoff) = policy.max_age.and_then(|max_age| now.checked_sub(max_age)) {
+        let visits = db.query_rows_and_then_named_cached(
+            &format!(
+                "SELECT id, place_id FROM moz_historyvisits
+                 WHERE visit_date < :cutoff
+                 ORDER BY visit_date
+                 LIMIT {}",
+                EXPIRATION_CHUNK_SIZE
+            ),
+            &[(":cutoff", &cutoff)],
+            |row| -> rusqlite::Result<_> { Ok((row.get::<_, RowId>(0)?, row.get::<_, RowId>(1)?)) },
+        )?;
+        scope.err_if_interrupted()?;
+        sql_support::each_chunk_mapped(
+            &visits,
+            |(visit_id, _)| visit_id,
+            |chunk, _| -> Result<()> {
+                db.execute(
+                    &format!(
+                        "DELETE FROM moz_historyvisits WHERE id IN ({})",
+                        sql_support::repeat_sql_vars(chunk.len())
+                    ),
+                    chunk,
+                )?;
+                Ok(())
+            },
+        )?;
+        report.expired_visits += visits.len();
+        if visits.len() == EXPIRATION_CHUNK_SIZE {
+            report.finished = false;
+        }
+        affected_pages.extend(visits.into_iter().map(|(_, place_id)| place_id));
+    }
+
+    let excess_pages = match policy.max_pages {
+        Some(max_pages) => {
+            let num_pages = db.query_one::<i64>("SELECT COUNT(*) FROM moz_places")? as usize;
+            num_pages.saturating_sub(max_pages)
+        }
+        None => 0,
+    };
+    let too_big = match policy.max_db_size {
+        Some(max_db_size) => used_db_size(db)? > max_db_size,
+        None => false,
+    };
+    let num_to_expire = if too_big {
+        EXPIRATION_CHUNK_SIZE
+    } else {
+        excess_pages.min(EXPIRATION_CHUNK_SIZE)
+    };
+    if num_to_expire > 0 {
+        let pages = db.query_rows_and_then_named_cached(
+            "SELECT id FROM moz_places
+             WHERE foreign_count = 0
+             ORDER BY hidden DESC,
+                      frecency ASC,
+                      MAX(last_visit_date_local, last_visit_date_remote) ASC
+             LIMIT :limit",
+            &[(":limit", &(num_to_expire as i64))],
+            |row| row.get::<_, RowId>(0),
+        )?;
+        scope.err_if_interrupted()?;
+        sql_support::each_chunk(&pages, |chunk, _| -> Result<()> {
+            report.expired_visits += db.execute(
+                &format!(
+                    "DELETE FROM moz_historyvisits WHERE place_id IN ({})",
+                    sql_support::repeat_sql_vars(chunk.len())
+                ),
+                chunk,
+            )?;
+            Ok(())
+        })?;
+        if (too_big || excess_pages > pages.len()) && pages.len() == num_to_expire {
+            report.finished = false;
+        }
+        affected_pages.extend(pages);
+    }
+
+    affected_pages.sort();
+    affected_pages.dedup();
+    scope.err_if_interrupted()?;
+    sql_support::each_chunk(&affected_pages, |chunk, _| -> Result<()> {
+        let vars = sql_support::repeat_sql_vars(chunk.len());
+        // Stale frecencies reference their pages, so remove them first.
+        db.execute(
+            &format!(
+                "DELETE FROM moz_places_stale_frecencies
+                 WHERE place_id IN (
+                   SELECT id FROM moz_places
+                   WHERE id IN ({})
+                     AND foreign_count = 0
+                     AND last_visit_date_local = 0
+                     AND last_visit_date_remote = 0
+                 )",
+                vars
+            ),
+            chunk,
+        )?;
+        report.expired_pages += db.execute(
+            &format!(
+                "DELETE FROM moz_places
+                 WHERE id IN ({})
+                   AND foreign_count = 0
+                   AND last_visit_date_local = 0
+                   AND last_visit_date_remote = 0",
+                vars
+            ),
+            chunk,
+        )?;
+        Ok(())
+    })?;
+    // The pages that are left lost some visits, so their frecencies changed.
+    sql_support::each_chunk(&affected_pages, |chunk, _| -> Result<()> {
+        let remaining_pages = db.query_rows_and_then_named(
+            &format!(
+                "SELECT id FROM moz_places WHERE id IN ({})",
+                sql_support::repeat_display(chunk.len(), ",", |index, f| write!(
+                    f,
+                    "{}",
+                    chunk[index].0
+                ))
+            ),
+            &[],
+            |row| row.get::<_, RowId>(0),
+        )?;
+        for page_id in remaining_pages {
+            scope.err_if_interrupted()?;
+            update_frecency(db, page_id, None)?;
+        }
+        Ok(())
+    })?;
+    delete_pending_temp_tables(db)?;
+
+    if report.expired_visits > 0 || report.expired_pages > 0 {
+        db.notify(PlacesEvent::HistoryChanged);
+    }
+    tx.commit()?;
+    log::info!("Expired history: {:?}", report);
+    Ok(report)
+}
+
+/// Returns the size of the database, not counting free pages.
+fn used_db_size(db: &PlacesDb) -> Result<u64> {
+    let page_count = db.query_one::<i64>("PRAGMA page_count")?;
+    let free_pages = db.query_one::<i64>("PRAGMA freelist_count")?;
+    let page_size = db.query_one::<i64>("PRAGMA page_size")?;
+    Ok(((page_count - free_pages) * page_size) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::bookmarks::{
+        insert_bookmark, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+    };
+    use crate::storage::history::{apply_observation, set_page_hidden};
+    use crate::types::VisitTransition;
+    use pretty_assertions::assert_eq;
+    use url::Url;
+
+    const DAY: u64 = 24 * 60 * 60 * 1000;
+
+    fn visit(conn: &PlacesDb, url: &str, at: Timestamp, visit_type: VisitTransition) {
+        apply_observation(
+            conn,
+            VisitObservation::new(Url::parse(url).unwrap())
+                .with_visit_type(visit_type)
+                .with_at(at),
+        )
+        .expect("Should apply visit");
+    }
+
+    fn page_urls(conn: &PlacesDb) -> Vec<String> {
+        conn.query_rows_and_then_named("SELECT url FROM moz_places ORDER BY url", &[], |row| {
+            row.get::<_, String>(0)
+        })
+        .expect("Should fetch page URLs")
+    }
+
+    #[test]
+    fn test_expire_by_age() -> Result<()> {
+        let conn = new_mem_connection();
+        let now = Timestamp(1_600_000_000_000);
+        let old = Timestamp(now.0 - 100 * DAY);
+        let recent = Timestamp(now.0 - DAY);
+        visit(&conn, "https://example.com/old", old, VisitTransition::Link);
+        visit(
+            &conn,
+            "https://example.com/both",
+            old,
+            VisitTransition::Link,
+        );
+        visit(
+            &conn,
+            "https://example.com/both",
+            recent,
+            VisitTransition::Link,
+        );
+        visit(
+            &conn,
+            "https://example.com/bookmarked",
+            old,
+            VisitTransition::Link,
+        );
+        visit(
+            &conn,
+            "https://example.com/recent",
+            recent,
+            VisitTransition::Link,
+        );
+        insert_bookmark(
+            &conn,
+            &InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: Url::parse("https://example.com/bookmarked")?,
+                title: None,
+            }
+            .into(),
+        )?;
+
+        let policy = ExpirationPolicy {
+            max_pages: None,
+            max_age: Some(Duration::from_millis(30 * DAY)),
+            max_db_size: None,
+        };
+        let scope = conn.begin_interrupt_scope();
+        let report = expire_history(&conn, &policy, now, &scope)?;
+        assert_eq!(
+            report,
+            ExpirationReport {
+                expired_visits: 3,
+                expired_pages: 1,
+                finished: true,
+            }
+        );
+        assert_eq!(
+            page_urls(&conn),
+            vec![
+                "https://example.com/bookmarked",
+                "https://example.com/both",
+                "https://example.com/recent",
+            ]
+        );
+        let num_visits = conn.query_one::<i64>("SELECT COUNT(*) FROM moz_historyvisits")?;
+        assert_eq!(num_visits, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_expire_by_page_count() -> Result<()> {
+        let conn = new_mem_connection();
+        let now = Timestamp(1_600_000_000_000);
+        visit(
+            &conn,
+            "https://example.com/typed",
+            Timestamp(now.0 - 3 * DAY),
+            VisitTransition::Typed,
+        );
+        visit(
+            &conn,
+            "https://example.com/link",
+            Timestamp(now.0 - 2 * DAY),
+            VisitTransition::Link,
+        );
+        visit(
+            &conn,
+            "https://example.com/hidden",
+            Timestamp(now.0 - DAY),
+            VisitTransition::Typed,
+        );
+        set_page_hidden(&conn, &Url::parse("https://example.com/hidden")?, true)?;
+
+        let policy = ExpirationPolicy {
+            max_pages: Some(1),
+            max_age: None,
+            max_db_size: None,
+        };
+        let scope = conn.begin_interrupt_scope();
+        let report = expire_history(&conn, &policy, now, &scope)?;
+        assert_eq!(
+            report,
+            ExpirationReport {
+                expired_visits: 2,
+                expired_pages: 2,
+                finished: true,
+            }
+        );
+        // Hidden pages go first, then the least frecent.
+        assert_eq!(page_urls(&conn), vec!["https://example.com/typed"]);
+
+        // Nothing to do the second time.
+        let report = expire_history(&conn, &policy, now, &scope)?;
+        assert_eq!(
+            report,
+            ExpirationReport {
+                expired_visits: 0,
+                expired_pages: 0,
+                finished: true,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_expire_by_size() -> Result<()> {
+        let conn = new_mem_connection();
+        let now = Timestamp(1_600_000_000_000);
+        visit(
+            &conn,
+            "https://example.com/a",
+            Timestamp(now.0 - DAY),
+            VisitTransition::Link,
+        );
+        visit(
+            &conn,
+            "https://example.com/b",
+            Timestamp(now.0 - DAY),
+            VisitTransition::Link,
+        );
+
+        // The database is never this small, so we expire everything we can.
+        let policy = ExpirationPolicy {
+            max_pages: None,
+            max_age: None,
+            max_db_size: Some(0),
+        };
+        let scope = conn.begin_interrupt_scope();
+        let report = expire_history(&conn, &policy, now, &scope)?;
+        assert_eq!(
+            report,
+            ExpirationReport {
+                expired_visits: 2,
+                expired_pages: 2,
+                finished: true,
+            }
+        );
+        assert!(page_urls(&conn).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_expire_in_chunks() -> Result<()> {
+        let conn = new_mem_connection();
+        let now = Timestamp(1_600_000_000_000);
+        let num_pages = EXPIRATION_CHUNK_SIZE + 50;
+        for i in 0..num_pages {
+            visit(
+                &conn,
+                &format!("https://example.com/{}", i),
+                Timestamp(now.0 - DAY + i as u64),
+                VisitTransition::Link,
+            );
+        }
+
+        let policy = ExpirationPolicy {
+            max_pages: Some(10),
+            max_age: None,
+            max_db_size: None,
+        };
+        let scope = conn.begin_interrupt_scope();
+        let report = expire_history(&conn, &policy, now, &scope)?;
+        assert_eq!(report.expired_pages, EXPIRATION_CHUNK_SIZE);
+        assert!(!report.finished);
+
+        let report = expire_history(&conn, &policy, now, &scope)?;
+        assert_eq!(report.expired_pages, num_pages - EXPIRATION_CHUNK_SIZE - 10);
+        assert!(report.finished);
+        assert_eq!(page_urls(&conn).len(), 10);
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Favicons for pages and origins, so that consumers don't need to keep their
+//! own icon cache. An icon can be associated with a single page, or with an
+//! origin, for icons like `/favicon.ico` that are used by every page on the
+//! site. Icons are removed when the last page or origin using them is removed,
+//! so removing history also removes the icons for those pages.
+
+use super::{fetch_page_info, RowId};
+use crate::db::PlacesDb;
+use crate::error::{InvalidPlaceInfo, Result};
+use crate::types::Timestamp;
+use rusqlite::Row;
+use sql_support::ConnExt;
+use std::time::Duration;
+use url::Url;
+
+/// How long icons without an explicit expiration time are kept, like Desktop.
+pub const DEFAULT_ICON_EXPIRATION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Icon {
+    pub url: Url,
+    /// The width of the icon in pixels, or 0 if unknown.
+    pub width: u32,
+    /// The icon data, or None if the app stores it elsewhere.
+    pub data: Option<Vec<u8>>,
+    pub expires_at: Timestamp,
+}
+
+impl Icon {
+    /// Creates an icon without data, which expires after the default time.
+    pub fn new(url: Url, width: u32) -> Self {
+        Self {
+            url,
+            width,
+            data: None,
+            expires_at: Timestamp::now()
+                .checked_add(DEFAULT_ICON_EXPIRATION)
+                .unwrap_or_else(Timestamp::now),
+        }
+    }
+
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        let url: String = row.get("icon_url")?;
+        Ok(Self {
+            url: Url::parse(&url)?,
+            width: row.get("width")?,
+            data: row.get("data")?,
+            expires_at: row.get("expires_at")?,
+        })
+    }
+}
+
+/// Inserts or updates an icon, returning its row id. Updating an icon
+/// without data keeps its existing data.
+fn upsert_icon(db: &PlacesDb, icon: &Icon) -> Result<RowId> {
+    db.execute_named_cached(
+        "INSERT INTO moz_icons(icon_url, width, data, expires_at)
+         VALUES(:icon_url, :width, :data, :expires_at)
+         ON CONFLICT(icon_url) DO UPDATE SET
+             width = excluded.width,
+             data = IFNULL(excluded.data, data),
+             expires_at = excluded.expires_at",
+        &[
+            (":icon_url", &icon.url.as_str()),
+            (":width", &icon.width),
+            (":data", &icon.data),
+            (":expires_at", &icon.expires_at),
+        ],
+    )?;
+    Ok(db.query_row_named(
+        "SELECT id FROM moz_icons WHERE icon_url = :icon_url",
+        &[(":icon_url", &icon.url.as_str())],
+        |row| row.get(0),
+    )?)
+}
+
+/// Associates an icon with the specified page.
+///
+/// # Arguments
+///
+/// * `conn` - A database connection on which to operate.
+///
+/// * `page_url` - The page, which must be in history or bookmarked.
+///
+/// * `icon` - The icon. This is added to any existing icons for the page,
+///   so that the page can have icons with different sizes.
+///
+/// # Returns
+///
+/// There is no success return value.
+pub fn set_icon_for_page(db: &PlacesDb, page_url: &Url, icon: &Icon) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    set_icon_for_page_in_tx(db, page_url, icon)?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub(crate) fn set_icon_for_page_in_tx(db: &PlacesDb, page_url: &Url, icon: &Icon) -> Result<()> {
+    let place_id = match fetch_page_info(db, page_url)? {
+        Some(info) => info.page.row_id,
+        None => return Err(InvalidPlaceInfo::NoSuchUrl.into()),
+    };
+    let icon_id = upsert_icon(db, icon)?;
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_icons_to_pages(place_id, icon_id)
+         VALUES(:place_id, :icon_id)",
+        &[(":place_id", &place_id), (":icon_id", &icon_id)],
+    )?;
+    Ok(())
+}
+
+/// Associates an icon with the origin of the specified page, so that it's
+/// used for every page on the site that doesn't have its own icon.
+///
+/// # Arguments
+///
+/// * `conn` - A database connection on which to operate.
+///
+/// * `page_url` - Any page with the origin. At least one page with the
+///   origin must be in history or bookmarked.
+///
+/// * `icon` - The icon.
+///
+/// # Returns
+///
+/// There is no success return value.
+pub fn set_icon_for_origin(db: &PlacesDb, page_url: &Url, icon: &Icon) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    let origin_id = db.try_query_row(
+        "SELECT id FROM moz_origins
+         WHERE prefix = get_prefix(:url) AND
+               host = get_host_and_port(:url)",
+        &[(":url", &page_url.as_str())],
+        |row| row.get::<_, RowId>(0),
+        true,
+    )?;
+    let origin_id = match origin_id {
+        Some(origin_id) => origin_id,
+        None => return Err(InvalidPlaceInfo::NoSuchUrl.into()),
+    };
+    let icon_id = upsert_icon(db, icon)?;
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_icons_to_origins(origin_id, icon_id)
+         VALUES(:origin_id, :icon_id)",
+        &[(":origin_id", &origin_id), (":icon_id", &icon_id)],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+// Icons for the page itself are preferred over icons for its origin. Then we
+// prefer the smallest icon that's at least `:width` wide, falling back to the
+// largest smaller icon.
+const GET_ICON_FOR_PAGE_SQL: &str = "
+    SELECT icon_url, width, data, expires_at FROM (
+        SELECT i.icon_url, i.width, i.data, i.expires_at, 0 AS is_origin
+        FROM moz_icons i
+        JOIN moz_icons_to_pages p ON p.icon_id = i.id
+        JOIN moz_places h ON h.id = p.place_id
+        WHERE h.url_hash = hash(:url) AND h.url = :url
+        UNION ALL
+        SELECT i.icon_url, i.width, i.data, i.expires_at, 1 AS is_origin
+        FROM moz_icons i
+        JOIN moz_icons_to_origins r ON r.icon_id = i.id
+        JOIN moz_origins o ON o.id = r.origin_id
+        WHERE o.prefix = get_prefix(:url) AND
+              o.host = get_host_and_port(:url)
+    )
+    ORDER BY is_origin, width >= :width DESC, ABS(width - :width)
+    LIMIT 1";
+
+/// Retrieves the best icon for the specified page.
+///
+/// # Arguments
+///
+/// * `conn` - A database connection on which to operate.
+///
+/// * `page_url` - The page to query.
+///
+/// * `preferred_width` - The width the icon will be shown at, in pixels.
+///
+/// # Returns
+///
+/// * The icon for the page, or the icon for its origin if the page doesn't
+///   have one. Expired icons are returned too, so the app can show them
+///   while it fetches a new one.
+pub fn get_icon_for_page(
+    db: &PlacesDb,
+    page_url: &Url,
+    preferred_width: u32,
+) -> Result<Option<Icon>> {
+    db.try_query_row(
+        GET_ICON_FOR_PAGE_SQL,
+        &[(":url", &page_url.as_str()), (":width", &preferred_width)],
+        Icon::from_row,
+        true,
+    )
+}
+
+/// Like `get_icon_for_page`, but only returns the icon's URL.
+pub fn get_icon_url_for_page(
+    db: &PlacesDb,
+    page_url: &Url,
+    preferred_width: u32,
+) -> Result<Option<Url>> {
+    Ok(get_icon_for_page(db, page_url, preferred_width)?.map(|icon| icon.url))
+}
+
+/// Removes all icons for the specified page. Icons for its origin are kept.
+pub fn remove_icons_for_page(db: &PlacesDb, page_url: &Url) -> Result<()> {
+    db.execute_named_cached(
+        "DELETE FROM moz_icons_to_pages
+         WHERE place_id = (SELECT id FROM moz_places
+                           WHERE url_hash = hash(:url) AND url = :url)",
+        &[(":url", &page_url.as_str())],
+    )?;
+    Ok(())
+}
+
+/// Removes all icons that have expired, so that they can be fetched again.
+/// Returns the number of icons removed.
+pub fn delete_expired_icons(db: &PlacesDb) -> Result<usize> {
+    Ok(db.execute_named_cached("DELETE FROM moz_icons WHERE expires_at < now()", &[])?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::history::{apply_observation, delete_everything};
+    use crate::types::VisitTransition;
+
+    fn visit(db: &PlacesDb, url: &Url) {
+        apply_observation(
+            db,
+            VisitObservation::new(url.clone())
+                .with_at(Timestamp::now())
+                .with_visit_type(VisitTransition::Link),
+        )
+        .expect("should apply observation");
+    }
+
+    fn icon(url: &str, width: u32) -> Icon {
+        Icon::new(Url::parse(url).unwrap(), width)
+    }
+
+    fn count_icons(db: &PlacesDb) -> i64 {
+        db.query_one("SELECT COUNT(*) FROM moz_icons").unwrap()
+    }
+
+    #[test]
+    fn test_page_icons() -> Result<()> {
+        let conn = new_mem_connection();
+        let page = Url::parse("https://example.com/page")?;
+        let unknown = Url::parse("https://example.org/page")?;
+        visit(&conn, &page);
+
+        assert_eq!(get_icon_for_page(&conn, &page, 16)?, None);
+        assert!(
+            set_icon_for_page(&conn, &unknown, &icon("https://example.org/i.png", 16)).is_err()
+        );
+
+        set_icon_for_page(&conn, &page, &icon("https://example.com/16.png", 16))?;
+        set_icon_for_page(&conn, &page, &icon("https://example.com/32.png", 32))?;
+        set_icon_for_page(&conn, &page, &icon("https://example.com/64.png", 64))?;
+        let url = |width| {
+            get_icon_url_for_page(&conn, &page, width)
+                .unwrap()
+                .map(Url::into_string)
+        };
+        assert_eq!(url(16), Some("https://example.com/16.png".into()));
+        assert_eq!(url(20), Some("https://example.com/32.png".into()));
+        assert_eq!(url(128), Some("https://example.com/64.png".into()));
+
+        // Updating an icon without data keeps its data.
+        let mut with_data = icon("https://example.com/16.png", 16);
+        with_data.data = Some(vec![1, 2, 3]);
+        set_icon_for_page(&conn, &page, &with_data)?;
+        set_icon_for_page(&conn, &page, &icon("https://example.com/16.png", 16))?;
+        let found = get_icon_for_page(&conn, &page, 16)?.expect("should have icon");
+        assert_eq!(found.data, Some(vec![1, 2, 3]));
+        assert_eq!(count_icons(&conn), 3);
+
+        remove_icons_for_page(&conn, &page)?;
+        assert_eq!(get_icon_for_page(&conn, &page, 16)?, None);
+        assert_eq!(count_icons(&conn), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_origin_icons() -> Result<()> {
+        let conn = new_mem_connection();
+        let page1 = Url::parse("https://example.com/1")?;
+        let page2 = Url::parse("https://example.com/2")?;
+        visit(&conn, &page1);
+        visit(&conn, &page2);
+
+        set_icon_for_origin(&conn, &page1, &icon("https://example.com/favicon.ico", 0))?;
+        assert!(set_icon_for_origin(
+            &conn,
+            &Url::parse("http://example.com")?,
+            &icon("http://example.com/favicon.ico", 0)
+        )
+        .is_err());
+
+        // Pages use the icon for their origin, even if they aren't in history.
+        for url in &[&page2, &Url::parse("https://example.com/3")?] {
+            assert_eq!(
+                get_icon_url_for_page(&conn, url, 16)?.map(Url::into_string),
+                Some("https://example.com/favicon.ico".into())
+            );
+        }
+
+        // ...unless they have their own icon.
+        set_icon_for_page(&conn, &page2, &icon("https://example.com/2.png", 32))?;
+        assert_eq!(
+            get_icon_url_for_page(&conn, &page2, 16)?.map(Url::into_string),
+            Some("https://example.com/2.png".into())
+        );
+
+        // Removing history removes the icons.
+        delete_everything(&conn)?;
+        assert_eq!(get_icon_for_page(&conn, &page2, 16)?, None);
+        assert_eq!(count_icons(&conn), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_expired_icons() -> Result<()> {
+        let conn = new_mem_connection();
+        let page = Url::parse("https://example.com/page")?;
+        visit(&conn, &page);
+
+        let mut expired = icon("https://example.com/old.png", 16);
+        expired.expires_at = Timestamp(1);
+        set_icon_for_page(&conn, &page, &expired)?;
+        set_icon_for_page(&conn, &page, &icon("https://example.com/new.png", 32))?;
+
+        delete_expired_icons(&conn)?;
+        assert_eq!(count_icons(&conn), 1);
+        assert_eq!(
+            get_icon_url_for_page(&conn, &page, 16)?.map(Url::into_string),
+            Some("https://example.com/new.png".into())
+        );
+        Ok(())
+    }
+}
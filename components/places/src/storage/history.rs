@@ -4,17 +4,19 @@
 
 use super::{fetch_page_info, new_page_info, PageInfo, RowId};
 use crate::db::PlacesDb;
-use crate::error::Result;
+use crate::error::{InvalidPlaceInfo, Result};
 use crate::frecency;
-use crate::hash;
+use crate::match_impl::{MatchBehavior, SearchBehavior};
 use crate::msg_types::{HistoryVisitInfo, HistoryVisitInfos};
 use crate::observation::VisitObservation;
+use crate::observer::PlacesEvent;
 use crate::storage::{delete_pending_temp_tables, get_meta, put_meta};
 use crate::types::{SyncStatus, Timestamp, VisitTransition, VisitTransitionSet};
 use rusqlite::types::ToSql;
 use rusqlite::Result as RusqliteResult;
 use rusqlite::{Row, NO_PARAMS};
-use sql_support::{self, ConnExt};
+use sql_support::{self, ConnExt, SqlInterruptScope};
+use std::collections::HashMap;
 use sync_guid::Guid as SyncGuid;
 use url::Url;
 
@@ -44,9 +46,9 @@ pub fn apply_observation_direct(
     if url.as_str().len() > super::URL_LENGTH_MAX {
         return Ok(None);
     }
-    let mut page_info = match fetch_page_info(db, &url)? {
-        Some(info) => info.page,
-        None => new_page_info(db, &url, None)?,
+    let (mut page_info, is_new_page) = match fetch_page_info(db, &url)? {
+        Some(info) => (info.page, false),
+        None => (new_page_info(db, &url, None)?, true),
     };
     let mut update_change_counter = false;
     let mut update_frec = false;
@@ -61,9 +63,13 @@ pub fn apply_observation_direct(
     // testing we return the rowid of the visit we added.
     let visit_row_id = match visit_ob.visit_type {
         Some(visit_type) => {
-            // A single non-hidden visit makes the place non-hidden.
+            // A single non-hidden visit makes the place non-hidden, and
+            // new places for hidden visits, like redirect sources, are
+            // hidden.
             if !visit_ob.get_is_hidden() {
                 updates.push(("hidden", ":hidden", &false));
+            } else if is_new_page {
+                updates.push(("hidden", ":hidden", &true));
             }
             if visit_type == VisitTransition::Typed {
                 page_info.typed += 1;
@@ -72,7 +78,17 @@ pub fn apply_observation_direct(
 
             let at = visit_ob.at.unwrap_or_else(Timestamp::now);
             let is_remote = visit_ob.is_remote.unwrap_or(false);
-            let row_id = add_visit(db, page_info.row_id, None, at, visit_type, !is_remote)?;
+            let from_visit = match visit_ob.referrer {
+                Some(ref referrer) => find_referring_visit(db, referrer, at)?,
+                None => None,
+            };
+            let row_id = add_visit(db, page_info.row_id, from_visit, at, visit_type, !is_remote)?;
+            db.notify(PlacesEvent::VisitAdded {
+                url: url.clone(),
+                page_guid: page_info.guid.clone(),
+                visit_date: at,
+                visit_type,
+            });
             // a new visit implies new frecency except in error cases.
             if !visit_ob.is_error.unwrap_or(false) {
                 update_frec = true;
@@ -134,6 +150,8 @@ pub fn update_frecency(db: &PlacesDb, id: RowId, redirect_boost: Option<bool>) -
         WHERE id = :page_id",
         &[(":frecency", &score), (":page_id", &id.0)],
     )?;
+    // Update the page's origin frecency, too.
+    delete_pending_temp_tables(db)?;
 
     Ok(())
 }
@@ -152,6 +170,163 @@ pub fn frecency_stale_at(db: &PlacesDb, url: &Url) -> Result<Option<Timestamp>>
     Ok(result)
 }
 
+/// The maximum number of URLs for which to recalculate frecencies at once.
+/// This is a trade-off between write efficiency and transaction time: higher
+/// maximums mean fewer write statements, but longer transactions, possibly
+/// blocking writes from other connections.
+const MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK: usize = 400;
+
+/// Recalculates the frecencies of all pages in `moz_places_stale_frecencies`,
+/// most recently marked first, and returns how many were recalculated.
+///
+/// This works in chunks, and checks `scope` between each page, so that it
+/// can be interrupted. It should be called on the sync connection, which
+/// commits periodically so that it doesn't block the main writer for too
+/// long. If it's interrupted, the pages that weren't recalculated yet stay
+/// marked as stale, and the next call picks up where this one left off.
+pub fn recalculate_stale_frecencies(db: &PlacesDb, scope: &SqlInterruptScope) -> Result<usize> {
+    let mut tx = db.begin_transaction()?;
+    let mut num_recalculated = 0;
+
+    let mut frecencies = Vec::with_capacity(MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK);
+    loop {
+        let sql = format!(
+            "SELECT place_id FROM moz_places_stale_frecencies
+             ORDER BY stale_at DESC
+             LIMIT {}",
+            MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK
+        );
+        let mut stmt = db.prepare_maybe_cached(&sql, true)?;
+        let mut results = stmt.query(NO_PARAMS)?;
+        while let Some(row) = results.next()? {
+            let place_id = row.get("place_id")?;
+            // Frecency recalculation runs several statements, so check to
+            // make sure we aren't interrupted before each calculation.
+            scope.err_if_interrupted()?;
+            let frecency = frecency::calculate_frecency(
+                db,
+                &frecency::DEFAULT_FRECENCY_SETTINGS,
+                place_id,
+                Some(false),
+            )?;
+            frecencies.push((place_id, frecency));
+        }
+        if frecencies.is_empty() {
+            break;
+        }
+
+        // Update all frecencies in one fell swoop...
+        db.execute_batch(&format!(
+            "WITH frecencies(id, frecency) AS (
+               VALUES {}
+             )
+             UPDATE moz_places SET
+               frecency = (SELECT frecency FROM frecencies f
+                           WHERE f.id = moz_places.id)
+             WHERE id IN (SELECT f.id FROM frecencies f)",
+            sql_support::repeat_display(frecencies.len(), ",", |index, f| {
+                let (id, frecency) = frecencies[index];
+                write!(f, "({}, {})", id, frecency)
+            })
+        ))?;
+        delete_pending_temp_tables(db)?;
+        db.notify(PlacesEvent::RankingChanged);
+        tx.maybe_commit()?;
+        scope.err_if_interrupted()?;
+
+        // ...And remove them from the stale table.
+        db.execute_batch(&format!(
+            "DELETE FROM moz_places_stale_frecencies
+             WHERE place_id IN ({})",
+            sql_support::repeat_display(frecencies.len(), ",", |index, f| {
+                let (id, _) = frecencies[index];
+                write!(f, "{}", id)
+            })
+        ))?;
+        num_recalculated += frecencies.len();
+        tx.maybe_commit()?;
+        scope.err_if_interrupted()?;
+
+        // If the query returned fewer URLs than the maximum, we're done.
+        // Otherwise, we might have more, so clear the ones we just
+        // recalculated and fetch the next chunk.
+        if frecencies.len() < MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK {
+            break;
+        }
+        frecencies.clear();
+    }
+
+    tx.commit()?;
+
+    Ok(num_recalculated)
+}
+
+const FRECENCY_LAST_DECAY_META_KEY: &str = "frecency_last_decay";
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Marks the frecencies of pages with visits that moved into an older bucket
+/// since we last did this as stale, and returns how many were marked. The
+/// first time, every page with a visit older than the first bucket is marked.
+///
+/// A page's frecency is only calculated when it's visited, but the weight of
+/// each visit depends on how many days ago it happened, so the frecencies of
+/// pages the user stopped visiting never decay on their own. Call
+/// `recalculate_stale_frecencies` afterward, or use
+/// `PlacesApi::update_frecencies`, which does both, to fix them up.
+pub fn decay_frecencies(db: &PlacesDb, now: Timestamp) -> Result<usize> {
+    let settings = &frecency::DEFAULT_FRECENCY_SETTINGS;
+    let last_decay = get_meta::<Timestamp>(db, FRECENCY_LAST_DECAY_META_KEY)?.unwrap_or_default();
+
+    let tx = db.begin_transaction()?;
+    let mut num_marked = 0;
+    for &cutoff_days in &[
+        settings.first_bucket_cutoff_days,
+        settings.second_bucket_cutoff_days,
+        settings.third_bucket_cutoff_days,
+        settings.fourth_bucket_cutoff_days,
+    ] {
+        // Ages are rounded to the nearest day, so we look a day further back
+        // to make sure we don't miss any visits that just crossed the cutoff.
+        // Marking a few extra pages is harmless.
+        let cutoff_ms = cutoff_days as u64 * MS_PER_DAY;
+        num_marked += db.execute_named_cached(
+            "INSERT OR IGNORE INTO moz_places_stale_frecencies(place_id, stale_at)
+             SELECT DISTINCT place_id, :now FROM moz_historyvisits
+             WHERE visit_date > :newest_at_last_decay AND
+                   visit_date <= :newest",
+            &[
+                (":now", &now),
+                (
+                    ":newest_at_last_decay",
+                    &(last_decay.as_millis() as i64 - (cutoff_ms + MS_PER_DAY) as i64),
+                ),
+                (":newest", &(now.as_millis() as i64 - cutoff_ms as i64)),
+            ],
+        )?;
+    }
+    put_meta(db, FRECENCY_LAST_DECAY_META_KEY, &now)?;
+    tx.commit()?;
+    Ok(num_marked)
+}
+
+// Returns the most recent visit to `referrer` at or before `at`, which is
+// the visit that led to a new visit at `at`. Like Desktop, we use this to
+// link the hops in a redirect chain.
+fn find_referring_visit(db: &PlacesDb, referrer: &str, at: Timestamp) -> Result<Option<RowId>> {
+    Ok(db.try_query_row(
+        "SELECT v.id FROM moz_historyvisits v
+         JOIN moz_places h ON h.id = v.place_id
+         WHERE h.url_hash = hash(:referrer) AND
+               h.url = :referrer AND
+               v.visit_date <= :at
+         ORDER BY v.visit_date DESC
+         LIMIT 1",
+        &[(":referrer", &referrer), (":at", &at)],
+        |row| row.get::<_, RowId>(0),
+        true,
+    )?)
+}
+
 // Add a single visit - you must know the page rowid. Does not update the
 // page info - if you are calling this, you will also need to update the
 // parent page with an updated change counter etc.
@@ -217,8 +392,13 @@ fn do_delete_place_by_guid(db: &PlacesDb, guid: &SyncGuid) -> Result<()> {
     db.execute_named_cached(sql, &[(":guid", guid), (":status", &SyncStatus::Normal)])?;
     // and try the delete - it might not exist, but that's ok.
     let delete_sql = "DELETE FROM moz_places WHERE guid = :guid";
-    db.execute_named_cached(delete_sql, &[(":guid", guid)])?;
+    let num_deleted = db.execute_named_cached(delete_sql, &[(":guid", guid)])?;
     delete_pending_temp_tables(db)?;
+    if num_deleted > 0 {
+        db.notify(PlacesEvent::PageRemoved {
+            page_guid: guid.clone(),
+        });
+    }
     Ok(())
 }
 
@@ -260,16 +440,19 @@ pub fn prune_destructively(db: &PlacesDb) -> Result<()> {
 
 pub fn wipe_local(db: &PlacesDb) -> Result<()> {
     let tx = db.begin_transaction()?;
+    db.execute_cached("DELETE FROM moz_places_tombstones", NO_PARAMS)?;
     wipe_local_in_tx(db, tx)?;
     Ok(())
 }
 
+/// Wipes all history, leaving pages that are bookmarked or otherwise
+/// referenced. Place tombstones are left alone, so callers must add or
+/// remove them first.
 fn wipe_local_in_tx(db: &PlacesDb, tx: crate::db::PlacesTransaction<'_>) -> Result<()> {
     use crate::frecency::DEFAULT_FRECENCY_SETTINGS;
     db.execute_all(&[
         "DELETE FROM moz_places WHERE foreign_count == 0",
         "DELETE FROM moz_historyvisits",
-        "DELETE FROM moz_places_tombstones",
         "DELETE FROM moz_inputhistory",
         "DELETE FROM moz_historyvisit_tombstones",
         "DELETE FROM moz_origins
@@ -277,7 +460,9 @@ fn wipe_local_in_tx(db: &PlacesDb, tx: crate::db::PlacesTransaction<'_>) -> Resu
         &format!(
             "UPDATE moz_places SET
                 frecency = {unvisited_bookmark_frec},
-                sync_change_counter = 0",
+                sync_change_counter = 0,
+                description = NULL,
+                preview_image_url = NULL",
             unvisited_bookmark_frec = DEFAULT_FRECENCY_SETTINGS.unvisited_bookmark_bonus
         ),
     ])?;
@@ -290,12 +475,16 @@ fn wipe_local_in_tx(db: &PlacesDb, tx: crate::db::PlacesTransaction<'_>) -> Resu
         update_frecency(db, row_id, None)?;
     }
     delete_pending_temp_tables(db)?;
+    db.notify(PlacesEvent::HistoryCleared);
     tx.commit()?;
     // Note: SQLite cannot VACUUM within a transaction.
     db.conn().execute("VACUUM", NO_PARAMS)?;
     Ok(())
 }
 
+/// Deletes all history, like `wipe_local`, and makes sure that synced history
+/// is also deleted from the server on the next sync, and that older visits
+/// from other clients aren't applied again.
 pub fn delete_everything(db: &PlacesDb) -> Result<()> {
     let tx = db.begin_transaction()?;
 
@@ -314,6 +503,21 @@ pub fn delete_everything(db: &PlacesDb) -> Result<()> {
 
     put_meta(db, DELETION_HIGH_WATER_MARK_META_KEY, &new_mark)?;
 
+    // Write tombstones for every page we've synced, so that the next sync
+    // removes them from the server, too. Otherwise, the pages and their old
+    // visits would stay on the server, and other clients would resurrect
+    // them. Bookmarked pages aren't removed locally, so they can't have
+    // tombstones: their history records are replaced on the server the next
+    // time they're visited, and the high water mark keeps their old visits
+    // from coming back in the meantime.
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_places_tombstones(guid)
+         SELECT guid FROM moz_places
+         WHERE sync_status = :status
+           AND foreign_count = 0",
+        &[(":status", &SyncStatus::Normal)],
+    )?;
+
     wipe_local_in_tx(db, tx)?;
     Ok(())
 }
@@ -367,6 +571,7 @@ fn delete_place_visit_at_time_in_tx(db: &PlacesDb, url: &str, visit_date: Timest
 
     cleanup_pages(db, &[to_clean])?;
     delete_pending_temp_tables(db)?;
+    db.notify(PlacesEvent::HistoryChanged);
     Ok(())
 }
 
@@ -440,6 +645,9 @@ pub fn delete_visits_between_in_tx(db: &PlacesDb, start: Timestamp, end: Timesta
         },
     )?;
     delete_pending_temp_tables(db)?;
+    if !visits.is_empty() {
+        db.notify(PlacesEvent::HistoryChanged);
+    }
     Ok(())
 }
 
@@ -986,43 +1194,50 @@ where
 ///
 /// This is done so that the FFI can more easily support returning
 /// false when asked if it's visited an invalid URL.
+///
+/// GeckoView asks about every link on a page at once, and pages often link
+/// to the same URL many times, so we only look up each URL once. The query
+/// only depends on the number of URLs in a chunk, so every full chunk reuses
+/// the same cached statement.
 pub fn get_visited_into(
     db: &PlacesDb,
     urls_idxs: &[(usize, Url)],
     result: &mut [bool],
 ) -> Result<()> {
-    sql_support::each_chunk_mapped(
-        &urls_idxs,
-        |(_, url)| url.as_str(),
-        |chunk, offset| -> Result<()> {
-            let values_with_idx = sql_support::repeat_display(chunk.len(), ",", |i, f| {
-                let (idx, url) = &urls_idxs[i + offset];
-                write!(f, "({},{},?)", *idx, hash::hash_url(url.as_str()))
-            });
-            let sql = format!(
-                "WITH to_fetch(fetch_url_index, url_hash, url) AS (VALUES {})
-                 SELECT fetch_url_index
-                 FROM moz_places h
-                 JOIN to_fetch f ON h.url_hash = f.url_hash
-                   AND h.url = f.url",
-                values_with_idx
-            );
-            let mut stmt = db.prepare(&sql)?;
-            for idx_r in stmt.query_and_then(chunk, |row| -> rusqlite::Result<_> {
-                Ok(row.get::<_, i64>(0)? as usize)
-            })? {
-                let idx = idx_r?;
-                result[idx] = true;
+    let scope = db.begin_interrupt_scope();
+    let mut idxs_for_url: HashMap<&str, Vec<usize>> = HashMap::with_capacity(urls_idxs.len());
+    for (idx, url) in urls_idxs {
+        idxs_for_url.entry(url.as_str()).or_default().push(*idx);
+    }
+    let urls = idxs_for_url.keys().cloned().collect::<Vec<_>>();
+    sql_support::each_chunk(&urls, |chunk, _| -> Result<()> {
+        scope.err_if_interrupted()?;
+        let sql = format!(
+            "WITH to_fetch(url) AS (VALUES {})
+             SELECT h.url
+             FROM to_fetch f
+             JOIN moz_places h ON h.url_hash = hash(f.url)
+               AND h.url = f.url",
+            sql_support::repeat_sql_values(chunk.len())
+        );
+        let mut stmt = db.prepare_cached(&sql)?;
+        let mut rows = stmt.query(chunk)?;
+        while let Some(row) = rows.next()? {
+            let url = row.get::<_, String>(0)?;
+            if let Some(idxs) = idxs_for_url.get(url.as_str()) {
+                for &idx in idxs {
+                    result[idx] = true;
+                }
             }
-            Ok(())
-        },
-    )?;
+        }
+        Ok(())
+    })?;
     Ok(())
 }
 
 /// Get the set of urls that were visited between `start` and `end`. Only considers local visits
 /// unless you pass in `include_remote`.
-pub fn get_visited_urls(
+pub fn get_visited_urls_in_range(
     db: &PlacesDb,
     start: Timestamp,
     end: Timestamp,
@@ -1056,6 +1271,7 @@ pub fn get_visit_infos(
     end: Timestamp,
     exclude_types: VisitTransitionSet,
 ) -> Result<HistoryVisitInfos> {
+    let scope = db.begin_interrupt_scope();
     let allowed_types = exclude_types.complement();
     let infos = db.query_rows_and_then_named_cached(
         "SELECT h.url, h.title, v.visit_date, v.visit_type
@@ -1072,6 +1288,7 @@ pub fn get_visit_infos(
         },
         HistoryVisitInfo::from_row,
     )?;
+    scope.err_if_interrupted()?;
     Ok(HistoryVisitInfos { infos })
 }
 
@@ -1100,6 +1317,7 @@ pub fn get_visit_page(
     count: i64,
     exclude_types: VisitTransitionSet,
 ) -> Result<HistoryVisitInfos> {
+    let scope = db.begin_interrupt_scope();
     let allowed_types = exclude_types.complement();
     let infos = db.query_rows_and_then_named_cached(
         "SELECT h.url, h.title, v.visit_date, v.visit_type
@@ -1117,9 +1335,253 @@ pub fn get_visit_page(
         },
         HistoryVisitInfo::from_row,
     )?;
+    scope.err_if_interrupted()?;
     Ok(HistoryVisitInfos { infos })
 }
 
+/// How many hops we follow back from a redirect target. This is only to stop
+/// us looping forever if the visits form a cycle; real chains are short.
+const MAX_REDIRECT_HOPS: u32 = 10;
+
+/// Returns the pages that redirected to `url`, starting with the page that
+/// redirected to it directly, and ending with the page the chain started
+/// on. If `url` was reached from more than one chain, sources from all of
+/// them are returned, nearest first. History UIs can use this to show a
+/// single entry for the whole chain, instead of one for every hop.
+pub fn get_redirect_sources(db: &PlacesDb, url: &Url) -> Result<Vec<Url>> {
+    let scope = db.begin_interrupt_scope();
+    // We step back from a visit to the visit it came from, but only if the
+    // visit was a redirect. The source that started the chain was reached
+    // some other way, like a link, so we stop there.
+    let urls = db.query_rows_and_then_named_cached(
+        &format!(
+            "WITH RECURSIVE
+             sources(visitId, fromVisit, visitType, hops) AS (
+               SELECT v.id, v.from_visit, v.visit_type, 0
+               FROM moz_historyvisits v
+               JOIN moz_places h ON h.id = v.place_id
+               WHERE h.url_hash = hash(:url) AND
+                     h.url = :url
+               UNION
+               SELECT v.id, v.from_visit, v.visit_type, s.hops + 1
+               FROM moz_historyvisits v
+               JOIN sources s ON s.fromVisit = v.id
+               WHERE s.visitType IN ({permanent}, {temporary}) AND
+                     s.hops < {max_hops}
+             )
+             SELECT h.url
+             FROM sources s
+             JOIN moz_historyvisits v ON v.id = s.visitId
+             JOIN moz_places h ON h.id = v.place_id
+             WHERE s.hops > 0 AND
+                   h.url <> :url
+             GROUP BY h.id
+             ORDER BY MIN(s.hops), h.url",
+            permanent = VisitTransition::RedirectPermanent as u8,
+            temporary = VisitTransition::RedirectTemporary as u8,
+            max_hops = MAX_REDIRECT_HOPS,
+        ),
+        &[(":url", &url.as_str())],
+        |row| -> Result<Url> { Ok(Url::parse(&row.get::<_, String>(0)?)?) },
+    )?;
+    scope.err_if_interrupted()?;
+    Ok(urls)
+}
+
+/// Hides or shows a page in history. Hidden pages, like the sources of
+/// redirects, are left out of `query_history`, top sites, and URL
+/// autofill, but their visits are still kept and synced. A page that's
+/// hidden becomes visible again if it's visited without a redirect. Returns
+/// true if the page exists.
+pub fn set_page_hidden(db: &PlacesDb, url: &Url, hidden: bool) -> Result<bool> {
+    let changes = db.execute_named_cached(
+        "UPDATE moz_places SET
+            hidden = :hidden
+         WHERE url_hash = hash(:url) AND
+               url = :url AND
+               hidden <> :hidden",
+        &[(":url", &url.as_str()), (":hidden", &hidden)],
+    )?;
+    if changes > 0 {
+        db.notify(PlacesEvent::HistoryChanged);
+        return Ok(true);
+    }
+    Ok(url_to_guid(db, url)?.is_some())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistorySearchResult {
+    pub url: Url,
+    pub title: Option<String>,
+    /// The most recent local or remote visit.
+    pub last_visit_date: Timestamp,
+    pub frecency: i32,
+}
+
+impl HistorySearchResult {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        let url: String = row.get("url")?;
+        Ok(Self {
+            url: Url::parse(&url)?,
+            title: row.get("title")?,
+            last_visit_date: row.get("last_visit_date")?,
+            frecency: row.get("frecency")?,
+        })
+    }
+}
+
+/// Searches history for pages with every word of `text` in their URL or
+/// title, ignoring case, for the search box in a history panel. Unlike
+/// `search_frecent`, this doesn't autofill, use adaptive matches or include
+/// unvisited bookmarks. Results are ordered by frecency, so the pages the
+/// user visits most come first. Like `search_frecent`, this can be canceled
+/// with the connection's interrupt handle.
+pub fn query_history(db: &PlacesDb, text: &str, limit: u32) -> Result<Vec<HistorySearchResult>> {
+    if text.trim().is_empty() {
+        return Ok(Vec::new());
+    }
+    let scope = db.begin_interrupt_scope();
+    let results = db.query_rows_and_then_named_cached(
+        "SELECT h.url, h.title, h.frecency,
+                MAX(h.last_visit_date_local, h.last_visit_date_remote) AS last_visit_date
+         FROM moz_places h
+         WHERE h.hidden = 0
+           AND AUTOCOMPLETE_MATCH(:text, h.url, IFNULL(h.title, ''), '',
+                                  h.visit_count_local + h.visit_count_remote,
+                                  h.typed, 0, 0,
+                                  :match_behavior, :search_behavior)
+         ORDER BY h.frecency DESC, last_visit_date DESC, h.id DESC
+         LIMIT :limit",
+        &[
+            (":text", &text),
+            (":match_behavior", &MatchBehavior::Anywhere),
+            (
+                ":search_behavior",
+                &(SearchBehavior::HISTORY | SearchBehavior::RESTRICT),
+            ),
+            (":limit", &limit),
+        ],
+        HistorySearchResult::from_row,
+    )?;
+    scope.err_if_interrupted()?;
+    Ok(results)
+}
+
+/// Metadata for a page, like the description and preview image from its
+/// `<meta>` tags, for showing rich content in new tab tiles and share sheets.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PageMetadata {
+    pub description: Option<String>,
+    pub preview_image_url: Option<Url>,
+}
+
+impl PageMetadata {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        let preview_image_url: Option<String> = row.get("preview_image_url")?;
+        Ok(Self {
+            description: row.get("description")?,
+            // Ignore invalid URLs instead of failing, since we'd only show
+            // a broken image for them anyway.
+            preview_image_url: preview_image_url.and_then(|url| Url::parse(&url).ok()),
+        })
+    }
+}
+
+/// Stores the metadata for a page in history, replacing any existing
+/// metadata. Descriptions are truncated, like titles, and preview image URLs
+/// that are too long are ignored. Metadata isn't synced.
+pub fn note_page_metadata(
+    db: &PlacesDb,
+    url: &Url,
+    description: Option<&str>,
+    preview_image_url: Option<&Url>,
+) -> Result<()> {
+    let description = description
+        .map(str::trim)
+        .filter(|d| !d.is_empty())
+        .map(|d| crate::util::slice_up_to(d, super::DESCRIPTION_LENGTH_MAX));
+    let preview_image_url = preview_image_url
+        .map(Url::as_str)
+        .filter(|u| u.len() <= super::URL_LENGTH_MAX);
+    let changes = db.execute_named_cached(
+        "UPDATE moz_places SET
+             description = :description,
+             preview_image_url = :preview_image_url
+         WHERE url_hash = hash(:url) AND url = :url",
+        &[
+            (":description", &description),
+            (":preview_image_url", &preview_image_url),
+            (":url", &url.as_str()),
+        ],
+    )?;
+    if changes == 0 {
+        return Err(InvalidPlaceInfo::NoSuchUrl.into());
+    }
+    Ok(())
+}
+
+/// Returns the metadata for a page, or None if the page isn't in history.
+pub fn get_page_metadata(db: &PlacesDb, url: &Url) -> Result<Option<PageMetadata>> {
+    db.try_query_row(
+        "SELECT description, preview_image_url FROM moz_places
+         WHERE url_hash = hash(:url) AND url = :url",
+        &[(":url", &url.as_str())],
+        PageMetadata::from_row,
+        true,
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopFrecentSiteInfo {
+    /// The most frecent page for the site.
+    pub url: Url,
+    pub title: Option<String>,
+    pub frecency: i32,
+    pub metadata: PageMetadata,
+}
+
+impl TopFrecentSiteInfo {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        let url: String = row.get("url")?;
+        Ok(Self {
+            url: Url::parse(&url)?,
+            title: row.get("title")?,
+            frecency: row.get("frecency")?,
+            metadata: PageMetadata::from_row(row)?,
+        })
+    }
+}
+
+/// Returns the most frecent sites, for a top sites grid on the new tab page.
+/// Each site is the most frecent visited page for an HTTP or HTTPS origin.
+/// Hidden pages, like redirect sources and framed pages, are excluded.
+pub fn get_top_frecent_site_infos(
+    db: &PlacesDb,
+    num_items: u32,
+    frecency_threshold: i64,
+) -> Result<Vec<TopFrecentSiteInfo>> {
+    // SQLite takes the bare `url` and `title` columns from the row with the
+    // highest frecency for each origin.
+    db.query_rows_and_then_named_cached(
+        "SELECT h.url, h.title, MAX(h.frecency) AS frecency, h.description,
+                h.preview_image_url
+         FROM moz_places h
+         JOIN moz_origins o ON o.id = h.origin_id
+         WHERE h.hidden = 0
+           AND h.frecency >= :frecency_threshold
+           AND (h.last_visit_date_local > 0 OR h.last_visit_date_remote > 0)
+           AND o.prefix IN ('http://', 'https://')
+         GROUP BY h.origin_id
+         ORDER BY frecency DESC, h.id DESC
+         LIMIT :limit",
+        rusqlite::named_params! {
+            ":frecency_threshold": frecency_threshold,
+            ":limit": num_items,
+        },
+        TopFrecentSiteInfo::from_row,
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::history_sync::*;
@@ -1182,7 +1644,7 @@ mod tests {
             .expect("Should apply visit");
         }
 
-        let visited_all = get_visited_urls(
+        let visited_all = get_visited_urls_in_range(
             &conn,
             Timestamp(now_u64 - 200_000),
             Timestamp(now_u64 - 1000),
@@ -1192,7 +1654,7 @@ mod tests {
         .into_iter()
         .collect::<HashSet<_>>();
 
-        let visited_local = get_visited_urls(
+        let visited_local = get_visited_urls_in_range(
             &conn,
             Timestamp(now_u64 - 200_000),
             Timestamp(now_u64 - 1000),
@@ -1471,6 +1933,32 @@ mod tests {
         assert_eq!(expect, results);
     }
 
+    #[test]
+    fn test_get_visited_many() -> Result<()> {
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+
+        for i in 0..10 {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(&format!("https://www.example.com/{}", i))?)
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+
+        // More URLs than fit in one chunk, with each visited URL repeated
+        // in every chunk.
+        let urls = (0..5000)
+            .map(|i| Url::parse(&format!("https://www.example.com/{}", i % 1000)))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let visited = get_visited(&conn, urls)?;
+        assert_eq!(visited.len(), 5000);
+        for (i, &did_see) in visited.iter().enumerate() {
+            assert_eq!(did_see, i % 1000 < 10, "Wrong value for idx {}", i);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_delete_visited() {
         let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite).expect("no memory db");
@@ -2167,4 +2655,438 @@ mod tests {
         assert_eq!(db_title.len(), crate::storage::TITLE_LENGTH_MAX);
         assert!(title.starts_with(&db_title));
     }
+
+    #[test]
+    fn test_get_top_frecent_site_infos() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite).expect("no memory db");
+        let visit = |url: &str, title: &str, visit_type, times| {
+            for _ in 0..times {
+                apply_observation(
+                    &conn,
+                    VisitObservation::new(Url::parse(url).unwrap())
+                        .with_title(title.to_string())
+                        .with_at(Timestamp::now())
+                        .with_visit_type(visit_type),
+                )
+                .expect("Should apply visit");
+            }
+        };
+        visit(
+            "https://example.com/1",
+            "Example 1",
+            VisitTransition::Link,
+            1,
+        );
+        visit(
+            "https://example.com/2",
+            "Example 2",
+            VisitTransition::Typed,
+            3,
+        );
+        visit(
+            "http://example.org/",
+            "Example Org",
+            VisitTransition::Link,
+            2,
+        );
+        visit(
+            "https://example.net/",
+            "Framed",
+            VisitTransition::FramedLink,
+            5,
+        );
+        visit("ftp://example.info/", "FTP", VisitTransition::Typed, 5);
+        apply_observation(
+            &conn,
+            VisitObservation::new(Url::parse("https://example.info/redirect").unwrap())
+                .with_at(Timestamp::now())
+                .with_visit_type(VisitTransition::Link)
+                .with_is_redirect_source(true),
+        )?;
+
+        let sites = get_top_frecent_site_infos(&conn, 10, 0)?;
+        assert_eq!(
+            sites
+                .iter()
+                .map(|site| (site.url.as_str(), site.title.as_ref().map(String::as_str)))
+                .collect::<Vec<_>>(),
+            vec![
+                ("https://example.com/2", Some("Example 2")),
+                ("http://example.org/", Some("Example Org")),
+            ]
+        );
+
+        assert_eq!(get_top_frecent_site_infos(&conn, 1, 0)?.len(), 1);
+        let threshold = i64::from(sites[0].frecency);
+        assert_eq!(get_top_frecent_site_infos(&conn, 10, threshold)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_everything_tombstones() -> Result<()> {
+        use crate::storage::bookmarks::{
+            self, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+        };
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let synced = Url::parse("http://example.com/synced")?;
+        let unsynced = Url::parse("http://example.com/unsynced")?;
+        let bookmarked = Url::parse("http://example.com/bookmarked")?;
+        for url in &[&synced, &unsynced, &bookmarked] {
+            apply_observation(
+                &conn,
+                VisitObservation::new((*url).clone())
+                    .with_at(Timestamp::now())
+                    .with_visit_type(VisitTransition::Link),
+            )?;
+        }
+        bookmarks::insert_bookmark(
+            &conn,
+            &InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: bookmarked.clone(),
+                title: None,
+            }
+            .into(),
+        )?;
+        conn.execute_named(
+            "UPDATE moz_places SET sync_status = :status
+             WHERE url_hash IN (hash(:synced), hash(:bookmarked))",
+            &[
+                (":status", &SyncStatus::Normal),
+                (":synced", &synced.as_str()),
+                (":bookmarked", &bookmarked.as_str()),
+            ],
+        )?;
+        let expected = vec![url_to_guid(&conn, &synced)?.expect("should have synced guid")];
+
+        delete_everything(&conn)?;
+
+        // The bookmarked page is kept, so it doesn't get a tombstone.
+        let tombstones =
+            conn.query_rows_and_then_named("SELECT guid FROM moz_places_tombstones", &[], |row| {
+                row.get::<_, SyncGuid>(0)
+            })?;
+        assert_eq!(tombstones, expected);
+
+        // Wiping for a sync disconnect doesn't remove anything from the
+        // server, so it removes the tombstones, too.
+        wipe_local(&conn)?;
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_places_tombstones")?,
+            0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_everything_bookmarked_page_visited_again() -> Result<()> {
+        use crate::storage::bookmarks::{
+            self, BookmarkPosition, BookmarkRootGuid, InsertableBookmark,
+        };
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let url = Url::parse("http://example.com/bookmarked")?;
+        apply_observation(
+            &conn,
+            VisitObservation::new(url.clone())
+                .with_at(Timestamp::now())
+                .with_visit_type(VisitTransition::Link),
+        )?;
+        bookmarks::insert_bookmark(
+            &conn,
+            &InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title: None,
+            }
+            .into(),
+        )?;
+        // Pretend we've synced the page.
+        conn.execute_named(
+            "UPDATE moz_places SET sync_status = :status, sync_change_counter = 0
+             WHERE url_hash = hash(:url) AND url = :url",
+            &[(":status", &SyncStatus::Normal), (":url", &url.as_str())],
+        )?;
+        let guid = url_to_guid(&conn, &url)?.expect("should have guid");
+
+        delete_everything(&conn)?;
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        apply_observation(
+            &conn,
+            VisitObservation::new(url.clone())
+                .with_at(Timestamp::now())
+                .with_visit_type(VisitTransition::Link),
+        )?;
+
+        // The new visit is uploaded, replacing the page's old visits on the
+        // server.
+        let outgoing = fetch_outgoing(&conn, 100, 100)?;
+        assert_eq!(outgoing.len(), 1);
+        match &outgoing[&guid] {
+            OutgoingInfo::Record(record) => assert_eq!(record.visits.len(), 1),
+            OutgoingInfo::Tombstone => panic!("Shouldn't upload a tombstone"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_page_metadata() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let url = Url::parse("https://example.com/article")?;
+        let image = Url::parse("https://example.com/image.png")?;
+        assert!(note_page_metadata(&conn, &url, Some("Description"), None).is_err());
+        assert_eq!(get_page_metadata(&conn, &url)?, None);
+
+        apply_observation(
+            &conn,
+            VisitObservation::new(url.clone())
+                .with_at(Timestamp::now())
+                .with_visit_type(VisitTransition::Link),
+        )?;
+        assert_eq!(
+            get_page_metadata(&conn, &url)?,
+            Some(PageMetadata::default())
+        );
+
+        let long_description = "a".repeat(crate::storage::DESCRIPTION_LENGTH_MAX + 1);
+        note_page_metadata(&conn, &url, Some(&long_description), Some(&image))?;
+        let metadata = get_page_metadata(&conn, &url)?.expect("should have metadata");
+        assert_eq!(
+            metadata.description.map(|d| d.len()),
+            Some(crate::storage::DESCRIPTION_LENGTH_MAX)
+        );
+        assert_eq!(metadata.preview_image_url, Some(image.clone()));
+
+        note_page_metadata(&conn, &url, Some(" Description "), None)?;
+        let expected = PageMetadata {
+            description: Some("Description".into()),
+            preview_image_url: None,
+        };
+        assert_eq!(get_page_metadata(&conn, &url)?, Some(expected.clone()));
+        let sites = get_top_frecent_site_infos(&conn, 10, 0)?;
+        assert_eq!(sites.len(), 1);
+        assert_eq!(sites[0].metadata, expected);
+
+        // Metadata is removed with history, even for pages that stay.
+        note_page_metadata(&conn, &url, Some("Description"), Some(&image))?;
+        conn.execute_cached("UPDATE moz_places SET foreign_count = 1", NO_PARAMS)?;
+        wipe_local(&conn)?;
+        assert_eq!(
+            get_page_metadata(&conn, &url)?,
+            Some(PageMetadata::default())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_query_history() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let visit = |url: &str, title: &str, visit_type, times| {
+            for _ in 0..times {
+                apply_observation(
+                    &conn,
+                    VisitObservation::new(Url::parse(url).unwrap())
+                        .with_title(title.to_string())
+                        .with_at(Timestamp::now())
+                        .with_visit_type(visit_type),
+                )
+                .expect("Should apply visit");
+            }
+        };
+        visit(
+            "https://example.com/donuts",
+            "Glazed",
+            VisitTransition::Link,
+            1,
+        );
+        visit(
+            "https://example.org/",
+            "Donut Shop",
+            VisitTransition::Typed,
+            3,
+        );
+        visit("https://example.net/", "Cake", VisitTransition::Link, 1);
+        visit(
+            "https://example.info/donuts",
+            "Framed",
+            VisitTransition::FramedLink,
+            1,
+        );
+
+        let search = |text: &str, limit| {
+            query_history(&conn, text, limit)
+                .expect("Should search")
+                .into_iter()
+                .map(|result| result.url.into_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            search("DONUT", 10),
+            vec!["https://example.org/", "https://example.com/donuts"]
+        );
+        assert_eq!(search("donut", 1), vec!["https://example.org/"]);
+        assert_eq!(
+            search("example glazed", 10),
+            vec!["https://example.com/donuts"]
+        );
+        assert_eq!(search("pie", 10), Vec::<String>::new());
+        assert_eq!(search(" ", 10), Vec::<String>::new());
+
+        let results = query_history(&conn, "cake", 10)?;
+        assert_eq!(results[0].title.as_ref().map(String::as_str), Some("Cake"));
+        assert!(results[0].last_visit_date > Timestamp(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_redirect_sources() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let start = Url::parse("https://example.com/start")?;
+        let hop = Url::parse("https://example.com/hop")?;
+        let end = Url::parse("https://example.com/end")?;
+        let other = Url::parse("https://example.com/other")?;
+        let now = Timestamp::now().as_millis();
+
+        // `start` redirects to `hop`, which redirects to `end`.
+        let visits = [
+            (&start, VisitTransition::Link, None, now - 3),
+            (
+                &hop,
+                VisitTransition::RedirectPermanent,
+                Some(&start),
+                now - 2,
+            ),
+            (
+                &end,
+                VisitTransition::RedirectTemporary,
+                Some(&hop),
+                now - 1,
+            ),
+            // A link from `end` isn't a redirect.
+            (&other, VisitTransition::Link, Some(&end), now),
+        ];
+        for (url, visit_type, referrer, at) in &visits {
+            apply_observation(
+                &conn,
+                VisitObservation::new((*url).clone())
+                    .with_visit_type(*visit_type)
+                    .with_referrer(referrer.cloned())
+                    .with_at(Timestamp(*at)),
+            )?;
+        }
+
+        assert_eq!(
+            get_redirect_sources(&conn, &end)?,
+            vec![hop.clone(), start.clone()]
+        );
+        assert_eq!(get_redirect_sources(&conn, &hop)?, vec![start.clone()]);
+        assert_eq!(get_redirect_sources(&conn, &start)?, Vec::<Url>::new());
+        assert_eq!(get_redirect_sources(&conn, &other)?, Vec::<Url>::new());
+
+        // Removing a hop breaks the chain, but keeps the visits to the
+        // target.
+        delete_place_by_guid(&conn, &url_to_guid(&conn, &hop)?.unwrap())?;
+        assert_eq!(get_redirect_sources(&conn, &end)?, Vec::<Url>::new());
+        assert!(get_visited(&conn, vec![end.clone()])?[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_page_hidden() -> Result<()> {
+        let mut conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let url = Url::parse("https://example.com/redirect")?;
+        let pi = get_observed_page(&mut conn, url.as_str())?;
+        assert!(!pi.hidden);
+        assert_eq!(query_history(&conn, "redirect", 10)?.len(), 1);
+
+        assert!(set_page_hidden(&conn, &url, true)?);
+        assert!(fetch_page_info(&conn, &url)?.unwrap().page.hidden);
+        assert_eq!(query_history(&conn, "redirect", 10)?.len(), 0);
+
+        assert!(set_page_hidden(&conn, &url, false)?);
+        assert!(!fetch_page_info(&conn, &url)?.unwrap().page.hidden);
+
+        let missing = Url::parse("https://example.com/missing")?;
+        assert!(!set_page_hidden(&conn, &missing, true)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_frecencies() -> Result<()> {
+        use crate::api::places_api::test::new_mem_api;
+
+        let api = new_mem_api();
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+        let now = Timestamp::now();
+        let old_url = Url::parse("https://example.com/old")?;
+        let old_typed_url = Url::parse("https://example.com/old-typed")?;
+        let recent_url = Url::parse("https://example.com/recent")?;
+        apply_observation(
+            &conn,
+            VisitObservation::new(old_url.clone())
+                .with_at(Timestamp(now.as_millis() - 60 * MS_PER_DAY))
+                .with_visit_type(VisitTransition::Link),
+        )?;
+        for days_ago in &[60, 50] {
+            apply_observation(
+                &conn,
+                VisitObservation::new(old_typed_url.clone())
+                    .with_at(Timestamp(now.as_millis() - days_ago * MS_PER_DAY))
+                    .with_visit_type(VisitTransition::Typed),
+            )?;
+        }
+        apply_observation(
+            &conn,
+            VisitObservation::new(recent_url.clone())
+                .with_at(now)
+                .with_visit_type(VisitTransition::Link),
+        )?;
+        // Pretend that we calculated the old pages' frecencies when the user
+        // visited them.
+        for url in &[&old_url, &old_typed_url] {
+            conn.execute_named(
+                "UPDATE moz_places SET frecency = 1000000
+                 WHERE url_hash = hash(:url) AND url = :url",
+                &[(":url", &url.as_str())],
+            )?;
+        }
+
+        // The first time, every page with an old visit is marked, and each
+        // one gets its own frecency.
+        assert_eq!(api.update_frecencies()?, 2);
+        let old_frecency = fetch_page_info(&conn, &old_url)?
+            .expect("should fetch old page")
+            .page
+            .frecency;
+        assert!(old_frecency > 0 && old_frecency < 1_000_000);
+        let old_typed_frecency = fetch_page_info(&conn, &old_typed_url)?
+            .expect("should fetch old typed page")
+            .page
+            .frecency;
+        assert!(old_typed_frecency > old_frecency && old_typed_frecency < 1_000_000);
+        assert_eq!(frecency_stale_at(&conn, &old_url)?, None);
+        assert_eq!(frecency_stale_at(&conn, &old_typed_url)?, None);
+        assert_eq!(api.update_frecencies()?, 0);
+
+        // After that, only the pages with visits that crossed a bucket
+        // cutoff are.
+        assert_eq!(
+            decay_frecencies(&conn, Timestamp(now.as_millis() + 2 * MS_PER_DAY))?,
+            0
+        );
+        assert_eq!(
+            decay_frecencies(&conn, Timestamp(now.as_millis() + 5 * MS_PER_DAY))?,
+            1
+        );
+        assert!(frecency_stale_at(&conn, &recent_url)?.is_some());
+        assert_eq!(frecency_stale_at(&conn, &old_url)?, None);
+        Ok(())
+    }
 }
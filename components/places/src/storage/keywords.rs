@@ -0,0 +1,250 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Keywords let the user visit a bookmarked URL by typing a short word in the
+//! address bar, like on Desktop. Each URL has at most one keyword, and each
+//! keyword belongs to one URL. Keywords are synced as the `keyword` field of
+//! every bookmark for the URL, and are removed along with the URL's last
+//! bookmark.
+
+use super::fetch_page_info;
+use crate::db::PlacesDb;
+use crate::error::{InvalidPlaceInfo, Result};
+use sql_support::ConnExt;
+use url::Url;
+
+/// Returns the normalized form of `keyword`, which is trimmed and lowercased
+/// like on Desktop, or None if it's empty.
+pub fn normalize_keyword(keyword: &str) -> Option<String> {
+    let keyword = keyword.trim();
+    if keyword.is_empty() {
+        None
+    } else {
+        Some(keyword.to_lowercase())
+    }
+}
+
+/// Sets the keyword for the specified URL.
+///
+/// # Arguments
+///
+/// * `conn` - A database connection on which to operate.
+///
+/// * `url` - The URL, which must be bookmarked.
+///
+/// * `keyword` - The keyword. This replaces the URL's existing keyword, and
+///   is removed from any other URL that has it.
+///
+/// # Returns
+///
+/// There is no success return value.
+pub fn set_keyword(db: &PlacesDb, url: &Url, keyword: &str) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    set_keyword_in_tx(db, url, keyword)?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub(crate) fn set_keyword_in_tx(db: &PlacesDb, url: &Url, keyword: &str) -> Result<()> {
+    let keyword = match normalize_keyword(keyword) {
+        Some(keyword) => keyword,
+        None => return Err(InvalidPlaceInfo::InvalidKeyword.into()),
+    };
+
+    // Keywords are only synced for bookmarks, so the URL must be bookmarked.
+    let place_id = match fetch_page_info(db, url)? {
+        Some(info) => info.page.row_id,
+        None => return Err(InvalidPlaceInfo::NoSuchUrl.into()),
+    };
+    let is_bookmarked = db.query_row_named(
+        "SELECT EXISTS(SELECT 1 FROM moz_bookmarks WHERE fk = :place_id)",
+        &[(":place_id", &place_id)],
+        |row| row.get::<_, bool>(0),
+    )?;
+    if !is_bookmarked {
+        return Err(InvalidPlaceInfo::NoSuchUrl.into());
+    }
+
+    // Leave the keyword alone if it's unchanged, so that we don't reupload
+    // the URL's bookmarks.
+    db.execute_named_cached(
+        "DELETE FROM moz_keywords
+         WHERE (keyword = :keyword OR place_id = :place_id) AND
+               NOT (keyword = :keyword AND place_id = :place_id)",
+        &[(":keyword", &keyword), (":place_id", &place_id)],
+    )?;
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_keywords(keyword, place_id)
+         VALUES(:keyword, :place_id)",
+        &[(":keyword", &keyword), (":place_id", &place_id)],
+    )?;
+    Ok(())
+}
+
+/// Removes the specified keyword.
+///
+/// # Arguments
+///
+/// * `conn` - A database connection on which to operate.
+///
+/// * `keyword` - The keyword to remove.
+///
+/// # Returns
+///
+/// There is no success return value - the operation is ignored if no URL
+/// has the keyword.
+pub fn remove_keyword(db: &PlacesDb, keyword: &str) -> Result<()> {
+    if let Some(keyword) = normalize_keyword(keyword) {
+        db.execute_named_cached(
+            "DELETE FROM moz_keywords WHERE keyword = :keyword",
+            &[(":keyword", &keyword)],
+        )?;
+    }
+    Ok(())
+}
+
+/// Retrieves the keyword for the specified URL.
+///
+/// # Arguments
+///
+/// * `conn` - A database connection on which to operate.
+///
+/// * `url` - The URL to query.
+///
+/// # Returns
+///
+/// * The keyword, or None if the URL doesn't have one.
+pub fn get_keyword_for_url(db: &PlacesDb, url: &Url) -> Result<Option<String>> {
+    Ok(db.try_query_row(
+        "SELECT k.keyword
+         FROM moz_keywords k
+         JOIN moz_places h ON h.id = k.place_id
+         WHERE url_hash = hash(:url) AND url = :url",
+        &[(":url", &url.as_str())],
+        |row| row.get::<_, String>("keyword"),
+        true,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::storage::bookmarks::{
+        bookmarks_get_url_for_keyword, delete_bookmark, insert_bookmark, update_bookmark,
+        BookmarkPosition, BookmarkRootGuid, InsertableBookmark, UpdatableBookmark,
+    };
+    use sync_guid::Guid as SyncGuid;
+
+    fn bookmark(db: &PlacesDb, url: &Url) -> SyncGuid {
+        insert_bookmark(
+            db,
+            &InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title: None,
+            }
+            .into(),
+        )
+        .expect("should insert bookmark")
+    }
+
+    fn foreign_count(db: &PlacesDb, url: &Url) -> Result<i64> {
+        Ok(db.query_row_named(
+            "SELECT foreign_count FROM moz_places
+             WHERE url_hash = hash(:url) AND url = :url",
+            &[(":url", &url.as_str())],
+            |row| row.get::<_, i64>(0),
+        )?)
+    }
+
+    #[test]
+    fn test_normalize_keyword() {
+        assert_eq!(normalize_keyword(" Donut "), Some("donut".to_string()));
+        assert_eq!(normalize_keyword(" "), None);
+    }
+
+    #[test]
+    fn test_keywords() -> Result<()> {
+        let conn = new_mem_connection();
+        let url1 = Url::parse("http://example.com/1")?;
+        let url2 = Url::parse("http://example.com/2")?;
+        let unbookmarked = Url::parse("http://example.com/3")?;
+        bookmark(&conn, &url1);
+        bookmark(&conn, &url2);
+
+        set_keyword(&conn, &url1, "Donut")?;
+        assert_eq!(get_keyword_for_url(&conn, &url1)?, Some("donut".into()));
+        assert_eq!(
+            bookmarks_get_url_for_keyword(&conn, "DONUT")?,
+            Some(url1.clone())
+        );
+        assert!(set_keyword(&conn, &unbookmarked, "juice").is_err());
+        assert!(set_keyword(&conn, &url1, "").is_err());
+
+        // Setting a new keyword replaces the old one.
+        set_keyword(&conn, &url1, "cake")?;
+        assert_eq!(bookmarks_get_url_for_keyword(&conn, "donut")?, None);
+        assert_eq!(
+            bookmarks_get_url_for_keyword(&conn, "cake")?,
+            Some(url1.clone())
+        );
+
+        // Keywords are unique, so they move from one URL to another.
+        set_keyword(&conn, &url2, "cake")?;
+        assert_eq!(get_keyword_for_url(&conn, &url1)?, None);
+        assert_eq!(get_keyword_for_url(&conn, &url2)?, Some("cake".into()));
+
+        remove_keyword(&conn, "cake")?;
+        assert_eq!(get_keyword_for_url(&conn, &url2)?, None);
+        assert_eq!(bookmarks_get_url_for_keyword(&conn, "cake")?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyword_removed_with_last_bookmark() -> Result<()> {
+        let conn = new_mem_connection();
+        let url = Url::parse("http://example.com/1")?;
+        let guid1 = bookmark(&conn, &url);
+        let guid2 = bookmark(&conn, &url);
+        set_keyword(&conn, &url, "donut")?;
+
+        // The keyword stays as long as the URL is bookmarked...
+        delete_bookmark(&conn, &guid1)?;
+        assert_eq!(get_keyword_for_url(&conn, &url)?, Some("donut".into()));
+
+        // ...And goes away with its last bookmark, so the page can expire.
+        delete_bookmark(&conn, &guid2)?;
+        assert_eq!(bookmarks_get_url_for_keyword(&conn, "donut")?, None);
+        assert_eq!(foreign_count(&conn, &url)?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keyword_removed_when_last_bookmark_changes_url() -> Result<()> {
+        let conn = new_mem_connection();
+        let old_url = Url::parse("http://example.com/old")?;
+        let new_url = Url::parse("http://example.com/new")?;
+        let guid = bookmark(&conn, &old_url);
+        set_keyword(&conn, &old_url, "donut")?;
+
+        update_bookmark(
+            &conn,
+            &guid,
+            &UpdatableBookmark {
+                url: Some(new_url.clone()),
+                ..Default::default()
+            }
+            .into(),
+        )?;
+        assert_eq!(bookmarks_get_url_for_keyword(&conn, "donut")?, None);
+        assert_eq!(get_keyword_for_url(&conn, &new_url)?, None);
+        assert_eq!(foreign_count(&conn, &old_url)?, 0);
+        Ok(())
+    }
+}
@@ -6,7 +6,11 @@
 // API and the database.
 
 pub mod bookmarks;
+pub mod expiration;
+pub mod favicons;
 pub mod history;
+pub mod keywords;
+pub mod pinned_sites;
 pub mod tags;
 
 use crate::db::PlacesDb;
@@ -15,7 +19,7 @@ use crate::msg_types::HistoryVisitInfo;
 use crate::types::{SyncStatus, Timestamp, VisitTransition};
 use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
 use rusqlite::Result as RusqliteResult;
-use rusqlite::Row;
+use rusqlite::{Row, NO_PARAMS};
 use serde_derive::*;
 use sql_support::{self, ConnExt};
 use std::fmt;
@@ -26,7 +30,7 @@ use url::Url;
 pub const URL_LENGTH_MAX: usize = 65536;
 pub const TITLE_LENGTH_MAX: usize = 4096;
 pub const TAG_LENGTH_MAX: usize = 100;
-// pub const DESCRIPTION_LENGTH_MAX: usize = 256;
+pub const DESCRIPTION_LENGTH_MAX: usize = 256;
 
 // Typesafe way to manage RowIds. Does it make sense? A better way?
 #[derive(Debug, Copy, Clone, PartialEq, PartialOrd, Eq, Ord, Deserialize, Serialize, Default)]
@@ -191,12 +195,204 @@ impl HistoryVisitInfo {
     }
 }
 
-pub fn run_maintenance(conn: &PlacesDb) -> Result<()> {
-    conn.execute_all(&[
-        "VACUUM",
-        "PRAGMA optimize",
-        "PRAGMA wal_checkpoint(PASSIVE)",
-    ])?;
+/// What `run_maintenance` did, for logging and telemetry.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceReport {
+    /// Pages without visits, bookmarks, tags or keywords that were removed.
+    pub pruned_places: usize,
+    /// Origins without pages that were removed.
+    pub pruned_origins: usize,
+    pub pruned_icons: usize,
+    /// The number of free pages returned to the file system.
+    pub vacuumed_pages: i64,
+    /// Problems found by the integrity check, up to
+    /// `MAINTENANCE_MAX_INTEGRITY_ERRORS`. This is empty if the database is
+    /// fine.
+    pub integrity_errors: Vec<String>,
+}
+
+/// The most free pages to return to the file system in one run, so that
+/// maintenance doesn't block other work on the write connection for long.
+pub const MAINTENANCE_VACUUM_PAGES: u32 = 1000;
+
+/// The most problems to report from the integrity check. Checking stops
+/// early once this many are found.
+pub const MAINTENANCE_MAX_INTEGRITY_ERRORS: u32 = 10;
+
+/// Cleans up the database. This is meant to be called periodically, from an
+/// idle-time worker, and does as little work as it can each time.
+pub fn run_maintenance(conn: &PlacesDb) -> Result<MaintenanceReport> {
+    let mut report = MaintenanceReport::default();
+    crate::api::matcher::decay_input_history(conn, Timestamp::now())?;
+
+    let tx = conn.begin_transaction()?;
+    report.pruned_places = conn.execute_cached(
+        "DELETE FROM moz_places
+         WHERE foreign_count = 0 AND
+               NOT EXISTS(SELECT 1 FROM moz_historyvisits
+                          WHERE place_id = moz_places.id)",
+        NO_PARAMS,
+    )?;
+    delete_pending_temp_tables(conn)?;
+    report.pruned_origins = conn.execute_cached(
+        "DELETE FROM moz_origins
+         WHERE NOT EXISTS(SELECT 1 FROM moz_places
+                          WHERE origin_id = moz_origins.id)",
+        NO_PARAMS,
+    )?;
+    report.pruned_icons = favicons::delete_expired_icons(conn)?;
+    tx.commit()?;
+
+    // Incremental vacuuming only works if the database was created with it
+    // enabled, or fully vacuumed after enabling it, so we do that once.
+    let auto_vacuum = conn.query_one::<i64>("PRAGMA auto_vacuum")?;
+    let free_pages_before = conn.query_one::<i64>("PRAGMA freelist_count")?;
+    if auto_vacuum == AUTO_VACUUM_INCREMENTAL {
+        conn.execute_batch(&format!(
+            "PRAGMA incremental_vacuum({})",
+            MAINTENANCE_VACUUM_PAGES
+        ))?;
+    } else {
+        conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL; VACUUM")?;
+    }
+    report.vacuumed_pages = free_pages_before - conn.query_one::<i64>("PRAGMA freelist_count")?;
+
+    conn.execute_all(&["PRAGMA optimize", "PRAGMA wal_checkpoint(PASSIVE)"])?;
+
+    report.integrity_errors = conn
+        .query_rows_and_then_named(
+            &format!("PRAGMA quick_check({})", MAINTENANCE_MAX_INTEGRITY_ERRORS),
+            &[],
+            |row| row.get::<_, String>(0),
+        )?
+        .into_iter()
+        .filter(|result| result != "ok")
+        .collect();
+    if !report.integrity_errors.is_empty() {
+        log::error!(
+            "Integrity check found {} problems",
+            report.integrity_errors.len()
+        );
+    }
+    log::info!("Finished maintenance: {:?}", report);
+    Ok(report)
+}
+
+const AUTO_VACUUM_INCREMENTAL: i64 = 2;
+
+/// How much disk space the database uses.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DbSize {
+    /// The size of the database file, in bytes.
+    pub db_bytes: u64,
+    /// How much of the database file is free pages, which maintenance
+    /// returns to the file system.
+    pub free_bytes: u64,
+    /// The size of the write-ahead log, in bytes. This is 0 for memory
+    /// databases, and after a `Truncate` checkpoint.
+    pub wal_bytes: u64,
+}
+
+/// Returns the sizes of the database and its write-ahead log.
+pub fn db_size(conn: &PlacesDb) -> Result<DbSize> {
+    let page_size = conn.query_one::<i64>("PRAGMA page_size")? as u64;
+    let page_count = conn.query_one::<i64>("PRAGMA page_count")? as u64;
+    let free_pages = conn.query_one::<i64>("PRAGMA freelist_count")? as u64;
+    // Memory databases have an empty file name.
+    let path =
+        conn.query_one::<String>("SELECT file FROM pragma_database_list WHERE name = 'main'")?;
+    let wal_bytes = if path.is_empty() {
+        0
+    } else {
+        match std::fs::metadata(format!("{}-wal", path)) {
+            Ok(metadata) => metadata.len(),
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        }
+    };
+    Ok(DbSize {
+        db_bytes: page_count * page_size,
+        free_bytes: free_pages * page_size,
+        wal_bytes,
+    })
+}
+
+/// How hard `checkpoint` should try to copy the write-ahead log back into
+/// the database. These are the same as SQLite's checkpoint modes, and have
+/// the same values as its `SQLITE_CHECKPOINT_*` constants.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckpointMode {
+    /// Copies as much as it can without waiting for readers or writers.
+    Passive = 0,
+    /// Waits for writers to finish, then copies the whole log.
+    Full = 1,
+    /// Like `Full`, and also waits for readers, so that the next writer
+    /// starts the log over.
+    Restart = 2,
+    /// Like `Restart`, and also truncates the log file. Use this before
+    /// backing up the database file.
+    Truncate = 3,
+}
+
+impl CheckpointMode {
+    pub fn from_primitive(p: u8) -> Option<Self> {
+        match p {
+            0 => Some(CheckpointMode::Passive),
+            1 => Some(CheckpointMode::Full),
+            2 => Some(CheckpointMode::Restart),
+            3 => Some(CheckpointMode::Truncate),
+            _ => None,
+        }
+    }
+
+    fn as_sql(self) -> &'static str {
+        match self {
+            CheckpointMode::Passive => "PASSIVE",
+            CheckpointMode::Full => "FULL",
+            CheckpointMode::Restart => "RESTART",
+            CheckpointMode::Truncate => "TRUNCATE",
+        }
+    }
+}
+
+/// What `checkpoint` did.
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CheckpointResult {
+    /// True if another connection kept the checkpoint from finishing.
+    pub busy: bool,
+    /// The number of pages in the write-ahead log.
+    pub log_pages: i64,
+    /// The number of those pages that were copied into the database.
+    pub checkpointed_pages: i64,
+}
+
+/// Copies the write-ahead log back into the database.
+pub fn checkpoint(conn: &PlacesDb, mode: CheckpointMode) -> Result<CheckpointResult> {
+    conn.query_row_and_then_named(
+        &format!("PRAGMA wal_checkpoint({})", mode.as_sql()),
+        &[],
+        |row| -> Result<_> {
+            Ok(CheckpointResult {
+                busy: row.get::<_, i64>(0)? != 0,
+                log_pages: row.get(1)?,
+                checkpointed_pages: row.get(2)?,
+            })
+        },
+        false,
+    )
+}
+
+/// Sets the size, in bytes, that the write-ahead log is truncated to after
+/// a checkpoint, or `None` to leave it as big as it grew. This only affects
+/// `conn`.
+pub fn set_journal_size_limit(conn: &PlacesDb, limit: Option<u64>) -> Result<()> {
+    let limit = limit.map_or(-1, |limit| limit as i64);
+    // `journal_size_limit` returns the new limit.
+    conn.query_one::<i64>(&format!("PRAGMA journal_size_limit = {}", limit))?;
     Ok(())
 }
 
@@ -237,6 +433,50 @@ mod tests {
     use super::*;
     use crate::api::places_api::test::new_mem_connection;
 
+    #[test]
+    fn test_run_maintenance() -> Result<()> {
+        use crate::api::places_api::{test::new_mem_api, ConnectionType};
+        use crate::observation::VisitObservation;
+
+        let api = new_mem_api();
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+        let visited = Url::parse("https://example.com/visited")?;
+        let unvisited = Url::parse("https://example.org/unvisited")?;
+        history::apply_observation(
+            &conn,
+            VisitObservation::new(visited.clone())
+                .with_at(Timestamp::now())
+                .with_visit_type(VisitTransition::Link),
+        )?;
+        // Observations without a visit type only add the page.
+        history::apply_observation(
+            &conn,
+            VisitObservation::new(unvisited.clone()).with_title("Title".to_string()),
+        )?;
+        let mut expired = favicons::Icon::new(Url::parse("https://example.com/icon.png")?, 16);
+        expired.expires_at = Timestamp(1);
+        favicons::set_icon_for_page(&conn, &visited, &expired)?;
+        api.close_connection(conn)?;
+
+        let report = api.run_maintenance()?;
+        assert_eq!(report.pruned_places, 1);
+        assert_eq!(report.pruned_icons, 1);
+        assert!(report.integrity_errors.is_empty());
+
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+        assert!(fetch_page_info(&conn, &visited)?.is_some());
+        assert!(fetch_page_info(&conn, &unvisited)?.is_none());
+        assert_eq!(
+            conn.query_one::<i64>("SELECT COUNT(*) FROM moz_origins")?,
+            1
+        );
+        api.close_connection(conn)?;
+
+        // Running it again doesn't find anything else to do.
+        assert_eq!(api.run_maintenance()?, MaintenanceReport::default());
+        Ok(())
+    }
+
     #[test]
     fn test_meta() {
         let conn = new_mem_connection();
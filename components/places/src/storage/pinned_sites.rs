@@ -0,0 +1,157 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pinned sites are the tiles that the user pinned to the top sites grid on
+//! the home screen. Unlike top sites, which come from history and change as
+//! the user browses, a pinned site stays in its slot until the user unpins
+//! it, and isn't removed when history is cleared. Pinned sites aren't synced.
+
+use super::{TITLE_LENGTH_MAX, URL_LENGTH_MAX};
+use crate::db::PlacesDb;
+use crate::error::{InvalidPlaceInfo, Result};
+use crate::types::Timestamp;
+use crate::util::slice_up_to;
+use rusqlite::Row;
+use sql_support::ConnExt;
+use url::Url;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PinnedSite {
+    pub url: Url,
+    pub title: Option<String>,
+    /// The site's slot in the grid.
+    pub position: u32,
+    pub date_added: Timestamp,
+}
+
+impl PinnedSite {
+    fn from_row(row: &Row<'_>) -> Result<Self> {
+        let url: String = row.get("url")?;
+        Ok(Self {
+            url: Url::parse(&url)?,
+            title: row.get("title")?,
+            position: row.get("position")?,
+            date_added: row.get("date_added")?,
+        })
+    }
+}
+
+/// Pins `url` to the slot at `position`, replacing the site that's already
+/// pinned there. If `url` is pinned to a different slot, it's moved.
+pub fn pin_site(db: &PlacesDb, url: &Url, title: Option<&str>, position: u32) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    pin_site_in_tx(db, url, title, position, Timestamp::now())?;
+    tx.commit()?;
+    Ok(())
+}
+
+pub(crate) fn pin_site_in_tx(
+    db: &PlacesDb,
+    url: &Url,
+    title: Option<&str>,
+    position: u32,
+    date_added: Timestamp,
+) -> Result<()> {
+    if url.as_str().len() > URL_LENGTH_MAX {
+        return Err(InvalidPlaceInfo::UrlTooLong.into());
+    }
+    db.execute_named_cached(
+        "DELETE FROM moz_pinned_sites
+         WHERE url = :url OR position = :position",
+        &[(":url", &url.as_str()), (":position", &position)],
+    )?;
+    db.execute_named_cached(
+        "INSERT INTO moz_pinned_sites(url, title, position, date_added)
+         VALUES(:url, :title, :position, :date_added)",
+        &[
+            (":url", &url.as_str()),
+            (":title", &title.map(|t| slice_up_to(t, TITLE_LENGTH_MAX))),
+            (":position", &position),
+            (":date_added", &date_added),
+        ],
+    )?;
+    Ok(())
+}
+
+/// Unpins `url`. Returns true if it was pinned.
+pub fn unpin_site(db: &PlacesDb, url: &Url) -> Result<bool> {
+    let changes = db.execute_named_cached(
+        "DELETE FROM moz_pinned_sites WHERE url = :url",
+        &[(":url", &url.as_str())],
+    )?;
+    Ok(changes > 0)
+}
+
+/// Returns all pinned sites, in the order of their slots.
+pub fn get_pinned_sites(db: &PlacesDb) -> Result<Vec<PinnedSite>> {
+    db.query_rows_and_then_named_cached(
+        "SELECT url, title, position, date_added
+         FROM moz_pinned_sites
+         ORDER BY position",
+        &[],
+        PinnedSite::from_row,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::storage::history::delete_everything;
+    use pretty_assertions::assert_eq;
+
+    fn pinned_urls(db: &PlacesDb) -> Vec<(u32, String)> {
+        get_pinned_sites(db)
+            .expect("should fetch pinned sites")
+            .into_iter()
+            .map(|site| (site.position, site.url.into_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_pin_unpin() -> Result<()> {
+        let conn = new_mem_connection();
+        let example = Url::parse("https://example.com/")?;
+        let mozilla = Url::parse("https://mozilla.org/")?;
+        let wikipedia = Url::parse("https://wikipedia.org/")?;
+
+        pin_site(&conn, &example, Some("Example"), 3)?;
+        pin_site(&conn, &mozilla, None, 0)?;
+        assert_eq!(
+            pinned_urls(&conn),
+            vec![
+                (0, "https://mozilla.org/".to_owned()),
+                (3, "https://example.com/".to_owned()),
+            ]
+        );
+        assert_eq!(
+            get_pinned_sites(&conn)?[1].title,
+            Some("Example".to_owned())
+        );
+
+        // Pinning to a slot that's taken replaces the old site, and pinning a
+        // site that's already pinned moves it.
+        pin_site(&conn, &wikipedia, None, 3)?;
+        pin_site(&conn, &mozilla, None, 1)?;
+        assert_eq!(
+            pinned_urls(&conn),
+            vec![
+                (1, "https://mozilla.org/".to_owned()),
+                (3, "https://wikipedia.org/".to_owned()),
+            ]
+        );
+
+        // Clearing history doesn't unpin anything.
+        delete_everything(&conn)?;
+        assert_eq!(pinned_urls(&conn).len(), 2);
+
+        assert!(unpin_site(&conn, &mozilla)?);
+        assert!(!unpin_site(&conn, &example)?);
+        assert_eq!(
+            pinned_urls(&conn),
+            vec![(3, "https://wikipedia.org/".to_owned())]
+        );
+        Ok(())
+    }
+}
@@ -2,9 +2,14 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use places::{api::places_api::PlacesApi, ErrorKind, Result, Timestamp};
+use places::{
+    api::places_api::{ConnectionType, PlacesApi},
+    storage::{bookmarks, pinned_sites},
+    ErrorKind, Result, Timestamp,
+};
 use rusqlite::types::{ToSql, ToSqlOutput};
 use rusqlite::{Connection, NO_PARAMS};
+use sql_support::ConnExt;
 use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use sync_guid::Guid;
@@ -248,12 +253,65 @@ fn test_import() -> Result<()> {
             url: Some("https://foo.bar".to_owned()),
             ..Default::default()
         },
+        FennecBookmark {
+            _id: 12,
+            parent: 0,
+            position: 6,
+            title: Some("Reading List".to_owned()),
+            r#type: &FennecBookmarkType::Folder,
+            ..Default::default()
+        },
+        FennecBookmark {
+            _id: 13,
+            parent: 12,
+            title: Some("Later".to_owned()),
+            url: Some("https://example.com/later".to_owned()),
+            ..Default::default()
+        },
+        FennecBookmark {
+            _id: 14,
+            parent: 1,
+            title: Some("Deleted".to_owned()),
+            url: Some("https://example.com/deleted".to_owned()),
+            deleted: true,
+            ..Default::default()
+        },
     ];
     insert_bookmarks(&fennec_db, &bookmarks)?;
 
     let places_api = PlacesApi::new(tmpdir.path().join("places.sqlite"))?;
 
-    places::import::import_fennec_bookmarks(&places_api, fennec_path)?;
+    let result = places::import::import_fennec_bookmarks(&places_api, fennec_path)?;
+    assert_eq!(result.num_total, 7);
+    assert_eq!(result.num_succeeded, 6);
+    assert_eq!(result.num_failed, 1);
+
+    let places_db = places_api.open_connection(ConnectionType::ReadOnly)?;
+    let guid = |id: i64| bookmarks.iter().find(|b| b._id == id).unwrap().guid.clone();
+    let fetch = |id: i64| bookmarks::public_node::fetch_bookmark(&places_db, &guid(id), false);
+
+    // Children are ordered by their Fennec positions, and the reading list
+    // folder is moved to the end of the mobile root.
+    let mobile = bookmarks::public_node::fetch_bookmark(
+        &places_db,
+        &bookmarks::BookmarkRootGuid::Mobile.as_guid(),
+        false,
+    )?
+    .unwrap();
+    assert_eq!(mobile.child_guids, Some(vec![guid(7), guid(6), guid(12)]));
+    let folder_one = fetch(7)?.unwrap();
+    assert_eq!(folder_one.child_guids, Some(vec![guid(8), guid(9)]));
+    let reading_list = fetch(12)?.unwrap();
+    assert_eq!(reading_list.child_guids, Some(vec![guid(13)]));
+
+    // Pinned sites and deleted bookmarks aren't imported.
+    let num_skipped = places_db.query_one::<i64>(&format!(
+        "SELECT COUNT(*) FROM moz_bookmarks WHERE guid IN ('{}', '{}', '{}')",
+        guid(-3),
+        guid(11),
+        guid(14)
+    ))?;
+    assert_eq!(num_skipped, 0);
 
     // Uncomment the following to debug with cargo test -- --nocapture.
     // println!(
@@ -264,3 +322,100 @@ fn test_import() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_import_pinned_sites() -> Result<()> {
+    let tmpdir = tempdir().unwrap();
+    let fennec_path = tmpdir.path().join("browser.db");
+    let fennec_db = empty_fennec_db(&fennec_path)?;
+
+    let bookmarks = [
+        FennecBookmark {
+            _id: 0,
+            parent: 0,
+            guid: Guid::from("places"),
+            r#type: &FennecBookmarkType::Folder,
+            ..Default::default()
+        },
+        FennecBookmark {
+            _id: -3,
+            parent: 0,
+            guid: Guid::from("pinned"),
+            title: Some("Pinned".to_owned()),
+            r#type: &FennecBookmarkType::Folder,
+            ..Default::default()
+        },
+        FennecBookmark {
+            _id: 1,
+            parent: 0,
+            guid: Guid::from("mobile"),
+            title: Some("Mobile Bookmarks".to_owned()),
+            r#type: &FennecBookmarkType::Folder,
+            ..Default::default()
+        },
+        FennecBookmark {
+            _id: 2,
+            parent: -3,
+            position: 4,
+            title: Some("Mozilla".to_owned()),
+            url: Some("https://www.mozilla.org/".to_owned()),
+            created: Some(Timestamp(1_500_000_000_000)),
+            ..Default::default()
+        },
+        FennecBookmark {
+            _id: 3,
+            parent: -3,
+            position: 1,
+            title: Some("Example".to_owned()),
+            url: Some("https://example.com/".to_owned()),
+            ..Default::default()
+        },
+        FennecBookmark {
+            _id: 4,
+            parent: -3,
+            position: 2,
+            title: Some("Not a valid URL".to_owned()),
+            url: Some("foo bar".to_owned()),
+            ..Default::default()
+        },
+        FennecBookmark {
+            _id: 5,
+            parent: -3,
+            position: 3,
+            url: Some("https://deleted.example.com/".to_owned()),
+            deleted: true,
+            ..Default::default()
+        },
+        // Not pinned.
+        FennecBookmark {
+            _id: 6,
+            parent: 1,
+            url: Some("https://bookmarked.example.com/".to_owned()),
+            ..Default::default()
+        },
+    ];
+    insert_bookmarks(&fennec_db, &bookmarks)?;
+
+    let places_api = PlacesApi::new(tmpdir.path().join("places.sqlite"))?;
+    let num_imported = places::import::import_fennec_pinned_sites(&places_api, fennec_path)?;
+    assert_eq!(num_imported, 2);
+
+    let conn = places_api.open_connection(ConnectionType::ReadOnly)?;
+    let sites = pinned_sites::get_pinned_sites(&conn)?;
+    assert_eq!(
+        sites
+            .iter()
+            .map(|site| (
+                site.position,
+                site.url.as_str(),
+                site.title.as_ref().unwrap().as_str()
+            ))
+            .collect::<Vec<_>>(),
+        vec![
+            (1, "https://example.com/", "Example"),
+            (4, "https://www.mozilla.org/", "Mozilla"),
+        ]
+    );
+    assert_eq!(sites[1].date_added, Timestamp(1_500_000_000_000));
+    Ok(())
+}
@@ -255,3 +255,140 @@ fn test_import() -> Result<()> {
 
     Ok(())
 }
+
+fn visit_types_for_url(conn: &Connection, url: &str) -> Result<Vec<u8>> {
+    let mut stmt = conn.prepare(
+        "SELECT v.visit_type FROM moz_historyvisits v
+         JOIN moz_places h ON h.id = v.place_id
+         WHERE h.url = :url
+         ORDER BY v.visit_date",
+    )?;
+    let types = stmt
+        .query_map_named(&[(":url", &url)], |row| row.get::<_, u8>(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(types)
+}
+
+#[test]
+fn test_import_visit_types() -> Result<()> {
+    let tmpdir = tempdir().unwrap();
+    let fennec_path = tmpdir.path().join("browser.db");
+    let fennec_db = empty_fennec_db(&fennec_path)?;
+
+    let history = [FennecHistory {
+        url: "https://example.com/".to_owned(),
+        ..Default::default()
+    }];
+    let visits = [
+        FennecVisit {
+            history: &history[0],
+            visit_type: VisitTransition::Typed,
+            date: Timestamp::from(1_565_117_389_897),
+            is_local: true,
+        },
+        FennecVisit {
+            history: &history[0],
+            visit_type: VisitTransition::Embed,
+            date: Timestamp::from(1_565_117_389_898),
+            is_local: true,
+        },
+        FennecVisit {
+            history: &history[0],
+            visit_type: VisitTransition::Reload,
+            date: Timestamp::from(1_565_117_389_899),
+            is_local: false,
+        },
+    ];
+    insert_history_and_visits(&fennec_db, &history, &visits)?;
+    // Fennec doesn't validate synced visit types.
+    fennec_db.execute(
+        "INSERT INTO visits(history_guid, visit_type, date, is_local)
+         VALUES (?, 42, 1565117389900, 0)",
+        &[&history[0].guid],
+    )?;
+
+    let places_api = PlacesApi::new(tmpdir.path().join("places.sqlite"))?;
+    places::import::import_fennec_history(&places_api, &fennec_path)?;
+    // Importing again shouldn't duplicate visits.
+    places::import::import_fennec_history(&places_api, &fennec_path)?;
+
+    let conn = places_api.open_connection(places::ConnectionType::ReadOnly)?;
+    assert_eq!(
+        visit_types_for_url(&conn, "https://example.com/")?,
+        vec![
+            VisitTransition::Typed as u8,
+            VisitTransition::Reload as u8,
+            VisitTransition::Link as u8,
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_import_resumes() -> Result<()> {
+    let tmpdir = tempdir().unwrap();
+    let fennec_path = tmpdir.path().join("browser.db");
+    let fennec_db = empty_fennec_db(&fennec_path)?;
+
+    let history = [FennecHistory {
+        url: "https://example.com/".to_owned(),
+        ..Default::default()
+    }];
+    let visits = [
+        FennecVisit {
+            history: &history[0],
+            visit_type: VisitTransition::Link,
+            date: Timestamp::from(1_565_117_389_897),
+            is_local: true,
+        },
+        FennecVisit {
+            history: &history[0],
+            visit_type: VisitTransition::Typed,
+            date: Timestamp::from(1_565_117_389_898),
+            is_local: true,
+        },
+        FennecVisit {
+            history: &history[0],
+            visit_type: VisitTransition::Bookmark,
+            date: Timestamp::from(1_565_117_389_899),
+            is_local: true,
+        },
+    ];
+    insert_history_and_visits(&fennec_db, &history, &visits)?;
+
+    let places_api = PlacesApi::new(tmpdir.path().join("places.sqlite"))?;
+    let conn = places_api.open_connection(places::ConnectionType::ReadWrite)?;
+
+    // Pretend that a previous import was interrupted after the first two
+    // visits.
+    let last_visit_id = fennec_db.query_row(
+        "SELECT _id FROM visits ORDER BY _id LIMIT 1 OFFSET 1",
+        NO_PARAMS,
+        |row| row.get::<_, i64>(0),
+    )?;
+    conn.execute(
+        "INSERT INTO moz_meta(key, value)
+         VALUES ('fennec_history_import_last_visit_id', ?)",
+        &[&last_visit_id],
+    )?;
+
+    places::import::import_fennec_history(&places_api, &fennec_path)?;
+    assert_eq!(
+        visit_types_for_url(&conn, "https://example.com/")?,
+        vec![VisitTransition::Bookmark as u8]
+    );
+
+    // Once an import finishes, the next one starts from the beginning.
+    places::import::import_fennec_history(&places_api, &fennec_path)?;
+    assert_eq!(
+        visit_types_for_url(&conn, "https://example.com/")?,
+        vec![
+            VisitTransition::Link as u8,
+            VisitTransition::Typed as u8,
+            VisitTransition::Bookmark as u8,
+        ]
+    );
+
+    Ok(())
+}
@@ -91,6 +91,8 @@ pub fn get_cli_fxa(config: Config, cred_file: &str) -> Result<CliFxa> {
         key_id: key.kid.clone(),
         access_token: token_info.token.clone(),
         tokenserver_url: tokenserver_url.clone(),
+        access_token_refresher: None,
+        interceptors: vec![],
     };
     let root_sync_key = KeyBundle::from_ksync_bytes(&key.key_bytes()?)?;
 
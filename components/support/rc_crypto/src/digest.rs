@@ -68,6 +68,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn sha1_digest() {
+        assert_eq!(
+            hex::encode(&digest(&SHA1, MESSAGE).unwrap()),
+            "b736efda7342c257b42af16d6f7b8da01d5aa165"
+        );
+    }
+
     #[test]
     fn digest_cleanly_rejects_gigantic_messages() {
         let message = vec![0; (std::i32::MAX as usize) + 1];
@@ -0,0 +1,80 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use crate::{digest, error::*, hmac};
+
+/// Fill `out` with key material derived from `secret` and `salt`, using
+/// PBKDF2 (RFC 2898) with HMAC-`digest_alg` as the pseudorandom function.
+pub fn derive(
+    digest_alg: &'static digest::Algorithm,
+    iterations: u32,
+    salt: &[u8],
+    secret: &[u8],
+    out: &mut [u8],
+) -> Result<()> {
+    if iterations == 0 {
+        return Err(ErrorKind::InternalError.into());
+    }
+    let key = hmac::SigningKey::new(digest_alg, secret);
+    let mut written = 0;
+    // Block indices are 1-based.
+    let mut block_index: u32 = 1;
+    while written < out.len() {
+        let mut salted = salt.to_vec();
+        salted.extend_from_slice(&block_index.to_be_bytes());
+        let mut u = hmac::sign(&key, &salted)?;
+        let mut block = u.as_ref().to_vec();
+        for _ in 1..iterations {
+            u = hmac::sign(&key, u.as_ref())?;
+            for (b, u_b) in block.iter_mut().zip(u.as_ref()) {
+                *b ^= u_b;
+            }
+        }
+        let len = std::cmp::min(block.len(), out.len() - written);
+        out[written..written + len].copy_from_slice(&block[..len]);
+        written += len;
+        block_index += 1;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex;
+
+    // Test vectors from RFC 7914, section 11.
+    #[test]
+    fn pbkdf2_produces_correct_result() {
+        let mut out = vec![0u8; 64];
+        derive(&digest::SHA256, 1, b"salt", b"passwd", &mut out).unwrap();
+        assert_eq!(
+            hex::encode(&out),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc\
+             49ca9cccf179b645991664b39d77ef317c71b845b1e30bd509112041d3a19783"
+        );
+        derive(&digest::SHA256, 80000, b"NaCl", b"Password", &mut out).unwrap();
+        assert_eq!(
+            hex::encode(&out),
+            "4ddcd8f60b98be21830cee5ef22701f9641a4418d04c0414aeff08876b34ab56\
+             a1d425a1225833549adb841b51c9b3176a272bdebba1d078478f62b397f33c8d"
+        );
+    }
+
+    #[test]
+    fn pbkdf2_truncates_output() {
+        let mut out = vec![0u8; 20];
+        derive(&digest::SHA256, 1, b"salt", b"passwd", &mut out).unwrap();
+        assert_eq!(
+            hex::encode(&out),
+            "55ac046e56e3089fec1691c22544b605f9418521"
+        );
+    }
+
+    #[test]
+    fn pbkdf2_rejects_zero_iterations() {
+        let mut out = vec![0u8; 32];
+        assert!(derive(&digest::SHA256, 0, b"salt", b"passwd", &mut out).is_err());
+    }
+}
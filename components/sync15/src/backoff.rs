@@ -0,0 +1,143 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Tracks when the servers have asked us to back off, so that we stop making
+//! requests until they're ready for us again.
+//!
+//! The state is shared between the storage client (which records backoff as
+//! it sees it in responses) and the `PersistedGlobalState` (which persists it
+//! between syncs), so it survives the client being re-created, and the app
+//! being restarted.
+
+use crate::error::{ErrorKind, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use viaduct::{header_names, Response};
+
+// How long we back off for when the server sends a `Retry-After` we don't
+// understand.
+const RETRY_AFTER_DEFAULT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, Default)]
+pub struct BackoffState(Arc<Mutex<Option<SystemTime>>>);
+
+impl BackoffState {
+    /// Returns the time we're backed off until, if it's in the future.
+    pub fn until(&self, now: SystemTime) -> Option<SystemTime> {
+        let until = *self.0.lock().unwrap();
+        until.filter(|until| *until > now)
+    }
+
+    /// Returns true if we've never been asked to back off, or the backoff
+    /// has expired, so there's nothing worth persisting.
+    pub fn is_expired(&self) -> bool {
+        self.until(SystemTime::now()).is_none()
+    }
+
+    /// Fails with a `BackoffError` if we're currently backed off.
+    pub fn check(&self, now: SystemTime) -> Result<()> {
+        match self.until(now) {
+            Some(until) => Err(ErrorKind::BackoffError(until).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Records that we should back off until `until`. If we're already backed
+    /// off for longer, that wins.
+    pub fn backoff_until(&self, until: SystemTime) {
+        let mut current = self.0.lock().unwrap();
+        if current.map_or(true, |current| current < until) {
+            log::warn!("Backing off until {:?}", until);
+            *current = Some(until);
+        }
+    }
+
+    /// Records any backoff requested by the storage server in `resp`, either
+    /// explicitly with `X-Weave-Backoff`, or with a `Retry-After` on a 503 or
+    /// 429.
+    pub fn note_response(&self, resp: &Response, now: SystemTime) {
+        if let Some(secs) = resp
+            .headers
+            .get_as::<u64, _>(header_names::X_WEAVE_BACKOFF)
+            .and_then(|r| r.ok())
+        {
+            self.backoff_until(now + Duration::from_secs(secs));
+        }
+        if resp.status == 503 || resp.status == 429 {
+            let delay = resp
+                .headers
+                .get_as::<f64, _>(header_names::RETRY_AFTER)
+                .and_then(|r| r.ok())
+                .map_or(RETRY_AFTER_DEFAULT, |secs| {
+                    Duration::from_millis((secs * 1000f64) as u64)
+                });
+            self.backoff_until(now + delay);
+        }
+    }
+}
+
+impl PartialEq for BackoffState {
+    fn eq(&self, other: &Self) -> bool {
+        *self.0.lock().unwrap() == *other.0.lock().unwrap()
+    }
+}
+
+// We persist the time as milliseconds since the epoch.
+impl Serialize for BackoffState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let until = *self.0.lock().unwrap();
+        until
+            .and_then(|until| until.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_millis() as u64)
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for BackoffState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let until = Option::<u64>::deserialize(deserializer)?
+            .map(|ms| UNIX_EPOCH + Duration::from_millis(ms));
+        Ok(BackoffState(Arc::new(Mutex::new(until))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let backoff = BackoffState::default();
+        assert!(backoff.check(now).is_ok());
+
+        backoff.backoff_until(now + Duration::from_secs(60));
+        // A shorter backoff doesn't replace a longer one.
+        backoff.backoff_until(now + Duration::from_secs(30));
+        assert_eq!(backoff.until(now), Some(now + Duration::from_secs(60)));
+        match backoff.check(now).unwrap_err().kind() {
+            ErrorKind::BackoffError(until) => assert_eq!(*until, now + Duration::from_secs(60)),
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // Clones share the state.
+        let shared = backoff.clone();
+        shared.backoff_until(now + Duration::from_secs(120));
+        assert_eq!(backoff.until(now), Some(now + Duration::from_secs(120)));
+
+        // And once the time has passed, we're no longer backed off.
+        assert!(backoff.check(now + Duration::from_secs(121)).is_ok());
+    }
+
+    #[test]
+    fn test_serde() {
+        let backoff = BackoffState::default();
+        assert_eq!(serde_json::to_string(&backoff).unwrap(), "null");
+        backoff.backoff_until(UNIX_EPOCH + Duration::from_millis(1_234_567));
+        let s = serde_json::to_string(&backoff).unwrap();
+        assert_eq!(s, "1234567");
+        assert_eq!(serde_json::from_str::<BackoffState>(&s).unwrap(), backoff);
+    }
+}
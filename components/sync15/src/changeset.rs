@@ -3,12 +3,19 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::bso_record::{EncryptedBso, Payload};
-use crate::client::{Sync15ClientResponse, Sync15StorageClient};
-use crate::error::{self, ErrorKind, ErrorResponse, Result};
+use crate::client::Sync15StorageClient;
+use crate::error::{self, Error, ErrorKind, ErrorResponse, Result};
 use crate::key_bundle::KeyBundle;
-use crate::request::{CollectionRequest, NormalResponseHandler, UploadInfo};
+use crate::request::{
+    BatchPoster, CollectionRequest, NormalResponseHandler, PostQueue, PostResponseHandler,
+    UploadInfo,
+};
 use crate::util::ServerTimestamp;
 use crate::CollState;
+use sync_guid::Guid;
+
+// How many times we try to upload a changeset before giving up.
+const MAX_UPLOAD_ATTEMPTS: usize = 2;
 
 #[derive(Debug, Clone)]
 pub struct RecordChangeset<Payload> {
@@ -65,29 +72,56 @@ impl IncomingChangeset {
         collection: String,
         collection_request: &CollectionRequest,
     ) -> Result<IncomingChangeset> {
-        let (records, timestamp) = match client.get_encrypted_records(collection_request)? {
-            Sync15ClientResponse::Success {
-                record,
-                last_modified,
-                ..
-            } => (record, last_modified),
-            other => return Err(other.create_storage_error().into()),
-        };
-        // xxx - duplication below of `timestamp` smells wrong
-        state.last_modified = timestamp;
+        let mut changes = Vec::new();
+        let timestamp = IncomingChangeset::fetch_chunks(
+            client,
+            state,
+            collection.clone(),
+            collection_request,
+            |chunk| {
+                changes.extend(chunk.changes);
+                Ok(())
+            },
+        )?;
         let mut result = IncomingChangeset::new(collection, timestamp);
-        result.changes.reserve(records.len());
-        for record in records {
-            // if we see a HMAC error, we've made an explicit decision to
-            // NOT handle it here, but restart the global state machine.
-            // That should cause us to re-read crypto/keys and things should
-            // work (although if for some reason crypto/keys was updated but
-            // not all storage was wiped we are probably screwed.)
-            let decrypted = record.decrypt(&state.key)?;
-            result.changes.push(decrypted.into_timestamped_payload());
-        }
+        result.changes = changes;
         Ok(result)
     }
+
+    /// Like `fetch`, but passes each page of decrypted records to `on_chunk`
+    /// as it's downloaded, instead of collecting them all. The timestamp of
+    /// each chunk is the collection's last modified time from `state`.
+    /// Returns the last modified time reported by the server.
+    pub fn fetch_chunks<F>(
+        client: &Sync15StorageClient,
+        state: &mut CollState,
+        collection: String,
+        collection_request: &CollectionRequest,
+        mut on_chunk: F,
+    ) -> Result<ServerTimestamp>
+    where
+        F: FnMut(IncomingChangeset) -> Result<()>,
+    {
+        let key = &state.key;
+        let chunk_timestamp = state.last_modified;
+        let timestamp = client.get_encrypted_records_paged(collection_request, |records| {
+            let mut chunk = IncomingChangeset::new(collection.clone(), chunk_timestamp);
+            chunk.changes.reserve(records.len());
+            for record in records {
+                // if we see a HMAC error, we've made an explicit decision to
+                // NOT handle it here, but restart the global state machine.
+                // That should cause us to re-read crypto/keys and things should
+                // work (although if for some reason crypto/keys was updated but
+                // not all storage was wiped we are probably screwed.)
+                let decrypted = record.decrypt(key)?;
+                chunk.changes.push(decrypted.into_timestamped_payload());
+            }
+            on_chunk(chunk)
+        })?;
+        // xxx - duplication below of `timestamp` smells wrong
+        state.last_modified = timestamp;
+        Ok(timestamp)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -129,12 +163,7 @@ impl<'a> CollectionUpdate<'a> {
         let xius = changeset.timestamp;
         if xius < state.last_modified {
             // We know we are going to fail the XIUS check...
-            return Err(
-                ErrorKind::StorageHttpError(ErrorResponse::PreconditionFailed {
-                    route: collection,
-                })
-                .into(),
-            );
+            return Err(ErrorKind::Conflict(collection).into());
         }
         let to_update = changeset.encrypt(&state.key)?;
         Ok(CollectionUpdate::new(
@@ -149,8 +178,17 @@ impl<'a> CollectionUpdate<'a> {
 
     /// Returns a list of the IDs that failed if allowed_dropped_records is true, otherwise
     /// returns an empty vec.
+    ///
+    /// Records that are too large for the server to accept are never sent,
+    /// even if `fully_atomic` is true - they're skipped and listed in
+    /// `skipped_ids`, so that one oversized record doesn't stop every other
+    /// record from being uploaded.
+    ///
+    /// If the upload fails with a server or network error before any records
+    /// were committed, the batch is abandoned and the whole upload is retried
+    /// once. Since we send the same `X-If-Unmodified-Since`, the retry fails
+    /// with a conflict if the server did commit the first attempt after all.
     pub fn upload(self) -> error::Result<UploadInfo> {
-        let mut failed = vec![];
         let mut q = self.client.new_post_queue(
             &self.collection,
             &self.state.config,
@@ -158,16 +196,22 @@ impl<'a> CollectionUpdate<'a> {
             NormalResponseHandler::new(!self.fully_atomic),
         )?;
 
-        for record in self.to_update.into_iter() {
-            let enqueued = q.enqueue(&record)?;
-            if !enqueued && self.fully_atomic {
-                return Err(ErrorKind::RecordTooLargeError.into());
+        let mut attempts = 0;
+        let skipped = loop {
+            attempts += 1;
+            match post_records(&mut q, &self.to_update) {
+                Ok(skipped) => break skipped,
+                Err(e) => {
+                    if attempts >= MAX_UPLOAD_ATTEMPTS || q.has_committed() || !is_transient(&e) {
+                        return Err(e);
+                    }
+                    log::warn!("Upload failed before anything was committed; retrying");
+                }
             }
-        }
+        };
 
-        q.flush(true)?;
         let mut info = q.completed_upload_info();
-        info.failed_ids.append(&mut failed);
+        info.skipped_ids = skipped;
         if self.fully_atomic {
             assert_eq!(
                 info.failed_ids.len(),
@@ -178,3 +222,31 @@ impl<'a> CollectionUpdate<'a> {
         Ok(info)
     }
 }
+
+// Enqueues and commits `records`, returning the IDs of the records that were
+// too large to upload.
+fn post_records<P, O>(q: &mut PostQueue<P, O>, records: &[EncryptedBso]) -> error::Result<Vec<Guid>>
+where
+    P: BatchPoster,
+    O: PostResponseHandler,
+{
+    let mut skipped = vec![];
+    for record in records {
+        if !q.enqueue(record)? {
+            log::warn!("Skipping record {} as it's too large to upload", record.id);
+            skipped.push(record.id.clone());
+        }
+    }
+    q.flush(true)?;
+    Ok(skipped)
+}
+
+// Errors that might not happen again if we retry the upload.
+fn is_transient(e: &Error) -> bool {
+    match e.kind() {
+        ErrorKind::StorageHttpError(ErrorResponse::ServerError { .. })
+        | ErrorKind::RequestError(_)
+        | ErrorKind::ServerBatchProblem(_) => true,
+        _ => false,
+    }
+}
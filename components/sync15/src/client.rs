@@ -2,23 +2,33 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::backoff::BackoffState;
 use crate::bso_record::{BsoRecord, EncryptedBso};
 use crate::error::{self, ErrorKind, ErrorResponse};
 use crate::record_types::MetaGlobalRecord;
 use crate::request::{
-    BatchPoster, CollectionRequest, InfoCollections, InfoConfiguration, PostQueue, PostResponse,
-    PostResponseHandler,
+    BatchPoster, CollectionRequest, InfoCollectionCounts, InfoCollectionUsage, InfoCollections,
+    InfoConfiguration, InfoQuota, PostQueue, PostResponse, PostResponseHandler, ServerOverview,
 };
+use crate::telemetry;
 use crate::token;
 use crate::util::ServerTimestamp;
 use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use sync_guid::Guid;
 use url::Url;
 use viaduct::{
     header_names::{self, AUTHORIZATION},
     Method, Request, Response,
 };
 
+// The most IDs the server lets us ask for in one request.
+const MAX_IDS_PER_REQUEST: usize = 100;
+
 /// A response from a GET request on a Sync15StorageClient, encapsulating all
 /// the variants users of this client needs to care about.
 #[derive(Debug, Clone)]
@@ -57,6 +67,9 @@ impl<T> Sync15ClientResponse<T> {
                 404 => Sync15ClientResponse::Error(ErrorResponse::NotFound { route }),
                 401 => Sync15ClientResponse::Error(ErrorResponse::Unauthorized { route }),
                 412 => Sync15ClientResponse::Error(ErrorResponse::PreconditionFailed { route }),
+                400 | 403 if is_over_quota(&resp) => {
+                    Sync15ClientResponse::Error(ErrorResponse::OverQuota { route })
+                }
                 // / TODO: 5XX errors should parse backoff etc.
                 500..=600 => {
                     Sync15ClientResponse::Error(ErrorResponse::ServerError { route, status })
@@ -66,6 +79,15 @@ impl<T> Sync15ClientResponse<T> {
         })
     }
 
+    /// Returns the record from a successful response, or the error for an
+    /// unsuccessful one.
+    pub fn into_record(self) -> error::Result<T> {
+        match self {
+            Sync15ClientResponse::Success { record, .. } => Ok(record),
+            other => Err(other.create_storage_error().into()),
+        }
+    }
+
     pub fn create_storage_error(self) -> ErrorKind {
         let inner = match self {
             Sync15ClientResponse::Success { status, route, .. } => {
@@ -75,17 +97,85 @@ impl<T> Sync15ClientResponse<T> {
                 log::warn!("Converting success response into an error");
                 ErrorResponse::RequestFailed { status, route }
             }
+            Sync15ClientResponse::Error(ErrorResponse::PreconditionFailed { route }) => {
+                return ErrorKind::Conflict(route);
+            }
+            Sync15ClientResponse::Error(ErrorResponse::OverQuota { route }) => {
+                return ErrorKind::OverQuota(collection_for_route(&route));
+            }
             Sync15ClientResponse::Error(e) => e,
         };
         ErrorKind::StorageHttpError(inner)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+/// Implemented by the application to give us a new FxA access token when the
+/// token server rejects the one we have, so that a sync that's already under
+/// way can carry on instead of failing with an auth error.
+pub trait AccessTokenRefresher {
+    fn refresh_access_token(&self) -> Result<String, failure::Error>;
+}
+
+/// Implemented to observe every request the storage client sends, and every
+/// response it receives. Useful for debugging, capturing traffic in tests, and
+/// adding headers that self-hosted servers need.
+pub trait StorageInterceptor {
+    /// Called just before `request` is sent. Implementations may add or
+    /// replace headers, but shouldn't change the method, URL or body, because
+    /// the request has already been signed. This is called for every attempt,
+    /// so requests we retry with a new token are seen more than once.
+    fn on_request(&self, _request: &mut Request) {}
+
+    /// Called with every response, before we look at it.
+    fn on_response(&self, _request: &Request, _response: &Response) {}
+}
+
+#[derive(Clone)]
 pub struct Sync15StorageClientInit {
     pub key_id: String,
     pub access_token: String,
     pub tokenserver_url: Url,
+    /// Called if the token server rejects `access_token` with a 401. If this
+    /// is `None`, or it fails, the sync fails with an auth error.
+    pub access_token_refresher: Option<Arc<dyn AccessTokenRefresher + Send + Sync>>,
+    /// Called, in order, for every request the client sends.
+    pub interceptors: Vec<Arc<dyn StorageInterceptor + Send + Sync>>,
+}
+
+impl PartialEq for Sync15StorageClientInit {
+    fn eq(&self, other: &Self) -> bool {
+        self.key_id == other.key_id
+            && self.access_token == other.access_token
+            && self.tokenserver_url == other.tokenserver_url
+            && match (&self.access_token_refresher, &other.access_token_refresher) {
+                (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+                (None, None) => true,
+                _ => false,
+            }
+            && self.interceptors.len() == other.interceptors.len()
+            && self
+                .interceptors
+                .iter()
+                .zip(other.interceptors.iter())
+                .all(|(a, b)| Arc::ptr_eq(a, b))
+    }
+}
+
+impl Eq for Sync15StorageClientInit {}
+
+impl fmt::Debug for Sync15StorageClientInit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sync15StorageClientInit")
+            .field("key_id", &self.key_id)
+            .field("access_token", &"(omitted)")
+            .field("tokenserver_url", &self.tokenserver_url)
+            .field(
+                "access_token_refresher",
+                &self.access_token_refresher.is_some(),
+            )
+            .field("interceptors", &self.interceptors.len())
+            .finish()
+    }
 }
 
 /// A trait containing the methods required to run through the setup state
@@ -109,6 +199,42 @@ pub trait SetupStorageClient {
 #[derive(Debug)]
 pub struct Sync15StorageClient {
     tsc: token::TokenProvider,
+    backoff: BackoffState,
+    interceptors: Interceptors,
+    requests: Mutex<BTreeMap<String, telemetry::Requests>>,
+}
+
+// A wrapper so we can derive `Debug` for the client.
+struct Interceptors(Vec<Arc<dyn StorageInterceptor + Send + Sync>>);
+
+impl fmt::Debug for Interceptors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} interceptors", self.0.len())
+    }
+}
+
+impl Interceptors {
+    fn send(&self, mut req: Request) -> error::Result<Response> {
+        if self.0.is_empty() {
+            return Ok(req.send()?);
+        }
+        self.on_request(&mut req);
+        let resp = req.clone().send()?;
+        self.on_response(&req, &resp);
+        Ok(resp)
+    }
+
+    fn on_request(&self, req: &mut Request) {
+        for interceptor in &self.0 {
+            interceptor.on_request(req);
+        }
+    }
+
+    fn on_response(&self, req: &Request, resp: &Response) {
+        for interceptor in &self.0 {
+            interceptor.on_response(req, resp);
+        }
+    }
 }
 
 impl SetupStorageClient for Sync15StorageClient {
@@ -157,6 +283,9 @@ impl SetupStorageClient for Sync15StorageClient {
         self.put("storage/crypto/keys", xius, keys)
     }
 
+    // Unlike our other writes, this is deliberately unconditional (ie, it
+    // doesn't send `X-If-Unmodified-Since`): we only wipe when we're starting
+    // over, and want everything gone, whatever other clients have written.
     fn wipe_all_remote(&self) -> error::Result<()> {
         let s = self.tsc.api_endpoint()?;
         let url = Url::parse(&s)?;
@@ -173,13 +302,86 @@ impl SetupStorageClient for Sync15StorageClient {
 
 impl Sync15StorageClient {
     pub fn new(init_params: Sync15StorageClientInit) -> error::Result<Sync15StorageClient> {
-        rc_crypto::ensure_initialized();
+        crate::crypto::ensure_initialized();
         let tsc = token::TokenProvider::new(
             init_params.tokenserver_url,
             init_params.access_token,
             init_params.key_id,
+            init_params.access_token_refresher,
         )?;
-        Ok(Sync15StorageClient { tsc })
+        Ok(Sync15StorageClient {
+            tsc,
+            backoff: BackoffState::default(),
+            interceptors: Interceptors(init_params.interceptors),
+            requests: Mutex::default(),
+        })
+    }
+
+    /// Fetches `info/quota`, which reports how much storage the user is
+    /// using. Note that `info/collections`, which lists the last modified
+    /// time of every collection, is available via `SetupStorageClient`.
+    pub fn fetch_info_quota(&self) -> error::Result<Sync15ClientResponse<InfoQuota>> {
+        self.relative_storage_request(Method::Get, "info/quota")
+    }
+
+    /// Fetches `info/collection_counts`, the number of records in each
+    /// collection.
+    pub fn fetch_info_collection_counts(
+        &self,
+    ) -> error::Result<Sync15ClientResponse<InfoCollectionCounts>> {
+        self.relative_storage_request(Method::Get, "info/collection_counts")
+    }
+
+    /// Fetches `info/collection_usage`, the size of each collection.
+    pub fn fetch_info_collection_usage(
+        &self,
+    ) -> error::Result<Sync15ClientResponse<InfoCollectionUsage>> {
+        self.relative_storage_request(Method::Get, "info/collection_usage")
+    }
+
+    /// Fetches all the `info/` endpoints and combines them into a
+    /// per-collection summary of what's stored on the server.
+    pub fn get_server_overview(&self) -> error::Result<ServerOverview> {
+        let collections = self.fetch_info_collections()?.into_record()?;
+        let counts = self.fetch_info_collection_counts()?.into_record()?;
+        let usage = self.fetch_info_collection_usage()?.into_record()?;
+        let quota = self.fetch_info_quota()?.into_record()?;
+        Ok(ServerOverview::new(&collections, &counts, &usage, quota))
+    }
+
+    /// Deletes all the user's data on the server (`DELETE /storage`),
+    /// including `meta/global` and `crypto/keys`. Like `wipe_all_remote`,
+    /// this is unconditional, and succeeds if there's nothing to delete.
+    pub fn delete_all(&self) -> error::Result<()> {
+        match self.relative_storage_request::<_, Value>(Method::Delete, "storage")? {
+            Sync15ClientResponse::Error(ErrorResponse::NotFound { .. })
+            | Sync15ClientResponse::Success { .. } => Ok(()),
+            resp => Err(resp.create_storage_error().into()),
+        }
+    }
+
+    /// Fetches the records in `collection` with the given `ids`, for example
+    /// to re-fetch records that failed to decrypt or validate, without
+    /// downloading the whole collection. The IDs are split across as many
+    /// requests as the server's limit needs. Records that don't exist on the
+    /// server, or are in a collection that doesn't exist, are left out of the
+    /// result.
+    pub fn get_encrypted_records_by_ids(
+        &self,
+        collection: &str,
+        ids: &[Guid],
+    ) -> error::Result<Vec<EncryptedBso>> {
+        let mut records = Vec::with_capacity(ids.len());
+        for chunk in ids.chunks(MAX_IDS_PER_REQUEST) {
+            let request = CollectionRequest::new(collection).full().ids(chunk);
+            match self.get_encrypted_records(&request)? {
+                Sync15ClientResponse::Success { record, .. } => records.extend(record),
+                // The collection doesn't exist, so neither do the records.
+                Sync15ClientResponse::Error(ErrorResponse::NotFound { .. }) => break,
+                other => return Err(other.create_storage_error().into()),
+            }
+        }
+        Ok(records)
     }
 
     pub fn get_encrypted_records(
@@ -189,6 +391,131 @@ impl Sync15StorageClient {
         self.collection_request(Method::Get, collection_request)
     }
 
+    /// Fetches the records for `collection_request`, passing them to
+    /// `on_page` as they're downloaded. If the request has a `page_size`, we
+    /// fetch one page at a time, following `X-Weave-Next-Offset` until we've
+    /// seen every record (or `limit` records, if set), so that we never need
+    /// to hold the entire response in memory.
+    ///
+    /// Subsequent pages are fetched with `X-If-Unmodified-Since` set to the
+    /// timestamp of the first page, so if another client writes to the
+    /// collection while we're paging, the request fails, and the sync should
+    /// be retried, rather than silently missing records.
+    ///
+    /// Returns the last modified timestamp of the collection.
+    pub fn get_encrypted_records_paged<F>(
+        &self,
+        collection_request: &CollectionRequest,
+        mut on_page: F,
+    ) -> error::Result<ServerTimestamp>
+    where
+        F: FnMut(Vec<EncryptedBso>) -> error::Result<()>,
+    {
+        let page_size = collection_request.page_size;
+        let mut remaining = collection_request.limit;
+        let mut request = collection_request.clone();
+        let mut unmodified_since = None;
+        loop {
+            request.limit = page_limit(page_size, remaining);
+            let url = request.build_url(Url::parse(&self.tsc.api_endpoint()?)?)?;
+            let mut req = self.build_request(Method::Get, url)?;
+            if let Some(ts) = unmodified_since {
+                req = req.header(header_names::X_IF_UNMODIFIED_SINCE, format!("{}", ts))?;
+            }
+            log::trace!("request: GET {} ({:?})", req.url.path(), req.url.query());
+            let resp = self.send(req)?;
+            log::trace!("response: {}", resp.status);
+            let next_offset = resp
+                .headers
+                .get(header_names::X_WEAVE_NEXT_OFFSET)
+                .map(ToString::to_string);
+            let (records, last_modified) =
+                match Sync15ClientResponse::<Vec<EncryptedBso>>::from_response(resp)? {
+                    Sync15ClientResponse::Success {
+                        record,
+                        last_modified,
+                        ..
+                    } => (record, last_modified),
+                    other => return Err(other.create_storage_error().into()),
+                };
+            let num_records = records.len();
+            on_page(records)?;
+            let timestamp = *unmodified_since.get_or_insert(last_modified);
+            if page_size == 0 {
+                return Ok(timestamp);
+            }
+            if remaining > 0 {
+                remaining = remaining.saturating_sub(num_records);
+                if remaining == 0 {
+                    return Ok(timestamp);
+                }
+            }
+            match next_offset {
+                Some(offset) => request.offset = Some(offset),
+                None => return Ok(timestamp),
+            }
+        }
+    }
+
+    // Sends an authorized request. If the storage server rejects our token,
+    // which can happen if it's revoked or expires earlier than we expected,
+    // we fetch a new token (refreshing the access token if necessary) and
+    // try again, once.
+    //
+    // We also refuse to send anything while the server has asked us to back
+    // off.
+    //
+    // Every request is recorded in the telemetry for its collection.
+    fn send(&self, req: Request) -> error::Result<Response> {
+        self.backoff.check(SystemTime::now())?;
+        let collection = collection_for_route(req.url.path());
+        let body_len = req.body.as_ref().map_or(0, Vec::len);
+        let started = Instant::now();
+        let mut attempts = 0;
+        let result = self.send_with_token_retry(req, &mut attempts);
+        self.requests
+            .lock()
+            .unwrap()
+            .entry(collection)
+            .or_default()
+            .record(
+                result.as_ref().ok().map(|resp| resp.status),
+                started.elapsed(),
+                body_len * attempts as usize,
+                result.as_ref().ok().map_or(0, |resp| resp.body.len()),
+                attempts.saturating_sub(1),
+            );
+        result
+    }
+
+    fn send_with_token_retry(&self, req: Request, attempts: &mut u32) -> error::Result<Response> {
+        *attempts += 1;
+        let resp = self.interceptors.send(req.clone())?;
+        self.backoff.note_response(&resp, SystemTime::now());
+        if resp.status != 401 {
+            return Ok(resp);
+        }
+        log::info!("Storage server rejected our token; fetching a new one");
+        self.tsc.drop_token();
+        let req = self.authorized(req)?;
+        *attempts += 1;
+        let resp = self.interceptors.send(req)?;
+        self.backoff.note_response(&resp, SystemTime::now());
+        Ok(resp)
+    }
+
+    /// Returns the requests we've made since this was last called, by
+    /// collection, for the sync telemetry.
+    pub(crate) fn take_request_telemetry(&self) -> BTreeMap<String, telemetry::Requests> {
+        std::mem::replace(&mut *self.requests.lock().unwrap(), BTreeMap::new())
+    }
+
+    /// Shares `backoff` with this client, so that backoff we see is
+    /// persisted, and backoff from previous syncs is honored.
+    pub(crate) fn set_backoff(&mut self, backoff: BackoffState) {
+        self.backoff = backoff;
+    }
+
     #[inline]
     fn authorized(&self, req: Request) -> error::Result<Request> {
         let hawk_header_value = self.tsc.authorization(&req)?;
@@ -229,7 +556,7 @@ impl Sync15StorageClient {
             req.url.path(),
             req.url.query()
         );
-        let resp = req.send()?;
+        let resp = self.send(req)?;
         log::trace!("response: {}", resp.status);
 
         let result = Sync15ClientResponse::from_response(resp)?;
@@ -323,9 +650,198 @@ impl<'a> BatchPoster for PostWrapper<'a> {
     }
 }
 
+// Returns the `limit` to use for the next page of a paged request. A `limit`
+// of 0 means "no limit", both for `remaining` and the result.
+fn page_limit(page_size: usize, remaining: usize) -> usize {
+    match (page_size, remaining) {
+        (0, r) | (r, 0) => r,
+        (p, r) => p.min(r),
+    }
+}
+
+// The collection a request is for, given its route (the path of its URL).
+// This is the collection for `storage/` requests, and "info" for `info/`
+// requests. Requests for `storage` itself, or for the whole account, are for
+// "storage". We record requests in telemetry under this name, and use it to
+// report which collection is over quota.
+fn collection_for_route(route: &str) -> String {
+    let mut segments = route.split('/');
+    while let Some(segment) = segments.next() {
+        match segment {
+            "storage" => {
+                return segments
+                    .next()
+                    .filter(|s| !s.is_empty())
+                    .unwrap_or("storage")
+                    .into()
+            }
+            "info" => return "info".into(),
+            _ => {}
+        }
+    }
+    "storage".into()
+}
+
+// Sync 1.5 servers report that the user is over quota with a 400 and the
+// error code 14 as the body. Some also use a 403, but so do proxies and
+// servers that block the request for other reasons, so we only treat a 403
+// as over quota if its body mentions the quota, or it says there's no quota
+// left in `X-Weave-Quota-Remaining`.
+fn is_over_quota(resp: &Response) -> bool {
+    let body = std::str::from_utf8(&resp.body).unwrap_or_default().trim();
+    match resp.status {
+        400 => body == "14",
+        403 => {
+            body == "14"
+                || body.to_ascii_lowercase().contains("quota")
+                || resp
+                    .headers
+                    .try_get::<f64, _>(header_names::X_WEAVE_QUOTA_REMAINING)
+                    .map_or(false, |remaining| remaining <= 0.0)
+        }
+        _ => false,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_page_limit() {
+        assert_eq!(page_limit(0, 0), 0);
+        assert_eq!(page_limit(0, 5000), 5000);
+        assert_eq!(page_limit(1000, 0), 1000);
+        assert_eq!(page_limit(1000, 5000), 1000);
+        assert_eq!(page_limit(1000, 400), 400);
+    }
+
+    #[test]
+    fn test_collection_for_route() {
+        let collection = |s| collection_for_route(Url::parse(s).unwrap().path());
+        assert_eq!(
+            collection("https://example.com/1.5/12345/storage/history?full=1"),
+            "history"
+        );
+        assert_eq!(
+            collection("https://example.com/1.5/12345/storage/crypto/keys"),
+            "crypto"
+        );
+        assert_eq!(
+            collection("https://example.com/1.5/12345/info/collections"),
+            "info"
+        );
+        assert_eq!(
+            collection("https://example.com/1.5/12345/storage"),
+            "storage"
+        );
+        assert_eq!(collection("https://example.com/1.5/12345"), "storage");
+    }
+
+    #[test]
+    fn test_over_quota() {
+        let url = Url::parse("https://example.com/1.5/12345/storage/bookmarks").unwrap();
+        let response = |status, body: &str| Response {
+            request_method: Method::Post,
+            url: url.clone(),
+            status,
+            headers: viaduct::Headers::new(),
+            body: body.as_bytes().to_vec(),
+        };
+        let with_quota_remaining = |status, remaining: &str| {
+            let mut resp = response(status, "");
+            resp.headers
+                .insert(header_names::X_WEAVE_QUOTA_REMAINING, remaining)
+                .unwrap();
+            resp
+        };
+        assert!(is_over_quota(&response(400, "14\n")));
+        assert!(is_over_quota(&response(403, "14")));
+        assert!(is_over_quota(&response(
+            403,
+            r#"{"status": "quota-exceeded", "errors": []}"#
+        )));
+        assert!(is_over_quota(&with_quota_remaining(403, "0")));
+        assert!(is_over_quota(&with_quota_remaining(403, "-12.5")));
+        // A plain 403 is a forbidden request, not an over quota one.
+        assert!(!is_over_quota(&response(403, "")));
+        assert!(!is_over_quota(&response(403, "Forbidden")));
+        assert!(!is_over_quota(&with_quota_remaining(403, "1024")));
+        assert!(!is_over_quota(&with_quota_remaining(400, "0")));
+        assert!(!is_over_quota(&response(400, "8")));
+        assert!(!is_over_quota(&response(500, "14")));
+
+        let resp = Sync15ClientResponse::<Value>::from_response(response(400, "14")).unwrap();
+        match resp.create_storage_error() {
+            ErrorKind::OverQuota(collection) => assert_eq!(collection, "bookmarks"),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_conflict() {
+        let resp = Sync15ClientResponse::<Value>::Error(ErrorResponse::PreconditionFailed {
+            route: "/1.5/12345/storage/bookmarks".into(),
+        });
+        match resp.create_storage_error() {
+            ErrorKind::Conflict(route) => assert_eq!(route, "/1.5/12345/storage/bookmarks"),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_interceptors() {
+        #[derive(Default)]
+        struct Recorder {
+            requests: Mutex<Vec<Option<String>>>,
+            responses: Mutex<Vec<(Option<String>, u16)>>,
+        }
+        impl StorageInterceptor for Recorder {
+            fn on_request(&self, request: &mut Request) {
+                // Record the header as we saw it, before adding our own.
+                let seen = request.headers.get("X-Test").map(ToString::to_string);
+                self.requests.lock().unwrap().push(seen);
+                request.headers.insert("X-Test", "1").unwrap();
+            }
+            fn on_response(&self, request: &Request, response: &Response) {
+                let sent = request.headers.get("X-Test").map(ToString::to_string);
+                self.responses.lock().unwrap().push((sent, response.status));
+            }
+        }
+        let first = Arc::new(Recorder::default());
+        let second = Arc::new(Recorder::default());
+        let interceptors = Interceptors(vec![first.clone(), second.clone()]);
+        let url = Url::parse("https://example.com/1.5/12345/info/collections").unwrap();
+
+        let mut request = Request::new(Method::Get, url.clone());
+        interceptors.on_request(&mut request);
+        // Each interceptor sees the changes made by the ones before it.
+        assert_eq!(*first.requests.lock().unwrap(), vec![None]);
+        assert_eq!(
+            *second.requests.lock().unwrap(),
+            vec![Some("1".to_string())]
+        );
+        assert_eq!(request.headers.get("X-Test"), Some("1"));
+
+        let response = Response {
+            request_method: Method::Get,
+            url,
+            status: 200,
+            headers: viaduct::Headers::new(),
+            body: b"{}".to_vec(),
+        };
+        interceptors.on_response(&request, &response);
+        // Both see the request as it was sent, with every interceptor's
+        // changes.
+        for recorder in &[first, second] {
+            assert_eq!(
+                *recorder.responses.lock().unwrap(),
+                vec![(Some("1".to_string()), 200)]
+            );
+        }
+    }
+
     #[test]
     fn test_send() {
         fn ensure_send<T: Send>() {}
@@ -0,0 +1,866 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::bso_record::Payload;
+use crate::changeset::{CollectionUpdate, IncomingChangeset, OutgoingChangeset};
+use crate::client::Sync15StorageClient;
+use crate::coll_state::CollState;
+use crate::collection_keys::CollectionKeys;
+use crate::crypto;
+use crate::error::Result;
+use crate::key_bundle::KeyBundle;
+use crate::request::CollectionRequest;
+use crate::state::GlobalState;
+use crate::telemetry;
+use crate::util::ServerTimestamp;
+use interrupt::Interruptee;
+use serde_json::Map;
+use sync_guid::Guid;
+
+use super::record::{ClientCommand, ClientRecord};
+use super::{
+    CommandProcessor, CommandStatus, RemoteClient, RepairRequest, CLIENTS_TTL, CLIENTS_TTL_REFRESH,
+    COLLECTION_NAME,
+};
+
+/// The driver for the clients engine. Processes the incoming client records,
+/// applying any commands sent to us, and works out which records need to be
+/// uploaded.
+struct Driver<'a> {
+    command_processor: &'a dyn CommandProcessor,
+    interruptee: &'a dyn Interruptee,
+    // The current time, used to decide if our record needs refreshing.
+    now: ServerTimestamp,
+    // All the clients we've seen, including us, keyed by record ID.
+    recent_clients: HashMap<String, RemoteClient>,
+    // Telemetry events for commands we've processed.
+    processed_events: Vec<telemetry::Event>,
+    // Telemetry events for commands we're sending, which we only record once
+    // they've been uploaded.
+    sent_events: Vec<telemetry::Event>,
+    // Responses to repair requests sent to us, and the record IDs of the
+    // clients to send them to.
+    repair_responses: Vec<(String, ClientCommand)>,
+}
+
+impl<'a> Driver<'a> {
+    fn new(
+        command_processor: &'a dyn CommandProcessor,
+        interruptee: &'a dyn Interruptee,
+        now: ServerTimestamp,
+    ) -> Driver<'a> {
+        Driver {
+            command_processor,
+            interruptee,
+            now,
+            recent_clients: HashMap::new(),
+            processed_events: Vec::new(),
+            sent_events: Vec::new(),
+            repair_responses: Vec::new(),
+        }
+    }
+
+    fn sync(
+        &mut self,
+        inbound: IncomingChangeset,
+        outgoing_commands: &[ClientCommand],
+    ) -> Result<OutgoingChangeset> {
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME.into(), inbound.timestamp);
+
+        let settings = self.command_processor.settings();
+
+        // Our own record on the server, and when it was last uploaded.
+        let mut existing_current_client = None;
+        let mut other_clients = Vec::new();
+
+        for (payload, modified) in inbound.changes {
+            self.interruptee.err_if_interrupted()?;
+
+            if payload.is_tombstone() {
+                log::debug!("Ignoring tombstone for client {}", payload.id);
+                continue;
+            }
+            let record: ClientRecord = match payload.into_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("Ignoring invalid client record: {}", e);
+                    continue;
+                }
+            };
+
+            if record.id == settings.fxa_device_id {
+                existing_current_client = Some((record, modified));
+            } else {
+                other_clients.push((record, modified));
+            }
+        }
+
+        // Apply the commands sent to us first, so that we can send any
+        // responses to the clients that asked in this sync.
+        if let Some((ref record, _)) = existing_current_client {
+            self.apply_incoming_commands(&record.commands)?;
+        }
+
+        for (record, modified) in other_clients {
+            self.interruptee.err_if_interrupted()?;
+
+            self.note_recent_client(&record, modified);
+
+            // Add any commands we haven't already sent to this client.
+            let mut new_record = record.clone();
+            let responses = self
+                .repair_responses
+                .iter()
+                .filter(|(requestor, _)| *requestor == record.id)
+                .map(|(_, command)| command);
+            for command in outgoing_commands.iter().chain(responses) {
+                if new_record.commands.iter().any(|c| c.same_command(command)) {
+                    continue;
+                }
+                new_record.commands.push(command.clone());
+                if let Some(event) = command_event("sendcommand", command) {
+                    self.sent_events.push(event);
+                }
+            }
+            if new_record != record {
+                outgoing.changes.push(Payload::from_record(new_record)?);
+            }
+        }
+
+        let current_client = self.current_client_record();
+        match existing_current_client {
+            Some((ref record, modified))
+                if !self.should_upload_current_client(&current_client, record, modified) =>
+            {
+                log::debug!("Our client record is up to date");
+                self.note_recent_client(&current_client, modified);
+            }
+            _ => {
+                log::debug!("Uploading our client record");
+                self.note_recent_client(&current_client, self.now);
+                let mut payload = Payload::from_record(current_client)?;
+                payload.data.insert("ttl".into(), CLIENTS_TTL.into());
+                outgoing.changes.push(payload);
+            }
+        }
+
+        Ok(outgoing)
+    }
+
+    fn note_recent_client(&mut self, record: &ClientRecord, modified: ServerTimestamp) {
+        self.recent_clients.insert(
+            record.id.clone(),
+            RemoteClient {
+                fxa_device_id: record.fxa_device_id.clone(),
+                device_name: record.name.clone(),
+                device_type: record.typ.clone(),
+                last_modified: modified,
+            },
+        );
+    }
+
+    fn apply_incoming_commands(&mut self, commands: &[ClientCommand]) -> Result<()> {
+        // Other clients might have sent us the same command more than once.
+        let mut seen = HashSet::new();
+        let mut seen_repair_requests = HashSet::new();
+        for client_command in commands {
+            self.interruptee.err_if_interrupted()?;
+            if let Some(request) = client_command.as_repair_request() {
+                if seen_repair_requests.insert(request.clone()) {
+                    if let Some(event) = command_event("processcommand", client_command) {
+                        self.processed_events.push(event);
+                    }
+                    self.apply_repair_request(&request)?;
+                }
+                continue;
+            }
+            let command = match client_command.as_command() {
+                Some(command) => command,
+                None => {
+                    log::warn!("Ignoring unsupported command {}", client_command.command);
+                    continue;
+                }
+            };
+            if !seen.insert(command.clone()) {
+                continue;
+            }
+            if let Some(event) = command_event("processcommand", client_command) {
+                self.processed_events.push(event);
+            }
+            match self.command_processor.apply_incoming_command(command)? {
+                CommandStatus::Applied => log::info!("Applied incoming command"),
+                CommandStatus::Ignored => log::info!("Ignored incoming command"),
+                CommandStatus::Unsupported => log::warn!("Incoming command is unsupported"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the app to reupload the records in a repair request, and queues
+    /// a response for the client that asked. If the upload fails, our record
+    /// still has the request, so we'll process it again on the next sync.
+    fn apply_repair_request(&mut self, request: &RepairRequest) -> Result<()> {
+        let ids = match self.command_processor.apply_repair_request(request)? {
+            Some(ids) => ids,
+            None => {
+                log::warn!(
+                    "Ignoring unsupported repair request for {}",
+                    request.collection
+                );
+                return Ok(());
+            }
+        };
+        log::info!(
+            "Reuploading {} of {} requested {} records",
+            ids.len(),
+            request.ids.len(),
+            request.collection
+        );
+        let settings = self.command_processor.settings();
+        let response = ClientCommand::repair_response(request, &settings.fxa_device_id, ids);
+        self.repair_responses
+            .push((request.requestor.clone(), response));
+        Ok(())
+    }
+
+    /// Builds our own client record from the current settings. Commands sent
+    /// to us have been applied by the time we upload, so it never has any.
+    fn current_client_record(&self) -> ClientRecord {
+        let settings = self.command_processor.settings();
+        ClientRecord {
+            id: settings.fxa_device_id.clone(),
+            name: settings.device_name.clone(),
+            typ: Some(settings.device_type.clone()),
+            commands: Vec::new(),
+            fxa_device_id: Some(settings.fxa_device_id.clone()),
+            version: None,
+            protocols: vec!["1.5".into()],
+            unknown_fields: Map::new(),
+        }
+    }
+
+    /// We upload our record if any of its fields have changed, if there were
+    /// commands we need to clear, or if it's due to be refreshed before its
+    /// TTL lapses. (We also upload it if it's missing from the server.)
+    fn should_upload_current_client(
+        &self,
+        current: &ClientRecord,
+        existing: &ClientRecord,
+        modified: ServerTimestamp,
+    ) -> bool {
+        !existing.commands.is_empty()
+            || !current.same_description(existing)
+            || self.needs_refresh(modified)
+    }
+
+    fn needs_refresh(&self, modified: ServerTimestamp) -> bool {
+        // If the record appears to be from the future, our clock is probably
+        // wrong, so we don't treat that as a reason to upload.
+        self.now
+            .duration_since(modified)
+            .map_or(false, |age| age >= Duration::from_secs(CLIENTS_TTL_REFRESH))
+    }
+}
+
+pub struct Engine<'a> {
+    pub command_processor: &'a dyn CommandProcessor,
+    pub interruptee: &'a dyn Interruptee,
+    /// The clients we saw in the most recent sync, including us, keyed by
+    /// their record ID.
+    pub recent_clients: HashMap<String, RemoteClient>,
+}
+
+impl<'a> Engine<'a> {
+    pub fn new(
+        command_processor: &'a dyn CommandProcessor,
+        interruptee: &'a dyn Interruptee,
+    ) -> Engine<'a> {
+        Engine {
+            command_processor,
+            interruptee,
+            recent_clients: HashMap::new(),
+        }
+    }
+
+    /// Syncs the clients collection. The clients collection is small, so we
+    /// always fetch all records, which also lets us check our own record is
+    /// current.
+    ///
+    /// `pending_commands` holds commands we've previously accepted from the
+    /// app but failed to upload. New commands from the app are added to it
+    /// before we touch the network, and it's only cleared once they've been
+    /// uploaded, so the caller should persist it even if the sync fails.
+    ///
+    /// Telemetry events for commands we process and send, including their
+    /// flow IDs, are recorded in `telem`.
+    pub fn sync(
+        &mut self,
+        storage_client: &Sync15StorageClient,
+        global_state: &GlobalState,
+        root_sync_key: &KeyBundle,
+        pending_commands: &mut Vec<ClientCommand>,
+        telem: &mut telemetry::SyncTelemetryPing,
+    ) -> Result<()> {
+        log::info!("Syncing collection {}", COLLECTION_NAME);
+
+        self.fetch_pending_commands(pending_commands)?;
+
+        let coll_keys =
+            CollectionKeys::from_encrypted_bso(global_state.keys.clone(), root_sync_key)?;
+        let mut coll_state = CollState {
+            config: global_state.config.clone(),
+            last_modified: global_state
+                .collections
+                .get(COLLECTION_NAME)
+                .cloned()
+                .unwrap_or_default(),
+            key: coll_keys.key_for_collection(COLLECTION_NAME).clone(),
+        };
+
+        let inbound = IncomingChangeset::fetch(
+            storage_client,
+            &mut coll_state,
+            COLLECTION_NAME.into(),
+            &CollectionRequest::new(COLLECTION_NAME).full(),
+        )?;
+
+        let mut driver = Driver::new(self.command_processor, self.interruptee, now());
+        let outgoing = driver.sync(inbound, pending_commands)?;
+        self.recent_clients = driver.recent_clients;
+        for event in driver.processed_events {
+            telem.event(event);
+        }
+
+        self.interruptee.err_if_interrupted()?;
+        if outgoing.changes.is_empty() {
+            log::info!("No client records to upload");
+            pending_commands.clear();
+            return Ok(());
+        }
+
+        let upload_info =
+            CollectionUpdate::new_from_changeset(storage_client, &coll_state, outgoing, true)?
+                .upload()?;
+        log::info!(
+            "Uploaded {} client records",
+            upload_info.successful_ids.len()
+        );
+        for event in driver.sent_events {
+            telem.event(event);
+        }
+        let settings = self.command_processor.settings();
+        clear_sent_commands(
+            pending_commands,
+            &upload_info.skipped_ids,
+            &settings.fxa_device_id,
+        );
+        Ok(())
+    }
+
+    /// Adds the app's outgoing commands to the pending list, skipping any
+    /// that are already pending. Each new command gets a flow ID, so that
+    /// telemetry can tie sending it to the other client processing it.
+    fn fetch_pending_commands(&self, pending_commands: &mut Vec<ClientCommand>) -> Result<()> {
+        for command in &self.command_processor.fetch_outgoing_commands()? {
+            let mut command = ClientCommand::from(command);
+            if pending_commands.iter().any(|c| c.same_command(&command)) {
+                continue;
+            }
+            command.flow_id = Some(crypto::random_guid().into_string());
+            pending_commands.push(command);
+        }
+        Ok(())
+    }
+}
+
+/// Clears the pending commands after an upload, unless another client's
+/// record was too large to upload. In that case, we keep them all, so that
+/// we try again on the next sync; clients whose records were uploaded won't
+/// get them twice, because we skip commands they already have.
+fn clear_sent_commands(
+    pending_commands: &mut Vec<ClientCommand>,
+    skipped_ids: &[Guid],
+    our_id: &str,
+) {
+    let num_skipped = skipped_ids.iter().filter(|id| *id != our_id).count();
+    if num_skipped == 0 {
+        pending_commands.clear();
+    } else {
+        log::warn!(
+            "Keeping {} pending commands for {} client records that were too large to upload",
+            pending_commands.len(),
+            num_skipped
+        );
+    }
+}
+
+/// Builds a telemetry event for sending or processing a command. We only
+/// report commands we know about, with flow IDs, since that's what lets them
+/// be tied together across devices.
+fn command_event(object: &'static str, command: &ClientCommand) -> Option<telemetry::Event> {
+    let name = match command.as_command() {
+        Some(command) => command.name(),
+        None if command.as_repair_request().is_some() => "repairRequest",
+        None if command.command == "repairResponse" => "repairResponse",
+        None => return None,
+    };
+    match &command.flow_id {
+        // Event extras have a maximum length. Flow IDs are normally UUIDs, so
+        // anything longer than that is junk.
+        Some(flow_id) if flow_id.len() <= 85 => {
+            Some(telemetry::Event::new(object, name).extra("flowID", flow_id.clone()))
+        }
+        _ => None,
+    }
+}
+
+fn now() -> ServerTimestamp {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    ServerTimestamp(since_epoch.as_secs() as i64 * 1000 + i64::from(since_epoch.subsec_millis()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::{Command, Settings, Type};
+    use interrupt::NeverInterrupts;
+    use serde_json::{json, Value};
+    use std::cell::RefCell;
+
+    struct TestProcessor {
+        settings: Settings,
+        outgoing_commands: HashSet<Command>,
+        applied: RefCell<Vec<Command>>,
+        // The IDs of the records we'd reupload if another client asked.
+        local_ids: Vec<String>,
+    }
+
+    impl TestProcessor {
+        fn new(outgoing_commands: HashSet<Command>) -> Self {
+            TestProcessor {
+                settings: Settings {
+                    fxa_device_id: "deviceAAAAAA".into(),
+                    device_name: "Laptop".into(),
+                    device_type: Type::Desktop,
+                },
+                outgoing_commands,
+                applied: RefCell::new(Vec::new()),
+                local_ids: Vec::new(),
+            }
+        }
+    }
+
+    impl CommandProcessor for TestProcessor {
+        fn settings(&self) -> &Settings {
+            &self.settings
+        }
+
+        fn apply_incoming_command(
+            &self,
+            command: Command,
+        ) -> std::result::Result<CommandStatus, failure::Error> {
+            self.applied.borrow_mut().push(command);
+            Ok(CommandStatus::Applied)
+        }
+
+        fn fetch_outgoing_commands(&self) -> std::result::Result<HashSet<Command>, failure::Error> {
+            Ok(self.outgoing_commands.clone())
+        }
+
+        fn apply_repair_request(
+            &self,
+            request: &RepairRequest,
+        ) -> std::result::Result<Option<Vec<String>>, failure::Error> {
+            if request.collection != "bookmarks" {
+                return Ok(None);
+            }
+            Ok(Some(
+                request
+                    .ids
+                    .iter()
+                    .filter(|id| self.local_ids.contains(id))
+                    .cloned()
+                    .collect(),
+            ))
+        }
+    }
+
+    const NOW: ServerTimestamp = ServerTimestamp(1_000_000_000_000);
+
+    fn inbound(records: &[(Value, ServerTimestamp)]) -> IncomingChangeset {
+        let mut changeset = IncomingChangeset::new(COLLECTION_NAME.into(), NOW);
+        for (value, modified) in records {
+            changeset
+                .changes
+                .push((Payload::from_json(value.clone()).unwrap(), *modified));
+        }
+        changeset
+    }
+
+    fn our_record() -> Value {
+        json!({
+            "id": "deviceAAAAAA",
+            "name": "Laptop",
+            "type": "desktop",
+            "fxaDeviceId": "deviceAAAAAA",
+            "protocols": ["1.5"],
+        })
+    }
+
+    fn outgoing_ids(outgoing: &OutgoingChangeset) -> Vec<&str> {
+        let mut ids = outgoing.changes.iter().map(Payload::id).collect::<Vec<_>>();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_uploads_missing_record() {
+        let processor = TestProcessor::new(HashSet::new());
+        let mut driver = Driver::new(&processor, &NeverInterrupts, NOW);
+        let outgoing = driver.sync(inbound(&[]), &[]).expect("should sync");
+        assert_eq!(outgoing_ids(&outgoing), vec!["deviceAAAAAA"]);
+        let bso = outgoing.changes[0].clone().into_bso(COLLECTION_NAME.into());
+        assert_eq!(bso.ttl, Some(CLIENTS_TTL));
+        let record: ClientRecord = bso.payload.into_record().unwrap();
+        assert_eq!(record.name, "Laptop");
+        assert_eq!(record.typ, Some(Type::Desktop));
+    }
+
+    #[test]
+    fn test_skips_unchanged_record() {
+        let processor = TestProcessor::new(HashSet::new());
+        let mut driver = Driver::new(&processor, &NeverInterrupts, NOW);
+        let modified = ServerTimestamp(NOW.0 - 1000);
+        let outgoing = driver
+            .sync(inbound(&[(our_record(), modified)]), &[])
+            .expect("should sync");
+        assert!(outgoing.changes.is_empty());
+    }
+
+    #[test]
+    fn test_refreshes_before_ttl() {
+        let processor = TestProcessor::new(HashSet::new());
+        let mut driver = Driver::new(&processor, &NeverInterrupts, NOW);
+        let modified = ServerTimestamp(NOW.0 - CLIENTS_TTL_REFRESH as i64 * 1000);
+        let outgoing = driver
+            .sync(inbound(&[(our_record(), modified)]), &[])
+            .expect("should sync");
+        assert_eq!(outgoing_ids(&outgoing), vec!["deviceAAAAAA"]);
+    }
+
+    #[test]
+    fn test_uploads_changed_name() {
+        let mut processor = TestProcessor::new(HashSet::new());
+        processor.settings.device_name = "Work laptop".into();
+        let mut driver = Driver::new(&processor, &NeverInterrupts, NOW);
+        let outgoing = driver
+            .sync(inbound(&[(our_record(), NOW)]), &[])
+            .expect("should sync");
+        assert_eq!(outgoing_ids(&outgoing), vec!["deviceAAAAAA"]);
+    }
+
+    #[test]
+    fn test_applies_incoming_commands() {
+        let processor = TestProcessor::new(HashSet::new());
+        let mut driver = Driver::new(&processor, &NeverInterrupts, NOW);
+        let mut record = our_record();
+        record["commands"] = json!([
+            { "command": "wipeEngine", "args": ["bookmarks"], "flowID": "flowAAAAAAAA" },
+            { "command": "wipeEngine", "args": ["bookmarks"] },
+            { "command": "resetAll", "args": [] },
+            { "command": "displayURI", "args": ["https://example.com"] },
+        ]);
+        let outgoing = driver
+            .sync(inbound(&[(record, NOW)]), &[])
+            .expect("should sync");
+        assert_eq!(
+            *processor.applied.borrow(),
+            vec![Command::Wipe("bookmarks".into()), Command::ResetAll]
+        );
+        // Only the command with a flow ID is reported.
+        assert_eq!(
+            driver
+                .processed_events
+                .iter()
+                .map(|e| serde_json::to_value(e).unwrap())
+                .collect::<Vec<_>>(),
+            vec![json!({
+                "object": "processcommand",
+                "method": "wipeEngine",
+                "extra": { "flowID": "flowAAAAAAAA" },
+            })]
+        );
+        // We re-upload our record to clear the commands.
+        assert_eq!(outgoing_ids(&outgoing), vec!["deviceAAAAAA"]);
+        let record: ClientRecord = outgoing.changes[0].clone().into_record().unwrap();
+        assert!(record.commands.is_empty());
+    }
+
+    #[test]
+    fn test_sends_outgoing_commands() {
+        let processor = TestProcessor::new(HashSet::new());
+        let mut driver = Driver::new(&processor, &NeverInterrupts, NOW);
+        let commands = vec![ClientCommand {
+            flow_id: Some("flowBBBBBBBB".into()),
+            ..ClientCommand::from(&Command::Reset("history".into()))
+        }];
+        let outgoing = driver
+            .sync(
+                inbound(&[
+                    (our_record(), NOW),
+                    (
+                        json!({
+                            "id": "deviceBBBBBB",
+                            "name": "Phone",
+                            "type": "mobile",
+                            "os": "Android",
+                        }),
+                        NOW,
+                    ),
+                    (
+                        json!({
+                            "id": "deviceCCCCCC",
+                            "name": "Tablet",
+                            "type": "tablet",
+                            "commands": [{ "command": "resetEngine", "args": ["history"] }],
+                        }),
+                        NOW,
+                    ),
+                ]),
+                &commands,
+            )
+            .expect("should sync");
+        // Our own record is unchanged, and the tablet already has the command.
+        assert_eq!(outgoing_ids(&outgoing), vec!["deviceBBBBBB"]);
+        let record: ClientRecord = outgoing.changes[0].clone().into_record().unwrap();
+        assert_eq!(
+            record
+                .commands
+                .iter()
+                .map(ClientCommand::as_command)
+                .collect::<Vec<_>>(),
+            vec![Some(Command::Reset("history".into()))]
+        );
+        assert_eq!(record.commands[0].flow_id, Some("flowBBBBBBBB".into()));
+        // Fields we don't know about are uploaded unchanged.
+        assert_eq!(record.unknown_fields["os"], "Android");
+        assert_eq!(
+            driver
+                .sent_events
+                .iter()
+                .map(|e| serde_json::to_value(e).unwrap())
+                .collect::<Vec<_>>(),
+            vec![json!({
+                "object": "sendcommand",
+                "method": "resetEngine",
+                "extra": { "flowID": "flowBBBBBBBB" },
+            })]
+        );
+    }
+
+    #[test]
+    fn test_clear_sent_commands() {
+        let commands = vec![ClientCommand::from(&Command::Reset("history".into()))];
+
+        let mut pending_commands = commands.clone();
+        clear_sent_commands(&mut pending_commands, &[], "deviceAAAAAA");
+        assert!(pending_commands.is_empty());
+
+        // Skipping our own record doesn't affect the commands we're sending.
+        let mut pending_commands = commands.clone();
+        clear_sent_commands(
+            &mut pending_commands,
+            &["deviceAAAAAA".into()],
+            "deviceAAAAAA",
+        );
+        assert!(pending_commands.is_empty());
+
+        // But skipping another client's record means it didn't get them.
+        let mut pending_commands = commands.clone();
+        clear_sent_commands(
+            &mut pending_commands,
+            &["deviceAAAAAA".into(), "deviceBBBBBB".into()],
+            "deviceAAAAAA",
+        );
+        assert_eq!(pending_commands, commands);
+    }
+
+    #[test]
+    fn test_responds_to_repair_requests() {
+        let mut processor = TestProcessor::new(HashSet::new());
+        processor.local_ids = vec!["bookmarkAAAA".into()];
+        let mut driver = Driver::new(&processor, &NeverInterrupts, NOW);
+        let mut record = our_record();
+        record["commands"] = json!([{
+            "command": "repairRequest",
+            "args": [{
+                "collection": "bookmarks",
+                "request": "upload",
+                "requestor": "deviceBBBBBB",
+                "ids": ["bookmarkAAAA", "bookmarkBBBB"],
+                "flowID": "flowCCCCCCCC",
+            }],
+            "flowID": "flowCCCCCCCC",
+        }, {
+            "command": "repairRequest",
+            "args": [{
+                "collection": "history",
+                "request": "upload",
+                "requestor": "deviceBBBBBB",
+                "ids": ["pageAAAAAAAA"],
+                "flowID": "flowDDDDDDDD",
+            }],
+        }]);
+        // The requestor's record comes before ours, but still gets the
+        // response.
+        let outgoing = driver
+            .sync(
+                inbound(&[
+                    (
+                        json!({
+                            "id": "deviceBBBBBB",
+                            "name": "Desktop",
+                            "type": "desktop",
+                        }),
+                        NOW,
+                    ),
+                    (
+                        json!({
+                            "id": "deviceCCCCCC",
+                            "name": "Phone",
+                            "type": "mobile",
+                        }),
+                        NOW,
+                    ),
+                    (record, NOW),
+                ]),
+                &[],
+            )
+            .expect("should sync");
+        // We only respond to the bookmarks request, and only to the client
+        // that asked.
+        assert_eq!(
+            outgoing_ids(&outgoing),
+            vec!["deviceAAAAAA", "deviceBBBBBB"]
+        );
+        let requestor = outgoing
+            .changes
+            .iter()
+            .find(|payload| payload.id() == "deviceBBBBBB")
+            .unwrap();
+        let record: ClientRecord = requestor.clone().into_record().unwrap();
+        assert_eq!(
+            serde_json::to_value(&record.commands).unwrap(),
+            json!([{
+                "command": "repairResponse",
+                "args": [{
+                    "collection": "bookmarks",
+                    "request": "upload",
+                    "clientID": "deviceAAAAAA",
+                    "flowID": "flowCCCCCCCC",
+                    "ids": ["bookmarkAAAA"],
+                }],
+                "flowID": "flowCCCCCCCC",
+            }])
+        );
+        let events = |events: &[telemetry::Event]| {
+            events
+                .iter()
+                .map(|e| serde_json::to_value(e).unwrap())
+                .collect::<Vec<_>>()
+        };
+        // The history request doesn't have a flow ID on the command, so it's
+        // not reported.
+        assert_eq!(
+            events(&driver.processed_events),
+            vec![json!({
+                "object": "processcommand",
+                "method": "repairRequest",
+                "extra": { "flowID": "flowCCCCCCCC" },
+            })]
+        );
+        assert_eq!(
+            events(&driver.sent_events),
+            vec![json!({
+                "object": "sendcommand",
+                "method": "repairResponse",
+                "extra": { "flowID": "flowCCCCCCCC" },
+            })]
+        );
+    }
+
+    #[test]
+    fn test_recent_clients() {
+        let processor = TestProcessor::new(HashSet::new());
+        let mut driver = Driver::new(&processor, &NeverInterrupts, NOW);
+        let modified = ServerTimestamp(NOW.0 - 1000);
+        driver
+            .sync(
+                inbound(&[
+                    (our_record(), modified),
+                    (
+                        json!({
+                            "id": "deviceBBBBBB",
+                            "name": "Phone",
+                            "type": "mobile",
+                            "fxaDeviceId": "deviceBBBBBB",
+                        }),
+                        ServerTimestamp(500),
+                    ),
+                ]),
+                &[],
+            )
+            .expect("should sync");
+        assert_eq!(driver.recent_clients.len(), 2);
+        assert_eq!(
+            driver.recent_clients["deviceAAAAAA"].last_modified,
+            modified
+        );
+        assert_eq!(
+            driver.recent_clients["deviceBBBBBB"],
+            RemoteClient {
+                fxa_device_id: Some("deviceBBBBBB".into()),
+                device_name: "Phone".into(),
+                device_type: Some(Type::Mobile),
+                last_modified: ServerTimestamp(500),
+            }
+        );
+    }
+
+    #[test]
+    fn test_fetch_pending_commands() {
+        let processor = TestProcessor::new(
+            vec![
+                Command::Reset("history".into()),
+                Command::Wipe("bookmarks".into()),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let engine = Engine::new(&processor, &NeverInterrupts);
+        // A command from a previous, failed, sync.
+        let mut pending = vec![ClientCommand::from(&Command::Reset("history".into()))];
+        engine
+            .fetch_pending_commands(&mut pending)
+            .expect("should fetch");
+        assert_eq!(pending.len(), 2);
+        assert_eq!(
+            pending[0].as_command(),
+            Some(Command::Reset("history".into()))
+        );
+        // The command we already had keeps its (lack of) flow ID, the new
+        // one gets a fresh one.
+        assert_eq!(pending[0].flow_id, None);
+        assert_eq!(
+            pending[1].as_command(),
+            Some(Command::Wipe("bookmarks".into()))
+        );
+        assert!(pending[1].flow_id.is_some());
+    }
+}
@@ -0,0 +1,198 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Support for the "clients" collection, which each device uses to advertise
+//! itself (name, type, etc) to other devices, and to send commands (like
+//! "wipe your bookmarks") to other devices.
+//!
+//! Unlike other collections, there's no local storage for the clients
+//! collection - the engine is driven entirely by a `CommandProcessor`
+//! supplied by the application.
+
+use crate::util::ServerTimestamp;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashSet;
+
+mod engine;
+mod record;
+
+pub use engine::Engine;
+pub use record::{ClientCommand, ClientRecord};
+
+/// The collection name for the clients engine.
+pub const COLLECTION_NAME: &str = "clients";
+
+/// The TTL for our own client record, in seconds. Records that aren't
+/// refreshed before this expire are removed by the server, and other devices
+/// consider us gone.
+pub const CLIENTS_TTL: u32 = 1_814_400; // 21 days
+
+/// How long, in seconds, we allow our own client record to go without being
+/// re-uploaded, even if nothing has changed. This is well inside
+/// `CLIENTS_TTL`, so that we always refresh it before it expires.
+pub const CLIENTS_TTL_REFRESH: u64 = 604_800; // 7 days
+
+/// The type of a client (or device). Matches the values used by desktop
+/// Sync and FxA. Types we don't know about are kept as-is, so that we don't
+/// drop them if we re-upload another client's record.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Type {
+    Desktop,
+    Mobile,
+    Tablet,
+    VR,
+    TV,
+    Unknown(String),
+}
+
+impl Type {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Type::Desktop => "desktop",
+            Type::Mobile => "mobile",
+            Type::Tablet => "tablet",
+            Type::VR => "vr",
+            Type::TV => "tv",
+            Type::Unknown(s) => s,
+        }
+    }
+}
+
+impl Default for Type {
+    fn default() -> Type {
+        Type::Mobile
+    }
+}
+
+impl From<String> for Type {
+    fn from(s: String) -> Type {
+        match s.as_str() {
+            "desktop" => Type::Desktop,
+            "mobile" => Type::Mobile,
+            "tablet" => Type::Tablet,
+            "vr" => Type::VR,
+            "tv" => Type::TV,
+            _ => Type::Unknown(s),
+        }
+    }
+}
+
+impl Serialize for Type {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Type {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Type, D::Error> {
+        Ok(String::deserialize(deserializer)?.into())
+    }
+}
+
+/// Information about the current client, used to build our own client record.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Settings {
+    /// The FxA device ID of this client, also used as the ID of our record in
+    /// the clients collection.
+    pub fxa_device_id: String,
+    /// The human-readable name of this client.
+    pub device_name: String,
+    /// The type of this client.
+    pub device_type: Type,
+}
+
+/// A client from the clients collection (which may be us), with the
+/// details a device management UI might want to show.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RemoteClient {
+    pub fxa_device_id: Option<String>,
+    pub device_name: String,
+    pub device_type: Option<Type>,
+    /// When the client last uploaded its record. Clients only upload their
+    /// record when it changes or is about to expire, so they may have synced
+    /// more recently than this.
+    pub last_modified: ServerTimestamp,
+}
+
+/// A command that can be sent to, or received from, another client.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Command {
+    /// Wipe all local data for the named engine.
+    Wipe(String),
+    /// Reset the sync state of the named engine.
+    Reset(String),
+    /// Reset the sync state of all engines.
+    ResetAll,
+}
+
+/// A request from another client to reupload records that it thinks are
+/// missing or wrong on the server. This is the first half of Desktop's
+/// repair protocol: once we've processed it, we send a `repairResponse`
+/// command back to the client that asked, listing the records we'll upload.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct RepairRequest {
+    /// The collection to repair. Desktop only repairs bookmarks.
+    pub collection: String,
+    /// The IDs of the records to reupload.
+    pub ids: Vec<String>,
+    /// The record ID of the client that made the request.
+    pub requestor: String,
+    /// Ties the request and our response together in telemetry.
+    pub flow_id: String,
+}
+
+/// What happened when we asked the application to process a command.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CommandStatus {
+    Applied,
+    Ignored,
+    Unsupported,
+}
+
+/// The interface the application implements to drive the clients engine.
+pub trait CommandProcessor {
+    /// Returns the settings for our own client record.
+    fn settings(&self) -> &Settings;
+
+    /// Processes a command sent to us by another client.
+    fn apply_incoming_command(&self, command: Command) -> Result<CommandStatus, failure::Error>;
+
+    /// Processes a request from another client to reupload records. This
+    /// should arrange for the store to upload the requested records it has
+    /// on its next sync, and return their IDs, which we send back to the
+    /// client that asked. Returns `None` if we can't repair the collection,
+    /// in which case we don't respond. By default, we don't support repair.
+    fn apply_repair_request(
+        &self,
+        _request: &RepairRequest,
+    ) -> Result<Option<Vec<String>>, failure::Error> {
+        Ok(None)
+    }
+
+    /// Returns the commands we should send to all other clients. Once
+    /// fetched, we take care of delivering them: if a sync fails before
+    /// they're uploaded, they're kept in the persisted global state and sent
+    /// on the next sync.
+    fn fetch_outgoing_commands(&self) -> Result<HashSet<Command>, failure::Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_round_trip() {
+        for (typ, s) in &[
+            (Type::Desktop, "\"desktop\""),
+            (Type::Mobile, "\"mobile\""),
+            (Type::Tablet, "\"tablet\""),
+            (Type::VR, "\"vr\""),
+            (Type::TV, "\"tv\""),
+            (Type::Unknown("fridge".into()), "\"fridge\""),
+        ] {
+            assert_eq!(serde_json::to_string(typ).unwrap(), *s);
+            assert_eq!(serde_json::from_str::<Type>(s).unwrap(), *typ);
+        }
+    }
+}
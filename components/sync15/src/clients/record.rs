@@ -0,0 +1,286 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use serde_derive::*;
+use serde_json::{json, Map, Value};
+
+use super::{Command, RepairRequest, Type};
+
+/// A record in the clients collection.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientRecord {
+    pub id: String,
+
+    pub name: String,
+
+    #[serde(default, rename = "type", skip_serializing_if = "Option::is_none")]
+    pub typ: Option<Type>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub commands: Vec<ClientCommand>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fxa_device_id: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub protocols: Vec<String>,
+
+    /// Fields we don't use, like Desktop's `os` and `formfactor`. We keep
+    /// them so that they survive when we add commands to another client's
+    /// record and upload it.
+    #[serde(flatten)]
+    pub unknown_fields: Map<String, Value>,
+}
+
+impl ClientRecord {
+    /// Returns true if the fields that describe the client (as opposed to
+    /// the commands queued for it) are the same in both records.
+    pub fn same_description(&self, other: &ClientRecord) -> bool {
+        self.id == other.id
+            && self.name == other.name
+            && self.typ == other.typ
+            && self.fxa_device_id == other.fxa_device_id
+    }
+}
+
+/// A command in a client record, as it appears on the wire. Most commands
+/// take strings as arguments, but some, like the repair commands, take
+/// objects.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientCommand {
+    pub command: String,
+
+    #[serde(default)]
+    pub args: Vec<Value>,
+
+    #[serde(default, rename = "flowID", skip_serializing_if = "Option::is_none")]
+    pub flow_id: Option<String>,
+}
+
+impl ClientCommand {
+    /// Returns true if both are the same command with the same arguments,
+    /// regardless of their flow IDs.
+    pub fn same_command(&self, other: &ClientCommand) -> bool {
+        self.command == other.command && self.args == other.args
+    }
+
+    /// Converts the wire representation into a `Command`, returning `None` if
+    /// the command is unknown or has the wrong arguments.
+    pub fn as_command(&self) -> Option<Command> {
+        match (self.command.as_str(), self.args.as_slice()) {
+            ("wipeEngine", [Value::String(engine)]) => Some(Command::Wipe(engine.clone())),
+            ("resetEngine", [Value::String(engine)]) => Some(Command::Reset(engine.clone())),
+            ("resetAll", []) => Some(Command::ResetAll),
+            _ => None,
+        }
+    }
+
+    /// Converts a `repairRequest` command into a `RepairRequest`, returning
+    /// `None` if this is a different command, or a request we don't
+    /// understand. Desktop only ever asks for records to be uploaded.
+    pub fn as_repair_request(&self) -> Option<RepairRequest> {
+        let args = match (self.command.as_str(), self.args.as_slice()) {
+            ("repairRequest", [args]) => args,
+            _ => return None,
+        };
+        let args: RepairRequestArgs = serde_json::from_value(args.clone()).ok()?;
+        if args.request != REPAIR_REQUEST_UPLOAD {
+            return None;
+        }
+        // Desktop sends the flow ID both in the arguments and with the
+        // command.
+        let flow_id = args.flow_id.or_else(|| self.flow_id.clone())?;
+        Some(RepairRequest {
+            collection: args.collection,
+            ids: args.ids,
+            requestor: args.requestor,
+            flow_id,
+        })
+    }
+
+    /// Builds the `repairResponse` command we send back to the client that
+    /// made `request`, listing the `ids` we're going to reupload.
+    pub fn repair_response(
+        request: &RepairRequest,
+        client_id: &str,
+        ids: Vec<String>,
+    ) -> ClientCommand {
+        ClientCommand {
+            command: "repairResponse".into(),
+            args: vec![json!({
+                "collection": request.collection,
+                "request": REPAIR_REQUEST_UPLOAD,
+                "clientID": client_id,
+                "flowID": request.flow_id,
+                "ids": ids,
+            })],
+            flow_id: Some(request.flow_id.clone()),
+        }
+    }
+}
+
+/// The only kind of repair request Desktop sends.
+const REPAIR_REQUEST_UPLOAD: &str = "upload";
+
+/// The argument to a `repairRequest` command.
+#[derive(Clone, Debug, Deserialize)]
+struct RepairRequestArgs {
+    collection: String,
+    request: String,
+    requestor: String,
+    ids: Vec<String>,
+    #[serde(default, rename = "flowID")]
+    flow_id: Option<String>,
+}
+
+impl Command {
+    /// The name of the command on the wire, which is also what we report
+    /// in telemetry.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Wipe(_) => "wipeEngine",
+            Command::Reset(_) => "resetEngine",
+            Command::ResetAll => "resetAll",
+        }
+    }
+}
+
+impl From<&Command> for ClientCommand {
+    fn from(command: &Command) -> ClientCommand {
+        let args = match command {
+            Command::Wipe(engine) | Command::Reset(engine) => vec![engine.clone().into()],
+            Command::ResetAll => vec![],
+        };
+        ClientCommand {
+            command: command.name().into(),
+            args,
+            flow_id: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_deserialize_desktop_record() {
+        let record: ClientRecord = serde_json::from_value(json!({
+            "id": "deviceAAAAAA",
+            "name": "Laptop",
+            "type": "desktop",
+            "commands": [{
+                "command": "wipeEngine",
+                "args": ["bookmarks"],
+                "flowID": "flow",
+            }],
+            "fxaDeviceId": "device",
+            "version": "71.0",
+            "protocols": ["1.5"],
+            "os": "Darwin",
+        }))
+        .unwrap();
+        assert_eq!(record.typ, Some(Type::Desktop));
+        assert_eq!(record.commands.len(), 1);
+        assert_eq!(
+            record.commands[0].as_command(),
+            Some(Command::Wipe("bookmarks".into()))
+        );
+        assert_eq!(record.fxa_device_id, Some("device".into()));
+    }
+
+    #[test]
+    fn test_desktop_record_round_trip() {
+        let value = json!({
+            "id": "deviceAAAAAA",
+            "name": "Laptop",
+            "type": "desktop",
+            "fxaDeviceId": "device",
+            "version": "71.0",
+            "protocols": ["1.5"],
+            "os": "Darwin",
+            "appPackage": "org.mozilla.firefox",
+            "application": "Firefox",
+            "formfactor": "laptop",
+        });
+        let record: ClientRecord = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(record.unknown_fields.len(), 4);
+        assert_eq!(record.unknown_fields["os"], "Darwin");
+        assert_eq!(serde_json::to_value(&record).unwrap(), value);
+    }
+
+    #[test]
+    fn test_command_round_trip() {
+        for command in &[
+            Command::Wipe("history".into()),
+            Command::Reset("passwords".into()),
+            Command::ResetAll,
+        ] {
+            assert_eq!(
+                ClientCommand::from(command).as_command().as_ref(),
+                Some(command)
+            );
+        }
+        let unknown = ClientCommand {
+            command: "displayURI".into(),
+            args: vec!["https://example.com".into()],
+            flow_id: None,
+        };
+        assert_eq!(unknown.as_command(), None);
+    }
+
+    #[test]
+    fn test_repair_request() {
+        let command: ClientCommand = serde_json::from_value(json!({
+            "command": "repairRequest",
+            "args": [{
+                "collection": "bookmarks",
+                "request": "upload",
+                "requestor": "deviceBBBBBB",
+                "ids": ["bookmarkAAAA", "bookmarkBBBB"],
+                "flowID": "flow",
+            }],
+        }))
+        .unwrap();
+        assert_eq!(command.as_command(), None);
+        let request = command.as_repair_request().expect("should be a request");
+        assert_eq!(
+            request,
+            RepairRequest {
+                collection: "bookmarks".into(),
+                ids: vec!["bookmarkAAAA".into(), "bookmarkBBBB".into()],
+                requestor: "deviceBBBBBB".into(),
+                flow_id: "flow".into(),
+            }
+        );
+
+        let response =
+            ClientCommand::repair_response(&request, "deviceAAAAAA", vec!["bookmarkAAAA".into()]);
+        assert_eq!(response.as_repair_request(), None);
+        assert_eq!(response.flow_id, Some("flow".into()));
+
+        // We only understand upload requests.
+        let mut unknown = command;
+        unknown.args[0]["request"] = "download".into();
+        assert_eq!(unknown.as_repair_request(), None);
+    }
+
+    #[test]
+    fn test_unknown_type_round_trip() {
+        let value = json!({
+            "id": "deviceBBBBBB",
+            "name": "Kitchen",
+            "type": "fridge",
+        });
+        let record: ClientRecord = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(record.typ, Some(Type::Unknown("fridge".into())));
+        assert_eq!(serde_json::to_value(&record).unwrap(), value);
+    }
+}
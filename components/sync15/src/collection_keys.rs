@@ -61,4 +61,38 @@ impl CollectionKeys {
     pub fn key_for_collection<'a>(&'a self, collection: &str) -> &'a KeyBundle {
         self.collections.get(collection).unwrap_or(&self.default)
     }
+
+    /// Gives `collection` its own random key, instead of the default key.
+    /// Returns false, and leaves the key alone, if the collection already has
+    /// its own key.
+    pub fn add_collection_key(&mut self, collection: &str) -> Result<bool> {
+        if self.collections.contains_key(collection) {
+            return Ok(false);
+        }
+        self.collections
+            .insert(collection.into(), KeyBundle::new_random()?);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collection_key_round_trip() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let mut keys = CollectionKeys::new_random().unwrap();
+        assert!(keys.add_collection_key("creditcards").unwrap());
+        let key = keys.key_for_collection("creditcards").clone();
+        assert_ne!(key, keys.default);
+        // Adding it again keeps the existing key.
+        assert!(!keys.add_collection_key("creditcards").unwrap());
+        assert_eq!(*keys.key_for_collection("creditcards"), key);
+
+        let bso = keys.to_encrypted_bso(&root_key).unwrap();
+        let decrypted = CollectionKeys::from_encrypted_bso(bso, &root_key).unwrap();
+        assert_eq!(*decrypted.key_for_collection("creditcards"), key);
+        assert_eq!(*decrypted.key_for_collection("bookmarks"), keys.default);
+    }
 }
@@ -0,0 +1,281 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The cryptographic operations we need to encrypt and decrypt records, and
+//! to sign our requests to the storage server, behind a trait so that
+//! consumers can supply their own implementation.
+//!
+//! With the `rc_crypto` feature (on by default), we use `rc_crypto`, which is
+//! backed by NSS. Consumers that don't ship NSS can turn it off, and install a
+//! different backend with `set_crypto_backend` before syncing. Our own tests
+//! use a pure-Rust backend when the feature is off.
+
+use crate::error::Result;
+use hawk::crypto as hc;
+use lazy_static::lazy_static;
+use std::sync::{Arc, Once, RwLock};
+use sync_guid::Guid;
+
+/// The operations needed for the Sync 1.5 record encryption scheme: AES-256
+/// in CBC mode, with an HMAC-SHA256 tag computed over the *base64* encoded
+/// ciphertext. See
+/// https://mozilla-services.readthedocs.io/en/latest/sync/storageformat5.html#record-encryption
+///
+/// The hashing and signing methods are also used for Hawk authentication.
+pub trait CryptoBackend: Send + Sync {
+    /// Encrypts `cleartext`, returning the ciphertext followed by the 32 byte
+    /// HMAC tag.
+    fn seal(&self, enc_key: &[u8], mac_key: &[u8], iv: &[u8], cleartext: &[u8]) -> Result<Vec<u8>>;
+
+    /// Verifies the tag at the end of `ciphertext_and_tag`, and returns the
+    /// decrypted cleartext. Fails if the tag doesn't match.
+    fn open(
+        &self,
+        enc_key: &[u8],
+        mac_key: &[u8],
+        iv: &[u8],
+        ciphertext_and_tag: &[u8],
+    ) -> Result<Vec<u8>>;
+
+    /// Fills `dest` with cryptographically secure random bytes, for keys,
+    /// IVs, and GUIDs.
+    fn fill_random(&self, dest: &mut [u8]) -> Result<()>;
+
+    /// Returns the HMAC-SHA256 of `data`, signed with `key`.
+    fn hmac_sha256(&self, key: &[u8], data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Returns the SHA-256 digest of `data`.
+    fn sha256(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// The default backend, which uses `rc_crypto`.
+#[cfg(feature = "rc_crypto")]
+#[derive(Debug, Default)]
+pub struct RcCryptoBackend;
+
+#[cfg(feature = "rc_crypto")]
+impl CryptoBackend for RcCryptoBackend {
+    fn seal(&self, enc_key: &[u8], mac_key: &[u8], iv: &[u8], cleartext: &[u8]) -> Result<Vec<u8>> {
+        use rc_crypto::aead::{self, SealingKey};
+        let key_bytes = [enc_key, mac_key].concat();
+        let key = SealingKey::new(&aead::LEGACY_SYNC_AES_256_CBC_HMAC_SHA256, &key_bytes)?;
+        let nonce =
+            aead::Nonce::try_assume_unique_for_key(&aead::LEGACY_SYNC_AES_256_CBC_HMAC_SHA256, iv)?;
+        Ok(aead::seal(&key, nonce, aead::Aad::empty(), cleartext)?)
+    }
+
+    fn open(
+        &self,
+        enc_key: &[u8],
+        mac_key: &[u8],
+        iv: &[u8],
+        ciphertext_and_tag: &[u8],
+    ) -> Result<Vec<u8>> {
+        use rc_crypto::aead::{self, OpeningKey};
+        let key_bytes = [enc_key, mac_key].concat();
+        let key = OpeningKey::new(&aead::LEGACY_SYNC_AES_256_CBC_HMAC_SHA256, &key_bytes)?;
+        let nonce =
+            aead::Nonce::try_assume_unique_for_key(&aead::LEGACY_SYNC_AES_256_CBC_HMAC_SHA256, iv)?;
+        Ok(aead::open(
+            &key,
+            nonce,
+            aead::Aad::empty(),
+            ciphertext_and_tag,
+        )?)
+    }
+
+    fn fill_random(&self, dest: &mut [u8]) -> Result<()> {
+        Ok(rc_crypto::rand::fill(dest)?)
+    }
+
+    fn hmac_sha256(&self, key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        use rc_crypto::{digest, hmac};
+        let key = hmac::SigningKey::new(&digest::SHA256, key);
+        Ok(hmac::sign(&key, data)?.as_ref().to_vec())
+    }
+
+    fn sha256(&self, data: &[u8]) -> Result<Vec<u8>> {
+        use rc_crypto::digest;
+        Ok(digest::digest(&digest::SHA256, data)?.as_ref().to_vec())
+    }
+}
+
+#[cfg(feature = "rc_crypto")]
+fn default_backend() -> Option<Arc<dyn CryptoBackend>> {
+    Some(Arc::new(RcCryptoBackend))
+}
+
+#[cfg(all(test, not(feature = "rc_crypto")))]
+fn default_backend() -> Option<Arc<dyn CryptoBackend>> {
+    Some(Arc::new(crate::test_crypto::TestCryptoBackend))
+}
+
+#[cfg(all(not(test), not(feature = "rc_crypto")))]
+fn default_backend() -> Option<Arc<dyn CryptoBackend>> {
+    None
+}
+
+lazy_static! {
+    static ref BACKEND: RwLock<Option<Arc<dyn CryptoBackend>>> = RwLock::new(default_backend());
+}
+
+/// Replaces the backend used for all record encryption and decryption, and
+/// request signing. This should be called once, before any keys are created
+/// or records synced. Without the `rc_crypto` feature, this *must* be called.
+pub fn set_crypto_backend(backend: Arc<dyn CryptoBackend>) {
+    *BACKEND.write().unwrap() = Some(backend);
+}
+
+pub(crate) fn backend() -> Arc<dyn CryptoBackend> {
+    BACKEND
+        .read()
+        .unwrap()
+        .clone()
+        .expect("No crypto backend; enable `rc_crypto` or call `set_crypto_backend`")
+}
+
+/// Initializes `rc_crypto` if we're using it, and tells `hawk` to sign
+/// requests with our backend. Must be called before making any requests to
+/// the token or storage servers.
+pub(crate) fn ensure_initialized() {
+    #[cfg(feature = "rc_crypto")]
+    rc_crypto::ensure_initialized();
+    static INIT_ONCE: Once = Once::new();
+    INIT_ONCE.call_once(|| {
+        // `hawk` only allows one cryptographer per process. If another
+        // component turned on `rc_crypto`'s Hawk support, it's already
+        // installed its own, which is fine: it's also backed by NSS.
+        let _ = hc::set_cryptographer(&BackendCryptographer);
+    });
+}
+
+/// Creates a random GUID, of 12 base64url characters, using our backend
+/// instead of `Guid::random`, which always uses `rc_crypto`.
+pub(crate) fn random_guid() -> Guid {
+    let mut bytes = [0u8; 9];
+    backend()
+        .fill_random(&mut bytes)
+        .expect("Failed to generate random bytes for a GUID");
+    Guid::from(base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD))
+}
+
+impl From<crate::error::Error> for hc::CryptoError {
+    // Our errors impl `Fail`, so we can do this.
+    fn from(e: crate::error::Error) -> Self {
+        hc::CryptoError::Other(e.into())
+    }
+}
+
+struct BackendCryptographer;
+
+struct BackendHmacKey(Vec<u8>);
+
+impl hc::HmacKey for BackendHmacKey {
+    fn sign(&self, data: &[u8]) -> std::result::Result<Vec<u8>, hc::CryptoError> {
+        Ok(backend().hmac_sha256(&self.0, data)?)
+    }
+}
+
+// Like `rc_crypto`'s Hawk support, we don't bother hashing incrementally;
+// the payloads we sign are small.
+struct BackendHasher(Vec<u8>);
+
+impl hc::Hasher for BackendHasher {
+    fn update(&mut self, data: &[u8]) -> std::result::Result<(), hc::CryptoError> {
+        self.0.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn finish(&mut self) -> std::result::Result<Vec<u8>, hc::CryptoError> {
+        Ok(backend().sha256(&self.0)?)
+    }
+}
+
+impl hc::Cryptographer for BackendCryptographer {
+    fn rand_bytes(&self, output: &mut [u8]) -> std::result::Result<(), hc::CryptoError> {
+        Ok(backend().fill_random(output)?)
+    }
+
+    fn new_key(
+        &self,
+        algorithm: hawk::DigestAlgorithm,
+        key: &[u8],
+    ) -> std::result::Result<Box<dyn hc::HmacKey>, hc::CryptoError> {
+        match algorithm {
+            hawk::DigestAlgorithm::Sha256 => Ok(Box::new(BackendHmacKey(key.to_vec()))),
+            algo => Err(hc::CryptoError::UnsupportedDigest(algo)),
+        }
+    }
+
+    fn constant_time_compare(&self, a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+    }
+
+    fn new_hasher(
+        &self,
+        algorithm: hawk::DigestAlgorithm,
+    ) -> std::result::Result<Box<dyn hc::Hasher>, hc::CryptoError> {
+        match algorithm {
+            hawk::DigestAlgorithm::Sha256 => Ok(Box::new(BackendHasher(vec![]))),
+            algo => Err(hc::CryptoError::UnsupportedDigest(algo)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_round_trip() {
+        let backend = backend();
+        let mut enc_key = [0u8; 32];
+        let mut mac_key = [0u8; 32];
+        let mut iv = [0u8; 16];
+        backend.fill_random(&mut enc_key).unwrap();
+        backend.fill_random(&mut mac_key).unwrap();
+        backend.fill_random(&mut iv).unwrap();
+
+        let sealed = backend
+            .seal(&enc_key, &mac_key, &iv, b"hello, world")
+            .unwrap();
+        assert_eq!(
+            backend.open(&enc_key, &mac_key, &iv, &sealed).unwrap(),
+            b"hello, world"
+        );
+
+        // A different HMAC key means the tag won't match.
+        mac_key[0] ^= 1;
+        assert!(backend.open(&enc_key, &mac_key, &iv, &sealed).is_err());
+    }
+
+    #[test]
+    fn test_random_guid() {
+        let guid = random_guid();
+        assert_eq!(guid.len(), 12);
+        assert!(guid.is_valid_for_sync_server());
+        assert_ne!(guid, random_guid());
+    }
+
+    #[test]
+    fn test_hawk_signing() {
+        ensure_initialized();
+        let credentials = hawk::Credentials {
+            id: "dh37fgj492je".to_string(),
+            key: hawk::Key::new("werxhqb98rpaxn39848xrunpaw3489ruxnpa98w4rxn", hawk::SHA256)
+                .unwrap(),
+        };
+        let header = hawk::RequestBuilder::new("GET", "example.com", 8000, "/resource/1?b=1&a=2")
+            .request()
+            .make_header(&credentials)
+            .unwrap();
+        let request =
+            hawk::RequestBuilder::new("GET", "example.com", 8000, "/resource/1?b=1&a=2").request();
+        assert!(request.validate_header(
+            &header,
+            &credentials.key,
+            std::time::Duration::from_secs(60)
+        ));
+    }
+}
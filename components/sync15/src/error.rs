@@ -4,7 +4,6 @@
 
 use failure::Fail;
 use interrupt::Interrupted;
-use rc_crypto::hawk;
 use std::string;
 use std::time::SystemTime;
 
@@ -16,6 +15,8 @@ pub enum ErrorResponse {
     Unauthorized { route: String },
     // 412
     PreconditionFailed { route: String },
+    // 400 with the "over quota" error code, or a 403 that mentions the quota.
+    OverQuota { route: String },
     // 5XX
     ServerError { route: String, status: u16 }, // TODO: info for "retry-after" and backoff handling etc here.
     // Other HTTP responses.
@@ -42,6 +43,17 @@ pub enum ErrorKind {
     #[fail(display = "Server requested backoff. Retry after {:?}", _0)]
     BackoffError(SystemTime),
 
+    // A 412 in response to an `X-If-Unmodified-Since` request, or an upload
+    // we know would get one. Another client changed `_0` since we last read
+    // it, so we need to download their changes before writing ours.
+    #[fail(display = "{} was modified by another client", _0)]
+    Conflict(String),
+
+    // The user is over their storage quota. `_0` is the collection we were
+    // writing to.
+    #[fail(display = "Over quota while writing to {}", _0)]
+    OverQuota(String),
+
     #[fail(display = "Outgoing record is too large to upload")]
     RecordTooLargeError,
 
@@ -80,6 +92,7 @@ pub enum ErrorKind {
     #[fail(display = "Store error: {}", _0)]
     StoreError(#[fail(cause)] failure::Error),
 
+    #[cfg(feature = "rc_crypto")]
     #[fail(display = "Crypto/NSS error: {}", _0)]
     CryptoError(#[fail(cause)] rc_crypto::Error),
 
@@ -110,7 +123,6 @@ pub enum ErrorKind {
 
 error_support::define_error! {
     ErrorKind {
-        (CryptoError, rc_crypto::Error),
         (Base64Decode, base64::DecodeError),
         (JsonError, serde_json::Error),
         (BadCleartextUtf8, std::string::FromUtf8Error),
@@ -123,3 +135,10 @@ error_support::define_error! {
         (HawkError, hawk::Error),
     }
 }
+
+#[cfg(feature = "rc_crypto")]
+error_support::define_error_conversions! {
+    ErrorKind {
+        (CryptoError, rc_crypto::Error),
+    }
+}
@@ -2,11 +2,11 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::crypto;
 use crate::error::{ErrorKind, Result};
-use rc_crypto::{
-    aead::{self, OpeningKey, SealingKey},
-    rand,
-};
+
+// The length of the HMAC-SHA256 tag at the end of the sealed ciphertext.
+const HMAC_LEN: usize = 32;
 
 #[derive(Clone, PartialEq, Eq, Hash)]
 pub struct KeyBundle {
@@ -40,7 +40,7 @@ impl KeyBundle {
 
     pub fn new_random() -> Result<KeyBundle> {
         let mut buffer = [0u8; 64];
-        rand::fill(&mut buffer)?;
+        crypto::backend().fill_random(&mut buffer)?;
         KeyBundle::from_ksync_bytes(&buffer)
     }
 
@@ -94,14 +94,13 @@ impl KeyBundle {
         }
         let iv = base64::decode(iv_base64)?;
         let ciphertext_bytes = base64::decode(enc_base64)?;
-        let key_bytes = [self.encryption_key(), self.hmac_key()].concat();
-        let key = OpeningKey::new(&aead::LEGACY_SYNC_AES_256_CBC_HMAC_SHA256, &key_bytes)?;
-        let nonce = aead::Nonce::try_assume_unique_for_key(
-            &aead::LEGACY_SYNC_AES_256_CBC_HMAC_SHA256,
+        let ciphertext_and_hmac = [ciphertext_bytes, decoded_hmac].concat();
+        let cleartext_bytes = crypto::backend().open(
+            self.encryption_key(),
+            self.hmac_key(),
             &iv,
+            &ciphertext_and_hmac,
         )?;
-        let ciphertext_and_hmac = [ciphertext_bytes, decoded_hmac].concat();
-        let cleartext_bytes = aead::open(&key, nonce, aead::Aad::empty(), &ciphertext_and_hmac)?;
         let cleartext = String::from_utf8(cleartext_bytes)?;
         Ok(cleartext)
     }
@@ -112,12 +111,9 @@ impl KeyBundle {
         cleartext_bytes: &[u8],
         iv: &[u8],
     ) -> Result<(String, String)> {
-        let key_bytes = [self.encryption_key(), self.hmac_key()].concat();
-        let key = SealingKey::new(&aead::LEGACY_SYNC_AES_256_CBC_HMAC_SHA256, &key_bytes)?;
-        let nonce =
-            aead::Nonce::try_assume_unique_for_key(&aead::LEGACY_SYNC_AES_256_CBC_HMAC_SHA256, iv)?;
-        let ciphertext_and_hmac = aead::seal(&key, nonce, aead::Aad::empty(), cleartext_bytes)?;
-        let ciphertext_len = ciphertext_and_hmac.len() - key.algorithm().tag_len();
+        let ciphertext_and_hmac =
+            crypto::backend().seal(self.encryption_key(), self.hmac_key(), iv, cleartext_bytes)?;
+        let ciphertext_len = ciphertext_and_hmac.len() - HMAC_LEN;
         // Do the string conversions here so we don't have to split and copy to 2 vectors.
         let (ciphertext, hmac_signature) = ciphertext_and_hmac.split_at(ciphertext_len);
         let enc_base64 = base64::encode(&ciphertext);
@@ -132,7 +128,7 @@ impl KeyBundle {
         cleartext_bytes: &[u8],
     ) -> Result<(String, String, String)> {
         let mut iv = [0u8; 16];
-        rand::fill(&mut iv)?;
+        crypto::backend().fill_random(&mut iv)?;
         let (enc_base64, hmac_base16) = self.encrypt_bytes_with_iv(cleartext_bytes, &iv)?;
         let iv_base64 = base64::encode(&iv);
         Ok((enc_base64, iv_base64, hmac_base16))
@@ -145,6 +141,15 @@ impl KeyBundle {
     pub fn encrypt_rand_iv(&self, cleartext: &str) -> Result<(String, String, String)> {
         self.encrypt_bytes_rand_iv(cleartext.as_bytes())
     }
+
+    /// Returns a fingerprint that changes if either key does, without
+    /// revealing them, so that we can persist it and notice when a key
+    /// changes. This is the HMAC tag from encrypting an empty message with a
+    /// zero IV.
+    pub fn fingerprint(&self) -> Result<String> {
+        let (_, hmac_base16) = self.encrypt_bytes_with_iv(&[], &[0u8; 16])?;
+        Ok(hmac_base16)
+    }
 }
 
 #[cfg(test)]
@@ -209,4 +214,15 @@ mod test {
             .unwrap();
         assert_eq!(&cleartext_bytes, &s.as_bytes());
     }
+
+    #[test]
+    fn test_fingerprint() {
+        let key_bundle = KeyBundle::from_base64(ENC_KEY_B64, HMAC_KEY_B64).unwrap();
+        let fingerprint = key_bundle.fingerprint().unwrap();
+        assert_eq!(key_bundle.clone().fingerprint().unwrap(), fingerprint);
+        assert_ne!(
+            KeyBundle::new_random().unwrap().fingerprint().unwrap(),
+            fingerprint
+        );
+    }
 }
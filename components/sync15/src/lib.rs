@@ -5,11 +5,14 @@
 #![allow(unknown_lints)]
 #![warn(rust_2018_idioms)]
 
+mod backoff;
 mod bso_record;
 mod changeset;
 mod client;
+pub mod clients;
 mod coll_state;
 mod collection_keys;
+mod crypto;
 mod error;
 mod key_bundle;
 mod migrate_state;
@@ -20,20 +23,39 @@ mod status;
 mod sync;
 mod sync_multiple;
 pub mod telemetry;
+#[cfg(test)]
+mod test_crypto;
 mod token;
+mod tombstone;
 mod util;
 
 // Re-export some of the types callers are likely to want for convenience.
 pub use crate::bso_record::{BsoRecord, CleartextBso, EncryptedBso, EncryptedPayload, Payload};
 pub use crate::changeset::{IncomingChangeset, OutgoingChangeset, RecordChangeset};
-pub use crate::client::{SetupStorageClient, Sync15StorageClient, Sync15StorageClientInit};
+pub use crate::client::{
+    AccessTokenRefresher, SetupStorageClient, StorageInterceptor, Sync15StorageClient,
+    Sync15StorageClientInit,
+};
 pub use crate::coll_state::{CollState, CollSyncIds, StoreSyncAssociation};
+#[cfg(feature = "rc_crypto")]
+pub use crate::crypto::RcCryptoBackend;
+pub use crate::crypto::{set_crypto_backend, CryptoBackend};
 pub use crate::error::{Error, ErrorKind, Result};
 pub use crate::key_bundle::KeyBundle;
 pub use crate::migrate_state::extract_v1_state;
-pub use crate::request::CollectionRequest;
-pub use crate::state::{GlobalState, SetupStateMachine};
+pub use crate::record_types::{MetaGlobalEngine, MetaGlobalRecord};
+pub use crate::request::{
+    CollectionOverview, CollectionRequest, InfoCollectionCounts, InfoCollectionUsage,
+    InfoCollections, InfoQuota, RequestOrder, ServerOverview,
+};
+pub use crate::state::{
+    provision_collection_key, update_meta_global, GlobalState, SetupStateMachine,
+};
 pub use crate::status::{ServiceStatus, SyncResult};
 pub use crate::sync::{synchronize, Store};
-pub use crate::sync_multiple::{sync_multiple, MemoryCachedState};
+pub use crate::sync_multiple::{
+    sync_multiple, sync_multiple_with_command_processor, sync_multiple_with_params, wipe_server,
+    MemoryCachedState, SyncMultipleParams,
+};
+pub use crate::tombstone::{reconcile, MaybeTombstone, Reconciled};
 pub use crate::util::{ServerTimestamp, SERVER_EPOCH};
@@ -46,6 +46,9 @@ pub fn extract_v1_state(
     };
     let pgs = PersistedGlobalState::V2 {
         declined: Some(meta_global.declined),
+        pending_commands: Vec::new(),
+        backoff: Default::default(),
+        key_fingerprints: Default::default(),
     };
     let new_global_state = serde_json::to_string(&pgs).ok();
 
@@ -132,6 +135,9 @@ mod tests {
         // state reflects that.
         let expected_state = serde_json::to_string(&PersistedGlobalState::V2 {
             declined: Some(Vec::<String>::new()),
+            pending_commands: Vec::new(),
+            backoff: Default::default(),
+            key_fingerprints: Default::default(),
         })
         .expect("should stringify");
         assert_eq!(new_state, Some(expected_state));
@@ -157,6 +163,9 @@ mod tests {
         let s = get_state_with_engine_changes_and_declined("", "\\\"foo\\\"");
         let expected_state = serde_json::to_string(&PersistedGlobalState::V2 {
             declined: Some(vec!["foo".to_string()]),
+            pending_commands: Vec::new(),
+            backoff: Default::default(),
+            key_fingerprints: Default::default(),
         })
         .unwrap();
         assert_eq!(
@@ -2,6 +2,7 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::crypto;
 use serde_derive::*;
 use std::collections::HashMap;
 use sync_guid::Guid;
@@ -25,10 +26,63 @@ pub struct MetaGlobalRecord {
     pub declined: Vec<String>,
 }
 
+impl MetaGlobalRecord {
+    /// Gives `engine` a new sync ID, which makes every other client reset
+    /// the engine the next time it syncs. Returns the new ID, or `None` if
+    /// the engine isn't in `engines`.
+    pub fn bump_engine_sync_id(&mut self, engine: &str) -> Option<Guid> {
+        let engine = self.engines.get_mut(engine)?;
+        engine.sync_id = crypto::random_guid();
+        Some(engine.sync_id.clone())
+    }
+
+    /// Adds `engine` to, or removes it from, the declined list.
+    pub fn set_declined(&mut self, engine: &str, declined: bool) {
+        self.declined.retain(|name| name != engine);
+        if declined {
+            self.declined.push(engine.to_owned());
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
 pub struct CryptoKeysRecord {
     pub id: Guid,
     pub collection: String,
     pub default: [String; 2],
+    #[serde(default)]
     pub collections: HashMap<String, [String; 2]>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meta_global_updates() {
+        let mut global = MetaGlobalRecord {
+            sync_id: "syncIDAAAAAA".into(),
+            storage_version: 5,
+            engines: vec![(
+                "bookmarks".to_owned(),
+                MetaGlobalEngine {
+                    version: 1,
+                    sync_id: "syncIDBBBBBB".into(),
+                },
+            )]
+            .into_iter()
+            .collect(),
+            declined: vec!["forms".to_owned()],
+        };
+
+        let sync_id = global.bump_engine_sync_id("bookmarks").unwrap();
+        assert_ne!(sync_id, Guid::from("syncIDBBBBBB"));
+        assert_eq!(global.engines["bookmarks"].sync_id, sync_id);
+        assert!(global.bump_engine_sync_id("history").is_none());
+
+        global.set_declined("history", true);
+        global.set_declined("history", true);
+        global.set_declined("forms", false);
+        assert_eq!(global.declined, vec!["history".to_owned()]);
+    }
+}
@@ -7,7 +7,7 @@ use crate::client::Sync15ClientResponse;
 use crate::error::{self, ErrorKind, Result};
 use crate::util::ServerTimestamp;
 use serde_derive::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::default::Default;
 use std::fmt;
 use std::ops::Deref;
@@ -15,10 +15,14 @@ use sync_guid::Guid;
 use url::{form_urlencoded::Serializer, Url, UrlQuery};
 use viaduct::status_codes;
 
+/// The order the server should return records in, for `sort=`.
 #[derive(Debug, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum RequestOrder {
+    /// Least recently modified first.
     Oldest,
+    /// Most recently modified first.
     Newest,
+    /// Highest `sortindex` first.
     Index,
 }
 
@@ -33,6 +37,17 @@ impl fmt::Display for RequestOrder {
     }
 }
 
+/// A request for records in a collection, built up from the query parameters
+/// the storage server supports. For example, to fetch the 50 most recently
+/// modified records in the tabs collection:
+///
+/// ```
+/// # use sync15::{CollectionRequest, RequestOrder};
+/// let request = CollectionRequest::new("tabs")
+///     .full()
+///     .sort_by(RequestOrder::Newest)
+///     .limit(50);
+/// ```
 #[derive(Debug, Clone, PartialEq)]
 pub struct CollectionRequest {
     pub collection: String,
@@ -44,6 +59,11 @@ pub struct CollectionRequest {
     pub order: Option<RequestOrder>,
     pub commit: bool,
     pub batch: Option<String>,
+    pub offset: Option<String>,
+    /// If non-zero, records are downloaded in pages of (at most) this many
+    /// records. Unlike the other fields, this isn't sent to the server, but
+    /// tells the client how to set `limit` and `offset` for each page.
+    pub page_size: usize,
 }
 
 impl CollectionRequest {
@@ -62,9 +82,14 @@ impl CollectionRequest {
             order: None,
             commit: false,
             batch: None,
+            offset: None,
+            page_size: 0,
         }
     }
 
+    /// Only fetch the records with these IDs. Note that the server limits
+    /// how many IDs can be requested at once; see
+    /// `Sync15StorageClient::get_encrypted_records_by_ids`.
     #[inline]
     pub fn ids<V>(mut self, v: V) -> CollectionRequest
     where
@@ -74,18 +99,21 @@ impl CollectionRequest {
         self
     }
 
+    /// Fetch full records, instead of just their IDs.
     #[inline]
     pub fn full(mut self) -> CollectionRequest {
         self.full = true;
         self
     }
 
+    /// Only fetch records last modified before `ts`.
     #[inline]
     pub fn older_than(mut self, ts: ServerTimestamp) -> CollectionRequest {
         self.older = Some(ts);
         self
     }
 
+    /// Only fetch records last modified after `ts`.
     #[inline]
     pub fn newer_than(mut self, ts: ServerTimestamp) -> CollectionRequest {
         self.newer = Some(ts);
@@ -98,12 +126,25 @@ impl CollectionRequest {
         self
     }
 
+    /// Fetch at most `num` records. 0 means there's no limit.
     #[inline]
     pub fn limit(mut self, num: usize) -> CollectionRequest {
         self.limit = num;
         self
     }
 
+    #[inline]
+    pub fn offset(mut self, offset: Option<String>) -> CollectionRequest {
+        self.offset = offset;
+        self
+    }
+
+    #[inline]
+    pub fn page_size(mut self, num: usize) -> CollectionRequest {
+        self.page_size = num;
+        self
+    }
+
     #[inline]
     pub fn batch(mut self, batch: Option<String>) -> CollectionRequest {
         self.batch = batch;
@@ -147,6 +188,9 @@ impl CollectionRequest {
         if let Some(o) = self.order {
             pairs.append_pair("sort", &format!("{}", o));
         }
+        if let Some(offset) = &self.offset {
+            pairs.append_pair("offset", offset);
+        }
         pairs.finish();
     }
 
@@ -282,6 +326,96 @@ impl Deref for InfoCollections {
     }
 }
 
+/// The response from `info/collection_counts`: the number of records in each
+/// collection.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InfoCollectionCounts(HashMap<String, u64>);
+
+impl Deref for InfoCollectionCounts {
+    type Target = HashMap<String, u64>;
+
+    fn deref(&self) -> &HashMap<String, u64> {
+        &self.0
+    }
+}
+
+/// The response from `info/collection_usage`: the size of each collection,
+/// in kilobytes.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InfoCollectionUsage(HashMap<String, f64>);
+
+impl Deref for InfoCollectionUsage {
+    type Target = HashMap<String, f64>;
+
+    fn deref(&self) -> &HashMap<String, f64> {
+        &self.0
+    }
+}
+
+/// The number of records, size and last modified time of a collection on
+/// the server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CollectionOverview {
+    pub last_modified: ServerTimestamp,
+    pub count: u64,
+    pub usage_bytes: u64,
+}
+
+/// A summary of everything the user has stored on the server, for support
+/// and debugging tools.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerOverview {
+    pub collections: BTreeMap<String, CollectionOverview>,
+    pub quota: InfoQuota,
+}
+
+impl ServerOverview {
+    pub fn new(
+        collections: &InfoCollections,
+        counts: &InfoCollectionCounts,
+        usage: &InfoCollectionUsage,
+        quota: InfoQuota,
+    ) -> ServerOverview {
+        let collections = collections
+            .iter()
+            .map(|(name, last_modified)| {
+                let overview = CollectionOverview {
+                    last_modified: *last_modified,
+                    count: counts.get(name).cloned().unwrap_or_default(),
+                    usage_bytes: usage
+                        .get(name)
+                        .map(|kb| (kb * 1024.0) as u64)
+                        .unwrap_or_default(),
+                };
+                (name.clone(), overview)
+            })
+            .collect();
+        ServerOverview { collections, quota }
+    }
+}
+
+/// The response from `info/quota`: how much storage the user is using, and
+/// their quota, if the server has one. The server reports both in kilobytes.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(from = "(f64, Option<f64>)")]
+pub struct InfoQuota {
+    pub usage_kb: f64,
+    pub quota_kb: Option<f64>,
+}
+
+impl From<(f64, Option<f64>)> for InfoQuota {
+    fn from((usage_kb, quota_kb): (f64, Option<f64>)) -> InfoQuota {
+        InfoQuota { usage_kb, quota_kb }
+    }
+}
+
+impl InfoQuota {
+    #[inline]
+    pub fn usage_bytes(&self) -> u64 {
+        (self.usage_kb * 1024.0) as u64
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct UploadResult {
     batch: Option<String>,
@@ -311,8 +445,14 @@ pub struct PostQueue<Post, OnResponse> {
     max_payload_bytes: usize,
     max_request_bytes: usize,
     queued: Vec<u8>,
+    // The IDs of the records in `queued`, so that we can check the server's
+    // response accounts for each of them.
+    queued_ids: Vec<Guid>,
     batch: BatchState,
     last_modified: ServerTimestamp,
+    // Whether any of our records have been written to the collection, either
+    // by committing a batch, or by a POST outside of a batch.
+    committed: bool,
 }
 
 pub trait BatchPoster {
@@ -333,6 +473,11 @@ pub trait BatchPoster {
 // is somewhat better for documentation.
 pub trait PostResponseHandler {
     fn handle_response(&mut self, r: PostResponse, mid_batch: bool) -> Result<()>;
+
+    /// Called when a batch is abandoned because a POST in it, or its commit,
+    /// failed. None of the records staged in the batch were written, so the
+    /// handler should forget any responses it's seen for them.
+    fn rollback(&mut self) {}
 }
 
 #[derive(Debug, Clone)]
@@ -378,6 +523,41 @@ impl PostResponseHandler for NormalResponseHandler {
             _ => Err(r.create_storage_error().into()),
         }
     }
+
+    fn rollback(&mut self) {
+        self.pending_success.clear();
+        self.pending_failed.clear();
+    }
+}
+
+// Checks the server's response to a POST against the IDs of the records we
+// sent. Records it doesn't mention weren't written, so we treat them as
+// failed, but if it mentions records we didn't send, we can't trust any of
+// it.
+fn validate_upload_result(result: &mut UploadResult, posted: &[Guid]) -> Result<()> {
+    let posted_ids: HashSet<&Guid> = posted.iter().collect();
+    if result
+        .success
+        .iter()
+        .chain(result.failed.keys())
+        .any(|id| !posted_ids.contains(id))
+    {
+        return Err(
+            ErrorKind::ServerBatchProblem("Invalid server response: unexpected record ID").into(),
+        );
+    }
+    let missing: Vec<Guid> = posted
+        .iter()
+        .filter(|id| !result.success.contains(id) && !result.failed.contains_key(*id))
+        .cloned()
+        .collect();
+    for id in missing {
+        log::warn!("Server response didn't mention record {}", id);
+        result
+            .failed
+            .insert(id, "Missing from the server's response".into());
+    }
+    Ok(())
 }
 
 impl<Poster, OnResponse> PostQueue<Poster, OnResponse>
@@ -401,6 +581,8 @@ where
             max_payload_bytes: config.max_record_payload_bytes,
             max_request_bytes: config.max_request_bytes,
             queued: Vec::new(),
+            queued_ids: Vec::new(),
+            committed: false,
         }
     }
 
@@ -412,6 +594,13 @@ where
         }
     }
 
+    /// Returns true if any of the records we've posted have been written to
+    /// the collection. If not, a failed upload can be retried from scratch.
+    #[inline]
+    pub fn has_committed(&self) -> bool {
+        self.committed
+    }
+
     pub fn enqueue(&mut self, record: &EncryptedBso) -> Result<bool> {
         let payload_length = record.payload.serialized_len();
 
@@ -484,13 +673,37 @@ where
             serde_json::to_writer(&mut self.queued, &record).unwrap();
         }
 
+        self.queued_ids.push(record.id.clone());
         self.post_limits.record_added(payload_length);
         self.batch_limits.record_added(payload_length);
 
         Ok(true)
     }
 
+    /// Posts the queued records, committing the batch if `want_commit` is
+    /// true. If the POST or the commit fails part way through a batch, the
+    /// batch is abandoned: none of its records were written, so we go back
+    /// to not being in a batch, and the records can be enqueued again.
     pub fn flush(&mut self, want_commit: bool) -> Result<()> {
+        let result = self.do_flush(want_commit);
+        // Posts outside of a batch are written as soon as they succeed, so
+        // there's nothing to roll back.
+        if result.is_err() && self.batch != BatchState::Unsupported {
+            self.rollback();
+        }
+        result
+    }
+
+    fn rollback(&mut self) {
+        if self.in_batch() {
+            log::warn!("Abandoning batch {:?}", self.batch);
+        }
+        self.batch = BatchState::NoBatch;
+        self.batch_limits.clear();
+        self.on_response.rollback();
+    }
+
+    fn do_flush(&mut self, want_commit: bool) -> Result<()> {
         if self.queued.is_empty() {
             assert!(
                 !self.in_batch(),
@@ -527,13 +740,17 @@ where
         );
 
         self.queued.truncate(0);
+        let posted_ids = std::mem::replace(&mut self.queued_ids, Vec::new());
 
         if want_commit || self.batch == BatchState::Unsupported {
             self.batch_limits.clear();
         }
         self.post_limits.clear();
 
-        let resp = resp_or_error?;
+        let mut resp = resp_or_error?;
+        if let Sync15ClientResponse::Success { ref mut record, .. } = resp {
+            validate_upload_result(record, &posted_ids)?;
+        }
 
         let (status, last_modified, record) = match resp {
             Sync15ClientResponse::Success {
@@ -556,6 +773,7 @@ where
         if want_commit {
             log::debug!("Committed batch {:?}", self.batch);
             self.batch = BatchState::NoBatch;
+            self.committed = true;
             self.on_response.handle_response(resp, false)?;
             return Ok(());
         }
@@ -570,6 +788,7 @@ where
             self.last_modified = last_modified;
             self.batch = BatchState::Unsupported;
             self.batch_limits.clear();
+            self.committed = true;
             self.on_response.handle_response(resp, false)?;
             return Ok(());
         }
@@ -612,6 +831,9 @@ where
 pub struct UploadInfo {
     pub successful_ids: Vec<Guid>,
     pub failed_ids: Vec<Guid>,
+    /// Records we didn't try to upload, because they're larger than the
+    /// server allows.
+    pub skipped_ids: Vec<Guid>,
     pub modified_timestamp: ServerTimestamp,
 }
 
@@ -625,6 +847,7 @@ impl<Poster> PostQueue<Poster, NormalResponseHandler> {
                     + self.on_response.pending_failed.len()
                     + self.on_response.pending_success.len(),
             ),
+            skipped_ids: Vec::new(),
             modified_timestamp: self.last_modified,
         };
 
@@ -648,6 +871,7 @@ impl<Poster> PostQueue<Poster, NormalResponseHandler> {
 mod test {
     use super::*;
     use crate::bso_record::{BsoRecord, EncryptedPayload};
+    use crate::error::ErrorResponse;
     use lazy_static::lazy_static;
     use std::cell::RefCell;
     use std::collections::VecDeque;
@@ -698,6 +922,121 @@ mod test {
             .unwrap();
         assert_eq!(complex.as_str(),
             "https://example.com/sync/storage/specific?full=1&limit=10&older=9876.54&newer=1234.56&sort=oldest");
+
+        let paged = CollectionRequest::new("history")
+            .full()
+            .limit(100)
+            .page_size(50)
+            .offset(Some("abc123".into()))
+            .build_url(base.clone())
+            .unwrap();
+        assert_eq!(
+            paged.as_str(),
+            "https://example.com/sync/storage/history?full=1&limit=100&offset=abc123"
+        );
+    }
+
+    // A response recorded from a storage server, for the request below.
+    const RECORDED_NEWEST_TABS: &str = r#"[
+        {"id": "client2AAAAA", "modified": 1574118080.22, "sortindex": 2,
+         "payload": "{\"IV\": \"aaaa\", \"hmac\": \"bbbb\", \"ciphertext\": \"cccc\"}"},
+        {"id": "client1AAAAA", "modified": 1574117937.57,
+         "payload": "{\"IV\": \"dddd\", \"hmac\": \"eeee\", \"ciphertext\": \"ffff\"}"}
+    ]"#;
+
+    #[test]
+    fn test_newest_records() {
+        let base = Url::parse("https://example.com/1.5/12345").unwrap();
+        let url = CollectionRequest::new("tabs")
+            .full()
+            .sort_by(RequestOrder::Newest)
+            .limit(2)
+            .newer_than(ServerTimestamp(1_574_000_000_000))
+            .build_url(base)
+            .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://example.com/1.5/12345/storage/tabs?full=1&limit=2&newer=1574000000&sort=newest"
+        );
+
+        let mut headers = viaduct::Headers::new();
+        headers
+            .insert(viaduct::header_names::X_LAST_MODIFIED, "1574118080.22")
+            .unwrap();
+        let resp = viaduct::Response {
+            request_method: viaduct::Method::Get,
+            url,
+            status: 200,
+            headers,
+            body: RECORDED_NEWEST_TABS.as_bytes().to_vec(),
+        };
+        let records = Sync15ClientResponse::<Vec<EncryptedBso>>::from_response(resp)
+            .unwrap()
+            .into_record()
+            .unwrap();
+        assert_eq!(
+            records.iter().map(|r| r.id.as_str()).collect::<Vec<_>>(),
+            vec!["client2AAAAA", "client1AAAAA"]
+        );
+        assert_eq!(records[0].modified, ServerTimestamp(1_574_118_080_220));
+        assert_eq!(records[0].sortindex, Some(2));
+    }
+
+    #[test]
+    fn test_info_quota() {
+        let quota: InfoQuota = serde_json::from_str("[34816.5, null]").unwrap();
+        assert_eq!(
+            quota,
+            InfoQuota {
+                usage_kb: 34816.5,
+                quota_kb: None
+            }
+        );
+        assert_eq!(quota.usage_bytes(), 35_652_096);
+        let quota: InfoQuota = serde_json::from_str("[1, 2048]").unwrap();
+        assert_eq!(quota.quota_kb, Some(2048.0));
+    }
+
+    #[test]
+    fn test_server_overview() {
+        let collections = InfoCollections::new(
+            vec![
+                ("bookmarks".to_string(), ServerTimestamp(1_000)),
+                ("history".to_string(), ServerTimestamp(2_000)),
+            ]
+            .into_iter()
+            .collect(),
+        );
+        let counts: InfoCollectionCounts =
+            serde_json::from_str(r#"{"bookmarks": 12, "history": 3400}"#).unwrap();
+        // Collections that are empty, but have been modified, aren't listed.
+        let usage: InfoCollectionUsage = serde_json::from_str(r#"{"history": 2.5}"#).unwrap();
+        let quota = InfoQuota {
+            usage_kb: 2.5,
+            quota_kb: None,
+        };
+        let overview = ServerOverview::new(&collections, &counts, &usage, quota);
+        assert_eq!(
+            overview.collections.into_iter().collect::<Vec<_>>(),
+            vec![
+                (
+                    "bookmarks".to_string(),
+                    CollectionOverview {
+                        last_modified: ServerTimestamp(1_000),
+                        count: 12,
+                        usage_bytes: 0,
+                    }
+                ),
+                (
+                    "history".to_string(),
+                    CollectionOverview {
+                        last_modified: ServerTimestamp(2_000),
+                        count: 3400,
+                        usage_bytes: 2560,
+                    }
+                ),
+            ]
+        );
     }
 
     #[derive(Debug, Clone)]
@@ -803,7 +1142,10 @@ mod test {
             let record = match response {
                 Sync15ClientResponse::Success { ref record, .. } => record,
                 _ => {
-                    panic!("only success codes are used in this test");
+                    // The server rejected the post, so any batch it was in
+                    // is gone.
+                    self.cur_batch = None;
+                    return Ok(response);
                 }
             };
 
@@ -933,6 +1275,30 @@ mod test {
     }
 
     // Actual record size (for max_request_len) will be larger by some amount
+    fn fake_upload_response<'a, T: Into<Option<&'a str>>>(
+        status: u16,
+        lm: i64,
+        batch: T,
+        success: &[&str],
+        failed: &[&str],
+    ) -> PostResponse {
+        let mut resp = fake_response(status, lm, batch);
+        if let Sync15ClientResponse::Success { ref mut record, .. } = resp {
+            record.success = success.iter().map(|&id| id.into()).collect();
+            record.failed = failed
+                .iter()
+                .map(|&id| (id.into(), "failed".to_string()))
+                .collect();
+        }
+        resp
+    }
+
+    fn make_record_with_id(id: &str, payload_size: usize) -> EncryptedBso {
+        let mut record = make_record(payload_size);
+        record.id = id.into();
+        record
+    }
+
     fn make_record(payload_size: usize) -> EncryptedBso {
         assert!(payload_size > *PAYLOAD_OVERHEAD);
         let ciphertext_len = payload_size - *PAYLOAD_OVERHEAD;
@@ -1395,9 +1761,150 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_pq_validates_response() {
+        let cfg = InfoConfiguration::default();
+        let time = 11_111_111_000;
+        let tester = TestPoster::new(
+            &cfg,
+            vec![fake_upload_response(
+                status_codes::OK,
+                time + 100_000,
+                None,
+                &["aaaaaaaaaaaa"],
+                &[],
+            )],
+        );
+        let mut pq = PostQueue::new(
+            &cfg,
+            ServerTimestamp(time),
+            tester.clone(),
+            NormalResponseHandler::new(true),
+        );
+        pq.enqueue(&make_record_with_id("aaaaaaaaaaaa", 100))
+            .unwrap();
+        pq.enqueue(&make_record_with_id("bbbbbbbbbbbb", 100))
+            .unwrap();
+        pq.flush(true).unwrap();
+
+        // The server didn't mention one of our records, so we can't assume it
+        // was written.
+        let info = pq.completed_upload_info();
+        assert_eq!(info.successful_ids, vec![Guid::from("aaaaaaaaaaaa")]);
+        assert_eq!(info.failed_ids, vec![Guid::from("bbbbbbbbbbbb")]);
+        assert!(pq.has_committed());
+
+        let tester = TestPoster::new(
+            &cfg,
+            vec![fake_upload_response(
+                status_codes::OK,
+                time + 100_000,
+                None,
+                &["aaaaaaaaaaaa", "cccccccccccc"],
+                &[],
+            )],
+        );
+        let mut pq = PostQueue::new(
+            &cfg,
+            ServerTimestamp(time),
+            tester.clone(),
+            NormalResponseHandler::new(true),
+        );
+        pq.enqueue(&make_record_with_id("aaaaaaaaaaaa", 100))
+            .unwrap();
+        match pq.flush(true).unwrap_err().kind() {
+            ErrorKind::ServerBatchProblem(_) => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert!(pq.completed_upload_info().successful_ids.is_empty());
+    }
+
+    #[test]
+    fn test_pq_commit_failure_rolls_back() {
+        let cfg = InfoConfiguration {
+            max_post_records: 1,
+            ..InfoConfiguration::default()
+        };
+        let time = 11_111_111_000;
+        let tester = TestPoster::new(
+            &cfg,
+            vec![
+                fake_upload_response(
+                    status_codes::ACCEPTED,
+                    time,
+                    Some("1234"),
+                    &["aaaaaaaaaaaa"],
+                    &[],
+                ),
+                Sync15ClientResponse::Error(ErrorResponse::ServerError {
+                    route: "test/path".into(),
+                    status: 503,
+                }),
+                fake_upload_response(
+                    status_codes::ACCEPTED,
+                    time,
+                    Some("5678"),
+                    &["aaaaaaaaaaaa"],
+                    &[],
+                ),
+                fake_upload_response(
+                    status_codes::OK,
+                    time + 100_000,
+                    Some("5678"),
+                    &["bbbbbbbbbbbb"],
+                    &[],
+                ),
+            ],
+        );
+        let mut pq = PostQueue::new(
+            &cfg,
+            ServerTimestamp(time),
+            tester.clone(),
+            NormalResponseHandler::new(false),
+        );
+        let records = vec![
+            make_record_with_id("aaaaaaaaaaaa", 100),
+            make_record_with_id("bbbbbbbbbbbb", 100),
+        ];
+
+        // The first record is staged, but the commit fails, so neither should
+        // be marked as uploaded, and we should be out of the batch.
+        for record in &records {
+            pq.enqueue(record).unwrap();
+        }
+        assert!(pq.flush(true).is_err());
+        assert!(!pq.has_committed());
+        assert_eq!(pq.batch, BatchState::NoBatch);
+        assert_eq!(pq.batch_limits.cur_records, 0);
+        assert_eq!(pq.last_modified, ServerTimestamp(time));
+        assert!(pq.on_response.pending_success.is_empty());
+        assert!(pq.on_response.successful_ids.is_empty());
+
+        // Trying again starts a new batch, with the same `X-I-U-S`.
+        for record in &records {
+            pq.enqueue(record).unwrap();
+        }
+        pq.flush(true).unwrap();
+        assert!(pq.has_committed());
+        assert_eq!(pq.last_modified, ServerTimestamp(time + 100_000));
+        let info = pq.completed_upload_info();
+        assert_eq!(
+            info.successful_ids,
+            vec![Guid::from("aaaaaaaaaaaa"), Guid::from("bbbbbbbbbbbb")]
+        );
+        assert!(info.failed_ids.is_empty());
+
+        let t = tester.borrow();
+        assert_eq!(t.all_posts.len(), 4);
+        assert_eq!(t.all_posts[2].batch.as_ref().unwrap(), "true");
+        assert_eq!(t.all_posts[2].xius, ServerTimestamp(time));
+        assert_eq!(t.batches.len(), 1);
+        assert_eq!(t.batches[0].id.as_ref().unwrap(), "5678");
+    }
+
     // TODO: Test
     //
-    // - error cases!!! We don't test our handling of server errors at all!
+    // - more error cases, like errors outside of a batch
     // - mixed bytes/record limits
     //
     // A lot of these have good examples in test_postqueue.js on deskftop sync
@@ -3,11 +3,13 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::collections::HashMap;
-use std::mem;
 
+use crate::backoff::BackoffState;
 use crate::bso_record::EncryptedBso;
 use crate::client::{SetupStorageClient, Sync15ClientResponse};
+use crate::clients::ClientCommand;
 use crate::collection_keys::CollectionKeys;
+use crate::crypto;
 use crate::error::{self, ErrorKind, ErrorResponse};
 use crate::key_bundle::KeyBundle;
 use crate::record_types::{MetaGlobalEngine, MetaGlobalRecord};
@@ -16,7 +18,6 @@ use crate::util::ServerTimestamp;
 use interrupt::Interruptee;
 use lazy_static::lazy_static;
 use serde_derive::*;
-use sync_guid::Guid;
 
 use self::SetupState::*;
 
@@ -45,7 +46,7 @@ lazy_static! {
 }
 
 /// State that we require the app to persist to storage for us.
-/// It's a little unfortunate we need this, because it's only tracking
+/// It's a little unfortunate we need this, because it's mostly tracking
 /// "declined engines", and even then, only needed in practice when there's
 /// no meta/global so we need to create one. It's extra unfortunate because we
 /// want to move away from "globally declined" engines anyway, moving towards
@@ -60,16 +61,60 @@ lazy_static! {
 pub enum PersistedGlobalState {
     /// V1 was when we persisted the entire GlobalState, keys and all!
 
-    /// V2 is just tracking the globally declined list.
+    /// V2 is tracking the globally declined list.
     /// None means "I've no idea" and theoretically should only happen on the
     /// very first sync for an app.
-    V2 { declined: Option<Vec<String>> },
+    /// It also holds the commands we've taken from the app to send to other
+    /// clients, but haven't yet uploaded, so that they survive a failed sync.
+    V2 {
+        declined: Option<Vec<String>>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pending_commands: Vec<ClientCommand>,
+        /// When the servers have asked us to back off until, if they have.
+        #[serde(default, skip_serializing_if = "BackoffState::is_expired")]
+        backoff: BackoffState,
+        /// Maps each store's collection name to a fingerprint of the key it
+        /// last synced with, so that we can reset it if the key changes,
+        /// even across restarts.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        key_fingerprints: HashMap<String, String>,
+    },
 }
 
 impl Default for PersistedGlobalState {
     #[inline]
     fn default() -> PersistedGlobalState {
-        PersistedGlobalState::V2 { declined: None }
+        PersistedGlobalState::V2 {
+            declined: None,
+            pending_commands: Vec::new(),
+            backoff: BackoffState::default(),
+            key_fingerprints: HashMap::new(),
+        }
+    }
+}
+
+impl PersistedGlobalState {
+    /// The commands waiting to be sent to other clients.
+    pub(crate) fn pending_commands_mut(&mut self) -> &mut Vec<ClientCommand> {
+        let PersistedGlobalState::V2 {
+            pending_commands, ..
+        } = self;
+        pending_commands
+    }
+
+    /// Our backoff state. This is shared with the storage client, which
+    /// updates it when the server asks us to back off.
+    pub(crate) fn backoff(&self) -> &BackoffState {
+        let PersistedGlobalState::V2 { backoff, .. } = self;
+        backoff
+    }
+
+    /// The fingerprints of the keys each store last synced with.
+    pub(crate) fn key_fingerprints_mut(&mut self) -> &mut HashMap<String, String> {
+        let PersistedGlobalState::V2 {
+            key_fingerprints, ..
+        } = self;
+        key_fingerprints
     }
 }
 
@@ -90,10 +135,10 @@ pub struct GlobalState {
 /// Creates a fresh `meta/global` record, using the default engine selections,
 /// and declined engines from our PersistedGlobalState.
 fn new_global(pgs: &PersistedGlobalState) -> error::Result<MetaGlobalRecord> {
-    let sync_id = Guid::random();
+    let sync_id = crypto::random_guid();
     let mut engines: HashMap<String, _> = HashMap::new();
     for (name, version) in DEFAULT_ENGINES.iter() {
-        let sync_id = Guid::random();
+        let sync_id = crypto::random_guid();
         engines.insert(
             name.to_string(),
             MetaGlobalEngine {
@@ -106,7 +151,9 @@ fn new_global(pgs: &PersistedGlobalState) -> error::Result<MetaGlobalRecord> {
     // we previously saw a meta/global then we would have updated it with what
     // it was at the time.
     let declined = match pgs {
-        PersistedGlobalState::V2 { declined: Some(d) } => d.clone(),
+        PersistedGlobalState::V2 {
+            declined: Some(d), ..
+        } => d.clone(),
         _ => {
             log::warn!("New meta/global without local app state - the list of declined engines is being reset");
             DEFAULT_DECLINED.iter().map(ToString::to_string).collect()
@@ -310,12 +357,8 @@ impl<'a> SetupStateMachine<'a> {
                 global_timestamp,
             } => {
                 // Update our PersistedGlobalState with the mega/global we just read.
-                mem::replace(
-                    self.pgs,
-                    PersistedGlobalState::V2 {
-                        declined: Some(global.declined.clone()),
-                    },
-                );
+                let PersistedGlobalState::V2 { declined, .. } = self.pgs;
+                *declined = Some(global.declined.clone());
                 // Now try and get keys etc - if we fresh-start we'll re-use declined.
                 match self.client.fetch_crypto_keys()? {
                     Sync15ClientResponse::Success {
@@ -379,15 +422,7 @@ impl<'a> SetupStateMachine<'a> {
                 // Wipe the server.
                 self.client.wipe_all_remote()?;
 
-                // Upload a fresh `meta/global`...
-                let new_global = new_global(self.pgs)?;
-                self.client
-                    .put_meta_global(ServerTimestamp::default(), &new_global)?;
-
-                // ...And a fresh `crypto/keys`.
-                let new_keys = CollectionKeys::new_random()?.to_encrypted_bso(&self.root_key)?;
-                self.client
-                    .put_crypto_keys(ServerTimestamp::default(), &new_keys)?;
+                upload_fresh_start(self.client, self.pgs, self.root_key)?;
 
                 // TODO(lina): Can we pass along server timestamps from the PUTs
                 // above, and avoid re-fetching the `m/g` and `c/k` we just
@@ -434,6 +469,70 @@ impl<'a> SetupStateMachine<'a> {
     }
 }
 
+/// Gives `collection` its own key in `crypto/keys`, so that it's no longer
+/// encrypted with the default key, and uploads the new keys. Returns false if
+/// the collection already has its own key, in which case nothing is uploaded.
+///
+/// Records already on the server aren't re-encrypted, so this should be done
+/// before a collection is first synced. The upload fails if `crypto/keys`
+/// changed since `global_state` was fetched. Other stores keep their keys,
+/// so only the store for `collection` is reset on the next sync.
+pub fn provision_collection_key(
+    client: &dyn SetupStorageClient,
+    global_state: &GlobalState,
+    root_key: &KeyBundle,
+    collection: &str,
+) -> error::Result<bool> {
+    let mut keys = CollectionKeys::from_encrypted_bso(global_state.keys.clone(), root_key)?;
+    if !keys.add_collection_key(collection)? {
+        return Ok(false);
+    }
+    log::info!("Uploading a new key for {}", collection);
+    client.put_crypto_keys(
+        global_state.keys.modified,
+        &keys.to_encrypted_bso(root_key)?,
+    )?;
+    Ok(true)
+}
+
+/// Applies `update` to a copy of `meta/global`, and uploads the result. This
+/// is how to change the declined engines, engine sync IDs, or storage
+/// version outside of a sync. The upload fails with a `Conflict` if
+/// `meta/global` changed since `global_state` was fetched. Returns the new
+/// record; the next sync will fetch it again.
+pub fn update_meta_global<F>(
+    client: &dyn SetupStorageClient,
+    global_state: &GlobalState,
+    update: F,
+) -> error::Result<MetaGlobalRecord>
+where
+    F: FnOnce(&mut MetaGlobalRecord),
+{
+    let mut global = global_state.global.clone();
+    update(&mut global);
+    log::info!("Uploading an updated meta/global");
+    client.put_meta_global(global_state.global_timestamp, &global)?;
+    Ok(global)
+}
+
+/// Uploads a fresh `meta/global`, with new sync IDs for every engine, and a
+/// fresh `crypto/keys`, to a server that's just been wiped. Every client
+/// will reset all its engines on its next sync.
+pub(crate) fn upload_fresh_start(
+    client: &dyn SetupStorageClient,
+    pgs: &PersistedGlobalState,
+    root_key: &KeyBundle,
+) -> error::Result<()> {
+    // Upload a fresh `meta/global`...
+    let new_global = new_global(pgs)?;
+    client.put_meta_global(ServerTimestamp::default(), &new_global)?;
+
+    // ...And a fresh `crypto/keys`.
+    let new_keys = CollectionKeys::new_random()?.to_encrypted_bso(root_key)?;
+    client.put_crypto_keys(ServerTimestamp::default(), &new_keys)?;
+    Ok(())
+}
+
 /// States in the remote setup process.
 /// TODO(lina): Add link once #56 is merged.
 #[derive(Debug)]
@@ -656,7 +755,7 @@ mod tests {
                 888_000,
             ),
         };
-        let mut pgs = PersistedGlobalState::V2 { declined: None };
+        let mut pgs = PersistedGlobalState::default();
 
         let mut state_machine =
             SetupStateMachine::for_full_sync(&client, &root_key, &mut pgs, &NeverInterrupts);
@@ -2,9 +2,11 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::clients::RemoteClient;
 use crate::error::{Error, ErrorKind, ErrorResponse};
 use crate::telemetry::SyncTelemetryPing;
 use std::collections::HashMap;
+use sync_guid::Guid;
 
 /// The general status of sync - should probably be moved to the "sync manager"
 /// once we have one!
@@ -20,6 +22,10 @@ pub enum ServiceStatus {
     AuthenticationError,
     /// We declined to do anything for backoff or rate-limiting reasons.
     BackedOff,
+    /// The user is over their storage quota, so we stopped uploading. The
+    /// failing engine's result says which collection hit the quota; see
+    /// `SyncResult::over_quota_collection`.
+    OverQuota,
     /// We were interrupted.
     Interrupted,
     /// Something else - you need to check the logs for more details. May
@@ -41,10 +47,11 @@ impl ServiceStatus {
                     ServiceStatus::ServiceError
                 }
             }
-            // BackoffError is also from the tokenserver.
-            ErrorKind::BackoffError(_) => ServiceStatus::ServiceError,
+            // BackoffError comes from either the tokenserver or storage.
+            ErrorKind::BackoffError(_) => ServiceStatus::BackedOff,
             ErrorKind::StorageHttpError(ref e) => match e {
                 ErrorResponse::Unauthorized { .. } => ServiceStatus::AuthenticationError,
+                ErrorResponse::OverQuota { .. } => ServiceStatus::OverQuota,
                 _ => ServiceStatus::ServiceError,
             },
 
@@ -54,6 +61,10 @@ impl ServiceStatus {
             | ErrorKind::HawkError(_) => ServiceStatus::NetworkError,
 
             ErrorKind::Interrupted(_) => ServiceStatus::Interrupted,
+            // Conflicts only affect one collection, so we carry on syncing
+            // the others.
+            ErrorKind::Conflict(_) => ServiceStatus::OtherError,
+            ErrorKind::OverQuota(_) => ServiceStatus::OverQuota,
             _ => ServiceStatus::OtherError,
         }
     }
@@ -73,5 +84,67 @@ pub struct SyncResult {
     /// Note that we expect the `String` to be replaced with an enum later.
     pub engine_results: HashMap<String, Result<(), Error>>,
 
+    /// The clients we saw while syncing the clients collection, keyed by
+    /// their record ID. Empty if the clients collection wasn't synced.
+    pub recent_clients: HashMap<String, RemoteClient>,
+
+    /// The engines enabled in `meta/global`, sorted by name. Sync doesn't
+    /// record which engines each client syncs, so these apply to all the
+    /// `recent_clients`. Empty if we didn't get as far as fetching
+    /// `meta/global`.
+    pub enabled_engines: Vec<String>,
+
+    /// The IDs of records each engine skipped uploading because they're too
+    /// large for the server. Engines that didn't skip any aren't listed.
+    pub skipped_records: HashMap<String, Vec<Guid>>,
+
+    /// The engines we didn't get to because the `deadline` in the
+    /// `SyncMultipleParams` passed before we started syncing them, in the
+    /// order we would have synced them.
+    pub deferred_engines: Vec<String>,
+
     pub telemetry: SyncTelemetryPing,
 }
+
+impl SyncResult {
+    /// Returns the collection that hit the user's storage quota, if any, so
+    /// that the app can tell the user.
+    pub fn over_quota_collection(&self) -> Option<&str> {
+        self.engine_results
+            .values()
+            .chain(std::iter::once(&self.result))
+            .filter_map(|result| match result.as_ref().map_err(Error::kind) {
+                Err(ErrorKind::OverQuota(collection)) => Some(collection.as_str()),
+                _ => None,
+            })
+            .next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_over_quota() {
+        let error = || Error::from(ErrorKind::OverQuota("bookmarks".into()));
+        assert_eq!(ServiceStatus::from_err(&error()), ServiceStatus::OverQuota);
+
+        let mut result = SyncResult {
+            service_status: ServiceStatus::OverQuota,
+            result: Ok(()),
+            engine_results: HashMap::new(),
+            recent_clients: HashMap::new(),
+            enabled_engines: Vec::new(),
+            skipped_records: HashMap::new(),
+            deferred_engines: Vec::new(),
+            telemetry: SyncTelemetryPing::new(),
+        };
+        assert_eq!(result.over_quota_collection(), None);
+        result.engine_results.insert("history".into(), Ok(()));
+        result
+            .engine_results
+            .insert("bookmarks".into(), Err(error()));
+        assert_eq!(result.over_quota_collection(), Some("bookmarks"));
+    }
+}
@@ -2,12 +2,12 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::changeset::{CollectionUpdate, IncomingChangeset, OutgoingChangeset};
+use crate::changeset::{CollectionUpdate, IncomingChangeset, OutgoingChangeset, RecordChangeset};
 use crate::client::Sync15StorageClient;
-use crate::coll_state::{LocalCollStateMachine, StoreSyncAssociation};
-use crate::error::Error;
+use crate::coll_state::{CollState, LocalCollStateMachine, StoreSyncAssociation};
+use crate::error::{Error, ErrorKind};
 use crate::key_bundle::KeyBundle;
-use crate::request::CollectionRequest;
+use crate::request::{CollectionRequest, UploadInfo};
 use crate::state::GlobalState;
 use crate::telemetry;
 use crate::util::ServerTimestamp;
@@ -27,12 +27,78 @@ pub trait Store {
         telem: &mut telemetry::Engine,
     ) -> Result<OutgoingChangeset, failure::Error>;
 
+    /// Returns true if the store would rather be given incoming records in
+    /// chunks, as each page is downloaded, using `apply_incoming_chunk` and
+    /// `finish_incoming`, instead of all at once with `apply_incoming`. This
+    /// is only used if the collection request has a `page_size`.
+    fn applies_incoming_in_chunks(&self) -> bool {
+        false
+    }
+
+    /// Applies one page of incoming records. Stores should apply each chunk
+    /// in its own transaction, so that they never need to hold every record
+    /// in memory, or in a single giant transaction. The collection's last
+    /// sync time shouldn't be updated until `finish_incoming` is called,
+    /// because if we're interrupted, we'll need to fetch all the pages again.
+    fn apply_incoming_chunk(
+        &self,
+        _chunk: IncomingChangeset,
+        _telem: &mut telemetry::EngineIncoming,
+    ) -> Result<(), failure::Error> {
+        Err(failure::err_msg("This store doesn't apply incoming chunks"))
+    }
+
+    /// Called once every chunk has been applied, to get the outgoing
+    /// changes. `timestamp` is the collection's new last modified time.
+    fn finish_incoming(
+        &self,
+        _timestamp: ServerTimestamp,
+        _telem: &mut telemetry::Engine,
+    ) -> Result<OutgoingChangeset, failure::Error> {
+        Err(failure::err_msg("This store doesn't apply incoming chunks"))
+    }
+
+    /// Returns the number of records to upload in each commit, if the store
+    /// wants its outgoing changes uploaded in "outbox" mode. In this mode,
+    /// the store's own change tracking acts as the outbox: the records are
+    /// uploaded in several smaller commits instead of one big one, and each
+    /// commit is reported to `outbox_chunk_uploaded` as soon as the server
+    /// accepts it. If we go offline, or are interrupted, part way through,
+    /// the records that were committed are marked as synced, and the rest
+    /// stay in the outbox to be flushed on the next sync.
+    fn outbox_chunk_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Called after each commit in outbox mode, with the collection's new
+    /// last modified time and the records the server accepted. Stores must
+    /// only mark those records as synced, leaving everything else to be
+    /// uploaded later.
+    fn outbox_chunk_uploaded(
+        &self,
+        _new_timestamp: ServerTimestamp,
+        _records_synced: Vec<Guid>,
+    ) -> Result<(), failure::Error> {
+        Err(failure::err_msg("This store doesn't support outbox mode"))
+    }
+
+    /// Called once the outgoing changes have been uploaded. In outbox mode,
+    /// `records_synced` is empty, because each record has already been passed
+    /// to `outbox_chunk_uploaded`.
     fn sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
         records_synced: Vec<Guid>,
     ) -> Result<(), failure::Error>;
 
+    /// Returns true if the store has local changes to upload. If it can
+    /// answer cheaply, implementing this lets us skip syncing the store
+    /// entirely when its collection hasn't changed on the server either. The
+    /// default assumes there are always changes.
+    fn has_outgoing_changes(&self) -> Result<bool, failure::Error> {
+        Ok(true)
+    }
+
     /// The store is responsible for building the collection request. Engines
     /// typically will store a lastModified timestamp and use that to build
     /// a request saying "give me full records since that date" - however, other
@@ -51,6 +117,9 @@ pub trait Store {
     fn wipe(&self) -> Result<(), failure::Error>;
 }
 
+/// Syncs a single store. Returns the IDs of any outgoing records we skipped
+/// because they're too large to upload. Those records are left as they are
+/// locally, and not passed to `sync_finished`.
 pub fn synchronize(
     client: &Sync15StorageClient,
     global_state: &GlobalState,
@@ -59,7 +128,7 @@ pub fn synchronize(
     fully_atomic: bool,
     telem_engine: &mut telemetry::Engine,
     interruptee: &impl Interruptee,
-) -> Result<(), Error> {
+) -> Result<Vec<Guid>, Error> {
     let collection = store.collection_name();
     log::info!("Syncing collection {}", collection);
 
@@ -73,26 +142,56 @@ pub fn synchronize(
                 "can't setup for the {} collection - hopefully it works later",
                 collection
             );
-            return Ok(());
+            return Ok(vec![]);
         }
     };
 
     let collection_request = store.get_collection_request()?;
     interruptee.err_if_interrupted()?;
-    let incoming_changes = IncomingChangeset::fetch(
-        client,
-        &mut coll_state,
-        collection.into(),
-        &collection_request,
-    )?;
-    assert_eq!(incoming_changes.timestamp, coll_state.last_modified);
+    let (new_timestamp, mut outgoing) =
+        if store.applies_incoming_in_chunks() && collection_request.page_size > 0 {
+            let mut telem_incoming = telemetry::EngineIncoming::new();
+            let mut num_changes = 0;
+            let new_timestamp = IncomingChangeset::fetch_chunks(
+                client,
+                &mut coll_state,
+                collection.into(),
+                &collection_request,
+                |chunk| {
+                    interruptee.err_if_interrupted()?;
+                    num_changes += chunk.changes.len();
+                    log::debug!("Applying {} remote changes", chunk.changes.len());
+                    store
+                        .apply_incoming_chunk(chunk, &mut telem_incoming)
+                        .map_err(ErrorKind::StoreError)?;
+                    Ok(())
+                },
+            )?;
+            telem_engine.incoming(telem_incoming);
+            log::info!("Downloaded and applied {} remote changes", num_changes);
+            (
+                new_timestamp,
+                store.finish_incoming(new_timestamp, telem_engine)?,
+            )
+        } else {
+            let incoming_changes = IncomingChangeset::fetch(
+                client,
+                &mut coll_state,
+                collection.into(),
+                &collection_request,
+            )?;
+            assert_eq!(incoming_changes.timestamp, coll_state.last_modified);
 
-    log::info!(
-        "Downloaded {} remote changes",
-        incoming_changes.changes.len()
-    );
-    let new_timestamp = incoming_changes.timestamp;
-    let mut outgoing = store.apply_incoming(incoming_changes, telem_engine)?;
+            log::info!(
+                "Downloaded {} remote changes",
+                incoming_changes.changes.len()
+            );
+            let new_timestamp = incoming_changes.timestamp;
+            (
+                new_timestamp,
+                store.apply_incoming(incoming_changes, telem_engine)?,
+            )
+        };
 
     interruptee.err_if_interrupted()?;
     // xxx - duplication below smells wrong
@@ -100,24 +199,127 @@ pub fn synchronize(
     coll_state.last_modified = new_timestamp;
 
     log::info!("Uploading {} outgoing changes", outgoing.changes.len());
+    if let Some(chunk_size) = store.outbox_chunk_size() {
+        let (result, upload_info) = upload_outbox(
+            client,
+            &mut coll_state,
+            store,
+            outgoing,
+            chunk_size,
+            interruptee,
+        );
+        record_outgoing_telemetry(telem_engine, &upload_info);
+        result?;
+        store.sync_finished(upload_info.modified_timestamp, vec![])?;
+        log::info!("Sync finished!");
+        return Ok(upload_info.skipped_ids);
+    }
+
     let upload_info =
         CollectionUpdate::new_from_changeset(client, &coll_state, outgoing, fully_atomic)?
             .upload()?;
 
     log::info!(
-        "Upload success ({} records success, {} records failed)",
+        "Upload success ({} records success, {} records failed, {} records skipped)",
         upload_info.successful_ids.len(),
-        upload_info.failed_ids.len()
+        upload_info.failed_ids.len(),
+        upload_info.skipped_ids.len()
     );
-    // ideally we'd report this per-batch, but for now, let's just report it
-    // as a total.
-    let mut telem_outgoing = telemetry::EngineOutgoing::new();
-    telem_outgoing.sent(upload_info.successful_ids.len() + upload_info.failed_ids.len());
-    telem_outgoing.failed(upload_info.failed_ids.len());
-    telem_engine.outgoing(telem_outgoing);
+    record_outgoing_telemetry(telem_engine, &upload_info);
 
     store.sync_finished(upload_info.modified_timestamp, upload_info.successful_ids)?;
 
     log::info!("Sync finished!");
+    Ok(upload_info.skipped_ids)
+}
+
+// Uploads `outgoing` in commits of `chunk_size` records, telling the store
+// about each one as it's committed. Returns the combined upload info for every
+// commit that succeeded, along with the result, so that we can report partial
+// progress even if a later commit fails.
+fn upload_outbox(
+    client: &Sync15StorageClient,
+    coll_state: &mut CollState,
+    store: &dyn Store,
+    outgoing: OutgoingChangeset,
+    chunk_size: usize,
+    interruptee: &impl Interruptee,
+) -> (Result<(), Error>, UploadInfo) {
+    let mut total = UploadInfo {
+        successful_ids: vec![],
+        failed_ids: vec![],
+        skipped_ids: vec![],
+        modified_timestamp: coll_state.last_modified,
+    };
+    let result = upload_outbox_chunks(
+        client,
+        coll_state,
+        store,
+        outgoing,
+        chunk_size,
+        interruptee,
+        &mut total,
+    );
+    if let Err(e) = &result {
+        log::warn!(
+            "Outbox upload stopped after {} records: {}",
+            total.successful_ids.len(),
+            e
+        );
+    }
+    (result, total)
+}
+
+// Each commit is fully atomic, and uses the previous commit's timestamp for
+// its XIUS check.
+fn upload_outbox_chunks(
+    client: &Sync15StorageClient,
+    coll_state: &mut CollState,
+    store: &dyn Store,
+    outgoing: OutgoingChangeset,
+    chunk_size: usize,
+    interruptee: &impl Interruptee,
+    total: &mut UploadInfo,
+) -> Result<(), Error> {
+    let RecordChangeset {
+        changes,
+        collection,
+        ..
+    } = outgoing;
+    let mut changes = changes.into_iter().peekable();
+    while changes.peek().is_some() {
+        interruptee.err_if_interrupted()?;
+        let chunk = OutgoingChangeset {
+            changes: changes.by_ref().take(chunk_size.max(1)).collect(),
+            timestamp: coll_state.last_modified,
+            collection: collection.clone(),
+        };
+        let info =
+            CollectionUpdate::new_from_changeset(client, coll_state, chunk, true)?.upload()?;
+        log::info!(
+            "Committed {} outbox records ({} skipped)",
+            info.successful_ids.len(),
+            info.skipped_ids.len()
+        );
+        coll_state.last_modified = info.modified_timestamp;
+        total.modified_timestamp = info.modified_timestamp;
+        total.skipped_ids.extend(info.skipped_ids);
+        total
+            .successful_ids
+            .extend(info.successful_ids.iter().cloned());
+        store
+            .outbox_chunk_uploaded(info.modified_timestamp, info.successful_ids)
+            .map_err(ErrorKind::StoreError)?;
+    }
     Ok(())
 }
+
+// ideally we'd report this per-batch, but for now, let's just report it
+// as a total. Skipped records count as failures, as desktop does.
+fn record_outgoing_telemetry(telem_engine: &mut telemetry::Engine, upload_info: &UploadInfo) {
+    let num_failed = upload_info.failed_ids.len() + upload_info.skipped_ids.len();
+    let mut telem_outgoing = telemetry::EngineOutgoing::new();
+    telem_outgoing.sent(upload_info.successful_ids.len() + num_failed);
+    telem_outgoing.failed(num_failed);
+    telem_engine.outgoing(telem_outgoing);
+}
@@ -6,17 +6,24 @@
 // global and local state between syncs.
 
 use crate::client::{Sync15StorageClient, Sync15StorageClientInit};
-use crate::error::Error;
+use crate::clients::{self, CommandProcessor};
+use crate::coll_state::StoreSyncAssociation;
+use crate::collection_keys::CollectionKeys;
+use crate::error::{Error, ErrorKind};
 use crate::key_bundle::KeyBundle;
-use crate::state::{GlobalState, PersistedGlobalState, SetupStateMachine};
+use crate::record_types::MetaGlobalRecord;
+use crate::state::{upload_fresh_start, GlobalState, PersistedGlobalState, SetupStateMachine};
 use crate::status::{ServiceStatus, SyncResult};
 use crate::sync::{self, Store};
 use crate::telemetry;
+use crate::util::ServerTimestamp;
 use failure::Fail;
 use interrupt::Interruptee;
 use std::collections::HashMap;
 use std::mem;
 use std::result;
+use std::time::SystemTime;
+use sync_guid::Guid;
 
 /// Info about the client to use. We reuse the client unless
 /// we discover the client_init has changed, in which case we re-create one.
@@ -44,6 +51,9 @@ impl ClientInfo {
 pub struct MemoryCachedState {
     last_client_info: Option<ClientInfo>,
     last_global_state: Option<GlobalState>,
+    // The `info/collections` timestamp for each collection we synced
+    // successfully, as of that sync.
+    synced_collections: HashMap<String, ServerTimestamp>,
 }
 
 /// Sync multiple stores
@@ -70,16 +80,87 @@ pub fn sync_multiple(
     storage_init: &Sync15StorageClientInit,
     root_sync_key: &KeyBundle,
     interruptee: &impl Interruptee,
+) -> SyncResult {
+    sync_multiple_with_command_processor(
+        None,
+        stores,
+        persisted_global_state,
+        mem_cached_state,
+        storage_init,
+        root_sync_key,
+        interruptee,
+    )
+}
+
+/// Like `sync_multiple`, but also syncs the clients collection before the
+/// stores if a `command_processor` is supplied. Commands sent to us by other
+/// clients are applied before any store is synced.
+pub fn sync_multiple_with_command_processor(
+    command_processor: Option<&dyn CommandProcessor>,
+    stores: &[&dyn Store],
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    interruptee: &impl Interruptee,
+) -> SyncResult {
+    sync_multiple_with_params(
+        &SyncMultipleParams::default(),
+        command_processor,
+        stores,
+        persisted_global_state,
+        mem_cached_state,
+        storage_init,
+        root_sync_key,
+        interruptee,
+    )
+}
+
+/// Options that control how `sync_multiple_with_params` syncs the stores.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyncMultipleParams {
+    /// The collection names of the stores to sync first, in the order they
+    /// should be synced. Stores that aren't listed are synced after these, in
+    /// the order they were passed. The clients engine is always synced before
+    /// any store.
+    pub engine_order: Vec<String>,
+    /// If set, we don't start syncing any more stores once this time has
+    /// passed, so that slow or low priority stores can be listed last and
+    /// skipped when there's not much time. The stores we skip are listed in
+    /// `SyncResult::deferred_engines`. A store that's already syncing when the
+    /// deadline passes isn't interrupted.
+    pub deadline: Option<SystemTime>,
+}
+
+/// Like `sync_multiple_with_command_processor`, but with `params` to control
+/// the order the stores are synced in, and when to stop.
+#[allow(clippy::too_many_arguments)]
+pub fn sync_multiple_with_params(
+    params: &SyncMultipleParams,
+    command_processor: Option<&dyn CommandProcessor>,
+    stores: &[&dyn Store],
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    interruptee: &impl Interruptee,
 ) -> SyncResult {
     let mut sync_result = SyncResult {
         service_status: ServiceStatus::OtherError,
         result: Ok(()),
         engine_results: HashMap::with_capacity(stores.len()),
+        recent_clients: HashMap::new(),
+        enabled_engines: Vec::new(),
+        skipped_records: HashMap::new(),
+        deferred_engines: Vec::new(),
         telemetry: telemetry::SyncTelemetryPing::new(),
     };
+    let mut pgs = load_persisted_global_state(persisted_global_state);
     match do_sync_multiple(
+        params,
+        command_processor,
         stores,
-        persisted_global_state,
+        &mut pgs,
         mem_cached_state,
         storage_init,
         root_sync_key,
@@ -99,16 +180,95 @@ pub fn sync_multiple(
                 sync_result.service_status,
                 e.backtrace()
             );
+            // Backoff from the tokenserver doesn't come via the storage
+            // client, so we record it here.
+            if let ErrorKind::BackoffError(until) = e.kind() {
+                pgs.backoff().backoff_until(*until);
+            }
             sync_result.result = Err(e);
         }
     }
+    // Even on failure we update the persisted state, so that we remember
+    // commands we didn't send, and any backoff.
+    match serde_json::to_string(&pgs) {
+        Ok(s) => *persisted_global_state = Some(s),
+        Err(e) => log::error!("Failed to serialize the persisted global state: {}", e),
+    }
     sync_result
 }
 
+/// Deletes all the user's data from the server, for when they ask us to
+/// remove their data from Sync, and then uploads a fresh `meta/global` and
+/// `crypto/keys` with new sync IDs, so that every client (including this
+/// one) resets its engines on the next sync, instead of trying to reconcile
+/// with the data that's gone. The arguments are the same as for
+/// `sync_multiple`, and `persisted_global_state` should be persisted
+/// afterwards, even on failure.
+pub fn wipe_server(
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+) -> result::Result<(), Error> {
+    let pgs = load_persisted_global_state(persisted_global_state);
+    // Everything we cached describes the data we're about to delete.
+    *mem_cached_state = MemoryCachedState::default();
+    let result = do_wipe_server(&pgs, storage_init, root_sync_key);
+    if let Err(e) = &result {
+        if let ErrorKind::BackoffError(until) = e.kind() {
+            pgs.backoff().backoff_until(*until);
+        }
+    }
+    match serde_json::to_string(&pgs) {
+        Ok(s) => *persisted_global_state = Some(s),
+        Err(e) => log::error!("Failed to serialize the persisted global state: {}", e),
+    }
+    result
+}
+
+fn do_wipe_server(
+    pgs: &PersistedGlobalState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+) -> result::Result<(), Error> {
+    pgs.backoff().check(SystemTime::now())?;
+    let mut client = Sync15StorageClient::new(storage_init.clone())?;
+    client.set_backoff(pgs.backoff().clone());
+    log::info!("Deleting all data from the server");
+    client.delete_all()?;
+    upload_fresh_start(&client, pgs, root_sync_key)?;
+    Ok(())
+}
+
+fn load_persisted_global_state(persisted_global_state: &Option<String>) -> PersistedGlobalState {
+    match persisted_global_state {
+        Some(persisted_string) => {
+            match serde_json::from_str::<PersistedGlobalState>(&persisted_string) {
+                Ok(state) => state,
+                _ => {
+                    // Don't log the error since it might contain sensitive
+                    // info (although currently it only contains the declined engines list)
+                    log::error!(
+                        "Failed to parse PersistedGlobalState from JSON! Falling back to default"
+                    );
+                    PersistedGlobalState::default()
+                }
+            }
+        }
+        None => {
+            log::info!("The application didn't give us persisted state - this is only expected on the very first run for a given user.");
+            PersistedGlobalState::default()
+        }
+    }
+}
+
 /// The actual worker for sync_multiple.
+#[allow(clippy::too_many_arguments)]
 fn do_sync_multiple(
+    params: &SyncMultipleParams,
+    command_processor: Option<&dyn CommandProcessor>,
     stores: &[&dyn Store],
-    persisted_global_state: &mut Option<String>,
+    pgs: &mut PersistedGlobalState,
     mem_cached_state: &mut MemoryCachedState,
     storage_init: &Sync15StorageClientInit,
     root_sync_key: &KeyBundle,
@@ -120,9 +280,18 @@ fn do_sync_multiple(
         return Ok(());
     }
 
+    if let Some(until) = pgs.backoff().until(SystemTime::now()) {
+        log::info!(
+            "Not syncing; we've been asked to back off until {:?}",
+            until
+        );
+        sync_result.service_status = ServiceStatus::BackedOff;
+        return Ok(());
+    }
+
     // We put None back into last_client_info now so if we fail entirely,
     // reinitialize everything related to the client.
-    let client_info = match mem::replace(&mut mem_cached_state.last_client_info, None) {
+    let mut client_info = match mem::replace(&mut mem_cached_state.last_client_info, None) {
         Some(client_info) => {
             // if our storage_init has changed it probably means the user has
             // changed, courtesy of the 'kid' in the structure. Thus, we can't
@@ -145,41 +314,25 @@ fn do_sync_multiple(
         }
     };
 
-    let mut pgs = match persisted_global_state {
-        Some(persisted_string) => {
-            match serde_json::from_str::<PersistedGlobalState>(&persisted_string) {
-                Ok(state) => state,
-                _ => {
-                    // Don't log the error since it might contain sensitive
-                    // info (although currently it only contains the declined engines list)
-                    log::error!(
-                        "Failed to parse PersistedGlobalState from JSON! Falling back to default"
-                    );
-                    PersistedGlobalState::default()
-                }
-            }
-        }
-        None => {
-            log::info!("The application didn't give us persisted state - this is only expected on the very first run for a given user.");
-            PersistedGlobalState::default()
-        }
-    };
+    client_info.client.set_backoff(pgs.backoff().clone());
+    // Forget requests from an earlier sync that failed before reporting them.
+    client_info.client.take_request_telemetry();
 
     if interruptee.was_interrupted() {
         sync_result.service_status = ServiceStatus::Interrupted;
         return Ok(());
     }
 
+    // The keys we synced with last time, if we still have them in memory.
+    let previous_keys;
+
     // Advance the state machine to the point where it can perform a full
     // sync. This may involve uploading meta/global, crypto/keys etc.
     let global_state = {
         let last_state = mem::replace(&mut mem_cached_state.last_global_state, None);
-        let mut state_machine = SetupStateMachine::for_full_sync(
-            &client_info.client,
-            &root_sync_key,
-            &mut pgs,
-            interruptee,
-        );
+        previous_keys = last_state.as_ref().map(|state| state.keys.clone());
+        let mut state_machine =
+            SetupStateMachine::for_full_sync(&client_info.client, &root_sync_key, pgs, interruptee);
         log::info!("Advancing state machine to ready (full)");
         let state = match state_machine.run_to_ready(last_state) {
             Err(e) => {
@@ -188,38 +341,117 @@ fn do_sync_multiple(
             }
             Ok(state) => state,
         };
-        // The state machine might have updated our persisted_global_state, so
-        // update the callers repr of it.
-        mem::replace(persisted_global_state, Some(serde_json::to_string(&pgs)?));
         sync_result.telemetry.uid(client_info.client.hashed_uid()?);
+        sync_result.enabled_engines = enabled_engines(&state.global);
         // As for client_info, put None back now so we start from scratch on error.
         mem_cached_state.last_global_state = None;
         state
     };
 
+    // If `crypto/keys` changed since we last synced, reset the stores whose
+    // keys changed, and leave the others alone.
+    if let Some(previous_keys) = previous_keys {
+        if previous_keys.modified != global_state.keys.modified {
+            mem_cached_state.synced_collections.clear();
+        }
+    }
+    let mut reset_failures =
+        reset_stores_with_changed_keys(stores, &global_state, root_sync_key, pgs)?;
+
     // Set the service status to OK here - we may adjust it based on an individual
     // store failing.
     sync_result.service_status = ServiceStatus::Ok;
 
     let mut num_failures = 0;
     let mut telem_sync = telemetry::SyncTelemetry::new();
-    for store in stores {
+
+    if let Some(command_processor) = command_processor {
+        let (telem_engine, failure) = sync_clients(
+            command_processor,
+            &client_info.client,
+            &global_state,
+            root_sync_key,
+            pgs,
+            interruptee,
+            sync_result,
+        );
+        telem_sync.engine(telem_engine);
+        if let Some(this_status) = failure {
+            num_failures += 1;
+            // Failing to sync clients doesn't stop us syncing the stores,
+            // unless it looks like a problem with the server or network.
+            if this_status != ServiceStatus::OtherError {
+                telem_sync.requests(client_info.client.take_request_telemetry());
+                sync_result.telemetry.sync(telem_sync);
+                sync_result.service_status = this_status;
+                return Ok(());
+            }
+        }
+        if interruptee.was_interrupted() {
+            sync_result.service_status = ServiceStatus::Interrupted;
+            return Ok(());
+        }
+    }
+
+    let names = stores
+        .iter()
+        .map(|store| store.collection_name())
+        .collect::<Vec<_>>();
+    for index in sync_order(&names, &params.engine_order) {
+        let store = stores[index];
         let name = store.collection_name();
+        if params
+            .deadline
+            .map_or(false, |deadline| SystemTime::now() >= deadline)
+        {
+            log::info!("Past the deadline; not syncing {}", name);
+            sync_result.deferred_engines.push(name.into());
+            continue;
+        }
         log::info!("Syncing {} engine!", name);
 
         let mut telem_engine = telemetry::Engine::new(name);
-        let result = sync::synchronize(
+        if let Some(e) = reset_failures.remove(name) {
+            // Syncing with the old state would fail to decrypt, or upload
+            // records we've already seen, so skip this store until next time.
+            num_failures += 1;
+            log::warn!("Not syncing {} as we failed to reset it: {:?}", name, e);
+            telem_engine.failure(&e);
+            telem_sync.engine(telem_engine);
+            sync_result.engine_results.insert(name.into(), Err(e));
+            continue;
+        }
+        let server_timestamp = global_state.collections.get(name).cloned();
+        if is_unchanged(store, &global_state, &mem_cached_state.synced_collections) {
+            log::info!("{} hasn't changed locally or on the server; skipping", name);
+            sync_result.engine_results.insert(name.into(), Ok(()));
+            continue;
+        }
+        mem_cached_state.synced_collections.remove(name);
+        let result = synchronize_with_retry(
             &client_info.client,
             &global_state,
             root_sync_key,
-            *store,
-            true,
+            store,
             &mut telem_engine,
             interruptee,
-        );
+        )
+        .map(|skipped| {
+            if !skipped.is_empty() {
+                log::warn!("{} skipped {} oversized records", name, skipped.len());
+                sync_result.skipped_records.insert(name.into(), skipped);
+            }
+        });
 
         match result {
-            Ok(()) => log::info!("Sync of {} was successful!", name),
+            Ok(()) => {
+                log::info!("Sync of {} was successful!", name);
+                if let Some(timestamp) = server_timestamp {
+                    mem_cached_state
+                        .synced_collections
+                        .insert(name.into(), timestamp);
+                }
+            }
             Err(ref e) => {
                 num_failures += 1;
                 // XXX - while we arrange to reset the global state machine
@@ -249,6 +481,7 @@ fn do_sync_multiple(
         }
     }
 
+    telem_sync.requests(client_info.client.take_request_telemetry());
     sync_result.telemetry.sync(telem_sync);
     if num_failures == 0 {
         // XXX - not clear if we should really only do this on full success,
@@ -259,3 +492,386 @@ fn do_sync_multiple(
     }
     Ok(())
 }
+
+/// Syncs the clients engine, and records the result. Returns the engine's
+/// telemetry, and if it failed, the status for the failure.
+fn sync_clients(
+    command_processor: &dyn CommandProcessor,
+    client: &Sync15StorageClient,
+    global_state: &GlobalState,
+    root_sync_key: &KeyBundle,
+    pgs: &mut PersistedGlobalState,
+    interruptee: &impl Interruptee,
+    sync_result: &mut SyncResult,
+) -> (telemetry::Engine, Option<ServiceStatus>) {
+    log::info!("Syncing clients engine!");
+    let mut telem_engine = telemetry::Engine::new(clients::COLLECTION_NAME);
+    let mut engine = clients::Engine::new(command_processor, interruptee);
+    let result = engine.sync(
+        client,
+        global_state,
+        root_sync_key,
+        pgs.pending_commands_mut(),
+        &mut sync_result.telemetry,
+    );
+    sync_result.recent_clients = engine.recent_clients;
+    let failure = match result {
+        Ok(()) => {
+            log::info!("Sync of clients was successful!");
+            None
+        }
+        Err(ref e) => {
+            log::warn!("Sync of clients failed! {:?}", e);
+            telem_engine.failure(e);
+            Some(ServiceStatus::from_err(e))
+        }
+    };
+    sync_result
+        .engine_results
+        .insert(clients::COLLECTION_NAME.into(), result);
+    (telem_engine, failure)
+}
+
+/// Returns true if we can skip syncing `store`, because the collection
+/// hasn't changed on the server since we last synced it, and the store
+/// doesn't have anything to upload. We also check that the store is still
+/// associated with the current sync IDs, since otherwise it needs a reset.
+fn is_unchanged(
+    store: &dyn Store,
+    global_state: &GlobalState,
+    synced_collections: &HashMap<String, ServerTimestamp>,
+) -> bool {
+    let name = store.collection_name();
+    let server_timestamp = match global_state.collections.get(name) {
+        Some(timestamp) => timestamp,
+        None => return false,
+    };
+    if synced_collections.get(name) != Some(server_timestamp) {
+        return false;
+    }
+    let engine = match global_state.global.engines.get(name) {
+        Some(engine) => engine,
+        None => return false,
+    };
+    match store.get_sync_assoc() {
+        Ok(StoreSyncAssociation::Connected(ids))
+            if ids.global == global_state.global.sync_id && ids.coll == engine.sync_id => {}
+        _ => return false,
+    }
+    match store.has_outgoing_changes() {
+        Ok(has_changes) => !has_changes,
+        Err(e) => {
+            log::warn!("Failed to check {} for local changes: {}", name, e);
+            false
+        }
+    }
+}
+
+/// Syncs `store`, and if another client wrote to the collection while we
+/// were syncing it, syncs it again. The second sync downloads their changes
+/// before we upload ours, instead of clobbering them. We only report
+/// telemetry for the last attempt.
+fn synchronize_with_retry(
+    client: &Sync15StorageClient,
+    global_state: &GlobalState,
+    root_sync_key: &KeyBundle,
+    store: &dyn Store,
+    telem_engine: &mut telemetry::Engine,
+    interruptee: &impl Interruptee,
+) -> result::Result<Vec<Guid>, Error> {
+    let result = sync::synchronize(
+        client,
+        global_state,
+        root_sync_key,
+        store,
+        true,
+        telem_engine,
+        interruptee,
+    );
+    match result.as_ref().map_err(Error::kind) {
+        Err(ErrorKind::Conflict(_)) => {
+            log::info!(
+                "{} was changed by another client; syncing it again",
+                store.collection_name()
+            );
+            *telem_engine = telemetry::Engine::new(store.collection_name());
+            sync::synchronize(
+                client,
+                global_state,
+                root_sync_key,
+                store,
+                true,
+                telem_engine,
+                interruptee,
+            )
+        }
+        _ => result,
+    }
+}
+
+/// Resets the stores whose collection key changed since they last synced,
+/// so that they fetch everything again with the new key. We remember a
+/// fingerprint of each store's key in `pgs`, so that we notice the change
+/// even if we've restarted since then. Stores whose key didn't change keep
+/// their state. Returns the error for each store we failed to reset.
+fn reset_stores_with_changed_keys(
+    stores: &[&dyn Store],
+    global_state: &GlobalState,
+    root_sync_key: &KeyBundle,
+    pgs: &mut PersistedGlobalState,
+) -> result::Result<HashMap<&'static str, Error>, Error> {
+    let keys = CollectionKeys::from_encrypted_bso(global_state.keys.clone(), root_sync_key)?;
+    let fingerprints = pgs.key_fingerprints_mut();
+    let mut failures = HashMap::new();
+    for store in stores {
+        let name = store.collection_name();
+        let fingerprint = keys.key_for_collection(name).fingerprint()?;
+        // A store we haven't seen before has nothing to reset.
+        let changed = fingerprints
+            .get(name)
+            .map_or(false, |previous| *previous != fingerprint);
+        if changed {
+            log::info!("The key for {} changed; resetting the store", name);
+            if let Err(e) = store.get_sync_assoc().and_then(|assoc| store.reset(&assoc)) {
+                failures.insert(name, ErrorKind::StoreError(e).into());
+                // Keep the old fingerprint, so that we try again next time.
+                continue;
+            }
+        }
+        fingerprints.insert(name.to_owned(), fingerprint);
+    }
+    Ok(failures)
+}
+
+/// Returns the names of the engines that `global` enables, sorted by name.
+fn enabled_engines(global: &MetaGlobalRecord) -> Vec<String> {
+    let mut engines = global
+        .engines
+        .keys()
+        .filter(|name| !global.declined.contains(name))
+        .cloned()
+        .collect::<Vec<_>>();
+    engines.sort();
+    engines
+}
+
+/// Returns the indexes of `names` in the order they should be synced: first
+/// the ones in `engine_order`, in that order, then the rest, in their
+/// original order.
+fn sync_order(names: &[&str], engine_order: &[String]) -> Vec<usize> {
+    let mut order = (0..names.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&index| {
+        engine_order
+            .iter()
+            .position(|name| name == names[index])
+            .unwrap_or_else(|| engine_order.len())
+    });
+    order
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::changeset::{IncomingChangeset, OutgoingChangeset};
+    use crate::coll_state::CollSyncIds;
+    use crate::record_types::MetaGlobalEngine;
+    use crate::request::{CollectionRequest, InfoCollections, InfoConfiguration};
+    use std::cell::Cell;
+
+    struct TestStore {
+        assoc: StoreSyncAssociation,
+        has_outgoing_changes: Cell<bool>,
+        resets: Cell<usize>,
+    }
+
+    impl TestStore {
+        fn new(assoc: StoreSyncAssociation) -> TestStore {
+            TestStore {
+                assoc,
+                has_outgoing_changes: Cell::new(false),
+                resets: Cell::new(0),
+            }
+        }
+    }
+
+    impl Store for TestStore {
+        fn collection_name(&self) -> &'static str {
+            "bookmarks"
+        }
+
+        fn apply_incoming(
+            &self,
+            _inbound: IncomingChangeset,
+            _telem: &mut telemetry::Engine,
+        ) -> Result<OutgoingChangeset, failure::Error> {
+            unreachable!("these tests shouldn't call these");
+        }
+
+        fn has_outgoing_changes(&self) -> Result<bool, failure::Error> {
+            Ok(self.has_outgoing_changes.get())
+        }
+
+        fn sync_finished(
+            &self,
+            _new_timestamp: ServerTimestamp,
+            _records_synced: Vec<Guid>,
+        ) -> Result<(), failure::Error> {
+            unreachable!("these tests shouldn't call these");
+        }
+
+        fn get_collection_request(&self) -> Result<CollectionRequest, failure::Error> {
+            unreachable!("these tests shouldn't call these");
+        }
+
+        fn get_sync_assoc(&self) -> Result<StoreSyncAssociation, failure::Error> {
+            Ok(self.assoc.clone())
+        }
+
+        fn reset(&self, _assoc: &StoreSyncAssociation) -> Result<(), failure::Error> {
+            self.resets.set(self.resets.get() + 1);
+            Ok(())
+        }
+
+        fn wipe(&self) -> Result<(), failure::Error> {
+            unreachable!("these tests shouldn't call these");
+        }
+    }
+
+    fn global_state_with_keys(keys: &CollectionKeys, root_key: &KeyBundle) -> GlobalState {
+        GlobalState {
+            config: InfoConfiguration::default(),
+            collections: InfoCollections::new(
+                vec![("bookmarks".to_owned(), ServerTimestamp(1000))]
+                    .into_iter()
+                    .collect(),
+            ),
+            global: MetaGlobalRecord {
+                sync_id: "syncIDAAAAAA".into(),
+                storage_version: 5usize,
+                engines: vec![(
+                    "bookmarks".to_owned(),
+                    MetaGlobalEngine {
+                        version: 1usize,
+                        sync_id: "syncIDBBBBBB".into(),
+                    },
+                )]
+                .into_iter()
+                .collect(),
+                declined: vec![],
+            },
+            global_timestamp: ServerTimestamp::default(),
+            keys: keys.to_encrypted_bso(root_key).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_is_unchanged() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let global_state =
+            global_state_with_keys(&CollectionKeys::new_random().unwrap(), &root_key);
+        let store = TestStore::new(StoreSyncAssociation::Connected(CollSyncIds {
+            global: "syncIDAAAAAA".into(),
+            coll: "syncIDBBBBBB".into(),
+        }));
+
+        let mut synced = HashMap::new();
+        assert!(!is_unchanged(&store, &global_state, &synced));
+        synced.insert("bookmarks".to_owned(), ServerTimestamp(1000));
+        assert!(is_unchanged(&store, &global_state, &synced));
+
+        // Local changes mean we need to sync.
+        store.has_outgoing_changes.set(true);
+        assert!(!is_unchanged(&store, &global_state, &synced));
+        store.has_outgoing_changes.set(false);
+
+        // So do remote changes.
+        synced.insert("bookmarks".to_owned(), ServerTimestamp(500));
+        assert!(!is_unchanged(&store, &global_state, &synced));
+        synced.insert("bookmarks".to_owned(), ServerTimestamp(1000));
+
+        // And so does a different sync ID, since the store needs a reset.
+        let store = TestStore::new(StoreSyncAssociation::Connected(CollSyncIds {
+            global: "syncIDAAAAAA".into(),
+            coll: "syncIDCCCCCC".into(),
+        }));
+        assert!(!is_unchanged(&store, &global_state, &synced));
+    }
+
+    #[test]
+    fn test_reset_stores_with_changed_keys() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let store = TestStore::new(StoreSyncAssociation::Connected(CollSyncIds {
+            global: "syncIDAAAAAA".into(),
+            coll: "syncIDBBBBBB".into(),
+        }));
+        let mut keys = CollectionKeys::new_random().unwrap();
+
+        // Each sync starts from freshly loaded persisted state, as if we'd
+        // restarted and lost our `MemoryCachedState` in between.
+        let mut persisted = serde_json::to_string(&PersistedGlobalState::default()).unwrap();
+        let mut sync_with_keys = |keys: &CollectionKeys| {
+            let mut pgs: PersistedGlobalState = serde_json::from_str(&persisted).unwrap();
+            let global_state = global_state_with_keys(keys, &root_key);
+            let failures =
+                reset_stores_with_changed_keys(&[&store], &global_state, &root_key, &mut pgs)
+                    .unwrap();
+            assert!(failures.is_empty());
+            persisted = serde_json::to_string(&pgs).unwrap();
+        };
+
+        // The first sync remembers the key, but has nothing to reset.
+        sync_with_keys(&keys);
+        assert_eq!(store.resets.get(), 0);
+
+        // Giving another collection its own key doesn't affect bookmarks.
+        assert!(keys.add_collection_key("history").unwrap());
+        sync_with_keys(&keys);
+        assert_eq!(store.resets.get(), 0);
+
+        // But rotating the default key does.
+        keys.default = KeyBundle::new_random().unwrap();
+        sync_with_keys(&keys);
+        assert_eq!(store.resets.get(), 1);
+
+        // And we only reset once for each change.
+        sync_with_keys(&keys);
+        assert_eq!(store.resets.get(), 1);
+    }
+
+    #[test]
+    fn test_enabled_engines() {
+        let global = MetaGlobalRecord {
+            sync_id: "syncIDAAAAAA".into(),
+            storage_version: 5usize,
+            engines: ["tabs", "bookmarks", "history"]
+                .iter()
+                .map(|&name| {
+                    (
+                        name.to_owned(),
+                        MetaGlobalEngine {
+                            version: 1usize,
+                            sync_id: "syncIDBBBBBB".into(),
+                        },
+                    )
+                })
+                .collect(),
+            declined: vec!["history".into(), "passwords".into()],
+        };
+        assert_eq!(enabled_engines(&global), vec!["bookmarks", "tabs"]);
+    }
+
+    #[test]
+    fn test_sync_order() {
+        let names = ["bookmarks", "history", "passwords", "tabs"];
+        assert_eq!(sync_order(&names, &[]), vec![0, 1, 2, 3]);
+        assert_eq!(
+            sync_order(&names, &["tabs".into(), "bookmarks".into()]),
+            vec![3, 0, 1, 2]
+        );
+        // Unknown names are ignored.
+        assert_eq!(
+            sync_order(&names, &["forms".into(), "passwords".into()]),
+            vec![2, 0, 1, 3]
+        );
+    }
+}
@@ -5,7 +5,7 @@
 // Manage recording sync telemetry. Assumes some external telemetry
 // library/code which manages submitting.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::time;
 
 use ffi_support::implement_into_ffi_by_json;
@@ -265,13 +265,16 @@ impl<'a> From<&'a Error> for SyncFailure {
                 }
             }
             ErrorKind::BackoffError(_) => SyncFailure::Http { code: 503 },
+            ErrorKind::Conflict(_) => SyncFailure::Http { code: 412 },
             ErrorKind::StorageHttpError(ref e) => match e {
                 ErrorResponse::NotFound { .. } => SyncFailure::Http { code: 404 },
                 ErrorResponse::Unauthorized { .. } => SyncFailure::Auth { from: "storage" },
                 ErrorResponse::PreconditionFailed { .. } => SyncFailure::Http { code: 412 },
+                ErrorResponse::OverQuota { .. } => SyncFailure::Http { code: 403 },
                 ErrorResponse::ServerError { status, .. } => SyncFailure::Http { code: *status },
                 ErrorResponse::RequestFailed { status, .. } => SyncFailure::Http { code: *status },
             },
+            #[cfg(feature = "rc_crypto")]
             ErrorKind::CryptoError(ref e) => SyncFailure::Unexpected {
                 error: e.to_string(),
             },
@@ -614,6 +617,111 @@ mod engine_tests {
     }
 }
 
+/// The HTTP requests made for one collection during a sync. Requests for
+/// `info/` endpoints are recorded under "info".
+#[derive(Debug, Default, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Requests {
+    count: u32,
+
+    /// The total time spent on the requests, in milliseconds.
+    #[serde(skip_serializing_if = "skip_if_default")]
+    took: u64,
+
+    /// The time taken by the slowest request, in milliseconds.
+    #[serde(skip_serializing_if = "skip_if_default")]
+    max_took: u64,
+
+    #[serde(skip_serializing_if = "skip_if_default")]
+    bytes_sent: usize,
+
+    #[serde(skip_serializing_if = "skip_if_default")]
+    bytes_received: usize,
+
+    /// Requests we sent again after the server rejected our token.
+    #[serde(skip_serializing_if = "skip_if_default")]
+    retries: u32,
+
+    /// Requests that failed without a response.
+    #[serde(skip_serializing_if = "skip_if_default")]
+    network_errors: u32,
+
+    /// The number of responses with each status code.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    statuses: BTreeMap<u16, u32>,
+}
+
+impl Requests {
+    /// Records a request, where `status` is `None` if we didn't get a
+    /// response, and `bytes_sent` includes any retries.
+    pub fn record(
+        &mut self,
+        status: Option<u16>,
+        took: time::Duration,
+        bytes_sent: usize,
+        bytes_received: usize,
+        retries: u32,
+    ) {
+        let took = took.as_millis() as u64;
+        self.count += 1;
+        self.took += took;
+        self.max_took = self.max_took.max(took);
+        self.bytes_sent += bytes_sent;
+        self.bytes_received += bytes_received;
+        self.retries += retries;
+        match status {
+            Some(status) => *self.statuses.entry(status).or_default() += 1,
+            None => self.network_errors += 1,
+        }
+    }
+
+    fn merge(&mut self, other: Requests) {
+        self.count += other.count;
+        self.took += other.took;
+        self.max_took = self.max_took.max(other.max_took);
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.retries += other.retries;
+        self.network_errors += other.network_errors;
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+    }
+}
+
+#[cfg(test)]
+mod requests_tests {
+    use super::*;
+
+    #[test]
+    fn test_requests() {
+        let mut requests = Requests::default();
+        requests.record(Some(200), time::Duration::from_millis(50), 0, 100, 0);
+        requests.record(Some(200), time::Duration::from_millis(150), 10, 20, 1);
+        requests.record(None, time::Duration::from_millis(10), 5, 0, 0);
+        assert_json(
+            &requests,
+            json!({
+                "count": 3,
+                "took": 210,
+                "maxTook": 150,
+                "bytesSent": 15,
+                "bytesReceived": 120,
+                "retries": 1,
+                "networkErrors": 1,
+                "statuses": { "200": 2 },
+            }),
+        );
+
+        let mut other = Requests::default();
+        other.record(Some(412), time::Duration::from_millis(200), 1, 0, 0);
+        requests.merge(other);
+        assert_eq!(requests.count, 4);
+        assert_eq!(requests.max_took, 200);
+        assert_eq!(requests.statuses.get(&412), Some(&1));
+    }
+}
+
 /// A single sync. May have many engines, may have its own failure.
 #[derive(Debug, Serialize, Default)]
 pub struct SyncTelemetry {
@@ -623,6 +731,9 @@ pub struct SyncTelemetry {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     engines: Vec<Engine>,
 
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    requests: BTreeMap<String, Requests>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "failureReason")]
     failure: Option<SyncFailure>,
@@ -638,6 +749,13 @@ impl SyncTelemetry {
         self.engines.push(e);
     }
 
+    /// Adds the HTTP requests made during the sync, by collection.
+    pub fn requests(&mut self, requests: BTreeMap<String, Requests>) {
+        for (collection, r) in requests {
+            self.requests.entry(collection).or_default().merge(r);
+        }
+    }
+
     pub fn failure(&mut self, failure: SyncFailure) {
         assert!(self.failure.is_none());
         self.failure = Some(failure);
@@ -0,0 +1,471 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A pure-Rust `CryptoBackend`, so that our tests can run without NSS. This
+//! is a straightforward, unoptimized implementation of AES-256 and SHA-256,
+//! that makes no attempt to resist timing attacks. It's only compiled for
+//! tests, and must never be used for real data.
+
+use crate::crypto::CryptoBackend;
+use crate::error::{ErrorKind, Result};
+use lazy_static::lazy_static;
+
+const BLOCK_LEN: usize = 16;
+const AES_256_KEY_LEN: usize = 32;
+const AES_256_ROUNDS: usize = 14;
+const TAG_LEN: usize = 32;
+
+#[derive(Debug, Default)]
+pub struct TestCryptoBackend;
+
+impl CryptoBackend for TestCryptoBackend {
+    fn seal(&self, enc_key: &[u8], mac_key: &[u8], iv: &[u8], cleartext: &[u8]) -> Result<Vec<u8>> {
+        let aes = Aes256::new(enc_key)?;
+        if iv.len() != BLOCK_LEN {
+            return Err(ErrorKind::BadKeyLength("iv", iv.len(), BLOCK_LEN).into());
+        }
+        // PKCS #7 padding always adds at least one byte.
+        let pad_len = BLOCK_LEN - cleartext.len() % BLOCK_LEN;
+        let mut data = cleartext.to_vec();
+        data.resize(cleartext.len() + pad_len, pad_len as u8);
+
+        let mut prev = [0u8; BLOCK_LEN];
+        prev.copy_from_slice(iv);
+        for chunk in data.chunks_mut(BLOCK_LEN) {
+            for (byte, p) in chunk.iter_mut().zip(&prev) {
+                *byte ^= p;
+            }
+            let mut block = [0u8; BLOCK_LEN];
+            block.copy_from_slice(chunk);
+            aes.encrypt_block(&mut block);
+            chunk.copy_from_slice(&block);
+            prev = block;
+        }
+
+        let tag = hmac_sha256(mac_key, base64::encode(&data).as_bytes());
+        data.extend_from_slice(&tag);
+        Ok(data)
+    }
+
+    fn open(
+        &self,
+        enc_key: &[u8],
+        mac_key: &[u8],
+        iv: &[u8],
+        ciphertext_and_tag: &[u8],
+    ) -> Result<Vec<u8>> {
+        let aes = Aes256::new(enc_key)?;
+        if iv.len() != BLOCK_LEN {
+            return Err(ErrorKind::BadKeyLength("iv", iv.len(), BLOCK_LEN).into());
+        }
+        if ciphertext_and_tag.len() < TAG_LEN + BLOCK_LEN {
+            return Err(ErrorKind::HmacMismatch.into());
+        }
+        let (ciphertext, tag) = ciphertext_and_tag.split_at(ciphertext_and_tag.len() - TAG_LEN);
+        let expected_tag = hmac_sha256(mac_key, base64::encode(ciphertext).as_bytes());
+        if expected_tag
+            .iter()
+            .zip(tag)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            != 0
+            || ciphertext.len() % BLOCK_LEN != 0
+        {
+            return Err(ErrorKind::HmacMismatch.into());
+        }
+
+        let mut data = Vec::with_capacity(ciphertext.len());
+        let mut prev = [0u8; BLOCK_LEN];
+        prev.copy_from_slice(iv);
+        for chunk in ciphertext.chunks(BLOCK_LEN) {
+            let mut block = [0u8; BLOCK_LEN];
+            block.copy_from_slice(chunk);
+            aes.decrypt_block(&mut block);
+            data.extend(block.iter().zip(&prev).map(|(b, p)| b ^ p));
+            prev.copy_from_slice(chunk);
+        }
+
+        // The tag matched, so bad padding means the sender encrypted it
+        // incorrectly. Either way, we can't trust the cleartext.
+        let pad_len = *data.last().unwrap() as usize;
+        if pad_len == 0
+            || pad_len > BLOCK_LEN
+            || data[data.len() - pad_len..]
+                .iter()
+                .any(|&b| b as usize != pad_len)
+        {
+            return Err(ErrorKind::HmacMismatch.into());
+        }
+        data.truncate(data.len() - pad_len);
+        Ok(data)
+    }
+
+    fn fill_random(&self, dest: &mut [u8]) -> Result<()> {
+        getrandom::getrandom(dest).expect("Failed to get random bytes from the OS");
+        Ok(())
+    }
+
+    fn hmac_sha256(&self, key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        Ok(hmac_sha256(key, data).to_vec())
+    }
+
+    fn sha256(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(sha256(data).to_vec())
+    }
+}
+
+struct Tables {
+    sbox: [u8; 256],
+    inv_sbox: [u8; 256],
+}
+
+lazy_static! {
+    // Rather than hardcoding the S-box, we compute it from its definition,
+    // as the multiplicative inverse in GF(2^8) followed by an affine
+    // transformation. `p` walks through every non-zero element by repeated
+    // multiplication by 3, and `q` tracks its inverse.
+    static ref TABLES: Tables = {
+        let mut sbox = [0u8; 256];
+        let mut inv_sbox = [0u8; 256];
+        let mut p: u8 = 1;
+        let mut q: u8 = 1;
+        loop {
+            p = p ^ (p << 1) ^ if p & 0x80 != 0 { 0x1b } else { 0 };
+            q ^= q << 1;
+            q ^= q << 2;
+            q ^= q << 4;
+            if q & 0x80 != 0 {
+                q ^= 0x09;
+            }
+            let x = q ^ q.rotate_left(1) ^ q.rotate_left(2) ^ q.rotate_left(3) ^ q.rotate_left(4);
+            sbox[p as usize] = x ^ 0x63;
+            if p == 1 {
+                break;
+            }
+        }
+        // Zero has no inverse, so it's special-cased.
+        sbox[0] = 0x63;
+        for (i, &s) in sbox.iter().enumerate() {
+            inv_sbox[s as usize] = i as u8;
+        }
+        Tables { sbox, inv_sbox }
+    };
+}
+
+fn xtime(b: u8) -> u8 {
+    (b << 1) ^ if b & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    product
+}
+
+struct Aes256 {
+    round_keys: [[u8; BLOCK_LEN]; AES_256_ROUNDS + 1],
+}
+
+impl Aes256 {
+    fn new(key: &[u8]) -> Result<Self> {
+        if key.len() != AES_256_KEY_LEN {
+            return Err(ErrorKind::BadKeyLength("enc_key", key.len(), AES_256_KEY_LEN).into());
+        }
+        let sbox = &TABLES.sbox;
+        let mut words = [[0u8; 4]; 4 * (AES_256_ROUNDS + 1)];
+        for (word, chunk) in words.iter_mut().zip(key.chunks(4)) {
+            word.copy_from_slice(chunk);
+        }
+        let mut rcon = 1u8;
+        for i in 8..words.len() {
+            let mut temp = words[i - 1];
+            if i % 8 == 0 {
+                temp.rotate_left(1);
+                for b in &mut temp {
+                    *b = sbox[*b as usize];
+                }
+                temp[0] ^= rcon;
+                rcon = xtime(rcon);
+            } else if i % 8 == 4 {
+                for b in &mut temp {
+                    *b = sbox[*b as usize];
+                }
+            }
+            for (j, t) in temp.iter().enumerate() {
+                words[i][j] = words[i - 8][j] ^ t;
+            }
+        }
+        let mut round_keys = [[0u8; BLOCK_LEN]; AES_256_ROUNDS + 1];
+        for (round_key, round_words) in round_keys.iter_mut().zip(words.chunks(4)) {
+            for (dest, word) in round_key.chunks_mut(4).zip(round_words) {
+                dest.copy_from_slice(word);
+            }
+        }
+        Ok(Aes256 { round_keys })
+    }
+
+    fn add_round_key(&self, state: &mut [u8; BLOCK_LEN], round: usize) {
+        for (b, k) in state.iter_mut().zip(&self.round_keys[round]) {
+            *b ^= k;
+        }
+    }
+
+    // The state is stored column by column, so byte `row + 4 * col` is at
+    // (row, col).
+    fn encrypt_block(&self, state: &mut [u8; BLOCK_LEN]) {
+        let sbox = &TABLES.sbox;
+        self.add_round_key(state, 0);
+        for round in 1..=AES_256_ROUNDS {
+            let prev = *state;
+            for row in 0..4 {
+                for col in 0..4 {
+                    // SubBytes and ShiftRows.
+                    state[row + 4 * col] = sbox[prev[row + 4 * ((col + row) % 4)] as usize];
+                }
+            }
+            if round != AES_256_ROUNDS {
+                for col in state.chunks_mut(4) {
+                    let a = [col[0], col[1], col[2], col[3]];
+                    col[0] = xtime(a[0]) ^ gmul(a[1], 3) ^ a[2] ^ a[3];
+                    col[1] = a[0] ^ xtime(a[1]) ^ gmul(a[2], 3) ^ a[3];
+                    col[2] = a[0] ^ a[1] ^ xtime(a[2]) ^ gmul(a[3], 3);
+                    col[3] = gmul(a[0], 3) ^ a[1] ^ a[2] ^ xtime(a[3]);
+                }
+            }
+            self.add_round_key(state, round);
+        }
+    }
+
+    fn decrypt_block(&self, state: &mut [u8; BLOCK_LEN]) {
+        let inv_sbox = &TABLES.inv_sbox;
+        self.add_round_key(state, AES_256_ROUNDS);
+        for round in (0..AES_256_ROUNDS).rev() {
+            let prev = *state;
+            for row in 0..4 {
+                for col in 0..4 {
+                    // InvShiftRows and InvSubBytes.
+                    state[row + 4 * ((col + row) % 4)] = inv_sbox[prev[row + 4 * col] as usize];
+                }
+            }
+            self.add_round_key(state, round);
+            if round != 0 {
+                for col in state.chunks_mut(4) {
+                    let a = [col[0], col[1], col[2], col[3]];
+                    col[0] = gmul(a[0], 14) ^ gmul(a[1], 11) ^ gmul(a[2], 13) ^ gmul(a[3], 9);
+                    col[1] = gmul(a[0], 9) ^ gmul(a[1], 14) ^ gmul(a[2], 11) ^ gmul(a[3], 13);
+                    col[2] = gmul(a[0], 13) ^ gmul(a[1], 9) ^ gmul(a[2], 14) ^ gmul(a[3], 11);
+                    col[3] = gmul(a[0], 11) ^ gmul(a[1], 13) ^ gmul(a[2], 9) ^ gmul(a[3], 14);
+                }
+            }
+        }
+    }
+}
+
+#[rustfmt::skip]
+const SHA256_K: [u32; 64] = [
+    0x428a_2f98, 0x7137_4491, 0xb5c0_fbcf, 0xe9b5_dba5, 0x3956_c25b, 0x59f1_11f1, 0x923f_82a4,
+    0xab1c_5ed5, 0xd807_aa98, 0x1283_5b01, 0x2431_85be, 0x550c_7dc3, 0x72be_5d74, 0x80de_b1fe,
+    0x9bdc_06a7, 0xc19b_f174, 0xe49b_69c1, 0xefbe_4786, 0x0fc1_9dc6, 0x240c_a1cc, 0x2de9_2c6f,
+    0x4a74_84aa, 0x5cb0_a9dc, 0x76f9_88da, 0x983e_5152, 0xa831_c66d, 0xb003_27c8, 0xbf59_7fc7,
+    0xc6e0_0bf3, 0xd5a7_9147, 0x06ca_6351, 0x1429_2967, 0x27b7_0a85, 0x2e1b_2138, 0x4d2c_6dfc,
+    0x5338_0d13, 0x650a_7354, 0x766a_0abb, 0x81c2_c92e, 0x9272_2c85, 0xa2bf_e8a1, 0xa81a_664b,
+    0xc24b_8b70, 0xc76c_51a3, 0xd192_e819, 0xd699_0624, 0xf40e_3585, 0x106a_a070, 0x19a4_c116,
+    0x1e37_6c08, 0x2748_774c, 0x34b0_bcb5, 0x391c_0cb3, 0x4ed8_aa4a, 0x5b9c_ca4f, 0x682e_6ff3,
+    0x748f_82ee, 0x78a5_636f, 0x84c8_7814, 0x8cc7_0208, 0x90be_fffa, 0xa450_6ceb, 0xbef9_a3f7,
+    0xc671_78f2,
+];
+
+#[rustfmt::skip]
+const SHA256_INITIAL_STATE: [u32; 8] = [
+    0x6a09_e667, 0xbb67_ae85, 0x3c6e_f372, 0xa54f_f53a, 0x510e_527f, 0x9b05_688c, 0x1f83_d9ab,
+    0x5be0_cd19,
+];
+
+// The variable names follow FIPS 180-4.
+#[allow(clippy::many_single_char_names)]
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    let mut state = SHA256_INITIAL_STATE;
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let temp1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_K[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let temp2 = s0.wrapping_add(maj);
+            h = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(temp1);
+            d = c;
+            c = b;
+            b = a;
+            a = temp1.wrapping_add(temp2);
+        }
+        for (s, v) in state.iter_mut().zip(&[a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(*v);
+        }
+    }
+
+    let mut digest = [0u8; 32];
+    for (dest, word) in digest.chunks_mut(4).zip(&state) {
+        dest.copy_from_slice(&word.to_be_bytes());
+    }
+    digest
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    const SHA256_BLOCK_LEN: usize = 64;
+    let mut block_key = [0u8; SHA256_BLOCK_LEN];
+    if key.len() > SHA256_BLOCK_LEN {
+        block_key[..32].copy_from_slice(&sha256(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+    let mut inner: Vec<u8> = block_key.iter().map(|b| b ^ 0x36).collect();
+    inner.extend_from_slice(data);
+    let mut outer: Vec<u8> = block_key.iter().map(|b| b ^ 0x5c).collect();
+    outer.extend_from_slice(&sha256(&inner));
+    sha256(&outer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        base16::decode(s).unwrap()
+    }
+
+    #[test]
+    fn test_aes_256_block() {
+        // From appendix C.3 of FIPS 197.
+        let aes = Aes256::new(&hex(
+            "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        ))
+        .unwrap();
+        let mut block = [0u8; BLOCK_LEN];
+        block.copy_from_slice(&hex("00112233445566778899aabbccddeeff"));
+        aes.encrypt_block(&mut block);
+        assert_eq!(block.to_vec(), hex("8ea2b7ca516745bfeafc49904b496089"));
+        aes.decrypt_block(&mut block);
+        assert_eq!(block.to_vec(), hex("00112233445566778899aabbccddeeff"));
+    }
+
+    #[test]
+    fn test_sha256() {
+        assert_eq!(
+            sha256(b"").to_vec(),
+            hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+        assert_eq!(
+            sha256(b"abc").to_vec(),
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq").to_vec(),
+            hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+        );
+    }
+
+    #[test]
+    fn test_hmac_sha256() {
+        // Test cases 2 and 6 from RFC 4231.
+        assert_eq!(
+            hmac_sha256(b"Jefe", b"what do ya want for nothing?").to_vec(),
+            hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843")
+        );
+        assert_eq!(
+            hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )
+            .to_vec(),
+            hex("60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54")
+        );
+    }
+
+    #[test]
+    fn test_padding() {
+        let backend = TestCryptoBackend;
+        let enc_key = [1u8; 32];
+        let mac_key = [2u8; 32];
+        let iv = [3u8; 16];
+        for len in 0..=(BLOCK_LEN * 2) {
+            let cleartext = vec![0x42; len];
+            let sealed = backend.seal(&enc_key, &mac_key, &iv, &cleartext).unwrap();
+            assert_eq!(sealed.len(), (len / BLOCK_LEN + 1) * BLOCK_LEN + TAG_LEN);
+            assert_eq!(
+                backend.open(&enc_key, &mac_key, &iv, &sealed).unwrap(),
+                cleartext
+            );
+        }
+    }
+
+    #[cfg(feature = "rc_crypto")]
+    #[test]
+    fn test_matches_rc_crypto() {
+        use crate::crypto::RcCryptoBackend;
+        let ours = TestCryptoBackend;
+        let theirs = RcCryptoBackend;
+        let mut enc_key = [0u8; 32];
+        let mut mac_key = [0u8; 32];
+        let mut iv = [0u8; 16];
+        ours.fill_random(&mut enc_key).unwrap();
+        ours.fill_random(&mut mac_key).unwrap();
+        ours.fill_random(&mut iv).unwrap();
+        for cleartext in &[
+            &b""[..],
+            b"hello",
+            b"a much longer message, over two blocks",
+        ] {
+            let sealed = ours.seal(&enc_key, &mac_key, &iv, cleartext).unwrap();
+            assert_eq!(
+                sealed,
+                theirs.seal(&enc_key, &mac_key, &iv, cleartext).unwrap()
+            );
+            assert_eq!(
+                theirs.open(&enc_key, &mac_key, &iv, &sealed).unwrap(),
+                cleartext.to_vec()
+            );
+            assert_eq!(
+                ours.hmac_sha256(&mac_key, cleartext).unwrap(),
+                theirs.hmac_sha256(&mac_key, cleartext).unwrap()
+            );
+            assert_eq!(
+                ours.sha256(cleartext).unwrap(),
+                theirs.sha256(cleartext).unwrap()
+            );
+        }
+    }
+}
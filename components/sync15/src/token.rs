@@ -2,13 +2,14 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use crate::client::AccessTokenRefresher;
 use crate::error::{self, ErrorKind, Result};
 use crate::util::ServerTimestamp;
-use rc_crypto::hawk;
 use serde_derive::*;
 use std::borrow::{Borrow, Cow};
 use std::cell::RefCell;
 use std::fmt;
+use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 use url::Url;
 use viaduct::{header_names, Request};
@@ -55,12 +56,25 @@ trait TokenFetcher {
 
 // Our "real" token fetcher, implementing the TokenFetcher trait, which hits
 // the token server
-#[derive(Debug)]
 struct TokenServerFetcher {
     // The stuff needed to fetch a token.
     server_url: Url,
-    access_token: String,
+    // This is replaced if the token server rejects it and we manage to get
+    // a new one from the refresher.
+    access_token: RefCell<String>,
     key_id: String,
+    refresher: Option<Arc<dyn AccessTokenRefresher + Send + Sync>>,
+}
+
+impl fmt::Debug for TokenServerFetcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> ::std::result::Result<(), fmt::Error> {
+        f.debug_struct("TokenServerFetcher")
+            .field("server_url", &self.server_url)
+            .field("access_token", &"(omitted)")
+            .field("key_id", &self.key_id)
+            .field("refresher", &self.refresher.is_some())
+            .finish()
+    }
 }
 
 fn fixup_server_url(mut url: Url) -> Result<Url> {
@@ -84,22 +98,26 @@ fn fixup_server_url(mut url: Url) -> Result<Url> {
 }
 
 impl TokenServerFetcher {
-    fn new(base_url: Url, access_token: String, key_id: String) -> Result<TokenServerFetcher> {
+    fn new(
+        base_url: Url,
+        access_token: String,
+        key_id: String,
+        refresher: Option<Arc<dyn AccessTokenRefresher + Send + Sync>>,
+    ) -> Result<TokenServerFetcher> {
         Ok(TokenServerFetcher {
             server_url: fixup_server_url(base_url)?,
-            access_token,
+            access_token: RefCell::new(access_token),
             key_id,
+            refresher,
         })
     }
-}
 
-impl TokenFetcher for TokenServerFetcher {
-    fn fetch_token(&self) -> Result<TokenFetchResult> {
+    fn fetch_token_with_access_token(&self, access_token: &str) -> Result<TokenFetchResult> {
         log::trace!("Fetching token from {}", self.server_url);
         let resp = Request::get(self.server_url.clone())
             .header(
                 header_names::AUTHORIZATION,
-                format!("Bearer {}", self.access_token),
+                format!("Bearer {}", access_token),
             )?
             .header(header_names::X_KEYID, self.key_id.clone())?
             .send()?;
@@ -131,12 +149,55 @@ impl TokenFetcher for TokenServerFetcher {
             server_timestamp,
         })
     }
+}
+
+impl TokenFetcher for TokenServerFetcher {
+    fn fetch_token(&self) -> Result<TokenFetchResult> {
+        fetch_with_refresh(
+            &self.access_token,
+            self.refresher.as_ref(),
+            |access_token| self.fetch_token_with_access_token(access_token),
+        )
+    }
 
     fn now(&self) -> SystemTime {
         SystemTime::now()
     }
 }
 
+// Calls `fetch` with our access token, and if the token server rejects it
+// with a 401, asks the refresher (if we have one) for a new access token and
+// tries again. If we can't get a new access token, the original error is
+// returned.
+fn fetch_with_refresh<T, F>(
+    access_token: &RefCell<String>,
+    refresher: Option<&Arc<dyn AccessTokenRefresher + Send + Sync>>,
+    fetch: F,
+) -> Result<T>
+where
+    F: Fn(&str) -> Result<T>,
+{
+    let result = fetch(&access_token.borrow());
+    let refresher = match (&result, refresher) {
+        (Err(e), Some(refresher)) => match e.kind() {
+            ErrorKind::TokenserverHttpError(401) => refresher,
+            _ => return result,
+        },
+        _ => return result,
+    };
+    log::info!("The token server rejected our access token; refreshing it");
+    match refresher.refresh_access_token() {
+        Ok(new_access_token) => {
+            access_token.replace(new_access_token);
+            fetch(&access_token.borrow())
+        }
+        Err(e) => {
+            log::warn!("Failed to refresh the access token: {}", e);
+            result
+        }
+    }
+}
+
 // The context stored by our TokenProvider when it has a TokenState::Token
 // state.
 struct TokenContext {
@@ -244,7 +305,7 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     fn new(fetcher: TF) -> Self {
         // We check this at the real entrypoint of the application, but tests
         // can/do bypass that, so check this here too.
-        rc_crypto::ensure_initialized();
+        crate::crypto::ensure_initialized();
         TokenProviderImpl {
             fetcher,
             current_state: RefCell::new(TokenState::NoToken),
@@ -388,9 +449,16 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     fn api_endpoint(&self) -> Result<String> {
         self.with_token(|ctx| Ok(ctx.token.api_endpoint.clone()))
     }
-    // TODO: we probably want a "drop_token/context" type method so that when
-    // using a token with some validity fails the caller can force a new one
-    // (in which case the new token request will probably fail with a 401)
+
+    // Forgets the current token, so the next call fetches a new one. Used
+    // when the storage server rejects a token we think is still valid. We
+    // hold on to the endpoint so that we can still detect node reassignment.
+    fn drop_token(&self) {
+        let state: &mut TokenState = &mut self.current_state.borrow_mut();
+        if let TokenState::Token(ctx) = state {
+            *state = TokenState::Failed(None, Some(ctx.token.api_endpoint.clone()));
+        }
+    }
 }
 
 // The public concrete object exposed by this module
@@ -400,8 +468,13 @@ pub struct TokenProvider {
 }
 
 impl TokenProvider {
-    pub fn new(url: Url, access_token: String, key_id: String) -> Result<Self> {
-        let fetcher = TokenServerFetcher::new(url, access_token, key_id)?;
+    pub fn new(
+        url: Url,
+        access_token: String,
+        key_id: String,
+        refresher: Option<Arc<dyn AccessTokenRefresher + Send + Sync>>,
+    ) -> Result<Self> {
+        let fetcher = TokenServerFetcher::new(url, access_token, key_id, refresher)?;
         Ok(Self {
             imp: TokenProviderImpl::new(fetcher),
         })
@@ -418,6 +491,10 @@ impl TokenProvider {
     pub fn api_endpoint(&self) -> Result<String> {
         self.imp.api_endpoint()
     }
+
+    pub fn drop_token(&self) {
+        self.imp.drop_token()
+    }
 }
 
 #[cfg(test)]
@@ -486,6 +563,87 @@ mod tests {
         assert_eq!(counter.get(), 1);
     }
 
+    #[test]
+    fn test_drop_token() {
+        let counter: Cell<u32> = Cell::new(0);
+        let endpoint = RefCell::new("api_endpoint".to_string());
+        let fetch = || {
+            counter.set(counter.get() + 1);
+            Ok(TokenFetchResult {
+                token: TokenserverToken {
+                    id: "id".to_string(),
+                    key: "key".to_string(),
+                    api_endpoint: endpoint.borrow().clone(),
+                    uid: 1,
+                    duration: 1000,
+                    hashed_fxa_uid: "hash".to_string(),
+                },
+                server_timestamp: ServerTimestamp(0i64),
+            })
+        };
+        let tsc = make_tsc(fetch, SystemTime::now);
+
+        tsc.api_endpoint().expect("should work");
+        assert_eq!(counter.get(), 1);
+
+        // Dropping the token should re-fetch, even though it's still valid.
+        tsc.drop_token();
+        tsc.api_endpoint().expect("should work");
+        assert_eq!(counter.get(), 2);
+
+        // We should still notice if the new token is for a different node.
+        tsc.drop_token();
+        endpoint.replace("new_endpoint".to_string());
+        tsc.api_endpoint().expect_err("should be reassigned");
+        assert_eq!(counter.get(), 3);
+    }
+
+    struct TestRefresher {
+        result: Option<String>,
+    }
+
+    impl AccessTokenRefresher for TestRefresher {
+        fn refresh_access_token(&self) -> ::std::result::Result<String, failure::Error> {
+            self.result
+                .clone()
+                .ok_or_else(|| failure::err_msg("no refresh token"))
+        }
+    }
+
+    #[test]
+    fn test_fetch_with_refresh() {
+        let fetch = |access_token: &str| -> Result<String> {
+            if access_token == "fresh" {
+                Ok("token".to_string())
+            } else {
+                Err(ErrorKind::TokenserverHttpError(401).into())
+            }
+        };
+
+        // Without a refresher, the 401 fails the fetch.
+        let access_token = RefCell::new("expired".to_string());
+        fetch_with_refresh(&access_token, None, fetch).expect_err("should fail");
+
+        // With one, we retry with the new access token, and keep it.
+        let refresher: Arc<dyn AccessTokenRefresher + Send + Sync> = Arc::new(TestRefresher {
+            result: Some("fresh".to_string()),
+        });
+        let token = fetch_with_refresh(&access_token, Some(&refresher), fetch).unwrap();
+        assert_eq!(token, "token");
+        assert_eq!(*access_token.borrow(), "fresh");
+
+        // If the refresher fails, we return the original error.
+        let access_token = RefCell::new("expired".to_string());
+        let refresher: Arc<dyn AccessTokenRefresher + Send + Sync> =
+            Arc::new(TestRefresher { result: None });
+        let err = fetch_with_refresh(&access_token, Some(&refresher), fetch).unwrap_err();
+        match err.kind() {
+            ErrorKind::TokenserverHttpError(401) => {}
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(*access_token.borrow(), "expired");
+    }
+
     #[test]
     fn test_backoff() {
         let counter: Cell<u32> = Cell::new(0);
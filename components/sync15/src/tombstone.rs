@@ -0,0 +1,182 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers for records that might be tombstones, so that each store doesn't
+//! need to handle `"deleted": true` payloads, tombstone TTLs, and deletion
+//! conflicts itself.
+
+use crate::bso_record::Payload;
+use crate::error::Result;
+use serde::{de::DeserializeOwned, Serialize};
+use sync_guid::Guid;
+
+/// A record that's either a tombstone, or a full record of type `T`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MaybeTombstone<T> {
+    Tombstone(Guid),
+    Record(T),
+}
+
+impl<T> MaybeTombstone<T> {
+    #[inline]
+    pub fn is_tombstone(&self) -> bool {
+        match self {
+            MaybeTombstone::Tombstone(_) => true,
+            MaybeTombstone::Record(_) => false,
+        }
+    }
+
+    #[inline]
+    pub fn record(&self) -> Option<&T> {
+        match self {
+            MaybeTombstone::Tombstone(_) => None,
+            MaybeTombstone::Record(record) => Some(record),
+        }
+    }
+
+    #[inline]
+    pub fn into_record(self) -> Option<T> {
+        match self {
+            MaybeTombstone::Tombstone(_) => None,
+            MaybeTombstone::Record(record) => Some(record),
+        }
+    }
+}
+
+impl<T: DeserializeOwned> MaybeTombstone<T> {
+    /// Parses an incoming payload, only deserializing it as a `T` if it isn't
+    /// a tombstone.
+    pub fn from_payload(payload: Payload) -> std::result::Result<Self, serde_json::Error> {
+        Ok(if payload.is_tombstone() {
+            MaybeTombstone::Tombstone(payload.id)
+        } else {
+            MaybeTombstone::Record(payload.into_record()?)
+        })
+    }
+}
+
+impl<T: Serialize> MaybeTombstone<T> {
+    /// Builds the outgoing payload. Tombstones are given `tombstone_ttl`, if
+    /// set, so that the server expires them; records keep whatever TTL they
+    /// serialize themselves.
+    pub fn into_payload(self, tombstone_ttl: Option<u32>) -> Result<Payload> {
+        match self {
+            MaybeTombstone::Tombstone(id) => Ok(match tombstone_ttl {
+                Some(ttl) => Payload::new_tombstone_with_ttl(id.into_string(), ttl),
+                None => Payload::new_tombstone(id.into_string()),
+            }),
+            MaybeTombstone::Record(record) => Payload::from_record(record),
+        }
+    }
+}
+
+/// What a store should do with an incoming record, as far as deletions are
+/// concerned.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reconciled<T> {
+    /// The record is deleted on both sides, or was deleted remotely and we
+    /// never had it. There's nothing to apply or upload, but the store
+    /// should forget any local tombstone.
+    BothDeleted,
+    /// The record was deleted remotely, so it should be deleted locally.
+    DeleteLocal,
+    /// We don't have the record, or we deleted it locally but it was changed
+    /// remotely, so the remote record should be applied as-is.
+    TakeRemote(T),
+    /// Neither side is a tombstone. The store needs to merge the records
+    /// itself.
+    Merge { local: T, remote: T },
+}
+
+/// Reconciles an incoming record with the local one, if there is one.
+/// Remote deletions always win over local changes, as they do for logins and
+/// history, but a remote change wins over a local deletion, so that we don't
+/// lose data another device thinks is still live.
+pub fn reconcile<T>(local: Option<MaybeTombstone<T>>, remote: MaybeTombstone<T>) -> Reconciled<T> {
+    match (local, remote) {
+        (None, MaybeTombstone::Tombstone(_))
+        | (Some(MaybeTombstone::Tombstone(_)), MaybeTombstone::Tombstone(_)) => {
+            Reconciled::BothDeleted
+        }
+        (Some(MaybeTombstone::Record(_)), MaybeTombstone::Tombstone(_)) => Reconciled::DeleteLocal,
+        (None, MaybeTombstone::Record(remote))
+        | (Some(MaybeTombstone::Tombstone(_)), MaybeTombstone::Record(remote)) => {
+            Reconciled::TakeRemote(remote)
+        }
+        (Some(MaybeTombstone::Record(local)), MaybeTombstone::Record(remote)) => {
+            Reconciled::Merge { local, remote }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::*;
+    use serde_json::json;
+
+    #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+    struct TestRecord {
+        id: Guid,
+        title: String,
+    }
+
+    fn record(title: &str) -> MaybeTombstone<TestRecord> {
+        MaybeTombstone::Record(TestRecord {
+            id: "aaaaaaaaaaaa".into(),
+            title: title.into(),
+        })
+    }
+
+    #[test]
+    fn test_payload_round_trip() {
+        let payload = Payload::from_json(json!({ "id": "aaaaaaaaaaaa", "deleted": true })).unwrap();
+        let tombstone = MaybeTombstone::<TestRecord>::from_payload(payload.clone()).unwrap();
+        assert_eq!(tombstone, MaybeTombstone::Tombstone("aaaaaaaaaaaa".into()));
+        assert_eq!(tombstone.clone().into_payload(None).unwrap(), payload);
+
+        let with_ttl = tombstone.into_payload(Some(60)).unwrap();
+        assert!(with_ttl.is_tombstone());
+        assert_eq!(with_ttl.into_bso("test".into()).ttl, Some(60));
+
+        let payload =
+            Payload::from_json(json!({ "id": "aaaaaaaaaaaa", "title": "hello" })).unwrap();
+        let parsed = MaybeTombstone::<TestRecord>::from_payload(payload.clone()).unwrap();
+        assert_eq!(parsed, record("hello"));
+        assert_eq!(parsed.into_payload(Some(60)).unwrap(), payload);
+
+        // Anything that isn't a tombstone must be a valid record.
+        let payload = Payload::from_json(json!({ "id": "bbbbbbbbbbbb" })).unwrap();
+        assert!(MaybeTombstone::<TestRecord>::from_payload(payload).is_err());
+    }
+
+    #[test]
+    fn test_reconcile() {
+        let tombstone = || MaybeTombstone::<TestRecord>::Tombstone("aaaaaaaaaaaa".into());
+        assert_eq!(reconcile(None, tombstone()), Reconciled::BothDeleted);
+        assert_eq!(
+            reconcile(Some(tombstone()), tombstone()),
+            Reconciled::BothDeleted
+        );
+        assert_eq!(
+            reconcile(Some(record("local")), tombstone()),
+            Reconciled::DeleteLocal
+        );
+        assert_eq!(
+            reconcile(Some(tombstone()), record("remote")),
+            Reconciled::TakeRemote(record("remote").into_record().unwrap())
+        );
+        assert_eq!(
+            reconcile(None, record("remote")),
+            Reconciled::TakeRemote(record("remote").into_record().unwrap())
+        );
+        assert_eq!(
+            reconcile(Some(record("local")), record("remote")),
+            Reconciled::Merge {
+                local: record("local").into_record().unwrap(),
+                remote: record("remote").into_record().unwrap(),
+            }
+        );
+    }
+}
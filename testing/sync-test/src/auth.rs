@@ -4,7 +4,6 @@ http://creativecommons.org/publicdomain/zero/1.0/ */
 use crate::Opts;
 use fxa_client::{self, Config as FxaConfig, FirefoxAccount};
 use logins::PasswordEngine;
-use std::collections::HashMap;
 use std::sync::{Arc, Once, ONCE_INIT};
 use sync15::{KeyBundle, Sync15StorageClientInit};
 use url::Url;
@@ -179,27 +178,11 @@ pub struct TestClient {
 
 impl TestClient {
     pub fn new(acct: Arc<TestAccount>) -> Result<Self, failure::Error> {
-        log::info!("Doing oauth flow!");
+        log::info!("Signing in with a password!");
 
         let mut fxa = FirefoxAccount::with_config(acct.cfg.clone());
-        let oauth_uri = fxa.begin_oauth_flow(&[SYNC_SCOPE])?;
-        let auth_url = acct.cfg.auth_url()?;
-        let redirected_to = run_helper_command(
-            "oauth",
-            &[&acct.email, &acct.pass, auth_url.as_str(), &oauth_uri],
-        )?;
-
-        log::info!("Helper command gave '{}'", redirected_to);
-
-        let final_url = Url::parse(&redirected_to)?;
-        let query_params = final_url
-            .query_pairs()
-            .into_owned()
-            .collect::<HashMap<String, String>>();
-
-        // should we be using the OAuthInfo this returns?
-        fxa.complete_oauth_flow(&query_params["code"], &query_params["state"])?;
-        log::info!("OAuth flow finished");
+        fxa.sign_in_with_password(&acct.email, &acct.pass)?;
+        log::info!("Signed in");
 
         Ok(Self {
             fxa,
@@ -228,6 +211,8 @@ impl TestClient {
             key_id: key.kid.clone(),
             access_token: token.token,
             tokenserver_url,
+            access_token_refresher: None,
+            interceptors: vec![],
         };
 
         let root_sync_key = KeyBundle::from_ksync_base64(&key.k)?;